@@ -0,0 +1,338 @@
+//! `clash-rs bench`, pushes traffic through an inbound and an outbound of an
+//! in-process instance to an echo server.
+//!
+//! Through DIRECT the echo server is a loopback one in this process, so the
+//! CPU usage includes the load generator and the echo server, which cost
+//! about the same between runs. A proxy can't reach that one, the traffic
+//! through it goes to an echo server where the proxy can reach it, run by
+//! `clash-rs bench --serve`.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+
+/// the size of the writes when measuring throughput
+const CHUNK_SIZE: usize = 64 * 1024;
+/// the size of the messages when measuring latency
+const PING_SIZE: usize = 64;
+/// round trips when measuring latency
+const PINGS: usize = 1000;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Inbound {
+    Http,
+    Socks,
+    Mixed,
+}
+
+pub struct Options {
+    pub inbound: Inbound,
+    /// the proxy or group from `config` the traffic leaves through
+    pub proxy: Option<String>,
+    /// the echo server, a loopback one if `None`
+    pub target: Option<SocketAddr>,
+    pub duration: Duration,
+    pub connections: usize,
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub fn run(config: &Path, cwd: Option<String>, opts: Options) -> Result<()> {
+    let echo = match opts.target {
+        Some(target) => target,
+        None => start_echo_server(TcpListener::bind("127.0.0.1:0")?)?,
+    };
+    let inbound = free_port()?;
+
+    let mut def = match &opts.proxy {
+        Some(_) => clash::ClashConfigDef::try_from(config.to_path_buf())?,
+        None => clash::ClashConfigDef::default(),
+    };
+    isolate(&mut def, opts.inbound, inbound.port());
+    let outbound = opts.proxy.as_deref().unwrap_or("DIRECT");
+    def.rule = vec![format!("MATCH,{}", outbound)];
+
+    let handle = clash::start(clash::Options {
+        config: clash::Config::Def(def),
+        cwd,
+        rt: Some(clash::TokioRuntime::MultiThread),
+        log_file: None,
+    })?;
+
+    println!(
+        "{} inbound -> {}, {} connections for {}s",
+        opts.inbound.to_possible_value().unwrap().get_name(),
+        outbound,
+        opts.connections,
+        opts.duration.as_secs()
+    );
+
+    let connect = |timeout| dial(opts.inbound, inbound, echo, timeout);
+    let cpu_before = cpu_time();
+    let started = Instant::now();
+    let bytes = throughput(&connect, opts.connections, opts.duration)?;
+    let elapsed = started.elapsed();
+    let cpu = cpu_time()
+        .zip(cpu_before)
+        .map(|(after, before)| after - before);
+    let latencies = latency(&connect)?;
+
+    handle.shutdown()?;
+
+    println!(
+        "throughput  {:.1} MiB/s",
+        bytes as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+    );
+    println!(
+        "latency     p50 {:?}  p99 {:?}  max {:?} over {} round trips",
+        percentile(&latencies, 50),
+        percentile(&latencies, 99),
+        latencies.last().copied().unwrap_or_default(),
+        latencies.len()
+    );
+    match cpu {
+        Some(cpu) => println!(
+            "cpu         {:.0}% of a core",
+            cpu.as_secs_f64() / elapsed.as_secs_f64() * 100.0
+        ),
+        None => println!("cpu         not supported on this platform"),
+    }
+    Ok(())
+}
+
+/// Keep the instance from touching anything a running one may use.
+fn isolate(def: &mut clash::ClashConfigDef, inbound: Inbound, port: u16) {
+    def.port = None;
+    def.socks_port = None;
+    def.mixed_port = None;
+    def.redir_port = None;
+    def.tproxy_port = None;
+    match inbound {
+        Inbound::Http => def.port = Some(port),
+        Inbound::Socks => def.socks_port = Some(port),
+        Inbound::Mixed => def.mixed_port = Some(port),
+    }
+    def.bind_address = "127.0.0.1".to_owned();
+    def.authentication = vec![];
+    def.tun = None;
+    def.dns.listen = None;
+    def.external_controller = None;
+    def.external_controller_tls = None;
+    def.external_controller_unix = None;
+    def.external_controller_pipe = None;
+    def.external_controller_grpc = None;
+    def.profile.store_selected = false;
+    def.profile.store_fake_ip = false;
+    def.reload.watch = false;
+    def.log_level = clash::LogLevel::Error;
+}
+
+fn free_port() -> io::Result<SocketAddr> {
+    TcpListener::bind("127.0.0.1:0")?.local_addr()
+}
+
+/// Run an echo server on `addr` until killed.
+pub fn serve(addr: SocketAddr) -> Result<()> {
+    let addr = start_echo_server(TcpListener::bind(addr)?)?;
+    println!("echo server listening on {}", addr);
+    loop {
+        thread::park();
+    }
+}
+
+fn start_echo_server(listener: TcpListener) -> io::Result<SocketAddr> {
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || echo(stream));
+        }
+    });
+    Ok(addr)
+}
+
+fn echo(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = stream.try_clone()?;
+    io::copy(&mut reader, &mut stream)?;
+    stream.shutdown(Shutdown::Write)
+}
+
+/// Connect to `target` through the inbound at `proxy`.
+fn dial(
+    inbound: Inbound,
+    proxy: SocketAddr,
+    target: SocketAddr,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect_timeout(&proxy, timeout)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(timeout))?;
+    match inbound {
+        Inbound::Http => http_connect(&mut stream, target)?,
+        Inbound::Socks | Inbound::Mixed => socks5_connect(&mut stream, target)?,
+    }
+    Ok(stream)
+}
+
+fn http_connect(stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+    write!(stream, "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target)?;
+    // read up to the end of the headers, without reading past them
+    let mut response = vec![];
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte)?;
+        response.push(byte[0]);
+    }
+    if !response.starts_with(b"HTTP/1.1 200") {
+        return Err(io::Error::other(format!(
+            "CONNECT failed: {}",
+            String::from_utf8_lossy(&response)
+                .lines()
+                .next()
+                .unwrap_or("")
+        )));
+    }
+    Ok(())
+}
+
+fn socks5_connect(stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+    stream.write_all(&[5, 1, 0])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [5, 0] {
+        return Err(io::Error::other("socks5 authentication required"));
+    }
+
+    let mut request = vec![5, 1, 0];
+    match target {
+        SocketAddr::V4(x) => {
+            request.push(1);
+            request.extend(x.ip().octets());
+        }
+        SocketAddr::V6(x) => {
+            request.push(4);
+            request.extend(x.ip().octets());
+        }
+    }
+    request.extend(target.port().to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(io::Error::other(format!(
+            "socks5 CONNECT failed: {}",
+            reply[1]
+        )));
+    }
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        _ => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+    };
+    let mut bound = vec![0; addr_len + 2];
+    stream.read_exact(&mut bound)
+}
+
+/// Bytes echoed back over `connections` connections writing as fast as they
+/// can for `duration`.
+fn throughput<D>(dial: &D, connections: usize, duration: Duration) -> Result<u64>
+where
+    D: Fn(Duration) -> io::Result<TcpStream> + Sync,
+{
+    thread::scope(|s| {
+        let workers: Vec<_> = (0..connections.max(1))
+            .map(|_| s.spawn(|| flood(dial(Duration::from_secs(10))?, duration)))
+            .collect();
+        let mut total = 0;
+        for worker in workers {
+            total += worker.join().map_err(|_| "bench worker panicked")??;
+        }
+        Ok(total)
+    })
+}
+
+fn flood(stream: TcpStream, duration: Duration) -> io::Result<u64> {
+    let mut writer = stream.try_clone()?;
+    let deadline = Instant::now() + duration;
+    let sender = thread::spawn(move || -> io::Result<()> {
+        let chunk = vec![0x5a; CHUNK_SIZE];
+        while Instant::now() < deadline {
+            writer.write_all(&chunk)?;
+        }
+        writer.shutdown(Shutdown::Write)
+    });
+
+    let mut reader = stream;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => received += n as u64,
+            // what's still in flight when the time is up doesn't count
+            Err(_) if Instant::now() >= deadline => break,
+            Err(e) => return Err(e),
+        }
+    }
+    sender
+        .join()
+        .map_err(|_| io::Error::other("sender panicked"))??;
+    Ok(received)
+}
+
+/// Round trip times of small messages on one connection, sorted.
+fn latency<D>(dial: &D) -> Result<Vec<Duration>>
+where
+    D: Fn(Duration) -> io::Result<TcpStream>,
+{
+    let mut stream = dial(Duration::from_secs(10))?;
+    let ping = [0x5a; PING_SIZE];
+    let mut pong = [0; PING_SIZE];
+    let mut latencies = Vec::with_capacity(PINGS);
+    for _ in 0..PINGS {
+        let started = Instant::now();
+        stream.write_all(&ping)?;
+        stream.read_exact(&mut pong)?;
+        latencies.push(started.elapsed());
+    }
+    latencies.sort();
+    Ok(latencies)
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+/// CPU time used by this process so far.
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let time = |x: libc::timeval| {
+        Duration::from_secs(x.tv_sec as u64)
+            + Duration::from_micros(x.tv_usec as u64)
+    };
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
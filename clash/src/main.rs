@@ -1,10 +1,19 @@
 extern crate clash_lib as clash;
 
-use clap::Parser;
+mod bench;
+mod profiles;
+#[cfg(windows)]
+mod service;
+#[cfg(target_os = "linux")]
+mod systemd;
+
+use clap::{Parser, Subcommand};
 use clash::TokioRuntime;
 use std::{
+    net::SocketAddr,
     path::{Path, PathBuf},
     process::exit,
+    time::Duration,
 };
 
 #[derive(Parser)]
@@ -26,22 +35,157 @@ struct Cli {
     #[clap(
         short = 't',
         long,
+        visible_alias = "test",
         value_parser,
         default_value = "false",
         help = "Test configuration and exit"
     )]
     test_config: bool,
+    #[clap(
+        short,
+        long,
+        value_name = "NAME",
+        help = "Run the profile NAME from the profiles directory instead of the \
+                configuration file"
+    )]
+    profile: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the Windows service
+    Service {
+        #[clap(subcommand)]
+        action: ServiceAction,
+    },
+    /// List the profiles, or switch the running instance to another
+    Profile {
+        #[clap(subcommand)]
+        action: ProfileAction,
+    },
+    /// Measure throughput, latency and CPU usage through an echo server
+    Bench {
+        /// The inbound the traffic enters through
+        #[clap(long, value_enum, default_value = "mixed")]
+        inbound: bench::Inbound,
+        /// The proxy or group from the configuration the traffic leaves
+        /// through, DIRECT if not given
+        #[clap(long, requires = "target")]
+        proxy: Option<String>,
+        /// The echo server to send the traffic to, one the proxy can reach,
+        /// a loopback one if not given
+        #[clap(long, value_name = "IP:PORT")]
+        target: Option<SocketAddr>,
+        /// Only run an echo server on ADDR, to be the target of a bench
+        /// elsewhere
+        #[clap(long, value_name = "ADDR", conflicts_with_all = ["proxy", "target"])]
+        serve: Option<SocketAddr>,
+        /// Seconds to measure throughput for
+        #[clap(long, default_value = "10")]
+        duration: u64,
+        /// Connections to measure throughput over
+        #[clap(long, default_value = "1")]
+        connections: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileAction {
+    /// List the profiles in the profiles directory
+    List,
+    /// Switch the running instance to the profile NAME, through the external
+    /// controller of the configuration
+    Switch { name: String },
+}
+
+#[derive(Subcommand)]
+enum ServiceAction {
+    /// Register a service running the given configuration on boot
+    Install,
+    /// Stop and remove the service
+    Uninstall,
+    /// Run as the service, only used by the service manager
+    Run,
 }
 
 fn main() {
     let cli = Cli::parse();
-    let file = cli
+    let dir = cli
         .directory
-        .as_ref()
-        .unwrap_or(&std::env::current_dir().unwrap())
-        .join(cli.config)
-        .to_string_lossy()
-        .to_string();
+        .clone()
+        .unwrap_or_else(|| std::env::current_dir().unwrap());
+    let file = match &cli.profile {
+        Some(name) => match clash::profiles::path(&dir, name) {
+            Ok(path) => path,
+            Err(e) => panic!("{}", e),
+        },
+        None => dir.join(cli.config),
+    }
+    .to_string_lossy()
+    .to_string();
+
+    if let Some(Command::Profile { action }) = cli.command {
+        let rv = match action {
+            ProfileAction::List => profiles::list(&dir),
+            ProfileAction::Switch { name } => {
+                profiles::switch(Path::new(&file), &name)
+            }
+        };
+        if let Err(e) = rv {
+            eprintln!("profile error: {}", e);
+            exit(1);
+        }
+        exit(0);
+    }
+
+    if let Some(Command::Bench {
+        inbound,
+        proxy,
+        target,
+        serve,
+        duration,
+        connections,
+    }) = cli.command
+    {
+        if let Some(addr) = serve {
+            if let Err(e) = bench::serve(addr) {
+                eprintln!("echo server error: {}", e);
+                exit(1);
+            }
+            exit(0);
+        }
+        if proxy.is_some() && !Path::new(&file).exists() {
+            panic!("config file not found: {}", file);
+        }
+        let opts = bench::Options {
+            inbound,
+            proxy,
+            target,
+            duration: Duration::from_secs(duration),
+            connections,
+        };
+        let cwd = cli.directory.map(|x| x.to_string_lossy().to_string());
+        if let Err(e) = bench::run(Path::new(&file), cwd, opts) {
+            eprintln!("bench error: {}", e);
+            exit(1);
+        }
+        exit(0);
+    }
+
+    if let Some(Command::Service { action }) = cli.command {
+        if !matches!(action, ServiceAction::Uninstall) && !Path::new(&file).exists()
+        {
+            panic!("config file not found: {}", file);
+        }
+        if let Err(e) = run_service_action(action, file, cli.directory) {
+            eprintln!("service error: {}", e);
+            exit(1);
+        }
+        exit(0);
+    }
 
     if !Path::new(&file).exists() {
         // TODO: offer a internal default config, to compatible with clash
@@ -49,7 +193,8 @@ fn main() {
         panic!("config file not found: {}", file);
     }
     if cli.test_config {
-        match clash::Config::File(file.clone()).try_parse() {
+        let cwd = cli.directory.clone().unwrap_or_else(|| PathBuf::from("."));
+        match clash::Config::File(file.clone()).try_parse_in(&cwd) {
             Ok(_) => {
                 println!("configuration file {} test is successful", file);
                 exit(0);
@@ -60,15 +205,60 @@ fn main() {
             }
         }
     }
-    match clash::start(clash::Options {
+
+    let handle = match clash::start(clash::Options {
         config: clash::Config::File(file),
         cwd: cli.directory.map(|x| x.to_string_lossy().to_string()),
         rt: Some(TokioRuntime::MultiThread),
         log_file: None,
     }) {
-        Ok(_) => {}
+        Ok(handle) => handle,
         Err(_) => {
             exit(1);
         }
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        let _ = systemd::notify("READY=1");
+        systemd::start_watchdog(&handle);
     }
+
+    let rv = handle.wait();
+
+    #[cfg(target_os = "linux")]
+    let _ = systemd::notify("STOPPING=1");
+
+    if rv.is_err() {
+        exit(1);
+    }
+}
+
+#[cfg(windows)]
+fn run_service_action(
+    action: ServiceAction,
+    file: String,
+    directory: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // the service doesn't start in the current directory
+    let file = std::path::absolute(&file)?.to_string_lossy().to_string();
+    let cwd = directory
+        .map(std::path::absolute)
+        .transpose()?
+        .map(|x| x.to_string_lossy().to_string());
+    match action {
+        ServiceAction::Install => service::install(&file, cwd.as_deref())?,
+        ServiceAction::Uninstall => service::uninstall()?,
+        ServiceAction::Run => service::run(file, cwd)?,
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn run_service_action(
+    _: ServiceAction,
+    _: String,
+    _: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("services are only supported on Windows, use systemd elsewhere".into())
 }
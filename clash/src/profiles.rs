@@ -0,0 +1,74 @@
+//! `clash-rs profile`, the named profiles in `profiles/` under the working
+//! directory, see [`clash::profiles`].
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs},
+    path::Path,
+    time::Duration,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Print the names of the profiles under `dir`.
+pub fn list(dir: &Path) -> Result<()> {
+    for name in clash::profiles::list(dir)? {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+/// Ask the instance running `config` to switch to the profile `name`,
+/// through its external controller.
+pub fn switch(config: &Path, name: &str) -> Result<()> {
+    clash::profiles::validate_name(name)?;
+    let def = clash::ClashConfigDef::try_from(config.to_path_buf())?;
+    let Some(controller) = def.external_controller else {
+        return Err(format!("no external-controller in {}", config.display()).into());
+    };
+    let addr = controller_addr(&controller)?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    // the switch returns once the profile is loaded
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    let mut request = format!(
+        "PUT /profiles/{} HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\n\
+         Connection: close\r\n",
+        name, addr
+    );
+    if let Some(secret) = def.secret.filter(|x| !x.is_empty()) {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", secret));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|x| x.parse::<u16>().ok())
+        .unwrap_or_default();
+    if !(200..300).contains(&status) {
+        let body = response.split_once("\r\n\r\n").map(|x| x.1).unwrap_or("");
+        return Err(format!("switching failed ({}): {}", status, body).into());
+    }
+    println!("switched to profile {}", name);
+    Ok(())
+}
+
+/// Where to reach a controller listening at `listen`.
+fn controller_addr(listen: &str) -> Result<SocketAddr> {
+    let listen = match listen.strip_prefix(':') {
+        Some(port) => format!("localhost:{}", port),
+        None => listen.to_owned(),
+    };
+    let mut addr = listen
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("invalid external-controller: {}", listen))?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+    Ok(addr)
+}
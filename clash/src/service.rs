@@ -0,0 +1,145 @@
+//! Running as a Windows service, registered with `clash-rs service install`.
+
+use std::{
+    ffi::OsString,
+    sync::{mpsc, OnceLock},
+    time::Duration,
+};
+
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+        ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+const SERVICE_NAME: &str = "clash-rs";
+
+/// what the service runs, set before handing over to the SCM
+static OPTIONS: OnceLock<(String, Option<String>)> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Register the service to run `config` in `cwd` on boot.
+pub fn install(config: &str, cwd: Option<&str>) -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    let mut launch_arguments = vec![OsString::from("-c"), OsString::from(config)];
+    if let Some(cwd) = cwd {
+        launch_arguments.extend([OsString::from("-d"), OsString::from(cwd)]);
+    }
+    launch_arguments.extend([OsString::from("service"), OsString::from("run")]);
+
+    let service = manager.create_service(
+        &ServiceInfo {
+            name: OsString::from(SERVICE_NAME),
+            display_name: OsString::from(SERVICE_NAME),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()
+                .map_err(windows_service::Error::Winapi)?,
+            launch_arguments,
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        },
+        ServiceAccess::CHANGE_CONFIG,
+    )?;
+    service.set_description("A custom protocol, rule based network proxy")
+}
+
+/// Stop the service if it's running and remove it.
+pub fn uninstall() -> windows_service::Result<()> {
+    let manager =
+        ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    service.delete()
+}
+
+/// Hand the process over to the SCM, returns once the service stopped.
+pub fn run(config: String, cwd: Option<String>) -> windows_service::Result<()> {
+    let _ = OPTIONS.set((config, cwd));
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        eprintln!("service error: {}", e);
+    }
+}
+
+fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: if state == ServiceState::Running {
+            ServiceControlAccept::STOP
+        } else {
+            ServiceControlAccept::empty()
+        },
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: if state == ServiceState::Running {
+            Duration::default()
+        } else {
+            Duration::from_secs(30)
+        },
+        process_id: None,
+    }
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let status_handle = service_control_handler::register(
+        SERVICE_NAME,
+        move |control| match control {
+            ServiceControl::Stop => {
+                let _ = stop_tx.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        },
+    )?;
+
+    status_handle.set_service_status(status(ServiceState::StartPending, 0))?;
+
+    let (config, cwd) = OPTIONS.get().cloned().unwrap_or_default();
+    let handle = match clash::start(clash::Options {
+        config: clash::Config::File(config),
+        cwd,
+        rt: Some(clash::TokioRuntime::MultiThread),
+        log_file: None,
+    }) {
+        Ok(handle) => handle,
+        Err(_) => {
+            return status_handle
+                .set_service_status(status(ServiceState::Stopped, 1));
+        }
+    };
+
+    status_handle.set_service_status(status(ServiceState::Running, 0))?;
+    let _ = stop_rx.recv();
+    status_handle.set_service_status(status(ServiceState::StopPending, 0))?;
+
+    let exit_code = match handle.shutdown() {
+        Ok(_) => 0,
+        Err(_) => 1,
+    };
+    status_handle.set_service_status(status(ServiceState::Stopped, exit_code))
+}
@@ -0,0 +1,58 @@
+//! `Type=notify` readiness and watchdog pings for systemd, see sd_notify(3).
+
+use std::{
+    ffi::OsString,
+    io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    time::Duration,
+};
+
+use tracing::warn;
+
+/// Send `state` to systemd, does nothing when not started by systemd.
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    let addr = socket_addr(&path)?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+fn socket_addr(path: &OsString) -> io::Result<SocketAddr> {
+    match path.as_bytes() {
+        [b'@', name @ ..] => SocketAddr::from_abstract_name(name),
+        _ => SocketAddr::from_pathname(path),
+    }
+}
+
+/// Ping the watchdog at half the interval systemd expects, if it's enabled
+/// for this process. The pings come from the runtime of `handle`, a stuck
+/// runtime stops them and gets restarted.
+pub fn start_watchdog(handle: &clash::Handle) {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    handle.on_heartbeat(interval / 2, || {
+        if let Err(e) = notify("WATCHDOG=1") {
+            warn!("failed to ping systemd watchdog: {}", e);
+        }
+    });
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
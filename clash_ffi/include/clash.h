@@ -0,0 +1,57 @@
+/*
+ * C bindings for embedding clash-rs, link against libclash_ffi.
+ *
+ * Functions that fail return NULL or -1, clash_last_error() then tells what
+ * went wrong. Callbacks run on the instance's own threads.
+ */
+
+#ifndef CLASH_H
+#define CLASH_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct ClashHandle ClashHandle;
+
+typedef void (*ClashLogCallback)(const char *level, const char *msg, void *ctx);
+typedef void (*ClashTrafficCallback)(int64_t up, int64_t down, void *ctx);
+typedef bool (*ClashProtectSocketCallback)(int fd, void *ctx);
+
+/* The message of the last error on the calling thread, or NULL. Valid until
+ * the next call on the same thread. */
+const char *clash_last_error(void);
+
+/* Start an instance with the config file at config_path, relative paths in
+ * the config are resolved against cwd, which may be NULL. */
+ClashHandle *clash_start(const char *config_path, const char *cwd);
+
+/* Replace the running config, the old one stays in place on failure. */
+int clash_reload(ClashHandle *handle, const char *config_path);
+
+/* Stop the instance and free handle. */
+int clash_shutdown(ClashHandle *handle);
+
+/* Call cb with every log line until the instance stops. */
+void clash_set_log_callback(ClashHandle *handle, ClashLogCallback cb, void *ctx);
+
+/* Call cb every second with the bytes uploaded and downloaded in the last
+ * second until the instance stops. */
+void clash_set_traffic_callback(ClashHandle *handle, ClashTrafficCallback cb,
+                                void *ctx);
+
+/* Call cb with the fd of every outbound socket before it connects, e.g. to
+ * pass it to VpnService.protect() on Android. The socket is dropped if cb
+ * returns false. NULL removes the callback. Applies to all instances, set it
+ * before clash_start. */
+void clash_set_protect_socket_callback(ClashProtectSocketCallback cb,
+                                       void *ctx);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CLASH_H */
@@ -0,0 +1,216 @@
+//! C bindings for embedding clash-rs in mobile apps, see `include/clash.h`.
+//!
+//! Functions that fail return NULL or -1, `clash_last_error` then tells what
+//! went wrong.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    ptr,
+};
+
+use clash_lib::{Config, Handle, Options, TokioRuntime};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(e: impl ToString) {
+    let msg = CString::new(e.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|x| *x.borrow_mut() = Some(msg));
+}
+
+/// The `ctx` pointer handed back to callbacks, which may run on any thread.
+/// Making that safe is up to the caller.
+#[derive(Clone, Copy)]
+struct Context(*mut c_void);
+
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
+impl Context {
+    // a method so closures capture the whole `Context`, not the pointer
+    fn get(self) -> *mut c_void {
+        self.0
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+pub type LogCallback =
+    extern "C" fn(level: *const c_char, msg: *const c_char, ctx: *mut c_void);
+pub type TrafficCallback = extern "C" fn(up: i64, down: i64, ctx: *mut c_void);
+pub type ProtectSocketCallback = extern "C" fn(fd: c_int, ctx: *mut c_void) -> bool;
+
+/// The message of the last error on the calling thread, or NULL. Valid until
+/// the next call on the same thread.
+#[no_mangle]
+pub extern "C" fn clash_last_error() -> *const c_char {
+    LAST_ERROR.with(|x| x.borrow().as_ref().map_or(ptr::null(), |x| x.as_ptr()))
+}
+
+/// Start an instance with the config file at `config_path`, relative paths
+/// in the config are resolved against `cwd`. Returns NULL on failure.
+///
+/// # Safety
+/// `config_path` must be a valid C string, `cwd` a valid C string or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn clash_start(
+    config_path: *const c_char,
+    cwd: *const c_char,
+) -> *mut Handle {
+    let Some(config_path) = to_str(config_path) else {
+        set_last_error("invalid config path");
+        return ptr::null_mut();
+    };
+
+    match clash_lib::start(Options {
+        config: Config::File(config_path.to_owned()),
+        cwd: to_str(cwd).map(ToOwned::to_owned),
+        rt: Some(TokioRuntime::MultiThread),
+        log_file: None,
+    }) {
+        Ok(handle) => Box::into_raw(Box::new(handle)),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Replace the running config with the one at `config_path`. Returns 0 on
+/// success, -1 if the old config is still in place.
+///
+/// # Safety
+/// `handle` must come from `clash_start` and not be shut down yet,
+/// `config_path` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn clash_reload(
+    handle: *mut Handle,
+    config_path: *const c_char,
+) -> c_int {
+    let Some(handle) = handle.as_ref() else {
+        set_last_error("invalid handle");
+        return -1;
+    };
+    let Some(config_path) = to_str(config_path) else {
+        set_last_error("invalid config path");
+        return -1;
+    };
+
+    match handle.reload(Config::File(config_path.to_owned())) {
+        Ok(_) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Stop the instance and free `handle`. Returns -1 if the instance stopped
+/// with an error.
+///
+/// # Safety
+/// `handle` must come from `clash_start`, it's invalid after this call.
+#[no_mangle]
+pub unsafe extern "C" fn clash_shutdown(handle: *mut Handle) -> c_int {
+    if handle.is_null() {
+        set_last_error("invalid handle");
+        return -1;
+    }
+
+    match Box::from_raw(handle).shutdown() {
+        Ok(_) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Call `cb` with every log line until the instance stops.
+///
+/// # Safety
+/// `handle` must come from `clash_start` and not be shut down yet, `ctx`
+/// must be usable from any thread.
+#[no_mangle]
+pub unsafe extern "C" fn clash_set_log_callback(
+    handle: *mut Handle,
+    cb: LogCallback,
+    ctx: *mut c_void,
+) {
+    let Some(handle) = handle.as_ref() else {
+        return;
+    };
+    let ctx = Context(ctx);
+    handle.on_log(move |event| {
+        let level = CString::new(event.level.to_string()).unwrap_or_default();
+        let msg = CString::new(event.msg.replace('\0', " ")).unwrap_or_default();
+        cb(level.as_ptr(), msg.as_ptr(), ctx.get());
+    });
+}
+
+/// Call `cb` every second with the bytes uploaded and downloaded in the last
+/// second until the instance stops.
+///
+/// # Safety
+/// `handle` must come from `clash_start` and not be shut down yet, `ctx`
+/// must be usable from any thread.
+#[no_mangle]
+pub unsafe extern "C" fn clash_set_traffic_callback(
+    handle: *mut Handle,
+    cb: TrafficCallback,
+    ctx: *mut c_void,
+) {
+    let Some(handle) = handle.as_ref() else {
+        return;
+    };
+    let ctx = Context(ctx);
+    handle.on_traffic(move |up, down| cb(up, down, ctx.get()));
+}
+
+/// Call `cb` with the fd of every outbound socket before it connects, e.g. to
+/// pass it to `VpnService.protect()` on Android. The socket is dropped if
+/// `cb` returns false. NULL removes the callback. Applies to all instances.
+///
+/// # Safety
+/// `ctx` must be usable from any thread.
+#[no_mangle]
+pub unsafe extern "C" fn clash_set_protect_socket_callback(
+    cb: Option<ProtectSocketCallback>,
+    ctx: *mut c_void,
+) {
+    let ctx = Context(ctx);
+    clash_lib::set_socket_protector(
+        cb.map(|cb| Box::new(move |fd| cb(fd, ctx.get())) as _),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CStr, ptr};
+
+    use super::{clash_last_error, clash_shutdown, clash_start};
+
+    #[test]
+    fn test_start_error() {
+        unsafe {
+            let handle = clash_start(c"/no/such/config.yaml".as_ptr(), ptr::null());
+            assert!(handle.is_null());
+            assert!(!clash_last_error().is_null());
+            assert!(!CStr::from_ptr(clash_last_error()).is_empty());
+
+            assert_eq!(clash_shutdown(ptr::null_mut()), -1);
+            assert_eq!(
+                CStr::from_ptr(clash_last_error()).to_str().unwrap(),
+                "invalid handle"
+            );
+        }
+    }
+}
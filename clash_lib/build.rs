@@ -5,9 +5,26 @@ fn main() -> std::io::Result<()> {
         println!("cargo::rustc-cfg=ci");
     }
 
+    println!("cargo:rerun-if-env-changed=CLASH_RS_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    if std::env::var("CLASH_RS_GIT_COMMIT").is_err() {
+        if let Some(commit) = std::process::Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|x| x.status.success())
+            .and_then(|x| String::from_utf8(x.stdout).ok())
+        {
+            println!("cargo:rustc-env=CLASH_RS_GIT_COMMIT={}", commit.trim());
+        }
+    }
+
     println!("cargo:rerun-if-changed=src/common/geodata/geodata.proto");
     prost_build::compile_protos(
         &["src/common/geodata/geodata.proto"],
         &["src/common/geodata"],
-    )
+    )?;
+
+    println!("cargo:rerun-if-changed=src/app/api/grpc/controller.proto");
+    tonic_build::compile_protos("src/app/api/grpc/controller.proto")
 }
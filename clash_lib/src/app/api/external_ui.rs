@@ -0,0 +1,165 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+use crate::common::{http::HttpClient, utils::download};
+
+/// Held by an update, the archive and staging paths next to the dashboard
+/// being the same for all of them.
+static UPDATING: Mutex<()> = Mutex::const_new(());
+
+/// The directory the dashboard is served from:
+/// `$CWD/{external-ui}[/{external-ui-name}]`
+pub fn ui_dir(cwd: &str, external_ui: &str, name: Option<&str>) -> PathBuf {
+    let dir = PathBuf::from(cwd).join(external_ui);
+    match name {
+        Some(name) if !name.is_empty() => dir.join(name),
+        _ => dir,
+    }
+}
+
+/// The dashboard needs to be downloaded if its folder is missing or empty.
+pub fn need_download(dir: &Path) -> bool {
+    std::fs::read_dir(dir)
+        .map(|mut x| x.next().is_none())
+        .unwrap_or(true)
+}
+
+/// Download the zip bundle from `url` and replace the content of `dir` with
+/// it. Concurrent updates run one after the other.
+pub async fn update(
+    dir: &Path,
+    url: &str,
+    client: &HttpClient,
+) -> anyhow::Result<()> {
+    let _updating = UPDATING.lock().await;
+    if let Some(parent) = dir.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let archive = with_suffix(dir, ".zip");
+    info!("downloading dashboard from {}", url);
+    download(url, &archive, client).await?;
+
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let rv = unpack(&archive, &dir);
+        let _ = std::fs::remove_file(&archive);
+        rv
+    })
+    .await?
+}
+
+/// Extract `archive` into `dir`.
+/// Bundles like github branch archives wrap everything in a single top level
+/// folder, in which case the content of that folder is used.
+fn unpack(archive: &Path, dir: &Path) -> anyhow::Result<()> {
+    let staging = with_suffix(dir, ".unzip");
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+
+    zip::ZipArchive::new(File::open(archive)?)?.extract(&staging)?;
+
+    let mut entries = std::fs::read_dir(&staging)?.collect::<Result<Vec<_>, _>>()?;
+    let root = match entries.pop() {
+        Some(entry) if entries.is_empty() && entry.file_type()?.is_dir() => {
+            entry.path()
+        }
+        _ => staging.clone(),
+    };
+
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    std::fs::rename(&root, dir)?;
+    if root != staging {
+        std::fs::remove_dir_all(&staging)?;
+    }
+
+    debug!("dashboard extracted to {}", dir.to_string_lossy());
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = OsString::from(path.as_os_str());
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::write::SimpleFileOptions;
+
+    use super::{need_download, ui_dir, unpack};
+
+    fn make_zip(path: &std::path::Path, files: &[(&str, &str)]) {
+        let mut w = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        for (name, content) in files {
+            w.start_file(*name, SimpleFileOptions::default()).unwrap();
+            w.write_all(content.as_bytes()).unwrap();
+        }
+        w.finish().unwrap();
+    }
+
+    #[test]
+    fn test_ui_dir() {
+        assert_eq!(
+            ui_dir("/etc/clash", "ui", Some("metacubexd")),
+            std::path::PathBuf::from("/etc/clash/ui/metacubexd")
+        );
+        assert_eq!(
+            ui_dir("/etc/clash", "ui", None),
+            std::path::PathBuf::from("/etc/clash/ui")
+        );
+    }
+
+    #[test]
+    fn test_unpack_single_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("ui.zip");
+        make_zip(
+            &archive,
+            &[
+                ("dashboard-gh-pages/index.html", "<html></html>"),
+                ("dashboard-gh-pages/assets/app.js", "void 0"),
+            ],
+        );
+
+        let dir = tmp.path().join("ui");
+        assert!(need_download(&dir));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("stale.html"), "").unwrap();
+
+        unpack(&archive, &dir).unwrap();
+
+        assert!(!need_download(&dir));
+        assert!(dir.join("index.html").exists());
+        assert!(dir.join("assets/app.js").exists());
+        assert!(!dir.join("stale.html").exists());
+        assert!(!tmp.path().join("ui.unzip").exists());
+    }
+
+    #[test]
+    fn test_unpack_flat() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = tmp.path().join("ui.zip");
+        make_zip(
+            &archive,
+            &[("index.html", "<html></html>"), ("app.js", "void 0")],
+        );
+
+        let dir = tmp.path().join("ui");
+        unpack(&archive, &dir).unwrap();
+
+        assert!(dir.join("index.html").exists());
+        assert!(dir.join("app.js").exists());
+    }
+}
@@ -0,0 +1,439 @@
+//! The controller API over gRPC, see `controller.proto`.
+
+// tonic::Status is what the handlers have to return
+#![allow(clippy::result_large_err)]
+
+use std::{pin::Pin, sync::Arc, time::Duration};
+
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast::Sender, Mutex},
+};
+use tokio_stream::wrappers::{BroadcastStream, IntervalStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    app::{
+        dispatcher::{Dispatcher, StatisticsManager, TrackerInfo},
+        dns::ThreadSafeDNSResolver,
+        inbound::manager::ThreadSafeInboundManager,
+        logging::LogEvent,
+        outbound::manager::ThreadSafeOutboundManager,
+        profile::ThreadSafeCacheFile,
+    },
+    common::timing::ConnectPhase,
+    config::def::{LogLevel, RunMode},
+    GlobalState,
+};
+
+use super::handlers::config::allow_lan;
+
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("clash.controller");
+}
+
+use proto::{
+    controller_server::{Controller, ControllerServer},
+    CloseConnectionRequest, Configs, Connection, ConnectionList, DelayHistory,
+    Empty, LogEntry, PatchConfigsRequest, Proxy, ProxyDelay, ProxyDelayRequest,
+    ProxyList, ProxyRequest, SelectProxyRequest, Traffic, Version,
+};
+
+const DEFAULT_DELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct ControllerService {
+    pub log_source: Sender<LogEvent>,
+    pub inbound_manager: ThreadSafeInboundManager,
+    pub dispatcher: Arc<Dispatcher>,
+    pub global_state: Arc<Mutex<GlobalState>>,
+    pub dns_resolver: ThreadSafeDNSResolver,
+    pub outbound_manager: ThreadSafeOutboundManager,
+    pub statistics_manager: Arc<StatisticsManager>,
+    pub cache_store: ThreadSafeCacheFile,
+}
+
+/// Serve the controller on `listener`, requests need the secret as a bearer
+/// token when it's not empty.
+pub async fn serve(
+    listener: TcpListener,
+    svc: ControllerService,
+    secret: String,
+) -> std::io::Result<()> {
+    tonic::transport::Server::builder()
+        .add_service(ControllerServer::with_interceptor(svc, move |req| {
+            check_auth(&secret, req)
+        }))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+        .map_err(std::io::Error::other)
+}
+
+fn check_auth(secret: &str, req: Request<()>) -> Result<Request<()>, Status> {
+    if secret.is_empty() {
+        return Ok(req);
+    }
+    match req.metadata().get("authorization") {
+        Some(v) if v.to_str().ok() == Some(&format!("Bearer {}", secret)) => Ok(req),
+        _ => Err(Status::unauthenticated("unauthorized")),
+    }
+}
+
+/// the name serde gives `v`, matching what the REST API reports
+fn serde_name<T: Serialize>(v: &T) -> String {
+    match serde_json::to_value(v) {
+        Ok(Value::String(s)) => s,
+        _ => String::new(),
+    }
+}
+
+fn parse_enum<T: serde::de::DeserializeOwned>(
+    field: &str,
+    v: String,
+) -> Result<T, Status> {
+    serde_json::from_value(Value::String(v.clone()))
+        .map_err(|_| Status::invalid_argument(format!("invalid {}: {}", field, v)))
+}
+
+/// build the typed proxy from the map the REST API serves
+fn proxy_from_map(v: Value) -> Proxy {
+    let str_of = |k: &str| v[k].as_str().unwrap_or_default().to_owned();
+    Proxy {
+        name: str_of("name"),
+        r#type: str_of("type"),
+        alive: v["alive"].as_bool().unwrap_or_default(),
+        udp: v["udp"].as_bool().unwrap_or_default(),
+        history: v["history"]
+            .as_array()
+            .map(|h| {
+                h.iter()
+                    .map(|x| DelayHistory {
+                        time: x["time"]
+                            .as_str()
+                            .and_then(|t| {
+                                chrono::DateTime::parse_from_rfc3339(t).ok()
+                            })
+                            .map(|t| t.timestamp_millis())
+                            .unwrap_or_default(),
+                        delay: x["delay"].as_u64().unwrap_or_default() as u32,
+                        mean_delay: x["meanDelay"].as_u64().unwrap_or_default()
+                            as u32,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        now: v["now"].as_str().map(|x| x.to_owned()),
+        all: v["all"]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|x| x.as_str().map(|x| x.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+fn connection_from_tracker(t: &TrackerInfo) -> Connection {
+    use std::sync::atomic::Ordering::Relaxed;
+
+    let sess = &t.session_holder;
+    Connection {
+        id: t.uuid.to_string(),
+        network: sess.network.to_string(),
+        r#type: serde_name(&sess.typ),
+        source: sess.source.to_string(),
+        destination: sess.destination.to_string(),
+        host: sess.destination.host(),
+        chains: t.proxy_chain.clone(),
+        rule: t.rule.clone(),
+        rule_payload: t.rule_payload.clone(),
+        upload: t.upload_total.load(Relaxed),
+        download: t.download_total.load(Relaxed),
+        upload_speed: t.upload_speed.load(Relaxed),
+        download_speed: t.download_speed.load(Relaxed),
+        start: t.start_time.timestamp_millis(),
+        inbound_name: sess.inbound_name.clone(),
+        sniff_host: sess.sniff_host.clone().unwrap_or_default(),
+        inbound_user: sess.inbound_user.clone().unwrap_or_default(),
+        timings: t.timings.as_ref().map(|x| {
+            let ms = |x: Duration| x.as_secs_f64() * 1000.0;
+            proto::ConnectTimings {
+                rule_match: ms(x.phase(ConnectPhase::RuleMatch)),
+                dns: ms(x.phase(ConnectPhase::Dns)),
+                connect: ms(x.phase(ConnectPhase::Connect)),
+                handshake: ms(x.phase(ConnectPhase::Handshake)),
+                first_byte: x.first_byte().map(ms),
+            }
+        }),
+    }
+}
+
+type GrpcStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Controller for ControllerService {
+    type StreamLogsStream = GrpcStream<LogEntry>;
+    type StreamTrafficStream = GrpcStream<Traffic>;
+
+    async fn get_version(
+        &self,
+        _: Request<Empty>,
+    ) -> Result<Response<Version>, Status> {
+        Ok(Response::new(Version {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        }))
+    }
+
+    async fn get_configs(
+        &self,
+        _: Request<Empty>,
+    ) -> Result<Response<Configs>, Status> {
+        let inbound_manager = self.inbound_manager.lock().await;
+        let ports = inbound_manager.get_ports();
+        let bind_address = inbound_manager.get_bind_address();
+
+        Ok(Response::new(Configs {
+            mode: self.dispatcher.get_mode().await.to_string(),
+            log_level: self.global_state.lock().await.log_level.to_string(),
+            ipv6: self.dns_resolver.ipv6(),
+            allow_lan: allow_lan(bind_address),
+            bind_address: bind_address.to_string(),
+            port: ports.port.map(u32::from),
+            socks_port: ports.socks_port.map(u32::from),
+            redir_port: ports.redir_port.map(u32::from),
+            tproxy_port: ports.tproxy_port.map(u32::from),
+            mixed_port: ports.mixed_port.map(u32::from),
+        }))
+    }
+
+    async fn patch_configs(
+        &self,
+        req: Request<PatchConfigsRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let req = req.into_inner();
+        let mode = req
+            .mode
+            .map(|x| parse_enum::<RunMode>("mode", x))
+            .transpose()?;
+        let log_level = req
+            .log_level
+            .map(|x| parse_enum::<LogLevel>("log level", x))
+            .transpose()?;
+
+        if let Some(mode) = mode {
+            self.dispatcher.set_mode(mode).await;
+        }
+        if let Some(log_level) = log_level {
+            self.global_state.lock().await.log_level = log_level;
+        }
+        if let Some(ipv6) = req.ipv6 {
+            self.dns_resolver.set_ipv6(ipv6);
+        }
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn list_proxies(
+        &self,
+        _: Request<Empty>,
+    ) -> Result<Response<ProxyList>, Status> {
+        let proxies =
+            serde_json::to_value(self.outbound_manager.get_proxies().await)
+                .map_err(|x| Status::internal(x.to_string()))?;
+        let mut proxies: Vec<_> = match proxies {
+            Value::Object(m) => {
+                m.into_iter().map(|(_, v)| proxy_from_map(v)).collect()
+            }
+            _ => vec![],
+        };
+        proxies.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Response::new(ProxyList { proxies }))
+    }
+
+    async fn get_proxy(
+        &self,
+        req: Request<ProxyRequest>,
+    ) -> Result<Response<Proxy>, Status> {
+        let name = req.into_inner().name;
+        let proxy = self
+            .outbound_manager
+            .get_outbound(&name)
+            .ok_or_else(|| Status::not_found(format!("proxy {} not found", name)))?;
+        let m = serde_json::to_value(self.outbound_manager.get_proxy(&proxy).await)
+            .map_err(|x| Status::internal(x.to_string()))?;
+        Ok(Response::new(proxy_from_map(m)))
+    }
+
+    async fn select_proxy(
+        &self,
+        req: Request<SelectProxyRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let req = req.into_inner();
+        let ctrl = self
+            .outbound_manager
+            .get_selector_control(&req.group)
+            .ok_or_else(|| {
+                Status::not_found(format!("selector {} not found", req.group))
+            })?;
+        ctrl.lock().await.select(&req.name).await.map_err(|x| {
+            Status::invalid_argument(format!(
+                "select {} for {} failed with error: {}",
+                req.name, req.group, x
+            ))
+        })?;
+        self.cache_store.set_selected(&req.group, &req.name).await;
+        self.statistics_manager
+            .abort_switched_away(&req.group, &req.name)
+            .await;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn get_proxy_delay(
+        &self,
+        req: Request<ProxyDelayRequest>,
+    ) -> Result<Response<ProxyDelay>, Status> {
+        let req = req.into_inner();
+        let proxy =
+            self.outbound_manager
+                .get_outbound(&req.name)
+                .ok_or_else(|| {
+                    Status::not_found(format!("proxy {} not found", req.name))
+                })?;
+        let timeout = match req.timeout {
+            0 => DEFAULT_DELAY_TIMEOUT,
+            ms => Duration::from_millis(ms as u64),
+        };
+        let (delay, mean_delay) = self
+            .outbound_manager
+            .url_test(proxy, &req.url, timeout)
+            .await
+            .map_err(|x| Status::unavailable(x.to_string()))?;
+        Ok(Response::new(ProxyDelay {
+            delay: delay as u32,
+            mean_delay: mean_delay as u32,
+        }))
+    }
+
+    async fn list_connections(
+        &self,
+        _: Request<Empty>,
+    ) -> Result<Response<ConnectionList>, Status> {
+        let snapshot = self.statistics_manager.snapshot().await;
+        Ok(Response::new(ConnectionList {
+            upload_total: snapshot.upload_total(),
+            download_total: snapshot.download_total(),
+            connections: snapshot
+                .connections()
+                .iter()
+                .map(connection_from_tracker)
+                .collect(),
+        }))
+    }
+
+    async fn close_connection(
+        &self,
+        req: Request<CloseConnectionRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let id = req.into_inner().id;
+        let id = id
+            .parse()
+            .map_err(|_| Status::invalid_argument(format!("invalid id: {}", id)))?;
+        self.statistics_manager.close(id).await;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn close_all_connections(
+        &self,
+        _: Request<Empty>,
+    ) -> Result<Response<Empty>, Status> {
+        self.statistics_manager.close_all().await;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn stream_traffic(
+        &self,
+        _: Request<Empty>,
+    ) -> Result<Response<Self::StreamTrafficStream>, Status> {
+        let mgr = self.statistics_manager.clone();
+        let ticker = tokio::time::interval(Duration::from_secs(1));
+        let s = IntervalStream::new(ticker).map(move |_| {
+            let (up, down) = mgr.now();
+            Ok(Traffic { up, down })
+        });
+        Ok(Response::new(Box::pin(s)))
+    }
+
+    async fn stream_logs(
+        &self,
+        _: Request<Empty>,
+    ) -> Result<Response<Self::StreamLogsStream>, Status> {
+        let s = BroadcastStream::new(self.log_source.subscribe()).filter_map(
+            |evt| async move {
+                // lagging behind only loses some lines
+                evt.ok().map(|evt| {
+                    Ok(LogEntry {
+                        level: evt.level.to_string(),
+                        payload: evt.msg,
+                        connection_id: evt.connection_id.unwrap_or_default(),
+                    })
+                })
+            },
+        );
+        Ok(Response::new(Box::pin(s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tonic::{Code, Request};
+
+    use super::{check_auth, proxy_from_map};
+
+    #[test]
+    fn test_check_auth() {
+        assert!(check_auth("", Request::new(())).is_ok());
+
+        let err = check_auth("secret", Request::new(())).unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+
+        let mut req = Request::new(());
+        req.metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        assert!(check_auth("secret", req).is_ok());
+
+        let mut req = Request::new(());
+        req.metadata_mut()
+            .insert("authorization", "Bearer wrong".parse().unwrap());
+        assert!(check_auth("secret", req).is_err());
+    }
+
+    #[test]
+    fn test_proxy_from_map() {
+        let p = proxy_from_map(json!({
+            "name": "PROXY",
+            "type": "Selector",
+            "alive": true,
+            "udp": false,
+            "now": "ss01",
+            "all": ["ss01", "ss02"],
+            "history": [{
+                "time": "2024-07-01T00:00:00Z",
+                "delay": 120,
+                "meanDelay": 110,
+            }],
+        }));
+        assert_eq!(p.name, "PROXY");
+        assert_eq!(p.r#type, "Selector");
+        assert!(p.alive);
+        assert_eq!(p.now.as_deref(), Some("ss01"));
+        assert_eq!(p.all, vec!["ss01", "ss02"]);
+        assert_eq!(p.history.len(), 1);
+        assert_eq!(p.history[0].time, 1719792000000);
+        assert_eq!(p.history[0].delay, 120);
+        assert_eq!(p.history[0].mean_delay, 110);
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State, http::StatusCode, response::IntoResponse, routing::get, Json,
+    Router,
+};
+use tracing::info;
+
+use crate::{
+    app::{api::AppState, dispatcher::StatisticsManager},
+    config::def::Bandwidth,
+};
+
+#[derive(Clone)]
+struct BandwidthState {
+    statistics_manager: Arc<StatisticsManager>,
+}
+
+pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_bandwidth).put(update_bandwidth))
+        .with_state(BandwidthState { statistics_manager })
+}
+
+async fn get_bandwidth(State(state): State<BandwidthState>) -> impl IntoResponse {
+    Json(state.statistics_manager.shaper().config())
+}
+
+/// Replace the limits until the next reload, open connections included.
+/// Spliced connections stop splicing within a second to be limited.
+async fn update_bandwidth(
+    State(state): State<BandwidthState>,
+    Json(bandwidth): Json<Bandwidth>,
+) -> impl IntoResponse {
+    info!("bandwidth limits updated: {:?}", bandwidth);
+    state.statistics_manager.shaper().update(bandwidth);
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use hickory_proto::rr::RecordType;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::app::{api::AppState, dns::ThreadSafeDNSResolver};
+
+#[derive(Clone)]
+struct CacheState {
+    resolver: ThreadSafeDNSResolver,
+}
+
+pub fn routes(resolver: ThreadSafeDNSResolver) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/fakeip/flush", post(flush_fake_ip))
+        .route("/dns", get(list_dns_cache).delete(remove_dns_cache))
+        .route("/dns/stats", get(dns_cache_stats))
+        .with_state(CacheState { resolver })
+}
+
+/// Old fake IPs may point to domains whose rules have changed, so the DNS
+/// cache holding them is dropped along with the pool.
+async fn flush_fake_ip(State(state): State<CacheState>) -> impl IntoResponse {
+    state.resolver.flush_fake_ip().await;
+    state.resolver.flush_cache().await;
+    info!("fake ip pool and dns cache flushed");
+    StatusCode::NO_CONTENT
+}
+
+async fn list_dns_cache(State(state): State<CacheState>) -> impl IntoResponse {
+    let mut entries = state.resolver.cache_entries().await;
+    entries.sort_by(|a, b| a.name.cmp(&b.name).then(a.typ.cmp(&b.typ)));
+    Json(entries)
+}
+
+async fn dns_cache_stats(State(state): State<CacheState>) -> impl IntoResponse {
+    Json(state.resolver.cache_stats())
+}
+
+#[derive(Deserialize)]
+struct RemoveDnsCacheQuery {
+    name: String,
+    /// all types if not given
+    #[serde(rename = "type")]
+    typ: Option<String>,
+}
+
+async fn remove_dns_cache(
+    State(state): State<CacheState>,
+    Query(q): Query<RemoveDnsCacheQuery>,
+) -> impl IntoResponse {
+    let typ = match q.typ.map(|x| x.to_uppercase().parse::<RecordType>()) {
+        Some(Ok(typ)) => Some(typ),
+        Some(Err(_)) => {
+            return (StatusCode::BAD_REQUEST, "invalid type").into_response();
+        }
+        None => None,
+    };
+    let removed = state.resolver.remove_cache(&q.name, typ).await;
+    info!("{} dns cache entries removed for {}", removed, q.name);
+    StatusCode::NO_CONTENT.into_response()
+}
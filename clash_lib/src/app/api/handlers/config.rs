@@ -17,7 +17,7 @@ use crate::{
         api::AppState,
         dispatcher,
         dns::ThreadSafeDNSResolver,
-        inbound::manager::{Ports, ThreadSafeInboundManager},
+        inbound::manager::{stop_listeners, Ports, ThreadSafeInboundManager},
     },
     config::{def, internal::config::BindAddress},
     GlobalState,
@@ -69,16 +69,20 @@ async fn get_configs(State(state): State<ConfigState>) -> impl IntoResponse {
         mode: Some(run_mode),
         log_level: Some(global_state.log_level),
         ipv6: Some(dns_resolver.ipv6()),
-        allow_lan: Some(match inbound_manager.get_bind_address() {
-            BindAddress::Any => true,
-            BindAddress::One(one) => match one {
-                crate::proxy::utils::Interface::IpAddr(ip) => !ip.is_loopback(),
-                crate::proxy::utils::Interface::Name(iface) => iface != "lo",
-            },
-        }),
+        allow_lan: Some(allow_lan(inbound_manager.get_bind_address())),
     })
 }
 
+pub(crate) fn allow_lan(bind_address: &BindAddress) -> bool {
+    match bind_address {
+        BindAddress::Any => true,
+        BindAddress::One(one) => match one {
+            crate::proxy::utils::Interface::IpAddr(ip) => !ip.is_loopback(),
+            crate::proxy::utils::Interface::Name(iface) => iface != "lo",
+        },
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 struct UpdateConfigRequest {
@@ -96,23 +100,12 @@ async fn update_configs(
     State(state): State<ConfigState>,
     Json(req): Json<UpdateConfigRequest>,
 ) -> impl IntoResponse {
-    let (done, wait) = tokio::sync::oneshot::channel();
     let g = state.global_state.lock().await;
     match (req.path, req.payload) {
         (_, Some(payload)) => {
             let msg = "config reloading from payload".to_string();
             let cfg = crate::Config::Str(payload);
-            match g.reload_tx.send((cfg, done)).await {
-                Ok(_) => {
-                    wait.await.unwrap();
-                    (StatusCode::NO_CONTENT, msg).into_response()
-                }
-                Err(_) => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "could not signal config reload",
-                )
-                    .into_response(),
-            }
+            reload(&g, cfg, msg).await
         }
         (Some(mut path), None) => {
             if !PathBuf::from(&path).is_absolute() {
@@ -131,18 +124,7 @@ async fn update_configs(
 
             let msg = format!("config reloading from file {}", path);
             let cfg: crate::Config = crate::Config::File(path);
-            match g.reload_tx.send((cfg, done)).await {
-                Ok(_) => {
-                    wait.await.unwrap();
-                    (StatusCode::NO_CONTENT, msg).into_response()
-                }
-
-                Err(_) => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "could not signal config reload",
-                )
-                    .into_response(),
-            }
+            reload(&g, cfg, msg).await
         }
         (None, None) => {
             (StatusCode::BAD_REQUEST, "no path or payload provided").into_response()
@@ -150,6 +132,29 @@ async fn update_configs(
     }
 }
 
+/// the reload is rolled back and reported as a bad request if the new config
+/// can't be applied
+pub(super) async fn reload(
+    g: &GlobalState,
+    cfg: crate::Config,
+    msg: String,
+) -> axum::response::Response {
+    let (done, wait) = tokio::sync::oneshot::channel();
+    if g.reload_tx.send((cfg, done)).await.is_err() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not signal config reload",
+        )
+            .into_response();
+    }
+    match wait.await {
+        Ok(Ok(_)) => (StatusCode::NO_CONTENT, msg).into_response(),
+        Ok(Err(e)) => (StatusCode::BAD_REQUEST, e).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "config reload aborted")
+            .into_response(),
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 struct PatchConfigRequest {
@@ -174,6 +179,22 @@ impl PatchConfigRequest {
             || self.mixed_port.is_some()
             || self.bind_address.is_some()
     }
+
+    /// `current` with the ports patched, a port of 0 closes the listener
+    fn ports(&self, current: &Ports) -> Ports {
+        let port = |new: Option<u16>, current| match new {
+            Some(0) => None,
+            Some(x) => Some(x),
+            None => current,
+        };
+        Ports {
+            port: port(self.port, current.port),
+            socks_port: port(self.socks_port, current.socks_port),
+            redir_port: port(self.redir_port, current.redir_port),
+            tproxy_port: port(self.tproxy_port, current.tproxy_port),
+            mixed_port: port(self.mixed_port, current.mixed_port),
+        }
+    }
 }
 
 async fn patch_configs(
@@ -189,10 +210,15 @@ async fn patch_configs(
 
     let mut inbound_manager = state.inbound_manager.lock().await;
 
+    let mut bind = None;
     if let Some(bind_address) = payload.bind_address.clone() {
         match bind_address.parse::<BindAddress>() {
             Ok(bind_address) => {
-                inbound_manager.set_bind_address(bind_address);
+                if bind_address.to_string()
+                    != inbound_manager.get_bind_address().to_string()
+                {
+                    bind = Some(bind_address);
+                }
             }
             Err(_) => {
                 return (
@@ -207,26 +233,35 @@ async fn patch_configs(
     let mut global_state = state.global_state.lock().await;
 
     if payload.rebuild_listeners() {
-        // TODO: maybe buggy
         let current_ports = inbound_manager.get_ports();
+        let ports = payload.ports(&current_ports);
 
-        let ports = Ports {
-            port: payload.port.or(current_ports.port),
-            socks_port: payload.socks_port.or(current_ports.socks_port),
-            redir_port: payload.redir_port.or(current_ports.redir_port),
-            tproxy_port: payload.tproxy_port.or(current_ports.tproxy_port),
-            mixed_port: payload.mixed_port.or(current_ports.mixed_port),
-        };
-
-        inbound_manager.rebuild_listeners(ports);
+        // listeners are rebound live, the connections they accepted are
+        // kept. The current ones only stop once the new ones are built.
+        if bind.is_some() || ports != current_ports {
+            let current_bind = inbound_manager.get_bind_address().clone();
+            if let Some(bind) = bind {
+                inbound_manager.set_bind_address(bind);
+            }
+            inbound_manager.rebuild_listeners(ports);
 
-        if let Some(h) = global_state.inbound_listener_handle.take() {
-            h.abort()
+            match inbound_manager.get_runner() {
+                Ok(r) => {
+                    stop_listeners(global_state.inbound_listener_handle.take())
+                        .await;
+                    global_state.inbound_listener_handle = Some(tokio::spawn(r));
+                }
+                Err(e) => {
+                    inbound_manager.set_bind_address(current_bind);
+                    inbound_manager.rebuild_listeners(current_ports);
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("failed to start listeners: {}", e),
+                    )
+                        .into_response();
+                }
+            }
         }
-
-        let r = inbound_manager.get_runner().unwrap();
-
-        global_state.inbound_listener_handle = Some(tokio::spawn(r));
     }
 
     if let Some(mode) = payload.mode {
@@ -243,3 +278,40 @@ async fn patch_configs(
 
     StatusCode::ACCEPTED.into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::app::inbound::manager::Ports;
+
+    use super::PatchConfigRequest;
+
+    #[test]
+    fn test_patch_ports() {
+        let current = Ports {
+            port: Some(7890),
+            redir_port: Some(7892),
+            tproxy_port: Some(7893),
+            ..Default::default()
+        };
+        let patch = |json| {
+            serde_json::from_str::<PatchConfigRequest>(json)
+                .unwrap()
+                .ports(&current)
+        };
+
+        // the same ports again change nothing, redir and tproxy included
+        assert_eq!(
+            patch(r#"{"redir-port": 7892, "tproxy-port": 7893}"#),
+            current
+        );
+        assert_eq!(
+            patch(r#"{"mixed-port": 7891, "port": 0}"#),
+            Ports {
+                mixed_port: Some(7891),
+                redir_port: Some(7892),
+                tproxy_port: Some(7893),
+                ..Default::default()
+            }
+        );
+    }
+}
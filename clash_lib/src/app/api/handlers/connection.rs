@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{cmp::Ordering, net::IpAddr, path::PathBuf, sync::Arc};
 
 use axum::{
     body::Body,
@@ -6,33 +6,194 @@ use axum::{
         ws::Message, FromRequest, Path, Query, Request, State, WebSocketUpgrade,
     },
     response::IntoResponse,
-    routing::{delete, get},
+    routing::get,
     Json, Router,
 };
-use http::HeaderMap;
+use http::{header, HeaderMap, StatusCode};
 use serde::Deserialize;
 use tracing::{debug, warn};
 
 use crate::app::{
     api::{handlers::utils::is_request_websocket, AppState},
-    dispatcher::StatisticsManager,
+    dispatcher::{CaptureOptions, Snapshot, StatisticsManager, TrackerInfo},
 };
 
 #[derive(Clone)]
 struct ConnectionState {
     statistics_manager: Arc<StatisticsManager>,
+    /// where the captures are written
+    capture_dir: PathBuf,
 }
 
-pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+pub fn routes(
+    statistics_manager: Arc<StatisticsManager>,
+    capture_dir: PathBuf,
+) -> Router<Arc<AppState>> {
     Router::new()
         .route("/", get(get_connections).delete(close_all_connection))
-        .route("/:id", delete(close_connection))
-        .with_state(ConnectionState { statistics_manager })
+        .route("/aborted", get(get_aborted_connections))
+        .route("/:id", get(get_connection).delete(close_connection))
+        .route(
+            "/:id/capture",
+            get(get_capture).post(start_capture).delete(stop_capture),
+        )
+        .with_state(ConnectionState {
+            statistics_manager,
+            capture_dir,
+        })
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum SortKey {
+    Start,
+    Upload,
+    Download,
+    UploadSpeed,
+    DownloadSpeed,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
 }
 
 #[derive(Deserialize)]
 struct GetConnectionsQuery {
+    /// seconds between two snapshots on the websocket
     interval: Option<u64>,
+
+    /// part of the destination or sniffed host, case insensitive
+    host: Option<String>,
+    /// the inbound listener that accepted the connection
+    inbound: Option<String>,
+    /// the user the inbound authenticated
+    user: Option<String>,
+    /// a proxy or group the connection goes through
+    chain: Option<String>,
+    /// the rule type or payload the connection matched
+    rule: Option<String>,
+    #[serde(rename = "sourceIP")]
+    source_ip: Option<IpAddr>,
+
+    sort: Option<SortKey>,
+    #[serde(default)]
+    order: SortOrder,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+impl GetConnectionsQuery {
+    fn matches(&self, c: &TrackerInfo) -> bool {
+        let sess = &c.session_holder;
+        if let Some(host) = &self.host {
+            let host = host.to_ascii_lowercase();
+            let matches = |h: &str| h.to_ascii_lowercase().contains(host.as_str());
+            if !matches(&sess.destination.host())
+                && !sess.sniff_host.as_deref().is_some_and(matches)
+            {
+                return false;
+            }
+        }
+        if let Some(inbound) = &self.inbound {
+            if !sess.inbound_name.eq_ignore_ascii_case(inbound) {
+                return false;
+            }
+        }
+        if let Some(user) = &self.user {
+            if sess.inbound_user.as_ref() != Some(user) {
+                return false;
+            }
+        }
+        if let Some(chain) = &self.chain {
+            if !c.proxy_chain.iter().any(|x| x == chain) {
+                return false;
+            }
+        }
+        if let Some(rule) = &self.rule {
+            if !c.rule.eq_ignore_ascii_case(rule) && &c.rule_payload != rule {
+                return false;
+            }
+        }
+        if let Some(ip) = self.source_ip {
+            if sess.source.ip() != ip {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn paginated(&self) -> bool {
+        self.offset.is_some() || self.limit.is_some()
+    }
+
+    fn apply_to(&self, snapshot: &mut Snapshot) {
+        if let Some(total) = self.apply(snapshot.connections_mut()) {
+            snapshot.set_total(total);
+        }
+    }
+
+    /// Filter, sort and paginate the connections in place, returns the number
+    /// of matched connections when paginating.
+    /// Pages are only stable when sorted, so paginating sorts by start time
+    /// unless asked otherwise.
+    fn apply(&self, conns: &mut Vec<TrackerInfo>) -> Option<usize> {
+        conns.retain(|c| self.matches(c));
+        let total = conns.len();
+
+        let sort = match self.sort {
+            Some(key) => Some(key),
+            None if self.paginated() => Some(SortKey::Start),
+            None => None,
+        };
+        if let Some(key) = sort {
+            conns.sort_by(|a, b| {
+                let ord = compare(key, a, b);
+                if self.order == SortOrder::Desc {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            });
+        }
+
+        if self.paginated() {
+            let offset = self.offset.unwrap_or(0).min(conns.len());
+            conns.drain(..offset);
+            if let Some(limit) = self.limit {
+                conns.truncate(limit);
+            }
+            Some(total)
+        } else {
+            None
+        }
+    }
+}
+
+fn compare(key: SortKey, a: &TrackerInfo, b: &TrackerInfo) -> Ordering {
+    use std::sync::atomic::Ordering::Relaxed;
+    match key {
+        SortKey::Start => a.start_time.cmp(&b.start_time),
+        SortKey::Upload => a
+            .upload_total
+            .load(Relaxed)
+            .cmp(&b.upload_total.load(Relaxed)),
+        SortKey::Download => a
+            .download_total
+            .load(Relaxed)
+            .cmp(&b.download_total.load(Relaxed)),
+        SortKey::UploadSpeed => a
+            .upload_speed
+            .load(Relaxed)
+            .cmp(&b.upload_speed.load(Relaxed)),
+        SortKey::DownloadSpeed => a
+            .download_speed
+            .load(Relaxed)
+            .cmp(&b.download_speed.load(Relaxed)),
+    }
 }
 
 async fn get_connections(
@@ -43,7 +204,8 @@ async fn get_connections(
 ) -> impl IntoResponse {
     if !is_request_websocket(headers) {
         let mgr = state.statistics_manager.clone();
-        let snapshot = mgr.snapshot().await;
+        let mut snapshot = mgr.snapshot().await;
+        q.apply_to(&mut snapshot);
         return Json(snapshot).into_response();
     }
 
@@ -59,12 +221,16 @@ async fn get_connections(
         warn!("ws upgrade error: {}", e);
     })
     .on_upgrade(move |mut socket| async move {
-        let interval = q.interval;
-
+        let mut ticker = tokio::time::interval(tokio::time::Duration::from_secs(
+            q.interval.unwrap_or(1).max(1),
+        ));
         let mgr = state.statistics_manager.clone();
 
         loop {
-            let snapshot = mgr.snapshot().await;
+            ticker.tick().await;
+
+            let mut snapshot = mgr.snapshot().await;
+            q.apply_to(&mut snapshot);
             let j = serde_json::to_vec(&snapshot).unwrap();
             let body = String::from_utf8(j).unwrap();
 
@@ -73,15 +239,32 @@ async fn get_connections(
                 debug!("ws send error: {}", e);
                 break;
             }
-
-            tokio::time::sleep(tokio::time::Duration::from_secs(
-                interval.unwrap_or(1),
-            ))
-            .await;
         }
     })
 }
 
+/// the connections closed because their proxy was removed or switched away
+/// from, with the reason
+async fn get_aborted_connections(
+    State(state): State<ConnectionState>,
+) -> impl IntoResponse {
+    Json(state.statistics_manager.aborted().await)
+}
+
+async fn get_connection(
+    State(state): State<ConnectionState>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    match state.statistics_manager.get(id).await {
+        Some(c) => Json(c).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("connection {} not found", id),
+        )
+            .into_response(),
+    }
+}
+
 async fn close_connection(
     State(state): State<ConnectionState>,
     Path(id): Path<uuid::Uuid>,
@@ -91,6 +274,78 @@ async fn close_connection(
     format!("connection {} closed", id).into_response()
 }
 
+/// Capture what the connection relays from now on, e.g.
+/// `POST /connections/:id/capture?maxBytes=1048576&duration=30&metadataOnly=true`.
+/// Connections already relayed by the kernel on Linux can't be captured,
+/// that's a 409. Only the newest captures are kept.
+async fn start_capture(
+    State(state): State<ConnectionState>,
+    Path(id): Path<uuid::Uuid>,
+    Query(options): Query<CaptureOptions>,
+) -> impl IntoResponse {
+    match state
+        .statistics_manager
+        .start_capture(id, &state.capture_dir, options)
+        .await
+    {
+        Ok(path) => Json(serde_json::json!({ "path": path })).into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (
+            StatusCode::NOT_FOUND,
+            format!("connection {} not found", id),
+        )
+            .into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => (
+            StatusCode::CONFLICT,
+            format!("connection {} is relayed by the kernel", id),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to capture {}: {}", id, e),
+        )
+            .into_response(),
+    }
+}
+
+async fn stop_capture(
+    State(state): State<ConnectionState>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    if state.statistics_manager.stop_capture(id).await {
+        format!("capture of {} stopped", id).into_response()
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("connection {} is not captured", id),
+        )
+            .into_response()
+    }
+}
+
+/// the pcapng file of the capture, also once the connection is closed
+async fn get_capture(
+    State(state): State<ConnectionState>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let path = state.capture_dir.join(format!("{}.pcapng", id));
+    match tokio::fs::read(&path).await {
+        Ok(content) => (
+            [
+                (header::CONTENT_TYPE, "application/x-pcapng".to_owned()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}.pcapng\"", id),
+                ),
+            ],
+            content,
+        )
+            .into_response(),
+        Err(_) => {
+            (StatusCode::NOT_FOUND, format!("no capture of {}", id)).into_response()
+        }
+    }
+}
+
 async fn close_all_connection(
     State(state): State<ConnectionState>,
 ) -> impl IntoResponse {
@@ -98,3 +353,97 @@ async fn close_all_connection(
     mgr.close_all().await;
     "all connections closed".into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU64;
+
+    use axum::extract::Query;
+
+    use crate::{app::dispatcher::TrackerInfo, session::Session};
+
+    use super::GetConnectionsQuery;
+
+    fn conn(host: &str, chain: &[&str], rule: &str, upload: u64) -> TrackerInfo {
+        TrackerInfo {
+            upload_total: AtomicU64::new(upload),
+            proxy_chain: chain.iter().map(|x| x.to_string()).collect(),
+            rule: rule.to_owned(),
+            session_holder: Session {
+                destination: (host.to_owned(), 443).try_into().unwrap(),
+                source: "192.168.1.2:50000".parse().unwrap(),
+                inbound_name: "Mixed".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn conns() -> Vec<TrackerInfo> {
+        vec![
+            conn("www.google.com", &["ss01", "PROXY"], "DomainSuffix", 10),
+            conn("github.com", &["ss02", "PROXY"], "DomainSuffix", 30),
+            conn("example.com", &["DIRECT"], "Match", 20),
+        ]
+    }
+
+    fn parse(q: &str) -> Option<GetConnectionsQuery> {
+        let uri = format!("/connections?{}", q).parse().unwrap();
+        Query::try_from_uri(&uri).ok().map(|x| x.0)
+    }
+
+    fn query(q: &str) -> GetConnectionsQuery {
+        parse(q).unwrap()
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut c = conns();
+        assert_eq!(query("host=GOOGLE").apply(&mut c), None);
+        assert_eq!(c.len(), 1);
+
+        let mut c = conns();
+        query("chain=PROXY&rule=domainsuffix").apply(&mut c);
+        assert_eq!(c.len(), 2);
+
+        let mut c = conns();
+        query("sourceIP=192.168.1.3").apply(&mut c);
+        assert!(c.is_empty());
+
+        let mut c = conns();
+        c[2].session_holder.sniff_host = Some("cdn.example.org".to_owned());
+        query("host=example.org&inbound=mixed").apply(&mut c);
+        assert_eq!(c.len(), 1);
+
+        let mut c = conns();
+        query("inbound=TUN").apply(&mut c);
+        assert!(c.is_empty());
+
+        let mut c = conns();
+        c[1].session_holder.inbound_user = Some("alice".to_owned());
+        query("user=alice").apply(&mut c);
+        assert_eq!(c.len(), 1);
+        assert_eq!(c[0].session_holder.destination.host(), "github.com");
+    }
+
+    #[test]
+    fn test_sort_paginate() {
+        let mut c = conns();
+        assert_eq!(query("sort=upload&limit=2").apply(&mut c), Some(3));
+        let hosts: Vec<_> = c
+            .iter()
+            .map(|x| x.session_holder.destination.host())
+            .collect();
+        assert_eq!(hosts, vec!["github.com", "example.com"]);
+
+        let mut c = conns();
+        assert_eq!(
+            query("sort=upload&order=asc&offset=2&limit=5").apply(&mut c),
+            Some(3)
+        );
+        assert_eq!(c.len(), 1);
+        assert_eq!(c[0].session_holder.destination.host(), "github.com");
+
+        assert!(parse("sort=foo").is_none());
+    }
+}
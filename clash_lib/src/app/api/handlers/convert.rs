@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use axum::{extract::Query, response::IntoResponse, Json};
+use http::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::app::remote_content_manager::providers::proxy_provider::subscription::{
+    self, Proxy,
+};
+
+#[derive(Deserialize)]
+pub struct ConvertQuery {
+    /// `yaml` for a `proxies:` section to paste in a config, JSON otherwise
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Converted {
+    proxies: Vec<Proxy>,
+    errors: Vec<String>,
+}
+
+/// Convert share links to clash-format proxies. The body is a JSON list of
+/// links or a subscription, links a line or base64 encoded.
+pub async fn handle(
+    Query(q): Query<ConvertQuery>,
+    body: String,
+) -> impl IntoResponse {
+    let links = match serde_json::from_str::<Vec<String>>(&body) {
+        Ok(links) => Some(links).filter(|x| !x.is_empty()),
+        Err(_) => subscription::share_links(body.as_bytes()),
+    };
+    let Some(links) = links else {
+        return (StatusCode::BAD_REQUEST, "no share links found").into_response();
+    };
+
+    let converted = subscription::convert(&links);
+    if q.format.as_deref() == Some("yaml") {
+        let proxies = HashMap::from([("proxies", converted.proxies)]);
+        return match serde_yaml::to_string(&proxies) {
+            Ok(yaml) => {
+                ([(header::CONTENT_TYPE, "text/yaml")], yaml).into_response()
+            }
+            Err(e) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+            }
+        };
+    }
+    Json(Converted {
+        proxies: converted.proxies,
+        errors: converted.errors,
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::Query, response::IntoResponse};
+    use http::StatusCode;
+
+    use super::ConvertQuery;
+
+    #[tokio::test]
+    async fn test_convert() {
+        let body = r#"["trojan://pw@example.com:443#a", "ftp://example.com"]"#;
+        let res = super::handle(Query(ConvertQuery { format: None }), body.into())
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["proxies"][0]["name"], "a");
+        assert_eq!(v["proxies"][0]["type"], "trojan");
+        assert_eq!(v["errors"].as_array().unwrap().len(), 1);
+
+        let res = super::handle(Query(ConvertQuery { format: None }), "x".into())
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
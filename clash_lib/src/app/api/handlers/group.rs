@@ -0,0 +1,142 @@
+use std::{collections::HashMap, future::Future, io, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use futures::StreamExt;
+use http::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    app::{api::AppState, outbound::manager::ThreadSafeOutboundManager},
+    proxy::AnyOutboundHandler,
+};
+
+/// members tested at the same time, so a large group doesn't open hundreds of
+/// connections at once
+const MAX_CONCURRENT_TESTS: usize = 16;
+
+#[derive(Clone)]
+struct GroupState {
+    outbound_manager: ThreadSafeOutboundManager,
+}
+
+pub fn routes(outbound_manager: ThreadSafeOutboundManager) -> Router<Arc<AppState>> {
+    let state = GroupState { outbound_manager };
+    Router::new()
+        .route("/:name/delay", get(get_group_delay))
+        .with_state(state)
+}
+
+#[derive(Deserialize)]
+struct DelayRequest {
+    url: String,
+    timeout: u16,
+}
+
+/// test all members of the group, up to `MAX_CONCURRENT_TESTS` at a time
+/// members failed the test are reported with a delay of 0
+async fn get_group_delay(
+    State(state): State<GroupState>,
+    Path(name): Path<String>,
+    Query(q): Query<DelayRequest>,
+) -> impl IntoResponse {
+    let outbound_manager = state.outbound_manager.clone();
+    let Some(group) = outbound_manager.get_outbound(&name) else {
+        return (StatusCode::NOT_FOUND, format!("group {} not found", name))
+            .into_response();
+    };
+    let Some(members) = group.members().await else {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("proxy {} is not a group", name),
+        )
+            .into_response();
+    };
+
+    let timeout = Duration::from_millis(q.timeout.into());
+    let results = test_all(members, |proxy| {
+        let outbound_manager = outbound_manager.clone();
+        let url = q.url.clone();
+        async move { outbound_manager.url_test(proxy, &url, timeout).await }
+    })
+    .await;
+
+    Json(results).into_response()
+}
+
+async fn test_all<F, Fut>(
+    members: Vec<AnyOutboundHandler>,
+    test: F,
+) -> HashMap<String, u16>
+where
+    F: Fn(AnyOutboundHandler) -> Fut,
+    Fut: Future<Output = io::Result<(u16, u16)>>,
+{
+    futures::stream::iter(members)
+        .map(|proxy| {
+            let name = proxy.name().to_owned();
+            let fut = test(proxy);
+            async move {
+                let delay = fut.await.map(|(delay, _)| delay).unwrap_or_default();
+                (name, delay)
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_TESTS)
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::proxy::{mocks::MockDummyOutboundHandler, AnyOutboundHandler};
+
+    use super::{test_all, MAX_CONCURRENT_TESTS};
+
+    fn proxy(name: String) -> AnyOutboundHandler {
+        let mut proxy = MockDummyOutboundHandler::new();
+        proxy.expect_name().return_const(name);
+        Arc::new(proxy)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_all_bounded() {
+        let members = (0..50).map(|i| proxy(format!("p{}", i))).collect();
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let results = test_all(members, |proxy| {
+            let running = running.clone();
+            let peak = peak.clone();
+            async move {
+                let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(n, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                if proxy.name() == "p7" {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "timeout"))
+                } else {
+                    Ok((100, 100))
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(peak.load(Ordering::SeqCst), MAX_CONCURRENT_TESTS);
+        assert_eq!(results.len(), 50);
+        assert_eq!(results["p0"], 100);
+        assert_eq!(results["p7"], 0);
+    }
+}
@@ -10,7 +10,12 @@ use http::HeaderMap;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 
-use crate::app::api::AppState;
+use memory_stats::memory_stats;
+
+use crate::{
+    app::{api::AppState, dispatcher::StatisticsManager},
+    common::buffer_pool::{self, PoolStats},
+};
 
 use super::utils::is_request_websocket;
 
@@ -23,6 +28,69 @@ pub struct GetMemoryQuery {
 struct GetMemoryResponse {
     inuse: usize,
     oslimit: usize,
+    /// virtual memory size of the process
+    #[serde(rename = "virtual")]
+    virtual_mem: usize,
+    /// alive tokio tasks, the goroutine count equivalent
+    tasks: usize,
+    /// tokio worker threads
+    workers: usize,
+    /// tracked connections
+    connections: usize,
+    /// open file descriptors, only available on linux and macos
+    fds: Option<usize>,
+    /// open sockets, only available on linux
+    sockets: Option<usize>,
+    /// the pool of relay buffers
+    buffers: PoolStats,
+}
+
+impl GetMemoryResponse {
+    async fn collect(mgr: &StatisticsManager) -> Self {
+        let stats = memory_stats();
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let (fds, sockets) = open_fds();
+        Self {
+            inuse: stats.map(|x| x.physical_mem).unwrap_or_default(),
+            oslimit: 0,
+            virtual_mem: stats.map(|x| x.virtual_mem).unwrap_or_default(),
+            tasks: metrics.num_alive_tasks(),
+            workers: metrics.num_workers(),
+            connections: mgr.connection_count().await,
+            fds,
+            sockets,
+            buffers: buffer_pool::stats(),
+        }
+    }
+}
+
+/// Returns the number of open fds and sockets of the current process
+#[cfg(target_os = "linux")]
+fn open_fds() -> (Option<usize>, Option<usize>) {
+    let Ok(dir) = std::fs::read_dir("/proc/self/fd") else {
+        return (None, None);
+    };
+    let (mut fds, mut sockets) = (0, 0);
+    for entry in dir.flatten() {
+        fds += 1;
+        if std::fs::read_link(entry.path())
+            .map(|x| x.to_string_lossy().starts_with("socket:"))
+            .unwrap_or_default()
+        {
+            sockets += 1;
+        }
+    }
+    (Some(fds), Some(sockets))
+}
+
+#[cfg(target_os = "macos")]
+fn open_fds() -> (Option<usize>, Option<usize>) {
+    (std::fs::read_dir("/dev/fd").map(|x| x.count()).ok(), None)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn open_fds() -> (Option<usize>, Option<usize>) {
+    (None, None)
 }
 pub async fn handle(
     headers: HeaderMap,
@@ -31,11 +99,7 @@ pub async fn handle(
     req: Request<Body>,
 ) -> impl IntoResponse {
     if !is_request_websocket(headers) {
-        let mgr = state.statistics_manager.clone();
-        let snapshot = GetMemoryResponse {
-            inuse: mgr.memory_usage(),
-            oslimit: 0,
-        };
+        let snapshot = GetMemoryResponse::collect(&state.statistics_manager).await;
         return Json(snapshot).into_response();
     }
 
@@ -56,10 +120,7 @@ pub async fn handle(
         let mgr = state.statistics_manager.clone();
 
         loop {
-            let snapshot = GetMemoryResponse {
-                inuse: mgr.memory_usage(),
-                oslimit: 0,
-            };
+            let snapshot = GetMemoryResponse::collect(&mgr).await;
             let j = serde_json::to_vec(&snapshot).unwrap();
             let body = String::from_utf8(j).unwrap();
 
@@ -75,3 +136,17 @@ pub async fn handle(
         }
     })
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::open_fds;
+
+    #[test]
+    fn test_open_fds() {
+        let _s = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (fds, sockets) = open_fds();
+
+        assert!(sockets.unwrap() >= 1);
+        assert!(fds.unwrap() >= sockets.unwrap());
+    }
+}
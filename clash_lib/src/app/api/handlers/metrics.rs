@@ -0,0 +1,14 @@
+use std::sync::Arc;
+
+use axum::{extract::State, response::IntoResponse};
+use http::header;
+
+use crate::app::{api::AppState, metrics};
+
+pub async fn handle(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let active = state.statistics_manager.connection_count().await;
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::gather(active),
+    )
+}
@@ -1,14 +1,23 @@
+pub mod bandwidth;
+pub mod cache;
 pub mod config;
 pub mod connection;
+pub mod convert;
 pub mod dns;
+pub mod group;
 pub mod hello;
 pub mod log;
 pub mod memory;
+pub mod metrics;
+pub mod pac;
+pub mod profiles;
 pub mod provider;
 pub mod proxy;
 pub mod restart;
 pub mod rule;
+pub mod statistics;
 pub mod traffic;
+pub mod upgrade;
 pub mod version;
 
 mod utils;
@@ -0,0 +1,189 @@
+use axum::{
+    extract::State, http::HeaderMap, response::IntoResponse, routing::get, Router,
+};
+use http::header;
+
+use crate::{
+    app::{
+        inbound::manager::{Ports, ThreadSafeInboundManager},
+        router::{RuleMatcher, ThreadSafeRouter},
+    },
+    config::{def::Pac, internal::proxy::PROXY_DIRECT},
+};
+
+#[derive(Clone)]
+struct PacState {
+    cfg: Pac,
+    inbound_manager: ThreadSafeInboundManager,
+    router: ThreadSafeRouter,
+}
+
+/// Serves the PAC file at the configured path, outside of the secret.
+pub fn routes(
+    cfg: Pac,
+    inbound_manager: ThreadSafeInboundManager,
+    router: ThreadSafeRouter,
+) -> Router {
+    Router::new()
+        .route(&cfg.path.clone(), get(handle))
+        .with_state(PacState {
+            cfg,
+            inbound_manager,
+            router,
+        })
+}
+
+async fn handle(
+    State(state): State<PacState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let host = match &state.cfg.proxy_host {
+        Some(host) => host.clone(),
+        None => headers
+            .get(header::HOST)
+            .and_then(|x| x.to_str().ok())
+            .map(host_of)
+            .unwrap_or("127.0.0.1")
+            .to_owned(),
+    };
+    let ports = state.inbound_manager.lock().await.get_ports();
+    let rules: &[Box<dyn RuleMatcher>] = if state.cfg.direct_rules {
+        state.router.get_all_rules().as_slice()
+    } else {
+        &[]
+    };
+
+    (
+        [(header::CONTENT_TYPE, "application/x-ns-proxy-autoconfig")],
+        generate(&ports, &host, rules),
+    )
+}
+
+/// the host of a `Host` header, without the port
+fn host_of(header: &str) -> &str {
+    if header.starts_with('[') {
+        match header.find(']') {
+            Some(end) => &header[..=end],
+            None => header,
+        }
+    } else {
+        header.split(':').next().unwrap_or(header)
+    }
+}
+
+/// A PAC file sending everything to the inbound ports on `host`, but for the
+/// domain rules leading `rules`, which the browser checks itself.
+fn generate(ports: &Ports, host: &str, rules: &[Box<dyn RuleMatcher>]) -> String {
+    let mut proxies = vec![];
+    if let Some(port) = ports.mixed_port {
+        proxies.push(format!("PROXY {}:{}", host, port));
+        proxies.push(format!("SOCKS5 {}:{}", host, port));
+    }
+    if let Some(port) = ports.port {
+        proxies.push(format!("PROXY {}:{}", host, port));
+    }
+    if let Some(port) = ports.socks_port {
+        proxies.push(format!("SOCKS5 {}:{}", host, port));
+    }
+    if proxies.is_empty() {
+        proxies.push("DIRECT".to_owned());
+    }
+
+    let mut out = String::from("function FindProxyForURL(url, host) {\n");
+    out.push_str(&format!(
+        "  var proxy = {};\n",
+        serde_json::to_string(&proxies.join("; ")).unwrap()
+    ));
+    // any other rule may need more than the host, the proxy decides from there
+    for rule in rules {
+        let payload = rule.payload().to_ascii_lowercase();
+        let cond = match rule.type_name() {
+            "Domain" => format!("host == {}", quoted(&payload)),
+            "DomainSuffix" => format!(
+                "host == {} || dnsDomainIs(host, {})",
+                quoted(&payload),
+                quoted(&format!(".{}", payload))
+            ),
+            "DomainKeyword" => {
+                format!("host.indexOf({}) >= 0", quoted(&payload))
+            }
+            _ => break,
+        };
+        let to = if rule.target() == PROXY_DIRECT {
+            "\"DIRECT\""
+        } else {
+            "proxy"
+        };
+        out.push_str(&format!("  if ({}) return {};\n", cond, to));
+    }
+    out.push_str("  return proxy;\n}\n");
+    out
+}
+
+fn quoted(s: &str) -> String {
+    serde_json::to_string(s).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::{
+        inbound::manager::Ports,
+        router::rules::{domain_suffix::DomainSuffix, ipcidr::IpCidr, RuleMatcher},
+    };
+
+    use super::{generate, host_of};
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!(host_of("192.168.1.2:9090"), "192.168.1.2");
+        assert_eq!(host_of("clash.lan"), "clash.lan");
+        assert_eq!(host_of("[fd00::1]:9090"), "[fd00::1]");
+    }
+
+    #[test]
+    fn test_generate() {
+        let ports = Ports {
+            port: None,
+            socks_port: Some(7891),
+            redir_port: None,
+            tproxy_port: None,
+            mixed_port: Some(7890),
+        };
+        let rules: Vec<Box<dyn RuleMatcher>> = vec![
+            Box::new(DomainSuffix {
+                suffix: "lan".to_owned(),
+                target: "DIRECT".to_owned(),
+            }),
+            Box::new(DomainSuffix {
+                suffix: "google.com".to_owned(),
+                target: "PROXY".to_owned(),
+            }),
+            Box::new(IpCidr {
+                ipnet: "10.0.0.0/8".parse().unwrap(),
+                target: "DIRECT".to_owned(),
+                no_resolve: true,
+                match_src: false,
+            }),
+            Box::new(DomainSuffix {
+                suffix: "example.com".to_owned(),
+                target: "DIRECT".to_owned(),
+            }),
+        ];
+        let pac = generate(&ports, "192.168.1.2", &rules);
+        assert!(pac.contains(
+            "var proxy = \"PROXY 192.168.1.2:7890; SOCKS5 192.168.1.2:7890; \
+             SOCKS5 192.168.1.2:7891\";"
+        ));
+        assert!(pac.contains(
+            "if (host == \"lan\" || dnsDomainIs(host, \".lan\")) return \
+             \"DIRECT\";"
+        ));
+        assert!(pac.contains("dnsDomainIs(host, \".google.com\")) return proxy;"));
+        // after a rule the browser can't check
+        assert!(!pac.contains("example.com"));
+        assert!(pac.ends_with("  return proxy;\n}\n"));
+
+        let pac = generate(&ports, "127.0.0.1", &[]);
+        assert_eq!(pac.lines().count(), 4);
+    }
+}
@@ -0,0 +1,72 @@
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::{get, put},
+    Json, Router,
+};
+use http::StatusCode;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    app::{api::AppState, profiles},
+    GlobalState,
+};
+
+#[derive(Clone)]
+struct ProfilesState {
+    global_state: Arc<Mutex<GlobalState>>,
+}
+
+pub fn routes(global_state: Arc<Mutex<GlobalState>>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_profiles))
+        .route("/:name", put(switch_profile))
+        .with_state(ProfilesState { global_state })
+}
+
+#[derive(Serialize)]
+struct ProfilesResponse {
+    /// the profile running, if the config was loaded from one
+    active: Option<String>,
+    profiles: Vec<String>,
+}
+
+async fn get_profiles(State(state): State<ProfilesState>) -> impl IntoResponse {
+    let g = state.global_state.lock().await;
+    let cwd = PathBuf::from(&g.cwd);
+    let profiles = match profiles::list(&cwd) {
+        Ok(x) => x,
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+                .into_response()
+        }
+    };
+    let active = g
+        .config_path
+        .read()
+        .await
+        .as_ref()
+        .and_then(|x| profiles::name_of(&cwd, x.as_ref()));
+
+    Json(ProfilesResponse { active, profiles }).into_response()
+}
+
+/// reload from the profile's file, the running config stays if it can't be
+/// applied
+async fn switch_profile(
+    State(state): State<ProfilesState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let g = state.global_state.lock().await;
+    let path = match profiles::path(g.cwd.as_ref(), &name) {
+        Ok(x) => x,
+        Err(e) => return (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+    };
+
+    let msg = format!("switching to profile {}", name);
+    let cfg = crate::Config::File(path.to_string_lossy().to_string());
+    super::config::reload(&g, cfg, msg).await
+}
@@ -14,8 +14,8 @@ use serde::Deserialize;
 
 use crate::{
     app::{
-        api::AppState, outbound::manager::ThreadSafeOutboundManager,
-        profile::ThreadSafeCacheFile,
+        api::AppState, dispatcher::StatisticsManager,
+        outbound::manager::ThreadSafeOutboundManager, profile::ThreadSafeCacheFile,
     },
     proxy::AnyOutboundHandler,
 };
@@ -24,15 +24,18 @@ use crate::{
 pub struct ProxyState {
     outbound_manager: ThreadSafeOutboundManager,
     cache_store: ThreadSafeCacheFile,
+    statistics_manager: Arc<StatisticsManager>,
 }
 
 pub fn routes(
     outbound_manager: ThreadSafeOutboundManager,
     cache_store: ThreadSafeCacheFile,
+    statistics_manager: Arc<StatisticsManager>,
 ) -> Router<Arc<AppState>> {
     let state = ProxyState {
         outbound_manager,
         cache_store,
+        statistics_manager,
     };
     Router::new()
         .route("/", get(get_proxies))
@@ -41,6 +44,7 @@ pub fn routes(
             Router::new()
                 .route("/", get(get_proxy).put(update_proxy))
                 .route("/delay", get(get_proxy_delay))
+                .route("/history", get(get_proxy_history))
                 .route_layer(middleware::from_fn_with_state(
                     state.clone(),
                     find_proxy_by_name,
@@ -98,6 +102,10 @@ async fn update_proxy(
             Ok(_) => {
                 let cache_store = state.cache_store;
                 cache_store.set_selected(proxy.name(), &payload.name).await;
+                state
+                    .statistics_manager
+                    .abort_switched_away(proxy.name(), &payload.name)
+                    .await;
                 (
                     StatusCode::ACCEPTED,
                     format!("selected proxy {} for {}", payload.name, proxy.name()),
@@ -151,3 +159,23 @@ async fn get_proxy_delay(
             .into_response(),
     }
 }
+
+#[derive(Deserialize)]
+struct HistoryRequest {
+    /// only return the most recent `limit` records
+    limit: Option<usize>,
+}
+
+async fn get_proxy_history(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+    Query(q): Query<HistoryRequest>,
+) -> impl IntoResponse {
+    let outbound_manager = state.outbound_manager.clone();
+    let history = outbound_manager
+        .delay_history(&proxy, q.limit.unwrap_or(usize::MAX))
+        .await;
+    let mut r = HashMap::new();
+    r.insert("history".to_owned(), history);
+    axum::response::Json(r)
+}
@@ -1,49 +1,48 @@
 #[cfg(unix)]
 use std::os::unix::process::CommandExt;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use axum::{response::IntoResponse, Json};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
 use serde_json::Map;
+use tokio::sync::Mutex;
+use tracing::info;
 
-pub async fn handle() -> impl IntoResponse {
+use crate::{
+    app::{api::AppState, dispatcher::StatisticsManager},
+    GlobalState,
+};
+
+/// how long to wait for connections to finish before restarting
+pub(crate) const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+pub struct RestartQuery {
+    /// drain timeout in seconds
+    drain: Option<u64>,
+}
+
+pub async fn handle(
+    State(state): State<Arc<AppState>>,
+    Query(q): Query<RestartQuery>,
+) -> impl IntoResponse {
     match std::env::current_exe() {
         Ok(exec) => {
+            schedule_restart(
+                exec,
+                state.global_state.clone(),
+                state.statistics_manager.clone(),
+                q.drain
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_DRAIN_TIMEOUT),
+            );
+
             let mut map = Map::new();
             map.insert("status".to_owned(), "ok".into());
-            tokio::spawn(async move {
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-
-                #[cfg(unix)]
-                {
-                    use tracing::info;
-
-                    let err = std::process::Command::new(exec)
-                        .args(std::env::args().skip(1))
-                        .envs(std::env::vars())
-                        .exec();
-                    info!("process restarted: {}", err);
-                }
-                #[cfg(windows)]
-                {
-                    use tracing::error;
-
-                    match std::process::Command::new(exec)
-                        .args(std::env::args().skip(1))
-                        .envs(std::env::vars())
-                        .stdin(std::process::Stdio::inherit())
-                        .stdout(std::process::Stdio::inherit())
-                        .stderr(std::process::Stdio::inherit())
-                        .spawn()
-                    {
-                        Ok(_) => {
-                            // exit the current process
-                            std::process::exit(0);
-                        }
-                        Err(e) => {
-                            error!("Failed to restart: {}", e);
-                        }
-                    }
-                }
-            });
             Json(map).into_response()
         }
         Err(e) => {
@@ -51,3 +50,80 @@ pub async fn handle() -> impl IntoResponse {
         }
     }
 }
+
+/// Restart the process with the same args once the connections are drained.
+/// Inbound listeners are stopped right away so no new connections are
+/// accepted, connections still alive after `drain_timeout` are closed.
+pub(crate) fn schedule_restart(
+    exec: PathBuf,
+    global_state: Arc<Mutex<GlobalState>>,
+    statistics_manager: Arc<StatisticsManager>,
+    drain_timeout: Duration,
+) {
+    tokio::spawn(async move {
+        // give the response a chance to be sent
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        drain_connections(global_state, statistics_manager, drain_timeout).await;
+
+        #[cfg(unix)]
+        {
+            let err = std::process::Command::new(exec)
+                .args(std::env::args().skip(1))
+                .envs(std::env::vars())
+                .exec();
+            info!("process restarted: {}", err);
+        }
+        #[cfg(windows)]
+        {
+            use tracing::error;
+
+            match std::process::Command::new(exec)
+                .args(std::env::args().skip(1))
+                .envs(std::env::vars())
+                .stdin(std::process::Stdio::inherit())
+                .stdout(std::process::Stdio::inherit())
+                .stderr(std::process::Stdio::inherit())
+                .spawn()
+            {
+                Ok(_) => {
+                    // exit the current process
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    error!("Failed to restart: {}", e);
+                }
+            }
+        }
+    });
+}
+
+async fn drain_connections(
+    global_state: Arc<Mutex<GlobalState>>,
+    statistics_manager: Arc<StatisticsManager>,
+    timeout: Duration,
+) {
+    {
+        let mut g = global_state.lock().await;
+        if let Some(h) = g.inbound_listener_handle.take() {
+            h.abort();
+        }
+        if let Some(h) = g.tunnel_listener_handle.take() {
+            h.abort();
+        }
+    }
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let remaining = statistics_manager.connection_count().await;
+        if remaining == 0 {
+            break;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            info!("closing {} connections before restart", remaining);
+            statistics_manager.close_all().await;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
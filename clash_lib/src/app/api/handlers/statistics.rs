@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State, http::StatusCode, response::IntoResponse, routing::get, Json,
+    Router,
+};
+use tracing::info;
+
+use crate::app::{api::AppState, dispatcher::StatisticsManager};
+
+#[derive(Clone)]
+struct StatisticsState {
+    statistics_manager: Arc<StatisticsManager>,
+}
+
+pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_statistics).delete(reset_statistics))
+        .with_state(StatisticsState { statistics_manager })
+}
+
+/// Total traffic, per proxy, per rule and per user, since the statistics were last
+/// reset.
+async fn get_statistics(State(state): State<StatisticsState>) -> impl IntoResponse {
+    Json(state.statistics_manager.traffic_stats())
+}
+
+async fn reset_statistics(
+    State(state): State<StatisticsState>,
+) -> impl IntoResponse {
+    state.statistics_manager.reset_statistic();
+    state.statistics_manager.persist().await;
+    info!("traffic statistics reset");
+    StatusCode::NO_CONTENT
+}
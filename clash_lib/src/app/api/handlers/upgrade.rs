@@ -0,0 +1,330 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::Map;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    app::{
+        api::{external_ui, AppState, GeoDatabases},
+        dispatcher::StatisticsManager,
+    },
+    common::{
+        geodata::GeoData,
+        http::HttpClient,
+        integrity::{download_verified, verify_download, Integrity},
+        mmdb::Mmdb,
+        utils::download,
+    },
+    GlobalState,
+};
+
+use super::restart::{schedule_restart, DEFAULT_DRAIN_TIMEOUT};
+
+#[derive(Clone)]
+struct UpgradeState {
+    ui_dir: Option<PathBuf>,
+    ui_url: Option<String>,
+    geo_databases: GeoDatabases,
+    http_client: HttpClient,
+    global_state: Arc<Mutex<GlobalState>>,
+    statistics_manager: Arc<StatisticsManager>,
+}
+
+pub fn routes(
+    ui_dir: Option<PathBuf>,
+    ui_url: Option<String>,
+    geo_databases: GeoDatabases,
+    http_client: HttpClient,
+    global_state: Arc<Mutex<GlobalState>>,
+    statistics_manager: Arc<StatisticsManager>,
+) -> Router<Arc<AppState>> {
+    let state = UpgradeState {
+        ui_dir,
+        ui_url,
+        geo_databases,
+        http_client,
+        global_state,
+        statistics_manager,
+    };
+    Router::new()
+        .route("/", post(upgrade_core))
+        .route("/ui", post(upgrade_ui))
+        .route("/geo", post(upgrade_geo))
+        .with_state(state)
+}
+
+fn ok() -> axum::response::Response {
+    let mut map = Map::new();
+    map.insert("status".to_owned(), "ok".into());
+    Json(map).into_response()
+}
+
+#[derive(Deserialize)]
+struct UpgradeCoreQuery {
+    /// where to download the new binary from
+    url: String,
+    /// hex encoded SHA256 of the new binary, the one published at
+    /// `<url>.sha256` by default
+    sha256: Option<String>,
+    /// drain timeout in seconds before restarting
+    drain: Option<u64>,
+}
+
+/// replace the running binary and restart
+async fn upgrade_core(
+    State(state): State<UpgradeState>,
+    Query(q): Query<UpgradeCoreQuery>,
+) -> impl IntoResponse {
+    let exec = match std::env::current_exe() {
+        Ok(exec) => exec,
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+                .into_response()
+        }
+    };
+
+    let digest = match q.sha256 {
+        Some(digest) => digest,
+        None => match published_digest(&q.url, &state.http_client).await {
+            Ok(digest) => digest,
+            Err(e) => {
+                error!("failed to get the digest of the new binary: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+            }
+        },
+    };
+    let integrity = Integrity {
+        sha256: Some(digest),
+        ..Default::default()
+    };
+
+    info!("downloading new binary from {}", q.url);
+    let new_exec = exec.with_extension("new");
+    if let Err(e) =
+        download_verified(&q.url, &new_exec, &state.http_client, &integrity).await
+    {
+        error!("failed to download new binary: {}", e);
+        let _ = std::fs::remove_file(&new_exec);
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+
+    if let Err(e) = replace_binary(&exec, &new_exec) {
+        error!("failed to replace binary: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+
+    info!("binary upgraded, restarting");
+    schedule_restart(
+        exec,
+        state.global_state,
+        state.statistics_manager,
+        q.drain
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_DRAIN_TIMEOUT),
+    );
+    ok()
+}
+
+/// the digest published along with the binary at `url`, in the format of
+/// `sha256sum`
+async fn published_digest(url: &str, client: &HttpClient) -> Result<String, String> {
+    let url = format!("{}.sha256", url);
+    let uri = url.parse::<hyper::Uri>().map_err(|x| x.to_string())?;
+    let res = client
+        .get(uri)
+        .await
+        .map_err(|x| format!("{}: {}", url, x))?;
+    if !res.status().is_success() {
+        return Err(format!("{}: {}", url, res.status()));
+    }
+    let body = hyper::body::to_bytes(res.into_body())
+        .await
+        .map_err(|x| format!("{}: {}", url, x))?;
+    parse_digest(&body).ok_or_else(|| format!("{}: no sha256 digest", url))
+}
+
+fn parse_digest(body: &[u8]) -> Option<String> {
+    let digest = std::str::from_utf8(body).ok()?.split_whitespace().next()?;
+    (digest.len() == 64 && digest.bytes().all(|x| x.is_ascii_hexdigit()))
+        .then(|| digest.to_owned())
+}
+
+/// the running binary is moved aside instead of overwritten, which is not
+/// allowed on windows
+fn replace_binary(exec: &Path, new_exec: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(new_exec, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    let old_exec = exec.with_extension("old");
+    let _ = std::fs::remove_file(&old_exec);
+    std::fs::rename(exec, &old_exec)?;
+    if let Err(e) = std::fs::rename(new_exec, exec) {
+        // put the old one back
+        std::fs::rename(&old_exec, exec)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn upgrade_ui(State(state): State<UpgradeState>) -> impl IntoResponse {
+    let (Some(dir), Some(url)) = (state.ui_dir, state.ui_url) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "external-ui or external-ui-url is not configured",
+        )
+            .into_response();
+    };
+
+    match external_ui::update(&dir, &url, &state.http_client).await {
+        Ok(_) => ok(),
+        Err(e) => {
+            error!("failed to upgrade external ui: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+/// download the mmdb and geosite databases again
+/// the new databases are in use after the next config reload or restart
+async fn upgrade_geo(State(state): State<UpgradeState>) -> impl IntoResponse {
+    let dbs = state.geo_databases;
+    let client = state.http_client;
+
+    if let Some(url) = dbs.mmdb_download_url {
+        let c = client.clone();
+        if let Err(e) = download_validated(
+            &url,
+            &dbs.mmdb,
+            &client,
+            &dbs.mmdb_integrity,
+            |p| async move {
+                Mmdb::new(p, None, &Integrity::default(), c)
+                    .await
+                    .map(|_| ())
+            },
+        )
+        .await
+        {
+            error!("failed to upgrade mmdb: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+        info!("mmdb upgraded from {}", url);
+    }
+
+    if let Some(url) = dbs.geosite_download_url {
+        let c = client.clone();
+        if let Err(e) = download_validated(
+            &url,
+            &dbs.geosite,
+            &client,
+            &dbs.geosite_integrity,
+            |p| async move {
+                GeoData::new(p, None, &Integrity::default(), c)
+                    .await
+                    .map(|_| ())
+            },
+        )
+        .await
+        {
+            error!("failed to upgrade geosite: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+        info!("geosite upgraded from {}", url);
+    }
+
+    ok()
+}
+
+/// download `url` next to `path` and only replace `path` if the download
+/// passes `integrity` and `validate`
+async fn download_validated<F, Fut>(
+    url: &str,
+    path: &Path,
+    client: &HttpClient,
+    integrity: &Integrity,
+    validate: F,
+) -> Result<(), String>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: Future<Output = Result<(), crate::Error>>,
+{
+    let tmp = path.with_extension("download");
+    let rv = async {
+        download(url, &tmp, client)
+            .await
+            .map_err(|x| x.to_string())?;
+        // signed versions are of `path`, that `tmp` replaces
+        verify_download(url, &tmp, path, client, integrity)
+            .await
+            .map_err(|x| x.to_string())?;
+        validate(tmp.clone()).await.map_err(|x| x.to_string())?;
+        std::fs::rename(&tmp, path).map_err(|x| x.to_string())
+    }
+    .await;
+    if rv.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_digest, replace_binary};
+
+    #[test]
+    fn test_parse_digest() {
+        let digest = "a".repeat(64);
+        assert_eq!(
+            parse_digest(format!("{}  clash-x86_64\n", digest).as_bytes()),
+            Some(digest.clone())
+        );
+        assert_eq!(
+            parse_digest(format!("{}\n", digest).as_bytes()),
+            Some(digest)
+        );
+        assert!(parse_digest(b"not found").is_none());
+        assert!(parse_digest(b"").is_none());
+    }
+
+    #[test]
+    fn test_replace_binary() {
+        let tmp = tempfile::tempdir().unwrap();
+        let exec = tmp.path().join("clash-rs");
+        let new_exec = exec.with_extension("new");
+        std::fs::write(&exec, "old").unwrap();
+        std::fs::write(&new_exec, "new").unwrap();
+
+        replace_binary(&exec, &new_exec).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&exec).unwrap(), "new");
+        assert_eq!(
+            std::fs::read_to_string(exec.with_extension("old")).unwrap(),
+            "old"
+        );
+        assert!(!new_exec.exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&exec).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+    }
+}
@@ -1,11 +1,67 @@
-use std::collections::HashMap;
-
 use axum::response::IntoResponse;
+use serde::Serialize;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildInfo {
+    version: &'static str,
+    /// git commit the binary is built from, if known at build time
+    commit: Option<&'static str>,
+    features: Vec<&'static str>,
+    platform: String,
+    start_time: chrono::DateTime<chrono::Utc>,
+}
+
+/// what this build supports, optional ones depend on the cargo features
+fn features() -> Vec<&'static str> {
+    let mut features = vec!["tun", "trojan", "vmess", "wireguard", "tor"];
+    if cfg!(feature = "shadowsocks") {
+        features.push("shadowsocks");
+    }
+    if cfg!(feature = "tuic") {
+        features.push("tuic");
+    }
+    if cfg!(feature = "onion") {
+        features.push("onion");
+    }
+    if cfg!(feature = "tracing") {
+        features.push("tracing");
+    }
+    features
+}
+
 pub async fn handle() -> impl IntoResponse {
-    let mut val = HashMap::new();
-    val.insert("version".to_owned(), VERSION.to_owned());
-    axum::response::Json(val)
+    axum::response::Json(BuildInfo {
+        version: VERSION,
+        commit: option_env!("CLASH_RS_GIT_COMMIT"),
+        features: features(),
+        platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+        start_time: *crate::START_TIME,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    #[tokio::test]
+    async fn test_build_info() {
+        let res = super::handle().await.into_response();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["version"], env!("CARGO_PKG_VERSION"));
+        assert!(v["features"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::Value::from("tun")));
+        assert!(v["platform"]
+            .as_str()
+            .unwrap()
+            .starts_with(std::env::consts::OS));
+        assert!(v["startTime"].is_string());
+    }
 }
@@ -0,0 +1,177 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use axum::Router;
+use tracing::warn;
+
+use super::server::serve_connection;
+
+/// The peer address reported to handlers for local connections.
+const LOCAL_PEER: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+/// how long to wait after failing to accept, not to spin while it lasts
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serve `app` on a unix socket at `path`.
+/// The socket is only accessible by the owner of the process. A socket left
+/// at `path` is replaced, anything else there is an error. Failing to
+/// accept doesn't stop it.
+#[cfg(unix)]
+pub async fn serve_unix(
+    path: std::path::PathBuf,
+    app: Router,
+) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    use tokio::net::UnixListener;
+
+    match std::fs::symlink_metadata(&path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(&path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    // bound in a directory only the owner can enter, and moved into place
+    // once it's restricted, so no one else can connect in between
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "no socket name")
+    })?;
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    // left behind by a crash
+    let _ = std::fs::remove_dir_all(&private);
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = (|| -> std::io::Result<UnixListener> {
+        let tmp = private.join(name);
+        let listener = UnixListener::bind(&tmp)?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&private);
+    let listener = bound?;
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("controller failed to accept: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        tokio::spawn(serve_connection(stream, app.clone(), LOCAL_PEER, true));
+    }
+}
+
+/// Serve `app` on a named pipe, remote clients are rejected. Failing to
+/// accept a client doesn't stop it.
+#[cfg(windows)]
+pub async fn serve_pipe(name: String, app: Router) -> std::io::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .reject_remote_clients(true)
+        .create(&name)?;
+
+    loop {
+        if let Err(e) = server.connect().await {
+            warn!("controller failed to accept on {}: {}", name, e);
+            tokio::time::sleep(ACCEPT_BACKOFF).await;
+            // the instance may be broken, another one takes over
+            server = next_pipe(&name).await;
+            continue;
+        }
+        let client = std::mem::replace(&mut server, next_pipe(&name).await);
+        tokio::spawn(serve_connection(client, app.clone(), LOCAL_PEER, true));
+    }
+}
+
+/// Another instance of the pipe `name` for the next client, retried until
+/// it can be created.
+#[cfg(windows)]
+async fn next_pipe(name: &str) -> tokio::net::windows::named_pipe::NamedPipeServer {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    loop {
+        match ServerOptions::new()
+            .reject_remote_clients(true)
+            .create(name)
+        {
+            Ok(server) => return server,
+            Err(e) => {
+                warn!("controller failed to open {}: {}", name, e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use axum::{routing::get, Router};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+
+    use super::serve_unix;
+
+    #[tokio::test]
+    async fn test_serve_unix() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("clash.sock");
+
+        let app = Router::new().route("/", get(|| async { "hello" }));
+        tokio::spawn(serve_unix(path.clone(), app));
+
+        let mut stream = loop {
+            if let Ok(s) = UnixStream::connect(&path).await {
+                break s;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // nothing left of the directory it was bound in
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await.unwrap();
+
+        assert!(buf.starts_with("HTTP/1.1 200"));
+        assert!(buf.ends_with("hello"));
+    }
+
+    #[tokio::test]
+    async fn test_serve_unix_not_a_socket() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("clash.sock");
+        std::fs::write(&path, "mine").unwrap();
+
+        let app = Router::new().route("/", get(|| async { "hello" }));
+        let err = serve_unix(path.clone(), app).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "mine");
+    }
+}
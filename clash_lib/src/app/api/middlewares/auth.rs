@@ -4,6 +4,8 @@ use futures::future::BoxFuture;
 use serde::Deserialize;
 use tower::{Layer, Service};
 
+use crate::app::api::server::LocalConnection;
+
 #[derive(Debug, Clone, Deserialize)]
 struct AuthQuery {
     token: String,
@@ -64,7 +66,9 @@ where
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        if self.token.is_empty() {
+        if self.token.is_empty()
+            || req.extensions().get::<LocalConnection>().is_some()
+        {
             return Box::pin(self.inner.call(req));
         }
 
@@ -6,17 +6,25 @@ use axum::{
     Router,
 };
 
+use futures::future::BoxFuture;
 use http::{header, Method};
-use tokio::sync::{broadcast::Sender, Mutex};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast::Sender, Mutex},
+};
 use tower::ServiceBuilder;
 use tower_http::{
     cors::{Any, CorsLayer},
     services::ServeDir,
     trace::TraceLayer,
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::{config::internal::config::Controller, GlobalState, Runner};
+use crate::{
+    common::{http::new_http_client, integrity::Integrity},
+    config::internal::config::Controller,
+    GlobalState, Runner,
+};
 
 use super::{
     dispatcher, dispatcher::StatisticsManager, dns::ThreadSafeDNSResolver,
@@ -25,12 +33,29 @@ use super::{
     router::ThreadSafeRouter,
 };
 
+mod external_ui;
+mod grpc;
 mod handlers;
+mod local;
 mod middlewares;
+mod server;
+mod tls;
 
 pub struct AppState {
     log_source_tx: Sender<LogEvent>,
     statistics_manager: Arc<StatisticsManager>,
+    global_state: Arc<Mutex<GlobalState>>,
+}
+
+/// geo databases that can be upgraded via `POST /upgrade/geo`
+#[derive(Clone, Default)]
+pub struct GeoDatabases {
+    pub mmdb: PathBuf,
+    pub mmdb_download_url: Option<String>,
+    pub geosite: PathBuf,
+    pub geosite_download_url: Option<String>,
+    pub mmdb_integrity: Integrity,
+    pub geosite_integrity: Integrity,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -45,12 +70,19 @@ pub fn get_api_runner(
     statistics_manager: Arc<StatisticsManager>,
     cache_store: ThreadSafeCacheFile,
     router: ThreadSafeRouter,
+    geo_databases: GeoDatabases,
     cwd: String,
 ) -> Option<Runner> {
-    if let Some(bind_addr) = controller_cfg.external_controller {
+    if controller_cfg.external_controller.is_some()
+        || controller_cfg.external_controller_tls.is_some()
+        || controller_cfg.external_controller_unix.is_some()
+        || controller_cfg.external_controller_pipe.is_some()
+        || controller_cfg.external_controller_grpc.is_some()
+    {
         let app_state = Arc::new(AppState {
             log_source_tx: log_source,
             statistics_manager: statistics_manager.clone(),
+            global_state: global_state.clone(),
         });
 
         let cors = CorsLayer::new()
@@ -58,75 +90,197 @@ pub fn get_api_runner(
             .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
             .allow_origin(Any);
 
-        let bind_addr = if bind_addr.starts_with(':') {
-            info!("hostname not provided, listening on localhost");
-            format!("localhost{}", bind_addr)
-        } else {
-            bind_addr
-        };
+        let ui_dir = controller_cfg.external_ui.as_ref().map(|x| {
+            external_ui::ui_dir(&cwd, x, controller_cfg.external_ui_name.as_deref())
+        });
 
         let runner = async move {
-            info!("Starting API server at {}", bind_addr);
+            let http_client = new_http_client(dns_resolver.clone())
+                .map_err(|x| crate::Error::DNSError(x.to_string()))?;
+
+            if let (Some(dir), Some(url)) =
+                (ui_dir.clone(), controller_cfg.external_ui_url.clone())
+            {
+                if external_ui::need_download(&dir) {
+                    let http_client = http_client.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) =
+                            external_ui::update(&dir, &url, &http_client).await
+                        {
+                            error!("failed to download external ui: {}", e);
+                        }
+                    });
+                }
+            }
+
+            let grpc_service = grpc::ControllerService {
+                log_source: app_state.log_source_tx.clone(),
+                inbound_manager: inbound_manager.clone(),
+                dispatcher: dispatcher.clone(),
+                global_state: global_state.clone(),
+                dns_resolver: dns_resolver.clone(),
+                outbound_manager: outbound_manager.clone(),
+                statistics_manager: statistics_manager.clone(),
+                cache_store: cache_store.clone(),
+            };
+            let secret = controller_cfg.secret.unwrap_or_default();
+
+            let pac = controller_cfg.pac.map(|cfg| {
+                handlers::pac::routes(cfg, inbound_manager.clone(), router.clone())
+            });
+
             let mut app = Router::new()
                 .route("/", get(handlers::hello::handle))
                 .route("/logs", get(handlers::log::handle))
                 .route("/traffic", get(handlers::traffic::handle))
                 .route("/version", get(handlers::version::handle))
                 .route("/memory", get(handlers::memory::handle))
+                .route("/metrics", get(handlers::metrics::handle))
                 .route("/restart", post(handlers::restart::handle))
+                .route("/convert", post(handlers::convert::handle))
                 .nest(
                     "/configs",
                     handlers::config::routes(
                         inbound_manager,
                         dispatcher,
-                        global_state,
+                        global_state.clone(),
                         dns_resolver.clone(),
                     ),
                 )
                 .nest("/rules", handlers::rule::routes(router))
+                .nest(
+                    "/profiles",
+                    handlers::profiles::routes(global_state.clone()),
+                )
                 .nest(
                     "/proxies",
-                    handlers::proxy::routes(outbound_manager.clone(), cache_store),
+                    handlers::proxy::routes(
+                        outbound_manager.clone(),
+                        cache_store,
+                        statistics_manager.clone(),
+                    ),
                 )
                 .nest(
                     "/connections",
-                    handlers::connection::routes(statistics_manager),
+                    handlers::connection::routes(
+                        statistics_manager.clone(),
+                        std::path::Path::new(&cwd).join("captures"),
+                    ),
+                )
+                .nest(
+                    "/statistics",
+                    handlers::statistics::routes(statistics_manager.clone()),
+                )
+                .nest(
+                    "/bandwidth",
+                    handlers::bandwidth::routes(statistics_manager.clone()),
                 )
+                .nest("/group", handlers::group::routes(outbound_manager.clone()))
                 .nest(
                     "/providers/proxies",
                     handlers::provider::routes(outbound_manager),
                 )
+                .nest("/cache", handlers::cache::routes(dns_resolver.clone()))
                 .nest("/dns", handlers::dns::routes(dns_resolver))
+                .nest(
+                    "/upgrade",
+                    handlers::upgrade::routes(
+                        ui_dir.clone(),
+                        controller_cfg.external_ui_url,
+                        geo_databases,
+                        http_client,
+                        global_state.clone(),
+                        statistics_manager.clone(),
+                    ),
+                )
                 .route_layer(middlewares::auth::AuthMiddlewareLayer::new(
-                    controller_cfg.secret.unwrap_or_default(),
+                    secret.clone(),
                 ))
                 .route_layer(cors)
                 .with_state(app_state)
                 .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()));
 
-            if let Some(external_ui) = controller_cfg.external_ui {
+            if let Some(pac) = pac {
+                app = app.merge(pac);
+            }
+
+            if let Some(ui_dir) = ui_dir {
                 app = app
                     .route("/ui", get(|| async { Redirect::to("/ui/") }))
-                    .nest_service(
-                        "/ui/",
-                        ServeDir::new(PathBuf::from(cwd).join(external_ui)),
-                    );
+                    .nest_service("/ui/", ServeDir::new(ui_dir));
+            }
+
+            let mut servers: Vec<BoxFuture<'static, std::io::Result<()>>> = vec![];
+
+            if let Some(bind_addr) = controller_cfg.external_controller {
+                let bind_addr = normalize_bind_addr(bind_addr);
+                info!("Starting API server at {}", bind_addr);
+                let listener = TcpListener::bind(&bind_addr).await?;
+                let app = app.clone();
+                servers.push(Box::pin(async move {
+                    axum::serve(
+                        listener,
+                        app.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .await
+                }));
+            }
+
+            if let Some(tls_cfg) = controller_cfg.external_controller_tls {
+                let acceptor = tls::new_acceptor(&tls_cfg, &cwd)?;
+                let bind_addr = normalize_bind_addr(tls_cfg.listen);
+                info!("Starting API server with TLS at {}", bind_addr);
+                let listener = TcpListener::bind(&bind_addr).await?;
+                servers.push(Box::pin(tls::serve(listener, acceptor, app.clone())));
             }
 
-            let listener = tokio::net::TcpListener::bind(&bind_addr).await.unwrap();
-
-            axum::serve(
-                listener,
-                app.into_make_service_with_connect_info::<SocketAddr>(),
-            )
-            .await
-            .map_err(|x| {
-                error!("API server error: {}", x);
-                crate::Error::Operation(format!("API server error: {}", x))
-            })
+            if let Some(path) = controller_cfg.external_controller_unix {
+                #[cfg(unix)]
+                {
+                    let path = std::path::Path::new(&cwd).join(path);
+                    info!("Starting API server at {}", path.to_string_lossy());
+                    servers.push(Box::pin(local::serve_unix(path, app.clone())));
+                }
+                #[cfg(not(unix))]
+                warn!("external-controller-unix {} is not supported", path);
+            }
+
+            if let Some(name) = controller_cfg.external_controller_pipe {
+                #[cfg(windows)]
+                {
+                    info!("Starting API server at {}", name);
+                    servers.push(Box::pin(local::serve_pipe(name, app.clone())));
+                }
+                #[cfg(not(windows))]
+                warn!("external-controller-pipe {} is not supported", name);
+            }
+
+            if let Some(bind_addr) = controller_cfg.external_controller_grpc {
+                let bind_addr = normalize_bind_addr(bind_addr);
+                info!("Starting gRPC API server at {}", bind_addr);
+                let listener = TcpListener::bind(&bind_addr).await?;
+                servers.push(Box::pin(grpc::serve(listener, grpc_service, secret)));
+            }
+
+            futures::future::try_join_all(servers)
+                .await
+                .map(|_| ())
+                .map_err(|x| {
+                    error!("API server error: {}", x);
+                    crate::Error::Operation(format!("API server error: {}", x))
+                })
         };
         Some(Box::pin(runner))
     } else {
         None
     }
 }
+
+fn normalize_bind_addr(bind_addr: String) -> String {
+    if bind_addr.starts_with(':') {
+        info!("hostname not provided, listening on localhost");
+        format!("localhost{}", bind_addr)
+    } else {
+        bind_addr
+    }
+}
@@ -0,0 +1,37 @@
+use std::net::SocketAddr;
+
+use axum::{extract::ConnectInfo, Extension, Router};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug;
+
+/// Marks requests coming from the unix socket or named pipe controller.
+/// Access to those is guarded by file permissions, so the secret is not
+/// required.
+#[derive(Clone, Copy)]
+pub struct LocalConnection;
+
+/// Serve `app` on a single accepted connection.
+pub async fn serve_connection<I>(io: I, app: Router, peer: SocketAddr, local: bool)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut app = app.layer(Extension(ConnectInfo(peer)));
+    if local {
+        app = app.layer(Extension(LocalConnection));
+    }
+
+    if let Err(e) = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(
+            TokioIo::new(io),
+            TowerToHyperService::new(app),
+        )
+        .await
+    {
+        debug!("controller connection from {} closed: {}", peer, e);
+    }
+}
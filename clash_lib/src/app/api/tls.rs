@@ -0,0 +1,153 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use axum::Router;
+use rustls::{server::AllowAnyAuthenticatedClient, RootCertStore, ServerConfig};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+use crate::{
+    common::tls::{load_certs, load_private_key},
+    config::def::ControllerTls,
+    Error,
+};
+
+use super::server::serve_connection;
+
+/// how long a client has to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build the TLS acceptor for the controller, all paths are relative to `cwd`.
+pub fn new_acceptor(cfg: &ControllerTls, cwd: &str) -> Result<TlsAcceptor, Error> {
+    let cwd = Path::new(cwd);
+    let certs = load_certs(&cwd.join(&cfg.certificate))?;
+    let key = load_private_key(&cwd.join(&cfg.private_key))?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &cfg.client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&cwd.join(ca))? {
+                roots.add(&cert).map_err(|x| {
+                    Error::InvalidConfig(format!("invalid client ca {}: {}", ca, x))
+                })?;
+            }
+            builder.with_client_cert_verifier(
+                AllowAnyAuthenticatedClient::new(roots).boxed(),
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut tls_config = builder.with_single_cert(certs, key).map_err(|x| {
+        Error::InvalidConfig(format!("invalid controller certificate: {}", x))
+    })?;
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+/// Serve `app` on `listener`, terminating TLS with `acceptor`. Failing to
+/// accept, e.g. out of file descriptors, doesn't stop it.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    app: Router,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("controller failed to accept: {}", e);
+                // not to spin while it lasts
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let handshake =
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+            let stream = match handshake.await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("controller tls handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("controller tls handshake with {} timed out", peer);
+                    return;
+                }
+            };
+
+            serve_connection(stream, app, peer, false).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::config::def::ControllerTls;
+
+    use super::{new_acceptor, serve};
+
+    static TEST_CERT: &str = include_str!("../dns/test/test.cert");
+    static TEST_KEY: &str = include_str!("../dns/test/test.key");
+
+    fn tls_config(client_ca: Option<&str>) -> ControllerTls {
+        ControllerTls {
+            listen: "127.0.0.1:0".to_owned(),
+            certificate: "cert.pem".to_owned(),
+            private_key: "key.pem".to_owned(),
+            client_ca: client_ca.map(|x| x.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_new_acceptor() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("cert.pem"), TEST_CERT).unwrap();
+        std::fs::write(tmp.path().join("key.pem"), TEST_KEY).unwrap();
+        let cwd = tmp.path().to_str().unwrap();
+
+        assert!(new_acceptor(&tls_config(None), cwd).is_ok());
+        assert!(new_acceptor(&tls_config(Some("cert.pem")), cwd).is_ok());
+        assert!(new_acceptor(&tls_config(Some("missing.pem")), cwd).is_err());
+    }
+
+    #[test]
+    fn test_new_acceptor_no_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("cert.pem"), TEST_CERT).unwrap();
+        std::fs::write(tmp.path().join("key.pem"), TEST_CERT).unwrap();
+
+        assert!(
+            new_acceptor(&tls_config(None), tmp.path().to_str().unwrap()).is_err()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("cert.pem"), TEST_CERT).unwrap();
+        std::fs::write(tmp.path().join("key.pem"), TEST_KEY).unwrap();
+        let acceptor =
+            new_acceptor(&tls_config(None), tmp.path().to_str().unwrap()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, acceptor, axum::Router::new()));
+
+        // never starts the handshake, closed once it's timed out
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)));
+    }
+}
@@ -0,0 +1,399 @@
+//! Captures of the bytes a connection relays, after decryption, as pcapng
+//! files to open in Wireshark. They're started from the API for one
+//! connection at a time and stop at a size or time cap.
+//!
+//! There are no packet headers to record, every read or write is a packet of
+//! the `USER0` link type, its direction in the packet flags. The packets go
+//! to the file from a blocking task, the relaying never waits for the disk.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{debug, warn};
+
+/// captures kept in the directory, the oldest are removed beyond
+pub const MAX_CAPTURES: usize = 32;
+
+/// LINKTYPE_USER0, the payload as is
+const LINKTYPE_USER0: u16 = 147;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CaptureOptions {
+    /// bytes of the file at which the capture stops
+    pub max_bytes: u64,
+    /// seconds after which the capture stops
+    pub duration: u64,
+    /// record only the sizes and times of what's relayed
+    pub metadata_only: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self {
+            max_bytes: 16 * 1024 * 1024,
+            duration: 60,
+            metadata_only: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// from the client to the remote
+    Upload,
+    /// from the remote to the client
+    Download,
+}
+
+pub struct Capture {
+    path: PathBuf,
+    options: CaptureOptions,
+    deadline: Instant,
+    /// bytes of the file so far, counted as the packets are queued
+    written: AtomicU64,
+    /// to the writer task, `None` once the capture is over
+    packets: Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Capture {
+    /// Start a capture to `path`, the connection being described by
+    /// `description` in the file.
+    pub async fn create(
+        path: &Path,
+        description: &str,
+        options: CaptureOptions,
+    ) -> io::Result<Self> {
+        let header = [section_header(), interface_description(description)].concat();
+        let written = header.len() as u64;
+        let file = path.to_owned();
+        let mut w = tokio::task::spawn_blocking(move || {
+            let mut w = BufWriter::new(File::create(file)?);
+            w.write_all(&header)?;
+            Ok::<_, io::Error>(w)
+        })
+        .await??;
+        debug!("capturing {} to {}", description, path.display());
+
+        // queued packets are bounded by `max_bytes`, as they're counted
+        // before they're sent
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let file = path.to_owned();
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some(packet) = rx.blocking_recv() {
+                if let Err(e) = w.write_all(&packet) {
+                    warn!("failed to write capture {}: {}", file.display(), e);
+                    return;
+                }
+            }
+            if let Err(e) = w.flush() {
+                warn!("failed to write capture {}: {}", file.display(), e);
+            }
+        });
+
+        Ok(Self {
+            path: path.to_owned(),
+            options,
+            deadline: Instant::now() + Duration::from_secs(options.duration),
+            written: AtomicU64::new(written),
+            packets: Mutex::new(Some(tx)),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.packets.lock().unwrap().is_none()
+    }
+
+    /// Add `data` relayed in `direction`, the capture ending once it's over
+    /// a cap.
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let mut packets = self.packets.lock().unwrap();
+        let Some(tx) = packets.as_ref() else {
+            return;
+        };
+        if Instant::now() >= self.deadline {
+            drop(packets);
+            self.finish();
+            return;
+        }
+
+        let packet = enhanced_packet(
+            SystemTime::now(),
+            direction,
+            data,
+            self.options.metadata_only,
+        );
+        let written = self.written.load(Ordering::Relaxed);
+        if written + packet.len() as u64 > self.options.max_bytes {
+            drop(packets);
+            self.finish();
+            return;
+        }
+        self.written
+            .store(written + packet.len() as u64, Ordering::Relaxed);
+        if tx.send(packet).is_err() {
+            // the writer failed
+            packets.take();
+        }
+    }
+
+    /// Close the file once what's queued is written, nothing is recorded
+    /// from then on.
+    pub fn finish(&self) {
+        if self.packets.lock().unwrap().take().is_some() {
+            debug!(
+                "capture {} done, {} bytes",
+                self.path.display(),
+                self.written.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    /// Wait for the file to be complete after `finish`.
+    pub async fn flushed(&self) {
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(writer) = writer {
+            let _ = writer.await;
+        }
+    }
+}
+
+/// Remove the oldest captures in `dir` but the newest `keep`.
+pub async fn prune(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut captures = vec![];
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|x| x == "pcapng") {
+            let modified = entry.metadata().await?.modified()?;
+            captures.push((modified, path));
+        }
+    }
+    if captures.len() <= keep {
+        return Ok(());
+    }
+    captures.sort();
+    for (_, path) in &captures[..captures.len() - keep] {
+        debug!("removing old capture {}", path.display());
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            warn!("failed to remove capture {}: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let len = (12 + body.len()) as u32;
+    let mut out = Vec::with_capacity(len as usize);
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(body);
+    out.extend_from_slice(&len.to_le_bytes());
+    out
+}
+
+/// append `value` padded to 32 bits
+fn padded(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend_from_slice(value);
+    out.resize(out.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+fn option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    out.extend_from_slice(&code.to_le_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    padded(out, value);
+}
+
+fn end_of_options(out: &mut Vec<u8>) {
+    option(out, OPT_END, &[]);
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // the section length isn't known in advance
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    option(&mut body, SHB_USERAPPL, b"clash-rs");
+    end_of_options(&mut body);
+    block(SECTION_HEADER, &body)
+}
+
+fn interface_description(description: &str) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // no snap length
+    body.extend_from_slice(&0u32.to_le_bytes());
+    option(&mut body, IF_NAME, b"clash-rs");
+    option(&mut body, OPT_COMMENT, description.as_bytes());
+    // microseconds
+    option(&mut body, IF_TSRESOL, &[6]);
+    end_of_options(&mut body);
+    block(INTERFACE_DESCRIPTION, &body)
+}
+
+fn enhanced_packet(
+    time: SystemTime,
+    direction: Direction,
+    data: &[u8],
+    metadata_only: bool,
+) -> Vec<u8> {
+    let ts = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let captured = if metadata_only { &[][..] } else { data };
+
+    let mut body = vec![];
+    // the only interface
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(ts as u32).to_le_bytes());
+    body.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    padded(&mut body, captured);
+    let flags: u32 = match direction {
+        Direction::Download => 0b01,
+        Direction::Upload => 0b10,
+    };
+    option(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+    end_of_options(&mut body);
+    block(ENHANCED_PACKET, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prune, Capture, CaptureOptions, Direction};
+
+    /// the type and length of the blocks in `file`
+    fn blocks(file: &[u8]) -> Vec<(u32, usize)> {
+        let mut out = vec![];
+        let mut rest = file;
+        while !rest.is_empty() {
+            let kind = u32::from_le_bytes(rest[..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(rest[len - 4..len], rest[4..8]);
+            out.push((kind, len));
+            rest = &rest[len..];
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("c.pcapng");
+        let capture = Capture::create(
+            &path,
+            "tcp 127.0.0.1:50000 -> example.com:443",
+            CaptureOptions::default(),
+        )
+        .await
+        .unwrap();
+        capture.record(Direction::Upload, b"GET / HTTP/1.1\r\n\r\n");
+        capture.record(Direction::Download, b"HTTP/1.1 200 OK\r\n\r\n");
+        capture.finish();
+        assert!(capture.is_done());
+        capture.record(Direction::Upload, b"ignored");
+        capture.flushed().await;
+
+        let file = std::fs::read(&path).unwrap();
+        let kinds = blocks(&file).iter().map(|x| x.0).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![0x0A0D_0D0A, 1, 6, 6]);
+        assert!(file
+            .windows(b"HTTP/1.1 200 OK".len())
+            .any(|x| x == b"HTTP/1.1 200 OK"));
+    }
+
+    #[tokio::test]
+    async fn test_caps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("c.pcapng");
+        let capture = Capture::create(
+            &path,
+            "udp",
+            CaptureOptions {
+                max_bytes: 400,
+                metadata_only: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        for _ in 0..100 {
+            capture.record(Direction::Download, &[0xff; 1200]);
+        }
+        assert!(capture.is_done());
+        capture.flushed().await;
+
+        let file = std::fs::read(&path).unwrap();
+        assert!(file.len() <= 400);
+        // sizes only
+        assert!(!file.windows(16).any(|x| x == [0xff; 16]));
+        assert!(blocks(&file).iter().filter(|x| x.0 == 6).count() > 1);
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..5 {
+            let path = dir.path().join(format!("{}.pcapng", i));
+            std::fs::write(&path, b"").unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(
+                std::time::SystemTime::UNIX_EPOCH
+                    + std::time::Duration::from_secs(i),
+            )
+            .unwrap();
+        }
+        std::fs::write(dir.path().join("other"), b"").unwrap();
+
+        prune(dir.path(), 2).await.unwrap();
+
+        let mut left = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, vec!["3.pcapng", "4.pcapng", "other"]);
+    }
+}
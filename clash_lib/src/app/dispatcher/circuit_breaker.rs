@@ -0,0 +1,225 @@
+//! Proxies failing most of their dials have their circuit opened for a
+//! while, their connections failing at once or sent elsewhere instead of
+//! each waiting out the timeout against a dead server. The first dial once
+//! it's over decides whether it closes or opens again.
+//!
+//! Only failures to reach the server or to set up the connection with it
+//! count, a dead destination behind a working proxy isn't the proxy's
+//! fault. DIRECT and REJECT have no circuit, neither do groups, which move
+//! off their failing members themselves.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    common::errors::is_server_error,
+    config::def,
+    proxy::{AnyOutboundHandler, OutboundType},
+};
+
+/// a probe that hasn't reported back by then was dropped, another is let
+/// through
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct State {
+    window_start: Option<Instant>,
+    failed: u64,
+    total: u64,
+    open_until: Option<Instant>,
+    /// when the probe went out once the circuit was open for long enough,
+    /// the other dials wait for its result
+    probe_since: Option<Instant>,
+}
+
+/// Whether `handler` has a circuit.
+pub fn covers(handler: &AnyOutboundHandler) -> bool {
+    !matches!(
+        handler.proto(),
+        OutboundType::Direct
+            | OutboundType::Reject
+            | OutboundType::Selector
+            | OutboundType::UrlTest
+            | OutboundType::Fallback
+            | OutboundType::LoadBalance
+            | OutboundType::Relay
+    )
+}
+
+/// Whether a dial that ended with `result` went through to the server.
+pub fn reached_server(result: Result<(), &io::Error>) -> bool {
+    result.map_or_else(|e| !is_server_error(e), |_| true)
+}
+
+pub struct CircuitBreaker {
+    cfg: def::CircuitBreaker,
+    states: Mutex<HashMap<String, State>>,
+}
+
+impl CircuitBreaker {
+    /// `None` if it's not enabled.
+    pub fn new(cfg: def::CircuitBreaker) -> Option<Self> {
+        cfg.enable.then(|| Self {
+            cfg,
+            states: Default::default(),
+        })
+    }
+
+    /// Whether to dial `outbound`, false while its circuit is open and
+    /// while the probe after that is out.
+    pub fn allow(&self, outbound: &str) -> bool {
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        let Some(state) = states.get_mut(outbound) else {
+            return true;
+        };
+        match (state.open_until, state.probe_since) {
+            (None, _) => true,
+            (Some(until), _) if now < until => false,
+            (Some(_), Some(since)) if now - since < PROBE_TIMEOUT => false,
+            (Some(_), _) => {
+                state.probe_since = Some(now);
+                true
+            }
+        }
+    }
+
+    /// where connections to an outbound with an open circuit go, `None` to
+    /// fail them
+    pub fn reroute(&self) -> Option<&str> {
+        self.cfg.reroute.as_deref()
+    }
+
+    /// Record the result of dialing `outbound`.
+    pub fn record(&self, outbound: &str, ok: bool) {
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(outbound.to_owned()).or_default();
+
+        if state.probe_since.take().is_some() {
+            if ok {
+                info!("circuit of {} closed", outbound);
+                state.open_until = None;
+            } else {
+                self.open(outbound, state, now);
+            }
+            return;
+        }
+        // dials let through before the circuit opened
+        if state.open_until.is_some() {
+            return;
+        }
+
+        let window = Duration::from_secs(self.cfg.window);
+        if !matches!(state.window_start, Some(x) if now - x < window) {
+            state.window_start = Some(now);
+            state.failed = 0;
+            state.total = 0;
+        }
+        state.total += 1;
+        if !ok {
+            state.failed += 1;
+        }
+        if state.total >= self.cfg.min_dials
+            && state.failed as f64 / state.total as f64 >= self.cfg.threshold
+        {
+            self.open(outbound, state, now);
+        }
+    }
+
+    fn open(&self, outbound: &str, state: &mut State, now: Instant) {
+        let open_for = Duration::from_secs(self.cfg.open_for);
+        warn!(
+            "circuit of {} open for {:?} after {}/{} failed dials",
+            outbound, open_for, state.failed, state.total
+        );
+        state.open_until = Some(now + open_for);
+        state.window_start = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::def;
+
+    use super::CircuitBreaker;
+
+    #[test]
+    fn test_circuit_breaker() {
+        assert!(CircuitBreaker::new(Default::default()).is_none());
+
+        let b = CircuitBreaker::new(def::CircuitBreaker {
+            enable: true,
+            min_dials: 4,
+            open_for: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        b.record("a", true);
+        b.record("a", false);
+        b.record("a", false);
+        assert!(b.allow("a"));
+        // 3 of 4 failed
+        b.record("a", false);
+        assert!(b.allow("b"));
+
+        // open for no time, the next dial is a probe
+        assert!(b.allow("a"));
+        b.record("a", false);
+        assert!(b.allow("a"));
+        b.record("a", true);
+        // closed again, a new window
+        b.record("a", false);
+        assert!(b.allow("a"));
+
+        let b = CircuitBreaker::new(def::CircuitBreaker {
+            enable: true,
+            min_dials: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        b.record("a", false);
+        assert!(!b.allow("a"));
+        assert!(b.allow("b"));
+    }
+
+    #[test]
+    fn test_single_probe() {
+        let b = CircuitBreaker::new(def::CircuitBreaker {
+            enable: true,
+            min_dials: 1,
+            open_for: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        b.record("a", false);
+
+        // the others wait for the probe
+        assert!(b.allow("a"));
+        assert!(!b.allow("a"));
+        b.record("a", true);
+        assert!(b.allow("a"));
+        assert!(b.allow("a"));
+    }
+
+    #[test]
+    fn test_reached_server() {
+        use std::io;
+
+        use crate::common::errors::server_error;
+
+        use super::reached_server;
+
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, "x");
+        assert!(reached_server(Ok(())));
+        // the destination behind the proxy refused
+        assert!(reached_server(Err(&refused())));
+        assert!(!reached_server(Err(&server_error(refused()))));
+    }
+}
@@ -1,18 +1,26 @@
 use crate::{
     app::{
         dispatcher::tracked::{TrackedDatagram, TrackedStream},
+        events, mitm,
         outbound::manager::ThreadSafeOutboundManager,
+        plugins::Plugins,
         router::ThreadSafeRouter,
     },
-    common::io::copy_buf_bidirectional_with_timeout,
+    common::{io::copy_pooled_bidirectional_with_timeout, timing::ConnectPhase},
     config::{
-        def::RunMode,
-        internal::proxy::{PROXY_DIRECT, PROXY_GLOBAL},
+        def::{self, RunMode, UdpFallback},
+        internal::{
+            config::{IdleTimeouts, SnifferConfig},
+            proxy::PROXY_DIRECT,
+            rule::Resolve,
+        },
     },
-    proxy::{datagram::UdpPacket, AnyInboundDatagram},
-    session::Session,
+    proxy::{
+        datagram::UdpPacket, AnyInboundDatagram, AnyOutboundHandler, AsTcpStream,
+    },
+    session::{Session, SocksAddr},
 };
-use futures::{SinkExt, StreamExt};
+use futures::{Sink, SinkExt, StreamExt};
 use std::{
     collections::HashMap,
     fmt::{Debug, Formatter},
@@ -25,17 +33,30 @@ use tokio::{
     sync::RwLock,
     task::JoinHandle,
 };
-use tracing::{debug, error, info, info_span, instrument, trace, warn, Instrument};
+use tracing::{
+    debug, error, error_span, info, info_span, instrument, trace, warn, Instrument,
+};
 
 use crate::app::dns::ThreadSafeDNSResolver;
 
-use super::statistics_manager::Manager;
+use super::{
+    circuit_breaker::{self, CircuitBreaker},
+    sniffer::{DatagramSniffState, SniffedStream, Sniffer},
+    statistics_manager::Manager,
+    timing::ConnectTimings,
+};
 
 pub struct Dispatcher {
     outbound_manager: ThreadSafeOutboundManager,
     router: ThreadSafeRouter,
     resolver: ThreadSafeDNSResolver,
     mode: Arc<Mutex<RunMode>>,
+    idle_timeouts: IdleTimeouts,
+    udp_fallback: UdpFallback,
+    sniffer: Option<Arc<Sniffer>>,
+    mitm: Option<Arc<mitm::Mitm>>,
+    plugins: Option<Arc<Plugins>>,
+    breaker: Option<Arc<CircuitBreaker>>,
 
     manager: Arc<Manager>,
 }
@@ -47,23 +68,45 @@ impl Debug for Dispatcher {
 }
 
 impl Dispatcher {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         outbound_manager: ThreadSafeOutboundManager,
         router: ThreadSafeRouter,
         resolver: ThreadSafeDNSResolver,
         mode: RunMode,
+        idle_timeouts: IdleTimeouts,
+        udp_fallback: UdpFallback,
 
         statistics_manager: Arc<Manager>,
+        sniffer: SnifferConfig,
+        mitm: Option<Arc<mitm::Mitm>>,
+        plugins: Option<Arc<Plugins>>,
     ) -> Self {
         Self {
             outbound_manager,
             router,
             resolver,
             mode: Arc::new(Mutex::new(mode)),
+            idle_timeouts,
+            udp_fallback,
+            sniffer: Sniffer::new(sniffer).map(Arc::new),
+            mitm,
+            plugins,
+            breaker: None,
             manager: statistics_manager,
         }
     }
 
+    /// Open the circuit of outbounds failing most of their dials.
+    pub fn with_circuit_breaker(mut self, cfg: def::CircuitBreaker) -> Self {
+        self.breaker = CircuitBreaker::new(cfg).map(Arc::new);
+        self
+    }
+
+    pub fn outbound_manager(&self) -> &ThreadSafeOutboundManager {
+        &self.outbound_manager
+    }
+
     pub async fn set_mode(&self, mode: RunMode) {
         info!("run mode switched to {}", mode);
 
@@ -74,47 +117,74 @@ impl Dispatcher {
         *self.mode.lock().unwrap()
     }
 
-    #[instrument(skip(self, sess, lhs))]
-    pub async fn dispatch_stream<S>(&self, sess: Session, mut lhs: S)
+    pub async fn dispatch_stream<S>(&self, sess: Session, lhs: S)
     where
-        S: AsyncRead + AsyncWrite + Unpin + Send,
+        S: AsyncRead + AsyncWrite + AsTcpStream + Unpin + Send,
     {
-        let sess = if self.resolver.fake_ip_enabled() {
-            match sess.destination {
-                crate::session::SocksAddr::Ip(addr) => {
-                    let ip = addr.ip();
-                    if self.resolver.is_fake_ip(ip).await {
-                        let host = self.resolver.reverse_lookup(ip).await;
-                        match host {
-                            Some(host) => {
-                                let mut sess = sess;
-                                sess.destination = crate::session::SocksAddr::Domain(
-                                    host,
-                                    addr.port(),
-                                );
-                                sess
-                            }
-                            None => {
-                                error!("failed to reverse lookup fake ip: {}", ip);
-                                return;
-                            }
-                        }
-                    } else {
-                        sess
-                    }
-                }
-                crate::session::SocksAddr::Domain(..) => sess,
+        let id = uuid::Uuid::new_v4();
+        let span = connection_span(id, &sess);
+        self.relay_stream(id, sess, lhs).instrument(span).await
+    }
+
+    async fn relay_stream<S>(&self, id: uuid::Uuid, sess: Session, lhs: S)
+    where
+        S: AsyncRead + AsyncWrite + AsTcpStream + Unpin + Send,
+    {
+        let Some(sess) = reverse_fake_ip(&self.resolver, sess)
+            .instrument(info_span!("resolve"))
+            .await
+        else {
+            return;
+        };
+
+        let mut sess = sess;
+        let mut lhs = match &self.sniffer {
+            Some(sniffer) => {
+                sniffer
+                    .sniff(&mut sess, lhs)
+                    .instrument(info_span!("sniff"))
+                    .await
             }
-        } else {
-            sess
+            None => SniffedStream::new(lhs, vec![]),
         };
 
+        let verdict = match &self.plugins {
+            Some(plugins) => plugins.inspect(&mut sess).await,
+            None => Default::default(),
+        };
+        if verdict.reject {
+            debug!("connection {} rejected by plugin", sess);
+            if let Err(e) = lhs.shutdown().await {
+                warn!("error closing local connection {}: {}", sess, e)
+            }
+            return;
+        }
+
+        let timings = Arc::new(ConnectTimings::default());
         let mode = *self.mode.lock().unwrap();
-        let (outbound_name, rule) = match mode {
-            RunMode::Global => (PROXY_GLOBAL, None),
-            RunMode::Rule => self.router.match_route(&sess).await,
-            RunMode::Direct => (PROXY_DIRECT, None),
+        let (outbound_name, rule) = match verdict.policy.as_deref() {
+            Some(policy) => (policy, None),
+            None => {
+                timings
+                    .run(ConnectPhase::RuleMatch, self.router.route(mode, &sess))
+                    .instrument(info_span!("match_rule"))
+                    .await
+            }
         };
+        if rule.and_then(|x| x.resolve()) == Some(Resolve::Local)
+            && !timings
+                .run(
+                    ConnectPhase::Dns,
+                    resolve_locally(&self.resolver, &mut sess),
+                )
+                .await
+        {
+            if let Err(e) = lhs.shutdown().await {
+                warn!("error closing local connection {}: {}", sess, e)
+            }
+            return;
+        }
+        record_route(&sess, outbound_name, rule);
 
         debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
 
@@ -123,33 +193,82 @@ impl Dispatcher {
             debug!("unknown rule: {}, fallback to direct", outbound_name);
             mgr.get_outbound(PROXY_DIRECT).unwrap()
         });
+        let Some(handler) = bypass_open_circuit(&self.breaker, &mgr, handler) else {
+            debug!("circuit of {} is open, closing {}", outbound_name, sess);
+            if let Err(e) = lhs.shutdown().await {
+                warn!("error closing local connection {}: {}", sess, e)
+            }
+            return;
+        };
 
-        match handler
-            .connect_stream(&sess, self.resolver.clone())
+        match timings
+            .run(
+                ConnectPhase::Handshake,
+                handler.connect_stream(&sess, self.resolver.clone()),
+            )
             .instrument(info_span!("connect_stream", outbound_name = outbound_name,))
             .await
         {
             Ok(rhs) => {
-                debug!("remote connection established {}", sess);
+                let chain = rhs.chain().path().await;
+                tracing::Span::current().record("chain", chain.as_str());
+                debug!("remote connection established {} via {}", sess, chain);
+                record_dial(&self.breaker, &handler, Ok(()));
                 let mut rhs = TrackedStream::new(
+                    id,
                     rhs,
                     self.manager.clone(),
                     sess.clone(),
                     rule,
+                    timings,
                 )
                 .await;
-                match copy_buf_bidirectional_with_timeout(
-                    &mut lhs,
-                    &mut rhs,
-                    4096,
-                    Duration::from_secs(10),
-                    Duration::from_secs(10),
-                )
-                .instrument(info_span!(
-                    "copy_bidirectional",
-                    outbound_name = outbound_name,
-                ))
-                .await
+                let intercept = match &self.mitm {
+                    Some(mitm) => match mitm.intercepts(&sess) {
+                        Some(host) => lhs
+                            .peek_byte()
+                            .await
+                            .and_then(mitm::Scheme::detect)
+                            .map(|scheme| (mitm, scheme, host)),
+                        None => None,
+                    },
+                    None => None,
+                };
+                let relay = async {
+                    if let Some((mitm, scheme, host)) = intercept {
+                        return mitm
+                            .relay(
+                                scheme,
+                                host,
+                                &mut lhs,
+                                rhs,
+                                self.idle_timeouts.tcp,
+                            )
+                            .await;
+                    }
+                    #[cfg(target_os = "linux")]
+                    if let Some(tcp) = lhs.as_tcp_stream() {
+                        if let Some(r) =
+                            rhs.splice(tcp, self.idle_timeouts.tcp).await
+                        {
+                            return r;
+                        }
+                    }
+                    copy_pooled_bidirectional_with_timeout(
+                        &mut lhs,
+                        &mut rhs,
+                        Duration::from_secs(10),
+                        Duration::from_secs(10),
+                        self.idle_timeouts.tcp,
+                    )
+                    .await
+                };
+                match relay
+                    .instrument(info_span!(
+                        "copy_bidirectional",
+                        outbound_name = outbound_name,
+                    ))
+                    .await
                 {
                     Ok((up, down)) => {
                         debug!(
@@ -198,7 +317,8 @@ impl Dispatcher {
                             match err.kind() {
                                 std::io::ErrorKind::UnexpectedEof
                                 | std::io::ErrorKind::ConnectionReset
-                                | std::io::ErrorKind::BrokenPipe => {
+                                | std::io::ErrorKind::BrokenPipe
+                                | std::io::ErrorKind::TimedOut => {
                                     debug!(
                                         "connection {} closed with error {}",
                                         sess, err
@@ -220,6 +340,7 @@ impl Dispatcher {
                     "failed to establish remote connection {}, error: {}",
                     sess, err
                 );
+                record_dial(&self.breaker, &handler, Err(&err));
                 if let Err(e) = lhs.shutdown().await {
                     warn!("error closing local connection {}: {}", sess, e)
                 }
@@ -235,195 +356,281 @@ impl Dispatcher {
         sess: Session,
         udp_inbound: AnyInboundDatagram,
     ) -> tokio::sync::oneshot::Sender<u8> {
-        let outbound_handle_guard = TimeoutUdpSessionManager::new();
+        let outbound_handle_guard =
+            TimeoutUdpSessionManager::new(self.idle_timeouts.clone());
 
         let router = self.router.clone();
         let outbound_manager = self.outbound_manager.clone();
         let resolver = self.resolver.clone();
         let mode = self.mode.clone();
         let manager = self.manager.clone();
+        let udp_fallback = self.udp_fallback;
+        let breaker = self.breaker.clone();
 
+        let unreachable = udp_inbound.unreachable();
         let (mut local_w, mut local_r) = udp_inbound.split();
         let (remote_receiver_w, mut remote_receiver_r) =
             tokio::sync::mpsc::channel(32);
 
         let s = sess.clone();
         let ss = sess.clone();
+        let sniffer = self.sniffer.clone();
         let t1 = tokio::spawn(async move {
-            while let Some(packet) = local_r.next().await {
-                let mut sess = sess.clone();
-                sess.source = packet.src_addr.clone().must_into_socket_addr();
-                sess.destination = packet.dst_addr.clone();
-
-                // populate fake ip for route matching
-                let sess = if resolver.fake_ip_enabled() {
-                    trace!("looking up fake ip for {sess}");
-                    match sess.destination {
-                        crate::session::SocksAddr::Ip(addr) => {
-                            let ip = addr.ip();
-                            if resolver.is_fake_ip(ip).await {
-                                trace!("fake ip detected");
-                                let host = resolver.reverse_lookup(ip).await;
-                                match host {
-                                    Some(host) => {
-                                        trace!("fake ip resolved to {}", host);
-                                        let mut sess = sess;
-                                        sess.destination =
-                                            crate::session::SocksAddr::Domain(
-                                                host,
-                                                addr.port(),
-                                            );
-                                        sess
-                                    }
-                                    None => {
-                                        error!(
-                                            "failed to reverse lookup fake ip: {}",
-                                            ip
-                                        );
-                                        continue;
-                                    }
-                                }
-                            } else {
-                                sess
-                            }
-                        }
-                        crate::session::SocksAddr::Domain(..) => sess,
-                    }
-                } else {
-                    sess
+            let mut sniff_state = DatagramSniffState::default();
+            loop {
+                let deadline = sniff_state.deadline();
+                let packet = tokio::select! {
+                    packet = local_r.next() => match packet {
+                        Some(packet) => Some(packet),
+                        None => break,
+                    },
+                    _ = tokio::time::sleep_until(
+                        deadline.unwrap_or_else(tokio::time::Instant::now)
+                    ), if deadline.is_some() => None,
                 };
 
-                // mutate packet for fake ip
-                let mut packet = packet;
-                packet.dst_addr = sess.destination.clone();
-
-                let mode = *mode.lock().unwrap();
+                let ready = match packet {
+                    Some(packet) => {
+                        let mut sess = sess.clone();
+                        sess.source =
+                            packet.src_addr.clone().must_into_socket_addr();
+                        sess.destination = packet.dst_addr.clone();
+
+                        // populate fake ip for route matching
+                        let Some(sess) = reverse_fake_ip(&resolver, sess).await
+                        else {
+                            continue;
+                        };
 
-                let (outbound_name, rule) = match mode {
-                    RunMode::Global => (PROXY_GLOBAL, None),
-                    RunMode::Rule => router.match_route(&sess).await,
-                    RunMode::Direct => (PROXY_DIRECT, None),
+                        match &sniffer {
+                            Some(sniffer) => sniffer.sniff_datagram(
+                                sess,
+                                packet,
+                                &mut sniff_state,
+                            ),
+                            None => vec![(sess, packet)],
+                        }
+                    }
+                    // QUIC flows that gave up on their ClientHello
+                    None => sniff_state.expired(),
                 };
 
-                let outbound_name = outbound_name.to_string();
+                for (mut sess, packet) in ready {
+                    let mode = *mode.lock().unwrap();
 
-                debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
-
-                let remote_receiver_w = remote_receiver_w.clone();
+                    let (outbound_name, rule) = router.route(mode, &sess).await;
+                    if rule.and_then(|x| x.resolve()) == Some(Resolve::Local)
+                        && !resolve_locally(&resolver, &mut sess).await
+                    {
+                        continue;
+                    }
 
-                let mgr = outbound_manager.clone();
-                let handler =
-                    mgr.get_outbound(&outbound_name).unwrap_or_else(|| {
-                        debug!(
-                            "unknown rule: {}, fallback to direct",
-                            outbound_name
-                        );
-                        mgr.get_outbound(PROXY_DIRECT).unwrap()
-                    });
-
-                match outbound_handle_guard
-                    .get_outbound_sender_mut(
-                        &outbound_name,
-                        packet.src_addr.clone().must_into_socket_addr(), /* this is only
-                                                                          * expected to be
-                                                                          * socket addr as it's
-                                                                          * from local
-                                                                          * udp */
-                    )
-                    .await
-                {
-                    None => {
-                        debug!("building {} outbound datagram connecting", sess);
-                        let outbound_datagram = match handler
-                            .connect_datagram(&sess, resolver.clone())
-                            .await
-                        {
-                            Ok(v) => v,
-                            Err(err) => {
-                                error!("failed to connect outbound: {}", err);
-                                continue;
-                            }
-                        };
+                    // mutate packet for fake ip and local resolution
+                    let mut packet = packet;
+                    // replies come from where the client sent to, not the
+                    // fake ip's or sniffed domain
+                    let reply_src = packet.dst_addr.clone();
+                    packet.dst_addr = sess.destination.clone();
 
-                        debug!("{} outbound datagram connected", sess);
+                    let outbound_name = outbound_name.to_string();
 
-                        let outbound_datagram = TrackedDatagram::new(
-                            outbound_datagram,
-                            manager.clone(),
-                            sess.clone(),
-                            rule,
-                        )
-                        .await;
+                    debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
 
-                        let (mut remote_w, mut remote_r) = outbound_datagram.split();
-                        let (remote_sender, mut remote_forwarder) =
-                            tokio::sync::mpsc::channel::<UdpPacket>(32);
+                    let remote_receiver_w = remote_receiver_w.clone();
 
-                        // remote -> local
-                        let r_handle = tokio::spawn(async move {
-                            while let Some(packet) = remote_r.next().await {
-                                // NAT
-                                let mut packet = packet;
-                                packet.src_addr = sess.destination.clone();
-                                packet.dst_addr = sess.source.into();
+                    let mgr = outbound_manager.clone();
+                    let handler =
+                        mgr.get_outbound(&outbound_name).unwrap_or_else(|| {
+                            debug!(
+                                "unknown rule: {}, fallback to direct",
+                                outbound_name
+                            );
+                            mgr.get_outbound(PROXY_DIRECT).unwrap()
+                        });
 
-                                debug!(
-                                    "UDP NAT for packet: {:?}, session: {}",
-                                    packet, sess
-                                );
-                                match remote_receiver_w.send(packet).await {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        warn!(
-                                            "failed to send packet to local: {}",
-                                            err
+                    match outbound_handle_guard
+                        .get_outbound_sender_mut(
+                            &outbound_name,
+                            packet.src_addr.clone().must_into_socket_addr(), /* this is only
+                                                                              * expected to be
+                                                                              * socket addr as it's
+                                                                              * from local
+                                                                              * udp */
+                        )
+                        .await
+                    {
+                        None => {
+                            let id = uuid::Uuid::new_v4();
+                            let span = connection_span(id, &sess);
+
+                            // the session is still keyed by the routed outbound,
+                            // so later packets find the fallback
+                            let handler = if handler.support_udp().await {
+                                handler
+                            } else {
+                                match udp_fallback {
+                                    UdpFallback::Reject => {
+                                        debug!(
+                                            parent: &span,
+                                            "{} doesn't support UDP, dropping {}",
+                                            outbound_name,
+                                            sess
+                                        );
+                                        report_unreachable(
+                                            &unreachable,
+                                            &packet,
+                                            &reply_src,
                                         );
+                                        continue;
                                     }
-                                }
-                            }
-                        });
-                        // local -> remote
-                        let w_handle = tokio::spawn(async move {
-                            while let Some(packet) = remote_forwarder.recv().await {
-                                match remote_w.send(packet).await {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        warn!(
-                                            "failed to send packet to remote: {}",
-                                            err
+                                    UdpFallback::Direct => {
+                                        debug!(
+                                            parent: &span,
+                                            "{} doesn't support UDP, sending {} direct",
+                                            outbound_name,
+                                            sess
                                         );
+                                        mgr.get_outbound(PROXY_DIRECT).unwrap()
                                     }
                                 }
-                            }
-                        });
-
-                        outbound_handle_guard
-                            .insert(
-                                &outbound_name,
-                                packet.src_addr.clone().must_into_socket_addr(),
-                                r_handle,
-                                w_handle,
-                                remote_sender.clone(),
+                            };
+                            let Some(handler) =
+                                bypass_open_circuit(&breaker, &mgr, handler)
+                            else {
+                                debug!(
+                                    parent: &span,
+                                    "circuit of {} is open, dropping {}",
+                                    outbound_name,
+                                    sess
+                                );
+                                report_unreachable(&unreachable, &packet, &reply_src);
+                                continue;
+                            };
+                            span.in_scope(|| record_route(&sess, handler.name(), rule));
+
+                            debug!(
+                                parent: &span,
+                                "building {} outbound datagram connecting", sess
+                            );
+                            let outbound_datagram = match handler
+                                .connect_datagram(&sess, resolver.clone())
+                                .instrument(span.clone())
+                                .await
+                            {
+                                Ok(v) => {
+                                    record_dial(&breaker, &handler, Ok(()));
+                                    v
+                                }
+                                Err(err) => {
+                                    error!(parent: &span, "failed to connect outbound: {}", err);
+                                    record_dial(&breaker, &handler, Err(&err));
+                                    report_unreachable(&unreachable, &packet, &reply_src);
+                                    continue;
+                                }
+                            };
+
+                            let chain = outbound_datagram.chain().path().await;
+                            span.record("chain", chain.as_str());
+                            debug!(
+                                parent: &span,
+                                "{} outbound datagram connected via {}", sess, chain
+                            );
+
+                            let outbound_datagram = TrackedDatagram::new(
+                                id,
+                                outbound_datagram,
+                                manager.clone(),
+                                sess.clone(),
+                                rule,
                             )
                             .await;
 
-                        match remote_sender.send(packet).await {
-                            Ok(_) => {}
+                            let (mut remote_w, mut remote_r) = outbound_datagram.split();
+                            let (remote_sender, mut remote_forwarder) =
+                                tokio::sync::mpsc::channel::<UdpPacket>(32);
+
+                            // remote -> local
+                            let r_handle = tokio::spawn(
+                                async move {
+                                    while let Some(packet) = remote_r.next().await {
+                                        // NAT
+                                        let mut packet = packet;
+                                        packet.src_addr = reply_src.clone();
+                                        packet.dst_addr = sess.source.into();
+
+                                        debug!(
+                                            "UDP NAT for packet: {:?}, session: {}",
+                                            packet, sess
+                                        );
+                                        match remote_receiver_w.send(packet).await {
+                                            Ok(_) => {}
+                                            Err(err) => {
+                                                warn!(
+                                                    "failed to send packet to local: {}",
+                                                    err
+                                                );
+                                            }
+                                        }
+                                    }
+                                }
+                                .instrument(span.clone()),
+                            );
+                            // local -> remote
+                            let w_handle = tokio::spawn(
+                                async move {
+                                    while let Some(packet) =
+                                        remote_forwarder.recv().await
+                                    {
+                                        match send_batch(
+                                            &mut remote_w,
+                                            packet,
+                                            &mut remote_forwarder,
+                                        )
+                                        .await
+                                        {
+                                            Ok(_) => {}
+                                            Err(err) => {
+                                                warn!(
+                                                    "failed to send packet to remote: \
+                                                     {}",
+                                                    err
+                                                );
+                                            }
+                                        }
+                                    }
+                                }
+                                .instrument(span),
+                            );
+
+                            outbound_handle_guard
+                                .insert(
+                                    &outbound_name,
+                                    packet.src_addr.clone().must_into_socket_addr(),
+                                    packet.dst_addr.port(),
+                                    r_handle,
+                                    w_handle,
+                                    remote_sender.clone(),
+                                )
+                                .await;
+
+                            match remote_sender.send(packet).await {
+                                Ok(_) => {}
+                                Err(err) => {
+                                    error!("failed to send packet to remote: {}", err);
+                                }
+                            };
+                        }
+                        Some(handle) => match handle.send(packet).await {
+                            // TODO: need to reset when GLOBAL select is changed
+                            Ok(_) => {
+                                debug!("reusing {} sent to remote", sess);
+                            }
                             Err(err) => {
                                 error!("failed to send packet to remote: {}", err);
                             }
-                        };
-                    }
-                    Some(handle) => match handle.send(packet).await {
-                        // TODO: need to reset when GLOBAL select is changed
-                        Ok(_) => {
-                            debug!("reusing {} sent to remote", sess);
-                        }
-                        Err(err) => {
-                            error!("failed to send packet to remote: {}", err);
-                        }
-                    },
-                };
+                        },
+                    };
+                }
             }
 
             trace!("UDP session local -> remote finished for {}", ss);
@@ -432,13 +639,11 @@ impl Dispatcher {
         let ss = s.clone();
         let t2 = tokio::spawn(async move {
             while let Some(packet) = remote_receiver_r.recv().await {
-                match local_w.send(packet.clone()).await {
+                match send_batch(&mut local_w, packet, &mut remote_receiver_r).await
+                {
                     Ok(_) => {}
                     Err(err) => {
-                        error!(
-                            "failed to send packet to local: {}, packet: {}",
-                            err, packet
-                        );
+                        error!("failed to send packet to local: {}", err);
                     }
                 }
             }
@@ -458,10 +663,162 @@ impl Dispatcher {
     }
 }
 
+/// The span every line logged for a connection is emitted under, from rule
+/// matching to the end of the relay. `connection_id` is the id the API lists
+/// the connection by. At the error level so that no `log-level` filters it
+/// out from under the warnings and errors it's there for.
+fn connection_span(id: uuid::Uuid, sess: &Session) -> tracing::Span {
+    error_span!(
+        "connection",
+        connection_id = %id,
+        network = %sess.network,
+        inbound = %sess.inbound_name,
+        source = %sess.source,
+        destination = %sess.destination,
+        outbound = tracing::field::Empty,
+        rule = tracing::field::Empty,
+        chain = tracing::field::Empty,
+    )
+}
+
+/// Point `sess` at the domain its fake IP destination stands for. `None` if
+/// the fake IP is unknown.
+async fn reverse_fake_ip(
+    resolver: &ThreadSafeDNSResolver,
+    sess: Session,
+) -> Option<Session> {
+    if !resolver.fake_ip_enabled() {
+        return Some(sess);
+    }
+    let SocksAddr::Ip(addr) = sess.destination else {
+        return Some(sess);
+    };
+    let ip = addr.ip();
+    if !resolver.is_fake_ip(ip).await {
+        return Some(sess);
+    }
+    match resolver.reverse_lookup(ip).await {
+        Some(host) => {
+            trace!("fake ip {} resolved to {}", ip, host);
+            let mut sess = sess;
+            sess.destination = SocksAddr::Domain(host, addr.port());
+            Some(sess)
+        }
+        None => {
+            error!("failed to reverse lookup fake ip: {}", ip);
+            None
+        }
+    }
+}
+
+/// Point `sess` at the locally resolved address of its domain, for rules
+/// with `resolve=local`.
+async fn resolve_locally(
+    resolver: &ThreadSafeDNSResolver,
+    sess: &mut Session,
+) -> bool {
+    let Some(domain) = sess.destination.domain() else {
+        return true;
+    };
+    match resolver.resolve(domain, false).await {
+        Ok(Some(ip)) => {
+            debug!("resolved {} locally to {}", domain, ip);
+            sess.destination = SocksAddr::from((ip, sess.destination.port()));
+            true
+        }
+        Ok(None) => {
+            warn!("failed to resolve {} locally: no address", sess);
+            false
+        }
+        Err(e) => {
+            warn!("failed to resolve {} locally: {}", sess, e);
+            false
+        }
+    }
+}
+
+/// Fill in the route of the current connection span.
+#[allow(clippy::borrowed_box)]
+fn record_route(
+    sess: &Session,
+    outbound_name: &str,
+    rule: Option<&Box<dyn crate::app::router::RuleMatcher>>,
+) {
+    let span = tracing::Span::current();
+    // the sniffed or reverse-looked-up destination
+    span.record("destination", tracing::field::display(&sess.destination));
+    span.record("outbound", outbound_name);
+    if let Some(rule) = rule {
+        span.record("rule", tracing::field::display(rule));
+    }
+}
+
+/// `handler`, or the reroute target if its circuit is open, `None` if there's
+/// none.
+fn bypass_open_circuit(
+    breaker: &Option<Arc<CircuitBreaker>>,
+    mgr: &ThreadSafeOutboundManager,
+    handler: AnyOutboundHandler,
+) -> Option<AnyOutboundHandler> {
+    let Some(breaker) = breaker else {
+        return Some(handler);
+    };
+    if !circuit_breaker::covers(&handler) || breaker.allow(handler.name()) {
+        return Some(handler);
+    }
+    breaker.reroute().and_then(|x| mgr.get_outbound(x))
+}
+
+fn record_dial(
+    breaker: &Option<Arc<CircuitBreaker>>,
+    handler: &AnyOutboundHandler,
+    result: Result<(), &std::io::Error>,
+) {
+    events::record_dial(handler.name(), result.is_ok());
+    if let Some(breaker) = breaker {
+        if circuit_breaker::covers(handler) {
+            breaker.record(handler.name(), circuit_breaker::reached_server(result));
+        }
+    }
+}
+
+/// Tell the client its `packet` to `dst` went nowhere, if the inbound can.
+fn report_unreachable(
+    unreachable: &Option<tokio::sync::mpsc::Sender<UdpPacket>>,
+    packet: &UdpPacket,
+    dst: &SocksAddr,
+) {
+    if let Some(tx) = unreachable {
+        let _ = tx.try_send(UdpPacket {
+            data: packet.data.clone(),
+            src_addr: packet.src_addr.clone(),
+            dst_addr: dst.clone(),
+        });
+    }
+}
+
+/// Hand `first` and whatever else is already queued in `rx` to `sink`,
+/// flushing once so they can go out in a single batch.
+async fn send_batch<S>(
+    sink: &mut S,
+    first: UdpPacket,
+    rx: &mut tokio::sync::mpsc::Receiver<UdpPacket>,
+) -> Result<(), S::Error>
+where
+    S: Sink<UdpPacket> + Unpin,
+{
+    sink.feed(first).await?;
+    while let Ok(packet) = rx.try_recv() {
+        sink.feed(packet).await?;
+    }
+    sink.flush().await
+}
+
 type OutboundPacketSender = tokio::sync::mpsc::Sender<UdpPacket>; // outbound packet sender
 
 struct TimeoutUdpSessionManager {
     map: Arc<RwLock<OutboundHandleMap>>,
+    timeouts: IdleTimeouts,
 
     cleaner: Option<JoinHandle<()>>,
 }
@@ -476,27 +833,45 @@ impl Drop for TimeoutUdpSessionManager {
 }
 
 impl TimeoutUdpSessionManager {
-    fn new() -> Self {
+    fn new(timeouts: IdleTimeouts) -> Self {
         let map = Arc::new(RwLock::new(OutboundHandleMap::new()));
-        let timeout = Duration::from_secs(10);
+        // often enough to reap sessions within a fraction of the shortest
+        // timeout
+        let scan = [timeouts.udp_dns, timeouts.udp_quic]
+            .into_iter()
+            .flatten()
+            .chain(timeouts.udp_ports.iter().map(|(_, x)| *x))
+            .fold(timeouts.udp, Duration::min)
+            .div_f64(2.0)
+            .max(Duration::from_secs(1));
 
         let map_cloned = map.clone();
 
         let cleaner = tokio::spawn(async move {
             trace!("timeout udp session cleaner scanning");
-            let mut interval = tokio::time::interval(timeout);
+            let mut interval = tokio::time::interval(scan);
+            let mut events = events::subscribe();
 
             loop {
-                interval.tick().await;
+                // sessions bound to the previous network are dropped, the
+                // next packet sets up a new one
+                let network_changed = tokio::select! {
+                    _ = interval.tick() => false,
+                    event = events.recv() => matches!(
+                        event,
+                        Ok(events::Event::NetworkChanged { .. })
+                    ),
+                };
                 trace!("timeout udp session cleaner ticking");
 
                 let mut g = map_cloned.write().await;
                 let mut alived = 0;
                 let mut expired = 0;
                 g.0.retain(|k, x| {
-                    let (h1, h2, _, last) = x;
+                    let (h1, h2, _, last, timeout) = x;
                     let now = Instant::now();
-                    let alive = now.duration_since(*last) < timeout;
+                    let alive =
+                        !network_changed && now.duration_since(*last) < *timeout;
                     if !alive {
                         expired += 1;
                         trace!("udp session expired: {:?}", k);
@@ -517,21 +892,31 @@ impl TimeoutUdpSessionManager {
 
         Self {
             map,
+            timeouts,
 
             cleaner: Some(cleaner),
         }
     }
 
+    /// The session times out by the destination of its first packet.
     async fn insert(
         &self,
         outbound_name: &str,
         src_addr: SocketAddr,
+        dst_port: u16,
         recv_handle: JoinHandle<()>,
         send_handle: JoinHandle<()>,
         sender: OutboundPacketSender,
     ) {
         let mut map = self.map.write().await;
-        map.insert(outbound_name, src_addr, recv_handle, send_handle, sender);
+        map.insert(
+            outbound_name,
+            src_addr,
+            recv_handle,
+            send_handle,
+            sender,
+            self.timeouts.udp_for(dst_port),
+        );
     }
 
     async fn get_outbound_sender_mut(
@@ -550,6 +935,7 @@ type OutboundHandleVal = (
     JoinHandle<()>,
     OutboundPacketSender,
     Instant,
+    Duration,
 );
 
 struct OutboundHandleMap(HashMap<OutboundHandleKey, OutboundHandleVal>);
@@ -566,10 +952,11 @@ impl OutboundHandleMap {
         recv_handle: JoinHandle<()>,
         send_handle: JoinHandle<()>,
         sender: OutboundPacketSender,
+        timeout: Duration,
     ) {
         self.0.insert(
             (outbound_name.to_string(), src_addr),
-            (recv_handle, send_handle, sender, Instant::now()),
+            (recv_handle, send_handle, sender, Instant::now(), timeout),
         );
     }
 
@@ -579,7 +966,7 @@ impl OutboundHandleMap {
         src_addr: SocketAddr,
     ) -> Option<OutboundPacketSender> {
         self.0.get_mut(&(outbound_name.to_owned(), src_addr)).map(
-            |(_, _, sender, last)| {
+            |(_, _, sender, last, _)| {
                 trace!(
                     "updating last access time for outbound {:?}",
                     (outbound_name, src_addr)
@@ -603,3 +990,29 @@ impl Drop for OutboundHandleMap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::{layer::SubscriberExt, EnvFilter};
+
+    use crate::{app::logging::EventCollector, session::Session};
+
+    use super::connection_span;
+
+    #[test]
+    fn test_connection_span_filtered_level() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel(4);
+        let subscriber = tracing_subscriber::registry()
+            .with(EventCollector::new(vec![tx]))
+            .with(EnvFilter::new("warn"));
+
+        let id = uuid::Uuid::new_v4();
+        tracing::subscriber::with_default(subscriber, || {
+            connection_span(id, &Session::default())
+                .in_scope(|| tracing::warn!("relay failed"));
+        });
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.connection_id, Some(id.to_string()));
+    }
+}
@@ -1,9 +1,17 @@
+mod capture;
+mod circuit_breaker;
 mod dispatcher_impl;
+mod shaper;
+mod sniffer;
 mod statistics_manager;
+mod timing;
 mod tracked;
 
+pub use capture::CaptureOptions;
 pub use dispatcher_impl::Dispatcher;
-pub use statistics_manager::Manager as StatisticsManager;
+pub use statistics_manager::{
+    Manager as StatisticsManager, ProxyChain, Snapshot, TrackerInfo,
+};
 pub use tracked::{
     BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram,
     ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
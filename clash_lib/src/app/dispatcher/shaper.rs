@@ -0,0 +1,275 @@
+//! Token bucket bandwidth limits per connection, per outbound and per source
+//! IP.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::time::Sleep;
+
+use crate::config::def::Bandwidth;
+
+/// A token bucket refilled at `rate` bytes per second, holding at most one
+/// second worth of tokens. A rate of 0 means unlimited.
+struct Bucket {
+    rate: Arc<AtomicU64>,
+    /// tokens and the time they were last refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: Arc<AtomicU64>) -> Self {
+        let tokens = rate.load(Ordering::Relaxed) as f64;
+        Self {
+            rate,
+            state: Mutex::new((tokens, Instant::now())),
+        }
+    }
+
+    /// Take `n` tokens, going into debt if there aren't enough. Returns how
+    /// long to wait for the debt to be paid off.
+    fn take(&self, n: usize) -> Duration {
+        let rate = self.rate.load(Ordering::Relaxed) as f64;
+        if rate == 0.0 {
+            return Duration::ZERO;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let (tokens, last) = &mut *state;
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate)
+            .min(rate)
+            - n as f64;
+        *last = now;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / rate)
+        }
+    }
+}
+
+/// Limits both directions of the connections sharing it.
+pub struct Limiter {
+    upload: Bucket,
+    download: Bucket,
+}
+
+impl Limiter {
+    fn new(rate: Arc<AtomicU64>) -> Self {
+        Self {
+            upload: Bucket::new(rate.clone()),
+            download: Bucket::new(rate),
+        }
+    }
+}
+
+type ProxyLimiters = HashMap<String, (Arc<AtomicU64>, Weak<Limiter>)>;
+
+#[derive(Default)]
+pub struct Shaper {
+    config: RwLock<Bandwidth>,
+    connection_rate: Arc<AtomicU64>,
+    source_rate: Arc<AtomicU64>,
+    /// the rate of each outbound and the limiter its connections share
+    proxies: Mutex<ProxyLimiters>,
+    sources: Mutex<HashMap<IpAddr, Weak<Limiter>>>,
+}
+
+impl Shaper {
+    pub fn config(&self) -> Bandwidth {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Apply new limits, to the connections already open as well. Spliced
+    /// connections poll `is_unlimited` and fall back to copying.
+    pub fn update(&self, config: Bandwidth) {
+        self.connection_rate
+            .store(config.connection, Ordering::Relaxed);
+        self.source_rate.store(config.source, Ordering::Relaxed);
+        let mut proxies = self.proxies.lock().unwrap();
+        proxies.retain(|_, (_, limiter)| limiter.strong_count() > 0);
+        for (name, (rate, _)) in proxies.iter() {
+            rate.store(
+                config.proxies.get(name).copied().unwrap_or_default(),
+                Ordering::Relaxed,
+            );
+        }
+        *self.config.write().unwrap() = config;
+    }
+
+    /// whether no limit is configured at all
+    pub fn is_unlimited(&self) -> bool {
+        self.config.read().unwrap().eq(&Bandwidth::default())
+    }
+
+    /// The limiters for a new connection through `proxy` from `source`.
+    pub fn throttle(&self, proxy: &str, source: IpAddr) -> Throttle {
+        let mut limiters =
+            vec![Arc::new(Limiter::new(self.connection_rate.clone()))];
+
+        {
+            let mut sources = self.sources.lock().unwrap();
+            match sources.get(&source).and_then(|x| x.upgrade()) {
+                Some(limiter) => limiters.push(limiter),
+                None => {
+                    sources.retain(|_, x| x.strong_count() > 0);
+                    let limiter = Arc::new(Limiter::new(self.source_rate.clone()));
+                    sources.insert(source, Arc::downgrade(&limiter));
+                    limiters.push(limiter);
+                }
+            }
+        }
+
+        let mut proxies = self.proxies.lock().unwrap();
+        let (rate, limiter) = proxies.entry(proxy.to_owned()).or_insert_with(|| {
+            let rate = self
+                .config
+                .read()
+                .unwrap()
+                .proxies
+                .get(proxy)
+                .copied()
+                .unwrap_or_default();
+            (Arc::new(AtomicU64::new(rate)), Weak::new())
+        });
+        match limiter.upgrade() {
+            Some(limiter) => limiters.push(limiter),
+            None => {
+                let new = Arc::new(Limiter::new(rate.clone()));
+                *limiter = Arc::downgrade(&new);
+                limiters.push(new);
+            }
+        }
+
+        Throttle {
+            limiters,
+            upload_wait: None,
+            download_wait: None,
+        }
+    }
+}
+
+/// The bandwidth limits of one connection. Bytes are accounted after they
+/// have been relayed, and the next read or write waits for the debt.
+pub struct Throttle {
+    limiters: Vec<Arc<Limiter>>,
+    upload_wait: Option<Pin<Box<Sleep>>>,
+    download_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    fn poll_wait(
+        wait: &mut Option<Pin<Box<Sleep>>>,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        if let Some(sleep) = wait {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *wait = None;
+        }
+        Poll::Ready(())
+    }
+
+    fn wait_for(wait: &mut Option<Pin<Box<Sleep>>>, d: Duration) {
+        if !d.is_zero() {
+            *wait = Some(Box::pin(tokio::time::sleep(d)));
+        }
+    }
+
+    pub fn poll_upload(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        Self::poll_wait(&mut self.upload_wait, cx)
+    }
+
+    pub fn poll_download(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        Self::poll_wait(&mut self.download_wait, cx)
+    }
+
+    pub fn uploaded(&mut self, n: usize) {
+        let d = self
+            .limiters
+            .iter()
+            .map(|x| x.upload.take(n))
+            .max()
+            .unwrap_or_default();
+        Self::wait_for(&mut self.upload_wait, d);
+    }
+
+    pub fn downloaded(&mut self, n: usize) {
+        let d = self
+            .limiters
+            .iter()
+            .map(|x| x.download.take(n))
+            .max()
+            .unwrap_or_default();
+        Self::wait_for(&mut self.download_wait, d);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        sync::{atomic::AtomicU64, Arc},
+        time::Duration,
+    };
+
+    use crate::config::def::Bandwidth;
+
+    use super::{Bucket, Shaper};
+
+    #[test]
+    fn test_bucket() {
+        let bucket = Bucket::new(Arc::new(AtomicU64::new(1000)));
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        let wait = bucket.take(500);
+        assert!(
+            wait > Duration::from_millis(400) && wait <= Duration::from_millis(500)
+        );
+
+        let unlimited = Bucket::new(Arc::new(AtomicU64::new(0)));
+        assert_eq!(unlimited.take(usize::MAX), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_shaper_update() {
+        let shaper = Shaper::default();
+        shaper.update(Bandwidth {
+            proxies: [("ss01".to_owned(), 1000)].into(),
+            ..Default::default()
+        });
+        let src: IpAddr = "192.168.1.2".parse().unwrap();
+
+        let mut a = shaper.throttle("ss01", src);
+        let mut b = shaper.throttle("ss01", src);
+        let mut direct = shaper.throttle("DIRECT", src);
+        // connections through ss01 share the bucket
+        a.uploaded(1000);
+        b.uploaded(500);
+        assert!(b.upload_wait.is_some());
+        direct.uploaded(100_000);
+        assert!(direct.upload_wait.is_none());
+
+        // new limits apply to open connections
+        shaper.update(Bandwidth {
+            source: 10,
+            ..Default::default()
+        });
+        a.downloaded(1000);
+        assert!(a.download_wait.is_some());
+        assert_eq!(shaper.config().source, 10);
+        let mut other = shaper.throttle("ss01", "192.168.1.3".parse().unwrap());
+        other.downloaded(5);
+        assert!(other.download_wait.is_none());
+    }
+}
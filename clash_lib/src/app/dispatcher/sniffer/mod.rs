@@ -0,0 +1,769 @@
+//! Reads the host name from the first bytes of a TCP connection, the SNI of
+//! a TLS ClientHello or the Host header of an HTTP request, and from the
+//! first packets of a QUIC connection.
+
+mod quic;
+
+use std::{
+    collections::HashMap,
+    mem::MaybeUninit,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, Interest, ReadBuf},
+    net::TcpStream,
+    time::Instant,
+};
+use tracing::debug;
+
+use crate::{
+    common::trie,
+    config::internal::config::{SniffProtocol, SniffRule, SnifferConfig},
+    proxy::{datagram::UdpPacket, AsTcpStream},
+    session::{Session, SocksAddr},
+};
+
+/// how long to wait for the client to speak first
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+const MAX_SNIFF_SIZE: usize = 8192;
+/// the largest TLS record, header included, a ClientHello is read whole
+/// up to it
+const MAX_TLS_RECORD: usize = 5 + (1 << 14);
+/// the packets of a QUIC flow held back while its ClientHello is incomplete
+const MAX_HELD_PACKETS: usize = 8;
+/// how long the host sniffed for a QUIC flow is kept after its last packet
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_FLOWS: usize = 4096;
+
+#[derive(Debug, PartialEq)]
+enum Sniffed {
+    Host(String),
+    NeedMore,
+    NotMatched,
+}
+
+pub struct Sniffer {
+    protocols: Vec<SniffRule>,
+    force_domain: Option<trie::StringTrie<bool>>,
+    skip_domain: Option<trie::StringTrie<bool>>,
+}
+
+fn domain_trie(domains: &[String]) -> Option<trie::StringTrie<bool>> {
+    if domains.is_empty() {
+        return None;
+    }
+    let mut t = trie::StringTrie::new();
+    for d in domains {
+        t.insert(d, Arc::new(true));
+    }
+    Some(t)
+}
+
+impl Sniffer {
+    /// `None` if sniffing is disabled.
+    pub fn new(cfg: SnifferConfig) -> Option<Self> {
+        if !cfg.enable || cfg.protocols.is_empty() {
+            return None;
+        }
+        Some(Self {
+            protocols: cfg.protocols,
+            force_domain: domain_trie(&cfg.force_domain),
+            skip_domain: domain_trie(&cfg.skip_domain),
+        })
+    }
+
+    fn rules_for(&self, sess: &Session) -> Vec<&SniffRule> {
+        let forced = match &sess.destination {
+            SocksAddr::Ip(_) => true,
+            SocksAddr::Domain(domain, _) => self
+                .force_domain
+                .as_ref()
+                .is_some_and(|x| x.search(domain).is_some()),
+        };
+        if !forced {
+            return vec![];
+        }
+        let port = sess.destination.port();
+        self.protocols
+            .iter()
+            .filter(|x| {
+                x.ports.is_empty() || x.ports.iter().any(|r| r.contains(&port))
+            })
+            .collect()
+    }
+
+    fn apply(&self, sess: &mut Session, host: String, override_destination: bool) {
+        debug!("sniffed {} for {}", host, sess);
+        let skipped = self
+            .skip_domain
+            .as_ref()
+            .is_some_and(|x| x.search(&host).is_some());
+        if override_destination && !skipped {
+            sess.destination =
+                SocksAddr::Domain(host.clone(), sess.destination.port());
+        }
+        sess.sniff_host = Some(host);
+    }
+
+    /// Sniff the host of `lhs` into `sess.sniff_host`, and make it the
+    /// destination if the protocol says so. The bytes read are replayed by
+    /// the returned stream.
+    pub async fn sniff<S>(&self, sess: &mut Session, mut lhs: S) -> SniffedStream<S>
+    where
+        S: AsyncRead + AsTcpStream + Unpin,
+    {
+        let rules = self.rules_for(sess);
+        if rules.is_empty() {
+            return SniffedStream::new(lhs, vec![]);
+        }
+
+        let deadline = Instant::now() + SNIFF_TIMEOUT;
+        let mut buf = Vec::with_capacity(MAX_SNIFF_SIZE);
+        let mut found = None;
+        loop {
+            let limit = sniff_limit(&buf);
+            if buf.len() >= limit {
+                break;
+            }
+            let filled = match lhs.as_tcp_stream() {
+                // plain sockets are peeked, leaving the bytes in the kernel
+                // so the connection can still be spliced
+                Some(tcp) => {
+                    tokio::time::timeout_at(
+                        deadline,
+                        peek_more(tcp, &mut buf, limit),
+                    )
+                    .await
+                }
+                None => {
+                    tokio::time::timeout_at(
+                        deadline,
+                        read_more(&mut lhs, &mut buf, limit),
+                    )
+                    .await
+                }
+            };
+            if !matches!(filled, Ok(Ok(true))) {
+                break;
+            }
+
+            let mut need_more = false;
+            for rule in rules.iter() {
+                match sniff(rule.protocol, &buf) {
+                    Sniffed::Host(host) => {
+                        found = Some((host, rule.override_destination));
+                        break;
+                    }
+                    Sniffed::NeedMore => need_more = true,
+                    Sniffed::NotMatched => {}
+                }
+            }
+            if found.is_some() || !need_more {
+                break;
+            }
+        }
+
+        if let Some((host, override_destination)) = found {
+            self.apply(sess, host, override_destination);
+        }
+
+        let prefix = if lhs.as_tcp_stream().is_some() {
+            vec![]
+        } else {
+            buf
+        };
+        SniffedStream::new(lhs, prefix)
+    }
+}
+
+/// A QUIC flow waiting for the rest of its ClientHello.
+struct HeldFlow {
+    crypto: quic::CryptoStream,
+    sess: Session,
+    packets: Vec<UdpPacket>,
+    deadline: Instant,
+}
+
+/// The QUIC flows of a UDP association, by source and destination. Every
+/// packet of a flow has to be routed the same way, so the packets before
+/// its ClientHello is complete are held back, and the host found is kept
+/// for the packets after.
+pub struct DatagramSniffState {
+    held: HashMap<(SocketAddr, SocksAddr), HeldFlow>,
+    sniffed: lru_time_cache::LruCache<(SocketAddr, SocksAddr), Option<String>>,
+}
+
+impl Default for DatagramSniffState {
+    fn default() -> Self {
+        Self {
+            held: HashMap::new(),
+            sniffed: lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                FLOW_TIMEOUT,
+                MAX_FLOWS,
+            ),
+        }
+    }
+}
+
+impl DatagramSniffState {
+    /// When the first held flow gives up waiting.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.held.values().map(|x| x.deadline).min()
+    }
+
+    /// The packets of the flows that waited past their deadline, to be
+    /// routed without a host.
+    pub fn expired(&mut self) -> Vec<(Session, UdpPacket)> {
+        let now = Instant::now();
+        let keys = self
+            .held
+            .iter()
+            .filter(|(_, x)| x.deadline <= now)
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        let mut expired = vec![];
+        for key in keys {
+            let flow = self.held.remove(&key).expect("flow is held");
+            self.sniffed.insert(key, None);
+            expired.extend(flow.packets.into_iter().map(|x| (flow.sess.clone(), x)));
+        }
+        expired
+    }
+}
+
+impl Sniffer {
+    /// Sniff the SNI of the QUIC flow `packet` belongs to into `sess`. The
+    /// packets ready to be routed with `sess` are returned, none while the
+    /// flow is held.
+    pub fn sniff_datagram(
+        &self,
+        mut sess: Session,
+        packet: UdpPacket,
+        state: &mut DatagramSniffState,
+    ) -> Vec<(Session, UdpPacket)> {
+        let Some(rule) = self
+            .rules_for(&sess)
+            .into_iter()
+            .find(|x| x.protocol == SniffProtocol::Quic)
+        else {
+            return vec![(sess, packet)];
+        };
+
+        let key = (sess.source, sess.destination.clone());
+        let mut ready = vec![];
+        let host = match state.sniffed.get(&key) {
+            Some(host) => host.clone(),
+            None => {
+                let mut flow = state.held.remove(&key).unwrap_or_else(|| HeldFlow {
+                    crypto: Default::default(),
+                    sess: sess.clone(),
+                    packets: vec![],
+                    deadline: Instant::now() + SNIFF_TIMEOUT,
+                });
+                let host = match useful(flow.crypto.push(&packet.data)) {
+                    Sniffed::NeedMore if flow.packets.len() < MAX_HELD_PACKETS => {
+                        flow.packets.push(packet);
+                        state.held.insert(key, flow);
+                        return vec![];
+                    }
+                    Sniffed::Host(host) => Some(host),
+                    _ => None,
+                };
+                state.sniffed.insert(key, host.clone());
+                ready = flow.packets;
+                host
+            }
+        };
+
+        if let Some(host) = host {
+            self.apply(&mut sess, host, rule.override_destination);
+        }
+        ready.push(packet);
+        ready.into_iter().map(|x| (sess.clone(), x)).collect()
+    }
+}
+
+/// How many bytes to sniff at most, given the ones so far.
+fn sniff_limit(buf: &[u8]) -> usize {
+    match buf {
+        [0x16, 0x03, _, hi, lo, ..] => (5 + u16::from_be_bytes([*hi, *lo]) as usize)
+            .clamp(MAX_SNIFF_SIZE, MAX_TLS_RECORD),
+        _ => MAX_SNIFF_SIZE,
+    }
+}
+
+/// Peek until more than `buf` holds is available, up to `limit` bytes.
+/// `false` on EOF.
+async fn peek_more(
+    tcp: &TcpStream,
+    buf: &mut Vec<u8>,
+    limit: usize,
+) -> std::io::Result<bool> {
+    let mut peeked = vec![0u8; limit];
+    // peek returns what's already there right away, so nothing new counts
+    // as not ready: the readiness is cleared and the next bytes wake us up
+    let n = tcp
+        .async_io(Interest::READABLE, || {
+            // SAFETY: peek only writes initialized bytes
+            let dst = unsafe {
+                &mut *(&mut peeked[..] as *mut [u8] as *mut [MaybeUninit<u8>])
+            };
+            match socket2::SockRef::from(tcp).peek(dst)? {
+                n if n != 0 && n <= buf.len() => {
+                    Err(std::io::ErrorKind::WouldBlock.into())
+                }
+                n => Ok(n),
+            }
+        })
+        .await?;
+    if n == 0 {
+        return Ok(false);
+    }
+    buf.clear();
+    buf.extend_from_slice(&peeked[..n]);
+    Ok(true)
+}
+
+async fn read_more<S: AsyncRead + Unpin>(
+    lhs: &mut S,
+    buf: &mut Vec<u8>,
+    limit: usize,
+) -> std::io::Result<bool> {
+    let mut chunk = vec![0; limit - buf.len()];
+    let n = lhs.read(&mut chunk).await?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n > 0)
+}
+
+fn sniff(protocol: SniffProtocol, buf: &[u8]) -> Sniffed {
+    useful(match protocol {
+        SniffProtocol::Tls => sniff_tls(buf),
+        SniffProtocol::Http => sniff_http(buf),
+        // only over UDP
+        SniffProtocol::Quic => Sniffed::NotMatched,
+    })
+}
+
+fn useful(sniffed: Sniffed) -> Sniffed {
+    match sniffed {
+        // an IP address isn't any better than what we have
+        Sniffed::Host(h)
+            if h.is_empty() || h.parse::<std::net::IpAddr>().is_ok() =>
+        {
+            Sniffed::NotMatched
+        }
+        x => x,
+    }
+}
+
+fn sniff_http(buf: &[u8]) -> Sniffed {
+    const METHODS: [&[u8]; 9] = [
+        b"GET ",
+        b"POST ",
+        b"HEAD ",
+        b"PUT ",
+        b"DELETE ",
+        b"OPTIONS ",
+        b"PATCH ",
+        b"CONNECT ",
+        b"TRACE ",
+    ];
+    if !METHODS.iter().any(|m| {
+        let n = m.len().min(buf.len());
+        buf[..n] == m[..n]
+    }) {
+        return Sniffed::NotMatched;
+    }
+
+    let mut lines = buf.split(|x| *x == b'\n');
+    // the request line
+    lines.next();
+    let mut complete = buf.ends_with(b"\n");
+    for line in lines {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            complete = true;
+            break;
+        }
+        let Some((name, value)) = std::str::from_utf8(line)
+            .ok()
+            .and_then(|x| x.split_once(':'))
+        else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("host") {
+            let value = value.trim();
+            let host = match value.strip_prefix('[') {
+                Some(v6) => v6.split(']').next().unwrap_or_default(),
+                None => value.rsplit_once(':').map(|x| x.0).unwrap_or(value),
+            };
+            return Sniffed::Host(host.to_lowercase());
+        }
+        complete = false;
+    }
+    if complete && buf.windows(4).any(|x| x == b"\r\n\r\n") {
+        Sniffed::NotMatched
+    } else {
+        Sniffed::NeedMore
+    }
+}
+
+fn sniff_tls(buf: &[u8]) -> Sniffed {
+    // record header: handshake, TLS 1.x
+    if buf.is_empty() || buf[0] != 0x16 {
+        return Sniffed::NotMatched;
+    }
+    if buf.len() < 5 {
+        return Sniffed::NeedMore;
+    }
+    if buf[1] != 0x03 {
+        return Sniffed::NotMatched;
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if buf.len() < 5 + record_len {
+        return Sniffed::NeedMore;
+    }
+    match client_hello_sni(&buf[5..5 + record_len]) {
+        Some(host) => Sniffed::Host(host),
+        None => Sniffed::NotMatched,
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|x| x[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2)
+            .map(|x| u16::from_be_bytes([x[0], x[1]]) as usize)
+    }
+}
+
+fn client_hello_sni(handshake: &[u8]) -> Option<String> {
+    let mut r = Reader(handshake);
+    // ClientHello
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    r.take(3)?;
+    // version and random
+    r.take(2 + 32)?;
+    let n = r.u8()?;
+    r.take(n)?;
+    let n = r.u16()?;
+    r.take(n)?;
+    let n = r.u8()?;
+    r.take(n)?;
+
+    let n = r.u16()?;
+    let mut exts = Reader(r.take(n)?);
+    while let (Some(typ), Some(n)) = (exts.u16(), exts.u16()) {
+        let data = exts.take(n)?;
+        if typ != 0x0000 {
+            continue;
+        }
+        let mut names = Reader(data);
+        let n = names.u16()?;
+        let mut names = Reader(names.take(n)?);
+        while let Some(name_type) = names.u8() {
+            let n = names.u16()?;
+            let name = names.take(n)?;
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(|x| x.to_lowercase());
+            }
+        }
+    }
+    None
+}
+
+/// A stream replaying the bytes read while sniffing.
+pub struct SniffedStream<S> {
+    inner: S,
+    prefix: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> SniffedStream<S> {
+    pub fn new(inner: S, prefix: Vec<u8>) -> Self {
+        Self {
+            inner,
+            prefix,
+            pos: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsTcpStream + Unpin> SniffedStream<S> {
+    /// The next byte to read, without consuming it. `None` on EOF or if the
+    /// client sends nothing in time.
+    pub async fn peek_byte(&mut self) -> Option<u8> {
+        if self.pos < self.prefix.len() {
+            return Some(self.prefix[self.pos]);
+        }
+        let mut buf = [0u8; 1];
+        let n = match self.inner.as_tcp_stream() {
+            Some(tcp) => {
+                tokio::time::timeout(SNIFF_TIMEOUT, tcp.peek(&mut buf)).await
+            }
+            None => {
+                let n =
+                    tokio::time::timeout(SNIFF_TIMEOUT, self.inner.read(&mut buf))
+                        .await;
+                if let Ok(Ok(1)) = n {
+                    self.prefix.truncate(self.pos);
+                    self.prefix.push(buf[0]);
+                }
+                n
+            }
+        };
+        match n {
+            Ok(Ok(1)) => Some(buf[0]),
+            _ => None,
+        }
+    }
+}
+
+impl<S: AsTcpStream> AsTcpStream for SniffedStream<S> {
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        // a socket with bytes left to replay can't be spliced
+        if self.pos < self.prefix.len() {
+            None
+        } else {
+            self.inner.as_tcp_stream()
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SniffedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let n = buf.remaining().min(this.prefix.len() - this.pos);
+            buf.put_slice(&this.prefix[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SniffedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        config::internal::config::{SniffProtocol, SniffRule, SnifferConfig},
+        session::{Session, SocksAddr},
+    };
+
+    use super::{sniff_http, sniff_tls, Sniffed, Sniffer, MAX_SNIFF_SIZE};
+
+    /// a ClientHello carrying only the SNI extension
+    pub(super) fn client_hello(sni: &str) -> Vec<u8> {
+        padded_client_hello(sni, 0)
+    }
+
+    /// a ClientHello with `padding` bytes of the padding extension before
+    /// the SNI one
+    fn padded_client_hello(sni: &str, padding: usize) -> Vec<u8> {
+        let name = sni.as_bytes();
+        let mut ext = vec![];
+        if padding > 0 {
+            ext.extend([0, 0x15]);
+            ext.extend((padding as u16).to_be_bytes());
+            ext.extend(vec![0; padding]);
+        }
+        ext.extend([0, 0]);
+        ext.extend((name.len() as u16 + 5).to_be_bytes());
+        ext.extend((name.len() as u16 + 3).to_be_bytes());
+        ext.push(0);
+        ext.extend((name.len() as u16).to_be_bytes());
+        ext.extend(name);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0; 32]);
+        body.push(0);
+        body.extend([0, 2, 0x13, 0x01]);
+        body.extend([1, 0]);
+        body.extend((ext.len() as u16).to_be_bytes());
+        body.extend(ext);
+
+        let mut hs = vec![0x01, 0];
+        hs.extend((body.len() as u16).to_be_bytes());
+        hs.extend(body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((hs.len() as u16).to_be_bytes());
+        record.extend(hs);
+        record
+    }
+
+    #[test]
+    fn test_sniff_tls() {
+        let hello = client_hello("www.Example.com");
+        assert_eq!(sniff_tls(&hello), Sniffed::Host("www.example.com".into()));
+        assert_eq!(sniff_tls(&hello[..20]), Sniffed::NeedMore);
+        assert_eq!(sniff_tls(b"GET / HTTP/1.1\r\n"), Sniffed::NotMatched);
+    }
+
+    #[test]
+    fn test_sniff_http() {
+        assert_eq!(
+            sniff_http(
+                b"GET / HTTP/1.1\r\nUser-Agent: x\r\nHost: example.com:8080\r\n"
+            ),
+            Sniffed::Host("example.com".into())
+        );
+        assert_eq!(sniff_http(b"GE"), Sniffed::NeedMore);
+        assert_eq!(
+            sniff_http(b"GET / HTTP/1.1\r\nAccept: */*"),
+            Sniffed::NeedMore
+        );
+        assert_eq!(
+            sniff_http(b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n"),
+            Sniffed::NotMatched
+        );
+        assert_eq!(sniff_http(b"\x16\x03\x01"), Sniffed::NotMatched);
+    }
+
+    #[tokio::test]
+    async fn test_sniff_override() {
+        let sniffer = Sniffer::new(SnifferConfig {
+            enable: true,
+            protocols: vec![SniffRule {
+                protocol: SniffProtocol::Tls,
+                ports: vec![443..=443],
+                override_destination: true,
+            }],
+            force_domain: vec![],
+            skip_domain: vec!["+.skip.com".to_owned()],
+        })
+        .unwrap();
+
+        for (sni, port, destination) in [
+            ("example.com", 443, "example.com"),
+            ("a.skip.com", 443, "1.2.3.4"),
+            ("example.com", 8443, "1.2.3.4"),
+        ] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let hello = client_hello(sni);
+            client.write_all(&hello).await.unwrap();
+
+            let mut sess = Session {
+                destination: SocksAddr::Ip(([1, 2, 3, 4], port).into()),
+                ..Default::default()
+            };
+            let mut s = sniffer.sniff(&mut sess, server).await;
+            assert_eq!(sess.destination.host(), destination);
+
+            // the sniffed bytes are still there
+            let mut buf = vec![0; hello.len()];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, hello);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sniff_large_client_hello() {
+        let sniffer = Sniffer::new(SnifferConfig {
+            enable: true,
+            protocols: vec![SniffRule {
+                protocol: SniffProtocol::Tls,
+                ports: vec![],
+                override_destination: true,
+            }],
+            force_domain: vec![],
+            skip_domain: vec![],
+        })
+        .unwrap();
+        let hello = padded_client_hello("example.com", 12000);
+        assert!(hello.len() > MAX_SNIFF_SIZE);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let writer = {
+            let hello = hello.clone();
+            tokio::spawn(async move {
+                // in two goes, the sniffer waits for the rest of the record
+                client.write_all(&hello[..4096]).await.unwrap();
+                tokio::task::yield_now().await;
+                client.write_all(&hello[4096..]).await.unwrap();
+                client
+            })
+        };
+
+        let mut sess = Session {
+            destination: SocksAddr::Ip(([1, 2, 3, 4], 443).into()),
+            ..Default::default()
+        };
+        let mut s = sniffer.sniff(&mut sess, server).await;
+        assert_eq!(sess.destination.host(), "example.com");
+
+        // peeked, the bytes are still in the socket
+        let _client = writer.await.unwrap();
+        let mut buf = vec![0; hello.len()];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, hello);
+
+        // read through a stream that isn't a socket too
+        let (mut client, server) = tokio::io::duplex(hello.len());
+        client.write_all(&hello).await.unwrap();
+        let mut sess = Session {
+            destination: SocksAddr::Ip(([1, 2, 3, 4], 443).into()),
+            ..Default::default()
+        };
+        sniffer.sniff(&mut sess, server).await;
+        assert_eq!(sess.destination.host(), "example.com");
+    }
+}
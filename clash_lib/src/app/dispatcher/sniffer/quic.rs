@@ -0,0 +1,396 @@
+//! Reads the SNI of a QUIC connection from the ClientHello carried by the
+//! CRYPTO frames of its Initial packets. Their keys derive from the
+//! destination connection ID alone, RFC 9001 section 5 and RFC 9369 for
+//! version 2. Clients with large ClientHellos split them over several
+//! packets, and shuffle the frames, so they're put back together by offset.
+
+use ring::{
+    aead::{self, quic::HeaderProtectionKey},
+    hkdf,
+};
+
+use super::{client_hello_sni, Reader, Sniffed, MAX_SNIFF_SIZE};
+
+struct Version {
+    salt: [u8; 20],
+    /// the type bits of Initial packets
+    initial: u8,
+    key: &'static [u8],
+    iv: &'static [u8],
+    hp: &'static [u8],
+}
+
+const V1: Version = Version {
+    salt: [
+        0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6,
+        0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+    ],
+    initial: 0,
+    key: b"quic key",
+    iv: b"quic iv",
+    hp: b"quic hp",
+};
+
+const V2: Version = Version {
+    salt: [
+        0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe,
+        0x6e, 0x26, 0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+    ],
+    initial: 1,
+    key: b"quicv2 key",
+    iv: b"quicv2 iv",
+    hp: b"quicv2 hp",
+};
+
+fn version(v: u32) -> Option<&'static Version> {
+    match v {
+        0x0000_0001 => Some(&V1),
+        0x6b33_43cf => Some(&V2),
+        _ => None,
+    }
+}
+
+/// The CRYPTO frames of the Initial packets of a connection seen so far.
+#[derive(Default)]
+pub(super) struct CryptoStream {
+    dcid: Vec<u8>,
+    frames: Vec<(usize, Vec<u8>)>,
+}
+
+impl CryptoStream {
+    /// Add the Initial packets in `datagram`, the host once the ClientHello
+    /// is complete.
+    pub(super) fn push(&mut self, datagram: &[u8]) -> Sniffed {
+        let mut rest = datagram;
+        let mut found = false;
+        // Initial packets come first when coalesced with others
+        while let Some((dcid, payload, len)) = open_initial(rest) {
+            rest = &rest[len..];
+            if dcid != self.dcid {
+                self.dcid = dcid;
+                self.frames.clear();
+            }
+            let Some(frames) = crypto_frames(&payload) else {
+                return Sniffed::NotMatched;
+            };
+            for (offset, data) in frames {
+                if offset + data.len() > MAX_SNIFF_SIZE {
+                    return Sniffed::NotMatched;
+                }
+                self.frames.push((offset, data.to_vec()));
+            }
+            found = true;
+        }
+        if !found && self.frames.is_empty() {
+            return Sniffed::NotMatched;
+        }
+
+        let hello = self.assemble();
+        if hello.len() < 4 {
+            return Sniffed::NeedMore;
+        }
+        // ClientHello
+        if hello[0] != 0x01 {
+            return Sniffed::NotMatched;
+        }
+        let len = u32::from_be_bytes([0, hello[1], hello[2], hello[3]]) as usize;
+        if hello.len() < 4 + len {
+            return Sniffed::NeedMore;
+        }
+        match client_hello_sni(&hello[..4 + len]) {
+            Some(host) => Sniffed::Host(host),
+            None => Sniffed::NotMatched,
+        }
+    }
+
+    /// The CRYPTO stream from its start up to the first gap.
+    fn assemble(&mut self) -> Vec<u8> {
+        self.frames.sort_by_key(|x| x.0);
+        let mut stream = vec![];
+        for (offset, data) in self.frames.iter() {
+            if *offset > stream.len() {
+                break;
+            }
+            let end = offset + data.len();
+            if end > stream.len() {
+                stream.extend_from_slice(&data[stream.len() - offset..]);
+            }
+        }
+        stream
+    }
+}
+
+fn varint(r: &mut Reader) -> Option<usize> {
+    let first = r.u8()?;
+    let len = 1 << (first >> 6);
+    let mut v = (first & 0x3f) as u64;
+    for b in r.take(len - 1)? {
+        v = v << 8 | *b as u64;
+    }
+    usize::try_from(v).ok()
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// HKDF-Expand-Label of TLS 1.3, with an empty context.
+fn expand_label(prk: &hkdf::Prk, label: &[u8], out: &mut [u8]) -> Option<()> {
+    let len = (out.len() as u16).to_be_bytes();
+    let label_len = [(6 + label.len()) as u8];
+    let info: [&[u8]; 5] = [&len, &label_len, b"tls13 ", label, &[0]];
+    prk.expand(&info, Len(out.len())).ok()?.fill(out).ok()
+}
+
+/// The packet protection key, IV and header protection key of the client
+/// Initial packets to `dcid`.
+fn client_keys(
+    version: &Version,
+    dcid: &[u8],
+) -> Option<([u8; 16], [u8; 12], [u8; 16])> {
+    let initial = hkdf::Salt::new(hkdf::HKDF_SHA256, &version.salt).extract(dcid);
+    let mut secret = [0; 32];
+    expand_label(&initial, b"client in", &mut secret)?;
+    let client = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &secret);
+
+    let (mut key, mut iv, mut hp) = ([0; 16], [0; 12], [0; 16]);
+    expand_label(&client, version.key, &mut key)?;
+    expand_label(&client, version.iv, &mut iv)?;
+    expand_label(&client, version.hp, &mut hp)?;
+    Some((key, iv, hp))
+}
+
+/// Decrypt the client Initial packet at the start of `buf`, returning its
+/// destination connection ID, payload and length.
+fn open_initial(buf: &[u8]) -> Option<(Vec<u8>, Vec<u8>, usize)> {
+    let first = *buf.first()?;
+    // long header with the fixed bit
+    if first & 0xc0 != 0xc0 {
+        return None;
+    }
+    let mut r = Reader(&buf[1..]);
+    let v = r.take(4)?;
+    let version = version(u32::from_be_bytes([v[0], v[1], v[2], v[3]]))?;
+    if (first >> 4) & 0x03 != version.initial {
+        return None;
+    }
+    let n = r.u8()?;
+    if n > 20 {
+        return None;
+    }
+    let dcid = r.take(n)?;
+    let n = r.u8()?;
+    r.take(n)?;
+    // token
+    let n = varint(&mut r)?;
+    r.take(n)?;
+    let len = varint(&mut r)?;
+
+    let pn_offset = buf.len() - r.0.len();
+    let end = pn_offset.checked_add(len)?;
+    if end > buf.len() {
+        return None;
+    }
+    let (key, iv, hp) = client_keys(version, dcid)?;
+
+    // the sample starts as if the packet number were 4 bytes
+    let sample = buf.get(pn_offset + 4..pn_offset + 20)?;
+    let mask = HeaderProtectionKey::new(&aead::quic::AES_128, &hp)
+        .ok()?
+        .new_mask(sample)
+        .ok()?;
+    let first = first ^ (mask[0] & 0x0f);
+    let pn_len = (first & 0x03) as usize + 1;
+    if pn_offset + pn_len > end {
+        return None;
+    }
+
+    let mut header = buf[..pn_offset + pn_len].to_vec();
+    header[0] = first;
+    let mut nonce = iv;
+    for i in 0..pn_len {
+        header[pn_offset + i] ^= mask[1 + i];
+        nonce[12 - pn_len + i] ^= header[pn_offset + i];
+    }
+
+    let key = aead::LessSafeKey::new(
+        aead::UnboundKey::new(&aead::AES_128_GCM, &key).ok()?,
+    );
+    let mut payload = buf[pn_offset + pn_len..end].to_vec();
+    let n = key
+        .open_in_place(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&header),
+            &mut payload,
+        )
+        .ok()?
+        .len();
+    payload.truncate(n);
+    Some((dcid.to_vec(), payload, end))
+}
+
+/// The CRYPTO frames in an Initial packet payload, `None` if it holds a
+/// frame that can't be in one.
+fn crypto_frames(payload: &[u8]) -> Option<Vec<(usize, &[u8])>> {
+    let mut r = Reader(payload);
+    let mut frames = vec![];
+    while let Some(typ) = r.u8() {
+        match typ {
+            // PADDING, PING
+            0x00 | 0x01 => {}
+            // ACK
+            0x02 | 0x03 => {
+                // largest acknowledged, delay
+                varint(&mut r)?;
+                varint(&mut r)?;
+                let ranges = varint(&mut r)?;
+                varint(&mut r)?;
+                for _ in 0..ranges {
+                    varint(&mut r)?;
+                    varint(&mut r)?;
+                }
+                // ECN counts
+                if typ == 0x03 {
+                    for _ in 0..3 {
+                        varint(&mut r)?;
+                    }
+                }
+            }
+            // CRYPTO
+            0x06 => {
+                let offset = varint(&mut r)?;
+                let n = varint(&mut r)?;
+                frames.push((offset, r.take(n)?));
+            }
+            // CONNECTION_CLOSE
+            0x1c => {
+                varint(&mut r)?;
+                varint(&mut r)?;
+                let n = varint(&mut r)?;
+                r.take(n)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::aead::{self, quic::HeaderProtectionKey};
+
+    use super::{client_keys, CryptoStream, Version, V1, V2};
+    use crate::app::dispatcher::sniffer::{tests::client_hello, Sniffed};
+
+    /// A client Initial packet to `dcid` carrying `frames`.
+    fn initial(version: &Version, dcid: &[u8], pn: u8, frames: &[u8]) -> Vec<u8> {
+        let (key, iv, hp) = client_keys(version, dcid).unwrap();
+        let mut payload = frames.to_vec();
+        // padded as clients do, the sample needs some
+        payload.resize(payload.len().max(64), 0);
+
+        let v: u32 = if version.initial == 0 { 1 } else { 0x6b33_43cf };
+        // one byte packet number
+        let mut packet = vec![0xc0 | version.initial << 4];
+        packet.extend(v.to_be_bytes());
+        packet.push(dcid.len() as u8);
+        packet.extend(dcid);
+        packet.extend([0, 0]);
+        // two byte varint length, packet number and payload with its tag
+        packet.extend((0x4000 | (1 + payload.len() + 16) as u16).to_be_bytes());
+        let pn_offset = packet.len();
+        packet.push(pn);
+
+        let mut nonce = iv;
+        nonce[11] ^= pn;
+        let key = aead::LessSafeKey::new(
+            aead::UnboundKey::new(&aead::AES_128_GCM, &key).unwrap(),
+        );
+        key.seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(packet.clone()),
+            &mut payload,
+        )
+        .unwrap();
+        packet.extend(payload);
+
+        let mask = HeaderProtectionKey::new(&aead::quic::AES_128, &hp)
+            .unwrap()
+            .new_mask(&packet[pn_offset + 4..pn_offset + 20])
+            .unwrap();
+        packet[0] ^= mask[0] & 0x0f;
+        packet[pn_offset] ^= mask[1];
+        packet
+    }
+
+    fn crypto(offset: usize, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x06];
+        frame.extend((0x4000 | offset as u16).to_be_bytes());
+        frame.extend((0x4000 | data.len() as u16).to_be_bytes());
+        frame.extend(data);
+        frame
+    }
+
+    #[test]
+    fn test_client_keys() {
+        // RFC 9001 appendix A.1
+        let (key, iv, hp) =
+            client_keys(&V1, &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08])
+                .unwrap();
+        assert_eq!(
+            key,
+            [
+                0x1f, 0x36, 0x96, 0x13, 0xdd, 0x76, 0xd5, 0x46, 0x77, 0x30, 0xef,
+                0xcb, 0xe3, 0xb1, 0xa2, 0x2d
+            ]
+        );
+        assert_eq!(
+            iv,
+            [
+                0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25,
+                0x5c
+            ]
+        );
+        assert_eq!(
+            hp,
+            [
+                0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e,
+                0x99, 0x33, 0xad, 0xed, 0xd2
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sniff_quic() {
+        let hello = &client_hello("www.Example.com")[5..];
+        let dcid = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        for version in [&V1, &V2] {
+            let mut s = CryptoStream::default();
+            let packet = initial(version, &dcid, 0, &crypto(0, hello));
+            assert_eq!(s.push(&packet), Sniffed::Host("www.example.com".into()));
+        }
+
+        // split over two packets, shuffled within the first
+        let (a, rest) = hello.split_at(10);
+        let (b, c) = rest.split_at(20);
+        let first = [crypto(10, b), vec![0x01], crypto(0, a)].concat();
+        let mut s = CryptoStream::default();
+        assert_eq!(s.push(&initial(&V1, &dcid, 0, &first)), Sniffed::NeedMore);
+        assert_eq!(
+            s.push(&initial(&V1, &dcid, 1, &crypto(30, c))),
+            Sniffed::Host("www.example.com".into())
+        );
+
+        let mut s = CryptoStream::default();
+        assert_eq!(s.push(b"\x40short header"), Sniffed::NotMatched);
+        let mut tampered = initial(&V1, &dcid, 0, &crypto(0, hello));
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(s.push(&tampered), Sniffed::NotMatched);
+    }
+}
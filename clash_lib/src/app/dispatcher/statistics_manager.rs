@@ -1,17 +1,24 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     sync::{
-        atomic::{AtomicI64, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
         Arc,
     },
 };
 
 use chrono::Utc;
 use memory_stats::memory_stats;
-use serde::Serialize;
-use tokio::sync::{oneshot::Sender, Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot::Sender, watch, Mutex, RwLock};
+use tracing::{debug, info, warn};
 
-use crate::session::Session;
+use crate::{app::profile::ThreadSafeCacheFile, session::Session};
+
+use super::{
+    capture::{self, Capture, CaptureOptions, Direction},
+    shaper::Shaper,
+    timing::ConnectTimings,
+};
 
 use super::tracked::Tracked;
 
@@ -23,6 +30,23 @@ impl ProxyChain {
         let mut chain = self.0.write().await;
         chain.push(s);
     }
+
+    /// the outbound the connection actually goes through, groups come after it
+    pub async fn first(&self) -> Option<String> {
+        self.0.read().await.first().cloned()
+    }
+
+    /// The route from the rule's target to the outbound, relay hops included,
+    /// e.g. `PROXY → relay → hop → node`.
+    pub async fn path(&self) -> String {
+        let chain = self.0.read().await;
+        chain
+            .iter()
+            .rev()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" → ")
+    }
 }
 
 #[derive(Serialize, Default)]
@@ -35,6 +59,11 @@ pub struct TrackerInfo {
     pub upload_total: AtomicU64,
     #[serde(rename = "download")]
     pub download_total: AtomicU64,
+    /// bytes per second over the last second
+    #[serde(rename = "uploadSpeed")]
+    pub upload_speed: AtomicU64,
+    #[serde(rename = "downloadSpeed")]
+    pub download_speed: AtomicU64,
     #[serde(rename = "start")]
     pub start_time: chrono::DateTime<Utc>,
     #[serde(rename = "chains")]
@@ -43,11 +72,35 @@ pub struct TrackerInfo {
     pub rule: String,
     #[serde(rename = "rulePayload")]
     pub rule_payload: String,
+    /// of establishing a TCP connection
+    #[serde(rename = "timings", skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Arc<ConnectTimings>>,
 
     #[serde(skip)]
     pub proxy_chain_holder: ProxyChain,
     #[serde(skip)]
     pub session_holder: Session,
+    /// totals seen at the last speed sample
+    #[serde(skip)]
+    pub upload_sampled: AtomicU64,
+    #[serde(skip)]
+    pub download_sampled: AtomicU64,
+    /// started from the API
+    #[serde(skip)]
+    pub capture: std::sync::RwLock<Option<Arc<Capture>>>,
+    /// relayed by the kernel, set under the `capture` lock so it can't be
+    /// captured then
+    #[serde(skip)]
+    pub spliced: AtomicBool,
+}
+
+impl TrackerInfo {
+    /// add what's been relayed to the capture, if there is one
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        if let Some(capture) = self.capture.read().unwrap().as_ref() {
+            capture.record(direction, data);
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -57,8 +110,138 @@ pub struct Snapshot {
     upload_total: i64,
     connections: Vec<TrackerInfo>,
     memory: usize,
+    /// number of connections matched before pagination
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+}
+
+impl Snapshot {
+    pub fn upload_total(&self) -> i64 {
+        self.upload_total
+    }
+
+    pub fn download_total(&self) -> i64 {
+        self.download_total
+    }
+
+    pub fn connections(&self) -> &[TrackerInfo] {
+        &self.connections
+    }
+
+    pub fn connections_mut(&mut self) -> &mut Vec<TrackerInfo> {
+        &mut self.connections
+    }
+
+    pub fn set_total(&mut self, total: usize) {
+        self.total = Some(total);
+    }
+}
+
+async fn copy_of(t: &TrackerInfo) -> TrackerInfo {
+    let chain = t.proxy_chain_holder.0.read().await;
+    TrackerInfo {
+        uuid: t.uuid,
+        upload_total: AtomicU64::new(t.upload_total.load(Ordering::Acquire)),
+        download_total: AtomicU64::new(t.download_total.load(Ordering::Acquire)),
+        upload_speed: AtomicU64::new(t.upload_speed.load(Ordering::Relaxed)),
+        download_speed: AtomicU64::new(t.download_speed.load(Ordering::Relaxed)),
+        start_time: t.start_time,
+        proxy_chain: chain.clone(),
+        rule: t.rule.clone(),
+        rule_payload: t.rule_payload.clone(),
+        timings: t.timings.clone(),
+        session: t.session_holder.as_map(),
+        session_holder: t.session_holder.clone(),
+        proxy_chain_holder: t.proxy_chain_holder.clone(),
+        ..Default::default()
+    }
+}
+
+/// Whether a connection with `chain` went through `group` to another member
+/// than `selected`.
+fn switched_away(chain: &[String], group: &str, selected: &str) -> bool {
+    chain.windows(2).any(|x| x[1] == group && x[0] != selected)
+}
+
+/// A connection closed because the proxy it went through was removed by a
+/// reload or switched away from by a selector.
+#[derive(Serialize)]
+pub struct AbortedConnection {
+    #[serde(flatten)]
+    pub connection: TrackerInfo,
+    pub reason: String,
+    #[serde(rename = "abortedAt")]
+    pub aborted_at: chrono::DateTime<Utc>,
+}
+
+/// how many aborted connections are kept for the API
+const MAX_ABORTED: usize = 100;
+
+/// bytes relayed through a proxy or matched by a rule
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct TrafficTotal {
+    pub upload: AtomicU64,
+    pub download: AtomicU64,
+}
+
+impl TrafficTotal {
+    fn copy(&self) -> Self {
+        Self {
+            upload: AtomicU64::new(self.upload.load(Ordering::Relaxed)),
+            download: AtomicU64::new(self.download.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn add(&self, other: &TrafficTotal) {
+        self.upload
+            .fetch_add(other.upload.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.download
+            .fetch_add(other.download.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
 }
 
+/// The totals since the statistics were last reset, kept across restarts.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TrafficStats {
+    pub upload_total: i64,
+    pub download_total: i64,
+    pub proxies: HashMap<String, TrafficTotal>,
+    pub rules: HashMap<String, TrafficTotal>,
+    /// per authenticated user
+    #[serde(default)]
+    pub users: HashMap<String, TrafficTotal>,
+}
+
+/// The per proxy, per rule and per user totals a connection adds its bytes
+/// to, resolved once when the connection is tracked.
+pub struct Counters {
+    proxy: Arc<TrafficTotal>,
+    rule: Option<Arc<TrafficTotal>>,
+    user: Option<Arc<TrafficTotal>>,
+}
+
+impl Counters {
+    pub fn uploaded(&self, n: usize) {
+        self.proxy.upload.fetch_add(n as u64, Ordering::Relaxed);
+        for x in [&self.rule, &self.user].into_iter().flatten() {
+            x.upload.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn downloaded(&self, n: usize) {
+        self.proxy.download.fetch_add(n as u64, Ordering::Relaxed);
+        for x in [&self.rule, &self.user].into_iter().flatten() {
+            x.download.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// how often the totals are written to the cache store, in seconds
+const PERSIST_INTERVAL: u64 = 60;
+
+type TotalMap = std::sync::Mutex<HashMap<String, Arc<TrafficTotal>>>;
+
 type ConnectionMap = HashMap<uuid::Uuid, (Tracked, Sender<()>)>;
 
 pub struct Manager {
@@ -69,10 +252,19 @@ pub struct Manager {
     download_blip: AtomicI64,
     upload_total: AtomicI64,
     download_total: AtomicI64,
+    proxy_totals: TotalMap,
+    rule_totals: TotalMap,
+    user_totals: TotalMap,
+    cache_store: Option<ThreadSafeCacheFile>,
+    shaper: Shaper,
+    abort_stale: AtomicBool,
+    aborted: Mutex<VecDeque<AbortedConnection>>,
+    /// true once the saved totals were restored
+    restored: watch::Sender<bool>,
 }
 
 impl Manager {
-    pub fn new() -> Arc<Self> {
+    pub fn new(cache_store: Option<ThreadSafeCacheFile>) -> Arc<Self> {
         let v = Arc::new(Self {
             connections: Arc::new(Mutex::new(HashMap::new())),
             upload_temp: AtomicI64::new(0),
@@ -81,6 +273,14 @@ impl Manager {
             download_blip: AtomicI64::new(0),
             upload_total: AtomicI64::new(0),
             download_total: AtomicI64::new(0),
+            proxy_totals: Default::default(),
+            rule_totals: Default::default(),
+            user_totals: Default::default(),
+            cache_store,
+            shaper: Shaper::default(),
+            abort_stale: AtomicBool::new(false),
+            aborted: Default::default(),
+            restored: watch::channel(false).0,
         });
         let c = v.clone();
         tokio::spawn(async move {
@@ -126,6 +326,78 @@ impl Manager {
         }
     }
 
+    /// whether `abort_stale` closes anything, from `abort-stale-connections`
+    pub fn set_abort_stale(&self, enabled: bool) {
+        self.abort_stale.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Close the connections whose proxy chain `stale` says goes through a
+    /// proxy that's gone, if enabled, and keep them for the API with
+    /// `reason`. Returns how many were closed.
+    pub async fn abort_stale<F>(&self, reason: &str, stale: F) -> usize
+    where
+        F: Fn(&[String]) -> bool,
+    {
+        if !self.abort_stale.load(Ordering::Relaxed) {
+            return 0;
+        }
+
+        let mut aborted = vec![];
+        let mut connections = self.connections.lock().await;
+        for (id, (tracked, _)) in connections.iter() {
+            let t = tracked.tracker_info();
+            if stale(&t.proxy_chain_holder.0.read().await) {
+                aborted.push((*id, copy_of(&t).await));
+            }
+        }
+        for (id, _) in aborted.iter() {
+            if let Some((_, close_notify)) = connections.remove(id) {
+                let _ = close_notify.send(());
+            }
+        }
+        drop(connections);
+
+        let n = aborted.len();
+        if n > 0 {
+            info!("aborted {} connections: {}", n, reason);
+            let now = Utc::now();
+            let mut list = self.aborted.lock().await;
+            for (_, connection) in aborted {
+                if list.len() == MAX_ABORTED {
+                    list.pop_front();
+                }
+                list.push_back(AbortedConnection {
+                    connection,
+                    reason: reason.to_owned(),
+                    aborted_at: now,
+                });
+            }
+        }
+        n
+    }
+
+    /// abort the connections `group` sent through another member than
+    /// `selected`, once it's been switched to it
+    pub async fn abort_switched_away(&self, group: &str, selected: &str) -> usize {
+        let reason = format!("{} switched to {}", group, selected);
+        self.abort_stale(&reason, |chain| switched_away(chain, group, selected))
+            .await
+    }
+
+    /// the most recently aborted connections, oldest first
+    pub async fn aborted(&self) -> Vec<AbortedConnection> {
+        let list = self.aborted.lock().await;
+        let mut out = Vec::with_capacity(list.len());
+        for x in list.iter() {
+            out.push(AbortedConnection {
+                connection: copy_of(&x.connection).await,
+                reason: x.reason.clone(),
+                aborted_at: x.aborted_at,
+            });
+        }
+        out
+    }
+
     pub fn push_uploaded(&self, n: usize) {
         self.upload_temp
             .fetch_add(n as i64, std::sync::atomic::Ordering::Relaxed);
@@ -153,21 +425,7 @@ impl Manager {
         let mut connections = vec![];
         let conns = self.connections.lock().await;
         for (_, v) in conns.iter() {
-            let t = v.0.tracker_info();
-            let chain = t.proxy_chain_holder.0.read().await;
-            connections.push(TrackerInfo {
-                uuid: t.uuid,
-                upload_total: AtomicU64::new(t.upload_total.load(Ordering::Acquire)),
-                download_total: AtomicU64::new(
-                    t.download_total.load(Ordering::Acquire),
-                ),
-                start_time: t.start_time,
-                proxy_chain: chain.clone(),
-                rule: t.rule.clone(),
-                rule_payload: t.rule_payload.clone(),
-                session: t.session_holder.as_map(),
-                ..Default::default()
-            });
+            connections.push(copy_of(&v.0.tracker_info()).await);
         }
 
         Snapshot {
@@ -179,10 +437,12 @@ impl Manager {
                 .load(std::sync::atomic::Ordering::Relaxed),
             connections,
             memory: self.memory_usage(),
+            total: None,
         }
     }
 
-    #[allow(dead_code)]
+    /// Zero the totals. Those of open connections are reset in place, for
+    /// their `Counters` to keep counting, the others are dropped.
     pub fn reset_statistic(&self) {
         self.upload_temp.store(0, Ordering::Relaxed);
         self.upload_blip.store(0, Ordering::Relaxed);
@@ -190,6 +450,171 @@ impl Manager {
         self.download_temp.store(0, Ordering::Relaxed);
         self.download_blip.store(0, Ordering::Relaxed);
         self.download_total.store(0, Ordering::Relaxed);
+        for m in [&self.proxy_totals, &self.rule_totals, &self.user_totals] {
+            m.lock().unwrap().retain(|_, v| {
+                v.upload.store(0, Ordering::Relaxed);
+                v.download.store(0, Ordering::Relaxed);
+                Arc::strong_count(v) > 1
+            });
+        }
+    }
+
+    /// The counters for a connection through `proxy`, matched by `rule` and
+    /// of the authenticated `user` if any.
+    pub fn counters(
+        &self,
+        proxy: &str,
+        rule: Option<&str>,
+        user: Option<&str>,
+    ) -> Counters {
+        let total = |m: &TotalMap, key: &str| {
+            m.lock().unwrap().entry(key.to_owned()).or_default().clone()
+        };
+        Counters {
+            proxy: total(&self.proxy_totals, proxy),
+            rule: rule.map(|rule| total(&self.rule_totals, rule)),
+            user: user.map(|user| total(&self.user_totals, user)),
+        }
+    }
+
+    pub fn traffic_stats(&self) -> TrafficStats {
+        let copy = |m: &TotalMap| {
+            m.lock()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (k.clone(), v.copy()))
+                .collect()
+        };
+        TrafficStats {
+            upload_total: self.upload_total.load(Ordering::Relaxed),
+            download_total: self.download_total.load(Ordering::Relaxed),
+            proxies: copy(&self.proxy_totals),
+            rules: copy(&self.rule_totals),
+            users: copy(&self.user_totals),
+        }
+    }
+
+    /// add the totals saved by the last run
+    async fn restore(&self) {
+        let Some(store) = &self.cache_store else {
+            return;
+        };
+        let Some(saved) = store.get_traffic_stats::<TrafficStats>().await else {
+            return;
+        };
+        debug!("restoring traffic statistics");
+        self.upload_total
+            .fetch_add(saved.upload_total, Ordering::Relaxed);
+        self.download_total
+            .fetch_add(saved.download_total, Ordering::Relaxed);
+        for (m, saved) in [
+            (&self.proxy_totals, saved.proxies),
+            (&self.rule_totals, saved.rules),
+            (&self.user_totals, saved.users),
+        ] {
+            let mut m = m.lock().unwrap();
+            for (k, v) in saved {
+                m.entry(k).or_default().add(&v);
+            }
+        }
+    }
+
+    /// Wait for the totals saved by the last run to be added.
+    pub async fn restored(&self) {
+        let _ = self.restored.subscribe().wait_for(|x| *x).await;
+    }
+
+    /// Save the totals, once the saved ones were restored so they aren't
+    /// overwritten.
+    pub async fn persist(&self) {
+        self.restored().await;
+        if let Some(store) = &self.cache_store {
+            store.set_traffic_stats(&self.traffic_stats()).await;
+        }
+    }
+
+    /// a copy of the connection with the given id, as found in a snapshot
+    pub async fn get(&self, id: uuid::Uuid) -> Option<TrackerInfo> {
+        let t = self.connections.lock().await.get(&id)?.0.tracker_info();
+        Some(copy_of(&t).await)
+    }
+
+    /// Capture what connection `id` relays from now on to
+    /// `dir/<id>.pcapng`, replacing an earlier capture of it. Fails with
+    /// `Unsupported` for a connection relayed by the kernel, and keeps only
+    /// the newest `MAX_CAPTURES` files in `dir`.
+    pub async fn start_capture(
+        &self,
+        id: uuid::Uuid,
+        dir: &std::path::Path,
+        options: CaptureOptions,
+    ) -> std::io::Result<std::path::PathBuf> {
+        let t = match self.connections.lock().await.get(&id) {
+            Some(x) => x.0.tracker_info(),
+            None => return Err(std::io::ErrorKind::NotFound.into()),
+        };
+        let sess = &t.session_holder;
+        let description = format!(
+            "{} {} {} -> {} via {}",
+            sess.network,
+            sess.inbound_name,
+            sess.source,
+            sess.destination,
+            t.proxy_chain_holder.path().await,
+        );
+
+        if t.spliced.load(Ordering::Acquire) {
+            return Err(std::io::ErrorKind::Unsupported.into());
+        }
+
+        tokio::fs::create_dir_all(dir).await?;
+        let path = dir.join(format!("{}.pcapng", id));
+        let capture = Arc::new(Capture::create(&path, &description, options).await?);
+        {
+            let mut current = t.capture.write().unwrap();
+            if t.spliced.load(Ordering::Acquire) {
+                capture.finish();
+                return Err(std::io::ErrorKind::Unsupported.into());
+            }
+            *current = Some(capture);
+        }
+        if let Err(e) = capture::prune(dir, capture::MAX_CAPTURES).await {
+            warn!("failed to remove old captures in {}: {}", dir.display(), e);
+        }
+        Ok(path)
+    }
+
+    /// Stop capturing connection `id`, returns whether it was captured.
+    pub async fn stop_capture(&self, id: uuid::Uuid) -> bool {
+        let Some(t) = self
+            .connections
+            .lock()
+            .await
+            .get(&id)
+            .map(|x| x.0.tracker_info())
+        else {
+            return false;
+        };
+        let capture = t.capture.write().unwrap().take();
+        match capture {
+            Some(capture) => {
+                capture.finish();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn shaper(&self) -> &Shaper {
+        &self.shaper
+    }
+
+    pub async fn connection_count(&self) -> usize {
+        self.connections.lock().await.len()
+    }
+
+    pub async fn connection_ids(&self) -> Vec<uuid::Uuid> {
+        self.connections.lock().await.keys().copied().collect()
     }
 
     pub fn memory_usage(&self) -> usize {
@@ -197,9 +622,18 @@ impl Manager {
     }
 
     async fn kick_off(&self) {
+        self.restore().await;
+        self.restored.send_replace(true);
+
         let mut ticker = tokio::time::interval(std::time::Duration::from_secs(1));
+        let mut ticks = 0u64;
         loop {
             ticker.tick().await;
+            ticks += 1;
+            if ticks == PERSIST_INTERVAL {
+                ticks = 0;
+                self.persist().await;
+            }
             self.upload_blip
                 .store(self.upload_temp.load(Ordering::Relaxed), Ordering::Relaxed);
             self.upload_temp.store(0, Ordering::Relaxed);
@@ -208,6 +642,247 @@ impl Manager {
                 Ordering::Relaxed,
             );
             self.download_temp.store(0, Ordering::Relaxed);
+
+            self.sample_speed().await;
         }
     }
+
+    /// update the per connection speeds from the totals since the last call
+    async fn sample_speed(&self) {
+        let conns = self.connections.lock().await;
+        for (_, v) in conns.iter() {
+            let t = v.0.tracker_info();
+            let up = t.upload_total.load(Ordering::Acquire);
+            let down = t.download_total.load(Ordering::Acquire);
+            t.upload_speed.store(
+                up.saturating_sub(t.upload_sampled.swap(up, Ordering::Relaxed)),
+                Ordering::Relaxed,
+            );
+            t.download_speed.store(
+                down.saturating_sub(
+                    t.download_sampled.swap(down, Ordering::Relaxed),
+                ),
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::{
+            dispatcher::tracked::{
+                ChainedStream, ChainedStreamWrapper, TrackedStream,
+            },
+            profile::ThreadSafeCacheFile,
+        },
+        session::Session,
+    };
+
+    use super::{switched_away, Manager, ProxyChain};
+
+    #[tokio::test]
+    async fn test_chain_path() {
+        let chain = ProxyChain::default();
+        for name in ["node", "hop", "relay", "PROXY"] {
+            chain.push(name.to_owned()).await;
+        }
+        assert_eq!(chain.first().await.as_deref(), Some("node"));
+        assert_eq!(chain.path().await, "PROXY → relay → hop → node");
+    }
+
+    #[tokio::test]
+    async fn test_track_metadata() {
+        let manager = Manager::new(None);
+        let (stream, _peer) = tokio::io::duplex(64);
+        let stream = Box::new(ChainedStreamWrapper::new(stream));
+        stream.append_to_chain("ss01").await;
+
+        let sess = Session {
+            source: "192.168.1.2:50000".parse().unwrap(),
+            destination: ("example.com".to_owned(), 443).try_into().unwrap(),
+            inbound_name: "Mixed".to_owned(),
+            sniff_host: Some("www.example.com".to_owned()),
+            ..Default::default()
+        };
+        let tracked = TrackedStream::new(
+            uuid::Uuid::new_v4(),
+            stream,
+            manager.clone(),
+            sess,
+            None,
+            Default::default(),
+        )
+        .await;
+
+        let ids = manager.connection_ids().await;
+        assert_eq!(ids.len(), 1);
+        let c = manager.get(ids[0]).await.unwrap();
+        assert_eq!(c.proxy_chain, vec!["ss01"]);
+
+        let v = serde_json::to_value(&c).unwrap();
+        assert_eq!(v["metadata"]["inboundName"], "Mixed");
+        assert_eq!(v["metadata"]["sniffHost"], "www.example.com");
+        assert_eq!(v["metadata"]["host"], "example.com");
+        assert_eq!(v["metadata"]["sourcePort"], 50000);
+
+        drop(tracked);
+        tokio::task::yield_now().await;
+        assert!(manager.get(ids[0]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_track_timings() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let manager = Manager::new(None);
+        let (stream, mut peer) = tokio::io::duplex(64);
+        let stream = Box::new(ChainedStreamWrapper::new(stream));
+        stream.append_to_chain("ss01").await;
+        let mut tracked = TrackedStream::new(
+            uuid::Uuid::new_v4(),
+            stream,
+            manager.clone(),
+            Session::default(),
+            None,
+            Default::default(),
+        )
+        .await;
+
+        let id = manager.connection_ids().await[0];
+        let manager = &manager;
+        let timings = || async move {
+            let c = manager.get(id).await.unwrap();
+            serde_json::to_value(&c).unwrap()["timings"].clone()
+        };
+        let v = timings().await;
+        assert!(v["handshake"].is_number());
+        assert!(v["firstByte"].is_null());
+
+        peer.write_all(b"hello").await.unwrap();
+        tracked.read_exact(&mut [0u8; 5]).await.unwrap();
+        assert!(timings().await["firstByte"].is_number());
+    }
+
+    #[tokio::test]
+    async fn test_abort_stale() {
+        let chain = ["node", "hop", "relay", "PROXY"].map(str::to_owned);
+        assert!(!switched_away(&chain, "PROXY", "relay"));
+        assert!(switched_away(&chain, "PROXY", "other"));
+        assert!(!switched_away(&chain, "node", "other"));
+
+        let manager = Manager::new(None);
+        let mut tracked = vec![];
+        for chain in [["a", "PROXY"], ["b", "PROXY"]] {
+            let (stream, _peer) = tokio::io::duplex(64);
+            let stream = Box::new(ChainedStreamWrapper::new(stream));
+            for name in chain {
+                stream.append_to_chain(name).await;
+            }
+            tracked.push(
+                TrackedStream::new(
+                    uuid::Uuid::new_v4(),
+                    stream,
+                    manager.clone(),
+                    Session::default(),
+                    None,
+                    Default::default(),
+                )
+                .await,
+            );
+        }
+
+        // off unless configured
+        assert_eq!(manager.abort_switched_away("PROXY", "b").await, 0);
+        manager.set_abort_stale(true);
+        assert_eq!(manager.abort_switched_away("PROXY", "b").await, 1);
+
+        let ids = manager.connection_ids().await;
+        assert_eq!(ids.len(), 1);
+        let left = manager.get(ids[0]).await.unwrap();
+        assert_eq!(left.proxy_chain, vec!["b", "PROXY"]);
+
+        let aborted = manager.aborted().await;
+        assert_eq!(aborted.len(), 1);
+        assert_eq!(aborted[0].connection.proxy_chain, vec!["a", "PROXY"]);
+        assert_eq!(aborted[0].reason, "PROXY switched to b");
+        let v = serde_json::to_value(&aborted[0]).unwrap();
+        assert_eq!(v["chains"][0], "a");
+        assert_eq!(v["reason"], "PROXY switched to b");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_traffic_stats() {
+        use std::{sync::atomic::Ordering::Relaxed, time::Duration};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let store = ThreadSafeCacheFile::new(path.to_str().unwrap(), false);
+
+        let manager = Manager::new(Some(store.clone()));
+        // the saved totals are restored on the task of the manager
+        tokio::time::timeout(Duration::from_secs(1), manager.restored())
+            .await
+            .unwrap();
+        let counters = manager.counters(
+            "ss01",
+            Some("DOMAIN-SUFFIX,google.com"),
+            Some("alice"),
+        );
+        counters.uploaded(10);
+        counters.downloaded(20);
+        manager.counters("DIRECT", None, None).downloaded(5);
+        manager.push_uploaded(10);
+        manager.persist().await;
+
+        // a restart adds up the saved totals
+        let manager = Manager::new(Some(store));
+        tokio::time::timeout(Duration::from_secs(1), manager.restored())
+            .await
+            .unwrap();
+        manager.counters("ss01", None, Some("alice")).uploaded(1);
+        let stats = manager.traffic_stats();
+        assert_eq!(stats.upload_total, 10);
+        assert_eq!(stats.proxies["ss01"].upload.load(Relaxed), 11);
+        assert_eq!(stats.proxies["ss01"].download.load(Relaxed), 20);
+        assert_eq!(stats.proxies["DIRECT"].download.load(Relaxed), 5);
+        assert_eq!(
+            stats.rules["DOMAIN-SUFFIX,google.com"]
+                .download
+                .load(Relaxed),
+            20
+        );
+        assert_eq!(stats.users["alice"].upload.load(Relaxed), 11);
+        assert_eq!(stats.users["alice"].download.load(Relaxed), 20);
+
+        manager.reset_statistic();
+        let stats = manager.traffic_stats();
+        assert_eq!(stats.upload_total, 0);
+        assert!(stats.proxies.is_empty());
+        assert!(stats.users.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reset_open_connection() {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        let manager = Manager::new(None);
+        let counters = manager.counters("ss01", Some("MATCH"), Some("alice"));
+        counters.uploaded(10);
+        manager.counters("DIRECT", None, None).uploaded(5);
+
+        manager.reset_statistic();
+        let stats = manager.traffic_stats();
+        assert_eq!(stats.proxies["ss01"].upload.load(Relaxed), 0);
+        // no connection counts to it anymore
+        assert!(!stats.proxies.contains_key("DIRECT"));
+
+        // an open connection keeps counting to the same totals
+        counters.uploaded(3);
+        let stats = manager.traffic_stats();
+        assert_eq!(stats.proxies["ss01"].upload.load(Relaxed), 3);
+        assert_eq!(stats.rules["MATCH"].upload.load(Relaxed), 3);
+        assert_eq!(stats.users["alice"].upload.load(Relaxed), 3);
+    }
 }
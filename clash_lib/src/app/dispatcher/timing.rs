@@ -0,0 +1,134 @@
+//! How long each phase of establishing a connection took, to tell whether
+//! slowness comes from DNS, the node or the protocol stack.
+//!
+//! The phases are timed by `common::timing`, this adds the wait for the
+//! first byte once established, and reports them all to the metrics.
+
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::OnceCell;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+
+use crate::{
+    app::metrics,
+    common::timing::{ConnectPhase, PhaseDurations},
+};
+
+fn label(phase: ConnectPhase) -> &'static str {
+    match phase {
+        ConnectPhase::RuleMatch => "rule_match",
+        ConnectPhase::Dns => "dns",
+        ConnectPhase::Connect => "connect",
+        ConnectPhase::Handshake => "handshake",
+    }
+}
+
+fn key(phase: ConnectPhase) -> &'static str {
+    match phase {
+        ConnectPhase::RuleMatch => "ruleMatch",
+        ConnectPhase::Dns => "dns",
+        ConnectPhase::Connect => "connect",
+        ConnectPhase::Handshake => "handshake",
+    }
+}
+
+#[derive(Default)]
+pub struct ConnectTimings {
+    phases: Arc<PhaseDurations>,
+    /// when the connection was established, and through which proxy
+    established: OnceCell<(Instant, String)>,
+    /// from then to the first byte from the remote
+    first_byte: OnceCell<Duration>,
+}
+
+impl ConnectTimings {
+    /// Run `fut` for the connection as `phase`, see `PhaseDurations::run`.
+    pub async fn run<F: Future>(&self, phase: ConnectPhase, fut: F) -> F::Output {
+        self.phases.run(phase, fut).await
+    }
+
+    pub fn phase(&self, phase: ConnectPhase) -> Duration {
+        self.phases.get(phase)
+    }
+
+    /// The wait for the first byte from the remote, once it came.
+    pub fn first_byte(&self) -> Option<Duration> {
+        self.first_byte.get().copied()
+    }
+
+    /// The connection is established through `proxy`, the phases so far go
+    /// to the metrics.
+    pub fn established(&self, proxy: &str) {
+        if self
+            .established
+            .set((Instant::now(), proxy.to_owned()))
+            .is_ok()
+        {
+            for phase in ConnectPhase::ALL {
+                metrics::connect_phase(proxy, label(phase), self.phase(phase));
+            }
+        }
+    }
+
+    /// Some bytes came from the remote, the first ones time the wait for
+    /// them.
+    pub fn received(&self) {
+        if self.first_byte.get().is_some() {
+            return;
+        }
+        if let Some((at, proxy)) = self.established.get() {
+            let elapsed = at.elapsed();
+            if self.first_byte.set(elapsed).is_ok() {
+                metrics::connect_phase(proxy, "first_byte", elapsed);
+            }
+        }
+    }
+}
+
+/// milliseconds of each phase, `firstByte` null until it came
+impl Serialize for ConnectTimings {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ms = |x: Duration| x.as_secs_f64() * 1000.0;
+        let mut map = serializer.serialize_map(Some(ConnectPhase::ALL.len() + 1))?;
+        for phase in ConnectPhase::ALL {
+            map.serialize_entry(key(phase), &ms(self.phase(phase)))?;
+        }
+        map.serialize_entry("firstByte", &self.first_byte().map(ms))?;
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::common::timing::{record_phase, ConnectPhase};
+
+    use super::ConnectTimings;
+
+    #[tokio::test]
+    async fn test_timings() {
+        let timings = ConnectTimings::default();
+        timings
+            .run(ConnectPhase::Dns, async {
+                record_phase(ConnectPhase::Connect, Duration::from_secs(1));
+            })
+            .await;
+
+        let v = serde_json::to_value(&timings).unwrap();
+        assert_eq!(v["connect"], 1000.0);
+        assert!(v["firstByte"].is_null());
+
+        // only once established
+        timings.received();
+        assert!(timings.first_byte().is_none());
+        timings.established("DIRECT");
+        timings.received();
+        assert!(timings.first_byte().is_some());
+        assert!(serde_json::to_value(&timings).unwrap()["firstByte"].is_number());
+    }
+}
@@ -1,22 +1,50 @@
-use std::{fmt::Debug, pin::Pin, sync::Arc, task::Poll};
+use std::{
+    any::Any,
+    fmt::Debug,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Poll},
+};
 
 use async_trait::async_trait;
 use futures::{Sink, Stream};
 use hyper::client::connect::{Connected, Connection};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
     sync::oneshot::{error::TryRecvError, Receiver},
 };
 use tracing::debug;
 
 use crate::{
-    app::router::RuleMatcher, proxy::datagram::UdpPacket, session::Session,
+    app::{metrics, router::RuleMatcher},
+    proxy::datagram::UdpPacket,
+    session::Session,
 };
 
-use super::statistics_manager::{Manager, ProxyChain, TrackerInfo};
+use super::{
+    capture::Direction,
+    shaper::Throttle,
+    statistics_manager::{Counters, Manager, ProxyChain, TrackerInfo},
+    timing::ConnectTimings,
+};
 
 pub struct Tracked(uuid::Uuid, Arc<TrackerInfo>);
 
+/// How often a spliced connection checks for bandwidth limits set since.
+#[cfg(target_os = "linux")]
+const LIMITS_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// the key of a rule in the traffic statistics, e.g. `DOMAIN-SUFFIX,google.com`
+fn rule_key(rule: &dyn RuleMatcher) -> String {
+    let payload = rule.payload();
+    if payload.is_empty() {
+        rule.type_name().to_owned()
+    } else {
+        format!("{},{}", rule.type_name(), payload)
+    }
+}
+
 impl Tracked {
     pub fn id(&self) -> uuid::Uuid {
         self.0
@@ -33,6 +61,11 @@ pub trait ChainedStream:
 {
     fn chain(&self) -> &ProxyChain;
     async fn append_to_chain(&self, name: &str);
+
+    /// the socket underneath, if this is a plain TCP connection
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        None
+    }
 }
 
 impl Connection for BoxedChainedStream {
@@ -61,7 +94,7 @@ impl<T> ChainedStreamWrapper<T> {
 #[async_trait]
 impl<T> ChainedStream for ChainedStreamWrapper<T>
 where
-    T: AsyncRead + AsyncWrite + Unpin + Debug + Send + Sync,
+    T: AsyncRead + AsyncWrite + Unpin + Debug + Send + Sync + 'static,
 {
     fn chain(&self) -> &ProxyChain {
         &self.chain
@@ -70,6 +103,10 @@ where
     async fn append_to_chain(&self, name: &str) {
         self.chain.push(name.to_owned()).await;
     }
+
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        (&self.inner as &dyn Any).downcast_ref()
+    }
 }
 
 impl<T> AsyncRead for ChainedStreamWrapper<T>
@@ -116,22 +153,39 @@ pub struct TrackedStream {
     inner: BoxedChainedStream,
     manager: Arc<Manager>,
     tracker: Arc<TrackerInfo>,
+    traffic: metrics::Traffic,
+    counters: Counters,
+    throttle: Throttle,
     close_notify: Receiver<()>,
 }
 
 impl TrackedStream {
     #[allow(clippy::borrowed_box)]
     pub async fn new(
+        uuid: uuid::Uuid,
         inner: BoxedChainedStream,
         manager: Arc<Manager>,
         sess: Session,
         rule: Option<&Box<dyn RuleMatcher>>,
+        timings: Arc<ConnectTimings>,
     ) -> Self {
-        let uuid = uuid::Uuid::new_v4();
         let chain = inner.chain().clone();
+        let proxy = chain.first().await.unwrap_or_default();
+        let traffic = metrics::Traffic::new(&proxy);
+        let counters = manager.counters(
+            &proxy,
+            rule.map(|x| rule_key(x.as_ref())).as_deref(),
+            sess.inbound_user.as_deref(),
+        );
+        metrics::connection_opened("tcp");
+        timings.established(&proxy);
+        let throttle = manager.shaper().throttle(&proxy, sess.source.ip());
         let (tx, rx) = tokio::sync::oneshot::channel();
         let s = Self {
             inner,
+            traffic,
+            counters,
+            throttle,
             manager: manager.clone(),
             tracker: Arc::new(TrackerInfo {
                 uuid,
@@ -146,6 +200,7 @@ impl TrackedStream {
                     .map(|x| x.payload().to_owned())
                     .unwrap_or_default(),
                 proxy_chain_holder: chain.clone(),
+                timings: Some(timings),
                 ..Default::default()
             }),
             close_notify: rx,
@@ -163,6 +218,107 @@ impl TrackedStream {
     fn tracker_info(&self) -> Arc<TrackerInfo> {
         self.tracker.clone()
     }
+
+    fn received(&self) {
+        if let Some(timings) = &self.tracker.timings {
+            timings.received();
+        }
+    }
+
+    /// Relay between `lhs` and the outbound socket in the kernel. Returns
+    /// `None` if the outbound isn't a plain TCP connection, bandwidth limits
+    /// are configured or the connection is captured, the caller falls back
+    /// to copying then. Limits set while splicing are checked every
+    /// `LIMITS_CHECK_INTERVAL`, splicing stops and `None` is returned for
+    /// the caller to copy the rest through the shaper.
+    /// Once spliced, the connection can't be captured.
+    #[cfg(target_os = "linux")]
+    pub async fn splice(
+        &mut self,
+        lhs: &TcpStream,
+        idle_timeout: Option<std::time::Duration>,
+    ) -> Option<Result<(u64, u64), crate::common::io::CopyBidirectionalError>> {
+        if !self.manager.shaper().is_unlimited() {
+            return None;
+        }
+        self.inner.tcp_stream()?;
+        {
+            let capture = self.tracker.capture.read().unwrap();
+            if capture.is_some() {
+                return None;
+            }
+            self.tracker
+                .spliced
+                .store(true, std::sync::atomic::Ordering::Release);
+        }
+        let Self {
+            inner,
+            manager,
+            tracker,
+            traffic,
+            counters,
+            close_notify,
+            ..
+        } = self;
+        let rhs = inner.tcp_stream()?;
+
+        debug!("splicing connection: {}", tracker.uuid);
+        let uploaded = |n| {
+            manager.push_uploaded(n);
+            traffic.upload.inc_by(n as u64);
+            counters.uploaded(n);
+            tracker
+                .upload_total
+                .fetch_add(n as u64, std::sync::atomic::Ordering::Release);
+        };
+        let downloaded = |n| {
+            if let Some(timings) = &tracker.timings {
+                timings.received();
+            }
+            manager.push_downloaded(n);
+            traffic.download.inc_by(n as u64);
+            counters.downloaded(n);
+            tracker
+                .download_total
+                .fetch_add(n as u64, std::sync::atomic::Ordering::Release);
+        };
+        let relay = crate::common::splice::copy_bidirectional(
+            lhs,
+            rhs,
+            &uploaded,
+            &downloaded,
+            std::time::Duration::from_secs(10),
+            idle_timeout,
+            async {
+                loop {
+                    tokio::time::sleep(LIMITS_CHECK_INTERVAL).await;
+                    if !manager.shaper().is_unlimited() {
+                        break;
+                    }
+                }
+            },
+        );
+
+        tokio::select! {
+            r = relay => match r {
+                Ok(Some(n)) => Some(Ok(n)),
+                Ok(None) => {
+                    debug!("bandwidth limited, splicing stopped: {}", tracker.uuid);
+                    tracker
+                        .spliced
+                        .store(false, std::sync::atomic::Ordering::Release);
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            },
+            _ = close_notify => {
+                debug!("connection closed by sig: {}", tracker.uuid);
+                Some(Err(crate::common::io::CopyBidirectionalError::RightClosed(
+                    std::io::ErrorKind::BrokenPipe.into(),
+                )))
+            }
+        }
+    }
 }
 
 impl Drop for TrackedStream {
@@ -192,9 +348,21 @@ impl AsyncRead for TrackedStream {
             },
         }
 
+        ready!(self.throttle.poll_download(cx));
+        let before = buf.filled().len();
         let v = Pin::new(self.inner.as_mut()).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            self.received();
+        }
+        self.tracker
+            .record(Direction::Download, &buf.filled()[before..]);
         let download = buf.filled().len();
         self.manager.push_downloaded(download);
+        self.traffic.download.inc_by(download as u64);
+        self.counters.downloaded(download);
+        if download > 0 {
+            self.throttle.downloaded(download);
+        }
         self.tracker
             .download_total
             .fetch_add(download as u64, std::sync::atomic::Ordering::Release);
@@ -219,12 +387,17 @@ impl AsyncWrite for TrackedStream {
             },
         }
 
+        ready!(self.throttle.poll_upload(cx));
         let v = Pin::new(self.inner.as_mut()).poll_write(cx, buf);
         let upload = match v {
             Poll::Ready(Ok(n)) => n,
             _ => return v,
         };
+        self.tracker.record(Direction::Upload, &buf[..upload]);
         self.manager.push_uploaded(upload);
+        self.traffic.upload.inc_by(upload as u64);
+        self.counters.uploaded(upload);
+        self.throttle.uploaded(upload);
         self.tracker
             .upload_total
             .fetch_add(upload as u64, std::sync::atomic::Ordering::Release);
@@ -356,22 +529,37 @@ pub struct TrackedDatagram {
     inner: BoxedChainedDatagram,
     manager: Arc<Manager>,
     tracker: Arc<TrackerInfo>,
+    traffic: metrics::Traffic,
+    counters: Counters,
+    throttle: Throttle,
     close_notify: Receiver<()>,
 }
 
 impl TrackedDatagram {
     #[allow(clippy::borrowed_box)]
     pub async fn new(
+        uuid: uuid::Uuid,
         inner: BoxedChainedDatagram,
         manager: Arc<Manager>,
         sess: Session,
         rule: Option<&Box<dyn RuleMatcher>>,
     ) -> Self {
-        let uuid = uuid::Uuid::new_v4();
         let chain = inner.chain().clone();
+        let proxy = chain.first().await.unwrap_or_default();
+        let traffic = metrics::Traffic::new(&proxy);
+        let counters = manager.counters(
+            &proxy,
+            rule.map(|x| rule_key(x.as_ref())).as_deref(),
+            sess.inbound_user.as_deref(),
+        );
+        metrics::connection_opened("udp");
+        let throttle = manager.shaper().throttle(&proxy, sess.source.ip());
         let (tx, rx) = tokio::sync::oneshot::channel();
         let s = Self {
             inner,
+            traffic,
+            counters,
+            throttle,
             manager: manager.clone(),
             tracker: Arc::new(TrackerInfo {
                 uuid,
@@ -427,9 +615,14 @@ impl Stream for TrackedDatagram {
             },
         }
 
+        ready!(self.throttle.poll_download(cx));
         let r = Pin::new(self.inner.as_mut()).poll_next(cx);
         if let Poll::Ready(Some(ref pkt)) = r {
+            self.tracker.record(Direction::Download, &pkt.data);
             self.manager.push_downloaded(pkt.data.len());
+            self.traffic.download.inc_by(pkt.data.len() as u64);
+            self.counters.downloaded(pkt.data.len());
+            self.throttle.downloaded(pkt.data.len());
             self.tracker.download_total.fetch_add(
                 pkt.data.len() as u64,
                 std::sync::atomic::Ordering::Relaxed,
@@ -455,6 +648,7 @@ impl Sink<UdpPacket> for TrackedDatagram {
                 }
             },
         }
+        ready!(self.throttle.poll_upload(cx));
         Pin::new(self.inner.as_mut()).poll_ready(cx)
     }
 
@@ -473,7 +667,11 @@ impl Sink<UdpPacket> for TrackedDatagram {
         }
 
         let upload = item.data.len();
+        self.tracker.record(Direction::Upload, &item.data);
         self.manager.push_uploaded(upload);
+        self.traffic.upload.inc_by(upload as u64);
+        self.counters.uploaded(upload);
+        self.throttle.uploaded(upload);
         self.tracker
             .upload_total
             .fetch_add(upload as u64, std::sync::atomic::Ordering::Relaxed);
@@ -3,7 +3,9 @@ use std::{
     fmt::Display,
     io::BufReader,
     net::{IpAddr, SocketAddr},
+    path::Path,
     sync::Arc,
+    time::Duration,
 };
 
 use ipnet::AddrParseError;
@@ -12,14 +14,20 @@ use rustls::{Certificate, PrivateKey};
 use url::Url;
 
 use crate::{
-    common::trie,
-    config::def::{DNSListen, DNSMode},
+    common::{
+        tls::{load_certs, load_private_key},
+        trie,
+    },
+    config::def::{CacheTtl, DNSListen, DNSMode, DNSStrategy, DNSTls, ServeStale},
     Error,
 };
 
 use super::{
     dns_client::DNSNetMode,
     dummy_keys::{TEST_CERT, TEST_KEY},
+    fakeip,
+    hosts::Hosts,
+    records::StaticRecords,
 };
 
 #[derive(Clone, Debug)]
@@ -75,16 +83,46 @@ pub struct Config {
     pub fallback: Vec<NameServer>,
     pub fallback_filter: FallbackFilter,
     pub listen: DNSListenAddr,
+    /// replaces the test certificate of the DoH and DoT listeners, once
+    /// loaded with `load_tls`
+    pub tls: Option<DNSTls>,
     pub enhance_mode: DNSMode,
     pub default_nameserver: Vec<NameServer>,
     pub fake_ip_range: ipnet::IpNet,
     pub fake_ip_filter: Vec<String>,
+    pub fake_ip_tun_only: bool,
     pub store_fake_ip: bool,
-    pub hosts: Option<trie::StringTrie<IpAddr>>,
+    pub hosts: Option<Hosts>,
     pub nameserver_policy: HashMap<String, NameServer>,
+    pub serve_stale: ServeStale,
+    pub cache_ttl: CacheTtl,
+    pub records: Option<Arc<StaticRecords>>,
+    pub strategy: DNSStrategy,
+    pub query_timeout: Duration,
 }
 
 impl Config {
+    /// Put the certificate of `tls` in the DoH and DoT listeners, its paths
+    /// relative to `cwd`.
+    pub fn load_tls(&mut self, cwd: &Path) -> Result<(), Error> {
+        let Some(tls) = &self.tls else {
+            return Ok(());
+        };
+        let invalid =
+            |e: Error| Error::InvalidConfig(format!("invalid dns.tls: {}", e));
+        let certs = load_certs(&cwd.join(&tls.certificate)).map_err(invalid)?;
+        let key = load_private_key(&cwd.join(&tls.private_key)).map_err(invalid)?;
+
+        if let Some((_, c)) = &mut self.listen.doh {
+            c.certificate_and_key = (certs.clone(), key.clone());
+            c.dns_hostname = tls.hostname.clone();
+        }
+        if let Some((_, c)) = &mut self.listen.dot {
+            c.certificate_and_key = (certs, key);
+        }
+        Ok(())
+    }
+
     pub fn parse_nameserver(servers: &[String]) -> Result<Vec<NameServer>, Error> {
         let mut nameservers = vec![];
 
@@ -101,7 +139,20 @@ impl Config {
                 ))
             })?;
 
-            let host = url.host_str().expect("dns host must be valid");
+            let host =
+                url.host_str().filter(|x| !x.is_empty()).ok_or_else(|| {
+                    Error::InvalidConfig(format!(
+                        "dns server has no host: {}",
+                        server
+                    ))
+                })?;
+
+            // the url keeps an explicit port apart from the host
+            let host = match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_owned(),
+            };
+            let host = host.as_str();
 
             let iface = url.fragment();
             let addr: String;
@@ -124,6 +175,10 @@ impl Config {
                     addr = Config::host_with_default_port(host, "443")?;
                     net = "DoH";
                 }
+                "quic" => {
+                    addr = Config::host_with_default_port(host, "853")?;
+                    net = "DoQ";
+                }
                 "dhcp" => {
                     addr = host.to_string();
                     net = "DHCP";
@@ -182,23 +237,6 @@ impl Config {
         Ok(output)
     }
 
-    pub fn parse_hosts(
-        hosts_mapping: &HashMap<String, String>,
-    ) -> anyhow::Result<trie::StringTrie<IpAddr>> {
-        let mut tree = trie::StringTrie::new();
-        tree.insert(
-            "localhost",
-            Arc::new("127.0.0.1".parse::<IpAddr>().unwrap()),
-        );
-
-        for (host, ip_str) in hosts_mapping.iter() {
-            let ip = ip_str.parse::<IpAddr>()?;
-            tree.insert(host.as_str(), Arc::new(ip));
-        }
-
-        Ok(tree)
-    }
-
     pub fn host_with_default_port(host: &str, port: &str) -> Result<String, Error> {
         let has_port_suffix = Regex::new(r":\d+$").unwrap();
 
@@ -257,15 +295,17 @@ impl TryFrom<&crate::config::def::Config> for Config {
                 .listen
                 .clone()
                 .map(|l| match l {
+                    // both UDP and TCP, for the answers too large for UDP
                     DNSListen::Udp(u) => {
                         let addr = u.parse::<SocketAddr>().map_err(|_| {
                             Error::InvalidConfig(format!(
-                                "invalid dns udp listen address: {}",
+                                "invalid dns listen address: {}",
                                 u
                             ))
                         })?;
                         Ok(DNSListenAddr {
                             udp: Some(addr),
+                            tcp: Some(addr),
                             ..Default::default()
                         })
                     }
@@ -358,24 +398,47 @@ impl TryFrom<&crate::config::def::Config> for Config {
                 })
                 .transpose()?
                 .unwrap_or_default(),
+            tls: dc.tls.clone(),
             enhance_mode: dc.enhanced_mode.clone(),
             default_nameserver,
-            fake_ip_range: dc.fake_ip_range.parse::<ipnet::IpNet>().map_err(
-                |_| Error::InvalidConfig(String::from("invalid fake ip range")),
-            )?,
+            fake_ip_range: dc
+                .fake_ip_range
+                .parse::<ipnet::IpNet>()
+                .map_err(|_| {
+                    Error::InvalidConfig(String::from("invalid fake ip range"))
+                })
+                .and_then(|x| fakeip::validate_range(&x).map(|_| x))?,
             fake_ip_filter: dc.fake_ip_filter.clone(),
+            fake_ip_tun_only: dc.fake_ip_tun_only,
             store_fake_ip: c.profile.store_fake_ip,
-            hosts: if dc.user_hosts && !c.hosts.is_empty() {
-                Config::parse_hosts(&c.hosts).ok()
+            hosts: Some(if dc.user_hosts {
+                Hosts::parse(&c.hosts)?
             } else {
-                let mut tree = trie::StringTrie::new();
-                tree.insert(
-                    "localhost",
-                    Arc::new("127.0.0.1".parse::<IpAddr>().unwrap()),
-                );
-                Some(tree)
-            },
+                Hosts::localhost()
+            }),
             nameserver_policy,
+            serve_stale: dc.serve_stale.clone(),
+            cache_ttl: if dc.cache_ttl.min <= dc.cache_ttl.max {
+                dc.cache_ttl.clone()
+            } else {
+                return Err(Error::InvalidConfig(
+                    "dns cache-ttl min is above max".to_owned(),
+                ));
+            },
+            records: if dc.records.is_empty() {
+                None
+            } else {
+                Some(Arc::new(StaticRecords::parse(&dc.records)?))
+            },
+            strategy: dc.strategy,
+            query_timeout: match dc.query_timeout {
+                0 => {
+                    return Err(Error::InvalidConfig(
+                        "dns query-timeout must be above 0".to_owned(),
+                    ))
+                }
+                x => Duration::from_secs(x),
+            },
         })
     }
 }
@@ -391,3 +454,71 @@ impl From<crate::config::def::FallbackFilter> for FallbackFilter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use rustls::PrivateKey;
+
+    use crate::{app::dns::dns_client::DNSNetMode, config::def::DNSTls};
+
+    use super::{Config, DNSListenAddr, DoHConfig};
+
+    #[test]
+    fn test_parse_nameserver() {
+        let servers = Config::parse_nameserver(&[
+            "https://dns.google/dns-query".to_owned(),
+            "https://1.1.1.1:8443/dns-query".to_owned(),
+            "tls://dns.google".to_owned(),
+            "8.8.8.8:5353".to_owned(),
+            "quic://dns.adguard-dns.com".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(servers[0].net, DNSNetMode::DoH);
+        assert_eq!(servers[0].address, "dns.google:443");
+        assert_eq!(servers[1].address, "1.1.1.1:8443");
+        assert_eq!(servers[2].net, DNSNetMode::DoT);
+        assert_eq!(servers[2].address, "dns.google:853");
+        assert_eq!(servers[3].net, DNSNetMode::Udp);
+        assert_eq!(servers[3].address, "8.8.8.8:5353");
+        assert_eq!(servers[4].net, DNSNetMode::DoQ);
+        assert_eq!(servers[4].address, "dns.adguard-dns.com:853");
+    }
+
+    #[test]
+    fn test_load_tls() {
+        let cwd = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/app/dns/test");
+        let tls = |certificate: &str| DNSTls {
+            certificate: certificate.to_owned(),
+            private_key: "test.key".to_owned(),
+            hostname: Some("dns.example.org".to_owned()),
+        };
+        let mut cfg = Config {
+            listen: DNSListenAddr {
+                doh: Some((
+                    "127.0.0.1:0".parse().unwrap(),
+                    DoHConfig {
+                        certificate_and_key: (vec![], PrivateKey(vec![])),
+                        dns_hostname: None,
+                    },
+                )),
+                ..Default::default()
+            },
+            tls: Some(tls("test.cert")),
+            ..Default::default()
+        };
+
+        cfg.load_tls(&cwd).unwrap();
+        let doh = &cfg.listen.doh.as_ref().unwrap().1;
+        assert!(!doh.certificate_and_key.0.is_empty());
+        assert!(!doh.certificate_and_key.1 .0.is_empty());
+        assert_eq!(doh.dns_hostname.as_deref(), Some("dns.example.org"));
+
+        // fails the config instead of the listener
+        cfg.tls = Some(tls("missing.cert"));
+        assert!(cfg.load_tls(&cwd).is_err());
+        cfg.tls = Some(tls("test.key"));
+        assert!(cfg.load_tls(&cwd).is_err());
+    }
+}
@@ -21,19 +21,49 @@ use std::{
 use tokio::{net::UdpSocket, sync::Mutex, task::yield_now};
 
 use hickory_proto::op::Message;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use super::config::NameServer;
 
 const IFACE_TTL: Duration = Duration::from_secs(20);
 const DHCP_TTL: Duration = Duration::from_secs(3600);
 const DHCP_TIMEOUT: Duration = Duration::from_secs(60);
+/// the shortest time nameservers are kept for, however short the lease
+const MIN_RENEW: Duration = Duration::from_secs(60);
+/// the wait before probing again after a failed probe, doubled after each
+/// failure up to `MAX_RETRY`
+const MIN_RETRY: Duration = Duration::from_secs(10);
+const MAX_RETRY: Duration = Duration::from_secs(600);
+
+/// The nameservers of a DHCP offer.
+struct Offer {
+    dns: Vec<Ipv4Addr>,
+    lease: Option<Duration>,
+}
+
+impl Offer {
+    /// When to ask again, at half the lease like a DHCP client renews it,
+    /// or after `DHCP_TTL` if the server didn't say.
+    fn renew_after(&self) -> Duration {
+        self.lease.map_or(DHCP_TTL, |x| (x / 2).max(MIN_RENEW))
+    }
+}
 
 struct Inner {
     clients: Vec<ThreadSafeDNSClient>,
     iface_expires_at: std::time::Instant,
     dns_expires_at: std::time::Instant,
     iface_addr: ipnet::IpNet,
+    /// the wait after the next failed probe
+    retry: Duration,
+}
+
+impl Inner {
+    /// Put off the next probe after a failed one.
+    fn probe_failed(&mut self) {
+        self.dns_expires_at = Instant::now().add(self.retry);
+        self.retry = (self.retry * 2).min(MAX_RETRY);
+    }
 }
 
 pub struct DhcpClient {
@@ -80,6 +110,7 @@ impl DhcpClient {
                 iface_expires_at: Instant::now(),
                 dns_expires_at: Instant::now(),
                 iface_addr: ipnet::IpNet::default(),
+                retry: MIN_RETRY,
             }),
         }
     }
@@ -87,23 +118,48 @@ impl DhcpClient {
     async fn resolve(&self) -> io::Result<Vec<ThreadSafeDNSClient>> {
         let expired = self.update_if_lease_expired().await?;
         if expired {
-            let dns = probe_dns_server(&self.iface).await?;
-            let mut inner = self.inner.lock().await;
-
-            inner.clients = make_clients(
-                dns.into_iter()
-                    .map(|s| NameServer {
-                        net: DNSNetMode::Udp,
-                        address: format!("{}:53", s),
-                        interface: None,
-                    })
-                    .collect(),
-                None,
-            )
-            .await;
+            match probe_dns_server(&self.iface).await {
+                Ok(offer) => {
+                    let clients = make_clients(
+                        offer
+                            .dns
+                            .iter()
+                            .map(|s| NameServer {
+                                net: DNSNetMode::Udp,
+                                address: format!("{}:53", s),
+                                interface: None,
+                            })
+                            .collect(),
+                        None,
+                    )
+                    .await;
+
+                    let mut inner = self.inner.lock().await;
+                    inner.dns_expires_at = Instant::now().add(offer.renew_after());
+                    inner.retry = MIN_RETRY;
+                    inner.clients = clients;
+                }
+                Err(e) => {
+                    // the old nameservers may well still work, the interface
+                    // is asked again after a while
+                    let mut inner = self.inner.lock().await;
+                    inner.probe_failed();
+                    if inner.clients.is_empty() {
+                        return Err(e);
+                    }
+                    warn!("keeping the DHCP nameservers of {}: {}", self.iface, e);
+                }
+            }
         }
 
-        Ok(self.inner.lock().await.clients.clone())
+        let clients = self.inner.lock().await.clients.clone();
+        if clients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("no DHCP nameservers of {} yet", self.iface),
+            ));
+        }
+        Ok(clients)
     }
 
     /// Check if interface updated or DHCP changed
@@ -111,7 +167,8 @@ impl DhcpClient {
     async fn update_if_lease_expired(&self) -> io::Result<bool> {
         let mut inner = self.inner.lock().await;
         if inner.clients.is_empty() {
-            return Ok(true);
+            // backing off after a failed probe
+            return Ok(Instant::now() >= inner.dns_expires_at);
         }
 
         if Instant::now() < inner.iface_expires_at {
@@ -153,7 +210,6 @@ impl DhcpClient {
                 {
                     Ok(false)
                 } else {
-                    inner.dns_expires_at = Instant::now().add(DHCP_TTL);
                     inner.iface_addr = ipnet::IpNet::new(
                         v4.ip.into(),
                         u32::from(v4.netmask.ok_or(io::Error::new(
@@ -194,7 +250,7 @@ async fn listen_dhcp_client(iface: &str) -> io::Result<UdpSocket> {
     .await
 }
 
-async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
+async fn probe_dns_server(iface: &str) -> io::Result<Offer> {
     debug!("probing NS servers from DHCP");
     let socket = listen_dhcp_client(iface).await?;
 
@@ -235,9 +291,10 @@ async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
             dhcproto::v4::OptionCode::Router,
             dhcproto::v4::OptionCode::DomainNameServer,
             dhcproto::v4::OptionCode::DomainName,
+            dhcproto::v4::OptionCode::AddressLeaseTime,
         ]));
 
-    let (mut tx, rx) = tokio::sync::oneshot::channel::<Vec<Ipv4Addr>>();
+    let (mut tx, rx) = tokio::sync::oneshot::channel::<Offer>();
 
     let mut rx = rx.fuse();
 
@@ -250,10 +307,13 @@ async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
 
         let get_response = async move {
             loop {
-                let (n_read, _) = r
-                    .recv_from(&mut buf)
-                    .await
-                    .expect("failed to receive DHCP offer");
+                let n_read = match r.recv_from(&mut buf).await {
+                    Ok((n, _)) => n,
+                    Err(e) => {
+                        debug!("failed to receive DHCP offer: {}", e);
+                        return None;
+                    }
+                };
 
                 // fucking deep if-else hell
                 if let Ok(reply) = dhcproto::v4::Message::from_bytes(&buf[..n_read])
@@ -274,7 +334,10 @@ async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
                                                         "got NS servers {:?} from DHCP",
                                                         dns
                                                     );
-                                                    return dns.clone();
+                                                    return Some(Offer {
+                                                        dns: dns.clone(),
+                                                        lease: lease_time(&reply),
+                                                    });
                                                 }
                                                 _ => yield_now().await,
                                             }
@@ -292,7 +355,7 @@ async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
 
         tokio::select! {
             _ = tx.closed() => {debug!("future cancelled, likely other clients won")},
-            value = get_response => tx.send(value).map_err(|x| debug!("send error: {:?}", x)).unwrap_or_default(),
+            Some(value) = get_response => tx.send(value).map_err(|_| debug!("send error")).unwrap_or_default(),
         }
     });
 
@@ -311,9 +374,62 @@ async fn probe_dns_server(iface: &str) -> io::Result<Vec<Ipv4Addr>> {
     }
 }
 
+fn lease_time(reply: &dhcproto::v4::Message) -> Option<Duration> {
+    match reply
+        .opts()
+        .get(dhcproto::v4::OptionCode::AddressLeaseTime)?
+    {
+        dhcproto::v4::DhcpOption::AddressLeaseTime(secs) => {
+            Some(Duration::from_secs(u64::from(*secs)))
+        }
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::dns::dhcp::probe_dns_server;
+    use std::time::Duration;
+
+    use std::time::Instant;
+
+    use crate::dns::dhcp::{
+        probe_dns_server, Inner, Offer, DHCP_TTL, MAX_RETRY, MIN_RENEW, MIN_RETRY,
+    };
+
+    #[test]
+    fn test_renew_after() {
+        let offer = |lease| Offer { dns: vec![], lease };
+        assert_eq!(offer(None).renew_after(), DHCP_TTL);
+        assert_eq!(
+            offer(Some(Duration::from_secs(7200))).renew_after(),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            offer(Some(Duration::from_secs(30))).renew_after(),
+            MIN_RENEW
+        );
+    }
+
+    #[test]
+    fn test_probe_failed() {
+        let mut inner = Inner {
+            clients: vec![],
+            iface_expires_at: Instant::now(),
+            dns_expires_at: Instant::now(),
+            iface_addr: ipnet::IpNet::default(),
+            retry: MIN_RETRY,
+        };
+        let before = Instant::now();
+        inner.probe_failed();
+        assert!(inner.dns_expires_at >= before + MIN_RETRY);
+        assert_eq!(inner.retry, MIN_RETRY * 2);
+
+        for _ in 0..10 {
+            inner.probe_failed();
+        }
+        assert_eq!(inner.retry, MAX_RETRY);
+        assert!(inner.dns_expires_at >= before + MAX_RETRY);
+    }
 
     #[tokio::test]
     #[ignore]
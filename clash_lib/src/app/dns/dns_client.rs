@@ -10,30 +10,33 @@ use std::{
 use async_trait::async_trait;
 
 use hickory_client::{
-    client, client::AsyncClient, proto::iocompat::AsyncIoTokioAsStd,
-    tcp::TcpClientStream, udp::UdpClientStream,
+    client, client::AsyncClient, tcp::TcpClientStream, udp::UdpClientStream,
 };
 use hickory_proto::error::ProtoError;
-use rustls::ClientConfig;
 use tokio::{sync::RwLock, task::JoinHandle};
 use tracing::{info, warn};
 
 use crate::{
-    common::tls::{self, GLOBAL_ROOT_STORE},
+    common::tls,
     dns::{dhcp::DhcpClient, ThreadSafeDNSClient},
+    proxy::transport::{tls::client_config, TLSOptions},
 };
 use hickory_proto::{
     h2::HttpsClientStreamBuilder,
     op::Message,
+    quic::QuicClientStream,
     rustls::tls_client_connect_with_bind_addr,
+    udp::UdpSocket as _,
     xfer::{DnsRequest, DnsRequestOptions, FirstAnswer},
     DnsHandle,
 };
-use tokio::net::{TcpStream as TokioTcpStream, UdpSocket as TokioUdpSocket};
 
 use crate::{proxy::utils::Interface, Error};
 
-use super::{ClashResolver, Client};
+use super::{
+    socket::{ProtectedTcpStream, ProtectedUdpSocket},
+    ClashResolver, Client,
+};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum DNSNetMode {
@@ -41,6 +44,7 @@ pub enum DNSNetMode {
     Tcp,
     DoT,
     DoH,
+    DoQ,
     Dhcp,
 }
 
@@ -51,6 +55,7 @@ impl Display for DNSNetMode {
             Self::Tcp => write!(f, "TCP"),
             Self::DoT => write!(f, "DoT"),
             Self::DoH => write!(f, "DoH"),
+            Self::DoQ => write!(f, "DoQ"),
             Self::Dhcp => write!(f, "DHCP"),
         }
     }
@@ -65,6 +70,7 @@ impl FromStr for DNSNetMode {
             "TCP" => Ok(Self::Tcp),
             "DoH" => Ok(Self::DoH),
             "DoT" => Ok(Self::DoT),
+            "DoQ" => Ok(Self::DoQ),
             "DHCP" => Ok(Self::Dhcp),
             _ => Err(Error::DNSError("unsupported protocol".into())),
         }
@@ -85,6 +91,7 @@ enum DnsConfig {
     Tcp(net::SocketAddr, Option<Interface>),
     Tls(net::SocketAddr, String, Option<Interface>),
     Https(net::SocketAddr, String, Option<Interface>),
+    Quic(net::SocketAddr, String, Option<Interface>),
 }
 
 impl Display for DnsConfig {
@@ -118,6 +125,13 @@ impl Display for DnsConfig {
                 }
                 write!(f, "host: {}", host)
             }
+            DnsConfig::Quic(addr, host, iface) => {
+                write!(f, "QUIC: {}:{} ", addr.ip(), addr.port())?;
+                if let Some(iface) = iface {
+                    write!(f, "bind: {}", iface)?;
+                }
+                write!(f, "host: {}", host)
+            }
         }
     }
 }
@@ -252,6 +266,26 @@ impl DnsClient {
                             iface: opts.iface,
                         }))
                     }
+                    DNSNetMode::DoQ => {
+                        let cfg = DnsConfig::Quic(
+                            net::SocketAddr::new(ip, opts.port),
+                            opts.host.clone(),
+                            opts.iface.clone(),
+                        );
+
+                        Ok(Arc::new(Self {
+                            inner: Arc::new(RwLock::new(Inner {
+                                c: None,
+                                bg_handle: None,
+                            })),
+
+                            cfg,
+                            host: opts.host,
+                            port: opts.port,
+                            net: opts.net,
+                            iface: opts.iface,
+                        }))
+                    }
                     _ => unreachable!("."),
                 }
             }
@@ -277,33 +311,12 @@ impl Client for DnsClient {
     }
 
     async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
-        let mut inner = self.inner.write().await;
-
-        if let Some(bg) = &inner.bg_handle {
-            if bg.is_finished() {
-                warn!(
-                    "dns client background task is finished, likely connection \
-                     closed, restarting a new one"
-                );
-                let (client, bg) = dns_stream_builder(&self.cfg).await?;
-                inner.c.replace(client);
-                inner.bg_handle.replace(bg);
-            }
-        } else {
-            // initializing client
-            info!("initializing dns client: {}", &self.cfg);
-            let (client, bg) = dns_stream_builder(&self.cfg).await?;
-            inner.c.replace(client);
-            inner.bg_handle.replace(bg);
-        }
+        let client = self.client().await?;
 
         let mut req = DnsRequest::new(msg.clone(), DnsRequestOptions::default());
         req.set_id(rand::random::<u16>());
 
-        inner
-            .c
-            .as_ref()
-            .unwrap()
+        client
             .send(req)
             .first_answer()
             .await
@@ -312,13 +325,44 @@ impl Client for DnsClient {
     }
 }
 
+impl DnsClient {
+    /// The client of the current connection, connecting again if it was
+    /// closed. Queries share the connection rather than waiting for each
+    /// other, TCP and TLS ones pipelined on it.
+    async fn client(&self) -> Result<AsyncClient, Error> {
+        {
+            let inner = self.inner.read().await;
+            if let (Some(c), Some(bg)) = (&inner.c, &inner.bg_handle) {
+                if !bg.is_finished() {
+                    return Ok(c.clone());
+                }
+            }
+        }
+
+        let mut inner = self.inner.write().await;
+        match (&inner.c, &inner.bg_handle) {
+            // reconnected by another query meanwhile
+            (Some(c), Some(bg)) if !bg.is_finished() => return Ok(c.clone()),
+            (_, Some(_)) => warn!(
+                "dns client background task is finished, likely connection \
+                 closed, restarting a new one"
+            ),
+            (_, None) => info!("initializing dns client: {}", &self.cfg),
+        }
+        let (client, bg) = dns_stream_builder(&self.cfg).await?;
+        inner.c.replace(client.clone());
+        inner.bg_handle.replace(bg);
+        Ok(client)
+    }
+}
+
 async fn dns_stream_builder(
     cfg: &DnsConfig,
 ) -> Result<(AsyncClient, JoinHandle<Result<(), ProtoError>>), Error> {
     match cfg {
         DnsConfig::Udp(addr, iface) => {
             let stream =
-                UdpClientStream::<TokioUdpSocket>::with_bind_addr_and_timeout(
+                UdpClientStream::<ProtectedUdpSocket>::with_bind_addr_and_timeout(
                     net::SocketAddr::new(addr.ip(), addr.port()),
                     // TODO: simplify this match
                     match iface {
@@ -333,16 +377,15 @@ async fn dns_stream_builder(
                 .map_err(|x| Error::DNSError(x.to_string()))
         }
         DnsConfig::Tcp(addr, iface) => {
-            let (stream, sender) = TcpClientStream::<
-                AsyncIoTokioAsStd<TokioTcpStream>,
-            >::with_bind_addr_and_timeout(
-                net::SocketAddr::new(addr.ip(), addr.port()),
-                match iface {
-                    Some(Interface::IpAddr(ip)) => Some(SocketAddr::new(*ip, 0)),
-                    _ => None,
-                },
-                Duration::from_secs(5),
-            );
+            let (stream, sender) =
+                TcpClientStream::<ProtectedTcpStream>::with_bind_addr_and_timeout(
+                    net::SocketAddr::new(addr.ip(), addr.port()),
+                    match iface {
+                        Some(Interface::IpAddr(ip)) => Some(SocketAddr::new(*ip, 0)),
+                        _ => None,
+                    },
+                    Duration::from_secs(5),
+                );
 
             client::AsyncClient::new(stream, sender, None)
                 .await
@@ -350,23 +393,22 @@ async fn dns_stream_builder(
                 .map_err(|x| Error::DNSError(x.to_string()))
         }
         DnsConfig::Tls(addr, host, iface) => {
-            let mut tls_config = ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(GLOBAL_ROOT_STORE.clone())
-                .with_no_client_auth();
-            tls_config.alpn_protocols = vec!["dot".into()];
-
-            let (stream, sender) = tls_client_connect_with_bind_addr::<
-                AsyncIoTokioAsStd<TokioTcpStream>,
-            >(
-                net::SocketAddr::new(addr.ip(), addr.port()),
-                match iface {
-                    Some(Interface::IpAddr(ip)) => Some(SocketAddr::new(*ip, 0)),
-                    _ => None,
-                },
-                host.clone(),
-                Arc::new(tls_config),
-            );
+            let tls_config = client_config(&TLSOptions {
+                sni: host.clone(),
+                alpn: Some(vec!["dot".to_owned()]),
+                ..Default::default()
+            })?;
+
+            let (stream, sender) =
+                tls_client_connect_with_bind_addr::<ProtectedTcpStream>(
+                    net::SocketAddr::new(addr.ip(), addr.port()),
+                    match iface {
+                        Some(Interface::IpAddr(ip)) => Some(SocketAddr::new(*ip, 0)),
+                        _ => None,
+                    },
+                    host.clone(),
+                    Arc::new(tls_config),
+                );
 
             client::AsyncClient::with_timeout(
                 stream,
@@ -379,11 +421,11 @@ async fn dns_stream_builder(
             .map_err(|x| Error::DNSError(x.to_string()))
         }
         DnsConfig::Https(addr, host, iface) => {
-            let mut tls_config = ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(GLOBAL_ROOT_STORE.clone())
-                .with_no_client_auth();
-            tls_config.alpn_protocols = vec!["h2".into()];
+            let mut tls_config = client_config(&TLSOptions {
+                sni: host.clone(),
+                alpn: Some(vec!["h2".to_owned()]),
+                ..Default::default()
+            })?;
 
             if host == &addr.ip().to_string() {
                 tls_config
@@ -396,7 +438,44 @@ async fn dns_stream_builder(
             if let Some(Interface::IpAddr(ip)) = iface {
                 stream_builder.bind_addr(net::SocketAddr::new(*ip, 0));
             }
-            let stream = stream_builder.build::<AsyncIoTokioAsStd<TokioTcpStream>>(
+            let stream = stream_builder.build::<ProtectedTcpStream>(
+                net::SocketAddr::new(addr.ip(), addr.port()),
+                host.clone(),
+            );
+
+            client::AsyncClient::connect(stream)
+                .await
+                .map(|(x, y)| (x, tokio::spawn(y)))
+                .map_err(|x| Error::DNSError(x.to_string()))
+        }
+        DnsConfig::Quic(addr, host, iface) => {
+            let mut tls_config = client_config(&TLSOptions {
+                sni: host.clone(),
+                alpn: Some(vec!["doq".to_owned()]),
+                ..Default::default()
+            })?;
+            // the session ticket of the last connection lets a reconnect
+            // send its first query early
+            tls_config.enable_early_data = true;
+
+            if host == &addr.ip().to_string() {
+                tls_config
+                    .dangerous()
+                    .set_certificate_verifier(Arc::new(tls::NoHostnameTlsVerifier));
+            }
+
+            // one connection, each query on a stream of its own
+            let mut stream_builder = QuicClientStream::builder();
+            stream_builder.crypto_config(tls_config);
+            let bind_addr = match iface {
+                Some(Interface::IpAddr(ip)) => net::SocketAddr::new(*ip, 0),
+                _ => match addr {
+                    SocketAddr::V4(_) => (net::Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (net::Ipv6Addr::UNSPECIFIED, 0).into(),
+                },
+            };
+            let stream = stream_builder.build_with_future(
+                ProtectedUdpSocket::bind(bind_addr),
                 net::SocketAddr::new(addr.ip(), addr.port()),
                 host.clone(),
             );
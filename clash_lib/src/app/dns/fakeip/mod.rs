@@ -3,7 +3,7 @@ use std::{
     sync::Arc,
 };
 
-use crate::{common::trie, Error};
+use crate::{app::router::rules::geodata::GeoSiteMatcher, common::trie, Error};
 
 use async_trait::async_trait;
 use byteorder::{BigEndian, ByteOrder};
@@ -18,6 +18,8 @@ pub use mem_store::InMemStore;
 pub struct Opts {
     pub ipnet: ipnet::IpNet,
     pub skipped_hostnames: Option<trie::StringTrie<bool>>,
+    /// the `geosite:` entries of `fake-ip-filter`
+    pub skipped_geosites: Vec<GeoSiteMatcher>,
     pub store: Box<dyn Store>,
 }
 
@@ -30,10 +32,27 @@ pub trait Store: Sync + Send {
     async fn del_by_ip(&mut self, ip: net::IpAddr);
     async fn exist(&mut self, ip: net::IpAddr) -> bool;
     async fn copy_to(&self, store: &mut Box<dyn Store>);
+    async fn flush(&mut self);
 }
 
 pub type ThreadSafeFakeDns = Arc<RwLock<FakeDns>>;
 
+/// The fake IPs are handed out of an IPv4 range, less its network address
+/// and the gateway, so it must have at least two more.
+pub fn validate_range(ipnet: &ipnet::IpNet) -> Result<(), Error> {
+    match ipnet {
+        ipnet::IpNet::V4(net) if net.prefix_len() <= 30 => Ok(()),
+        ipnet::IpNet::V4(_) => Err(Error::InvalidConfig(format!(
+            "fake ip range {} is too small",
+            ipnet
+        ))),
+        ipnet::IpNet::V6(_) => Err(Error::InvalidConfig(format!(
+            "fake ip range {} must be IPv4",
+            ipnet
+        ))),
+    }
+}
+
 pub struct FakeDns {
     max: u32,
     min: u32,
@@ -41,15 +60,17 @@ pub struct FakeDns {
     gateway: u32,
     offset: u32,
     skipped_hostnames: Option<trie::StringTrie<bool>>,
+    skipped_geosites: Vec<GeoSiteMatcher>,
     ipnet: ipnet::IpNet,
     store: Box<dyn Store>,
 }
 
 impl FakeDns {
     pub fn new(opt: Opts) -> Result<Self, Error> {
+        validate_range(&opt.ipnet)?;
         let ip = match opt.ipnet.network() {
             net::IpAddr::V4(ip) => ip,
-            _ => unreachable!("fakeip range must be valid ipv4 subnet"),
+            _ => unreachable!("validated above"),
         };
         let min = Self::ip_to_uint(&ip) + 2;
         let prefix_len = opt.ipnet.prefix_len();
@@ -65,6 +86,7 @@ impl FakeDns {
             gateway: min - 1,
             offset: 0,
             skipped_hostnames: opt.skipped_hostnames,
+            skipped_geosites: opt.skipped_geosites,
             ipnet: opt.ipnet,
             store: opt.store,
         })
@@ -89,10 +111,11 @@ impl FakeDns {
     }
 
     pub fn should_skip(&self, domain: &str) -> bool {
-        match &self.skipped_hostnames {
+        let skipped = match &self.skipped_hostnames {
             None => false,
             Some(host) => host.search(domain).is_some(),
-        }
+        };
+        skipped || self.skipped_geosites.iter().any(|x| x.matches(domain))
     }
 
     pub async fn exist(&mut self, ip: net::IpAddr) -> bool {
@@ -121,6 +144,11 @@ impl FakeDns {
         self.ipnet
     }
 
+    pub async fn flush(&mut self) {
+        self.store.flush().await;
+        self.offset = 0;
+    }
+
     #[allow(dead_code)]
     pub async fn copy_from(&mut self, src: &Self) {
         src.store.copy_to(&mut self.store).await;
@@ -161,7 +189,21 @@ mod tests {
 
     use crate::{app::dns::fakeip::mem_store::InMemStore, common::trie};
 
-    use super::{FakeDns, Opts};
+    use super::{validate_range, FakeDns, Opts};
+
+    #[test]
+    fn test_validate_range() {
+        for (range, valid) in [
+            ("198.18.0.1/16", true),
+            ("192.168.0.0/30", true),
+            ("192.168.0.0/31", false),
+            ("192.168.0.1/32", false),
+            ("fd00::/64", false),
+        ] {
+            let ipnet = range.parse::<ipnet::IpNet>().unwrap();
+            assert_eq!(validate_range(&ipnet).is_ok(), valid, "{}", range);
+        }
+    }
 
     #[tokio::test]
     async fn test_inmem_basic() {
@@ -170,6 +212,7 @@ mod tests {
         let mut pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            skipped_geosites: vec![],
             store,
         })
         .unwrap();
@@ -202,6 +245,7 @@ mod tests {
         let mut pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            skipped_geosites: vec![],
             store,
         })
         .unwrap();
@@ -230,6 +274,7 @@ mod tests {
         let pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: Some(tree),
+            skipped_geosites: vec![],
             store,
         })
         .unwrap();
@@ -246,6 +291,7 @@ mod tests {
         let mut pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            skipped_geosites: vec![],
             store,
         })
         .unwrap();
@@ -259,6 +305,32 @@ mod tests {
         assert_ne!(first, next);
     }
 
+    #[tokio::test]
+    async fn test_pool_flush() {
+        let store = Box::new(InMemStore::new(10));
+
+        let ipnet = "192.168.0.0/29".parse::<ipnet::IpNet>().unwrap();
+        let mut pool = FakeDns::new(Opts {
+            ipnet,
+            skipped_hostnames: None,
+            skipped_geosites: vec![],
+            store,
+        })
+        .unwrap();
+
+        pool.lookup("foo.com").await;
+        let bar = pool.lookup("bar.com").await;
+
+        pool.flush().await;
+
+        assert!(!pool.exist(bar).await);
+        assert!(pool.reverse_lookup(bar).await.is_none());
+        assert_eq!(
+            pool.lookup("bar.com").await,
+            net::IpAddr::from([192, 168, 0, 2])
+        );
+    }
+
     #[tokio::test]
     #[ignore = "copy not implemented"]
     async fn test_pool_clone() {
@@ -268,6 +340,7 @@ mod tests {
         let mut pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            skipped_geosites: vec![],
             store,
         })
         .unwrap();
@@ -282,6 +355,7 @@ mod tests {
         let mut new_pool = FakeDns::new(Opts {
             ipnet,
             skipped_hostnames: None,
+            skipped_geosites: vec![],
             store,
         })
         .unwrap();
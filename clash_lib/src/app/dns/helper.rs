@@ -5,46 +5,66 @@ use crate::{
     },
     proxy::utils::Interface,
 };
+use futures::{future, stream, StreamExt};
 use std::sync::Arc;
 use tracing::{debug, warn};
 
 use super::config::NameServer;
 
+/// How many clients are built at once, DoH and DoT ones resolving and
+/// connecting to their servers first.
+const PARALLELISM: usize = 8;
+
+/// Build the clients of `servers` concurrently, in their order, skipping
+/// those that fail.
 pub async fn make_clients(
     servers: Vec<NameServer>,
     resolver: Option<Arc<dyn ClashResolver>>,
 ) -> Vec<ThreadSafeDNSClient> {
-    let mut rv = Vec::new();
-
-    for s in servers {
-        debug!("building nameserver: {:?}", s);
+    stream::iter(servers)
+        .map(|s| make_client(s, resolver.clone()))
+        .buffered(PARALLELISM)
+        .filter_map(future::ready)
+        .collect()
+        .await
+}
 
-        let (host, port) = if s.net == DNSNetMode::Dhcp {
-            (s.address.as_str(), "0")
-        } else {
-            let port = s.address.split(':').last().unwrap();
-            let host = s
-                .address
-                .strip_suffix(format!(":{}", port).as_str())
-                .unwrap_or_else(|| panic!("invalid address: {}", s.address));
-            (host, port)
-        };
+async fn make_client(
+    s: NameServer,
+    resolver: Option<Arc<dyn ClashResolver>>,
+) -> Option<ThreadSafeDNSClient> {
+    debug!("building nameserver: {:?}", s);
 
-        match DnsClient::new_client(Opts {
-            r: resolver.as_ref().cloned(),
-            host: host.to_string(),
-            port: port
-                .parse::<u16>()
-                .unwrap_or_else(|_| panic!("no port for DNS server: {}", s.address)),
-            net: s.net.to_owned(),
-            iface: s.interface.as_ref().map(|x| Interface::Name(x.to_owned())),
-        })
-        .await
+    let (host, port) = if s.net == DNSNetMode::Dhcp {
+        (s.address.as_str(), 0)
+    } else {
+        // validated when the config was loaded
+        match s
+            .address
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
         {
-            Ok(c) => rv.push(c),
-            Err(e) => warn!("initializing DNS client {} with error {}", &s, e),
+            Some(x) => x,
+            None => {
+                warn!("invalid address for DNS server: {}", s.address);
+                return None;
+            }
         }
-    }
+    };
 
-    rv
+    match DnsClient::new_client(Opts {
+        r: resolver,
+        host: host.to_string(),
+        port,
+        net: s.net.to_owned(),
+        iface: s.interface.as_ref().map(|x| Interface::Name(x.to_owned())),
+    })
+    .await
+    {
+        Ok(c) => Some(c),
+        Err(e) => {
+            warn!("initializing DNS client {} with error {}", &s, e);
+            None
+        }
+    }
 }
@@ -0,0 +1,176 @@
+//! The `hosts` of the config, like /etc/hosts with wildcards: a domain maps
+//! to its addresses or, like a CNAME, to another domain.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+};
+
+use hickory_proto::rr;
+
+use crate::{common::trie, config::def::HostValue, Error};
+
+#[derive(Clone)]
+enum Entry {
+    Ips(Vec<IpAddr>),
+    Alias(String),
+}
+
+/// Where a domain of the hosts leads.
+#[derive(Debug, PartialEq)]
+pub enum Resolved {
+    Ips(Vec<IpAddr>),
+    /// an alias of a domain that's not in the hosts, to be resolved instead
+    Name(String),
+}
+
+#[derive(Clone)]
+pub struct Hosts {
+    trie: trie::StringTrie<Entry>,
+}
+
+fn key(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+impl Hosts {
+    /// Only `localhost`.
+    pub fn localhost() -> Self {
+        let mut trie = trie::StringTrie::new();
+        trie.insert(
+            "localhost",
+            Arc::new(Entry::Ips(vec![Ipv4Addr::LOCALHOST.into()])),
+        );
+        Self { trie }
+    }
+
+    pub fn parse(mapping: &HashMap<String, HostValue>) -> Result<Self, Error> {
+        let mut hosts = Self::localhost();
+        for (host, value) in mapping {
+            let invalid = |msg: &str| {
+                Error::InvalidConfig(format!(
+                    "invalid hosts entry {}: {}",
+                    host, msg
+                ))
+            };
+            let entry = match value {
+                HostValue::One(x) => match x.parse::<IpAddr>() {
+                    Ok(ip) => Entry::Ips(vec![ip]),
+                    Err(_) if rr::Name::from_str_relaxed(x).is_ok() => {
+                        Entry::Alias(key(x))
+                    }
+                    Err(_) => return Err(invalid("neither an IP nor a domain")),
+                },
+                HostValue::Many(ips) if ips.is_empty() => {
+                    return Err(invalid("no IPs"))
+                }
+                HostValue::Many(ips) => Entry::Ips(
+                    ips.iter()
+                        .map(|x| x.parse::<IpAddr>())
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| invalid("a list can only have IPs"))?,
+                ),
+            };
+            if !hosts.trie.insert(&key(host), Arc::new(entry)) {
+                return Err(invalid("not a domain"));
+            }
+        }
+
+        // the loops of wildcards are only found when they're looked up
+        for host in mapping.keys().filter(|x| {
+            !x.contains('*') && !x.starts_with('.') && !x.starts_with('+')
+        }) {
+            hosts
+                .lookup(host)
+                .map_err(|e| Error::InvalidConfig(e.to_string()))?;
+        }
+        Ok(hosts)
+    }
+
+    /// Where `host` leads, following its aliases within the hosts, `None`
+    /// if it's not in them.
+    pub fn lookup(&self, host: &str) -> Result<Option<Resolved>, Error> {
+        let mut name = key(host);
+        let mut aliases = HashSet::new();
+        loop {
+            let Some(entry) = self.trie.search(&name).and_then(|x| x.get_data())
+            else {
+                return Ok((!aliases.is_empty()).then_some(Resolved::Name(name)));
+            };
+            match entry {
+                Entry::Ips(ips) => return Ok(Some(Resolved::Ips(ips.clone()))),
+                Entry::Alias(target) => {
+                    if !aliases.insert(name) {
+                        return Err(Error::DNSError(format!(
+                            "hosts alias loop from {}",
+                            host
+                        )));
+                    }
+                    name = target.clone();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::config::def::HostValue;
+
+    use super::{Hosts, Resolved};
+
+    fn parse(entries: &[(&str, HostValue)]) -> Result<Hosts, crate::Error> {
+        Hosts::parse(
+            &entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect::<HashMap<_, _>>(),
+        )
+    }
+
+    #[test]
+    fn test_hosts() {
+        let one = |x: &str| HostValue::One(x.to_owned());
+        let hosts = parse(&[
+            (
+                "nas.lan",
+                HostValue::Many(vec!["10.0.0.1".into(), "fd00::1".into()]),
+            ),
+            ("files.lan", one("NAS.lan")),
+            ("media.lan", one("files.lan")),
+            ("*.cdn.lan", one("cdn.example.com")),
+        ])
+        .unwrap();
+
+        let nas = Some(Resolved::Ips(vec![
+            "10.0.0.1".parse().unwrap(),
+            "fd00::1".parse().unwrap(),
+        ]));
+        assert_eq!(hosts.lookup("Media.lan.").unwrap(), nas);
+        assert_eq!(hosts.lookup("nas.lan").unwrap(), nas);
+        assert_eq!(
+            hosts.lookup("img.cdn.lan").unwrap(),
+            Some(Resolved::Name("cdn.example.com".to_owned()))
+        );
+        assert_eq!(
+            hosts.lookup("localhost").unwrap(),
+            Some(Resolved::Ips(vec!["127.0.0.1".parse().unwrap()]))
+        );
+        assert_eq!(hosts.lookup("example.com").unwrap(), None);
+
+        assert!(parse(&[("a.lan", one("b.lan")), ("b.lan", one("a.lan"))]).is_err());
+        assert!(parse(&[("a.lan", one("a.lan"))]).is_err());
+        assert!(parse(&[(
+            "a.lan",
+            HostValue::Many(vec!["10.0.0.1".into(), "b.lan".into()])
+        )])
+        .is_err());
+
+        // found when it's looked up
+        let hosts = parse(&[("*.loop.lan", one("x.loop.lan"))]).unwrap();
+        assert!(hosts.lookup("y.loop.lan").is_err());
+    }
+}
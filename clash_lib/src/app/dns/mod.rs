@@ -2,7 +2,8 @@ use async_trait::async_trait;
 
 use std::fmt::Debug;
 
-use hickory_proto::op;
+use hickory_proto::{op, rr::RecordType};
+use serde::Serialize;
 use std::sync::Arc;
 
 #[cfg(test)]
@@ -15,14 +16,17 @@ mod dummy_keys;
 mod fakeip;
 mod filters;
 mod helper;
+mod hosts;
+mod records;
 pub mod resolver;
 mod server;
+mod socket;
 
 pub use config::Config;
 
 pub use resolver::{new as new_resolver, EnhancedResolver, SystemResolver};
 
-pub use server::get_dns_listener;
+pub use server::{answer_hijacked, get_dns_listener};
 
 #[async_trait]
 pub trait Client: Sync + Send + Debug {
@@ -67,7 +71,9 @@ pub trait ClashResolver: Sync + Send {
 
     /// Only used for look up fake IP
     async fn reverse_lookup(&self, ip: std::net::IpAddr) -> Option<String>;
+    /// Whether `ip` is in the fake IP range, handed out or not.
     async fn is_fake_ip(&self, ip: std::net::IpAddr) -> bool;
+    /// Whether `ip` is a fake IP currently mapped to a domain.
     async fn fake_ip_exists(&self, ip: std::net::IpAddr) -> bool;
 
     fn ipv6(&self) -> bool;
@@ -76,4 +82,39 @@ pub trait ClashResolver: Sync + Send {
     fn kind(&self) -> ResolverKind;
 
     fn fake_ip_enabled(&self) -> bool;
+
+    /// Drop all fake IP mappings, addresses handed out before are not
+    /// resolvable back to their domains anymore.
+    async fn flush_fake_ip(&self);
+    /// Drop all cached DNS responses.
+    async fn flush_cache(&self);
+    /// The cached DNS responses that are not expired yet.
+    async fn cache_entries(&self) -> Vec<CacheEntry>;
+    /// Drop the cached responses for `name`, of all types if `typ` is `None`.
+    /// Returns how many were dropped.
+    async fn remove_cache(&self, name: &str, typ: Option<RecordType>) -> usize;
+    /// The lookups of the DNS cache so far.
+    fn cache_stats(&self) -> CacheStats;
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CacheEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// seconds before the entry expires from the cache
+    pub ttl: u64,
+    /// the upstream that answered
+    pub upstream: String,
+    pub answers: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// of the hits, those of a negative answer
+    pub negative_hits: u64,
+    pub misses: u64,
+    /// expired answers served as the upstreams failed
+    pub stale: u64,
 }
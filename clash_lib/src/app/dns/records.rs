@@ -0,0 +1,269 @@
+//! The static records of `dns.records`, a tiny zone answered before any
+//! upstream is asked, e.g. for the names of a homelab.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use hickory_proto::{
+    op,
+    rr::{
+        rdata::{A, AAAA, CNAME, TXT},
+        Name, RData, Record, RecordType,
+    },
+};
+
+use crate::Error;
+
+const TTL: u32 = 60;
+/// how many CNAMEs are followed within the zone
+const MAX_CNAME_CHAIN: usize = 8;
+
+pub struct StaticRecords {
+    /// by lowercase name without the trailing dot
+    records: HashMap<String, Vec<RData>>,
+}
+
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+fn split_field(s: &str) -> Option<(&str, &str)> {
+    let (field, rest) = s.split_once(char::is_whitespace)?;
+    Some((field, rest.trim_start()))
+}
+
+impl StaticRecords {
+    /// One record per line, `name TYPE value`, e.g.
+    /// `nas.home.lan A 192.168.1.10`, of type A, AAAA, CNAME or TXT.
+    pub fn parse(lines: &[String]) -> Result<Self, Error> {
+        let mut records: HashMap<String, Vec<RData>> = HashMap::new();
+        for line in lines {
+            let invalid = |msg: &str| {
+                Error::InvalidConfig(format!(
+                    "invalid dns record `{}`: {}",
+                    line, msg
+                ))
+            };
+            let (name, typ, value) = split_field(line.trim())
+                .and_then(|(name, rest)| {
+                    let (typ, value) = split_field(rest)?;
+                    Some((name, typ, value))
+                })
+                .ok_or_else(|| invalid("expected `name TYPE value`"))?;
+            let rdata = match typ.to_uppercase().as_str() {
+                "A" => RData::A(A(value
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| invalid("not an IPv4 address"))?)),
+                "AAAA" => RData::AAAA(AAAA(
+                    value
+                        .parse::<Ipv6Addr>()
+                        .map_err(|_| invalid("not an IPv6 address"))?,
+                )),
+                "CNAME" => RData::CNAME(CNAME(
+                    Name::from_str(&format!("{}.", key(value)))
+                        .map_err(|_| invalid("not a domain"))?,
+                )),
+                "TXT" => {
+                    RData::TXT(TXT::new(vec![value.trim_matches('"').to_owned()]))
+                }
+                _ => return Err(invalid("the type must be A, AAAA, CNAME or TXT")),
+            };
+
+            let rdatas = records.entry(key(name)).or_default();
+            rdatas.push(rdata);
+            if rdatas.len() > 1
+                && rdatas.iter().any(|x| x.record_type() == RecordType::CNAME)
+            {
+                return Err(invalid("a CNAME can't have other records"));
+            }
+        }
+        Ok(Self { records })
+    }
+
+    /// The answer to the query of `message` if its name is in the zone,
+    /// following CNAMEs within it. A name without records of the type
+    /// queried is answered with none. A CNAME out of the zone ends the
+    /// answer, see `out_of_zone`.
+    pub fn answer(&self, message: &op::Message) -> Option<op::Message> {
+        let q = message.query()?;
+        self.records.get(&key(&q.name().to_ascii()))?;
+
+        let mut name = q.name().clone();
+        let mut answers = vec![];
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(rdatas) = self.records.get(&key(&name.to_ascii())) else {
+                break;
+            };
+            let cname = rdatas.iter().find_map(|x| match x {
+                RData::CNAME(c) if q.query_type() != RecordType::CNAME => {
+                    Some(c.0.clone())
+                }
+                _ => None,
+            });
+            match cname {
+                Some(target) => {
+                    answers.push(Record::from_rdata(
+                        name,
+                        TTL,
+                        RData::CNAME(CNAME(target.clone())),
+                    ));
+                    name = target;
+                }
+                None => {
+                    answers.extend(
+                        rdatas
+                            .iter()
+                            .filter(|x| x.record_type() == q.query_type())
+                            .map(|x| {
+                                Record::from_rdata(name.clone(), TTL, x.clone())
+                            }),
+                    );
+                    break;
+                }
+            }
+        }
+
+        let mut m = op::Message::new();
+        m.set_id(message.id());
+        m.set_message_type(op::MessageType::Response);
+        m.set_op_code(message.op_code());
+        m.set_recursion_desired(message.recursion_desired());
+        m.set_recursion_available(true);
+        m.set_authoritative(true);
+        m.add_query(q.clone());
+        m.add_answers(answers);
+        Some(m)
+    }
+
+    /// The target of the CNAME out of the zone that `answer` ends with, to
+    /// be resolved upstream.
+    pub fn out_of_zone(&self, answer: &op::Message) -> Option<Name> {
+        if answer.query()?.query_type() == RecordType::CNAME {
+            return None;
+        }
+        match answer.answers().last()?.data() {
+            Some(RData::CNAME(c)) if !self.contains(&c.0.to_ascii()) => {
+                Some(c.0.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `host` is a name of the zone.
+    pub fn contains(&self, host: &str) -> bool {
+        self.records.contains_key(&key(host))
+    }
+
+    /// The addresses `host` has in the zone, `None` if it has none.
+    pub fn ips(&self, host: &str, record_type: RecordType) -> Option<Vec<IpAddr>> {
+        let mut m = op::Message::new();
+        m.add_query(op::Query::query(Name::from_str(host).ok()?, record_type));
+        let ips = self
+            .answer(&m)?
+            .answers()
+            .iter()
+            .filter_map(|x| match x.data() {
+                Some(RData::A(a)) => Some(IpAddr::V4(a.0)),
+                Some(RData::AAAA(aaaa)) => Some(IpAddr::V6(aaaa.0)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        (!ips.is_empty()).then_some(ips)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use hickory_proto::{
+        op,
+        rr::{Name, RData, RecordType},
+    };
+
+    use super::StaticRecords;
+
+    fn query(
+        records: &StaticRecords,
+        name: &str,
+        typ: RecordType,
+    ) -> Option<op::Message> {
+        let mut m = op::Message::new();
+        m.add_query(op::Query::query(Name::from_str(name).unwrap(), typ));
+        records.answer(&m)
+    }
+
+    #[test]
+    fn test_static_records() {
+        let records = StaticRecords::parse(&[
+            "nas.home.lan A 192.168.1.10".to_owned(),
+            "NAS.home.lan  AAAA   fd00::10".to_owned(),
+            "media.home.lan CNAME nas.home.lan".to_owned(),
+            "home.lan TXT \"v=spf1 -all\"".to_owned(),
+        ])
+        .unwrap();
+
+        let m = query(&records, "nas.home.lan.", RecordType::A).unwrap();
+        assert!(m.authoritative());
+        assert_eq!(m.answers().len(), 1);
+
+        // the CNAME, then the address it points to
+        let m = query(&records, "Media.Home.Lan", RecordType::AAAA).unwrap();
+        assert_eq!(m.answers().len(), 2);
+        assert_eq!(m.answers()[0].record_type(), RecordType::CNAME);
+        assert_eq!(m.answers()[1].record_type(), RecordType::AAAA);
+
+        let m = query(&records, "home.lan", RecordType::TXT).unwrap();
+        match m.answers()[0].data() {
+            Some(RData::TXT(txt)) => assert_eq!(txt.to_string(), "v=spf1 -all"),
+            x => panic!("unexpected {:?}", x),
+        }
+
+        // in the zone without records of the type
+        let m = query(&records, "home.lan", RecordType::A).unwrap();
+        assert!(m.answers().is_empty());
+        assert!(query(&records, "www.home.lan", RecordType::A).is_none());
+
+        assert_eq!(
+            records.ips("media.home.lan", RecordType::A),
+            Some(vec!["192.168.1.10".parse().unwrap()])
+        );
+        assert_eq!(records.ips("home.lan", RecordType::A), None);
+        assert!(records.contains("NAS.home.lan."));
+        assert!(!records.contains("www.home.lan"));
+
+        for line in [
+            "nas.home.lan A fd00::10",
+            "nas.home.lan MX mail.home.lan",
+            "nas.home.lan A",
+            "media.home.lan CNAME nas.home.lan\nmedia.home.lan A 1.1.1.1",
+        ] {
+            let lines = line.lines().map(ToOwned::to_owned).collect::<Vec<_>>();
+            assert!(StaticRecords::parse(&lines).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_out_of_zone() {
+        let records = StaticRecords::parse(&[
+            "media.home.lan CNAME cdn.example.com".to_owned(),
+            "www.home.lan CNAME media.home.lan".to_owned(),
+        ])
+        .unwrap();
+
+        // the chain ends out of the zone, to be followed upstream
+        let m = query(&records, "www.home.lan", RecordType::A).unwrap();
+        assert_eq!(m.answers().len(), 2);
+        assert_eq!(
+            records.out_of_zone(&m),
+            Some(Name::from_str("cdn.example.com.").unwrap())
+        );
+        assert_eq!(records.ips("www.home.lan", RecordType::A), None);
+
+        let m = query(&records, "www.home.lan", RecordType::CNAME).unwrap();
+        assert_eq!(records.out_of_zone(&m), None);
+    }
+}
@@ -2,22 +2,26 @@ use async_trait::async_trait;
 use futures::{FutureExt, TryFutureExt};
 use rand::prelude::SliceRandom;
 use std::{
+    collections::HashMap,
     net,
     sync::{
-        atomic::{AtomicBool, Ordering::Relaxed},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::sync::RwLock;
-use tracing::{debug, instrument, warn};
+use tracing::{debug, info, instrument, warn};
 
 use hickory_proto::{op, rr};
 
 use crate::{
-    app::profile::ThreadSafeCacheFile,
-    common::{mmdb::Mmdb, trie},
-    config::def::DNSMode,
+    app::{
+        metrics, profile::ThreadSafeCacheFile,
+        router::rules::geodata::GeoSiteMatcher,
+    },
+    common::{geodata::GeoData, mmdb::Mmdb, trie},
+    config::def::{CacheTtl, DNSMode, DNSStrategy, ServeStale},
     dns::{helper::make_clients, ThreadSafeDNSClient},
     Error,
 };
@@ -28,23 +32,142 @@ use crate::dns::{
         DomainFilter, FallbackDomainFilter, FallbackIPFilter, GeoIPFilter,
         IPNetFilter,
     },
-    ClashResolver, Config, ResolverKind,
+    hosts::{Hosts, Resolved},
+    records::StaticRecords,
+    CacheEntry, CacheStats, ClashResolver, Config, ResolverKind,
 };
 
-static TTL: Duration = Duration::from_secs(60);
+/// The `fake-ip-filter` split into its domains and its `geosite:` lists,
+/// the lists being skipped without the geosite data.
+fn fake_ip_filter(
+    filter: &[String],
+    geodata: Option<&GeoData>,
+) -> (Option<trie::StringTrie<bool>>, Vec<GeoSiteMatcher>) {
+    let mut hosts = None;
+    let mut geosites = vec![];
+    for entry in filter {
+        let Some(code) = entry.strip_prefix("geosite:") else {
+            hosts
+                .get_or_insert_with(trie::StringTrie::new)
+                .insert(entry, Arc::new(true));
+            continue;
+        };
+        let Some(geodata) = geodata else {
+            warn!("fake-ip-filter {} ignored without geosite data", entry);
+            continue;
+        };
+        match GeoSiteMatcher::new(code.to_owned(), String::new(), geodata) {
+            Ok(matcher) => geosites.push(matcher),
+            Err(e) => warn!("fake-ip-filter {} ignored: {}", entry, e),
+        }
+    }
+    (hosts, geosites)
+}
+
+/// how long a nameserver that failed is tried last with `FirstHealthy`
+const UNHEALTHY_FOR: Duration = Duration::from_secs(60);
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// of the answers from the hosts
+const HOSTS_TTL: u32 = 60;
+
+#[derive(Clone)]
+struct CachedResponse {
+    msg: op::Message,
+    upstream: String,
+    stored: Instant,
+    expires: Instant,
+    /// the upstreams failed to refresh the expired answer, it's served
+    /// stale without asking them again until then
+    stale_until: Option<Instant>,
+}
+
+/// The lookups of the cache, counted on every query.
+#[derive(Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+    stale: AtomicU64,
+}
+
+impl CacheCounters {
+    fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Relaxed),
+            negative_hits: self.negative_hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            stale: self.stale.load(Relaxed),
+        }
+    }
+}
+
+/// Whether `msg` has no answer, the name not existing, having no records
+/// of the type or the upstream failing.
+fn is_negative(msg: &op::Message) -> bool {
+    msg.response_code() != op::ResponseCode::NoError || msg.answers().is_empty()
+}
+
+/// How long `msg` is cached, the least TTL of its answers within `limits`.
+/// A negative answer is cached for `limits.negative` at most, less if its
+/// SOA says so. `None` if it's not cached.
+fn cache_duration(msg: &op::Message, limits: &CacheTtl) -> Option<Duration> {
+    match msg.response_code() {
+        op::ResponseCode::NoError if !msg.answers().is_empty() => {
+            let ttl = msg.answers().iter().map(|x| x.ttl()).min().unwrap();
+            Some(Duration::from_secs(
+                ttl.max(limits.min).min(limits.max) as u64
+            ))
+        }
+        op::ResponseCode::NoError
+        | op::ResponseCode::NXDomain
+        | op::ResponseCode::ServFail => {
+            let ttl = msg
+                .name_servers()
+                .iter()
+                .map(|x| x.ttl())
+                .min()
+                .map_or(limits.negative, |x| x.min(limits.negative));
+            (ttl > 0).then(|| Duration::from_secs(ttl as u64))
+        }
+        _ => None,
+    }
+}
+
+fn map_ttls(msg: &mut op::Message, f: impl Fn(u32) -> u32) {
+    for record in msg.answers_mut() {
+        record.set_ttl(f(record.ttl()));
+    }
+    for record in msg.name_servers_mut() {
+        record.set_ttl(f(record.ttl()));
+    }
+    for record in msg.additionals_mut() {
+        record.set_ttl(f(record.ttl()));
+    }
+}
 
 pub struct EnhancedResolver {
     ipv6: AtomicBool,
-    hosts: Option<trie::StringTrie<net::IpAddr>>,
+    hosts: Option<Hosts>,
     main: Vec<ThreadSafeDNSClient>,
 
     fallback: Option<Vec<ThreadSafeDNSClient>>,
     fallback_domain_filters: Option<Vec<Box<dyn FallbackDomainFilter>>>,
     fallback_ip_filters: Option<Vec<Box<dyn FallbackIPFilter>>>,
 
-    lru_cache: Option<Arc<RwLock<lru_time_cache::LruCache<String, op::Message>>>>,
+    /// entries outlive `expires` when serving stale answers
+    lru_cache: Option<Arc<RwLock<lru_time_cache::LruCache<String, CachedResponse>>>>,
+    serve_stale: Option<ServeStale>,
+    cache_ttl: CacheTtl,
+    cache_stats: CacheCounters,
+    /// the zone of `dns.records`, answered before anything else
+    records: Option<Arc<StaticRecords>>,
     policy: Option<trie::StringTrie<Vec<ThreadSafeDNSClient>>>,
 
+    strategy: DNSStrategy,
+    query_timeout: Duration,
+    /// when each client last failed, by id
+    failures: Mutex<HashMap<String, Instant>>,
+
     fake_dns: Option<ThreadSafeFakeDns>,
 }
 
@@ -72,8 +195,16 @@ impl EnhancedResolver {
             fallback_domain_filters: None,
             fallback_ip_filters: None,
             lru_cache: None,
+            serve_stale: None,
+            cache_ttl: Default::default(),
+            cache_stats: Default::default(),
+            records: None,
             policy: None,
 
+            strategy: Default::default(),
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            failures: Default::default(),
+
             fake_dns: None,
         }
     }
@@ -82,6 +213,7 @@ impl EnhancedResolver {
         cfg: &Config,
         store: ThreadSafeCacheFile,
         mmdb: Arc<Mmdb>,
+        geodata: Option<Arc<GeoData>>,
     ) -> Self {
         let default_resolver = Arc::new(EnhancedResolver {
             ipv6: AtomicBool::new(false),
@@ -91,20 +223,26 @@ impl EnhancedResolver {
             fallback_domain_filters: None,
             fallback_ip_filters: None,
             lru_cache: None,
+            serve_stale: None,
+            cache_ttl: Default::default(),
+            cache_stats: Default::default(),
+            records: None,
             policy: None,
 
+            strategy: cfg.strategy,
+            query_timeout: cfg.query_timeout,
+            failures: Default::default(),
+
             fake_dns: None,
         });
 
-        Self {
-            ipv6: AtomicBool::new(cfg.ipv6),
-            main: make_clients(
-                cfg.nameserver.clone(),
-                Some(default_resolver.clone()),
-            )
-            .await,
-            hosts: cfg.hosts.clone(),
-            fallback: if !cfg.fallback.is_empty() {
+        // the clients of each list are built at the same time
+        let (main, fallback, policy) = tokio::join!(
+            make_clients(cfg.nameserver.clone(), Some(default_resolver.clone())),
+            async {
+                if cfg.fallback.is_empty() {
+                    return None;
+                }
                 Some(
                     make_clients(
                         cfg.fallback.clone(),
@@ -112,9 +250,23 @@ impl EnhancedResolver {
                     )
                     .await,
                 )
-            } else {
-                None
             },
+            futures::future::join_all(cfg.nameserver_policy.iter().map(
+                |(domain, ns)| {
+                    let clients = make_clients(
+                        vec![ns.to_owned()],
+                        Some(default_resolver.clone()),
+                    );
+                    async move { (domain.as_str(), Arc::new(clients.await)) }
+                }
+            )),
+        );
+
+        Self {
+            ipv6: AtomicBool::new(cfg.ipv6),
+            main,
+            hosts: cfg.hosts.clone(),
+            fallback,
             fallback_domain_filters: if !cfg.fallback_filter.domain.is_empty() {
                 Some(vec![Box::new(DomainFilter::new(
                     cfg.fallback_filter
@@ -149,48 +301,51 @@ impl EnhancedResolver {
             },
             lru_cache: Some(Arc::new(RwLock::new(
                 lru_time_cache::LruCache::with_expiry_duration_and_capacity(
-                    TTL, 4096,
+                    Duration::from_secs(
+                        cfg.cache_ttl.max as u64
+                            + if cfg.serve_stale.enable {
+                                cfg.serve_stale.max_age
+                            } else {
+                                0
+                            },
+                    ),
+                    4096,
                 ),
             ))),
-            policy: if !cfg.nameserver_policy.is_empty() {
+            serve_stale: Some(cfg.serve_stale.clone()).filter(|x| x.enable),
+            cache_ttl: cfg.cache_ttl.clone(),
+            cache_stats: Default::default(),
+            records: cfg.records.clone(),
+            policy: if !policy.is_empty() {
                 let mut p = trie::StringTrie::new();
-                for (domain, ns) in &cfg.nameserver_policy {
-                    p.insert(
-                        domain.as_str(),
-                        Arc::new(
-                            make_clients(
-                                vec![ns.to_owned()],
-                                Some(default_resolver.clone()),
-                            )
-                            .await,
-                        ),
-                    );
+                for (domain, clients) in policy {
+                    p.insert(domain, clients);
                 }
                 Some(p)
             } else {
                 None
             },
+            strategy: cfg.strategy,
+            query_timeout: cfg.query_timeout,
+            failures: Default::default(),
             fake_dns: match cfg.enhance_mode {
-                DNSMode::FakeIp => Some(Arc::new(RwLock::new(
-                    fakeip::FakeDns::new(fakeip::Opts {
-                        ipnet: cfg.fake_ip_range,
-                        skipped_hostnames: if !cfg.fake_ip_filter.is_empty() {
-                            let mut host = trie::StringTrie::new();
-                            for domain in cfg.fake_ip_filter.iter() {
-                                host.insert(domain.as_str(), Arc::new(true));
-                            }
-                            Some(host)
-                        } else {
-                            None
-                        },
-                        store: if cfg.store_fake_ip {
-                            Box::new(FileStore::new(store))
-                        } else {
-                            Box::new(InMemStore::new(1000))
-                        },
-                    })
-                    .unwrap(),
-                ))),
+                DNSMode::FakeIp => {
+                    let (skipped_hostnames, skipped_geosites) =
+                        fake_ip_filter(&cfg.fake_ip_filter, geodata.as_deref());
+                    Some(Arc::new(RwLock::new(
+                        fakeip::FakeDns::new(fakeip::Opts {
+                            ipnet: cfg.fake_ip_range,
+                            skipped_hostnames,
+                            skipped_geosites,
+                            store: if cfg.store_fake_ip {
+                                Box::new(FileStore::new(store))
+                            } else {
+                                Box::new(InMemStore::new(1000))
+                            },
+                        })
+                        .unwrap(),
+                    )))
+                }
                 DNSMode::RedirHost => {
                     warn!(
                         "dns redir-host is not supported and will not do anything"
@@ -202,10 +357,84 @@ impl EnhancedResolver {
         }
     }
 
+    /// Races all `clients`, for those without a resolver at hand.
     pub async fn batch_exchange(
         clients: &Vec<ThreadSafeDNSClient>,
         message: &op::Message,
     ) -> anyhow::Result<op::Message> {
+        Self::race(clients, message, DEFAULT_QUERY_TIMEOUT)
+            .await
+            .map(|x| x.0)
+    }
+
+    /// Asks `clients` as the strategy says, returns the answer and the id
+    /// of the client that answered.
+    async fn batch_exchange_from(
+        &self,
+        clients: &Vec<ThreadSafeDNSClient>,
+        message: &op::Message,
+    ) -> anyhow::Result<(op::Message, String)> {
+        match self.strategy {
+            DNSStrategy::Race => {
+                Self::race(clients, message, self.query_timeout).await
+            }
+            DNSStrategy::Sequential => {
+                self.sequential(clients.iter(), message).await
+            }
+            DNSStrategy::FirstHealthy => {
+                let (healthy, unhealthy): (Vec<_>, Vec<_>) = {
+                    let failures = self.failures.lock().unwrap();
+                    clients.iter().partition(|c| {
+                        failures
+                            .get(&c.id())
+                            .filter(|x| x.elapsed() < UNHEALTHY_FOR)
+                            .is_none()
+                    })
+                };
+                self.sequential(healthy.into_iter().chain(unhealthy), message)
+                    .await
+            }
+        }
+    }
+
+    /// Asks `clients` one at a time until one answers, remembering which
+    /// failed.
+    async fn sequential<'a>(
+        &self,
+        clients: impl Iterator<Item = &'a ThreadSafeDNSClient>,
+        message: &op::Message,
+    ) -> anyhow::Result<(op::Message, String)> {
+        let mut last_err = None;
+        for c in clients {
+            match tokio::time::timeout(self.query_timeout, c.exchange(message)).await
+            {
+                Ok(Ok(msg)) => {
+                    self.failures.lock().unwrap().remove(&c.id());
+                    return Ok((msg, c.id()));
+                }
+                Ok(Err(e)) => {
+                    debug!("DNS client {} resolve error: {}", c.id(), e);
+                    last_err = Some(e);
+                }
+                Err(_) => {
+                    debug!("DNS client {} timed out", c.id());
+                    last_err =
+                        Some(Error::DNSError("DNS query timeout".into()).into());
+                }
+            }
+            self.failures.lock().unwrap().insert(c.id(), Instant::now());
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::DNSError("no nameserver to query".into()).into()
+        }))
+    }
+
+    /// Asks all `clients` at once, the first answer wins.
+    async fn race(
+        clients: &Vec<ThreadSafeDNSClient>,
+        message: &op::Message,
+        timeout: Duration,
+    ) -> anyhow::Result<(op::Message, String)> {
         let mut queries = Vec::new();
         for c in clients {
             queries.push(
@@ -219,12 +448,13 @@ impl EnhancedResolver {
                             )
                         })
                         .await
+                        .map(|x| (x, c.id()))
                 }
                 .boxed(),
             )
         }
 
-        let timeout = tokio::time::sleep(Duration::from_secs(10));
+        let timeout = tokio::time::sleep(timeout);
 
         tokio::select! {
             result = futures::future::select_ok(queries) => match result {
@@ -265,18 +495,176 @@ impl EnhancedResolver {
     }
 
     async fn exchange(&self, message: op::Message) -> anyhow::Result<op::Message> {
+        let Some(records) = &self.records else {
+            return self.exchange_cached(message).await;
+        };
+        let Some(mut msg) = records.answer(&message) else {
+            return self.exchange_cached(message).await;
+        };
+        // an alias of a domain out of the zone is answered with the CNAMEs
+        // and the records of the domain
+        if let (Some(target), Some(q)) = (records.out_of_zone(&msg), message.query())
+        {
+            let mut query = op::Message::new();
+            query.set_id(message.id());
+            query.set_recursion_desired(true);
+            query.add_query(op::Query::query(target, q.query_type()));
+            let resolved = self.exchange_cached(query).await?;
+            msg.set_response_code(resolved.response_code());
+            msg.set_authoritative(false);
+            msg.add_answers(resolved.answers().to_vec());
+        }
+        Ok(msg)
+    }
+
+    /// `message` answered from the cache or upstream
+    async fn exchange_cached(
+        &self,
+        message: op::Message,
+    ) -> anyhow::Result<op::Message> {
         if let Some(q) = message.query() {
             if let Some(lru) = &self.lru_cache {
                 if let Some(cached) = lru.read().await.peek(q.to_string().as_str()) {
-                    return Ok(cached.clone());
+                    let now = Instant::now();
+                    if cached.expires > now {
+                        metrics::dns_cache_lookup(true);
+                        self.cache_stats.hits.fetch_add(1, Relaxed);
+                        if is_negative(&cached.msg) {
+                            self.cache_stats.negative_hits.fetch_add(1, Relaxed);
+                        }
+                        // the TTLs count down while cached
+                        let elapsed = now.duration_since(cached.stored).as_secs();
+                        let elapsed = u32::try_from(elapsed).unwrap_or(u32::MAX);
+                        let mut msg = cached.msg.clone();
+                        map_ttls(&mut msg, |ttl| ttl.saturating_sub(elapsed));
+                        return Ok(msg);
+                    }
+                    if cached.stale_until.is_some_and(|x| x > now) {
+                        if let Some(msg) = self.stale_msg(cached) {
+                            debug!(
+                                "dns query {} answered stale, upstream failing",
+                                q
+                            );
+                            self.count_stale();
+                            return Ok(msg);
+                        }
+                    }
+                }
+                metrics::dns_cache_lookup(false);
+                self.cache_stats.misses.fetch_add(1, Relaxed);
+            }
+            match self.exchange_no_cache(&message).await {
+                Ok(msg) if msg.response_code() == op::ResponseCode::ServFail => {
+                    match self.stale_answer(q).await {
+                        Some(stale) => {
+                            info!("dns query {} answered stale: SERVFAIL", q);
+                            self.count_stale();
+                            Ok(stale)
+                        }
+                        None => Ok(msg),
+                    }
                 }
+                Ok(msg) => Ok(msg),
+                Err(e) => match self.stale_answer(q).await {
+                    Some(msg) => {
+                        info!("dns query {} answered stale: {}", q, e);
+                        self.count_stale();
+                        Ok(msg)
+                    }
+                    None => Err(e),
+                },
             }
-            self.exchange_no_cache(&message).await
         } else {
             Err(anyhow!("invalid query"))
         }
     }
 
+    /// The answer of the hosts to an A or AAAA query, with all the
+    /// addresses of the family the host has. An alias of a domain outside
+    /// of the hosts is answered with a CNAME and the records of the domain.
+    async fn hosts_answer(
+        &self,
+        message: &op::Message,
+    ) -> anyhow::Result<Option<op::Message>> {
+        let (Some(hosts), Some(q)) = (&self.hosts, message.query()) else {
+            return Ok(None);
+        };
+        if !matches!(q.query_type(), rr::RecordType::A | rr::RecordType::AAAA) {
+            return Ok(None);
+        }
+
+        let mut m = op::Message::new();
+        match hosts.lookup(&q.name().to_ascii())? {
+            None => return Ok(None),
+            Some(Resolved::Ips(ips)) => {
+                m.add_answers(
+                    ips.into_iter()
+                        .filter_map(|ip| match (ip, q.query_type()) {
+                            (net::IpAddr::V4(v4), rr::RecordType::A) => {
+                                Some(rr::RData::A(rr::rdata::A(v4)))
+                            }
+                            (net::IpAddr::V6(v6), rr::RecordType::AAAA) => {
+                                Some(rr::RData::AAAA(rr::rdata::AAAA(v6)))
+                            }
+                            _ => None,
+                        })
+                        .map(|x| {
+                            rr::Record::from_rdata(q.name().clone(), HOSTS_TTL, x)
+                        }),
+                );
+            }
+            Some(Resolved::Name(name)) => {
+                let target = rr::Name::from_str_relaxed(&name)?
+                    .append_domain(&rr::Name::root())?;
+                let mut query = op::Message::new();
+                query.set_id(message.id());
+                query.set_recursion_desired(true);
+                query.add_query(op::Query::query(target.clone(), q.query_type()));
+                let resolved = self.exchange(query).await?;
+
+                m.set_response_code(resolved.response_code());
+                m.add_answer(rr::Record::from_rdata(
+                    q.name().clone(),
+                    HOSTS_TTL,
+                    rr::RData::CNAME(rr::rdata::CNAME(target)),
+                ));
+                m.add_answers(resolved.answers().iter().cloned());
+            }
+        }
+
+        m.set_id(message.id());
+        m.set_message_type(op::MessageType::Response);
+        m.set_op_code(message.op_code());
+        m.set_recursion_desired(message.recursion_desired());
+        m.set_recursion_available(true);
+        m.add_query(q.clone());
+        Ok(Some(m))
+    }
+
+    /// The expired cached answer to `q` with its TTLs lowered, if serving
+    /// stale answers.
+    async fn stale_answer(&self, q: &op::Query) -> Option<op::Message> {
+        let lru = self.lru_cache.as_ref()?.read().await;
+        self.stale_msg(lru.peek(q.to_string().as_str())?)
+    }
+
+    /// `cached` with its TTLs lowered, if serving stale answers. A cached
+    /// SERVFAIL is no answer to serve.
+    fn stale_msg(&self, cached: &CachedResponse) -> Option<op::Message> {
+        let serve_stale = self.serve_stale.as_ref()?;
+        if cached.msg.response_code() == op::ResponseCode::ServFail {
+            return None;
+        }
+        let mut msg = cached.msg.clone();
+        map_ttls(&mut msg, |_| serve_stale.answer_ttl);
+        Some(msg)
+    }
+
+    fn count_stale(&self) {
+        metrics::dns_stale_answer();
+        self.cache_stats.stale.fetch_add(1, Relaxed);
+    }
+
     async fn exchange_no_cache(
         &self,
         message: &op::Message,
@@ -289,47 +677,57 @@ impl EnhancedResolver {
             }
 
             if let Some(matched) = self.match_policy(message) {
-                return EnhancedResolver::batch_exchange(matched, message).await;
+                return self.batch_exchange_from(matched, message).await;
             }
 
-            EnhancedResolver::batch_exchange(&self.main, message).await
+            self.batch_exchange_from(&self.main, message).await
         };
 
         let rv = query.await;
 
-        if let Ok(msg) = &rv {
-            if let Some(lru) = &self.lru_cache {
+        if let Ok((msg, upstream)) = &rv {
+            if let (Some(lru), Some(duration)) =
+                (&self.lru_cache, cache_duration(msg, &self.cache_ttl))
+            {
                 if !(q.query_type() == rr::RecordType::TXT
                     && q.name().to_ascii().starts_with("_acme-challenge."))
                 {
-                    // TODO: make this TTL wired to LRU cache
-                    #[allow(unused_variables)]
-                    let ttl = if msg.answer_count() != 0 {
-                        msg.answers()
-                            .iter()
-                            .map(|x| x.ttl())
-                            .min()
-                            .unwrap_or_default()
-                    } else if msg.name_server_count() != 0 {
-                        msg.name_servers()
-                            .iter()
-                            .map(|x| x.ttl())
-                            .min()
-                            .unwrap_or_default()
-                    } else {
-                        msg.additionals()
-                            .iter()
-                            .map(|x| x.ttl())
-                            .min()
-                            .unwrap_or_default()
+                    let key = q.to_string();
+                    let now = Instant::now();
+                    let mut lru = lru.write().await;
+                    // when serving stale, a failure doesn't replace the
+                    // answer kept, that's served for as long as the
+                    // failure would have been cached
+                    let failed = self.serve_stale.is_some()
+                        && msg.response_code() == op::ResponseCode::ServFail;
+                    let kept = match lru.get_mut(&key) {
+                        Some(cached)
+                            if failed
+                                && cached.msg.response_code()
+                                    != op::ResponseCode::ServFail =>
+                        {
+                            cached.stale_until = Some(now + duration);
+                            true
+                        }
+                        _ => false,
                     };
-
-                    lru.write().await.insert(q.to_string(), msg.clone());
+                    if !kept {
+                        lru.insert(
+                            key,
+                            CachedResponse {
+                                msg: msg.clone(),
+                                upstream: upstream.clone(),
+                                stored: now,
+                                expires: now + duration,
+                                stale_until: None,
+                            },
+                        );
+                    }
                 }
             }
         }
 
-        rv
+        rv.map(|x| x.0)
     }
 
     fn match_policy(&self, m: &op::Message) -> Option<&Vec<ThreadSafeDNSClient>> {
@@ -346,33 +744,29 @@ impl EnhancedResolver {
     async fn ip_exchange(
         &self,
         message: &op::Message,
-    ) -> anyhow::Result<op::Message> {
+    ) -> anyhow::Result<(op::Message, String)> {
         if let Some(matched) = self.match_policy(message) {
-            return EnhancedResolver::batch_exchange(matched, message).await;
+            return self.batch_exchange_from(matched, message).await;
         }
 
         if self.should_only_query_fallback(message) {
             // self.fallback guaranteed in the above check
-            return EnhancedResolver::batch_exchange(
-                self.fallback.as_ref().unwrap(),
-                message,
-            )
-            .await;
+            return self
+                .batch_exchange_from(self.fallback.as_ref().unwrap(), message)
+                .await;
         }
 
-        let main_query = EnhancedResolver::batch_exchange(&self.main, message);
+        let main_query = self.batch_exchange_from(&self.main, message);
 
         if self.fallback.is_none() {
             return main_query.await;
         }
 
-        let fallback_query = EnhancedResolver::batch_exchange(
-            self.fallback.as_ref().unwrap(),
-            message,
-        );
+        let fallback_query =
+            self.batch_exchange_from(self.fallback.as_ref().unwrap(), message);
 
         if let Ok(main_result) = main_query.await {
-            let ip_list = EnhancedResolver::ip_list_of_message(&main_result);
+            let ip_list = EnhancedResolver::ip_list_of_message(&main_result.0);
             if !ip_list.is_empty() {
                 // TODO: only check 1st?
                 if !self.should_ip_fallback(&ip_list[0]) {
@@ -478,14 +872,25 @@ impl ClashResolver for EnhancedResolver {
         host: &str,
         enhanced: bool,
     ) -> anyhow::Result<Option<net::Ipv4Addr>> {
+        let alias;
+        let mut host = host;
         if enhanced {
-            if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V4(v4) => *v4,
-                        _ => unreachable!("invalid IP family"),
-                    }));
+            match self.hosts.as_ref().map(|x| x.lookup(host)).transpose()? {
+                Some(Some(Resolved::Ips(ips))) => {
+                    let v4 = ips
+                        .into_iter()
+                        .filter_map(|x| match x {
+                            net::IpAddr::V4(v4) => Some(v4),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    return Ok(v4.choose(&mut rand::thread_rng()).copied());
+                }
+                Some(Some(Resolved::Name(name))) => {
+                    alias = name;
+                    host = &alias;
                 }
+                _ => {}
             }
         }
 
@@ -493,7 +898,21 @@ impl ClashResolver for EnhancedResolver {
             return Ok(Some(ip));
         }
 
-        if enhanced && self.fake_ip_enabled() {
+        // the names of the zone get their real addresses, not fake ones,
+        // also the aliases of domains out of it
+        if let Some(ips) = self
+            .records
+            .as_ref()
+            .and_then(|x| x.ips(host, rr::RecordType::A))
+        {
+            return match ips.choose(&mut rand::thread_rng()).unwrap() {
+                net::IpAddr::V4(v4) => Ok(Some(*v4)),
+                _ => unreachable!("invalid IP family"),
+            };
+        }
+        let in_zone = self.records.as_ref().is_some_and(|x| x.contains(host));
+
+        if enhanced && self.fake_ip_enabled() && !in_zone {
             let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
             if !fake_dns.should_skip(host) {
                 let ip = fake_dns.lookup(host).await;
@@ -523,14 +942,25 @@ impl ClashResolver for EnhancedResolver {
             return Err(Error::DNSError("ipv6 disabled".into()).into());
         }
 
+        let alias;
+        let mut host = host;
         if enhanced {
-            if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V6(v6) => *v6,
-                        _ => unreachable!("invalid IP family"),
-                    }));
+            match self.hosts.as_ref().map(|x| x.lookup(host)).transpose()? {
+                Some(Some(Resolved::Ips(ips))) => {
+                    let v6 = ips
+                        .into_iter()
+                        .filter_map(|x| match x {
+                            net::IpAddr::V6(v6) => Some(v6),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    return Ok(v6.choose(&mut rand::thread_rng()).copied());
+                }
+                Some(Some(Resolved::Name(name))) => {
+                    alias = name;
+                    host = &alias;
                 }
+                _ => {}
             }
         }
 
@@ -538,6 +968,17 @@ impl ClashResolver for EnhancedResolver {
             return Ok(Some(ip));
         }
 
+        if let Some(ips) = self
+            .records
+            .as_ref()
+            .and_then(|x| x.ips(host, rr::RecordType::AAAA))
+        {
+            return match ips.choose(&mut rand::thread_rng()).unwrap() {
+                net::IpAddr::V6(v6) => Ok(Some(*v6)),
+                _ => unreachable!("invalid IP family"),
+            };
+        }
+
         match self.lookup_ip(host, rr::RecordType::AAAA).await {
             Ok(result) => match result.choose(&mut rand::thread_rng()).unwrap() {
                 net::IpAddr::V6(v6) => Ok(Some(*v6)),
@@ -549,6 +990,9 @@ impl ClashResolver for EnhancedResolver {
     }
 
     async fn exchange(&self, message: op::Message) -> anyhow::Result<op::Message> {
+        if let Some(msg) = self.hosts_answer(&message).await? {
+            return Ok(msg);
+        }
         self.exchange(message).await
     }
 
@@ -595,6 +1039,74 @@ impl ClashResolver for EnhancedResolver {
         let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
         fake_dns.reverse_lookup(ip).await
     }
+
+    async fn flush_fake_ip(&self) {
+        if let Some(fake_dns) = &self.fake_dns {
+            fake_dns.write().await.flush().await;
+        }
+    }
+
+    async fn flush_cache(&self) {
+        if let Some(lru) = &self.lru_cache {
+            lru.write().await.clear();
+        }
+    }
+
+    async fn cache_entries(&self) -> Vec<CacheEntry> {
+        let Some(lru) = &self.lru_cache else {
+            return vec![];
+        };
+        let now = Instant::now();
+        lru.read()
+            .await
+            .peek_iter()
+            .filter(|(_, v)| v.expires > now)
+            .filter_map(|(_, v)| {
+                let q = v.msg.query()?;
+                Some(CacheEntry {
+                    name: q.name().to_string(),
+                    typ: q.query_type().to_string(),
+                    ttl: v.expires.saturating_duration_since(now).as_secs(),
+                    upstream: v.upstream.clone(),
+                    answers: v
+                        .msg
+                        .answers()
+                        .iter()
+                        .filter_map(|x| x.data().map(|x| x.to_string()))
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    async fn remove_cache(&self, name: &str, typ: Option<rr::RecordType>) -> usize {
+        let Some(lru) = &self.lru_cache else {
+            return 0;
+        };
+        let name = name.trim_end_matches('.');
+        let mut lru = lru.write().await;
+        let keys: Vec<_> = lru
+            .peek_iter()
+            .filter(|(_, v)| {
+                v.msg.query().is_some_and(|q| {
+                    q.name()
+                        .to_ascii()
+                        .trim_end_matches('.')
+                        .eq_ignore_ascii_case(name)
+                        && (typ.is_none() || typ == Some(q.query_type()))
+                })
+            })
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in keys.iter() {
+            lru.remove(k);
+        }
+        keys.len()
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.cache_stats.snapshot()
+    }
 }
 
 #[cfg(test)]
@@ -615,6 +1127,430 @@ mod tests {
         ThreadSafeDNSClient,
     };
 
+    #[tokio::test]
+    async fn test_cache_entries() {
+        use std::time::Instant;
+
+        use tokio::sync::RwLock;
+
+        use crate::app::dns::{CacheStats, ClashResolver};
+
+        let mut resolver = EnhancedResolver::new_default().await;
+        let lru = Arc::new(RwLock::new(
+            lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                Duration::from_secs(60),
+                16,
+            ),
+        ));
+        resolver.lru_cache = Some(lru.clone());
+
+        for typ in [rr::RecordType::A, rr::RecordType::AAAA] {
+            let q =
+                op::Query::query(rr::Name::from_ascii("example.com.").unwrap(), typ);
+            let mut m = op::Message::new();
+            m.add_query(q.clone());
+            lru.write().await.insert(
+                q.to_string(),
+                super::CachedResponse {
+                    msg: m,
+                    upstream: "udp#8.8.8.8:53".to_owned(),
+                    stored: Instant::now(),
+                    expires: Instant::now() + Duration::from_secs(30),
+                    stale_until: None,
+                },
+            );
+        }
+
+        let entries = resolver.cache_entries().await;
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|x| x.name == "example.com."
+            && x.upstream == "udp#8.8.8.8:53"
+            && x.ttl <= 30));
+
+        // the entries have no answers, a negative hit
+        let mut m = op::Message::new();
+        m.add_query(op::Query::query(
+            rr::Name::from_ascii("example.com.").unwrap(),
+            rr::RecordType::A,
+        ));
+        resolver.exchange(m).await.unwrap();
+        assert_eq!(
+            resolver.cache_stats(),
+            CacheStats {
+                hits: 1,
+                negative_hits: 1,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            resolver
+                .remove_cache("example.com", Some(rr::RecordType::AAAA))
+                .await,
+            1
+        );
+        let entries = resolver.cache_entries().await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].typ, "A");
+
+        assert_eq!(resolver.remove_cache("EXAMPLE.com.", None).await, 1);
+        assert!(resolver.cache_entries().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_records_out_of_zone() {
+        use std::time::Instant;
+
+        use tokio::sync::RwLock;
+
+        use crate::app::dns::{records::StaticRecords, ClashResolver};
+
+        let mut resolver = EnhancedResolver::new_default().await;
+        resolver.records = Some(Arc::new(
+            StaticRecords::parse(&[
+                "media.home.lan CNAME cdn.example.com".to_owned()
+            ])
+            .unwrap(),
+        ));
+        // the upstream answer of the domain out of the zone
+        let lru = Arc::new(RwLock::new(
+            lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                Duration::from_secs(60),
+                16,
+            ),
+        ));
+        let q = op::Query::query(
+            rr::Name::from_ascii("cdn.example.com.").unwrap(),
+            rr::RecordType::A,
+        );
+        let mut m = op::Message::new();
+        m.add_query(q.clone());
+        m.add_answer(rr::Record::from_rdata(
+            q.name().clone(),
+            300,
+            rr::RData::A(rr::rdata::A::new(93, 184, 216, 34)),
+        ));
+        lru.write().await.insert(
+            q.to_string(),
+            super::CachedResponse {
+                msg: m,
+                upstream: "udp#8.8.8.8:53".to_owned(),
+                stored: Instant::now(),
+                expires: Instant::now() + Duration::from_secs(30),
+
+                stale_until: None,
+            },
+        );
+        resolver.lru_cache = Some(lru);
+
+        let mut m = op::Message::new();
+        m.add_query(op::Query::query(
+            rr::Name::from_ascii("media.home.lan.").unwrap(),
+            rr::RecordType::A,
+        ));
+        let answer = resolver.exchange(m).await.unwrap();
+        let types = answer
+            .answers()
+            .iter()
+            .map(|x| x.record_type())
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec![rr::RecordType::CNAME, rr::RecordType::A]);
+
+        assert_eq!(
+            resolver.resolve_v4("media.home.lan", true).await.unwrap(),
+            Some("93.184.216.34".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_cache_duration() {
+        use crate::config::def::CacheTtl;
+
+        let limits = CacheTtl {
+            min: 10,
+            max: 600,
+            negative: 30,
+        };
+        let record = |ttl| {
+            rr::Record::from_rdata(
+                rr::Name::from_ascii("example.com.").unwrap(),
+                ttl,
+                rr::RData::A(rr::rdata::A::new(93, 184, 216, 34)),
+            )
+        };
+
+        let secs = |x| Some(Duration::from_secs(x));
+
+        // the least TTL of the answers
+        let mut m = op::Message::new();
+        m.add_answers([record(300), record(120)]);
+        assert_eq!(super::cache_duration(&m, &limits), secs(120));
+
+        // within the limits
+        let mut m = op::Message::new();
+        m.add_answer(record(3));
+        assert_eq!(super::cache_duration(&m, &limits), secs(10));
+        let mut m = op::Message::new();
+        m.add_answer(record(86400));
+        assert_eq!(super::cache_duration(&m, &limits), secs(600));
+
+        // negative, for the TTL of the SOA if less
+        assert_eq!(
+            super::cache_duration(&op::Message::new(), &limits),
+            secs(30)
+        );
+        let mut m = op::Message::new();
+        m.set_response_code(op::ResponseCode::NXDomain);
+        m.add_name_server(record(5));
+        assert_eq!(super::cache_duration(&m, &limits), secs(5));
+        m.set_response_code(op::ResponseCode::ServFail);
+        assert!(super::is_negative(&m));
+        assert_eq!(super::cache_duration(&m, &limits), secs(5));
+        m.set_response_code(op::ResponseCode::Refused);
+        assert_eq!(super::cache_duration(&m, &limits), None);
+        let no_negative = CacheTtl {
+            negative: 0,
+            ..limits
+        };
+        assert_eq!(
+            super::cache_duration(&op::Message::new(), &no_negative),
+            None
+        );
+
+        let mut m = op::Message::new();
+        m.add_answer(record(300));
+        super::map_ttls(&mut m, |ttl| ttl.saturating_sub(400));
+        assert_eq!(m.answers()[0].ttl(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_strategy() {
+        use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+        use async_trait::async_trait;
+
+        use crate::{app::dns::Client, config::def::DNSStrategy};
+
+        #[derive(Debug)]
+        struct Counting {
+            id: &'static str,
+            fails: bool,
+            queries: AtomicUsize,
+        }
+
+        #[async_trait]
+        impl Client for Counting {
+            fn id(&self) -> String {
+                self.id.to_owned()
+            }
+
+            async fn exchange(
+                &self,
+                msg: &op::Message,
+            ) -> anyhow::Result<op::Message> {
+                self.queries.fetch_add(1, Relaxed);
+                if self.fails {
+                    anyhow::bail!("unreachable");
+                }
+                Ok(msg.clone())
+            }
+        }
+
+        let bad = Arc::new(Counting {
+            id: "bad",
+            fails: true,
+            queries: AtomicUsize::new(0),
+        });
+        let good = Arc::new(Counting {
+            id: "good",
+            fails: false,
+            queries: AtomicUsize::new(0),
+        });
+        let clients: Vec<ThreadSafeDNSClient> = vec![bad.clone(), good.clone()];
+        let m = op::Message::new();
+
+        let mut resolver = EnhancedResolver::new_default().await;
+        resolver.strategy = DNSStrategy::Sequential;
+        for _ in 0..2 {
+            let (_, id) = resolver.batch_exchange_from(&clients, &m).await.unwrap();
+            assert_eq!(id, "good");
+        }
+        assert_eq!(bad.queries.load(Relaxed), 2);
+        assert_eq!(good.queries.load(Relaxed), 2);
+
+        // the one that failed lately isn't asked while the other answers
+        resolver.strategy = DNSStrategy::FirstHealthy;
+        for _ in 0..2 {
+            let (_, id) = resolver.batch_exchange_from(&clients, &m).await.unwrap();
+            assert_eq!(id, "good");
+        }
+        assert_eq!(bad.queries.load(Relaxed), 2);
+        assert_eq!(good.queries.load(Relaxed), 4);
+
+        assert!(resolver
+            .batch_exchange_from(&vec![bad.clone() as ThreadSafeDNSClient], &m)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stale_answer() {
+        use std::time::Instant;
+
+        use tokio::sync::RwLock;
+
+        use crate::{app::dns::ClashResolver, config::def::ServeStale};
+
+        let mut resolver = EnhancedResolver::new_default().await;
+        let lru = Arc::new(RwLock::new(
+            lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                Duration::from_secs(3600),
+                16,
+            ),
+        ));
+        resolver.lru_cache = Some(lru.clone());
+
+        let q = op::Query::query(
+            rr::Name::from_ascii("example.com.").unwrap(),
+            rr::RecordType::A,
+        );
+        let mut m = op::Message::new();
+        m.add_query(q.clone());
+        m.add_answer(rr::Record::from_rdata(
+            q.name().clone(),
+            300,
+            rr::RData::A(rr::rdata::A::new(93, 184, 216, 34)),
+        ));
+        lru.write().await.insert(
+            q.to_string(),
+            super::CachedResponse {
+                msg: m,
+                upstream: "udp#8.8.8.8:53".to_owned(),
+                stored: Instant::now() - Duration::from_secs(2),
+                expires: Instant::now() - Duration::from_secs(1),
+
+                stale_until: None,
+            },
+        );
+
+        // expired entries aren't listed
+        assert!(resolver.cache_entries().await.is_empty());
+        assert!(resolver.stale_answer(&q).await.is_none());
+
+        resolver.serve_stale = Some(ServeStale {
+            enable: true,
+            ..Default::default()
+        });
+        let stale = resolver.stale_answer(&q).await.unwrap();
+        assert_eq!(stale.answers().len(), 1);
+        assert_eq!(stale.answers()[0].ttl(), 30);
+    }
+
+    #[tokio::test]
+    async fn test_stale_on_servfail() {
+        use std::{
+            sync::atomic::{AtomicUsize, Ordering::Relaxed},
+            time::Instant,
+        };
+
+        use async_trait::async_trait;
+        use tokio::sync::RwLock;
+
+        use crate::{
+            app::dns::{CacheStats, ClashResolver, Client},
+            config::def::ServeStale,
+        };
+
+        #[derive(Debug, Default)]
+        struct ServFailing {
+            queries: AtomicUsize,
+        }
+
+        #[async_trait]
+        impl Client for ServFailing {
+            fn id(&self) -> String {
+                "servfail".to_owned()
+            }
+
+            async fn exchange(
+                &self,
+                msg: &op::Message,
+            ) -> anyhow::Result<op::Message> {
+                self.queries.fetch_add(1, Relaxed);
+                let mut m = msg.clone();
+                m.set_message_type(op::MessageType::Response);
+                m.set_response_code(op::ResponseCode::ServFail);
+                Ok(m)
+            }
+        }
+
+        let upstream = Arc::new(ServFailing::default());
+        let mut resolver = EnhancedResolver::new_default().await;
+        resolver.main = vec![upstream.clone() as ThreadSafeDNSClient];
+        resolver.serve_stale = Some(ServeStale {
+            enable: true,
+            ..Default::default()
+        });
+        let lru = Arc::new(RwLock::new(
+            lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                Duration::from_secs(3600),
+                16,
+            ),
+        ));
+        resolver.lru_cache = Some(lru.clone());
+
+        let query = |name: &str| {
+            let mut m = op::Message::new();
+            m.add_query(op::Query::query(
+                rr::Name::from_ascii(name).unwrap(),
+                rr::RecordType::A,
+            ));
+            m
+        };
+        let mut m = query("example.com.");
+        m.add_answer(rr::Record::from_rdata(
+            rr::Name::from_ascii("example.com.").unwrap(),
+            300,
+            rr::RData::A(rr::rdata::A::new(93, 184, 216, 34)),
+        ));
+        lru.write().await.insert(
+            m.query().unwrap().to_string(),
+            super::CachedResponse {
+                msg: m,
+                upstream: "udp#8.8.8.8:53".to_owned(),
+                stored: Instant::now() - Duration::from_secs(2),
+                expires: Instant::now() - Duration::from_secs(1),
+                stale_until: None,
+            },
+        );
+
+        // the upstream fails, the expired answer is served instead
+        let answer = resolver.exchange(query("example.com.")).await.unwrap();
+        assert_eq!(answer.response_code(), op::ResponseCode::NoError);
+        assert_eq!(answer.answers().len(), 1);
+        assert_eq!(answer.answers()[0].ttl(), 30);
+        assert_eq!(upstream.queries.load(Relaxed), 1);
+
+        // and kept being served without asking the upstream again
+        let answer = resolver.exchange(query("example.com.")).await.unwrap();
+        assert_eq!(answer.answers().len(), 1);
+        assert_eq!(upstream.queries.load(Relaxed), 1);
+
+        // nothing to serve stale, the client gets the SERVFAIL
+        let answer = resolver.exchange(query("example.org.")).await.unwrap();
+        assert_eq!(answer.response_code(), op::ResponseCode::ServFail);
+        assert_eq!(upstream.queries.load(Relaxed), 2);
+
+        assert_eq!(
+            resolver.cache_stats(),
+            CacheStats {
+                misses: 2,
+                stale: 2,
+                ..Default::default()
+            }
+        );
+    }
+
     #[tokio::test]
     async fn test_bad_labels_with_custom_resolver() {
         let name = rr::Name::from_str_relaxed("some_domain.understore")
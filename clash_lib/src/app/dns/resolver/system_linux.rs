@@ -1,13 +1,14 @@
 use std::sync::atomic::AtomicBool;
 
 use async_trait::async_trait;
+use hickory_proto::rr::RecordType;
 use hickory_resolver::{
     name_server::{GenericConnector, TokioRuntimeProvider},
     AsyncResolver,
 };
 use rand::seq::IteratorRandom;
 
-use crate::app::dns::{ClashResolver, ResolverKind};
+use crate::app::dns::{CacheEntry, CacheStats, ClashResolver, ResolverKind};
 
 pub struct SystemResolver {
     inner: AsyncResolver<GenericConnector<TokioRuntimeProvider>>,
@@ -90,6 +91,24 @@ impl ClashResolver for SystemResolver {
     async fn reverse_lookup(&self, _: std::net::IpAddr) -> Option<String> {
         None
     }
+
+    async fn flush_fake_ip(&self) {}
+
+    async fn flush_cache(&self) {
+        self.inner.clear_cache();
+    }
+
+    async fn cache_entries(&self) -> Vec<CacheEntry> {
+        vec![]
+    }
+
+    async fn remove_cache(&self, _: &str, _: Option<RecordType>) -> usize {
+        0
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
 }
 
 #[cfg(test)]
@@ -1,10 +1,11 @@
 use std::sync::atomic::AtomicBool;
 
 use async_trait::async_trait;
+use hickory_proto::rr::RecordType;
 use rand::seq::IteratorRandom;
 
 use crate::{
-    app::dns::{ClashResolver, ResolverKind},
+    app::dns::{CacheEntry, CacheStats, ClashResolver, ResolverKind},
     Error,
 };
 
@@ -109,6 +110,22 @@ impl ClashResolver for SystemResolver {
     async fn reverse_lookup(&self, _: std::net::IpAddr) -> Option<String> {
         None
     }
+
+    async fn flush_fake_ip(&self) {}
+
+    async fn flush_cache(&self) {}
+
+    async fn cache_entries(&self) -> Vec<CacheEntry> {
+        vec![]
+    }
+
+    async fn remove_cache(&self, _: &str, _: Option<RecordType>) -> usize {
+        0
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
 }
 
 #[cfg(test)]
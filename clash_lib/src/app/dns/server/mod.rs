@@ -3,10 +3,11 @@ use std::{net::IpAddr, time::Duration};
 use async_trait::async_trait;
 
 use hickory_proto::{
+    error::ProtoError,
     op::{Header, Message, MessageType, OpCode, ResponseCode},
     rr::{
         rdata::{A, AAAA},
-        RData, Record, RecordType,
+        Name, RData, Record, RecordType,
     },
 };
 use hickory_server::{
@@ -30,6 +31,9 @@ struct DnsListener {
 
 struct DnsHandler {
     resolver: ThreadSafeDNSResolver,
+    /// answer with fake IPs in fake-ip mode, not when they're for the tun
+    /// device only
+    fake_ip: bool,
 }
 
 #[derive(Error, Debug)]
@@ -73,46 +77,16 @@ impl DnsHandler {
             return Ok(response_handle.send_response(resp).await?);
         }
 
-        if self.resolver.fake_ip_enabled() {
-            let name = request.query().name();
-            let host = if name.is_fqdn() {
-                name.to_string().strip_suffix('.').unwrap().to_string()
-            } else {
-                name.to_string()
-            };
+        if self.fake_ip && self.resolver.fake_ip_enabled() {
+            let name = request.query().name().into();
+            let records = fake_ip_answer(&self.resolver, &name).await?;
 
             let builder = MessageResponseBuilder::from_message_request(request);
             let mut header = Header::response_from_request(request.header());
             header.set_authoritative(true);
 
-            match self.resolver.resolve(&host, true).await {
-                Ok(resp) => match resp {
-                    Some(ip) => {
-                        let rdata = match ip {
-                            IpAddr::V4(a) => RData::A(A(a)),
-                            IpAddr::V6(aaaa) => RData::AAAA(AAAA(aaaa)),
-                        };
-
-                        let records = vec![Record::from_rdata(
-                            name.into(),
-                            DEFAULT_DNS_SERVER_TTL,
-                            rdata,
-                        )];
-
-                        let resp =
-                            builder.build(header, records.iter(), &[], &[], &[]);
-                        return Ok(response_handle.send_response(resp).await?);
-                    }
-                    None => {
-                        let resp = builder.build_no_records(header);
-                        return Ok(response_handle.send_response(resp).await?);
-                    }
-                },
-                Err(e) => {
-                    debug!("dns resolve error: {}", e);
-                    return Err(DNSError::QueryFailed(e.to_string()));
-                }
-            }
+            let resp = builder.build(header, records.iter(), &[], &[], &[]);
+            return Ok(response_handle.send_response(resp).await?);
         }
 
         let mut m = Message::new();
@@ -171,12 +145,76 @@ impl DnsHandler {
     }
 }
 
+/// The fake IP of `name`, no records if it's not to get one.
+async fn fake_ip_answer(
+    resolver: &ThreadSafeDNSResolver,
+    name: &Name,
+) -> Result<Vec<Record>, DNSError> {
+    let host = name.to_string();
+    let host = host.strip_suffix('.').unwrap_or(&host);
+
+    match resolver.resolve(host, true).await {
+        Ok(Some(ip)) => {
+            let rdata = match ip {
+                IpAddr::V4(a) => RData::A(A(a)),
+                IpAddr::V6(aaaa) => RData::AAAA(AAAA(aaaa)),
+            };
+            Ok(vec![Record::from_rdata(
+                name.clone(),
+                DEFAULT_DNS_SERVER_TTL,
+                rdata,
+            )])
+        }
+        Ok(None) => Ok(vec![]),
+        Err(e) => {
+            debug!("dns resolve error: {}", e);
+            Err(DNSError::QueryFailed(e.to_string()))
+        }
+    }
+}
+
+/// Answer a query hijacked from the tun device, with fake IPs in fake-ip
+/// mode.
+pub async fn answer_hijacked(
+    resolver: &ThreadSafeDNSResolver,
+    query: &[u8],
+) -> Result<Vec<u8>, DNSError> {
+    let invalid = |e: ProtoError| DNSError::InvalidOpQuery(e.to_string());
+
+    let request = Message::from_vec(query).map_err(invalid)?;
+    let Some(q) = request.query().cloned() else {
+        return Err(DNSError::InvalidOpQuery("no question".to_owned()));
+    };
+
+    let mut response = match q.query_type() {
+        RecordType::A | RecordType::AAAA if resolver.fake_ip_enabled() => {
+            let mut m = Message::new();
+            m.set_authoritative(true);
+            m.set_recursion_available(true);
+            m.add_query(q.clone());
+            if q.query_type() == RecordType::A || resolver.ipv6() {
+                m.add_answers(fake_ip_answer(resolver, q.name()).await?);
+            }
+            m
+        }
+        _ => resolver
+            .exchange(request.clone())
+            .await
+            .map_err(|e| DNSError::QueryFailed(e.to_string()))?,
+    };
+    response.set_id(request.id());
+    response.set_message_type(MessageType::Response);
+    response.set_op_code(request.op_code());
+    response.set_recursion_desired(request.recursion_desired());
+    response.to_vec().map_err(invalid)
+}
+
 #[async_trait]
 impl RequestHandler for DnsHandler {
     async fn handle_request<R: ResponseHandler>(
         &self,
         request: &Request,
-        response_handle: R,
+        mut response_handle: R,
     ) -> ResponseInfo {
         debug!(
             "got dns request [{}][{}][{}] from {}",
@@ -186,13 +224,25 @@ impl RequestHandler for DnsHandler {
             request.src()
         );
 
-        match self.handle(request, response_handle).await {
+        match self.handle(request, response_handle.clone()).await {
             Ok(info) => info,
             Err(e) => {
                 debug!("dns request error: {}", e);
-                let mut h = Header::new();
-                h.set_response_code(ResponseCode::ServFail);
-                h.into()
+                let code = match e {
+                    DNSError::InvalidOpQuery(_) => ResponseCode::NotImp,
+                    _ => ResponseCode::ServFail,
+                };
+                // for the client not to wait for an answer that won't come
+                let resp = MessageResponseBuilder::from_message_request(request)
+                    .error_msg(request.header(), code);
+                response_handle
+                    .send_response(resp)
+                    .await
+                    .unwrap_or_else(|_| {
+                        let mut h = Header::new();
+                        h.set_response_code(code);
+                        h.into()
+                    })
             }
         }
     }
@@ -200,77 +250,130 @@ impl RequestHandler for DnsHandler {
 
 static DEFAULT_DNS_SERVER_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// The DNS listeners of `cfg`, bound already.
 pub async fn get_dns_listener(
     cfg: Config,
     resolver: ThreadSafeDNSResolver,
-) -> Option<Runner> {
-    let h = DnsHandler { resolver };
+) -> Result<Option<Runner>, crate::Error> {
+    if cfg.tls.is_none() && (cfg.listen.doh.is_some() || cfg.listen.dot.is_some()) {
+        warn!(
+            "dns doh and dot listeners use a test certificate, set dns.tls for \
+             clients to trust them"
+        );
+    }
+
+    let h = DnsHandler {
+        resolver,
+        fake_ip: !cfg.fake_ip_tun_only,
+    };
     let mut s = ServerFuture::new(h);
 
     let mut has_server = false;
+    let failed = |kind: &str, addr: std::net::SocketAddr, e: std::io::Error| {
+        crate::Error::DNSError(format!(
+            "dns server can't listen on {} {}: {}",
+            kind, addr, e
+        ))
+    };
 
     if let Some(addr) = cfg.listen.udp {
         has_server = true;
-        UdpSocket::bind(addr)
+        let socket = UdpSocket::bind(addr)
             .await
-            .map(|x| {
-                info!("dns server listening on udp: {}", addr);
-                s.register_socket(x);
-            })
-            .ok()?;
+            .map_err(|e| failed("udp", addr, e))?;
+        info!("dns server listening on udp: {}", addr);
+        s.register_socket(socket);
     }
     if let Some(addr) = cfg.listen.tcp {
         has_server = true;
-        TcpListener::bind(addr)
+        let listener = TcpListener::bind(addr)
             .await
-            .map(|x| {
-                info!("dns server listening on tcp: {}", addr);
-                s.register_listener(x, DEFAULT_DNS_SERVER_TIMEOUT);
-            })
-            .ok()?;
+            .map_err(|e| failed("tcp", addr, e))?;
+        info!("dns server listening on tcp: {}", addr);
+        s.register_listener(listener, DEFAULT_DNS_SERVER_TIMEOUT);
     }
     if let Some(c) = cfg.listen.doh {
         has_server = true;
-        TcpListener::bind(c.0)
+        let listener = TcpListener::bind(c.0)
             .await
-            .and_then(|x| {
-                info!("dns server listening on doh: {}", c.0);
-                s.register_https_listener(
-                    x,
-                    DEFAULT_DNS_SERVER_TIMEOUT,
-                    c.1.certificate_and_key,
-                    c.1.dns_hostname,
-                )?;
-                Ok(())
-            })
-            .ok()?;
+            .map_err(|e| failed("doh", c.0, e))?;
+        info!("dns server listening on doh: {}", c.0);
+        s.register_https_listener(
+            listener,
+            DEFAULT_DNS_SERVER_TIMEOUT,
+            c.1.certificate_and_key,
+            c.1.dns_hostname,
+        )
+        .map_err(|e| failed("doh", c.0, e))?;
     }
     if let Some(c) = cfg.listen.dot {
         has_server = true;
-        TcpListener::bind(c.0)
+        let listener = TcpListener::bind(c.0)
             .await
-            .and_then(|x| {
-                info!("dns server listening on dot: {}", c.0);
-                s.register_tls_listener(
-                    x,
-                    DEFAULT_DNS_SERVER_TIMEOUT,
-                    c.1.certificate_and_key,
-                )?;
-                Ok(())
-            })
-            .ok()?;
+            .map_err(|e| failed("dot", c.0, e))?;
+        info!("dns server listening on dot: {}", c.0);
+        s.register_tls_listener(
+            listener,
+            DEFAULT_DNS_SERVER_TIMEOUT,
+            c.1.certificate_and_key,
+        )
+        .map_err(|e| failed("dot", c.0, e))?;
     }
 
     if !has_server {
-        return None;
+        return Ok(None);
     }
 
     let mut l = DnsListener { server: s };
 
-    Some(Box::pin(async move {
+    Ok(Some(Box::pin(async move {
         l.server.block_until_done().await.map_err(|x| {
             warn!("dns server error: {}", x);
             crate::Error::DNSError(format!("dns server error: {}", x))
         })
-    }))
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hickory_proto::{
+        op::{Message, MessageType, Query},
+        rr::{rdata::A, Name, RData, RecordType},
+    };
+
+    use crate::app::dns::{MockClashResolver, ThreadSafeDNSResolver};
+
+    #[tokio::test]
+    async fn test_answer_hijacked() {
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_fake_ip_enabled().return_const(true);
+        resolver.expect_ipv6().return_const(false);
+        resolver
+            .expect_resolve()
+            .returning(|_, enhanced| Ok(enhanced.then_some([198, 18, 0, 5].into())));
+        let resolver: ThreadSafeDNSResolver = Arc::new(resolver);
+
+        let name = Name::from_ascii("example.com.").unwrap();
+        for (typ, answers) in [(RecordType::A, 1), (RecordType::AAAA, 0)] {
+            let mut query = Message::new();
+            query.set_id(7);
+            query.add_query(Query::query(name.clone(), typ));
+            let answer = super::answer_hijacked(&resolver, &query.to_vec().unwrap())
+                .await
+                .unwrap();
+
+            let answer = Message::from_vec(&answer).unwrap();
+            assert_eq!(answer.id(), 7);
+            assert_eq!(answer.message_type(), MessageType::Response);
+            assert_eq!(answer.answers().len(), answers);
+            if typ == RecordType::A {
+                assert_eq!(
+                    answer.answers()[0].data(),
+                    Some(&RData::A(A::new(198, 18, 0, 5)))
+                );
+            }
+        }
+    }
 }
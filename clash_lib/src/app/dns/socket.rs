@@ -0,0 +1,155 @@
+//! The sockets to the nameservers, made like the ones of the outbounds so
+//! the socket protector sees them too. Left out, they'd loop into an
+//! Android VPN.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use hickory_proto::{
+    iocompat::AsyncIoTokioAsStd,
+    tcp::{Connect, DnsTcpStream},
+    udp::{DnsUdpSocket, QuicLocalAddr, UdpSocket},
+    TokioTime,
+};
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::proxy::utils::{new_udp_socket, protect_socket};
+
+/// A UDP socket to a nameserver, for plain DNS and DoQ.
+pub(super) struct ProtectedUdpSocket(tokio::net::UdpSocket);
+
+impl ProtectedUdpSocket {
+    fn unspecified(addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsUdpSocket for ProtectedUdpSocket {
+    type Time = TokioTime;
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        DnsUdpSocket::poll_recv_from(&self.0, cx, buf)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        DnsUdpSocket::poll_send_to(&self.0, cx, buf, target)
+    }
+}
+
+#[async_trait]
+impl UdpSocket for ProtectedUdpSocket {
+    async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        Self::connect_with_bind(addr, Self::unspecified(addr)).await
+    }
+
+    async fn connect_with_bind(
+        addr: SocketAddr,
+        bind_addr: SocketAddr,
+    ) -> io::Result<Self> {
+        let socket = Self::bind(bind_addr).await?;
+        socket.0.connect(addr).await?;
+        Ok(socket)
+    }
+
+    async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        new_udp_socket(
+            Some(&addr),
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .map(Self)
+    }
+}
+
+impl QuicLocalAddr for ProtectedUdpSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+}
+
+/// A TCP stream to a nameserver, for plain DNS, DoT and DoH.
+pub(super) struct ProtectedTcpStream(AsyncIoTokioAsStd<TcpStream>);
+
+impl DnsTcpStream for ProtectedTcpStream {
+    type Time = TokioTime;
+}
+
+#[async_trait]
+impl Connect for ProtectedTcpStream {
+    async fn connect_with_bind(
+        addr: SocketAddr,
+        bind_addr: Option<SocketAddr>,
+    ) -> io::Result<Self> {
+        let domain = match addr {
+            SocketAddr::V4(_) => socket2::Domain::IPV4,
+            SocketAddr::V6(_) => socket2::Domain::IPV6,
+        };
+        let socket = socket2::Socket::new(domain, socket2::Type::STREAM, None)?;
+        protect_socket(&socket)?;
+        if let Some(bind_addr) = bind_addr {
+            socket.bind(&bind_addr.into())?;
+        }
+        socket.set_nodelay(true)?;
+        socket.set_nonblocking(true)?;
+
+        let stream = TcpSocket::from_std_stream(socket.into())
+            .connect(addr)
+            .await?;
+        Ok(Self(AsyncIoTokioAsStd(stream)))
+    }
+}
+
+impl AsyncRead for ProtectedTcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProtectedTcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
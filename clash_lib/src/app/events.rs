@@ -0,0 +1,347 @@
+//! Event bus for operational events, delivered to the configured webhooks.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::Utc;
+use hyper::{Body, Request};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+use crate::{
+    common::http::HttpClient,
+    config::def::{ErrorRate, Events, Webhook},
+    Runner,
+};
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Event {
+    ProxyDown {
+        proxy: String,
+    },
+    ProxyUp {
+        proxy: String,
+    },
+    ProviderUpdateFailed {
+        provider: String,
+        error: String,
+    },
+    ConfigReloaded,
+    ConfigReloadFailed {
+        error: String,
+    },
+    HighErrorRate {
+        outbound: String,
+        failed: u64,
+        total: u64,
+    },
+    /// the default outbound interface or its addresses changed
+    NetworkChanged {
+        interface: Option<String>,
+    },
+    /// the system clock is off, by `offset` milliseconds behind the NTP
+    /// server
+    ClockSkew {
+        offset: i64,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::ProxyDown { .. } => "proxy-down",
+            Event::ProxyUp { .. } => "proxy-up",
+            Event::ProviderUpdateFailed { .. } => "provider-update-failed",
+            Event::ConfigReloaded => "config-reloaded",
+            Event::ConfigReloadFailed { .. } => "config-reload-failed",
+            Event::HighErrorRate { .. } => "high-error-rate",
+            Event::NetworkChanged { .. } => "network-changed",
+            Event::ClockSkew { .. } => "clock-skew",
+        }
+    }
+}
+
+static BUS: Lazy<broadcast::Sender<Event>> = Lazy::new(|| broadcast::channel(64).0);
+
+/// whether the error rate monitor is running, dials aren't recorded otherwise
+static TRACKING: AtomicBool = AtomicBool::new(false);
+
+/// outbound name -> dials in the current window, the map is only written to
+/// the first time an outbound is seen
+static DIALS: Lazy<RwLock<HashMap<String, Dials>>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct Dials {
+    failed: AtomicU64,
+    total: AtomicU64,
+}
+
+impl Dials {
+    fn record(&self, ok: bool) {
+        if !ok {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take the counts and start a new window, `(failed, total)`.
+    fn take(&self) -> (u64, u64) {
+        let failed = self.failed.swap(0, Ordering::Relaxed);
+        let total = self.total.swap(0, Ordering::Relaxed);
+        (failed.min(total), total)
+    }
+}
+
+/// Stops recording dials when the error rate monitor goes away.
+struct Tracking;
+
+impl Tracking {
+    fn start() -> Self {
+        DIALS.write().unwrap().clear();
+        TRACKING.store(true, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for Tracking {
+    fn drop(&mut self) {
+        TRACKING.store(false, Ordering::Relaxed);
+        DIALS.write().unwrap().clear();
+    }
+}
+
+pub fn publish(event: Event) {
+    debug!("event: {:?}", event);
+    let _ = BUS.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    BUS.subscribe()
+}
+
+/// Record the result of connecting through `outbound`, for the error rate
+/// monitor.
+pub fn record_dial(outbound: &str, ok: bool) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(dials) = DIALS.read().unwrap().get(outbound) {
+        dials.record(ok);
+        return;
+    }
+    DIALS
+        .write()
+        .unwrap()
+        .entry(outbound.to_owned())
+        .or_default()
+        .record(ok);
+}
+
+/// Publish `high-error-rate` for the outbounds over the threshold and start a
+/// new window.
+fn check_error_rate(cfg: &ErrorRate) {
+    let mut idle = vec![];
+    let mut high = vec![];
+    for (outbound, dials) in DIALS.read().unwrap().iter() {
+        let (failed, total) = dials.take();
+        if total == 0 {
+            idle.push(outbound.clone());
+        } else if total >= cfg.min_connections
+            && failed as f64 / total as f64 >= cfg.threshold
+        {
+            high.push(Event::HighErrorRate {
+                outbound: outbound.clone(),
+                failed,
+                total,
+            });
+        }
+    }
+    // forget the outbounds that were removed or aren't used any more
+    if !idle.is_empty() {
+        let mut dials = DIALS.write().unwrap();
+        for outbound in idle {
+            if dials
+                .get(&outbound)
+                .is_some_and(|x| x.total.load(Ordering::Relaxed) == 0)
+            {
+                dials.remove(&outbound);
+            }
+        }
+    }
+    high.into_iter().for_each(publish);
+}
+
+fn wants(hook: &Webhook, event: &Event) -> bool {
+    hook.events.is_empty() || hook.events.iter().any(|x| x == event.kind())
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    time: chrono::DateTime<Utc>,
+}
+
+async fn deliver(hook: Webhook, body: String, client: HttpClient) {
+    let mut req =
+        Request::post(&hook.url).header("Content-Type", "application/json");
+    for (k, v) in hook.headers.iter() {
+        req = req.header(k, v);
+    }
+    let req = match req.body(Body::from(body)) {
+        Ok(req) => req,
+        Err(e) => {
+            warn!("invalid webhook {}: {}", hook.url, e);
+            return;
+        }
+    };
+
+    match tokio::time::timeout(Duration::from_secs(10), client.request(req)).await {
+        Ok(Ok(res)) if res.status().is_success() => {}
+        Ok(Ok(res)) => warn!("webhook {} returned {}", hook.url, res.status()),
+        Ok(Err(e)) => warn!("webhook {} failed: {}", hook.url, e),
+        Err(_) => warn!("webhook {} timed out", hook.url),
+    }
+}
+
+/// Deliver events to the webhooks, `None` if there are none configured.
+pub fn get_runner(cfg: Events, client: HttpClient) -> Option<Runner> {
+    if cfg.webhooks.is_empty() {
+        return None;
+    }
+
+    let mut rx = subscribe();
+    Some(Box::pin(async move {
+        let mut ticker =
+            tokio::time::interval(Duration::from_secs(cfg.error_rate.window.max(1)));
+        // drop the dials recorded before this runner started
+        ticker.tick().await;
+        let _tracking = Tracking::start();
+
+        loop {
+            let event = tokio::select! {
+                _ = ticker.tick() => {
+                    check_error_rate(&cfg.error_rate);
+                    continue;
+                }
+                event = rx.recv() => event,
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("{} events dropped", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            let body = match serde_json::to_string(&Payload {
+                event: &event,
+                time: Utc::now(),
+            }) {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("failed to serialize event: {}", e);
+                    continue;
+                }
+            };
+            for hook in cfg.webhooks.iter().filter(|x| wants(x, &event)) {
+                tokio::spawn(deliver(hook.clone(), body.clone(), client.clone()));
+            }
+        }
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::def::{ErrorRate, Webhook};
+
+    use super::{
+        check_error_rate, record_dial, subscribe, wants, Event, Tracking, DIALS,
+    };
+
+    #[test]
+    fn test_wants() {
+        let mut hook = Webhook {
+            url: "http://localhost".to_owned(),
+            events: vec![],
+            headers: Default::default(),
+        };
+        let event = Event::ProxyDown {
+            proxy: "ss01".to_owned(),
+        };
+        assert!(wants(&hook, &event));
+        hook.events = vec!["config-reloaded".to_owned()];
+        assert!(!wants(&hook, &event));
+        assert!(wants(&hook, &Event::ConfigReloaded));
+    }
+
+    #[test]
+    fn test_payload() {
+        let event = Event::HighErrorRate {
+            outbound: "ss01".to_owned(),
+            failed: 3,
+            total: 4,
+        };
+        let v = serde_json::to_value(&event).unwrap();
+        assert_eq!(v["type"], "high-error-rate");
+        assert_eq!(v["outbound"], "ss01");
+    }
+
+    #[test]
+    fn test_error_rate() {
+        let mut rx = subscribe();
+        record_dial("test-error-rate-untracked", false);
+        assert!(!DIALS
+            .read()
+            .unwrap()
+            .contains_key("test-error-rate-untracked"));
+
+        let tracking = Tracking::start();
+        for i in 0..10 {
+            record_dial("test-error-rate-bad", i % 5 == 0);
+            record_dial("test-error-rate-good", true);
+        }
+        record_dial("test-error-rate-few", false);
+
+        check_error_rate(&ErrorRate {
+            threshold: 0.5,
+            window: 60,
+            min_connections: 5,
+        });
+
+        let mut events = vec![];
+        while let Ok(e) = rx.try_recv() {
+            events.push(e);
+        }
+        assert!(events.contains(&Event::HighErrorRate {
+            outbound: "test-error-rate-bad".to_owned(),
+            failed: 8,
+            total: 10,
+        }));
+        assert!(!events.iter().any(|e| matches!(
+            e,
+            Event::HighErrorRate { outbound, .. }
+                if outbound.starts_with("test-error-rate-")
+                    && outbound != "test-error-rate-bad"
+        )));
+
+        // an empty window forgets the outbound
+        check_error_rate(&ErrorRate::default());
+        assert!(!DIALS.read().unwrap().contains_key("test-error-rate-bad"));
+
+        drop(tracking);
+        record_dial("test-error-rate-bad", false);
+        assert!(!DIALS.read().unwrap().contains_key("test-error-rate-bad"));
+    }
+}
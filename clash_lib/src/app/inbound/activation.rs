@@ -0,0 +1,338 @@
+//! Listening sockets handed over by systemd socket activation or launchd.
+//!
+//! A socket is used by the inbound it's named after, `http`, `socks` or
+//! `mixed`, set with `FileDescriptorName=` in the systemd socket unit or as
+//! the key under `Sockets` in the launchd plist. Unnamed systemd sockets are
+//! used by the inbound listening on the same port.
+//!
+//! The sockets are kept open for the life of the process, so the inbounds
+//! can listen on them again after a reload.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+};
+
+use once_cell::sync::Lazy;
+use tracing::{debug, info, warn};
+
+use crate::{config::def::InboundTcp, proxy::utils::apply_socket_buffers};
+
+/// the names of the inbounds that can take an activated socket
+#[cfg(target_os = "macos")]
+const NAMES: &[&str] = &["http", "socks", "mixed"];
+
+struct Activated {
+    name: Option<String>,
+    listener: TcpListener,
+}
+
+static ACTIVATED: Lazy<Vec<Activated>> = Lazy::new(|| {
+    let activated = receive();
+    for x in activated.iter() {
+        debug!(
+            "activated socket {} at {:?}",
+            x.name.as_deref().unwrap_or("(unnamed)"),
+            x.listener.local_addr()
+        );
+    }
+    activated
+});
+
+/// The activated socket for the inbound `name` listening at `addr`, if any.
+fn tcp_listener(name: &str, addr: SocketAddr) -> Option<TcpListener> {
+    let matches_addr = |x: &Activated| {
+        x.listener.local_addr().is_ok_and(|local| {
+            local.port() == addr.port()
+                && (addr.ip().is_unspecified() || local.ip() == addr.ip())
+        })
+    };
+    let activated = ACTIVATED
+        .iter()
+        .find(|x| x.name.as_deref() == Some(name))
+        .or_else(|| {
+            ACTIVATED
+                .iter()
+                .find(|x| x.name.is_none() && matches_addr(x))
+        })?;
+
+    match activated.listener.try_clone() {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            warn!("failed to use activated socket for {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Listen on the activated socket for the inbound `name` listening at
+/// `addr`, or bind a new one with `options`.
+pub async fn bind_tcp(
+    name: &str,
+    addr: SocketAddr,
+    options: &InboundTcp,
+) -> io::Result<tokio::net::TcpListener> {
+    match tcp_listener(name, addr) {
+        Some(listener) => {
+            info!(
+                "{} listening on activated socket {:?}",
+                name,
+                listener.local_addr()
+            );
+            if let Err(e) = apply_socket_buffers(&socket2::SockRef::from(&listener))
+            {
+                warn!("failed to size the socket buffers of {}: {}", name, e);
+            }
+            tokio::net::TcpListener::from_std(listener)
+        }
+        None => tokio::net::TcpListener::from_std(listen(addr, options)?),
+    }
+}
+
+/// Bind a listening socket at `addr`, with Fast Open and deferred accept if
+/// the platform supports them.
+fn listen(addr: SocketAddr, options: &InboundTcp) -> io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    // as tokio does, so a restart doesn't wait for TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+
+    // failing these only costs some latency
+    if options.fast_open {
+        if let Err(e) = set_fast_open(&socket) {
+            warn!("failed to enable TCP Fast Open on {}: {}", addr, e);
+        }
+    }
+    if options.defer_accept > 0 {
+        if let Err(e) = set_defer_accept(&socket, options.defer_accept) {
+            warn!("failed to enable deferred accept on {}: {}", addr, e);
+        }
+    }
+    if let Err(e) = apply_socket_buffers(&socket) {
+        warn!("failed to size the socket buffers of {}: {}", addr, e);
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn set_tcp_option(
+    socket: &socket2::Socket,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Accept data in the SYN of clients with a Fast Open cookie.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_fast_open(socket: &socket2::Socket) -> io::Result<()> {
+    // the queue of connections pending the rest of their handshake
+    set_tcp_option(socket, libc::TCP_FASTOPEN, 256)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn set_fast_open(socket: &socket2::Socket) -> io::Result<()> {
+    set_tcp_option(socket, libc::TCP_FASTOPEN, 1)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+fn set_fast_open(_: &socket2::Socket) -> io::Result<()> {
+    debug!("TCP Fast Open isn't supported on this platform");
+    Ok(())
+}
+
+/// Only wake the accept loop once a connection has sent something, or
+/// `secs` passed, so port scanners don't.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_defer_accept(socket: &socket2::Socket, secs: u32) -> io::Result<()> {
+    set_tcp_option(
+        socket,
+        libc::TCP_DEFER_ACCEPT,
+        secs.min(libc::c_int::MAX as u32) as libc::c_int,
+    )
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_defer_accept(_: &socket2::Socket, _: u32) -> io::Result<()> {
+    debug!("deferred accept isn't supported on this platform");
+    Ok(())
+}
+
+/// Wrap `fd` if it's a listening TCP socket, `fd` is left alone otherwise.
+#[cfg(unix)]
+fn to_listener(fd: std::os::fd::RawFd) -> io::Result<TcpListener> {
+    use std::os::fd::{BorrowedFd, FromRawFd};
+
+    // not ours to close until it's known to be usable
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let socket = socket2::SockRef::from(&borrowed);
+    let is_tcp = socket.r#type().is_ok_and(|x| x == socket2::Type::STREAM)
+        && socket.local_addr().is_ok_and(|x| x.as_socket().is_some());
+    if !is_tcp {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a TCP socket",
+        ));
+    }
+    socket.set_nonblocking(true)?;
+    // keep it from leaking into child processes
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+/// See sd_listen_fds(3).
+#[cfg(all(unix, not(target_os = "macos")))]
+fn receive() -> Vec<Activated> {
+    const LISTEN_FDS_START: i32 = 3;
+
+    let ours = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|x| x.parse::<u32>().ok())
+        .is_some_and(|x| x == std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|x| x.parse::<i32>().ok())
+        .unwrap_or_default();
+    if !ours || count <= 0 {
+        return vec![];
+    }
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .filter_map(|fd| {
+            // systemd names sockets without a FileDescriptorName= `unknown`
+            let name = names
+                .next()
+                .filter(|x| !x.is_empty() && *x != "unknown")
+                .map(ToOwned::to_owned);
+            match to_listener(fd) {
+                Ok(listener) => Some(Activated { name, listener }),
+                Err(e) => {
+                    warn!("ignoring activated socket {}: {}", fd, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// See launch_activate_socket(3).
+#[cfg(target_os = "macos")]
+fn receive() -> Vec<Activated> {
+    use std::ffi::{c_char, c_int, CString};
+
+    extern "C" {
+        fn launch_activate_socket(
+            name: *const c_char,
+            fds: *mut *mut c_int,
+            cnt: *mut libc::size_t,
+        ) -> c_int;
+    }
+
+    let mut activated = vec![];
+    for name in NAMES {
+        let c_name = CString::new(*name).expect("no nul in socket names");
+        let mut fds: *mut c_int = std::ptr::null_mut();
+        let mut cnt: libc::size_t = 0;
+        // fails with ESRCH when not started by launchd, or ENOENT when
+        // there's no socket with the name
+        if unsafe { launch_activate_socket(c_name.as_ptr(), &mut fds, &mut cnt) }
+            != 0
+        {
+            continue;
+        }
+        for i in 0..cnt {
+            let fd = unsafe { *fds.add(i) };
+            match to_listener(fd) {
+                Ok(listener) => activated.push(Activated {
+                    name: Some(name.to_string()),
+                    listener,
+                }),
+                Err(e) => warn!("ignoring activated socket {}: {}", fd, e),
+            }
+        }
+        unsafe { libc::free(fds as *mut libc::c_void) };
+    }
+    activated
+}
+
+#[cfg(not(unix))]
+fn receive() -> Vec<Activated> {
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::bind_tcp;
+    #[cfg(unix)]
+    use super::to_listener;
+    use crate::config::def::InboundTcp;
+
+    #[tokio::test]
+    async fn test_bind_tcp() {
+        let options = InboundTcp {
+            fast_open: true,
+            defer_accept: 5,
+        };
+        let listener = bind_tcp("test", "127.0.0.1:0".parse().unwrap(), &options)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // accepted once it has sent something
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 5];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_to_listener() {
+        use std::os::fd::{AsRawFd, IntoRawFd};
+
+        // left open when it isn't a TCP socket
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(to_listener(udp.as_raw_fd()).is_err());
+        assert!(udp.local_addr().is_ok());
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = to_listener(tcp.into_raw_fd()).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+}
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::{sync::Mutex, task::JoinHandle};
 
 use crate::{
     app::{
@@ -7,7 +7,10 @@ use crate::{
         inbound::network_listener::{ListenerType, NetworkInboundListener},
     },
     common::auth::ThreadSafeAuthenticator,
-    config::internal::config::{BindAddress, Inbound},
+    config::{
+        def::InboundTcp,
+        internal::config::{BindAddress, Inbound},
+    },
     Error, Runner,
 };
 use std::{collections::HashMap, sync::Arc};
@@ -16,12 +19,15 @@ pub struct InboundManager {
     network_listeners: HashMap<ListenerType, NetworkInboundListener>,
     dispatcher: Arc<Dispatcher>,
     bind_address: BindAddress,
+    tcp_options: InboundTcp,
     authenticator: ThreadSafeAuthenticator,
+    /// as configured, also those of the listeners not run here
+    ports: Ports,
 }
 
 pub type ThreadSafeInboundManager = Arc<Mutex<InboundManager>>;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Ports {
     pub port: Option<u16>,
     #[serde(rename = "socks-port")]
@@ -34,6 +40,16 @@ pub struct Ports {
     pub mixed_port: Option<u16>,
 }
 
+/// Stop the listeners run by `handle` and wait for their sockets to close,
+/// so that the listeners started next can bind the same ports. The
+/// connections they accepted run on tasks of their own and carry on.
+pub async fn stop_listeners(handle: Option<JoinHandle<Result<(), Error>>>) {
+    if let Some(h) = handle {
+        h.abort();
+        let _ = h.await;
+    }
+}
+
 impl InboundManager {
     pub fn new(
         inbound: Inbound,
@@ -46,7 +62,9 @@ impl InboundManager {
             network_listeners,
             dispatcher,
             bind_address: inbound.bind_address,
+            tcp_options: inbound.tcp,
             authenticator,
+            ports: Ports::default(),
         };
 
         let ports = Ports {
@@ -82,31 +100,11 @@ impl InboundManager {
     }
 
     pub fn get_ports(&self) -> Ports {
-        let mut ports = Ports {
-            port: None,
-            socks_port: None,
-            redir_port: None,
-            tproxy_port: None,
-            mixed_port: None,
-        };
-        self.network_listeners
-            .values()
-            .for_each(|x| match x.listener_type {
-                ListenerType::Http => {
-                    ports.port = Some(x.port);
-                }
-                ListenerType::Socks5 => {
-                    ports.socks_port = Some(x.port);
-                }
-                ListenerType::Mixed => {
-                    ports.mixed_port = Some(x.port);
-                }
-            });
-
-        ports
+        self.ports.clone()
     }
 
     pub fn rebuild_listeners(&mut self, ports: Ports) {
+        self.ports = ports.clone();
         let mut network_listeners = HashMap::new();
         if let Some(http_port) = ports.port {
             network_listeners.insert(
@@ -114,6 +112,7 @@ impl InboundManager {
                 NetworkInboundListener {
                     name: "HTTP".to_string(),
                     bind_addr: self.bind_address.clone(),
+                    tcp_options: self.tcp_options,
                     port: http_port,
                     listener_type: ListenerType::Http,
                     dispatcher: self.dispatcher.clone(),
@@ -128,6 +127,7 @@ impl InboundManager {
                 NetworkInboundListener {
                     name: "SOCKS5".to_string(),
                     bind_addr: self.bind_address.clone(),
+                    tcp_options: self.tcp_options,
                     port: socks_port,
                     listener_type: ListenerType::Socks5,
                     dispatcher: self.dispatcher.clone(),
@@ -142,6 +142,7 @@ impl InboundManager {
                 NetworkInboundListener {
                     name: "Mixed".to_string(),
                     bind_addr: self.bind_address.clone(),
+                    tcp_options: self.tcp_options,
                     port: mixed_port,
                     listener_type: ListenerType::Mixed,
                     dispatcher: self.dispatcher.clone(),
@@ -1,2 +1,3 @@
+pub mod activation;
 pub mod manager;
 pub mod network_listener;
@@ -1,5 +1,6 @@
 use crate::{
-    common::auth::ThreadSafeAuthenticator, config::internal::config::BindAddress,
+    common::auth::ThreadSafeAuthenticator,
+    config::{def::InboundTcp, internal::config::BindAddress},
 };
 
 use crate::proxy::{http, mixed, socks, AnyInboundListener};
@@ -24,6 +25,7 @@ pub enum ListenerType {
 pub struct NetworkInboundListener {
     pub name: String,
     pub bind_addr: BindAddress,
+    pub tcp_options: InboundTcp,
     pub port: u16,
     pub listener_type: ListenerType,
     pub dispatcher: Arc<Dispatcher>,
@@ -109,16 +111,19 @@ impl NetworkInboundListener {
         let listener: AnyInboundListener = match self.listener_type {
             ListenerType::Http => http::Listener::new(
                 (ip, self.port).into(),
+                self.tcp_options,
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
             ),
             ListenerType::Socks5 => socks::Listener::new(
                 (ip, self.port).into(),
+                self.tcp_options,
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
             ),
             ListenerType::Mixed => mixed::Listener::new(
                 (ip, self.port).into(),
+                self.tcp_options,
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
             ),
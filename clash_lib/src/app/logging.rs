@@ -1,6 +1,11 @@
-use std::io::IsTerminal;
+use std::{
+    fs::{File, OpenOptions},
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
-use crate::def::LogLevel;
+use crate::def::{LogFile, LogFormat, LogLevel};
 use opentelemetry::{
     global::{self},
     trace::TracerProvider,
@@ -12,12 +17,26 @@ use opentelemetry_semantic_conventions::{
     SCHEMA_URL,
 };
 use serde::Serialize;
+use serde_json::Value;
 use tokio::sync::broadcast::Sender;
 
 use tracing::{debug, error};
 use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
 use tracing_oslog::OsLogger;
-use tracing_subscriber::{filter, filter::Directive, prelude::*, EnvFilter, Layer};
+use tracing_subscriber::{
+    field::RecordFields,
+    filter,
+    filter::Directive,
+    fmt::{
+        format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields,
+        MakeWriter,
+    },
+    prelude::*,
+    registry::LookupSpan,
+    EnvFilter, Layer,
+};
+
+type Map = serde_json::Map<String, Value>;
 
 impl From<LogLevel> for filter::LevelFilter {
     fn from(level: LogLevel) -> Self {
@@ -37,10 +56,36 @@ pub struct LogEvent {
     pub level: LogLevel,
     #[serde(rename = "payload")]
     pub msg: String,
+    /// The connection the line was logged for, if any.
+    #[serde(rename = "connectionId", skip_serializing_if = "Option::is_none")]
+    pub connection_id: Option<String>,
 }
 
 pub struct EventCollector(Vec<Sender<LogEvent>>);
 
+/// The `connection_id` field of a span, kept in the span's extensions.
+struct ConnectionId(String);
+
+struct ConnectionIdVisitor(Option<String>);
+
+impl tracing::field::Visit for ConnectionIdVisitor {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == "connection_id" {
+            self.0 = Some(value.to_owned());
+        }
+    }
+
+    fn record_debug(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &dyn std::fmt::Debug,
+    ) {
+        if field.name() == "connection_id" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
 impl EventCollector {
     pub fn new(recivers: Vec<Sender<LogEvent>>) -> Self {
         Self(recivers)
@@ -49,16 +94,35 @@ impl EventCollector {
 
 impl<S> Layer<S> for EventCollector
 where
-    S: tracing::Subscriber,
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
 {
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let mut visitor = ConnectionIdVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(connection_id), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(ConnectionId(connection_id));
+        }
+    }
+
     fn on_event(
         &self,
         event: &tracing::Event<'_>,
-        _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
         let mut strs = vec![];
         event.record(&mut EventVisitor(&mut strs));
 
+        let connection_id = ctx.event_scope(event).and_then(|mut scope| {
+            scope.find_map(|span| {
+                span.extensions().get::<ConnectionId>().map(|x| x.0.clone())
+            })
+        });
+
         let event = LogEvent {
             level: match *event.metadata().level() {
                 tracing::Level::ERROR => LogLevel::Error,
@@ -68,6 +132,7 @@ where
                 tracing::Level::TRACE => LogLevel::Debug,
             },
             msg: strs.join(" "),
+            connection_id,
         };
         for tx in &self.0 {
             _ = tx.send(event.clone());
@@ -75,29 +140,275 @@ where
     }
 }
 
-struct W(Option<NonBlocking>);
+/// A log file renamed to `<path>.1` once it's larger than `max_size`,
+/// shifting older files up to `<path>.<max_backups>`.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_backups: usize,
+    max_age: Option<Duration>,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(
+        path: PathBuf,
+        max_size: u64,
+        max_backups: usize,
+        max_age: Option<Duration>,
+    ) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let f = Self {
+            path,
+            max_size,
+            max_backups,
+            max_age,
+            file,
+            size,
+        };
+        f.remove_expired();
+        Ok(f)
+    }
+
+    fn backup(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn remove_expired(&self) {
+        let Some(max_age) = self.max_age else {
+            return;
+        };
+        for n in 1..=self.max_backups {
+            let backup = self.backup(n);
+            let expired = std::fs::metadata(&backup)
+                .and_then(|x| x.modified())
+                .ok()
+                .and_then(|x| SystemTime::now().duration_since(x).ok())
+                .is_some_and(|x| x > max_age);
+            if expired {
+                let _ = std::fs::remove_file(backup);
+            }
+        }
+    }
 
-impl std::io::Write for W {
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.max_backups == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(self.backup(self.max_backups));
+            for n in (1..self.max_backups).rev() {
+                let _ = std::fs::rename(self.backup(n), self.backup(n + 1));
+            }
+            std::fs::rename(&self.path, self.backup(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        self.remove_expired();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match self.0 {
-            Some(ref mut w) => w.write(buf),
-            None => Ok(buf.len()),
+        if self.max_size > 0
+            && self.size > 0
+            && self.size + buf.len() as u64 > self.max_size
+        {
+            self.rotate()?;
         }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        match self.0 {
-            Some(ref mut w) => w.flush(),
-            None => Ok(()),
+        self.file.flush()
+    }
+}
+
+fn file_appender(
+    cwd: &str,
+    log_file: &LogFile,
+) -> anyhow::Result<Option<(NonBlocking, WorkerGuard)>> {
+    let Some(path) = &log_file.path else {
+        return Ok(None);
+    };
+    let file = RotatingFile::open(
+        Path::new(cwd).join(path),
+        log_file.max_size * 1024 * 1024,
+        log_file.max_backups,
+        (log_file.max_age > 0)
+            .then(|| Duration::from_secs(log_file.max_age * 24 * 60 * 60)),
+    )?;
+    Ok(Some(tracing_appender::non_blocking(file)))
+}
+
+fn text_layer<S, W>(writer: W, ansi: bool) -> impl Layer<S> + Send + Sync
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::Layer::new()
+        .with_ansi(ansi)
+        .compact()
+        .with_target(true)
+        .with_file(true)
+        .with_line_number(true)
+        .with_level(true)
+        .with_thread_ids(true)
+        .with_writer(writer)
+}
+
+fn json_layer<S, W>(writer: W) -> impl Layer<S> + Send + Sync
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::Layer::new()
+        .fmt_fields(JsonFields)
+        .event_format(JsonFormat)
+        .with_writer(writer)
+}
+
+/// Span fields as a JSON object.
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> std::fmt::Result {
+        let mut map = Map::new();
+        fields.record(&mut JsonVisitor(&mut map));
+        write!(writer, "{}", Value::Object(map))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> std::fmt::Result {
+        let mut map = serde_json::from_str(&current.fields).unwrap_or_default();
+        fields.record(&mut JsonVisitor(&mut map));
+        current.fields = Value::Object(map).to_string();
+        Ok(())
+    }
+}
+
+/// One JSON object per line: `time`, `level`, `module`, `message`, the
+/// `connection_id` of the enclosing span if any, and the remaining event and
+/// span `fields`.
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        let meta = event.metadata();
+        let mut fields = Map::new();
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let ext = span.extensions();
+                if let Some(Ok(span_fields)) = ext
+                    .get::<FormattedFields<JsonFields>>()
+                    .map(|x| serde_json::from_str::<Map>(&x.fields))
+                {
+                    fields.extend(span_fields);
+                }
+            }
         }
+        event.record(&mut JsonVisitor(&mut fields));
+
+        let mut record = Map::new();
+        record.insert(
+            "time".into(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+                .into(),
+        );
+        record.insert("level".into(), meta.level().as_str().into());
+        record.insert(
+            "module".into(),
+            meta.module_path().unwrap_or(meta.target()).into(),
+        );
+        if let Some(message) = fields.remove("message") {
+            record.insert("message".into(), message);
+        }
+        if let Some(id) = fields.remove("connection_id") {
+            record.insert("connection_id".into(), id);
+        }
+        if !fields.is_empty() {
+            record.insert("fields".into(), Value::Object(fields));
+        }
+        writeln!(writer, "{}", Value::Object(record))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map);
+
+impl<'a> tracing::field::Visit for JsonVisitor<'a> {
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_error(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &(dyn std::error::Error + 'static),
+    ) {
+        self.0.insert(field.name().into(), value.to_string().into());
+    }
+
+    fn record_debug(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &dyn std::fmt::Debug,
+    ) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
     }
 }
 
 pub fn setup_logging(
     level: LogLevel,
+    format: LogFormat,
     collector: EventCollector,
     cwd: &str,
-    log_file: Option<String>,
+    log_file: LogFile,
 ) -> anyhow::Result<Option<WorkerGuard>> {
     let filter = EnvFilter::builder()
         .with_default_directive(
@@ -145,13 +456,12 @@ pub fn setup_logging(
         None
     };
 
-    let (appender, g) = if let Some(log_file) = log_file {
-        let file_appender = tracing_appender::rolling::daily(cwd, log_file);
-        let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
-        (Some(non_blocking), Some(guard))
-    } else {
-        (None, None)
+    let (appender, g) = match file_appender(cwd, &log_file)? {
+        Some((appender, guard)) => (Some(appender), Some(guard)),
+        None => (None, None),
     };
+    // without a file there'd be no logs at all
+    let console = log_file.console || appender.is_none();
 
     let console_layer = if cfg!(feature = "tracing") {
         Some(console_subscriber::spawn())
@@ -159,26 +469,31 @@ pub fn setup_logging(
         None
     };
 
-    let subscriber = tracing_subscriber::registry()
-        .with(jaeger)
-        .with(filter)
-        .with(collector)
-        .with(console_layer)
-        .with(
-            tracing_subscriber::fmt::Layer::new()
-                .with_ansi(std::io::stdout().is_terminal())
-                .compact()
-                .with_target(true)
-                .with_file(true)
-                .with_line_number(true)
-                .with_level(true)
-                .with_thread_ids(true)
-                .with_writer(move || -> Box<dyn std::io::Write> {
-                    Box::new(W(appender.clone()))
-                })
-                .with_writer(std::io::stdout),
-        )
-        .with(ios_os_log);
+    let subscriber =
+        tracing_subscriber::registry()
+            .with(jaeger)
+            .with(filter)
+            .with(collector)
+            .with(console_layer)
+            .with((console && format == LogFormat::Text).then(|| {
+                text_layer(std::io::stdout, std::io::stdout().is_terminal())
+            }))
+            .with(
+                (console && format == LogFormat::Json)
+                    .then(|| json_layer(std::io::stdout)),
+            )
+            .with(
+                appender
+                    .clone()
+                    .filter(|_| format == LogFormat::Text)
+                    .map(|x| text_layer(x, false)),
+            )
+            .with(
+                appender
+                    .filter(|_| format == LogFormat::Json)
+                    .map(json_layer),
+            )
+            .with(ios_os_log);
 
     tracing::subscriber::set_global_default(subscriber)
         .map_err(|x| anyhow!("setup logging error: {}", x))?;
@@ -241,3 +556,96 @@ impl<'a> tracing::field::Visit for EventVisitor<'a> {
         println!("error {} = {}", field.name(), value);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+    use tracing_subscriber::prelude::*;
+
+    use super::{json_layer, EventCollector, RotatingFile};
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clash.log");
+        let mut f = RotatingFile::open(path.clone(), 10, 2, None).unwrap();
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            f.write_all(line.as_bytes()).unwrap();
+        }
+        f.flush().unwrap();
+
+        let read =
+            |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("clash.log"), "dddddd\n");
+        assert_eq!(read("clash.log.1"), "cccccc\n");
+        assert_eq!(read("clash.log.2"), "bbbbbb\n");
+        assert!(!dir.path().join("clash.log.3").exists());
+    }
+
+    #[test]
+    fn test_json_format() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let writer = buf.clone();
+        let subscriber = tracing_subscriber::registry().with(json_layer(
+            move || -> Box<dyn Write> {
+                struct W(Arc<Mutex<Vec<u8>>>);
+                impl Write for W {
+                    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                        self.0.lock().unwrap().write(buf)
+                    }
+
+                    fn flush(&mut self) -> std::io::Result<()> {
+                        Ok(())
+                    }
+                }
+                Box::new(W(writer.clone()))
+            },
+        ));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("conn", connection_id = "c1");
+            let _g = span.enter();
+            tracing::info!(bytes = 42u64, "closed");
+        });
+
+        let out = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        let record: Value = serde_json::from_str(out.trim()).unwrap();
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["message"], "closed");
+        assert_eq!(record["connection_id"], "c1");
+        assert_eq!(record["fields"]["bytes"], 42);
+        assert!(record["module"]
+            .as_str()
+            .unwrap()
+            .ends_with("logging::tests"));
+        assert!(record["time"].is_string());
+    }
+
+    #[test]
+    fn test_collect_connection_id() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel(4);
+        let subscriber =
+            tracing_subscriber::registry().with(EventCollector::new(vec![tx]));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("outside");
+            let span = tracing::info_span!("connection", connection_id = "c1");
+            let _g = span.enter();
+            tracing::info_span!("match_rule").in_scope(|| tracing::info!("inside"));
+        });
+
+        let outside = rx.try_recv().unwrap();
+        assert_eq!(outside.connection_id, None);
+        assert!(serde_json::to_value(&outside)
+            .unwrap()
+            .get("connectionId")
+            .is_none());
+        let inside = rx.try_recv().unwrap();
+        assert_eq!(inside.msg, "inside");
+        assert_eq!(inside.connection_id.as_deref(), Some("c1"));
+    }
+}
@@ -0,0 +1,269 @@
+//! Prometheus metrics, exported by the controller on `/metrics`.
+
+use std::{collections::HashMap, time::Duration};
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    core::{Collector, MetricVec, MetricVecBuilder},
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+static REGISTRY: Lazy<Registry> =
+    Lazy::new(|| Registry::new_custom(Some("clash".to_owned()), None).unwrap());
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(c: T) -> T {
+    REGISTRY
+        .register(Box::new(c.clone()))
+        .expect("metric registered twice");
+    c
+}
+
+static TRAFFIC: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("traffic_bytes_total", "bytes relayed through a proxy"),
+            &["proxy", "direction"],
+        )
+        .unwrap(),
+    )
+});
+
+static CONNECTIONS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("connections_total", "connections handled"),
+            &["network"],
+        )
+        .unwrap(),
+    )
+});
+
+static CONNECTIONS_ACTIVE: Lazy<IntGauge> = Lazy::new(|| {
+    register(
+        IntGauge::new("connections_active", "connections currently open").unwrap(),
+    )
+});
+
+static DNS_CACHE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("dns_cache_lookups_total", "dns cache lookups"),
+            &["result"],
+        )
+        .unwrap(),
+    )
+});
+
+static HEALTHCHECK_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "healthcheck_latency_seconds",
+                "latency of successful proxy health checks",
+            )
+            .buckets(vec![
+                0.05, 0.1, 0.2, 0.3, 0.5, 0.75, 1.0, 1.5, 2.0, 3.0, 5.0,
+            ]),
+            &["proxy"],
+        )
+        .unwrap(),
+    )
+});
+
+static CONNECT_PHASES: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "connect_phase_seconds",
+                "time spent in each phase of establishing a connection",
+            )
+            .buckets(vec![
+                0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ]),
+            &["proxy", "phase"],
+        )
+        .unwrap(),
+    )
+});
+
+static HEALTHCHECK_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("healthcheck_failures_total", "failed proxy health checks"),
+            &["proxy"],
+        )
+        .unwrap(),
+    )
+});
+
+static RULE_HITS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("rule_hits_total", "sessions matched by a rule"),
+            &["rule", "target"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Traffic counters of a single connection, resolved once so the hot path
+/// doesn't have to look up the labels.
+pub struct Traffic {
+    pub upload: IntCounter,
+    pub download: IntCounter,
+}
+
+impl Traffic {
+    pub fn new(proxy: &str) -> Self {
+        Self {
+            upload: TRAFFIC.with_label_values(&[proxy, "upload"]),
+            download: TRAFFIC.with_label_values(&[proxy, "download"]),
+        }
+    }
+}
+
+pub fn connection_opened(network: &str) {
+    CONNECTIONS_TOTAL.with_label_values(&[network]).inc();
+}
+
+pub fn dns_cache_lookup(hit: bool) {
+    DNS_CACHE
+        .with_label_values(&[if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+/// An expired answer served because the upstreams failed.
+pub fn dns_stale_answer() {
+    DNS_CACHE.with_label_values(&["stale"]).inc();
+}
+
+/// `phase` is one of rule_match, dns, connect, handshake and first_byte.
+pub fn connect_phase(proxy: &str, phase: &str, elapsed: Duration) {
+    CONNECT_PHASES
+        .with_label_values(&[proxy, phase])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn healthcheck(proxy: &str, latency: Option<Duration>) {
+    match latency {
+        Some(latency) => HEALTHCHECK_LATENCY
+            .with_label_values(&[proxy])
+            .observe(latency.as_secs_f64()),
+        None => HEALTHCHECK_FAILURES.with_label_values(&[proxy]).inc(),
+    }
+}
+
+/// `rule` is the type of the rule, its payload would make too many series.
+pub fn rule_hit(rule: &str, target: &str) {
+    RULE_HITS.with_label_values(&[rule, target]).inc();
+}
+
+/// Remove the series of `vec` whose `label` isn't one to `keep`.
+fn remove_series<T: MetricVecBuilder>(
+    vec: &MetricVec<T>,
+    label: &str,
+    keep: &dyn Fn(&str) -> bool,
+) {
+    for family in vec.collect() {
+        for m in family.get_metric() {
+            let labels: HashMap<&str, &str> = m
+                .get_label()
+                .iter()
+                .map(|x| (x.get_name(), x.get_value()))
+                .collect();
+            if labels.get(label).is_some_and(|x| !keep(x)) {
+                let _ = vec.remove(&labels);
+            }
+        }
+    }
+}
+
+/// A reload removed the proxies `keep` doesn't, their series go too.
+pub fn retain_proxies(keep: impl Fn(&str) -> bool) {
+    remove_series(&TRAFFIC, "proxy", &keep);
+    remove_series(&CONNECT_PHASES, "proxy", &keep);
+    remove_series(&HEALTHCHECK_LATENCY, "proxy", &keep);
+    remove_series(&HEALTHCHECK_FAILURES, "proxy", &keep);
+    remove_series(&RULE_HITS, "target", &keep);
+}
+
+/// A reload replaced the rules, their hits start over.
+pub fn reset_rule_hits() {
+    RULE_HITS.reset();
+}
+
+/// Render all metrics in the prometheus text format.
+/// `active_connections` is sampled by the caller at scrape time.
+pub fn gather(active_connections: usize) -> String {
+    CONNECTIONS_ACTIVE.set(active_connections as i64);
+    // make sure every family shows up even before its first sample
+    Lazy::force(&TRAFFIC);
+    Lazy::force(&CONNECTIONS_TOTAL);
+    Lazy::force(&DNS_CACHE);
+    Lazy::force(&CONNECT_PHASES);
+    Lazy::force(&HEALTHCHECK_LATENCY);
+    Lazy::force(&HEALTHCHECK_FAILURES);
+    Lazy::force(&RULE_HITS);
+
+    let mut buf = vec![];
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buf)
+        .expect("encode metrics");
+    String::from_utf8(buf).expect("metrics are utf8")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn test_gather() {
+        let traffic = super::Traffic::new("ss01");
+        traffic.upload.inc_by(10);
+        traffic.download.inc_by(20);
+        super::rule_hit("DOMAIN-SUFFIX", "ss01");
+        super::dns_cache_lookup(true);
+        super::healthcheck("ss01", Some(Duration::from_millis(120)));
+        super::healthcheck("ss02", None);
+        super::connect_phase("ss01", "dns", Duration::from_millis(3));
+
+        let out = super::gather(3);
+        assert!(out.contains(
+            r#"clash_traffic_bytes_total{direction="upload",proxy="ss01"} 10"#
+        ));
+        assert!(out.contains(
+            r#"clash_traffic_bytes_total{direction="download",proxy="ss01"} 20"#
+        ));
+        assert!(out.contains("clash_connections_active 3"));
+        assert!(out.contains(
+            r#"clash_rule_hits_total{rule="DOMAIN-SUFFIX",target="ss01"} 1"#
+        ));
+        assert!(out.contains(r#"clash_dns_cache_lookups_total{result="hit"}"#));
+        assert!(out
+            .contains(r#"clash_healthcheck_latency_seconds_count{proxy="ss01"} 1"#));
+        assert!(out.contains(r#"clash_healthcheck_failures_total{proxy="ss02"} 1"#));
+        assert!(out.contains(
+            r#"clash_connect_phase_seconds_count{phase="dns",proxy="ss01"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_retain_proxies() {
+        super::Traffic::new("gone01").upload.inc();
+        super::Traffic::new("kept01").upload.inc();
+        super::healthcheck("gone01", None);
+        super::rule_hit("MATCH", "gone01");
+        super::rule_hit("GEOIP", "kept01");
+
+        super::retain_proxies(|x| x != "gone01");
+        let out = super::gather(0);
+        assert!(!out.contains("gone01"));
+        assert!(out.contains(
+            r#"clash_traffic_bytes_total{direction="upload",proxy="kept01"} 1"#
+        ));
+        assert!(
+            out.contains(r#"clash_rule_hits_total{rule="GEOIP",target="kept01"}"#)
+        );
+    }
+}
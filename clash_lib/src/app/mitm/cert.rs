@@ -0,0 +1,347 @@
+//! A CA issuing leaf certificates for intercepted hosts. Certificates are
+//! ECDSA P-256, DER encoded by hand as only a handful of fields are needed.
+
+use std::{net::IpAddr, path::Path, sync::Arc};
+
+use chrono::{DateTime, Datelike, Duration, Utc};
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+use rustls::{sign::CertifiedKey, Certificate, PrivateKey};
+use tracing::info;
+
+use crate::{
+    common::der::{der_content, der_elements},
+    Error,
+};
+
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_ORGANIZATION: &[u8] = &[0x55, 0x04, 0x0a];
+const OID_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+const OID_EXT_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const OID_SERVER_AUTH: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|x| **x == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn seq(parts: &[&[u8]]) -> Vec<u8> {
+    tlv(0x30, &parts.concat())
+}
+
+fn oid(oid: &[u8]) -> Vec<u8> {
+    tlv(0x06, oid)
+}
+
+fn bit_string(unused: u8, bits: &[u8]) -> Vec<u8> {
+    tlv(0x03, &[&[unused], bits].concat())
+}
+
+fn time(t: DateTime<Utc>) -> Vec<u8> {
+    // UTCTime can't go past 2049
+    if t.year() < 2050 {
+        tlv(0x17, t.format("%y%m%d%H%M%SZ").to_string().as_bytes())
+    } else {
+        tlv(0x18, t.format("%Y%m%d%H%M%SZ").to_string().as_bytes())
+    }
+}
+
+fn name(common_name: &str) -> Vec<u8> {
+    let attr = |typ: &[u8], value: &str| {
+        tlv(0x31, &seq(&[&oid(typ), &tlv(0x0c, value.as_bytes())]))
+    };
+    seq(&[
+        &attr(OID_ORGANIZATION, "clash-rs"),
+        &attr(OID_COMMON_NAME, common_name),
+    ])
+}
+
+fn extension(id: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+    let critical = if critical { tlv(0x01, &[0xff]) } else { vec![] };
+    seq(&[&oid(id), &critical, &tlv(0x04, value)])
+}
+
+/// The encoded subject of a certificate.
+fn subject_of(cert: &[u8]) -> Option<Vec<u8>> {
+    let cert = *der_elements(cert)?.first()?;
+    let tbs = *der_elements(der_content(cert))?.first()?;
+    let fields = der_elements(der_content(tbs))?;
+    // skip the explicitly tagged version
+    let skip = if fields.first()?.first() == Some(&0xa0) {
+        1
+    } else {
+        0
+    };
+    // serial, signature, issuer, validity, subject
+    fields.get(skip + 4).map(|x| x.to_vec())
+}
+
+fn to_pem(label: &str, der: &[u8]) -> String {
+    use base64::Engine;
+    let b64 = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut out = format!("-----BEGIN {}-----\n", label);
+    for line in b64.as_bytes().chunks(64) {
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", label));
+    out
+}
+
+fn crypto_err(e: impl std::fmt::Display) -> Error {
+    Error::Crypto(e.to_string())
+}
+
+pub struct CertificateAuthority {
+    cert: Vec<u8>,
+    subject: Vec<u8>,
+    key: EcdsaKeyPair,
+    /// one key for all leaves, they only live in memory
+    leaf_key: Vec<u8>,
+    rng: SystemRandom,
+}
+
+impl CertificateAuthority {
+    /// Generate a new CA.
+    pub fn generate() -> Result<(Self, Vec<u8>), Error> {
+        let rng = SystemRandom::new();
+        let key =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+                .map_err(crypto_err)?
+                .as_ref()
+                .to_vec();
+        let mut ca = Self::from_parts(vec![], &key)?;
+        let subject = name("clash-rs MITM CA");
+        let now = Utc::now();
+        ca.cert = ca.sign(
+            &subject,
+            &subject,
+            now - Duration::days(1),
+            now + Duration::days(3650),
+            ca.key.public_key().as_ref(),
+            &[
+                extension(OID_BASIC_CONSTRAINTS, true, &seq(&[&tlv(0x01, &[0xff])])),
+                // keyCertSign, cRLSign
+                extension(OID_KEY_USAGE, true, &bit_string(1, &[0x06])),
+            ],
+        )?;
+        ca.subject = subject;
+        Ok((ca, key))
+    }
+
+    fn from_parts(cert: Vec<u8>, key: &[u8]) -> Result<Self, Error> {
+        let rng = SystemRandom::new();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, key, &rng)
+                .map_err(|e| {
+                    Error::Crypto(format!(
+                        "CA key must be ECDSA P-256 PKCS#8: {}",
+                        e
+                    ))
+                })?;
+        let leaf_key =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+                .map_err(crypto_err)?
+                .as_ref()
+                .to_vec();
+        let subject = if cert.is_empty() {
+            vec![]
+        } else {
+            subject_of(&cert)
+                .ok_or_else(|| Error::Crypto("malformed CA certificate".into()))?
+        };
+        Ok(Self {
+            cert,
+            subject,
+            key,
+            leaf_key,
+            rng,
+        })
+    }
+
+    /// Load the CA from PEM files, generating and saving one if the
+    /// certificate doesn't exist.
+    pub fn load_or_generate(
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> Result<Self, Error> {
+        if !cert_path.exists() {
+            let (ca, key) = Self::generate()?;
+            std::fs::write(cert_path, to_pem("CERTIFICATE", &ca.cert))?;
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            std::io::Write::write_all(
+                &mut options.open(key_path)?,
+                to_pem("PRIVATE KEY", &key).as_bytes(),
+            )?;
+            info!(
+                "generated MITM CA {}, clients must trust it",
+                cert_path.display()
+            );
+            return Ok(ca);
+        }
+
+        let cert = rustls_pemfile::certs(&mut std::io::BufReader::new(
+            std::fs::File::open(cert_path)?,
+        ))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::Crypto("no certificate in MITM CA file".into()))?;
+        let key = rustls_pemfile::pkcs8_private_keys(&mut std::io::BufReader::new(
+            std::fs::File::open(key_path)?,
+        ))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::Crypto("no PKCS#8 key in MITM CA key file".into()))?;
+        Self::from_parts(cert, &key)
+    }
+
+    fn sign(
+        &self,
+        issuer: &[u8],
+        subject: &[u8],
+        not_before: DateTime<Utc>,
+        not_after: DateTime<Utc>,
+        public_key: &[u8],
+        extensions: &[Vec<u8>],
+    ) -> Result<Vec<u8>, Error> {
+        let mut serial = [0u8; 16];
+        self.rng.fill(&mut serial).map_err(crypto_err)?;
+        // positive and without leading zeros
+        serial[0] = (serial[0] & 0x7f) | 0x40;
+
+        let algorithm = seq(&[&oid(OID_ECDSA_SHA256)]);
+        let spki = seq(&[
+            &seq(&[&oid(OID_EC_PUBLIC_KEY), &oid(OID_P256)]),
+            &bit_string(0, public_key),
+        ]);
+        let tbs = seq(&[
+            &tlv(0xa0, &tlv(0x02, &[2])),
+            &tlv(0x02, &serial),
+            &algorithm,
+            issuer,
+            &seq(&[&time(not_before), &time(not_after)]),
+            subject,
+            &spki,
+            &tlv(0xa3, &tlv(0x30, &extensions.concat())),
+        ]);
+        let signature = self.key.sign(&self.rng, &tbs).map_err(crypto_err)?;
+        Ok(seq(&[&tbs, &algorithm, &bit_string(0, signature.as_ref())]))
+    }
+
+    /// A certificate for `host` chained to this CA.
+    pub fn issue(&self, host: &str) -> Result<Arc<CertifiedKey>, Error> {
+        let leaf_key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_ASN1_SIGNING,
+            &self.leaf_key,
+            &self.rng,
+        )
+        .map_err(crypto_err)?;
+        let san = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => tlv(0x87, &ip.octets()),
+            Ok(IpAddr::V6(ip)) => tlv(0x87, &ip.octets()),
+            Err(_) => tlv(0x82, host.as_bytes()),
+        };
+        let now = Utc::now();
+        let cert = self.sign(
+            &self.subject,
+            &name(host),
+            now - Duration::days(1),
+            // the most clients accept for a leaf
+            now + Duration::days(365),
+            leaf_key.public_key().as_ref(),
+            &[
+                extension(OID_SUBJECT_ALT_NAME, false, &tlv(0x30, &san)),
+                // digitalSignature
+                extension(OID_KEY_USAGE, true, &bit_string(7, &[0x80])),
+                extension(OID_EXT_KEY_USAGE, false, &seq(&[&oid(OID_SERVER_AUTH)])),
+            ],
+        )?;
+
+        let key = rustls::sign::any_ecdsa_type(&PrivateKey(self.leaf_key.clone()))
+            .map_err(crypto_err)?;
+        Ok(Arc::new(CertifiedKey::new(
+            vec![Certificate(cert), Certificate(self.cert.clone())],
+            key,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        Certificate, RootCertStore, ServerName,
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::{subject_of, CertificateAuthority};
+
+    struct Fixed(Arc<CertifiedKey>);
+
+    impl ResolvesServerCert for Fixed {
+        fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+            Some(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_issue() {
+        let dir =
+            std::env::temp_dir().join(format!("mitm-ca-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("ca.crt"), dir.join("ca.key"));
+        let generated = CertificateAuthority::load_or_generate(&cert, &key).unwrap();
+        let ca = CertificateAuthority::load_or_generate(&cert, &key).unwrap();
+        assert_eq!(generated.cert, ca.cert);
+        assert_eq!(subject_of(&ca.cert).unwrap(), generated.subject);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(ca.cert.clone())).unwrap();
+        let client = TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let server = TlsAcceptor::from(Arc::new(
+            rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(Fixed(
+                    ca.issue("example.com").unwrap(),
+                ))),
+        ));
+
+        let (c, s) = tokio::io::duplex(16384);
+        let (c, s) = tokio::join!(
+            client.connect(ServerName::try_from("example.com").unwrap(), c),
+            server.accept(s)
+        );
+        c.expect("client should trust the leaf");
+        s.unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+//! Decrypts and rewrites HTTP(S) traffic to configured hosts. The client is
+//! served a certificate issued by a local CA, requests are matched against
+//! the rewrite rules and forwarded over the outbound connection.
+
+pub(crate) mod cert;
+mod rewrite;
+
+use std::{
+    convert::Infallible,
+    fmt::{Display, Formatter},
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hyper::{client::conn::SendRequest, Body, Request, Response, StatusCode};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerName,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{debug, warn};
+
+use crate::{
+    common::{
+        io::{copy_pooled_bidirectional_with_timeout, CopyBidirectionalError},
+        tls::GLOBAL_ROOT_STORE,
+        trie,
+    },
+    config::internal::config::{MitmConfig, MitmRule},
+    session::{Session, SocksAddr},
+    Error,
+};
+
+use cert::CertificateAuthority;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    /// Tell the protocol from the first byte the client sent.
+    pub fn detect(first: u8) -> Option<Self> {
+        match first {
+            // TLS handshake record
+            0x16 => Some(Self::Https),
+            // an HTTP method
+            b'A'..=b'Z' => Some(Self::Http),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Scheme {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http => write!(f, "http"),
+            Self::Https => write!(f, "https"),
+        }
+    }
+}
+
+struct Fixed(Arc<CertifiedKey>);
+
+impl ResolvesServerCert for Fixed {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+pub struct Mitm {
+    hosts: trie::StringTrie<bool>,
+    rules: Vec<MitmRule>,
+    ca: CertificateAuthority,
+    certs: Mutex<lru_time_cache::LruCache<String, Arc<CertifiedKey>>>,
+    upstream_tls: Arc<rustls::ClientConfig>,
+}
+
+impl Mitm {
+    /// `None` if MITM is disabled. The CA paths are relative to `cwd`.
+    pub fn new(cfg: MitmConfig, cwd: &Path) -> Result<Option<Arc<Self>>, Error> {
+        if !cfg.enable {
+            return Ok(None);
+        }
+        let ca = CertificateAuthority::load_or_generate(
+            &cwd.join(&cfg.ca_cert),
+            &cwd.join(&cfg.ca_key),
+        )?;
+        Ok(Some(Arc::new(Self::with_ca(cfg, ca))))
+    }
+
+    fn with_ca(cfg: MitmConfig, ca: CertificateAuthority) -> Self {
+        let mut hosts = trie::StringTrie::new();
+        for host in cfg.hosts.iter() {
+            hosts.insert(host, Arc::new(true));
+        }
+
+        let mut upstream_tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+            .with_no_client_auth();
+        upstream_tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Self {
+            hosts,
+            rules: cfg.rewrite,
+            ca,
+            certs: Mutex::new(
+                lru_time_cache::LruCache::with_expiry_duration_and_capacity(
+                    Duration::from_secs(24 * 60 * 60),
+                    1024,
+                ),
+            ),
+            upstream_tls: Arc::new(upstream_tls),
+        }
+    }
+
+    /// The host to intercept the session for, if it's in the host list.
+    pub fn intercepts(&self, sess: &Session) -> Option<String> {
+        let host = match (&sess.sniff_host, &sess.destination) {
+            (Some(host), _) => host.clone(),
+            (None, SocksAddr::Domain(domain, _)) => domain.clone(),
+            (None, SocksAddr::Ip(_)) => return None,
+        };
+        self.hosts.search(&host).is_some().then_some(host)
+    }
+
+    fn cert_for(&self, host: &str) -> Result<Arc<CertifiedKey>, Error> {
+        let mut certs = self.certs.lock().unwrap();
+        if let Some(cert) = certs.get(host) {
+            return Ok(cert.clone());
+        }
+        let cert = self.ca.issue(host)?;
+        certs.insert(host.to_owned(), cert.clone());
+        Ok(cert)
+    }
+
+    /// Terminate the client connection and serve its requests, forwarding
+    /// them to `host` over `rhs`.
+    pub async fn relay<L, R>(
+        self: &Arc<Self>,
+        scheme: Scheme,
+        host: String,
+        lhs: &mut L,
+        rhs: R,
+        idle_timeout: Option<Duration>,
+    ) -> Result<(u64, u64), CopyBidirectionalError>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+        R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match scheme {
+            Scheme::Http => self.serve(scheme, host, lhs, rhs, idle_timeout).await,
+            Scheme::Https => {
+                let other = |e| CopyBidirectionalError::Other(io::Error::other(e));
+                let name = ServerName::try_from(host.as_str())
+                    .map_err(|e| other(e.to_string()))?;
+                // fail before the client sees our certificate
+                let rhs = TlsConnector::from(self.upstream_tls.clone())
+                    .connect(name, rhs)
+                    .await
+                    .map_err(CopyBidirectionalError::RightClosed)?;
+
+                let cert = self.cert_for(&host).map_err(|e| other(e.to_string()))?;
+                let mut server_tls = rustls::ServerConfig::builder()
+                    .with_safe_defaults()
+                    .with_no_client_auth()
+                    .with_cert_resolver(Arc::new(Fixed(cert)));
+                server_tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+                let mut lhs = TlsAcceptor::from(Arc::new(server_tls))
+                    .accept(lhs)
+                    .await
+                    .map_err(CopyBidirectionalError::LeftClosed)?;
+
+                self.serve(scheme, host, &mut lhs, rhs, idle_timeout).await
+            }
+        }
+    }
+
+    async fn serve<L, R>(
+        self: &Arc<Self>,
+        scheme: Scheme,
+        host: String,
+        lhs: &mut L,
+        rhs: R,
+        idle_timeout: Option<Duration>,
+    ) -> Result<(u64, u64), CopyBidirectionalError>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+        R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, mut upstream) = hyper::client::conn::Builder::new()
+            .handshake::<_, Body>(rhs)
+            .await
+            .map_err(|e| CopyBidirectionalError::RightClosed(io::Error::other(e)))?;
+        let sender = Arc::new(tokio::sync::Mutex::new(sender));
+
+        let mitm = self.clone();
+        let service_host = host.clone();
+        let service = hyper::service::service_fn(move |req| {
+            let mitm = mitm.clone();
+            let sender = sender.clone();
+            let host = service_host.clone();
+            async move {
+                Ok::<_, Infallible>(mitm.handle(scheme, &host, &sender, req).await)
+            }
+        });
+
+        // hyper wants to own the connection, feed it through a pipe
+        let (mut local, remote) = tokio::io::duplex(16 * 1024);
+        let serve = async move {
+            let mut conn = Box::pin(
+                hyper::server::conn::Http::new()
+                    .http1_only(true)
+                    .serve_connection(remote, service)
+                    .with_upgrades(),
+            );
+            let served = tokio::select! {
+                r = conn.as_mut() => r,
+                _ = &mut upstream => {
+                    conn.as_mut().graceful_shutdown();
+                    return conn.await;
+                }
+            };
+            // drops the sender, so the upstream connection finishes once an
+            // upgrade is handed over
+            drop(conn);
+            let _ = upstream.await;
+            served
+        };
+        let copy = copy_pooled_bidirectional_with_timeout(
+            lhs,
+            &mut local,
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            idle_timeout,
+        );
+
+        let (copied, served) = tokio::join!(copy, serve);
+        if let Err(e) = served {
+            debug!("mitm {}://{} failed: {}", scheme, host, e);
+        }
+        copied
+    }
+
+    async fn handle(
+        &self,
+        scheme: Scheme,
+        host: &str,
+        sender: &tokio::sync::Mutex<SendRequest<Body>>,
+        mut req: Request<Body>,
+    ) -> Response<Body> {
+        // the rules match the host intercepted, whatever the client sends
+        // in the Host header
+        let url = format!(
+            "{}://{}{}",
+            scheme,
+            host,
+            req.uri()
+                .path_and_query()
+                .map(|x| x.as_str())
+                .unwrap_or("/")
+        );
+
+        let response_actions =
+            match rewrite::rewrite_request(&self.rules, &url, &mut req) {
+                Ok(actions) => actions,
+                Err(resp) => {
+                    debug!("mitm answered {} with {}", url, resp.status());
+                    return resp;
+                }
+            };
+
+        let client_upgrade = hyper::upgrade::on(&mut req);
+        let resp = {
+            let mut sender = sender.lock().await;
+            match std::future::poll_fn(|cx| sender.poll_ready(cx)).await {
+                Ok(()) => sender.send_request(req).await,
+                Err(e) => Err(e),
+            }
+        };
+        let mut resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!("mitm failed to forward {}: {}", url, e);
+                let mut resp = Response::new(Body::empty());
+                *resp.status_mut() = StatusCode::BAD_GATEWAY;
+                return resp;
+            }
+        };
+
+        if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+            let server_upgrade = hyper::upgrade::on(&mut resp);
+            tokio::spawn(async move {
+                if let (Ok(mut client), Ok(mut server)) =
+                    tokio::join!(client_upgrade, server_upgrade)
+                {
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut server)
+                        .await;
+                }
+            });
+        }
+
+        rewrite::rewrite_response(&response_actions, &mut resp);
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Arc};
+
+    use hyper::{Body, Request, Response};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::{def::MitmAction, internal::config::MitmConfig};
+
+    use super::{CertificateAuthority, Mitm, Scheme};
+
+    #[tokio::test]
+    async fn test_relay_http() {
+        let (ca, _) = CertificateAuthority::generate().unwrap();
+        let mitm = Arc::new(Mitm::with_ca(
+            MitmConfig {
+                enable: true,
+                ca_cert: String::new(),
+                ca_key: String::new(),
+                hosts: vec!["example.com".to_owned()],
+                rewrite: vec![crate::config::internal::config::MitmRule {
+                    url: regex::Regex::new("^http://example\\.com/ads").unwrap(),
+                    action: MitmAction::Reject,
+                }],
+            },
+            ca,
+        ));
+
+        // the server echoes the path
+        let (rhs, server) = tokio::io::duplex(4096);
+        tokio::spawn(hyper::server::conn::Http::new().serve_connection(
+            server,
+            hyper::service::service_fn(|req: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::new(Body::from(
+                    req.uri().path().to_owned(),
+                )))
+            }),
+        ));
+
+        let (mut client, mut lhs) = tokio::io::duplex(4096);
+        let relay = tokio::spawn(async move {
+            mitm.relay(Scheme::Http, "example.com".to_owned(), &mut lhs, rhs, None)
+                .await
+        });
+
+        client
+            .write_all(
+                b"GET /ads/1 HTTP/1.1\r\nHost: example.com\r\n\r\nGET /page \
+                  HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 403"));
+        assert!(resp.contains("HTTP/1.1 200"));
+        assert!(resp.ends_with("/page"));
+        drop(client);
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_match_intercepted_host() {
+        let (ca, _) = CertificateAuthority::generate().unwrap();
+        let mitm = Arc::new(Mitm::with_ca(
+            MitmConfig {
+                enable: true,
+                ca_cert: String::new(),
+                ca_key: String::new(),
+                hosts: vec!["example.com".to_owned()],
+                rewrite: vec![crate::config::internal::config::MitmRule {
+                    url: regex::Regex::new("^http://example\\.com/ads").unwrap(),
+                    action: MitmAction::Reject,
+                }],
+            },
+            ca,
+        ));
+
+        let (rhs, server) = tokio::io::duplex(4096);
+        tokio::spawn(hyper::server::conn::Http::new().serve_connection(
+            server,
+            hyper::service::service_fn(|_: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            }),
+        ));
+
+        let (mut client, mut lhs) = tokio::io::duplex(4096);
+        let relay = tokio::spawn(async move {
+            mitm.relay(Scheme::Http, "example.com".to_owned(), &mut lhs, rhs, None)
+                .await
+        });
+
+        // another Host doesn't get the request past the rules
+        client
+            .write_all(
+                b"GET /ads/1 HTTP/1.1\r\nHost: example.org\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 403"));
+        drop(client);
+        relay.await.unwrap().unwrap();
+    }
+}
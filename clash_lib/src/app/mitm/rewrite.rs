@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use hyper::{
+    header::{HeaderName, HeaderValue, LOCATION},
+    Body, HeaderMap, Request, Response, StatusCode,
+};
+
+use crate::config::{def::MitmAction, internal::config::MitmRule};
+
+fn edit_headers(
+    headers: &mut HeaderMap,
+    set: &HashMap<String, String>,
+    remove: &[String],
+) {
+    for name in remove {
+        headers.remove(name.as_str());
+    }
+    for (name, value) in set {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+fn respond(status: StatusCode) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Apply the rules matching `url` to the request. `Err` is a response to
+/// answer without reaching the server, `Ok` the actions left for the
+/// response from the server. A redirect's location is expanded with the
+/// captures of the rule, `$1` or `${name}`.
+#[allow(clippy::result_large_err)]
+pub fn rewrite_request<'a>(
+    rules: &'a [MitmRule],
+    url: &str,
+    req: &mut Request<Body>,
+) -> Result<Vec<&'a MitmAction>, Response<Body>> {
+    let mut response_actions = vec![];
+    for (rule, captures) in
+        rules.iter().filter_map(|x| Some((x, x.url.captures(url)?)))
+    {
+        match &rule.action {
+            MitmAction::Reject => return Err(respond(StatusCode::FORBIDDEN)),
+            MitmAction::Redirect { location } => {
+                let mut resp = respond(StatusCode::FOUND);
+                let mut expanded = String::new();
+                captures.expand(location, &mut expanded);
+                if let Ok(location) = HeaderValue::from_str(&expanded) {
+                    resp.headers_mut().insert(LOCATION, location);
+                }
+                return Err(resp);
+            }
+            MitmAction::Mock {
+                status,
+                headers,
+                body,
+            } => {
+                let mut resp = Response::new(Body::from(body.clone()));
+                *resp.status_mut() =
+                    StatusCode::from_u16(*status).unwrap_or(StatusCode::OK);
+                edit_headers(resp.headers_mut(), headers, &[]);
+                return Err(resp);
+            }
+            MitmAction::RequestHeader { set, remove } => {
+                edit_headers(req.headers_mut(), set, remove)
+            }
+            MitmAction::ResponseHeader { .. } => response_actions.push(&rule.action),
+        }
+    }
+    Ok(response_actions)
+}
+
+pub fn rewrite_response(actions: &[&MitmAction], resp: &mut Response<Body>) {
+    for action in actions {
+        if let MitmAction::ResponseHeader { set, remove } = action {
+            edit_headers(resp.headers_mut(), set, remove);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use hyper::{Body, Request, Response, StatusCode};
+
+    use crate::config::{def::MitmAction, internal::config::MitmRule};
+
+    use super::{rewrite_request, rewrite_response};
+
+    fn rule(url: &str, action: MitmAction) -> MitmRule {
+        MitmRule {
+            url: regex::Regex::new(url).unwrap(),
+            action,
+        }
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let rules = vec![
+            rule(
+                "^https://example\\.com/",
+                MitmAction::RequestHeader {
+                    set: HashMap::from([("DNT".to_owned(), "1".to_owned())]),
+                    remove: vec!["x-tracking-id".to_owned()],
+                },
+            ),
+            rule(
+                "^https://example\\.com/",
+                MitmAction::ResponseHeader {
+                    set: HashMap::new(),
+                    remove: vec!["set-cookie".to_owned()],
+                },
+            ),
+            rule("^https://example\\.com/ads/", MitmAction::Reject),
+            rule(
+                "^https://example\\.com/old/(.*)",
+                MitmAction::Redirect {
+                    location: "https://example.org/$1".to_owned(),
+                },
+            ),
+        ];
+
+        let mut req = Request::builder()
+            .header("X-Tracking-Id", "42")
+            .body(Body::empty())
+            .unwrap();
+        let actions =
+            rewrite_request(&rules, "https://example.com/index.html", &mut req)
+                .unwrap();
+        assert_eq!(req.headers()["dnt"], "1");
+        assert!(!req.headers().contains_key("x-tracking-id"));
+
+        let mut resp = Response::builder()
+            .header("Set-Cookie", "a=b")
+            .body(Body::empty())
+            .unwrap();
+        rewrite_response(&actions, &mut resp);
+        assert!(resp.headers().is_empty());
+
+        let mut req = Request::new(Body::empty());
+        let resp = rewrite_request(&rules, "https://example.com/ads/1", &mut req)
+            .unwrap_err();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = rewrite_request(&rules, "https://example.com/old/a?b", &mut req)
+            .unwrap_err();
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(resp.headers()["location"], "https://example.org/a?b");
+
+        assert!(rewrite_request(&rules, "http://example.com/ads/", &mut req)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_redirect_captures() {
+        let rules = vec![rule(
+            "example\\.com/(?P<page>\\w+)\\.php",
+            MitmAction::Redirect {
+                location: "https://example.org/${page}.html".to_owned(),
+            },
+        )];
+
+        // only what the location names is kept of the URL
+        let mut req = Request::new(Body::empty());
+        let resp = rewrite_request(
+            &rules,
+            "http://www.example.com/index.php?lang=en",
+            &mut req,
+        )
+        .unwrap_err();
+        assert_eq!(resp.headers()["location"], "https://example.org/index.html");
+    }
+}
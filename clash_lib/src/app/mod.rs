@@ -1,9 +1,18 @@
 pub mod api;
 pub mod dispatcher;
 pub mod dns;
+pub mod events;
 pub mod inbound;
 pub mod logging;
+pub mod metrics;
+pub mod mitm;
+pub mod net_monitor;
+pub mod ntp;
 pub mod outbound;
+pub mod plugins;
 pub mod profile;
+pub mod profiles;
+pub mod reload;
 pub mod remote_content_manager;
 pub mod router;
+pub mod shutdown;
@@ -0,0 +1,230 @@
+//! Watches for default route and interface address changes. When the default
+//! outbound interface changes, `network-changed` is published: UDP sessions
+//! bound to the old network are dropped and health checks run again.
+//!
+//! Linux is notified through a netlink route socket, other platforms poll
+//! the interface list.
+
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::{future::Future, io};
+
+use tracing::{info, warn};
+
+use crate::{
+    app::events,
+    proxy::utils::{get_outbound_interface, refresh_outbound_interface},
+    Runner,
+};
+
+/// a change usually comes as a burst of messages, wait for it to settle
+#[cfg(target_os = "linux")]
+const SETTLE: Duration = Duration::from_secs(1);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Publish `network-changed` if `refresh` found the default interface
+/// changed.
+fn check(refresh: impl FnOnce() -> bool) {
+    if refresh() {
+        let interface = get_outbound_interface().map(|x| x.name);
+        info!("network changed, default interface: {:?}", interface);
+        events::publish(events::Event::NetworkChanged { interface });
+    }
+}
+
+/// Call `on_change` once each burst of messages from `wait` settled, until
+/// `wait` fails.
+#[cfg(target_os = "linux")]
+async fn debounce<F, Fut>(mut wait: F, mut on_change: impl FnMut()) -> io::Error
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<()>>,
+{
+    loop {
+        if let Err(e) = wait().await {
+            return e;
+        }
+        while let Ok(r) = tokio::time::timeout(SETTLE, wait()).await {
+            if let Err(e) = r {
+                return e;
+            }
+        }
+        on_change();
+    }
+}
+
+pub fn get_runner() -> Runner {
+    Box::pin(async move {
+        // resolve it before watching, so the first change is noticed
+        let _ = get_outbound_interface();
+
+        #[cfg(target_os = "linux")]
+        match netlink::Socket::new() {
+            Ok(socket) => {
+                let e =
+                    debounce(|| socket.wait(), || check(refresh_outbound_interface))
+                        .await;
+                warn!("network monitor failed: {}, polling instead", e);
+            }
+            Err(e) => warn!("failed to watch netlink: {}, polling instead", e),
+        }
+
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            ticker.tick().await;
+            check(refresh_outbound_interface);
+        }
+    })
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    use std::{
+        io,
+        mem::{size_of, zeroed},
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    };
+
+    use tokio::io::unix::AsyncFd;
+
+    pub struct Socket(AsyncFd<OwnedFd>);
+
+    impl Socket {
+        /// A route socket subscribed to link, address and route changes.
+        pub fn new() -> io::Result<Self> {
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_ROUTE,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            let mut addr: libc::sockaddr_nl = unsafe { zeroed() };
+            addr.nl_family = libc::AF_NETLINK as _;
+            addr.nl_groups = (libc::RTMGRP_LINK
+                | libc::RTMGRP_IPV4_IFADDR
+                | libc::RTMGRP_IPV6_IFADDR
+                | libc::RTMGRP_IPV4_ROUTE
+                | libc::RTMGRP_IPV6_ROUTE) as _;
+            if unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    &addr as *const _ as *const libc::sockaddr,
+                    size_of::<libc::sockaddr_nl>() as _,
+                )
+            } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self(AsyncFd::new(fd)?))
+        }
+
+        /// Wait for the next message, its content doesn't matter.
+        pub async fn wait(&self) -> io::Result<()> {
+            let mut buf = [0u8; 8192];
+            loop {
+                let mut guard = self.0.readable().await?;
+                match guard.try_io(|fd| {
+                    let n = unsafe {
+                        libc::recv(
+                            fd.as_raw_fd(),
+                            buf.as_mut_ptr() as *mut _,
+                            buf.len(),
+                            0,
+                        )
+                    };
+                    let err = io::Error::last_os_error();
+                    // messages were dropped, that's a change all the same
+                    if n >= 0 || err.raw_os_error() == Some(libc::ENOBUFS) {
+                        Ok(())
+                    } else {
+                        Err(err)
+                    }
+                }) {
+                    Ok(r) => return r,
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use crate::app::events::{self, Event};
+
+    #[test]
+    fn test_check() {
+        let mut rx = events::subscribe();
+        super::check(|| false);
+        super::check(|| true);
+        super::check(|| false);
+
+        // other tests publish on the same bus
+        let mut changes = 0;
+        loop {
+            match rx.try_recv() {
+                Ok(Event::NetworkChanged { .. }) => changes += 1,
+                Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+        assert_eq!(changes, 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test(start_paused = true)]
+    async fn test_debounce() {
+        use std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            time::Duration,
+        };
+
+        use tokio::{sync::Notify, time::sleep};
+
+        let message = Arc::new(Notify::new());
+        let changes = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let message = message.clone();
+            let changes = changes.clone();
+            super::debounce(
+                move || {
+                    let message = message.clone();
+                    async move {
+                        message.notified().await;
+                        Ok(())
+                    }
+                },
+                move || {
+                    changes.fetch_add(1, Ordering::Relaxed);
+                },
+            )
+        });
+
+        // a burst is a single change, once it settled
+        for _ in 0..3 {
+            message.notify_one();
+            sleep(Duration::from_millis(500)).await;
+        }
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(changes.load(Ordering::Relaxed), 0);
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(changes.load(Ordering::Relaxed), 1);
+
+        message.notify_one();
+        sleep(Duration::from_millis(1100)).await;
+        assert_eq!(changes.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,219 @@
+//! Checks the system clock against an NTP server. VMess puts a timestamp in
+//! its handshake that servers reject when it's off by more than two minutes,
+//! which looks like the server is down. A skew past [`WARN_SKEW`] is logged
+//! and published as `clock-skew`, and with `compensate` set the timestamps
+//! sent by VMess and simple-obfs come from [`now`] corrected by the measured
+//! offset. Shadowsocks 2022 stamps its headers inside the shadowsocks crate
+//! with the system clock, which isn't compensated.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicI64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    app::{dns::ThreadSafeDNSResolver, events},
+    config::def::NtpService,
+    proxy::utils::new_udp_socket,
+    Runner,
+};
+
+/// seconds between the NTP epoch, 1900, and the unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const PACKET_SIZE: usize = 48;
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// skews below this don't matter to any protocol
+const WARN_SKEW: Duration = Duration::from_secs(30);
+
+/// milliseconds to add to the system clock
+static OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// The current time, corrected by the measured clock offset if enabled.
+pub fn now() -> SystemTime {
+    let offset = OFFSET.load(Ordering::Relaxed);
+    let now = SystemTime::now();
+    if offset >= 0 {
+        now + Duration::from_millis(offset as u64)
+    } else {
+        now - Duration::from_millis(offset.unsigned_abs())
+    }
+}
+
+pub fn get_runner(
+    cfg: NtpService,
+    dns_resolver: ThreadSafeDNSResolver,
+) -> Option<Runner> {
+    // a previous config may have set it
+    if !cfg.enable || !cfg.compensate {
+        OFFSET.store(0, Ordering::Relaxed);
+    }
+    if !cfg.enable {
+        return None;
+    }
+
+    Some(Box::pin(async move {
+        let mut ticker =
+            tokio::time::interval(Duration::from_secs(cfg.interval.max(1) * 60));
+        loop {
+            ticker.tick().await;
+            let offset = match check(&cfg, &dns_resolver).await {
+                Ok(offset) => offset,
+                Err(e) => {
+                    warn!("failed to query NTP server {}: {}", cfg.server, e);
+                    continue;
+                }
+            };
+            debug!("clock offset from {}: {}ms", cfg.server, offset);
+
+            if offset.unsigned_abs() >= WARN_SKEW.as_millis() as u64 {
+                warn!(
+                    "system clock is off by {:.1}s, VMess and other protocols \
+                     with timestamps may fail{}",
+                    offset as f64 / 1000.0,
+                    if cfg.compensate { ", compensating" } else { "" }
+                );
+                events::publish(events::Event::ClockSkew { offset });
+            }
+            if cfg.compensate {
+                OFFSET.store(offset, Ordering::Relaxed);
+            }
+        }
+    }))
+}
+
+/// The milliseconds the system clock is behind the server.
+async fn check(
+    cfg: &NtpService,
+    dns_resolver: &ThreadSafeDNSResolver,
+) -> std::io::Result<i64> {
+    let ip = match cfg.server.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => dns_resolver
+            .resolve(&cfg.server, false)
+            .await
+            .map_err(std::io::Error::other)?
+            .ok_or_else(|| std::io::Error::other("no address"))?,
+    };
+    query(SocketAddr::new(ip, cfg.port)).await
+}
+
+async fn query(server: SocketAddr) -> std::io::Result<i64> {
+    let bind: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    // protected like the sockets of the outbounds, not to loop into a VPN
+    let socket = new_udp_socket(
+        Some(&SocketAddr::new(bind, 0)),
+        None,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        None,
+    )
+    .await?;
+    socket.connect(server).await?;
+
+    let mut request = [0; PACKET_SIZE];
+    // no leap second warning, version 4, client mode
+    request[0] = 0x23;
+    let sent = SystemTime::now();
+    write_timestamp(&mut request[40..48], sent);
+    socket.send(&request).await?;
+
+    let mut response = [0; PACKET_SIZE];
+    let n = tokio::time::timeout(TIMEOUT, socket.recv(&mut response))
+        .await
+        .map_err(|_| std::io::Error::other("timed out"))??;
+    let received = SystemTime::now();
+    if n < PACKET_SIZE {
+        return Err(std::io::Error::other("short response"));
+    }
+    // the server has to echo our transmit time as the origin time
+    if response[24..32] != request[40..48] {
+        return Err(std::io::Error::other("unexpected response"));
+    }
+    // a kiss-o'-death or unsynchronized server
+    if response[1] == 0 {
+        return Err(std::io::Error::other("server not synchronized"));
+    }
+
+    let server_received = read_timestamp(&response[32..40]);
+    let server_sent = read_timestamp(&response[40..48]);
+    Ok(offset(sent, server_received, server_sent, received))
+}
+
+/// The clock offset in milliseconds, see RFC 5905 section 8.
+fn offset(
+    sent: SystemTime,
+    server_received: SystemTime,
+    server_sent: SystemTime,
+    received: SystemTime,
+) -> i64 {
+    let diff = |a: SystemTime, b: SystemTime| match a.duration_since(b) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    };
+    (diff(server_received, sent) + diff(server_sent, received)) / 2
+}
+
+fn read_timestamp(buf: &[u8]) -> SystemTime {
+    let secs = u32::from_be_bytes(buf[0..4].try_into().unwrap()) as u64;
+    let frac = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as u64;
+    let nanos = (frac * 1_000_000_000) >> 32;
+    SystemTime::UNIX_EPOCH
+        + Duration::from_secs(secs.saturating_sub(NTP_UNIX_OFFSET))
+        + Duration::from_nanos(nanos)
+}
+
+fn write_timestamp(buf: &mut [u8], time: SystemTime) {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = (since_epoch.as_secs() + NTP_UNIX_OFFSET) as u32;
+    let frac = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    buf[0..4].copy_from_slice(&secs.to_be_bytes());
+    buf[4..8].copy_from_slice(&(frac as u32).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use tokio::net::UdpSocket;
+
+    use super::{query, read_timestamp, write_timestamp, PACKET_SIZE};
+
+    #[test]
+    fn test_timestamp() {
+        let now = SystemTime::now();
+        let mut buf = [0; 8];
+        write_timestamp(&mut buf, now);
+        let read = read_timestamp(&buf);
+        let diff = now.duration_since(read).unwrap_or_default();
+        assert!(diff < Duration::from_micros(1));
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        // a server an hour ahead
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; PACKET_SIZE];
+            let (_, peer) = server.recv_from(&mut buf).await.unwrap();
+            let mut response = [0; PACKET_SIZE];
+            response[0] = 0x24;
+            response[1] = 2;
+            response[24..32].copy_from_slice(&buf[40..48]);
+            let ahead = SystemTime::now() + Duration::from_secs(3600);
+            write_timestamp(&mut response[32..40], ahead);
+            write_timestamp(&mut response[40..48], ahead);
+            server.send_to(&response, peer).await.unwrap();
+        });
+
+        let offset = query(addr).await.unwrap();
+        assert!((offset - 3_600_000).abs() < 1000, "offset: {}", offset);
+    }
+}
@@ -1,9 +1,15 @@
 use anyhow::Result;
 use erased_serde::Serialize;
+use futures::{stream, StreamExt};
 use hyper::Uri;
-use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::{Mutex, RwLock};
-use tracing::{debug, error, warn};
+use tracing::{debug, error};
 
 use tracing::info;
 
@@ -13,7 +19,7 @@ use crate::app::{
     remote_content_manager::{
         healthcheck::HealthCheck,
         providers::{file_vehicle, http_vehicle},
-        ProxyManager,
+        DelayHistory, ProxyManager,
     },
 };
 
@@ -30,13 +36,16 @@ use crate::{
 use crate::{
     config::internal::proxy::{OutboundGroupProtocol, OutboundProxyProtocol},
     proxy::{
-        direct, reject, relay, selector::ThreadSafeSelectorControl, urltest,
+        registry, relay, selector::ThreadSafeSelectorControl, urltest,
         AnyOutboundHandler,
     },
     Error,
 };
 
-use super::utils::proxy_groups_dag_sort;
+use super::utils::{namespace_provider_groups, proxy_groups_dag_sort};
+
+/// How many providers load at once.
+const PROVIDER_PARALLELISM: usize = 8;
 
 static RESERVED_PROVIDER_NAME: &str = "default";
 
@@ -54,9 +63,9 @@ pub type ThreadSafeOutboundManager = Arc<OutboundManager>;
 impl OutboundManager {
     pub async fn new(
         outbounds: Vec<OutboundProxyProtocol>,
-        outbound_groups: Vec<OutboundGroupProtocol>,
+        mut outbound_groups: Vec<OutboundGroupProtocol>,
         proxy_providers: HashMap<String, OutboundProxyProviderDef>,
-        proxy_names: Vec<String>,
+        mut proxy_names: Vec<String>,
         dns_resolver: ThreadSafeDNSResolver,
         cache_store: ThreadSafeCacheFile,
         cwd: String,
@@ -64,7 +73,8 @@ impl OutboundManager {
         let mut handlers = HashMap::new();
         let mut provider_registry = HashMap::new();
         let mut selector_control = HashMap::new();
-        let proxy_manager = ProxyManager::new(dns_resolver.clone());
+        let proxy_manager =
+            ProxyManager::new(dns_resolver.clone(), Some(cache_store.clone()));
 
         debug!("initializing proxy providers");
         Self::load_proxy_providers(
@@ -72,14 +82,21 @@ impl OutboundManager {
             proxy_providers,
             proxy_manager.clone(),
             dns_resolver.clone(),
+            cache_store.clone(),
             &mut provider_registry,
         )
         .await?;
 
+        let (provider_groups, provider_proxies) =
+            Self::provider_groups(&provider_registry).await;
+        proxy_names.extend(provider_groups.iter().map(|x| x.name().to_owned()));
+        outbound_groups.extend(provider_groups);
+
         debug!("initializing handlers");
         Self::load_handlers(
             outbounds,
             outbound_groups,
+            provider_proxies,
             proxy_names,
             proxy_manager.clone(),
             &mut provider_registry,
@@ -106,6 +123,34 @@ impl OutboundManager {
         self.proxy_providers.get(name).cloned()
     }
 
+    /// the names of all proxies and groups, those from providers included
+    pub async fn proxy_names(&self) -> HashSet<String> {
+        let mut names: HashSet<_> = self.handlers.keys().cloned().collect();
+        for provider in self.proxy_providers.values() {
+            let proxies = provider.read().await.proxies().await;
+            names.extend(proxies.iter().map(|x| x.name().to_owned()));
+        }
+        names
+    }
+
+    /// the hosts of all proxy servers, those from providers included
+    pub async fn server_hosts(&self) -> HashSet<String> {
+        let mut hosts: HashSet<_> = self
+            .handlers
+            .values()
+            .filter_map(|x| x.server().map(|(host, _)| host.to_owned()))
+            .collect();
+        for provider in self.proxy_providers.values() {
+            let proxies = provider.read().await.proxies().await;
+            hosts.extend(
+                proxies
+                    .iter()
+                    .filter_map(|x| x.server().map(|(host, _)| host.to_owned())),
+            );
+        }
+        hosts
+    }
+
     // API handles start
     pub fn get_selector_control(
         &self,
@@ -157,6 +202,17 @@ impl OutboundManager {
         r
     }
 
+    /// the stored health check history of `proxy`, oldest first
+    pub async fn delay_history(
+        &self,
+        proxy: &AnyOutboundHandler,
+        limit: usize,
+    ) -> Vec<DelayHistory> {
+        self.proxy_manager
+            .full_delay_history(proxy.name(), limit)
+            .await
+    }
+
     /// a wrapper of proxy_manager.url_test so that proxy_manager is not exposed
     pub async fn url_test(
         &self,
@@ -174,10 +230,43 @@ impl OutboundManager {
 
     // API handlers end
 
+    /// The groups declared by the providers, named `<provider>/<group>`,
+    /// and the proxies of those providers by `<provider>/<proxy>`. They're
+    /// taken from the payloads at startup, updates to them apply on reload.
+    async fn provider_groups(
+        provider_registry: &HashMap<String, ThreadSafeProxyProvider>,
+    ) -> (
+        Vec<OutboundGroupProtocol>,
+        HashMap<String, AnyOutboundHandler>,
+    ) {
+        let mut groups = vec![];
+        let mut proxies = HashMap::new();
+
+        let mut names = provider_registry.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let provider = provider_registry[name].read().await;
+            let declared = provider.groups().await;
+            if declared.is_empty() {
+                continue;
+            }
+            let members = provider.proxies().await;
+            let member_names = members.iter().map(|x| x.name().to_owned()).collect();
+            groups.extend(namespace_provider_groups(name, declared, &member_names));
+            proxies.extend(
+                members
+                    .into_iter()
+                    .map(|x| (format!("{}/{}", name, x.name()), x)),
+            );
+        }
+        (groups, proxies)
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn load_handlers(
         outbounds: Vec<OutboundProxyProtocol>,
         outbound_groups: Vec<OutboundGroupProtocol>,
+        provider_proxies: HashMap<String, AnyOutboundHandler>,
         proxy_names: Vec<String>,
         proxy_manager: ProxyManager,
         provider_registry: &mut HashMap<String, ThreadSafeProxyProvider>,
@@ -188,46 +277,7 @@ impl OutboundManager {
         let mut proxy_providers = vec![];
 
         for outbound in outbounds.iter() {
-            match outbound {
-                OutboundProxyProtocol::Direct => {
-                    handlers
-                        .insert(PROXY_DIRECT.to_string(), direct::Handler::new());
-                }
-
-                OutboundProxyProtocol::Reject => {
-                    handlers
-                        .insert(PROXY_REJECT.to_string(), reject::Handler::new());
-                }
-                #[cfg(feature = "shadowsocks")]
-                OutboundProxyProtocol::Ss(s) => {
-                    handlers.insert(s.name.clone(), s.try_into()?);
-                }
-
-                OutboundProxyProtocol::Socks5(s) => {
-                    handlers.insert(s.name.clone(), s.try_into()?);
-                }
-
-                OutboundProxyProtocol::Vmess(v) => {
-                    handlers.insert(v.name.clone(), v.try_into()?);
-                }
-
-                OutboundProxyProtocol::Trojan(v) => {
-                    handlers.insert(v.name.clone(), v.try_into()?);
-                }
-
-                OutboundProxyProtocol::Wireguard(wg) => {
-                    warn!("wireguard is experimental");
-                    handlers.insert(wg.name.clone(), wg.try_into()?);
-                }
-
-                OutboundProxyProtocol::Tor(tor) => {
-                    handlers.insert(tor.name.clone(), tor.try_into()?);
-                }
-                #[cfg(feature = "tuic")]
-                OutboundProxyProtocol::Tuic(tuic) => {
-                    handlers.insert(tuic.name.clone(), tuic.try_into()?);
-                }
-            }
+            handlers.insert(outbound.name().to_owned(), registry::create(outbound)?);
         }
 
         let mut outbound_groups = outbound_groups;
@@ -240,6 +290,7 @@ impl OutboundManager {
             interval: u64,
             lazy: bool,
             handlers: &HashMap<String, AnyOutboundHandler>,
+            provider_proxies: &HashMap<String, AnyOutboundHandler>,
             proxy_manager: ProxyManager,
             proxy_providers: &mut Vec<ThreadSafeProxyProvider>,
             provider_registry: &mut HashMap<String, ThreadSafeProxyProvider>,
@@ -255,6 +306,7 @@ impl OutboundManager {
                 .map(|x| {
                     handlers
                         .get(x)
+                        .or_else(|| provider_proxies.get(x))
                         .ok_or_else(|| {
                             Error::InvalidConfig(format!("proxy {} not found", x))
                         })
@@ -308,6 +360,7 @@ impl OutboundManager {
                             0,
                             true,
                             handlers,
+                            &provider_proxies,
                             proxy_manager.clone(),
                             &mut proxy_providers,
                             provider_registry,
@@ -358,6 +411,7 @@ impl OutboundManager {
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
                             handlers,
+                            &provider_proxies,
                             proxy_manager.clone(),
                             &mut proxy_providers,
                             provider_registry,
@@ -411,6 +465,7 @@ impl OutboundManager {
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
                             handlers,
+                            &provider_proxies,
                             proxy_manager.clone(),
                             &mut proxy_providers,
                             provider_registry,
@@ -463,6 +518,7 @@ impl OutboundManager {
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
                             handlers,
+                            &provider_proxies,
                             proxy_manager.clone(),
                             &mut proxy_providers,
                             provider_registry,
@@ -484,9 +540,12 @@ impl OutboundManager {
                     let load_balance = loadbalance::Handler::new(
                         loadbalance::HandlerOptions {
                             name: proto.name.clone(),
+                            strategy: proto.strategy.unwrap_or_default(),
+                            weights: proto.weights.clone().unwrap_or_default(),
                             ..Default::default()
                         },
                         providers,
+                        proxy_manager.clone(),
                     );
 
                     handlers.insert(proto.name.clone(), Arc::new(load_balance));
@@ -514,6 +573,7 @@ impl OutboundManager {
                             0,
                             true,
                             handlers,
+                            &provider_proxies,
                             proxy_manager.clone(),
                             &mut proxy_providers,
                             provider_registry,
@@ -593,6 +653,7 @@ impl OutboundManager {
         proxy_providers: HashMap<String, OutboundProxyProviderDef>,
         proxy_manager: ProxyManager,
         resolver: ThreadSafeDNSResolver,
+        cache_store: ThreadSafeCacheFile,
         provider_registry: &mut HashMap<String, ThreadSafeProxyProvider>,
     ) -> Result<(), Error> {
         for (name, provider) in proxy_providers.into_iter() {
@@ -605,7 +666,9 @@ impl OutboundManager {
                         http.path,
                         Some(cwd.clone()),
                         resolver.clone(),
-                    );
+                        Some(cache_store.clone()),
+                    )
+                    .with_integrity(http.integrity);
                     let hc = HealthCheck::new(
                         vec![],
                         http.health_check.url,
@@ -667,21 +730,24 @@ impl OutboundManager {
             }
         }
 
-        for p in provider_registry.values() {
-            info!("initializing provider {}", p.read().await.name());
-            let p = p.write().await;
-            match p.initialize().await {
-                Ok(_) => {}
-                Err(err) => {
-                    error!(
-                        "failed to initialize proxy provider {}: {}",
-                        p.name(),
-                        err
-                    );
+        // a provider that fails to load is left empty rather than holding up
+        // the others
+        stream::iter(provider_registry.values())
+            .for_each_concurrent(PROVIDER_PARALLELISM, |p| async move {
+                let p = p.write().await;
+                info!("initializing provider {}", p.name());
+                match p.initialize().await {
+                    Ok(_) => info!("initialized provider {}", p.name()),
+                    Err(err) => {
+                        error!(
+                            "failed to initialize proxy provider {}: {}",
+                            p.name(),
+                            err
+                        );
+                    }
                 }
-            }
-            info!("initialized provider {}", p.name());
-        }
+            })
+            .await;
 
         Ok(())
     }
@@ -1,9 +1,67 @@
 use std::{
     cell::RefCell,
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
 };
 
-use crate::{config::internal::proxy::OutboundGroupProtocol, Error};
+use tracing::warn;
+
+use crate::{
+    config::internal::proxy::{OutboundGroupProtocol, PROXY_DIRECT, PROXY_REJECT},
+    Error,
+};
+
+/// The groups declared by the payload of `provider`, renamed
+/// `<provider>/<group>` with their proxies `<provider>/<proxy>`, as they
+/// may only use the proxies and groups of the payload, or DIRECT and
+/// REJECT. Unknown proxies are left out, and the groups left empty.
+pub fn namespace_provider_groups(
+    provider: &str,
+    mut groups: Vec<OutboundGroupProtocol>,
+    proxies: &HashSet<String>,
+) -> Vec<OutboundGroupProtocol> {
+    let builtin = |x: &str| x == PROXY_DIRECT || x == PROXY_REJECT;
+
+    for g in groups.iter_mut() {
+        let (name, _, use_provider) = g.parts_mut();
+        if use_provider.take().is_some() {
+            warn!("provider {} group {}: `use` is ignored", provider, name);
+        }
+    }
+    // until no group is left empty by the groups removed before
+    loop {
+        let names: HashSet<_> = groups.iter().map(|x| x.name().to_owned()).collect();
+        let before = groups.len();
+        groups.retain_mut(|g| {
+            let (name, members, _) = g.parts_mut();
+            let members = members.get_or_insert_with(Vec::new);
+            members.retain(|x| {
+                let known = builtin(x) || proxies.contains(x) || names.contains(x);
+                if !known {
+                    warn!("provider {} group {}: {} not found", provider, name, x);
+                }
+                known
+            });
+            if members.is_empty() {
+                warn!("provider {} group {} has no proxies", provider, name);
+            }
+            !members.is_empty()
+        });
+        if groups.len() == before {
+            break;
+        }
+    }
+
+    for g in groups.iter_mut() {
+        let (name, members, _) = g.parts_mut();
+        *name = format!("{}/{}", provider, name);
+        for x in members.iter_mut().flatten() {
+            if !builtin(x) {
+                *x = format!("{}/{}", provider, x);
+            }
+        }
+    }
+    groups
+}
 
 // copy paste from https://github.com/Dreamacro/clash/blob/6a661bff0c185f38c4bd9d21c91a3233ba5fdb97/config/utils.go#L21
 pub fn proxy_groups_dag_sort(
@@ -229,6 +287,52 @@ mod tests {
         assert_eq!(groups.last().unwrap().name(), "relay");
     }
 
+    #[test]
+    fn test_namespace_provider_groups() {
+        let groups = vec![
+            OutboundGroupProtocol::Select(OutboundGroupSelect {
+                name: "select".to_owned(),
+                proxies: Some(vec![
+                    "auto".to_owned(),
+                    "hk".to_owned(),
+                    "DIRECT".to_owned(),
+                ]),
+                ..Default::default()
+            }),
+            OutboundGroupProtocol::UrlTest(OutboundGroupUrlTest {
+                name: "auto".to_owned(),
+                proxies: Some(vec!["hk".to_owned(), "jp".to_owned()]),
+                use_provider: Some(vec!["other".to_owned()]),
+                ..Default::default()
+            }),
+            // left empty, then the group using it too
+            OutboundGroupProtocol::Fallback(OutboundGroupFallback {
+                name: "gone".to_owned(),
+                proxies: Some(vec!["us".to_owned()]),
+                ..Default::default()
+            }),
+            OutboundGroupProtocol::Relay(OutboundGroupRelay {
+                name: "chain".to_owned(),
+                proxies: Some(vec!["gone".to_owned()]),
+                ..Default::default()
+            }),
+        ];
+        let proxies = ["hk".to_owned(), "jp".to_owned()].into();
+
+        let mut groups = super::namespace_provider_groups("sub", groups, &proxies);
+        assert_eq!(groups.len(), 2);
+        let (name, members, _) = groups[0].parts_mut();
+        assert_eq!(name, "sub/select");
+        assert_eq!(
+            members.as_deref().unwrap(),
+            ["sub/auto", "sub/hk", "DIRECT"]
+        );
+        let (name, members, use_provider) = groups[1].parts_mut();
+        assert_eq!(name, "sub/auto");
+        assert_eq!(members.as_deref().unwrap(), ["sub/hk", "sub/jp"]);
+        assert!(use_provider.is_none());
+    }
+
     #[test]
     fn test_proxy_groups_dag_sort_cycle() {
         let g1 = OutboundGroupRelay {
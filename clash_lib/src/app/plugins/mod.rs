@@ -0,0 +1,269 @@
+//! Experimental WASM plugins that inspect TCP connections once they're
+//! sniffed and before they're routed. A plugin can reject the connection,
+//! pick the proxy or group it goes through, or rewrite its host.
+//!
+//! A plugin is a WebAssembly module exporting
+//!
+//! - `memory`, its linear memory
+//! - `alloc(len: i32) -> i32`, where the host writes the input
+//! - `on_connection(ptr: i32, len: i32) -> i64`, called with the input, a
+//!   JSON object like
+//!   `{"network":"TCP","source":"192.168.1.2:51234","host":"example.com",
+//!   "port":443,"sniff-host":"example.com","inbound":"mixed",
+//!   "user":"alice"}`, `user` being null unless the inbound authenticated
+//!   it, returning
+//!   `ptr << 32 | len` of its JSON verdict
+//!   `{"reject":false,"policy":"PROXY","host":"example.org"}`, or 0 to leave
+//!   the connection alone
+//!
+//! and may import `env.log(level: i32, ptr: i32, len: i32)` to log a UTF-8
+//! message, levels 0 to 4 being error to trace.
+//!
+//! Each call gets `fuel` instructions and the plugin can't grow its memory
+//! past `memory` MiB. The calls run on blocking threads, as many at once as
+//! there are CPUs, each on an instance of the plugin of its own: instances
+//! don't share their memory and a call may find it as another left it. The
+//! plugins of a connection run in the order they're configured, each seeing
+//! the host set by the ones before. A failing plugin is logged and skipped,
+//! it never takes connections down with it.
+
+#[cfg(feature = "wasm-plugins")]
+mod wasm;
+#[cfg(not(feature = "wasm-plugins"))]
+mod wasm {
+    /// No plugins can be loaded without the runtime.
+    pub(super) enum Plugin {}
+
+    impl Plugin {
+        pub fn name(&self) -> &str {
+            match *self {}
+        }
+
+        pub fn call(&self, _: &[u8]) -> Result<Option<Vec<u8>>, crate::Error> {
+            match *self {}
+        }
+    }
+}
+
+use std::{net::SocketAddr, path::Path, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+use crate::{
+    config::def,
+    session::{Session, SocksAddr},
+    Error,
+};
+
+/// What the plugins decided about a connection.
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct Verdict {
+    pub reject: bool,
+    /// the proxy or group to use instead of the one the rules pick
+    pub policy: Option<String>,
+    /// the host to route and dial instead
+    pub host: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Connection<'a> {
+    network: String,
+    source: SocketAddr,
+    host: String,
+    port: u16,
+    sniff_host: Option<&'a str>,
+    inbound: &'a str,
+    user: Option<&'a str>,
+}
+
+impl<'a> From<&'a Session> for Connection<'a> {
+    fn from(sess: &'a Session) -> Self {
+        Self {
+            network: sess.network.to_string(),
+            source: sess.source,
+            host: sess.destination.host(),
+            port: sess.destination.port(),
+            sniff_host: sess.sniff_host.as_deref(),
+            inbound: &sess.inbound_name,
+            user: sess.inbound_user.as_deref(),
+        }
+    }
+}
+
+pub struct Plugins {
+    plugins: Vec<Arc<wasm::Plugin>>,
+    /// bounds the calls running at once
+    calls: Semaphore,
+}
+
+impl Plugins {
+    pub fn new(
+        cfg: Vec<def::Plugin>,
+        cwd: &Path,
+    ) -> Result<Option<Arc<Self>>, Error> {
+        if cfg.is_empty() {
+            return Ok(None);
+        }
+
+        #[cfg(feature = "wasm-plugins")]
+        {
+            let plugins = cfg
+                .iter()
+                .map(|x| {
+                    wasm::Plugin::load(
+                        &cwd.join(&x.path),
+                        x.fuel,
+                        x.memory * 1024 * 1024,
+                    )
+                    .map(Arc::new)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Some(Arc::new(Self::with(plugins))))
+        }
+
+        #[cfg(not(feature = "wasm-plugins"))]
+        {
+            let _ = cwd;
+            warn!(
+                "{} plugins configured but this build doesn't support them, \
+                 enable the wasm-plugins feature",
+                cfg.len()
+            );
+            Ok(None)
+        }
+    }
+
+    #[cfg(feature = "wasm-plugins")]
+    fn with(plugins: Vec<Arc<wasm::Plugin>>) -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |x| x.get());
+        Self {
+            plugins,
+            calls: Semaphore::new(cpus),
+        }
+    }
+
+    /// Call `plugin` off the runtime, it may run until its fuel is out.
+    async fn call(
+        &self,
+        plugin: &Arc<wasm::Plugin>,
+        input: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let _permit = self.calls.acquire().await.expect("never closed");
+        let plugin = plugin.clone();
+        tokio::task::spawn_blocking(move || plugin.call(&input))
+            .await
+            .map_err(|e| Error::Operation(e.to_string()))?
+    }
+
+    /// Run the plugins on `sess`, rewriting its host if they ask to.
+    pub async fn inspect(&self, sess: &mut Session) -> Verdict {
+        let mut verdict = Verdict::default();
+        for plugin in self.plugins.iter() {
+            let input = serde_json::to_vec(&Connection::from(&*sess))
+                .expect("connection is serializable");
+            let output = match self.call(plugin, input).await {
+                Ok(Some(output)) => output,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("plugin {} failed on {}: {}", plugin.name(), sess, e);
+                    continue;
+                }
+            };
+            let v: Verdict = match serde_json::from_slice(&output) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("invalid verdict from plugin {}: {}", plugin.name(), e);
+                    continue;
+                }
+            };
+            debug!("plugin {} on {}: {:?}", plugin.name(), sess, v);
+
+            if let Some(host) = v.host.filter(|x| !x.is_empty()) {
+                sess.destination =
+                    SocksAddr::Domain(host.clone(), sess.destination.port());
+                sess.sniff_host = Some(host.clone());
+                verdict.host = Some(host);
+            }
+            if v.policy.is_some() {
+                verdict.policy = v.policy;
+            }
+            if v.reject {
+                verdict.reject = true;
+                break;
+            }
+        }
+        verdict
+    }
+}
+
+#[cfg(all(test, feature = "wasm-plugins"))]
+mod tests {
+    use crate::session::{Session, SocksAddr};
+
+    use std::sync::Arc;
+
+    use super::{wasm::Plugin, Plugins, Verdict};
+
+    /// A plugin answering every connection with `verdict`.
+    fn constant(verdict: &str) -> Arc<Plugin> {
+        let wat = format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{}")
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "on_connection") (param i32 i32) (result i64)
+                    i64.const {}))"#,
+            verdict.replace('"', "\\\""),
+            verdict.len()
+        );
+        Plugin::new(
+            "test".to_owned(),
+            &wat::parse_str(wat).unwrap(),
+            1000,
+            1 << 20,
+        )
+        .map(Arc::new)
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_inspect() {
+        let plugins = Plugins::with(vec![
+            constant(r#"{"host":"example.org","policy":"A"}"#),
+            constant(r#"{"policy":"B"}"#),
+        ]);
+        let mut sess = Session {
+            destination: SocksAddr::Domain("example.com".to_owned(), 443),
+            ..Default::default()
+        };
+        assert_eq!(
+            plugins.inspect(&mut sess).await,
+            Verdict {
+                reject: false,
+                policy: Some("B".to_owned()),
+                host: Some("example.org".to_owned()),
+            }
+        );
+        assert_eq!(sess.destination.host(), "example.org");
+        assert_eq!(sess.destination.port(), 443);
+
+        let plugins = Plugins::with(vec![
+            constant(r#"{"reject":true}"#),
+            constant(r#"{"policy":"B"}"#),
+        ]);
+        let verdict = plugins.inspect(&mut sess).await;
+        assert!(verdict.reject);
+        assert_eq!(verdict.policy, None);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_verdict() {
+        let plugins = Plugins::with(vec![constant("not json")]);
+        let mut sess = Session::default();
+        assert_eq!(plugins.inspect(&mut sess).await, Verdict::default());
+    }
+}
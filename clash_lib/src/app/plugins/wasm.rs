@@ -0,0 +1,300 @@
+use std::{fmt::Display, path::Path, sync::Mutex};
+
+use tracing::{debug, error, info, trace, warn};
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TypedFunc,
+};
+
+use crate::Error;
+
+/// the longest verdict or log message read from a plugin
+const MAX_OUTPUT: usize = 64 * 1024;
+
+struct State {
+    name: String,
+    limits: StoreLimits,
+}
+
+struct Instance {
+    store: Store<State>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_connection: TypedFunc<(i32, i32), i64>,
+}
+
+pub(super) struct Plugin {
+    name: String,
+    fuel: u64,
+    memory: usize,
+    engine: Engine,
+    module: Module,
+    /// the instances not in a call, one more is made when all are
+    idle: Mutex<Vec<Instance>>,
+}
+
+fn failed<E: Display>(name: &str) -> impl Fn(E) -> Error + '_ {
+    move |e| Error::InvalidConfig(format!("plugin {}: {}", name, e))
+}
+
+impl Plugin {
+    pub fn load(path: &Path, fuel: u64, memory: usize) -> Result<Self, Error> {
+        let name = path
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let wasm = std::fs::read(path).map_err(failed(&name))?;
+        let plugin = Self::new(name, &wasm, fuel, memory)?;
+        debug!("loaded plugin {} from {}", plugin.name, path.display());
+        Ok(plugin)
+    }
+
+    pub fn new(
+        name: String,
+        wasm: &[u8],
+        fuel: u64,
+        memory: usize,
+    ) -> Result<Self, Error> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(failed(&name))?;
+
+        let plugin = Self {
+            name,
+            fuel,
+            memory,
+            engine,
+            module,
+            idle: Mutex::new(vec![]),
+        };
+        // fails the config rather than the first call
+        let instance = plugin.instantiate()?;
+        plugin.idle.lock().unwrap().push(instance);
+        Ok(plugin)
+    }
+
+    /// A new instance of the module, with its own memory.
+    fn instantiate(&self) -> Result<Instance, Error> {
+        let name = &self.name;
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.memory)
+            .instances(1)
+            .build();
+        let mut store = Store::new(
+            &self.engine,
+            State {
+                name: name.clone(),
+                limits,
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        // the start function runs on this
+        store.set_fuel(self.fuel).map_err(failed(name))?;
+
+        let mut linker = Linker::new(&self.engine);
+        linker.func_wrap("env", "log", log).map_err(failed(name))?;
+        let instance = linker
+            .instantiate(&mut store, &self.module)
+            .and_then(|x| x.start(&mut store))
+            .map_err(failed(name))?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| failed(name)("no memory exported"))?;
+        let alloc = instance
+            .get_typed_func(&store, "alloc")
+            .map_err(failed(name))?;
+        let on_connection = instance
+            .get_typed_func(&store, "on_connection")
+            .map_err(failed(name))?;
+
+        Ok(Instance {
+            store,
+            memory,
+            alloc,
+            on_connection,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Call `on_connection` with `input`, returning the verdict if there's
+    /// one. This blocks until the plugin returns or runs out of fuel, on an
+    /// idle instance or a new one.
+    pub fn call(&self, input: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut instance = match idle {
+            Some(instance) => instance,
+            None => self.instantiate()?,
+        };
+        let output = Self::call_on(&mut instance, self.fuel, input)?;
+        // one that failed may be left in any state, it's dropped
+        self.idle.lock().unwrap().push(instance);
+        Ok(output)
+    }
+
+    fn call_on(
+        instance: &mut Instance,
+        fuel: u64,
+        input: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let op = |e: wasmi::Error| Error::Operation(e.to_string());
+
+        let Instance {
+            store,
+            memory,
+            alloc,
+            on_connection,
+        } = instance;
+        store.set_fuel(fuel).map_err(op)?;
+
+        let len = input.len() as i32;
+        let ptr = alloc.call(&mut *store, len).map_err(op)?;
+        memory
+            .write(&mut *store, ptr as u32 as usize, input)
+            .map_err(|e| Error::Operation(e.to_string()))?;
+
+        let packed = on_connection.call(&mut *store, (ptr, len)).map_err(op)?;
+        let packed = packed as u64;
+        if packed == 0 {
+            return Ok(None);
+        }
+        let (ptr, len) = ((packed >> 32) as usize, (packed as u32) as usize);
+        if len > MAX_OUTPUT {
+            return Err(Error::Operation(format!("verdict too long: {}", len)));
+        }
+        let mut output = vec![0; len];
+        memory
+            .read(&*store, ptr, &mut output)
+            .map_err(|e| Error::Operation(e.to_string()))?;
+        Ok(Some(output))
+    }
+}
+
+/// `env.log(level, ptr, len)`
+fn log(caller: Caller<'_, State>, level: i32, ptr: i32, len: i32) {
+    let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory)
+    else {
+        return;
+    };
+    let mut msg = vec![0; (len as u32 as usize).min(MAX_OUTPUT)];
+    if memory.read(&caller, ptr as u32 as usize, &mut msg).is_err() {
+        return;
+    }
+    let msg = String::from_utf8_lossy(&msg);
+    let name = &caller.data().name;
+    match level {
+        0 => error!("[plugin {}] {}", name, msg),
+        1 => warn!("[plugin {}] {}", name, msg),
+        2 => info!("[plugin {}] {}", name, msg),
+        3 => debug!("[plugin {}] {}", name, msg),
+        _ => trace!("[plugin {}] {}", name, msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Plugin;
+
+    fn plugin(wat: &str, fuel: u64) -> Plugin {
+        Plugin::new(
+            "test".to_owned(),
+            &wat::parse_str(wat).unwrap(),
+            fuel,
+            1 << 20,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_call() {
+        // echoes its input back as the verdict
+        let echo = plugin(
+            r#"(module
+                (import "env" "log" (func $log (param i32 i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "called")
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "on_connection") (param i32 i32) (result i64)
+                    (call $log (i32.const 2) (i32.const 0) (i32.const 6))
+                    (i64.or
+                        (i64.shl (i64.extend_i32_u (local.get 0)) (i64.const 32))
+                        (i64.extend_i32_u (local.get 1)))))"#,
+            1000,
+        );
+        assert_eq!(echo.call(b"{}").unwrap().unwrap(), b"{}");
+        assert_eq!(echo.call(b"[1]").unwrap().unwrap(), b"[1]");
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let spin = plugin(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 0)
+                (func (export "on_connection") (param i32 i32) (result i64)
+                    (loop $spin (br $spin))
+                    i64.const 0))"#,
+            1000,
+        );
+        assert!(spin.call(b"{}").is_err());
+        // refuelled for every call
+        assert!(spin.call(b"{}").is_err());
+
+        // past the memory limit
+        let grow = plugin(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32)
+                    (drop (memory.grow (i32.const 64)))
+                    (i32.const 0))
+                (func (export "on_connection") (param i32 i32) (result i64)
+                    (i64.extend_i32_s (memory.size))))"#,
+            1000,
+        );
+        assert_eq!(grow.call(b"{}").unwrap().unwrap().len(), 1);
+
+        let invalid = Plugin::new("test".to_owned(), b"not wasm", 1000, 1 << 20);
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_concurrent_calls() {
+        // counts its calls in its memory
+        let counter = plugin(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "on_connection") (param i32 i32) (result i64)
+                    (i32.store (i32.const 0)
+                        (i32.add (i32.load (i32.const 0)) (i32.const 1)))
+                    i64.const 4))"#,
+            1000,
+        );
+        let calls = std::sync::Barrier::new(4);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    calls.wait();
+                    assert!(counter.call(b"{}").unwrap().is_some());
+                });
+            }
+        });
+
+        // no more instances than calls at once
+        let instances = counter.idle.lock().unwrap().len();
+        assert!((1..=4).contains(&instances));
+
+        // and they're reused
+        let count = || {
+            let output = counter.call(b"{}").unwrap().unwrap();
+            u32::from_le_bytes(output.try_into().unwrap())
+        };
+        let first = count();
+        assert_eq!(count(), first + 1);
+        assert_eq!(counter.idle.lock().unwrap().len(), instances);
+    }
+}
@@ -1,68 +1,65 @@
-use std::{collections::HashMap, sync::Arc};
+//! The on-disk store under the working directory, keeping state across
+//! restarts: selector choices, fake IP mappings, provider etags and health
+//! check history.
 
-use serde::{Deserialize, Serialize};
-use tracing::{error, trace};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Db {
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+const SELECTED: &str = "selected";
+const IP_TO_HOST: &str = "ip_to_host";
+const HOST_TO_IP: &str = "host_to_ip";
+const ETAG: &str = "etag";
+const DELAY_HISTORY: &str = "delay_history";
+const TRAFFIC: &str = "traffic";
+
+/// a database can only be opened once, reloads share the open one
+static OPENED: Lazy<Mutex<HashMap<PathBuf, sled::Db>>> = Lazy::new(Default::default);
+
+/// the yaml file used before the store was a database
+#[derive(Deserialize, Default)]
+struct LegacyDb {
+    #[serde(default)]
     selected: HashMap<String, String>,
+    #[serde(default)]
     ip_to_host: HashMap<String, String>,
+    #[serde(default)]
     host_to_ip: HashMap<String, String>,
 }
 
 #[derive(Clone)]
-pub struct ThreadSafeCacheFile(Arc<tokio::sync::RwLock<CacheFile>>);
+pub struct ThreadSafeCacheFile(CacheFile);
 
 impl ThreadSafeCacheFile {
     pub fn new(path: &str, store_selected: bool) -> Self {
-        let store = Arc::new(tokio::sync::RwLock::new(CacheFile::new(
-            path,
-            store_selected,
-        )));
-
-        let path = path.to_string();
-        let store_clone = store.clone();
-
-        if store_selected {
-            tokio::spawn(async move {
-                let store = store_clone;
-                loop {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-                    let r = store.read().await;
-                    let db = r.db.clone();
-                    drop(r);
-
-                    let s = match serde_yaml::to_string(&db) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            error!("failed to serialize cache file: {}", e);
-                            continue;
-                        }
-                    };
-
-                    if let Err(e) = tokio::fs::write(&path, s).await {
-                        error!("failed to write cache file: {}", e);
-                    } else {
-                        trace!("cache file flushed to {}", path);
-                    }
-                }
-            });
-        }
+        Self(CacheFile::new(path, store_selected))
+    }
 
-        Self(store)
+    /// Keep the selector choices of the named profile `profile` apart from
+    /// the others, `None` for a config that isn't a profile.
+    pub fn for_profile(mut self, profile: Option<&str>) -> Self {
+        self.0.selected = match profile {
+            Some(name) => format!("{}/{}", SELECTED, name),
+            None => SELECTED.to_owned(),
+        };
+        self
     }
 
     pub async fn set_selected(&self, group: &str, server: &str) {
-        let mut g = self.0.write().await;
-        if g.store_selected() {
-            g.set_selected(group, server);
+        if self.0.store_selected {
+            self.0.insert(&self.0.selected, group, server.as_bytes());
         }
     }
 
     pub async fn get_selected(&self, group: &str) -> Option<String> {
-        let g = self.0.read().await;
-        if g.store_selected() {
-            g.db.selected.get(group).cloned()
+        if self.0.store_selected {
+            self.0.get_string(&self.0.selected, group)
         } else {
             None
         }
@@ -70,99 +67,350 @@ impl ThreadSafeCacheFile {
 
     #[allow(dead_code)]
     pub async fn get_selected_map(&self) -> HashMap<String, String> {
-        let g = self.0.read().await;
-        if g.store_selected() {
-            g.get_selected_map()
+        if self.0.store_selected {
+            self.0.get_map(&self.0.selected)
         } else {
             HashMap::new()
         }
     }
 
     pub async fn set_ip_to_host(&self, ip: &str, host: &str) {
-        self.0.write().await.set_ip_to_host(ip, host);
+        self.0.insert(IP_TO_HOST, ip, host.as_bytes());
     }
 
     pub async fn set_host_to_ip(&self, host: &str, ip: &str) {
-        self.0.write().await.set_host_to_ip(host, ip);
+        self.0.insert(HOST_TO_IP, host, ip.as_bytes());
     }
 
     pub async fn get_fake_ip(&self, ip_or_host: &str) -> Option<String> {
-        self.0.read().await.get_fake_ip(ip_or_host)
+        self.0
+            .get_string(IP_TO_HOST, ip_or_host)
+            .or_else(|| self.0.get_string(HOST_TO_IP, ip_or_host))
     }
 
     pub async fn delete_fake_ip_pair(&self, ip: &str, host: &str) {
-        self.0.write().await.delete_fake_ip_pair(ip, host);
+        self.0.remove(IP_TO_HOST, ip);
+        self.0.remove(HOST_TO_IP, host);
+    }
+
+    pub async fn flush_fake_ip(&self) {
+        self.0.clear(IP_TO_HOST);
+        self.0.clear(HOST_TO_IP);
+    }
+
+    /// the etag of the last download from `url`
+    pub async fn get_etag(&self, url: &str) -> Option<String> {
+        self.0.get_string(ETAG, url)
+    }
+
+    pub async fn set_etag(&self, url: &str, etag: &str) {
+        self.0.insert(ETAG, url, etag.as_bytes());
+    }
+
+    /// the health check results of `proxy`, oldest first
+    pub async fn get_delay_history<T: DeserializeOwned>(
+        &self,
+        proxy: &str,
+    ) -> Vec<T> {
+        let tree = format!("{}/{}", DELAY_HISTORY, proxy);
+        self.0.import_delay_history(&tree, proxy);
+        self.0
+            .values(&tree)
+            .into_iter()
+            .filter_map(|v| {
+                serde_json::from_slice(&v)
+                    .map_err(|e| warn!("invalid delay history of {}: {}", proxy, e))
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// Append a health check result of `proxy`, dropping the oldest one if
+    /// `drop_oldest`. Each result is an entry of its own, so a health check
+    /// doesn't write the whole history again.
+    pub async fn push_delay_history<T: Serialize>(
+        &self,
+        proxy: &str,
+        entry: &T,
+        drop_oldest: bool,
+    ) {
+        let tree = format!("{}/{}", DELAY_HISTORY, proxy);
+        match serde_json::to_vec(entry) {
+            Ok(v) => self.0.push(&tree, &v, drop_oldest),
+            Err(e) => error!("failed to serialize delay history: {}", e),
+        }
+    }
+
+    pub async fn get_traffic_stats<T: DeserializeOwned>(&self) -> Option<T> {
+        let v = self.0.get(TRAFFIC, "stats")?;
+        serde_json::from_slice(&v)
+            .map_err(|e| warn!("invalid traffic statistics: {}", e))
+            .ok()
+    }
+
+    pub async fn set_traffic_stats<T: Serialize>(&self, stats: &T) {
+        match serde_json::to_vec(stats) {
+            Ok(v) => self.0.insert(TRAFFIC, "stats", &v),
+            Err(e) => error!("failed to serialize traffic statistics: {}", e),
+        }
+    }
+
+    /// Write pending changes to disk.
+    pub async fn flush(&self) {
+        if let Err(e) = self.0.db.flush_async().await {
+            error!("failed to flush cache store: {}", e);
+        }
     }
 }
 
+#[derive(Clone)]
 struct CacheFile {
-    db: Db,
+    db: sled::Db,
 
     store_selected: bool,
+    /// the tree holding the selector choices
+    selected: String,
 }
 
 impl CacheFile {
-    pub fn new(path: &str, store_selected: bool) -> Self {
-        let db = match std::fs::read_to_string(path) {
-            Ok(s) => match serde_yaml::from_str(&s) {
-                Ok(db) => db,
-                Err(e) => {
-                    error!(
-                        "failed to parse cache file: {}, initilizing a new one",
-                        e
-                    );
-                    Db {
-                        selected: HashMap::new(),
-                        ip_to_host: HashMap::new(),
-                        host_to_ip: HashMap::new(),
-                    }
-                }
-            },
-            Err(e) => {
-                error!("failed to read cache file: {}, initializing a new one", e);
-                Db {
-                    selected: HashMap::new(),
-                    ip_to_host: HashMap::new(),
-                    host_to_ip: HashMap::new(),
-                }
+    fn new(path: &str, store_selected: bool) -> Self {
+        let path = PathBuf::from(path);
+        let mut opened = OPENED.lock().unwrap();
+        let db = match opened.get(&path) {
+            Some(db) => db.clone(),
+            None => {
+                let db = open(&path);
+                opened.insert(path, db.clone());
+                db
             }
         };
 
-        Self { db, store_selected }
+        Self {
+            db,
+            store_selected,
+            selected: SELECTED.to_owned(),
+        }
     }
 
-    pub fn store_selected(&self) -> bool {
-        self.store_selected
+    fn get(&self, tree: &str, key: &str) -> Option<sled::IVec> {
+        self.db
+            .open_tree(tree)
+            .and_then(|t| t.get(key))
+            .map_err(|e| error!("failed to read cache store: {}", e))
+            .ok()
+            .flatten()
     }
 
-    pub fn set_selected(&mut self, group: &str, server: &str) {
-        self.db
-            .selected
-            .insert(group.to_string(), server.to_string());
+    fn get_string(&self, tree: &str, key: &str) -> Option<String> {
+        self.get(tree, key)
+            .and_then(|v| String::from_utf8(v.to_vec()).ok())
     }
 
-    pub fn get_selected_map(&self) -> HashMap<String, String> {
-        self.db.selected.clone()
+    fn get_map(&self, tree: &str) -> HashMap<String, String> {
+        let Ok(t) = self.db.open_tree(tree) else {
+            return HashMap::new();
+        };
+        t.iter()
+            .filter_map(|x| x.ok())
+            .filter_map(|(k, v)| {
+                Some((
+                    String::from_utf8(k.to_vec()).ok()?,
+                    String::from_utf8(v.to_vec()).ok()?,
+                ))
+            })
+            .collect()
     }
 
-    pub fn set_ip_to_host(&mut self, ip: &str, host: &str) {
-        self.db.ip_to_host.insert(ip.to_string(), host.to_string());
+    fn insert(&self, tree: &str, key: &str, value: &[u8]) {
+        if let Err(e) = self.db.open_tree(tree).and_then(|t| t.insert(key, value)) {
+            error!("failed to write cache store: {}", e);
+        }
     }
 
-    pub fn set_host_to_ip(&mut self, host: &str, ip: &str) {
-        self.db.host_to_ip.insert(host.to_string(), ip.to_string());
+    /// the values of `tree` in the order of their keys
+    fn values(&self, tree: &str) -> Vec<sled::IVec> {
+        let Ok(t) = self.db.open_tree(tree) else {
+            return vec![];
+        };
+        t.iter().values().filter_map(|x| x.ok()).collect()
     }
 
-    pub fn get_fake_ip(&self, ip_or_host: &str) -> Option<String> {
-        self.db
-            .ip_to_host
-            .get(ip_or_host)
-            .or_else(|| self.db.host_to_ip.get(ip_or_host))
-            .cloned()
+    /// Append `value` to `tree`, keyed by an increasing id.
+    fn push(&self, tree: &str, value: &[u8], drop_oldest: bool) {
+        let pushed = self.db.open_tree(tree).and_then(|t| {
+            t.insert(self.db.generate_id()?.to_be_bytes(), value)?;
+            if drop_oldest {
+                t.pop_min()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = pushed {
+            error!("failed to write cache store: {}", e);
+        }
     }
 
-    pub fn delete_fake_ip_pair(&mut self, ip: &str, host: &str) {
-        self.db.ip_to_host.remove(ip);
-        self.db.host_to_ip.remove(host);
+    /// Move the history of `proxy` stored as a single list by older
+    /// versions into `tree`.
+    fn import_delay_history(&self, tree: &str, proxy: &str) {
+        let Some(v) = self.get(DELAY_HISTORY, proxy) else {
+            return;
+        };
+        match serde_json::from_slice::<Vec<serde_json::Value>>(&v) {
+            Ok(history) => {
+                for entry in history {
+                    self.push(tree, entry.to_string().as_bytes(), false);
+                }
+            }
+            Err(e) => warn!("invalid delay history of {}: {}", proxy, e),
+        }
+        self.remove(DELAY_HISTORY, proxy);
+    }
+
+    fn remove(&self, tree: &str, key: &str) {
+        if let Err(e) = self.db.open_tree(tree).and_then(|t| t.remove(key)) {
+            error!("failed to write cache store: {}", e);
+        }
+    }
+
+    fn clear(&self, tree: &str) {
+        if let Err(e) = self.db.open_tree(tree).and_then(|t| t.clear()) {
+            error!("failed to write cache store: {}", e);
+        }
+    }
+}
+
+/// Open the database at `path`, importing the legacy yaml file found there.
+/// Falls back to a temporary database if it can't be opened.
+fn open(path: &Path) -> sled::Db {
+    let legacy = if path.is_file() {
+        let backup = path.with_extension("db.bak");
+        let legacy = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|s| serde_yaml::from_str::<LegacyDb>(&s).ok());
+        if let Err(e) = std::fs::rename(path, &backup) {
+            error!("failed to move legacy cache file away: {}", e);
+        } else {
+            info!("legacy cache file moved to {}", backup.display());
+        }
+        legacy
+    } else {
+        None
+    };
+
+    let db = match sled::open(path) {
+        Ok(db) => db,
+        Err(e) => {
+            error!(
+                "failed to open cache store {}: {}, using a temporary one",
+                path.display(),
+                e
+            );
+            sled::Config::new()
+                .temporary(true)
+                .open()
+                .expect("open temporary cache store")
+        }
+    };
+
+    if let Some(legacy) = legacy {
+        for (tree, m) in [
+            (SELECTED, legacy.selected),
+            (IP_TO_HOST, legacy.ip_to_host),
+            (HOST_TO_IP, legacy.host_to_ip),
+        ] {
+            if let Ok(t) = db.open_tree(tree) {
+                for (k, v) in m {
+                    let _ = t.insert(k, v.as_bytes());
+                }
+            }
+        }
+    }
+
+    db
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThreadSafeCacheFile;
+
+    #[tokio::test]
+    async fn test_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let store = ThreadSafeCacheFile::new(path.to_str().unwrap(), true);
+
+        store.set_selected("PROXY", "ss01").await;
+        assert_eq!(store.get_selected("PROXY").await.as_deref(), Some("ss01"));
+
+        store.set_ip_to_host("198.18.0.2", "example.com").await;
+        store.set_host_to_ip("example.com", "198.18.0.2").await;
+        assert_eq!(
+            store.get_fake_ip("198.18.0.2").await.as_deref(),
+            Some("example.com")
+        );
+        store.flush_fake_ip().await;
+        assert!(store.get_fake_ip("example.com").await.is_none());
+
+        store.set_etag("https://example.com/sub", "\"abc\"").await;
+        assert_eq!(
+            store.get_etag("https://example.com/sub").await.as_deref(),
+            Some("\"abc\"")
+        );
+
+        for i in [1, 2, 3] {
+            store.push_delay_history("ss01", &i, false).await;
+        }
+        assert_eq!(store.get_delay_history::<u16>("ss01").await, vec![1, 2, 3]);
+
+        // profiles keep their own choices
+        let work = store.clone().for_profile(Some("work"));
+        assert!(work.get_selected("PROXY").await.is_none());
+        work.set_selected("PROXY", "ss02").await;
+        assert_eq!(work.get_selected("PROXY").await.as_deref(), Some("ss02"));
+        assert_eq!(store.get_selected("PROXY").await.as_deref(), Some("ss01"));
+
+        // opening it again shares the same database
+        let store = ThreadSafeCacheFile::new(path.to_str().unwrap(), false);
+        assert!(store.get_selected("PROXY").await.is_none());
+        assert_eq!(
+            store.get_etag("https://example.com/sub").await.as_deref(),
+            Some("\"abc\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_legacy_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        std::fs::write(
+            &path,
+            "selected:\n  PROXY: ss02\nip_to_host: {}\nhost_to_ip: {}\n",
+        )
+        .unwrap();
+
+        let store = ThreadSafeCacheFile::new(path.to_str().unwrap(), true);
+        assert_eq!(store.get_selected("PROXY").await.as_deref(), Some("ss02"));
+        assert!(path.is_dir());
+        assert!(dir.path().join("cache.db.bak").is_file());
+    }
+
+    #[tokio::test]
+    async fn test_delay_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let store = ThreadSafeCacheFile::new(path.to_str().unwrap(), true);
+
+        for i in 1..=3 {
+            store.push_delay_history("ss01", &i, false).await;
+        }
+        store.push_delay_history("ss01", &4, true).await;
+        assert_eq!(store.get_delay_history::<u16>("ss01").await, vec![2, 3, 4]);
+        assert!(store.get_delay_history::<u16>("ss02").await.is_empty());
+
+        // the list stored by older versions is imported once
+        store.0.insert(super::DELAY_HISTORY, "ss02", b"[1, 2]");
+        assert_eq!(store.get_delay_history::<u16>("ss02").await, vec![1, 2]);
+        store.push_delay_history("ss02", &3, false).await;
+        assert_eq!(store.get_delay_history::<u16>("ss02").await, vec![1, 2, 3]);
     }
 }
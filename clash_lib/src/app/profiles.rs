@@ -0,0 +1,105 @@
+//! Named profiles, config files in `profiles/` under the working directory
+//! that the running instance can switch between, e.g. with
+//! `PUT /profiles/work` for `profiles/work.yaml`.
+//!
+//! Switching is a reload from the profile's file. Selector choices are kept
+//! per profile, see [`super::profile::ThreadSafeCacheFile::for_profile`].
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::Error;
+
+const PROFILE_DIR: &str = "profiles";
+
+/// The names of the profiles under `cwd`, sorted.
+pub fn list(cwd: &Path) -> io::Result<Vec<String>> {
+    let dir = match std::fs::read_dir(cwd.join(PROFILE_DIR)) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut names = vec![];
+    for entry in dir {
+        let path = entry?.path();
+        if path.is_file() {
+            if let Some(name) = name_of(cwd, &path) {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Check `name` names a file right in the profiles directory.
+pub fn validate_name(name: &str) -> Result<(), Error> {
+    let valid =
+        !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']);
+    if !valid {
+        return Err(Error::InvalidConfig(format!(
+            "invalid profile name: {}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// The file of the profile `name` under `cwd`.
+pub fn path(cwd: &Path, name: &str) -> Result<PathBuf, Error> {
+    validate_name(name)?;
+
+    let dir = cwd.join(PROFILE_DIR);
+    ["yaml", "yml"]
+        .into_iter()
+        .map(|ext| dir.join(format!("{}.{}", name, ext)))
+        .find(|x| x.is_file())
+        .ok_or_else(|| Error::InvalidConfig(format!("profile {} not found", name)))
+}
+
+/// The name of the profile `path` is the file of, if it's one under `cwd`.
+pub fn name_of(cwd: &Path, path: &Path) -> Option<String> {
+    let is_yaml = path.extension().is_some_and(|x| x == "yaml" || x == "yml");
+    let dir = std::fs::canonicalize(cwd.join(PROFILE_DIR)).ok()?;
+    let parent = std::fs::canonicalize(path.parent()?).ok()?;
+    if !is_yaml || parent != dir {
+        return None;
+    }
+    Some(path.file_stem()?.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list, name_of, path};
+
+    #[test]
+    fn test_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = dir.path();
+        assert!(list(cwd).unwrap().is_empty());
+
+        std::fs::create_dir(cwd.join("profiles")).unwrap();
+        std::fs::write(cwd.join("profiles/work.yaml"), "port: 7890\n").unwrap();
+        std::fs::write(cwd.join("profiles/home.yml"), "port: 7891\n").unwrap();
+        std::fs::write(cwd.join("profiles/notes.txt"), "").unwrap();
+        std::fs::write(cwd.join("config.yaml"), "port: 7892\n").unwrap();
+
+        assert_eq!(list(cwd).unwrap(), vec!["home", "work"]);
+        assert_eq!(
+            path(cwd, "home").unwrap(),
+            cwd.join("profiles").join("home.yml")
+        );
+        assert!(path(cwd, "notes").is_err());
+        assert!(path(cwd, "../config").is_err());
+
+        assert_eq!(
+            name_of(cwd, &cwd.join("profiles/work.yaml")).as_deref(),
+            Some("work")
+        );
+        assert!(name_of(cwd, &cwd.join("config.yaml")).is_none());
+    }
+}
@@ -0,0 +1,225 @@
+//! Config reload triggers, and deciding what happens to the connections that
+//! are alive when a new config takes over.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tracing::{info, warn};
+
+use crate::{app::dispatcher::StatisticsManager, config::overrides, Config};
+
+pub type ReloadResult = Result<(), String>;
+pub type ReloadSender = mpsc::Sender<(Config, oneshot::Sender<ReloadResult>)>;
+/// the file the running config was loaded from, follows reloads from files
+/// such as profile switches
+pub type ConfigPath = Arc<RwLock<Option<String>>>;
+
+/// how often the watched config file is checked
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+const OUTBOUND_KEYS: &[&str] = &["proxies", "proxy-groups", "proxy-providers"];
+const RULE_KEYS: &[&str] = &["rules", "rule-providers", "mode"];
+const INBOUND_KEYS: &[&str] = &[
+    "port",
+    "socks-port",
+    "redir-port",
+    "tproxy-port",
+    "mixed-port",
+    "authentication",
+    "bind-address",
+];
+const DNS_KEYS: &[&str] = &["dns", "hosts", "ipv6"];
+
+/// Which parts of the config changed on a reload.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub outbounds: bool,
+    pub rules: bool,
+    pub inbounds: bool,
+    pub dns: bool,
+    pub tun: bool,
+}
+
+impl ConfigDiff {
+    pub fn all() -> Self {
+        Self {
+            outbounds: true,
+            rules: true,
+            inbounds: true,
+            dns: true,
+            tun: true,
+        }
+    }
+
+    /// Compare two snapshots of the raw config, everything is considered
+    /// changed when either is missing.
+    pub fn between(old: Option<&Value>, new: Option<&Value>) -> Self {
+        let (Some(old), Some(new)) = (old, new) else {
+            return Self::all();
+        };
+        let changed = |keys: &[&str]| keys.iter().any(|k| old.get(k) != new.get(k));
+        Self {
+            outbounds: changed(OUTBOUND_KEYS),
+            rules: changed(RULE_KEYS),
+            inbounds: changed(INBOUND_KEYS),
+            dns: changed(DNS_KEYS),
+            tun: changed(&["tun"]),
+        }
+    }
+
+    /// Connections opened under the old config were routed through proxies
+    /// or by rules that may not exist anymore.
+    pub fn drains_connections(&self) -> bool {
+        self.outbounds || self.rules
+    }
+}
+
+/// Close the connections in `ids` that are still alive after `timeout`.
+pub fn drain(
+    statistics_manager: Arc<StatisticsManager>,
+    ids: Vec<uuid::Uuid>,
+    timeout: Duration,
+) {
+    if ids.is_empty() {
+        return;
+    }
+    info!(
+        "draining {} connections from the previous config in {:?}",
+        ids.len(),
+        timeout
+    );
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        for id in ids {
+            statistics_manager.close(id).await;
+        }
+    });
+}
+
+async fn request_reload(reload_tx: &ReloadSender, path: &str) {
+    let (done, wait) = oneshot::channel();
+    if reload_tx
+        .send((Config::File(path.to_owned()), done))
+        .await
+        .is_err()
+    {
+        warn!("could not signal config reload");
+        return;
+    }
+    if let Ok(Err(e)) = wait.await {
+        warn!("config reload from {} failed: {}", path, e);
+    }
+}
+
+/// The latest change to the config file at `path` or its overrides.
+fn modified(path: &str) -> Option<std::time::SystemTime> {
+    let mtime = |p: &Path| std::fs::metadata(p).and_then(|x| x.modified()).ok();
+    let path = Path::new(path);
+    let config = mtime(path)?;
+    // the directory changes when an override is removed
+    let dir = mtime(&overrides::override_dir(path));
+    let files = overrides::override_files(path).unwrap_or_default();
+    Some(
+        files
+            .iter()
+            .map(|x| mtime(x))
+            .chain([dir])
+            .flatten()
+            .fold(config, std::cmp::max),
+    )
+}
+
+/// Reload the config whenever the file at `config_path` or one of its
+/// overrides is modified.
+pub fn watch(config_path: ConfigPath, reload_tx: ReloadSender) {
+    tokio::spawn(async move {
+        let mut watched = None;
+        let mut last = None;
+        let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let Some(path) = config_path.read().await.clone() else {
+                continue;
+            };
+            let now = modified(&path);
+            if watched.as_ref() != Some(&path) {
+                info!("watching {} for changes", PathBuf::from(&path).display());
+                watched = Some(path);
+                last = now;
+                continue;
+            }
+            // editors may remove the file before writing the new one
+            if now.is_none() || now == last {
+                continue;
+            }
+            last = now;
+            info!("{} changed", path);
+            request_reload(&reload_tx, &path).await;
+        }
+    });
+}
+
+/// Reload the config from the file at `config_path` on SIGHUP.
+#[cfg(unix)]
+pub fn reload_on_hangup(config_path: ConfigPath, reload_tx: ReloadSender) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received");
+            match config_path.read().await.clone() {
+                Some(path) => request_reload(&reload_tx, &path).await,
+                None => warn!("not started from a config file, nothing to reload"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::ConfigDiff;
+
+    #[test]
+    fn test_config_diff() {
+        let old = json!({
+            "port": 7890,
+            "proxies": [{"name": "ss01"}],
+            "rules": ["MATCH,ss01"],
+            "dns": {"enable": false},
+            "log-level": "info",
+        });
+
+        assert_eq!(ConfigDiff::between(Some(&old), None), ConfigDiff::all());
+        assert_eq!(
+            ConfigDiff::between(Some(&old), Some(&old)),
+            ConfigDiff::default()
+        );
+
+        let mut new = old.clone();
+        new["log-level"] = json!("debug");
+        new["port"] = json!(7891);
+        let diff = ConfigDiff::between(Some(&old), Some(&new));
+        assert!(diff.inbounds);
+        assert!(!diff.drains_connections());
+
+        new["rules"] = json!(["MATCH,DIRECT"]);
+        let diff = ConfigDiff::between(Some(&old), Some(&new));
+        assert!(diff.rules);
+        assert!(!diff.outbounds);
+        assert!(diff.drains_connections());
+    }
+}
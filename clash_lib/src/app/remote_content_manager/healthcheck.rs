@@ -3,7 +3,7 @@ use std::sync::Arc;
 use tokio::time::Instant;
 use tracing::debug;
 
-use crate::proxy::AnyOutboundHandler;
+use crate::{app::events, proxy::AnyOutboundHandler};
 
 use super::ProxyManager;
 
@@ -63,8 +63,16 @@ impl HealthCheck {
         let task_handle = tokio::spawn(async move {
             let mut ticker =
                 tokio::time::interval(tokio::time::Duration::from_secs(interval));
+            let mut events = events::subscribe();
             loop {
                 tokio::select! {
+                    event = events.recv() => {
+                        if let Ok(events::Event::NetworkChanged { .. }) = event {
+                            debug!("network changed, healthcheck: {}", url);
+                            proxy_manager.check(&proxies, &url, None).await;
+                            inner.write().await.last_check = tokio::time::Instant::now();
+                        }
+                    },
                     _ = ticker.tick() => {
                         debug!("healthcheck ticking: {}, lazy: {}", url, lazy);
                         let now = tokio::time::Instant::now();
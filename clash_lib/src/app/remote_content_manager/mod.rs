@@ -1,7 +1,7 @@
 use std::{
     collections::{HashMap, VecDeque},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU32, Ordering},
         Arc,
     },
     time::Duration,
@@ -11,8 +11,8 @@ use chrono::{DateTime, Utc};
 
 use futures::{stream::FuturesUnordered, StreamExt};
 use hyper::Request;
-use serde::Serialize;
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, time::Instant};
 use tracing::{debug, instrument, trace};
 
 use crate::{
@@ -22,24 +22,78 @@ use crate::{
 
 use self::http_client::LocalConnector;
 
-use super::dns::ThreadSafeDNSResolver;
+use super::{
+    dns::ThreadSafeDNSResolver, events, metrics, profile::ThreadSafeCacheFile,
+};
 
 pub mod healthcheck;
 mod http_client;
 pub mod providers;
 
-#[derive(Clone, Serialize)]
+/// how many health check results are kept per proxy
+const MAX_HISTORY: usize = 1000;
+/// how many of them are reported along with the proxy
+const RECENT_HISTORY: usize = 10;
+/// consecutive failed dials after which a proxy is considered dead until
+/// the next successful health check
+const MAX_DIAL_FAILURES: u32 = 3;
+/// how long a proxy that failed one health check sits out of url-test and
+/// fallback selection once it recovers, doubling with every further failure
+const CHECK_COOLDOWN: Duration = Duration::from_secs(30);
+const MAX_CHECK_COOLDOWN: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DelayHistory {
     time: DateTime<Utc>,
     delay: u16,
     #[serde(rename = "meanDelay")]
     mean_delay: u16,
+    failed: bool,
 }
 
 #[derive(Default)]
 struct ProxyState {
     alive: AtomicBool,
     delay_history: VecDeque<DelayHistory>,
+    dial_failures: AtomicU32,
+    /// failed health checks since the proxy last stayed up through its
+    /// cooldown
+    check_failures: u32,
+    cooldown_until: Option<Instant>,
+}
+
+impl ProxyState {
+    /// Count a health check. A proxy recovering from failed ones cools down
+    /// before it's selected again, and only a check passed after the
+    /// cooldown clears the failures, so a flapping proxy sits out longer
+    /// every time.
+    fn record_check(&mut self, ok: bool, now: Instant) {
+        if !ok {
+            self.check_failures = self.check_failures.saturating_add(1);
+            self.cooldown_until = None;
+            return;
+        }
+        if self.check_failures == 0 {
+            return;
+        }
+        match self.cooldown_until {
+            Some(until) if until <= now => {
+                self.check_failures = 0;
+                self.cooldown_until = None;
+            }
+            Some(_) => {}
+            None => {
+                let cooldown = CHECK_COOLDOWN
+                    .saturating_mul(1 << (self.check_failures - 1).min(16))
+                    .min(MAX_CHECK_COOLDOWN);
+                self.cooldown_until = Some(now + cooldown);
+            }
+        }
+    }
+
+    fn cooling_down(&self, now: Instant) -> bool {
+        self.cooldown_until.is_some_and(|x| x > now)
+    }
 }
 
 /// ProxyManager is the latency registry.
@@ -47,15 +101,21 @@ struct ProxyState {
 pub struct ProxyManager {
     proxy_state: Arc<RwLock<HashMap<String, ProxyState>>>,
     dns_resolver: ThreadSafeDNSResolver,
+    /// keeps the delay history across restarts
+    cache_store: Option<ThreadSafeCacheFile>,
 
     connector_map:
         Arc<RwLock<HashMap<String, hyper_rustls::HttpsConnector<LocalConnector>>>>,
 }
 
 impl ProxyManager {
-    pub fn new(dns_resolver: ThreadSafeDNSResolver) -> Self {
+    pub fn new(
+        dns_resolver: ThreadSafeDNSResolver,
+        cache_store: Option<ThreadSafeCacheFile>,
+    ) -> Self {
         Self {
             dns_resolver,
+            cache_store,
             proxy_state: Arc::new(RwLock::new(HashMap::new())),
             connector_map: Arc::new(RwLock::new(HashMap::new())),
         }
@@ -93,25 +153,114 @@ impl ProxyManager {
             .unwrap_or(true) // if not found, assume it's alive
     }
 
+    /// Whether the proxy is alive and not cooling down from failed health
+    /// checks, i.e. fit for url-test and fallback to pick.
+    pub async fn available(&self, name: &str) -> bool {
+        let cooling_down = self
+            .proxy_state
+            .read()
+            .await
+            .get(name)
+            .is_some_and(|x| x.cooling_down(Instant::now()));
+        !cooling_down && self.alive(name).await
+    }
+
     pub async fn report_alive(&self, name: &str, alive: bool) {
+        self.restore_history(name).await;
         let mut state = self.proxy_state.write().await;
-        let state = state.entry(name.to_owned()).or_default();
-        state.alive.store(alive, Ordering::Relaxed)
+        // unknown proxies are assumed alive, see `alive`
+        let state = state.entry(name.to_owned()).or_insert_with(|| ProxyState {
+            alive: AtomicBool::new(true),
+            ..Default::default()
+        });
+        if state.alive.swap(alive, Ordering::Relaxed) != alive {
+            let proxy = name.to_owned();
+            events::publish(if alive {
+                events::Event::ProxyUp { proxy }
+            } else {
+                events::Event::ProxyDown { proxy }
+            });
+        }
+    }
+
+    /// Count a dial through the proxy, failures being the proxy server's.
+    /// One may still be a fluke, so it's only marked dead after a few in a
+    /// row.
+    pub async fn report_dial(&self, name: &str, ok: bool) {
+        if ok {
+            if let Some(state) = self.proxy_state.read().await.get(name) {
+                state.dial_failures.store(0, Ordering::Relaxed);
+            }
+            return;
+        }
+        self.restore_history(name).await;
+        let failures = {
+            let mut state = self.proxy_state.write().await;
+            let state = state.entry(name.to_owned()).or_insert_with(|| ProxyState {
+                alive: AtomicBool::new(true),
+                ..Default::default()
+            });
+            state.dial_failures.fetch_add(1, Ordering::Relaxed) + 1
+        };
+        if failures >= MAX_DIAL_FAILURES {
+            debug!("{} failed to dial {} times in a row", name, failures);
+            self.report_alive(name, false).await;
+        }
     }
 
+    /// the most recent health check results, oldest first
     pub async fn delay_history(&self, name: &str) -> Vec<DelayHistory> {
+        self.full_delay_history(name, RECENT_HISTORY).await
+    }
+
+    /// up to `limit` of the stored health check results, oldest first
+    pub async fn full_delay_history(
+        &self,
+        name: &str,
+        limit: usize,
+    ) -> Vec<DelayHistory> {
+        self.restore_history(name).await;
         self.proxy_state
             .read()
             .await
             .get(name)
-            .map(|x| x.delay_history.clone())
+            .map(|x| {
+                let skip = x.delay_history.len().saturating_sub(limit);
+                x.delay_history.iter().skip(skip).cloned().collect()
+            })
             .unwrap_or_default()
-            .into()
     }
 
+    /// Load the history saved by a previous run, if it's not known yet.
+    /// Done before anything is known of the proxy, so the history in memory
+    /// is the one stored and trims it along.
+    async fn restore_history(&self, name: &str) {
+        let Some(store) = &self.cache_store else {
+            return;
+        };
+        if self.proxy_state.read().await.contains_key(name) {
+            return;
+        }
+        let history = VecDeque::from(store.get_delay_history(name).await);
+        if history.is_empty() {
+            return;
+        }
+        self.proxy_state
+            .write()
+            .await
+            .entry(name.to_owned())
+            .or_insert_with(|| ProxyState {
+                alive: AtomicBool::new(!history.back().is_some_and(|x| x.failed)),
+                delay_history: history,
+                ..Default::default()
+            });
+    }
+
+    /// the delay of the last health check, `u16::MAX` if the proxy isn't
+    /// available
     pub async fn last_delay(&self, name: &str) -> u16 {
         let max = u16::MAX;
-        if !self.alive(name).await {
+        if !self.available(name).await {
             return max;
         }
         self.delay_history(name)
@@ -228,21 +377,46 @@ impl ProxyManager {
 
         let result = tester.await;
 
+        self.restore_history(&name).await;
         self.report_alive(&name, result.is_ok()).await;
+        metrics::healthcheck(
+            &name,
+            result
+                .as_ref()
+                .ok()
+                .map(|x| Duration::from_millis(x.0 as u64)),
+        );
 
         let ins = DelayHistory {
             time: Utc::now(),
             delay: result.as_ref().map(|x| x.0).unwrap_or(0),
             mean_delay: result.as_ref().map(|x| x.1).unwrap_or(0),
+            failed: result.is_err(),
         };
 
         let mut state = self.proxy_state.write().await;
         let state = state.entry(name.to_owned()).or_default();
 
-        state.delay_history.push_back(ins);
-        if state.delay_history.len() > 10 {
+        if result.is_ok() {
+            state.dial_failures.store(0, Ordering::Relaxed);
+        }
+        state.record_check(result.is_ok(), Instant::now());
+        if let Some(until) = state.cooldown_until {
+            debug!(
+                "{} cooling down for {:?} after {} failed health checks",
+                name,
+                until.saturating_duration_since(Instant::now()),
+                state.check_failures
+            );
+        }
+        let full = state.delay_history.len() >= MAX_HISTORY;
+        if full {
             state.delay_history.pop_front();
         }
+        if let Some(store) = &self.cache_store {
+            store.push_delay_history(&name, &ins, full).await;
+        }
+        state.delay_history.push_back(ins);
 
         result
     }
@@ -253,6 +427,7 @@ mod tests {
     use std::{net::Ipv4Addr, sync::Arc, time::Duration};
 
     use futures::TryFutureExt;
+    use tokio::time::Instant;
 
     use crate::{
         app::{
@@ -272,7 +447,7 @@ mod tests {
         mock_resolver.expect_ipv6().return_const(false);
 
         let manager =
-            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver), None);
 
         let mock_handler = direct::Handler::new();
 
@@ -308,6 +483,54 @@ mod tests {
         assert!(manager.delay_history(PROXY_DIRECT).await.len() == 10);
     }
 
+    #[tokio::test]
+    async fn test_proxy_manager_dial_failures() {
+        let manager = remote_content_manager::ProxyManager::new(
+            Arc::new(MockClashResolver::new()),
+            None,
+        );
+
+        manager.report_dial("a", false).await;
+        manager.report_dial("a", false).await;
+        manager.report_dial("a", true).await;
+        manager.report_dial("a", false).await;
+        manager.report_dial("a", false).await;
+        assert!(manager.alive("a").await);
+        manager.report_dial("a", false).await;
+        assert!(!manager.alive("a").await);
+    }
+
+    #[test]
+    fn test_check_cooldown() {
+        let mut state = super::ProxyState::default();
+        let now = Instant::now();
+        state.record_check(true, now);
+        assert!(!state.cooling_down(now));
+
+        state.record_check(false, now);
+        state.record_check(true, now);
+        assert!(state.cooling_down(now + Duration::from_secs(29)));
+        assert!(!state.cooling_down(now + Duration::from_secs(30)));
+
+        // failed again before staying up through the cooldown
+        state.record_check(false, now);
+        state.record_check(true, now);
+        assert!(state.cooling_down(now + Duration::from_secs(59)));
+        // passing checks during the cooldown don't clear the failures
+        state.record_check(true, now + Duration::from_secs(10));
+        assert_eq!(state.check_failures, 2);
+
+        state.record_check(true, now + Duration::from_secs(60));
+        assert_eq!(state.check_failures, 0);
+        assert!(!state.cooling_down(now + Duration::from_secs(60)));
+
+        for _ in 0..100 {
+            state.record_check(false, now);
+        }
+        state.record_check(true, now);
+        assert!(!state.cooling_down(now + super::MAX_CHECK_COOLDOWN));
+    }
+
     #[tokio::test]
     async fn test_proxy_manager_timeout() {
         let mut mock_resolver = MockClashResolver::new();
@@ -316,7 +539,7 @@ mod tests {
         });
 
         let manager =
-            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver), None);
 
         let mut mock_handler = MockDummyOutboundHandler::new();
         mock_handler
@@ -345,5 +568,10 @@ mod tests {
         assert!(!manager.alive(PROXY_DIRECT).await);
         assert!(manager.last_delay(PROXY_DIRECT).await == u16::MAX);
         assert!(manager.delay_history(PROXY_DIRECT).await.len() == 1);
+
+        let history = manager.full_delay_history(PROXY_DIRECT, 100).await;
+        assert_eq!(history.len(), 1);
+        assert!(history[0].failed);
+        assert_eq!(history[0].delay, 0);
     }
 }
@@ -7,18 +7,24 @@ use std::{
 
 use chrono::{DateTime, Utc};
 use futures::future::BoxFuture;
-use tokio::sync::{Mutex, RwLock};
+use rand::Rng;
+use tokio::{
+    sync::{Mutex, RwLock},
+    task::JoinHandle,
+};
 use tracing::{debug, info, trace, warn};
 
-use crate::common::utils;
+use crate::{app::events, common::utils};
+
+use super::{update_policy, ProviderVehicleType, ThreadSafeProviderVehicle};
 
-use super::{ProviderVehicleType, ThreadSafeProviderVehicle};
+/// how far an update may stray from the interval, so providers sharing an
+/// interval don't all hit their servers at once
+const JITTER: f64 = 0.1;
 
 struct Inner {
     updated_at: SystemTime,
     hash: [u8; 16],
-
-    thread_handle: Option<tokio::task::JoinHandle<()>>,
 }
 
 pub struct Fetcher<U, P> {
@@ -27,8 +33,13 @@ pub struct Fetcher<U, P> {
     vehicle: ThreadSafeProviderVehicle,
     ticker_interval: Duration,
     inner: std::sync::Arc<tokio::sync::RwLock<Inner>>,
+    /// held by an update from fetching to storing the hash, so manual and
+    /// scheduled ones don't race
+    updating: Arc<Mutex<()>>,
     parser: Arc<Mutex<P>>,
     pub on_update: Option<Arc<Mutex<U>>>,
+    /// the scheduled updates, cancelled by `destroy` and on drop
+    updater: std::sync::Mutex<Option<JoinHandle<()>>>,
 }
 
 impl<T, U, P> Fetcher<U, P>
@@ -52,10 +63,11 @@ where
             inner: Arc::new(tokio::sync::RwLock::new(Inner {
                 updated_at: SystemTime::UNIX_EPOCH,
                 hash: [0; 16],
-                thread_handle: None,
             })),
+            updating: Arc::new(Mutex::new(())),
             parser: Arc::new(Mutex::new(parser)),
             on_update: on_update.map(|f| Arc::new(Mutex::new(f))),
+            updater: std::sync::Mutex::new(None),
         }
     }
 
@@ -72,183 +84,210 @@ where
     }
 
     pub async fn initial(&self) -> anyhow::Result<T> {
-        let mut is_local = false;
-        let mut immediately_update = false;
-
         let vehicle_path = self.vehicle.path().to_owned();
 
         let mut inner = self.inner.write().await;
 
-        let content = match metadata(&vehicle_path) {
+        let local = match metadata(&vehicle_path) {
             Ok(meta) => {
-                let content = fs::read(&vehicle_path)?;
-                is_local = true;
                 inner.updated_at = meta.modified()?;
-                immediately_update = SystemTime::now()
-                    .duration_since(inner.updated_at)
-                    .expect("wrong system clock")
-                    > self.interval;
-                content
+                Some(fs::read(&vehicle_path)?)
             }
-            Err(_) => self.vehicle.read().await?,
+            Err(_) => None,
         };
-
-        let parser_guard = self.parser.lock().await;
-
-        let proxies = match (parser_guard)(&content) {
-            Ok(proxies) => proxies,
-            Err(e) => {
-                if !is_local {
-                    return Err(e);
+        // a modification time in the future counts as fresh
+        let immediately_update = local.is_some()
+            && SystemTime::now()
+                .duration_since(inner.updated_at)
+                .unwrap_or_default()
+                > self.interval;
+
+        let parser = self.parser.lock().await;
+        let (parsed, content, fetched) = match local {
+            Some(content) => match (parser)(&content) {
+                Ok(parsed) => (parsed, content, false),
+                Err(e) => {
+                    warn!("{} cache is invalid, fetching: {}", self.name, e);
+                    let content = fetch(&self.vehicle).await?;
+                    ((parser)(&content)?, content, true)
                 }
-                let content = self.vehicle.read().await?;
-                (parser_guard)(&content)?
+            },
+            None => {
+                let content = fetch(&self.vehicle).await?;
+                ((parser)(&content)?, content, true)
             }
         };
+        drop(parser);
 
-        if self.vehicle_type() != ProviderVehicleType::File && !is_local {
-            let p = self.vehicle.path().to_owned();
-            let path = Path::new(p.as_str());
-            let prefix = path.parent().unwrap();
-            if !prefix.exists() {
-                fs::create_dir_all(prefix)?;
-            }
-            fs::write(self.vehicle.path(), &content)?;
+        if fetched {
+            save(&self.vehicle, &content)?;
+            inner.updated_at = SystemTime::now();
         }
-
-        inner.hash = utils::md5(&content)[..16]
-            .try_into()
-            .expect("md5 must be 16 bytes");
+        inner.hash = hash(&content);
 
         drop(inner);
 
         if !self.ticker_interval.is_zero() {
-            self.pull_loop(
-                immediately_update,
-                tokio::time::interval(self.ticker_interval),
-            )
-            .await;
+            self.schedule(immediately_update);
         }
 
-        Ok(proxies)
+        Ok(parsed)
     }
 
-    pub async fn update(&self) -> anyhow::Result<(T, bool)> {
+    /// Fetch the content, returning it parsed if it changed since the last
+    /// time.
+    pub async fn update(&self) -> anyhow::Result<Option<T>> {
         Fetcher::<U, P>::update_inner(
-            self.inner.clone(),
-            self.vehicle.clone(),
-            self.parser.clone(),
+            &self.inner,
+            &self.updating,
+            &self.vehicle,
+            &self.parser,
         )
         .await
     }
 
     async fn update_inner(
-        inner: Arc<RwLock<Inner>>,
-        vehicle: ThreadSafeProviderVehicle,
-        parser: Arc<Mutex<P>>,
-    ) -> anyhow::Result<(T, bool)> {
-        let mut this = inner.write().await;
-        let content = vehicle.read().await?;
-        let proxies = (parser.lock().await)(&content)?;
-
+        inner: &RwLock<Inner>,
+        updating: &Mutex<()>,
+        vehicle: &ThreadSafeProviderVehicle,
+        parser: &Mutex<P>,
+    ) -> anyhow::Result<Option<T>> {
+        let _updating = updating.lock().await;
+        // not holding the lock of `inner` while fetching, which can take a
+        // while
+        let content = fetch(vehicle).await?;
+        let hash = hash(&content);
         let now = SystemTime::now();
-        let hash = utils::md5(&content)[..16]
-            .try_into()
-            .expect("md5 must be 16 bytes");
-
-        if hash == this.hash {
-            this.updated_at = now;
-            filetime::set_file_times(vehicle.path(), now.into(), now.into())?;
-            return Ok((proxies, true));
-        }
 
-        if vehicle.typ() != ProviderVehicleType::File {
-            let p = vehicle.path().to_owned();
-            let path = Path::new(p.as_str());
-            let prefix = path.parent().unwrap();
-            if !prefix.exists() {
-                fs::create_dir_all(prefix)?;
+        if hash == inner.read().await.hash {
+            inner.write().await.updated_at = now;
+            if vehicle.typ() != ProviderVehicleType::File {
+                filetime::set_file_times(vehicle.path(), now.into(), now.into())?;
             }
-
-            fs::write(vehicle.path(), &content)?;
+            return Ok(None);
         }
 
+        let parsed = (parser.lock().await)(&content)?;
+        save(vehicle, &content)?;
+
+        let mut this = inner.write().await;
         this.hash = hash;
         this.updated_at = now;
 
-        Ok((proxies, false))
+        Ok(Some(parsed))
     }
 
-    #[cfg(test)]
-    pub async fn destroy(&mut self) {
-        if let Some(handle) = self.inner.write().await.thread_handle.take() {
-            handle.abort();
-        }
-    }
-
-    async fn pull_loop(
-        &self,
-        immediately_update: bool,
-        mut ticker: tokio::time::Interval,
-    ) {
+    /// Update every interval, give or take the jitter, in the update window
+    /// if there's one.
+    fn schedule(&self, immediately_update: bool) {
         let inner = self.inner.clone();
+        let updating = self.updating.clone();
         let vehicle = self.vehicle.clone();
         let parser = self.parser.clone();
         let on_update = self.on_update.clone();
         let name = self.name.clone();
-        let fire_immediately = immediately_update;
+        let interval = self.ticker_interval;
 
-        let thread_handle = Some(tokio::spawn(async move {
+        let handle = tokio::spawn(async move {
             debug!("fetcher {} started", &name);
+            if !immediately_update {
+                tokio::time::sleep(jittered(interval)).await;
+            }
             loop {
-                let inner = inner.clone();
-                let vehicle = vehicle.clone();
-                let parser = parser.clone();
-                let name = name.clone();
-                let on_update = on_update.clone();
-                let update = || async move {
-                    let (elm, same) =
-                        match Fetcher::<U, P>::update_inner(inner, vehicle, parser)
-                            .await
-                        {
-                            Ok((elm, same)) => (elm, same),
-                            Err(e) => {
-                                warn!("{} update failed: {}", &name, e);
-                                return;
-                            }
-                        };
-
-                    if same {
-                        trace!("fetcher {} no update", &name);
-                        return;
-                    }
-
-                    if let Some(on_update) = on_update {
+                update_policy::wait_for_window().await;
+                match Fetcher::<U, P>::update_inner(
+                    &inner, &updating, &vehicle, &parser,
+                )
+                .await
+                {
+                    Ok(Some(parsed)) => {
                         info!("fetcher {} updated", &name);
-                        on_update.lock().await(elm).await;
+                        if let Some(on_update) = &on_update {
+                            on_update.lock().await(parsed).await;
+                        }
+                    }
+                    Ok(None) => trace!("fetcher {} no update", &name),
+                    Err(e) => {
+                        warn!("{} update failed: {}", &name, e);
+                        events::publish(events::Event::ProviderUpdateFailed {
+                            provider: name.clone(),
+                            error: e.to_string(),
+                        });
                     }
-                };
-
-                if fire_immediately {
-                    update().await;
-                    ticker.tick().await;
-                } else {
-                    ticker.tick().await;
-                    update().await;
                 }
+                tokio::time::sleep(jittered(interval)).await;
             }
-        }));
+        });
 
-        self.inner.write().await.thread_handle = thread_handle;
+        // initialized again, the new schedule replaces the old one
+        if let Some(old) = self.updater.lock().unwrap().replace(handle) {
+            old.abort();
+        }
     }
 }
 
+impl<U, P> Fetcher<U, P> {
+    /// Stop the scheduled updates.
+    pub fn destroy(&self) {
+        if let Some(handle) = self.updater.lock().unwrap().take() {
+            debug!("fetcher {} stopped", self.name);
+            handle.abort();
+        }
+    }
+}
+
+impl<U, P> Drop for Fetcher<U, P> {
+    fn drop(&mut self) {
+        self.destroy();
+    }
+}
+
+/// Read `vehicle`, with a permit if it's remote so only so many fetches
+/// share the uplink.
+async fn fetch(vehicle: &ThreadSafeProviderVehicle) -> std::io::Result<Vec<u8>> {
+    let _permit = match vehicle.typ() {
+        ProviderVehicleType::File => None,
+        _ => update_policy::fetch_permit().await,
+    };
+    vehicle.read().await
+}
+
+fn hash(content: &[u8]) -> [u8; 16] {
+    utils::md5(content)[..16]
+        .try_into()
+        .expect("md5 must be 16 bytes")
+}
+
+/// Cache fetched content at the vehicle's path.
+fn save(vehicle: &ThreadSafeProviderVehicle, content: &[u8]) -> std::io::Result<()> {
+    if vehicle.typ() == ProviderVehicleType::File {
+        return Ok(());
+    }
+    let path = Path::new(vehicle.path());
+    if let Some(prefix) = path.parent() {
+        if !prefix.exists() {
+            fs::create_dir_all(prefix)?;
+        }
+    }
+    fs::write(path, content)
+}
+
+fn jittered(interval: Duration) -> Duration {
+    interval.mul_f64(rand::thread_rng().gen_range(1.0 - JITTER..1.0 + JITTER))
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{path::Path, sync::Arc, time::Duration};
+    use std::{
+        sync::{
+            atomic::{AtomicU8, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
 
     use futures::future::BoxFuture;
-    use tokio::time::sleep;
+    use tokio::{sync::mpsc, time::sleep};
 
     use crate::app::remote_content_manager::providers::{
         MockProviderVehicle, ProviderVehicleType,
@@ -256,31 +295,41 @@ mod tests {
 
     use super::Fetcher;
 
-    #[tokio::test]
-    async fn test_fetcher() {
-        let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-        let tx1 = tx.clone();
+    type Updater = fn(String) -> BoxFuture<'static, ()>;
+
+    /// A vehicle for a file at `path` holding `[1, 2, 3]`, that reads
+    /// `read()`.
+    fn vehicle<F>(path: &std::path::Path, read: F) -> MockProviderVehicle
+    where
+        F: Fn() -> Vec<u8> + Send + 'static,
+    {
+        std::fs::write(path, vec![1, 2, 3]).unwrap();
+        let mut vehicle = MockProviderVehicle::new();
+        vehicle
+            .expect_path()
+            .return_const(path.to_str().unwrap().to_owned());
+        vehicle.expect_read().returning(move || Ok(read()));
+        vehicle.expect_typ().return_const(ProviderVehicleType::File);
+        vehicle
+    }
 
-        let mut mock_vehicle = MockProviderVehicle::new();
-        let mock_file = std::env::temp_dir().join("mock_provider_vehicle");
-        if Path::new(mock_file.to_str().unwrap()).exists() {
-            std::fs::remove_file(&mock_file).unwrap();
+    fn parser(
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+    ) -> impl Fn(&[u8]) -> anyhow::Result<String> + Send + Sync + 'static {
+        move |i: &[u8]| {
+            tx.send(i.to_owned()).unwrap();
+            Ok("parsed".to_owned())
         }
-        std::fs::write(&mock_file, vec![1, 2, 3]).unwrap();
+    }
 
-        mock_vehicle
-            .expect_path()
-            .return_const(mock_file.to_str().unwrap().to_owned());
-        mock_vehicle.expect_read().returning(|| Ok(vec![4, 5, 6]));
-        mock_vehicle
-            .expect_typ()
-            .return_const(ProviderVehicleType::File);
-
-        let parser = move |i: &[u8]| -> anyhow::Result<String> {
-            let copy = i.to_owned();
-            tx1.try_send(copy).unwrap();
-            Ok("parsed".to_owned())
-        };
+    #[tokio::test(start_paused = true)]
+    async fn test_fetcher() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let dir = tempfile::tempdir().unwrap();
+        let reads = AtomicU8::new(4);
+        let vehicle = vehicle(&dir.path().join("provider"), move || {
+            vec![reads.fetch_add(1, Ordering::Relaxed)]
+        });
 
         let updater = move |input: String| -> BoxFuture<'static, ()> {
             Box::pin(async move {
@@ -288,30 +337,76 @@ mod tests {
             })
         };
 
-        let mut f = Fetcher::new(
+        let f = Fetcher::new(
             "test_fetcher".to_string(),
             Duration::from_secs(1),
-            Arc::new(mock_vehicle),
-            parser,
+            Arc::new(vehicle),
+            parser(tx),
             Some(updater),
         );
 
         let _ = f.initial().await;
 
-        sleep(Duration::from_secs_f64(5.5)).await;
-        f.destroy().await;
-
-        drop(tx);
-        drop(f);
+        sleep(Duration::from_secs_f64(3.5)).await;
+        f.destroy();
 
         let mut parsed = vec![];
-
-        while let Some(message) = rx.recv().await {
+        while let Ok(message) = rx.try_recv() {
             parsed.push(message);
         }
-
-        assert!(parsed.len() > 5);
+        assert!(parsed.len() >= 3, "parsed: {:?}", parsed);
         assert_eq!(parsed[0], vec![1, 2, 3]);
-        assert_eq!(parsed[1], vec![4, 5, 6]);
+        assert_eq!(parsed[1], vec![4]);
+        assert_eq!(parsed[2], vec![5]);
+
+        // no more updates once destroyed
+        sleep(Duration::from_secs_f64(1.5)).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unchanged() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let dir = tempfile::tempdir().unwrap();
+        let vehicle = vehicle(&dir.path().join("provider"), || vec![1, 2, 3]);
+
+        let f = Fetcher::new(
+            "test_unchanged".to_string(),
+            Duration::ZERO,
+            Arc::new(vehicle),
+            parser(tx),
+            None::<Updater>,
+        );
+
+        f.initial().await.unwrap();
+        assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3]);
+
+        // not parsed again
+        assert!(f.update().await.unwrap().is_none());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_updates() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let dir = tempfile::tempdir().unwrap();
+        let vehicle = vehicle(&dir.path().join("provider"), || vec![4]);
+
+        let f = Fetcher::new(
+            "test_concurrent_updates".to_string(),
+            Duration::ZERO,
+            Arc::new(vehicle),
+            parser(tx),
+            None::<Updater>,
+        );
+        f.initial().await.unwrap();
+
+        // the one after the other sees the content unchanged
+        let (a, b) = tokio::join!(f.update(), f.update());
+        let updated = [a.unwrap(), b.unwrap()];
+        assert_eq!(updated.iter().filter(|x| x.is_some()).count(), 1);
+        assert_eq!(rx.try_recv().unwrap(), vec![1, 2, 3]);
+        assert_eq!(rx.try_recv().unwrap(), vec![4]);
+        assert!(rx.try_recv().is_err());
     }
 }
@@ -1,15 +1,16 @@
 use super::{ProviderVehicle, ProviderVehicleType};
 use crate::{
-    app::dns::ThreadSafeDNSResolver,
+    app::{dns::ThreadSafeDNSResolver, profile::ThreadSafeCacheFile},
     common::{
         errors::map_io_error,
         http::{new_http_client, HttpClient},
+        integrity::{current_version, record_version, Integrity},
     },
 };
 
 use async_trait::async_trait;
 
-use hyper::{body, Uri};
+use hyper::{body, header, Body, Request, StatusCode, Uri};
 
 use std::io;
 
@@ -19,6 +20,9 @@ pub struct Vehicle {
     pub url: Uri,
     pub path: PathBuf,
     http_client: HttpClient,
+    /// remembers the etags so unchanged content is not downloaded again
+    cache_store: Option<ThreadSafeCacheFile>,
+    integrity: Integrity,
 }
 
 impl Vehicle {
@@ -27,6 +31,7 @@ impl Vehicle {
         path: P,
         cwd: Option<P>,
         dns_resolver: ThreadSafeDNSResolver,
+        cache_store: Option<ThreadSafeCacheFile>,
     ) -> Self {
         let client =
             new_http_client(dns_resolver).expect("failed to create http client");
@@ -37,22 +42,82 @@ impl Vehicle {
                 None => path.as_ref().to_path_buf(),
             },
             http_client: client,
+            cache_store,
+            integrity: Integrity::default(),
         }
     }
+
+    /// check the downloaded content with `integrity` before it's used
+    pub fn with_integrity(mut self, integrity: Integrity) -> Self {
+        self.integrity = integrity;
+        self
+    }
 }
 
 #[async_trait]
 impl ProviderVehicle for Vehicle {
     async fn read(&self) -> std::io::Result<Vec<u8>> {
-        body::to_bytes(
-            self.http_client
-                .get(self.url.clone())
-                .await
-                .map_err(|x| io::Error::new(io::ErrorKind::Other, x.to_string()))?,
-        )
-        .await
-        .map_err(map_io_error)
-        .map(|x| x.into_iter().collect::<Vec<u8>>())
+        let url = self.url.to_string();
+        // the etag is only useful if the content is still around
+        let etag = match &self.cache_store {
+            Some(store) if self.path.exists() => store.get_etag(&url).await,
+            _ => None,
+        };
+
+        let mut req = Request::get(self.url.clone());
+        if let Some(etag) = &etag {
+            req = req.header(header::IF_NONE_MATCH, etag);
+        }
+        let req = req
+            .body(Body::empty())
+            .map_err(|x| io::Error::new(io::ErrorKind::InvalidInput, x))?;
+        let res = self
+            .http_client
+            .request(req)
+            .await
+            .map_err(|x| io::Error::new(io::ErrorKind::Other, x.to_string()))?;
+
+        if etag.is_some() && res.status() == StatusCode::NOT_MODIFIED {
+            return tokio::fs::read(&self.path).await;
+        }
+
+        let new_etag = res
+            .headers()
+            .get(header::ETAG)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_owned());
+        let content = body::to_bytes(res.into_body())
+            .await
+            .map_err(map_io_error)?;
+
+        if !self.integrity.is_empty() {
+            let verified = async {
+                let signature = self
+                    .integrity
+                    .fetch_signature(&url, &self.http_client)
+                    .await?;
+                let current = current_version(&self.path);
+                if let Some(version) =
+                    self.integrity
+                        .verify(&content, signature.as_deref(), current)?
+                {
+                    record_version(&self.path, version)?;
+                }
+                Ok::<_, crate::Error>(())
+            }
+            .await;
+            if let Err(e) = verified {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} failed verification: {}", url, e),
+                ));
+            }
+        }
+
+        if let (Some(store), Some(etag)) = (&self.cache_store, new_etag) {
+            store.set_etag(&url, &etag).await;
+        }
+        Ok(content.to_vec())
     }
 
     fn path(&self) -> &str {
@@ -80,7 +145,13 @@ mod tests {
             .unwrap();
         let p = std::env::temp_dir().join("test_http_vehicle");
         let r = Arc::new(EnhancedResolver::new_default().await);
-        let v = super::Vehicle::new(u, p, None, r.clone() as ThreadSafeDNSResolver);
+        let v = super::Vehicle::new(
+            u,
+            p,
+            None,
+            r.clone() as ThreadSafeDNSResolver,
+            None,
+        );
 
         let data = v.read().await.unwrap();
         assert_eq!(str::from_utf8(&data).unwrap(), "HTTPBIN is awesome");
@@ -13,6 +13,7 @@ pub mod file_vehicle;
 pub mod http_vehicle;
 pub mod proxy_provider;
 pub mod rule_provider;
+pub mod update_policy;
 
 #[cfg(test)]
 use mockall::automock;
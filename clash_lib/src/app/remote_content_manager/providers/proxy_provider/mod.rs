@@ -2,6 +2,8 @@ pub mod plain_provider;
 
 pub mod proxy_set_provider;
 
+pub mod subscription;
+
 pub use plain_provider::PlainProvider;
 pub use proxy_set_provider::ProxySetProvider;
 
@@ -11,7 +13,8 @@ use async_trait::async_trait;
 use tokio::sync::RwLock;
 
 use crate::{
-    app::remote_content_manager::providers::Provider, proxy::AnyOutboundHandler,
+    app::remote_content_manager::providers::Provider,
+    config::internal::proxy::OutboundGroupProtocol, proxy::AnyOutboundHandler,
 };
 
 pub type ThreadSafeProxyProvider = Arc<RwLock<dyn ProxyProvider + Send + Sync>>;
@@ -22,4 +25,8 @@ pub trait ProxyProvider: Provider {
     async fn touch(&self);
     /// this is a blocking call, you may want to spawn a new task to run this
     async fn healthcheck(&self);
+    /// the `proxy-groups` of the payload, if it declares any
+    async fn groups(&self) -> Vec<OutboundGroupProtocol> {
+        vec![]
+    }
 }
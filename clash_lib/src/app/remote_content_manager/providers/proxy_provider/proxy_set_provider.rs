@@ -5,9 +5,9 @@ use erased_serde::Serialize as ESerialize;
 use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
-use tracing::debug;
+use tracing::{debug, warn};
 
-use super::ProxyProvider;
+use super::{subscription, ProxyProvider};
 use crate::{
     app::remote_content_manager::{
         healthcheck::HealthCheck,
@@ -17,8 +17,8 @@ use crate::{
         },
     },
     common::errors::map_io_error,
-    config::internal::proxy::OutboundProxyProtocol,
-    proxy::{direct, reject, AnyOutboundHandler},
+    config::internal::proxy::{OutboundGroupProtocol, OutboundProxyProtocol},
+    proxy::{registry, AnyOutboundHandler},
     Error,
 };
 
@@ -26,22 +26,26 @@ use crate::{
 struct ProviderScheme {
     #[serde(rename = "proxies")]
     proxies: Option<Vec<HashMap<String, Value>>>,
+    #[serde(rename = "proxy-groups", default)]
+    proxy_groups: Vec<HashMap<String, Value>>,
+}
+
+/// what a payload is parsed into
+struct Payload {
+    proxies: Vec<AnyOutboundHandler>,
+    groups: Vec<OutboundGroupProtocol>,
 }
 
 struct Inner {
     proxies: Vec<AnyOutboundHandler>,
+    groups: Vec<OutboundGroupProtocol>,
     hc: Arc<HealthCheck>,
 }
 
-type ProxyUpdater = Box<
-    dyn Fn(Vec<AnyOutboundHandler>) -> BoxFuture<'static, ()>
-        + Send
-        + Sync
-        + 'static,
->;
-type ProxyParser = Box<
-    dyn Fn(&[u8]) -> anyhow::Result<Vec<AnyOutboundHandler>> + Send + Sync + 'static,
->;
+type ProxyUpdater =
+    Box<dyn Fn(Payload) -> BoxFuture<'static, ()> + Send + Sync + 'static>;
+type ProxyParser =
+    Box<dyn Fn(&[u8]) -> anyhow::Result<Payload> + Send + Sync + 'static>;
 
 pub struct ProxySetProvider {
     fetcher: Fetcher<ProxyUpdater, ProxyParser>,
@@ -67,70 +71,76 @@ impl ProxySetProvider {
 
         let inner = Arc::new(tokio::sync::RwLock::new(Inner {
             proxies: vec![],
+            groups: vec![],
             hc: hc.clone(),
         }));
 
         let inner_clone = inner.clone();
 
         let n = name.clone();
-        let updater: ProxyUpdater = Box::new(
-            move |input: Vec<AnyOutboundHandler>| -> BoxFuture<'static, ()> {
+        let updater: ProxyUpdater =
+            Box::new(move |input: Payload| -> BoxFuture<'static, ()> {
                 let hc = hc.clone();
                 let n = n.clone();
                 let inner: Arc<tokio::sync::RwLock<Inner>> = inner_clone.clone();
                 Box::pin(async move {
                     let mut inner = inner.write().await;
-                    debug!("updating {} proxies for: {}", n, input.len());
-                    inner.proxies.clone_from(&input);
-                    hc.update(input).await;
+                    debug!("updating {} proxies for: {}", n, input.proxies.len());
+                    inner.proxies.clone_from(&input.proxies);
+                    inner.groups = input.groups;
+                    hc.update(input.proxies).await;
                     // check once after update
                     tokio::spawn(async move {
                         hc.check().await;
                     });
                 })
-            },
-        );
+            });
 
         let n = name.clone();
-        let parser: ProxyParser = Box::new(
-            move |input: &[u8]| -> anyhow::Result<Vec<AnyOutboundHandler>> {
-                let scheme: ProviderScheme =
-                    serde_yaml::from_slice(input).map_err(|x| {
-                        Error::InvalidConfig(format!(
-                            "proxy provider parse error {}: {}",
-                            n, x
-                        ))
-                    })?;
-                let proxies = scheme.proxies;
+        let parser: ProxyParser =
+            Box::new(move |input: &[u8]| -> anyhow::Result<Payload> {
+                let (proxies, groups) =
+                    match serde_yaml::from_slice::<ProviderScheme>(input) {
+                        Ok(scheme) => (scheme.proxies, scheme.proxy_groups),
+                        Err(e) => match subscription::share_links(input) {
+                            Some(links) => {
+                                let converted = subscription::convert(&links);
+                                for e in converted.errors {
+                                    warn!("{}: skipping share link {}", n, e);
+                                }
+                                (Some(converted.proxies), vec![])
+                            }
+                            None => {
+                                return Err(Error::InvalidConfig(format!(
+                                    "proxy provider parse error {}: {}",
+                                    n, e
+                                ))
+                                .into())
+                            }
+                        },
+                    };
                 if let Some(proxies) = proxies {
                     let proxies = proxies
                         .into_iter()
                         .filter_map(|x| OutboundProxyProtocol::try_from(x).ok())
-                        .map(|x| match x {
-                            OutboundProxyProtocol::Direct => {
-                                Ok(direct::Handler::new())
-                            }
-                            OutboundProxyProtocol::Reject => {
-                                Ok(reject::Handler::new())
-                            }
-                            #[cfg(feature = "shadowsocks")]
-                            OutboundProxyProtocol::Ss(s) => s.try_into(),
-                            OutboundProxyProtocol::Socks5(s) => s.try_into(),
-                            OutboundProxyProtocol::Trojan(tr) => tr.try_into(),
-                            OutboundProxyProtocol::Vmess(vm) => vm.try_into(),
-                            OutboundProxyProtocol::Wireguard(wg) => wg.try_into(),
-                            OutboundProxyProtocol::Tor(tor) => tor.try_into(),
-                            #[cfg(feature = "tuic")]
-                            OutboundProxyProtocol::Tuic(tuic) => tuic.try_into(),
+                        .map(|x| registry::create(&x))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let groups = groups
+                        .into_iter()
+                        .filter_map(|x| {
+                            OutboundGroupProtocol::try_from(x)
+                                .inspect_err(|e| {
+                                    warn!("{}: skipping proxy group {}", n, e)
+                                })
+                                .ok()
                         })
-                        .collect::<Result<Vec<_>, _>>();
-                    Ok(proxies?)
+                        .collect();
+                    Ok(Payload { proxies, groups })
                 } else {
                     Err(Error::InvalidConfig(format!("{}: proxies is empty", n))
                         .into())
                 }
-            },
-        );
+            });
 
         let fetcher = Fetcher::new(name, interval, vehicle, parser, Some(updater));
         Ok(Self { fetcher, inner })
@@ -153,7 +163,11 @@ impl Provider for ProxySetProvider {
 
     async fn initialize(&self) -> std::io::Result<()> {
         let ele = self.fetcher.initial().await.map_err(map_io_error)?;
-        debug!("{} initialized with {} proxies", self.name(), ele.len());
+        debug!(
+            "{} initialized with {} proxies",
+            self.name(),
+            ele.proxies.len()
+        );
         if let Some(updater) = self.fetcher.on_update.as_ref() {
             updater.lock().await(ele).await;
         }
@@ -161,18 +175,14 @@ impl Provider for ProxySetProvider {
     }
 
     async fn update(&self) -> std::io::Result<()> {
-        let (ele, same) = self.fetcher.update().await.map_err(map_io_error)?;
-        debug!(
-            "{} updated with {} proxies, same? {}",
-            self.name(),
-            ele.len(),
-            same
-        );
-        if !same {
-            if let Some(updater) = self.fetcher.on_update.as_ref() {
-                let f = updater.lock().await;
-                f(ele).await;
-            }
+        let Some(ele) = self.fetcher.update().await.map_err(map_io_error)? else {
+            debug!("{} not changed", self.name());
+            return Ok(());
+        };
+        debug!("{} updated with {} proxies", self.name(), ele.proxies.len());
+        if let Some(updater) = self.fetcher.on_update.as_ref() {
+            let f = updater.lock().await;
+            f(ele).await;
         }
         Ok(())
     }
@@ -209,6 +219,10 @@ impl ProxyProvider for ProxySetProvider {
     async fn healthcheck(&self) {
         self.inner.read().await.hc.check().await;
     }
+
+    async fn groups(&self) -> Vec<OutboundGroupProtocol> {
+        self.inner.read().await.groups.clone()
+    }
 }
 
 #[cfg(test)]
@@ -260,7 +274,7 @@ proxies:
 
         let mock_resolver = MockClashResolver::new();
 
-        let latency_manager = ProxyManager::new(Arc::new(mock_resolver));
+        let latency_manager = ProxyManager::new(Arc::new(mock_resolver), None);
         let hc = HealthCheck::new(
             vec![],
             "http://www.google.com".to_owned(),
@@ -0,0 +1,504 @@
+//! Subscriptions of share links, an `ss://`, `vmess://` or `trojan://` URI a
+//! line, the whole often base64 encoded. They're turned into clash-format
+//! proxies for providers and for the `/convert` API.
+
+use std::collections::{HashMap, HashSet};
+
+use base64::Engine;
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use url::{Host, Url};
+
+use crate::{config::internal::proxy::OutboundProxyProtocol, Error};
+
+pub type Proxy = HashMap<String, Value>;
+
+#[derive(Default, Debug)]
+pub struct Converted {
+    pub proxies: Vec<Proxy>,
+    /// why the other links couldn't be converted
+    pub errors: Vec<String>,
+}
+
+/// The share links of a subscription, `None` if `input` isn't one.
+pub fn share_links(input: &[u8]) -> Option<Vec<String>> {
+    let text = std::str::from_utf8(input).ok()?.trim();
+    let text = if text.contains("://") {
+        text.to_owned()
+    } else {
+        String::from_utf8(decode_base64(text)?).ok()?
+    };
+    let links = text
+        .lines()
+        .map(str::trim)
+        .filter(|x| x.contains("://"))
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+    (!links.is_empty()).then_some(links)
+}
+
+/// Convert the share links, each proxy being checked as a provider's would
+/// and named uniquely.
+pub fn convert(links: &[String]) -> Converted {
+    let mut converted = Converted::default();
+    let mut names = HashSet::new();
+    for link in links {
+        let proxy = parse_link(link).and_then(|x| {
+            OutboundProxyProtocol::try_from(x.clone())?;
+            Ok(x)
+        });
+        match proxy {
+            Ok(mut proxy) => {
+                let name = proxy["name"].as_str().unwrap_or_default().to_owned();
+                let mut unique = name.clone();
+                for i in 2.. {
+                    if names.insert(unique.clone()) {
+                        break;
+                    }
+                    unique = format!("{} {}", name, i);
+                }
+                proxy.insert("name".to_owned(), unique.into());
+                converted.proxies.push(proxy);
+            }
+            Err(e) => converted.errors.push(format!("{}: {}", redact(link), e)),
+        }
+    }
+    converted
+}
+
+/// The clash-format proxy of a share link.
+pub fn parse_link(link: &str) -> Result<Proxy, Error> {
+    match link.split_once("://") {
+        Some(("ss", rest)) => parse_ss(rest),
+        Some(("vmess", rest)) => parse_vmess(rest),
+        Some(("trojan", _)) => parse_trojan(link),
+        Some((scheme, _)) => Err(Error::InvalidConfig(format!(
+            "unsupported share link scheme: {}",
+            scheme
+        ))),
+        None => Err(Error::InvalidConfig("not a share link".to_owned())),
+    }
+}
+
+/// the scheme of `link` only, not to log credentials
+fn redact(link: &str) -> String {
+    match link.split_once("://") {
+        Some((scheme, _)) => format!("{}://...", scheme),
+        None => "...".to_owned(),
+    }
+}
+
+/// SIP002 `ss://base64(method:password)@host:port/?plugin=...#name`, the
+/// legacy `ss://base64(method:password@host:port)#name` as well.
+fn parse_ss(rest: &str) -> Result<Proxy, Error> {
+    let invalid = || Error::InvalidConfig("invalid ss link".to_owned());
+
+    let (rest, name) = split_name(rest);
+    let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let rest = rest.trim_end_matches('/');
+    let (userinfo, host_port) = match rest.rsplit_once('@') {
+        Some((userinfo, host_port)) => {
+            let userinfo = match decode_base64(userinfo) {
+                Some(x) => String::from_utf8(x).map_err(|_| invalid())?,
+                // the methods of shadowsocks 2022 aren't encoded
+                None => percent_decode(userinfo),
+            };
+            (userinfo, host_port.to_owned())
+        }
+        None => {
+            let decoded = decode_base64(rest)
+                .and_then(|x| String::from_utf8(x).ok())
+                .ok_or_else(invalid)?;
+            let (userinfo, host_port) =
+                decoded.rsplit_once('@').ok_or_else(invalid)?;
+            (userinfo.to_owned(), host_port.to_owned())
+        }
+    };
+    let (cipher, password) = userinfo.split_once(':').ok_or_else(invalid)?;
+    let (server, port) = split_host_port(&host_port).ok_or_else(invalid)?;
+
+    let mut proxy = new_proxy("ss", &name, &server, port);
+    proxy.insert("cipher".to_owned(), cipher.into());
+    proxy.insert("password".to_owned(), password.into());
+    proxy.insert("udp".to_owned(), true.into());
+
+    let plugin = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == "plugin")
+        .map(|(_, v)| v.into_owned());
+    if let Some(plugin) = plugin {
+        let (plugin, opts) = parse_ss_plugin(&plugin)?;
+        proxy.insert("plugin".to_owned(), plugin.into());
+        proxy.insert("plugin-opts".to_owned(), Value::Mapping(opts));
+    }
+    Ok(proxy)
+}
+
+/// `obfs-local;obfs=http;obfs-host=...` or `v2ray-plugin;path=...;tls`
+fn parse_ss_plugin(plugin: &str) -> Result<(&'static str, Mapping), Error> {
+    let mut parts = plugin.split(';');
+    let kind = parts.next().unwrap_or_default();
+    let args = parts
+        .map(|x| x.split_once('=').unwrap_or((x, "")))
+        .collect::<HashMap<_, _>>();
+
+    let mut opts = Mapping::new();
+    match kind {
+        "obfs-local" | "simple-obfs" => {
+            let mode = args.get("obfs").copied().unwrap_or("http");
+            opts.insert("mode".into(), mode.into());
+            if let Some(host) = args.get("obfs-host") {
+                opts.insert("host".into(), (*host).into());
+            }
+            Ok(("obfs", opts))
+        }
+        "v2ray-plugin" => {
+            let mode = args.get("mode").copied().unwrap_or("websocket");
+            opts.insert("mode".into(), mode.into());
+            if let Some(host) = args.get("host") {
+                opts.insert("host".into(), (*host).into());
+            }
+            let path = args.get("path").copied().unwrap_or("/");
+            opts.insert("path".into(), path.into());
+            opts.insert("tls".into(), args.contains_key("tls").into());
+            opts.insert("mux".into(), args.contains_key("mux").into());
+            Ok(("v2ray-plugin", opts))
+        }
+        _ => Err(Error::InvalidConfig(format!(
+            "unsupported ss plugin: {}",
+            kind
+        ))),
+    }
+}
+
+/// the JSON of v2rayN links, ports and ids being numbers or strings
+#[derive(Deserialize)]
+struct VmessLink {
+    #[serde(default)]
+    ps: String,
+    add: String,
+    port: serde_json::Value,
+    id: String,
+    #[serde(default)]
+    aid: serde_json::Value,
+    scy: Option<String>,
+    net: Option<String>,
+    host: Option<String>,
+    path: Option<String>,
+    tls: Option<String>,
+    sni: Option<String>,
+    alpn: Option<String>,
+}
+
+/// `vmess://base64(json)`
+fn parse_vmess(rest: &str) -> Result<Proxy, Error> {
+    let invalid =
+        |e: String| Error::InvalidConfig(format!("invalid vmess link: {}", e));
+
+    let json =
+        decode_base64(rest).ok_or_else(|| invalid("not base64".to_owned()))?;
+    let link: VmessLink =
+        serde_json::from_slice(&json).map_err(|e| invalid(e.to_string()))?;
+    let port = json_number(&link.port).ok_or_else(|| invalid("port".to_owned()))?;
+    let alter_id = json_number(&link.aid).unwrap_or(0);
+
+    let mut proxy = new_proxy("vmess", &link.ps, &link.add, port);
+    proxy.insert("uuid".to_owned(), link.id.into());
+    proxy.insert("alterId".to_owned(), alter_id.into());
+    let cipher = link.scy.filter(|x| !x.is_empty());
+    proxy.insert(
+        "cipher".to_owned(),
+        cipher.unwrap_or_else(|| "auto".to_owned()).into(),
+    );
+    proxy.insert("udp".to_owned(), true.into());
+    if link.tls.as_deref() == Some("tls") {
+        proxy.insert("tls".to_owned(), true.into());
+        if let Some(sni) = link.sni.filter(|x| !x.is_empty()) {
+            proxy.insert("servername".to_owned(), sni.into());
+        }
+        if let Some(alpn) = link.alpn.filter(|x| !x.is_empty()) {
+            proxy.insert("alpn".to_owned(), split_list(&alpn));
+        }
+    }
+
+    let host = link.host.filter(|x| !x.is_empty());
+    let path = link.path.filter(|x| !x.is_empty());
+    match link.net.as_deref().unwrap_or("tcp") {
+        "tcp" | "" => {}
+        "ws" => {
+            proxy.insert("network".to_owned(), "ws".into());
+            proxy.insert("ws-opts".to_owned(), ws_opts(path, host));
+        }
+        "h2" => {
+            let mut opts = Mapping::new();
+            if let Some(host) = host {
+                opts.insert("host".into(), split_list(&host));
+            }
+            opts.insert(
+                "path".into(),
+                path.unwrap_or_else(|| "/".to_owned()).into(),
+            );
+            proxy.insert("network".to_owned(), "h2".into());
+            proxy.insert("h2-opts".to_owned(), Value::Mapping(opts));
+        }
+        "grpc" => {
+            proxy.insert("network".to_owned(), "grpc".into());
+            proxy.insert("grpc-opts".to_owned(), grpc_opts(path));
+        }
+        net => return Err(invalid(format!("unsupported network {}", net))),
+    }
+    Ok(proxy)
+}
+
+/// `trojan://password@host:port?sni=...&type=ws&path=...#name`
+fn parse_trojan(link: &str) -> Result<Proxy, Error> {
+    let invalid =
+        |e: &str| Error::InvalidConfig(format!("invalid trojan link: {}", e));
+
+    let url = Url::parse(link).map_err(|e| invalid(&e.to_string()))?;
+    let server = match url.host() {
+        Some(Host::Domain(x)) => x.to_owned(),
+        Some(Host::Ipv4(x)) => x.to_string(),
+        Some(Host::Ipv6(x)) => x.to_string(),
+        None => return Err(invalid("no host")),
+    };
+    let query = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+    let name = percent_decode(url.fragment().unwrap_or_default());
+
+    let mut proxy = new_proxy("trojan", &name, &server, url.port().unwrap_or(443));
+    proxy.insert("password".to_owned(), percent_decode(url.username()).into());
+    proxy.insert("udp".to_owned(), true.into());
+    if let Some(sni) = query.get("sni").or(query.get("peer")) {
+        proxy.insert("sni".to_owned(), sni.as_str().into());
+    }
+    if let Some(alpn) = query.get("alpn") {
+        proxy.insert("alpn".to_owned(), split_list(alpn));
+    }
+    if query
+        .get("allowInsecure")
+        .is_some_and(|x| x == "1" || x == "true")
+    {
+        proxy.insert("skip-cert-verify".to_owned(), true.into());
+    }
+
+    match query.get("type").map(String::as_str).unwrap_or("tcp") {
+        "tcp" => {}
+        "ws" => {
+            proxy.insert("network".to_owned(), "ws".into());
+            proxy.insert(
+                "ws-opts".to_owned(),
+                ws_opts(query.get("path").cloned(), query.get("host").cloned()),
+            );
+        }
+        "grpc" => {
+            proxy.insert("network".to_owned(), "grpc".into());
+            proxy.insert(
+                "grpc-opts".to_owned(),
+                grpc_opts(query.get("serviceName").cloned()),
+            );
+        }
+        net => return Err(invalid(&format!("unsupported network {}", net))),
+    }
+    Ok(proxy)
+}
+
+fn new_proxy(kind: &str, name: &str, server: &str, port: u16) -> Proxy {
+    let name = if name.is_empty() {
+        format!("{}:{}", server, port)
+    } else {
+        name.to_owned()
+    };
+    HashMap::from([
+        ("name".to_owned(), name.into()),
+        ("type".to_owned(), kind.into()),
+        ("server".to_owned(), server.into()),
+        ("port".to_owned(), port.into()),
+    ])
+}
+
+fn ws_opts(path: Option<String>, host: Option<String>) -> Value {
+    let mut opts = Mapping::new();
+    opts.insert("path".into(), path.unwrap_or_else(|| "/".to_owned()).into());
+    if let Some(host) = host.filter(|x| !x.is_empty()) {
+        let mut headers = Mapping::new();
+        headers.insert("Host".into(), host.into());
+        opts.insert("headers".into(), Value::Mapping(headers));
+    }
+    Value::Mapping(opts)
+}
+
+fn grpc_opts(service_name: Option<String>) -> Value {
+    let mut opts = Mapping::new();
+    if let Some(name) = service_name.filter(|x| !x.is_empty()) {
+        opts.insert("grpc-service-name".into(), name.into());
+    }
+    Value::Mapping(opts)
+}
+
+/// `a,b` as a list
+fn split_list(s: &str) -> Value {
+    s.split(',')
+        .map(|x| Value::from(x.trim()))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+fn json_number(v: &serde_json::Value) -> Option<u16> {
+    match v {
+        serde_json::Value::Number(x) => x.as_u64()?.try_into().ok(),
+        serde_json::Value::String(x) => x.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// `rest#name`, the name percent-decoded
+fn split_name(s: &str) -> (&str, String) {
+    match s.split_once('#') {
+        Some((rest, name)) => (rest, percent_decode(name)),
+        None => (s, String::new()),
+    }
+}
+
+/// `host:port` or `[v6]:port`
+fn split_host_port(s: &str) -> Option<(String, u16)> {
+    let (host, port) = s.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Some((host.to_owned(), port.parse().ok()?))
+}
+
+/// base64 with either alphabet, padded or not
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s
+        .chars()
+        .filter(|x| !x.is_whitespace())
+        .map(|x| match x {
+            '-' => '+',
+            '_' => '/',
+            x => x,
+        })
+        .collect::<String>();
+    base64::engine::general_purpose::STANDARD_NO_PAD
+        .decode(s.trim_end_matches('='))
+        .ok()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // from_str_radix takes a sign too, `%+f` isn't an escape
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|x| x.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(x)) => {
+                out.push(x);
+                i += 3;
+            }
+            (x, _) => {
+                out.push(x);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+
+    use super::{convert, parse_link, share_links};
+
+    fn b64(s: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(s)
+    }
+
+    #[test]
+    fn test_ss() {
+        let link = format!(
+            "ss://{}@10.0.0.1:8388/?plugin={}#my%20ss",
+            b64("aes-256-gcm:pass:word").trim_end_matches('='),
+            "obfs-local%3Bobfs%3Dhttp%3Bobfs-host%3Dexample.com"
+        );
+        let proxy = parse_link(&link).unwrap();
+        assert_eq!(proxy["name"], "my ss");
+        assert_eq!(proxy["server"], "10.0.0.1");
+        assert_eq!(proxy["port"], 8388);
+        assert_eq!(proxy["cipher"], "aes-256-gcm");
+        assert_eq!(proxy["password"], "pass:word");
+        assert_eq!(proxy["plugin"], "obfs");
+        assert_eq!(proxy["plugin-opts"]["host"], "example.com");
+
+        let legacy =
+            format!("ss://{}#old", b64("chacha20-ietf-poly1305:pw@[::1]:443"));
+        let proxy = parse_link(&legacy).unwrap();
+        assert_eq!(proxy["server"], "::1");
+        assert_eq!(proxy["password"], "pw");
+
+        let ss2022 = "ss://2022-blake3-aes-128-gcm:a%2Bb%3D@example.com:443";
+        let proxy = parse_link(ss2022).unwrap();
+        assert_eq!(proxy["password"], "a+b=");
+        assert_eq!(proxy["name"], "example.com:443");
+    }
+
+    #[test]
+    fn test_vmess() {
+        let json = r#"{"v":"2","ps":"hk","add":"hk.example.com","port":"443",
+            "id":"b831381d-6324-4d53-ad4f-8cda48b30811","aid":0,"net":"ws",
+            "host":"cdn.example.com","path":"/ray","tls":"tls"}"#;
+        let proxy = parse_link(&format!("vmess://{}", b64(json))).unwrap();
+        assert_eq!(proxy["type"], "vmess");
+        assert_eq!(proxy["port"], 443);
+        assert_eq!(proxy["cipher"], "auto");
+        assert_eq!(proxy["tls"], true);
+        assert_eq!(proxy["network"], "ws");
+        assert_eq!(proxy["ws-opts"]["path"], "/ray");
+        assert_eq!(proxy["ws-opts"]["headers"]["Host"], "cdn.example.com");
+    }
+
+    #[test]
+    fn test_trojan() {
+        let proxy = parse_link(
+            "trojan://p%40ss@[2001:db8::1]:8443?sni=example.com&type=grpc&\
+             serviceName=svc&allowInsecure=1#tj",
+        )
+        .unwrap();
+        assert_eq!(proxy["name"], "tj");
+        assert_eq!(proxy["server"], "2001:db8::1");
+        assert_eq!(proxy["port"], 8443);
+        assert_eq!(proxy["password"], "p@ss");
+        assert_eq!(proxy["sni"], "example.com");
+        assert_eq!(proxy["skip-cert-verify"], true);
+        assert_eq!(proxy["grpc-opts"]["grpc-service-name"], "svc");
+    }
+
+    #[test]
+    fn test_subscription() {
+        let text = "trojan://pw@a.example.com:443#same\n\
+                    trojan://pw@b.example.com:443#same\n\
+                    hysteria://x@c.example.com:443\n";
+        let links = share_links(b64(text).as_bytes()).unwrap();
+        assert_eq!(links, share_links(text.as_bytes()).unwrap());
+        assert_eq!(links.len(), 3);
+
+        let converted = convert(&links);
+        assert_eq!(converted.proxies.len(), 2);
+        assert_eq!(converted.proxies[0]["name"], "same");
+        assert_eq!(converted.proxies[1]["name"], "same 2");
+        assert_eq!(converted.errors.len(), 1);
+        assert!(converted.errors[0].starts_with("hysteria://..."));
+
+        assert!(share_links(b"proxies: []").is_none());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(super::percent_decode("a%20b%2Fc"), "a b/c");
+        // not escapes, left as they are
+        assert_eq!(super::percent_decode("%+f%-1%zz%4"), "%+f%-1%zz%4");
+        assert_eq!(super::percent_decode("100%"), "100%");
+    }
+}
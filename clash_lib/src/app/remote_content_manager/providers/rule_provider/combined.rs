@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use erased_serde::Serialize as ESerialize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::remote_content_manager::providers::{
+        Provider, ProviderType, ProviderVehicleType,
+    },
+    session::Session,
+};
+
+use super::{
+    provider::{RuleProvider, ThreadSafeRuleProvider},
+    RuleSetBehavior,
+};
+
+/// How the sets of a `combine` provider make up its own.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleSetOperator {
+    /// in any of the sets
+    Union,
+    /// in all of them
+    Intersection,
+    /// in the first and none of the others
+    Difference,
+}
+
+impl RuleSetOperator {
+    /// Whether a connection is in the combined set given whether it's in
+    /// each set, only as many of them are searched as needed.
+    pub fn matches(&self, mut sets: impl Iterator<Item = bool>) -> bool {
+        match self {
+            RuleSetOperator::Union => sets.any(|x| x),
+            RuleSetOperator::Intersection => sets.all(|x| x),
+            RuleSetOperator::Difference => {
+                sets.next().unwrap_or_default() && !sets.any(|x| x)
+            }
+        }
+    }
+}
+
+/// A set made of other rule providers, e.g. the difference of an upstream
+/// list and one's own exceptions. It has no content of its own, the
+/// providers it's made of are fetched and updated as usual.
+///
+/// Nothing is compiled: a search asks each of them in turn, so an update
+/// of one shows right away.
+pub struct CombinedRuleProvider {
+    name: String,
+    operator: RuleSetOperator,
+    providers: Vec<ThreadSafeRuleProvider>,
+}
+
+impl CombinedRuleProvider {
+    pub fn new(
+        name: String,
+        operator: RuleSetOperator,
+        providers: Vec<ThreadSafeRuleProvider>,
+    ) -> Self {
+        Self {
+            name,
+            operator,
+            providers,
+        }
+    }
+}
+
+impl RuleProvider for CombinedRuleProvider {
+    fn search(&self, sess: &Session) -> bool {
+        self.operator
+            .matches(self.providers.iter().map(|x| x.search(sess)))
+    }
+
+    /// classical unless all the sets have the same behavior
+    fn behavior(&self) -> RuleSetBehavior {
+        let mut behaviors = self.providers.iter().map(|x| x.behavior());
+        match behaviors.next() {
+            Some(first) if behaviors.all(|x| x == first) => first,
+            _ => RuleSetBehavior::Classical,
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for CombinedRuleProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn vehicle_type(&self) -> ProviderVehicleType {
+        ProviderVehicleType::Compatible
+    }
+
+    fn typ(&self) -> ProviderType {
+        ProviderType::Rule
+    }
+
+    async fn initialize(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    async fn update(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m: HashMap<String, Box<dyn ESerialize + Send>> = HashMap::new();
+
+        m.insert("name".to_owned(), Box::new(self.name().to_string()));
+        m.insert("type".to_owned(), Box::new(self.typ().to_string()));
+        m.insert(
+            "vehicleType".to_owned(),
+            Box::new(self.vehicle_type().to_string()),
+        );
+        m.insert("behavior".to_owned(), Box::new(self.behavior().to_string()));
+        m.insert("operator".to_owned(), Box::new(self.operator));
+        m.insert(
+            "providers".to_owned(),
+            Box::new(
+                self.providers
+                    .iter()
+                    .map(|x| x.name().to_owned())
+                    .collect::<Vec<_>>(),
+            ),
+        );
+
+        m
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RuleSetOperator;
+
+    #[test]
+    fn test_operator() {
+        let cases = [
+            (vec![true, false], true, false, true),
+            (vec![true, true], true, true, false),
+            (vec![false, true], true, false, false),
+            (vec![false, false], false, false, false),
+            (vec![true], true, true, true),
+            (vec![], false, true, false),
+        ];
+        for (sets, union, intersection, difference) in cases {
+            let m = |op: RuleSetOperator| op.matches(sets.iter().copied());
+            assert_eq!(m(RuleSetOperator::Union), union, "{:?}", sets);
+            assert_eq!(m(RuleSetOperator::Intersection), intersection, "{:?}", sets);
+            assert_eq!(m(RuleSetOperator::Difference), difference, "{:?}", sets);
+        }
+    }
+}
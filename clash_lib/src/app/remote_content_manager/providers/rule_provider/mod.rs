@@ -1,4 +1,6 @@
 mod cidr_trie;
+mod combined;
 mod provider;
 
+pub use combined::{CombinedRuleProvider, RuleSetOperator};
 pub use provider::{RuleProviderImpl, RuleSetBehavior, ThreadSafeRuleProvider};
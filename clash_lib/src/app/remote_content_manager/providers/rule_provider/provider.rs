@@ -33,7 +33,7 @@ struct ProviderScheme {
     pub payload: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum RuleSetBehavior {
     Domain,
@@ -203,13 +203,14 @@ impl Provider for RuleProviderImpl {
     }
 
     async fn update(&self) -> std::io::Result<()> {
-        let (ele, same) = self.fetcher.update().await.map_err(map_io_error)?;
-        debug!("rule provider {} updated. same? {}", self.name(), same);
-        if !same {
-            if let Some(updater) = self.fetcher.on_update.as_ref() {
-                let f = updater.lock().await;
-                f(ele).await;
-            }
+        let Some(ele) = self.fetcher.update().await.map_err(map_io_error)? else {
+            debug!("rule provider {} not changed", self.name());
+            return Ok(());
+        };
+        debug!("rule provider {} updated", self.name());
+        if let Some(updater) = self.fetcher.on_update.as_ref() {
+            let f = updater.lock().await;
+            f(ele).await;
         }
         Ok(())
     }
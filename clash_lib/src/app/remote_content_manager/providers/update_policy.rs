@@ -0,0 +1,156 @@
+//! When and how many providers may fetch at once, set globally with
+//! `provider-update`. Scheduled updates wait for the window, if any, and
+//! every fetch from a remote takes a permit.
+
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use chrono::{Local, NaiveTime, Timelike};
+use once_cell::sync::Lazy;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use crate::Error;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// The time of day updates are allowed in, `03:00-05:00`, spanning
+/// midnight if it ends before it starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UpdateWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl UpdateWindow {
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+
+    /// how long from `now` until the window opens, zero if it's open
+    pub fn until_open(&self, now: NaiveTime) -> Duration {
+        if self.contains(now) {
+            return Duration::ZERO;
+        }
+        let secs = |t: NaiveTime| u64::from(t.num_seconds_from_midnight());
+        Duration::from_secs((secs(self.start) + DAY - secs(now)) % DAY)
+    }
+}
+
+impl FromStr for UpdateWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || Error::InvalidConfig(format!("invalid update window: {}", s));
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let time = |x: &str| {
+            NaiveTime::parse_from_str(x.trim(), "%H:%M").map_err(|_| invalid())
+        };
+        let (start, end) = (time(start)?, time(end)?);
+        if start == end {
+            return Err(invalid());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl Display for UpdateWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UpdatePolicy {
+    /// scheduled updates only happen in this window
+    pub window: Option<UpdateWindow>,
+    /// fetches at once, 0 for no limit
+    pub concurrency: usize,
+}
+
+struct State {
+    window: Option<UpdateWindow>,
+    permits: Option<Arc<Semaphore>>,
+}
+
+static POLICY: Lazy<RwLock<State>> = Lazy::new(|| {
+    RwLock::new(State {
+        window: None,
+        permits: None,
+    })
+});
+
+/// Apply `policy` to the updates from now on, fetches already waiting for
+/// a permit keep to the old limit.
+pub fn set_update_policy(policy: UpdatePolicy) {
+    let mut state = POLICY.write().unwrap();
+    state.window = policy.window;
+    state.permits = (policy.concurrency > 0)
+        .then(|| Arc::new(Semaphore::new(policy.concurrency)));
+}
+
+/// Wait for the update window to open.
+pub(crate) async fn wait_for_window() {
+    let Some(window) = POLICY.read().unwrap().window else {
+        return;
+    };
+    let wait = window.until_open(Local::now().time());
+    if !wait.is_zero() {
+        debug!("waiting {:?} for the update window {}", wait, window);
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// A permit to fetch, `None` if there's no limit.
+pub(crate) async fn fetch_permit() -> Option<OwnedSemaphorePermit> {
+    let permits = POLICY.read().unwrap().permits.clone()?;
+    permits.acquire_owned().await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::NaiveTime;
+
+    use super::UpdateWindow;
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_update_window() {
+        let w = "03:00-05:00".parse::<UpdateWindow>().unwrap();
+        assert_eq!(w.to_string(), "03:00-05:00");
+        assert!(w.contains(at(4, 59)));
+        assert!(!w.contains(at(5, 0)));
+        assert_eq!(w.until_open(at(3, 30)), Duration::ZERO);
+        assert_eq!(w.until_open(at(2, 0)), Duration::from_secs(3600));
+        assert_eq!(w.until_open(at(6, 0)), Duration::from_secs(21 * 3600));
+
+        let w = "23:30 - 01:00".parse::<UpdateWindow>().unwrap();
+        assert!(w.contains(at(0, 15)));
+        assert!(w.contains(at(23, 45)));
+        assert!(!w.contains(at(1, 0)));
+        assert_eq!(w.until_open(at(23, 0)), Duration::from_secs(1800));
+
+        assert!("03:00".parse::<UpdateWindow>().is_err());
+        assert!("03:00-03:00".parse::<UpdateWindow>().is_err());
+        assert!("25:00-05:00".parse::<UpdateWindow>().is_err());
+    }
+}
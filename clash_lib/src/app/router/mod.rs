@@ -7,26 +7,45 @@ use crate::{
 };
 
 use crate::{
-    common::mmdb::Mmdb,
-    config::internal::{config::RuleProviderDef, rule::RuleType},
+    common::{
+        mmdb::Mmdb,
+        timing::{record_phase, ConnectPhase},
+    },
+    config::{
+        def::{RunMode, Unmatched},
+        internal::{
+            config::{Finals, RuleProviderDef},
+            proxy::{PROXY_DIRECT, PROXY_GLOBAL, PROXY_REJECT},
+            rule::{Resolve, RuleType},
+        },
+    },
     session::{Session, SocksAddr},
 };
 
 use crate::app::router::rules::final_::Final;
-use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use hyper::Uri;
 use tracing::{debug, error, info};
 
 use super::{
     dns::ThreadSafeDNSResolver,
+    metrics,
+    profile::ThreadSafeCacheFile,
     remote_content_manager::providers::{
         file_vehicle, http_vehicle,
-        rule_provider::{RuleProviderImpl, ThreadSafeRuleProvider},
+        rule_provider::{
+            CombinedRuleProvider, RuleProviderImpl, ThreadSafeRuleProvider,
+        },
     },
 };
 
-mod rules;
+pub(crate) mod rules;
 
 use crate::common::geodata::GeoData;
 pub use rules::RuleMatcher;
@@ -36,6 +55,7 @@ pub struct Router {
     #[allow(dead_code)]
     rule_provider_registry: HashMap<String, ThreadSafeRuleProvider>,
     dns_resolver: ThreadSafeDNSResolver,
+    finals: Finals,
 }
 
 pub type ThreadSafeRouter = Arc<Router>;
@@ -49,6 +69,7 @@ impl Router {
         dns_resolver: ThreadSafeDNSResolver,
         mmdb: Arc<Mmdb>,
         geodata: Arc<GeoData>,
+        cache_store: ThreadSafeCacheFile,
         cwd: String,
     ) -> Self {
         let mut rule_provider_registry = HashMap::new();
@@ -59,6 +80,7 @@ impl Router {
             dns_resolver.clone(),
             mmdb.clone(),
             geodata.clone(),
+            cache_store,
             cwd,
         )
         .await
@@ -78,30 +100,61 @@ impl Router {
                 .collect(),
             dns_resolver,
             rule_provider_registry,
+            finals: Finals::default(),
+        }
+    }
+
+    /// Route connections no rule matched with `finals` instead of DIRECT.
+    pub fn with_finals(mut self, finals: Finals) -> Self {
+        self.finals = finals;
+        self
+    }
+
+    /// The outbound for `sess` in run mode `mode`. Rules are only evaluated
+    /// in rule mode, global sends everything to the GLOBAL selector and
+    /// direct to DIRECT.
+    pub async fn route<'a>(
+        &'a self,
+        mode: RunMode,
+        sess: &Session,
+    ) -> (&'a str, Option<&'a Box<dyn RuleMatcher>>) {
+        match mode {
+            RunMode::Global => (PROXY_GLOBAL, None),
+            RunMode::Rule => self.match_route(sess).await,
+            RunMode::Direct => (PROXY_DIRECT, None),
         }
     }
 
     pub async fn match_route<'a>(
         &'a self,
-        sess: &'a Session,
-    ) -> (&str, Option<&Box<dyn RuleMatcher>>) {
+        sess: &Session,
+    ) -> (&'a str, Option<&'a Box<dyn RuleMatcher>>) {
         let mut sess_resolved = false;
+        let mut remote = None;
         let mut sess_dup = sess.clone();
+        let inbound_final = self.finals.inbounds.get(&sess.inbound_name);
 
         for r in self.rules.iter() {
+            // the inbound's own final takes over from MATCH
+            if inbound_final.is_some() && r.type_name() == "Match" {
+                break;
+            }
             if sess.destination.is_domain()
                 && r.should_resolve_ip()
                 && !sess_resolved
+                && !*remote.get_or_insert_with(|| self.resolved_remotely(sess))
             {
                 debug!(
                     "rule `{r}` resolving domain {} locally",
                     sess.destination.domain().unwrap()
                 );
-                if let Ok(Some(ip)) = self
+                let start = Instant::now();
+                let resolved = self
                     .dns_resolver
                     .resolve(sess.destination.domain().unwrap(), false)
-                    .await
-                {
+                    .await;
+                record_phase(ConnectPhase::Dns, start.elapsed());
+                if let Ok(Some(ip)) = resolved {
                     sess_dup.destination =
                         SocksAddr::from((ip, sess.destination.port()));
                     sess_resolved = true;
@@ -116,11 +169,29 @@ impl Router {
                     r.type_name()
                 );
                 debug!("matched rule details: {}", r);
+                metrics::rule_hit(r.type_name(), r.target());
                 return (r.target(), Some(r));
             }
         }
 
-        (MATCH, None)
+        let target = match inbound_final {
+            Some(target) => target.as_str(),
+            None => match self.finals.unmatched {
+                Unmatched::Direct => PROXY_DIRECT,
+                Unmatched::Reject => PROXY_REJECT,
+            },
+        };
+        info!("matched {} to final {}", &sess_dup, target);
+        metrics::rule_hit(MATCH, target);
+        (target, None)
+    }
+
+    /// Whether a `resolve=remote` rule matches `sess` by its domain, which
+    /// then must not be looked up here for the IP rules before it either.
+    fn resolved_remotely(&self, sess: &Session) -> bool {
+        self.rules
+            .iter()
+            .any(|r| r.resolve() == Some(Resolve::Remote) && r.apply(sess))
     }
 
     async fn load_rule_providers(
@@ -129,8 +200,10 @@ impl Router {
         resolver: ThreadSafeDNSResolver,
         mmdb: Arc<Mmdb>,
         geodata: Arc<GeoData>,
+        cache_store: ThreadSafeCacheFile,
         cwd: String,
     ) -> Result<(), Error> {
+        let mut combined = HashMap::new();
         for (name, provider) in rule_providers.into_iter() {
             match provider {
                 RuleProviderDef::Http(http) => {
@@ -141,7 +214,9 @@ impl Router {
                         http.path,
                         Some(cwd.clone()),
                         resolver.clone(),
-                    );
+                        Some(cache_store.clone()),
+                    )
+                    .with_integrity(http.integrity);
 
                     let provider = RuleProviderImpl::new(
                         name.clone(),
@@ -173,12 +248,15 @@ impl Router {
 
                     rule_provider_registry.insert(name, Arc::new(provider));
                 }
+                RuleProviderDef::Combine(combine) => {
+                    combined.insert(name, combine);
+                }
             }
         }
 
-        for p in rule_provider_registry.values() {
-            let p = p.clone();
-            tokio::spawn(async move {
+        // all at once, one failing leaves it empty and the others loaded
+        futures::future::join_all(rule_provider_registry.values().map(
+            |p| async move {
                 info!("initializing rule provider {}", p.name());
                 match p.initialize().await {
                     Ok(_) => {
@@ -192,7 +270,41 @@ impl Router {
                         );
                     }
                 }
-            });
+            },
+        ))
+        .await;
+
+        // combined providers may be made of other combined ones, each round
+        // builds those whose providers are all built
+        while !combined.is_empty() {
+            let ready = combined
+                .iter()
+                .filter(|(_, c)| {
+                    c.providers
+                        .iter()
+                        .all(|x| rule_provider_registry.contains_key(x))
+                })
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            if ready.is_empty() {
+                let names = combined.keys().cloned().collect::<Vec<_>>();
+                return Err(Error::InvalidConfig(format!(
+                    "rule providers {} are made of unknown or each other's \
+                     providers",
+                    names.join(", ")
+                )));
+            }
+            for name in ready {
+                let c = combined.remove(&name).unwrap();
+                let providers = c
+                    .providers
+                    .iter()
+                    .map(|x| rule_provider_registry[x].clone())
+                    .collect();
+                let provider =
+                    CombinedRuleProvider::new(name.clone(), c.operator, providers);
+                rule_provider_registry.insert(name, Arc::new(provider));
+            }
         }
 
         Ok(())
@@ -281,6 +393,9 @@ pub fn map_rule_type(
             target,
             is_src: false,
         }),
+        RuleType::Network { network, target } => {
+            Box::new(rules::network::Network { network, target })
+        }
         RuleType::ProcessName {
             process_name,
             target,
@@ -312,6 +427,67 @@ pub fn map_rule_type(
                 unreachable!("you shouldn't next rule-set within another rule-set")
             }
         },
+        RuleType::InUser { users, target } => {
+            Box::new(rules::user::InUser { users, target })
+        }
         RuleType::Match { target } => Box::new(Final { target }),
+        RuleType::Resolve { rule, resolve } => {
+            Box::new(rules::resolve::WithResolve {
+                rule: map_rule_type(*rule, mmdb, geodata, rule_provider_registry),
+                resolve,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::{
+        app::{
+            dns::MockClashResolver,
+            router::rules::{
+                domain_suffix::DomainSuffix, ipcidr::IpCidr, resolve::WithResolve,
+            },
+        },
+        config::internal::{config::Finals, rule::Resolve},
+        session::{Session, SocksAddr},
+    };
+
+    use super::Router;
+
+    #[tokio::test]
+    async fn test_resolve_remote() {
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_resolve().never();
+
+        let router = Router {
+            rules: vec![
+                Box::new(IpCidr {
+                    ipnet: "10.0.0.0/8".parse().unwrap(),
+                    target: "DIRECT".to_owned(),
+                    match_src: false,
+                    no_resolve: false,
+                }),
+                Box::new(WithResolve {
+                    rule: Box::new(DomainSuffix {
+                        suffix: "example.org".to_owned(),
+                        target: "auto".to_owned(),
+                    }),
+                    resolve: Resolve::Remote,
+                }),
+            ],
+            rule_provider_registry: HashMap::new(),
+            dns_resolver: Arc::new(resolver),
+            finals: Finals::default(),
+        };
+
+        let sess = Session {
+            destination: SocksAddr::Domain("www.example.org".to_owned(), 443),
+            ..Default::default()
+        };
+        let (target, _) = router.match_route(&sess).await;
+        assert_eq!(target, "auto");
     }
 }
@@ -79,6 +79,11 @@ impl GeoSiteMatcher {
             matcher: matcher_group,
         })
     }
+
+    /// Whether `domain` is in the list, the same as the rule matching it.
+    pub fn matches(&self, domain: &str) -> bool {
+        self.matcher.apply(domain)
+    }
 }
 
 impl Display for GeoSiteMatcher {
@@ -2,7 +2,7 @@ use std::{collections::HashMap, fmt::Display};
 
 use erased_serde::Serialize;
 
-use crate::session::Session;
+use crate::{config::internal::rule::Resolve, session::Session};
 
 pub mod domain;
 pub mod domain_keyword;
@@ -11,9 +11,12 @@ pub mod final_;
 pub mod geodata;
 pub mod geoip;
 pub mod ipcidr;
+pub mod network;
 pub mod port;
 pub mod process;
+pub mod resolve;
 pub mod ruleset;
+pub mod user;
 
 pub trait RuleMatcher: Send + Sync + Unpin + Display {
     /// check if the rule should apply to the session
@@ -32,6 +35,12 @@ pub trait RuleMatcher: Send + Sync + Unpin + Display {
         false
     }
 
+    /// where the domains of the matched connections are resolved, if the
+    /// rule says
+    fn resolve(&self) -> Option<Resolve> {
+        None
+    }
+
     fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let mut m: HashMap<String, Box<dyn Serialize + Send>> = HashMap::new();
         m.insert("type".to_string(), Box::new(self.type_name().to_owned()));
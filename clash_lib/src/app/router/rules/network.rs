@@ -0,0 +1,34 @@
+use crate::{
+    app::router::rules::RuleMatcher,
+    session::{Network as SessionNetwork, Session},
+};
+
+#[derive(Clone)]
+pub struct Network {
+    pub network: SessionNetwork,
+    pub target: String,
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} network {}", self.target, self.network)
+    }
+}
+
+impl RuleMatcher for Network {
+    fn apply(&self, sess: &Session) -> bool {
+        sess.network == self.network
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.network.to_string()
+    }
+
+    fn type_name(&self) -> &str {
+        "Network"
+    }
+}
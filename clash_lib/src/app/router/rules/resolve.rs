@@ -0,0 +1,52 @@
+use std::{collections::HashMap, fmt::Display};
+
+use erased_serde::Serialize;
+
+use crate::{
+    app::router::rules::RuleMatcher, config::internal::rule::Resolve,
+    session::Session,
+};
+
+/// A rule with `resolve=local` or `resolve=remote`.
+pub struct WithResolve {
+    pub rule: Box<dyn RuleMatcher>,
+    pub resolve: Resolve,
+}
+
+impl Display for WithResolve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} resolve={}", self.rule, self.resolve)
+    }
+}
+
+impl RuleMatcher for WithResolve {
+    fn apply(&self, sess: &Session) -> bool {
+        self.rule.apply(sess)
+    }
+
+    fn target(&self) -> &str {
+        self.rule.target()
+    }
+
+    fn payload(&self) -> String {
+        self.rule.payload()
+    }
+
+    fn type_name(&self) -> &str {
+        self.rule.type_name()
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.rule.should_resolve_ip()
+    }
+
+    fn resolve(&self) -> Option<Resolve> {
+        Some(self.resolve)
+    }
+
+    fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m = self.rule.as_map();
+        m.insert("resolve".to_string(), Box::new(self.resolve.to_string()));
+        m
+    }
+}
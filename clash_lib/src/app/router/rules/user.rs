@@ -0,0 +1,57 @@
+use crate::{app::router::rules::RuleMatcher, session::Session};
+
+/// Matches the connections of the users the inbound authenticated.
+#[derive(Clone)]
+pub struct InUser {
+    pub users: Vec<String>,
+    pub target: String,
+}
+
+impl std::fmt::Display for InUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} inbound user {}", self.target, self.payload())
+    }
+}
+
+impl RuleMatcher for InUser {
+    fn apply(&self, sess: &Session) -> bool {
+        sess.inbound_user
+            .as_ref()
+            .is_some_and(|user| self.users.contains(user))
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.users.join("/")
+    }
+
+    fn type_name(&self) -> &str {
+        "InUser"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app::router::rules::RuleMatcher, session::Session};
+
+    use super::InUser;
+
+    #[test]
+    fn test_in_user() {
+        let rule = InUser {
+            users: vec!["alice".to_owned(), "bob".to_owned()],
+            target: "PROXY".to_owned(),
+        };
+        assert_eq!(rule.payload(), "alice/bob");
+
+        let mut sess = Session::default();
+        assert!(!rule.apply(&sess));
+        sess.inbound_user = Some("bob".to_owned());
+        assert!(rule.apply(&sess));
+        sess.inbound_user = Some("carol".to_owned());
+        assert!(!rule.apply(&sess));
+    }
+}
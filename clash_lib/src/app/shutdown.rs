@@ -0,0 +1,75 @@
+//! Exiting without cutting connections short: the listeners stop, live
+//! connections get `drain-timeout` to finish, then state is saved.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::app::{dispatcher::StatisticsManager, profile::ThreadSafeCacheFile};
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Resolves on ctrl-c, or SIGTERM on unix.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = term.recv() => info!("received SIGTERM"),
+                    _ = tokio::signal::ctrl_c() => {},
+                }
+                return;
+            }
+            Err(e) => warn!("failed to listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Wait up to `timeout` for the tracked connections to finish, then close
+/// the rest.
+pub async fn drain(statistics_manager: &StatisticsManager, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut alive = statistics_manager.connection_count().await;
+    if alive > 0 && !timeout.is_zero() {
+        info!("waiting up to {:?} for {} connections", timeout, alive);
+        while alive > 0 && Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            alive = statistics_manager.connection_count().await;
+        }
+    }
+    if alive > 0 {
+        info!("closing {} connections", alive);
+        statistics_manager.close_all().await;
+    }
+}
+
+/// Save what would otherwise be lost on exit.
+pub async fn save(
+    statistics_manager: &StatisticsManager,
+    cache_store: &ThreadSafeCacheFile,
+) {
+    statistics_manager.persist().await;
+    cache_store.flush().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use crate::app::dispatcher::StatisticsManager;
+
+    #[tokio::test]
+    async fn test_drain_idle() {
+        let manager = StatisticsManager::new(None);
+        let start = Instant::now();
+        super::drain(&manager, Duration::from_secs(10)).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
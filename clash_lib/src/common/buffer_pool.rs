@@ -0,0 +1,312 @@
+//! The copy buffers of relayed connections, taken from a global pool and
+//! put back when the relay ends rather than allocated for each connection.
+//! Up to the `relay-buffer` budget of idle buffers is kept for reuse.
+//!
+//! In auto mode the buffer of a stream that keeps filling it grows, so a
+//! single fast stream over a link with a large bandwidth-delay product
+//! isn't held back by small reads and writes. It shrinks back once the
+//! stream slows down.
+
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+
+const DEFAULT_SIZE: usize = 4 * 1024;
+const DEFAULT_BUDGET: usize = 4 * 1024 * 1024;
+
+pub(crate) struct Pool {
+    size: AtomicUsize,
+    budget: AtomicUsize,
+    /// the size buffers may grow to, 0 when they don't
+    max_size: AtomicUsize,
+    idle: Mutex<Vec<Box<[u8]>>>,
+    in_use: AtomicUsize,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+    grown: AtomicU64,
+}
+
+static POOL: Pool = Pool::new(DEFAULT_SIZE, DEFAULT_BUDGET, 0);
+
+/// Give out buffers of `size` bytes, keeping up to `budget` bytes of idle
+/// ones, that may grow up to `max_size` if set. The idle buffers of another
+/// size are freed.
+pub fn set_buffer_pool(size: usize, budget: usize, max_size: Option<usize>) {
+    POOL.configure(size, budget, max_size);
+}
+
+/// The pool for the API.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolStats {
+    /// bytes of each buffer
+    pub size: usize,
+    /// buffers relaying connections
+    pub in_use: usize,
+    /// buffers kept for reuse
+    pub idle: usize,
+    /// buffers allocated since start
+    pub allocated: u64,
+    /// buffers taken from the pool rather than allocated
+    pub reused: u64,
+    /// times a buffer grew for a fast stream
+    pub grown: u64,
+}
+
+pub fn stats() -> PoolStats {
+    POOL.stats()
+}
+
+impl Pool {
+    /// `max_size` 0 for buffers that don't grow
+    pub(crate) const fn new(size: usize, budget: usize, max_size: usize) -> Self {
+        Self {
+            size: AtomicUsize::new(size),
+            budget: AtomicUsize::new(budget),
+            max_size: AtomicUsize::new(max_size),
+            idle: Mutex::new(Vec::new()),
+            in_use: AtomicUsize::new(0),
+            allocated: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            grown: AtomicU64::new(0),
+        }
+    }
+
+    fn configure(&self, size: usize, budget: usize, max_size: Option<usize>) {
+        let size = size.max(1);
+        self.size.store(size, Ordering::Relaxed);
+        self.budget.store(budget, Ordering::Relaxed);
+        self.max_size
+            .store(max_size.unwrap_or_default(), Ordering::Relaxed);
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|x| x.len() == size);
+        idle.truncate(budget / size);
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        PoolStats {
+            size: self.size.load(Ordering::Relaxed),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            idle: self.idle.lock().unwrap().len(),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            grown: self.grown.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Take a buffer, allocating one if there's none idle.
+    pub(crate) fn take(&'static self) -> PooledBuffer {
+        let size = self.size.load(Ordering::Relaxed);
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        if let Some(buf) = self.idle.lock().unwrap().pop() {
+            self.reused.fetch_add(1, Ordering::Relaxed);
+            return PooledBuffer {
+                buf,
+                pool: Some(self),
+            };
+        }
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+        PooledBuffer {
+            buf: vec![0; size].into_boxed_slice(),
+            pool: Some(self),
+        }
+    }
+
+    /// keep `buf` for reuse if it's of the current size and within the
+    /// budget
+    fn put_back(&self, buf: Box<[u8]>) {
+        let size = self.size.load(Ordering::Relaxed);
+        if buf.len() != size {
+            return;
+        }
+        let max_idle = self.budget.load(Ordering::Relaxed) / size;
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < max_idle {
+            idle.push(buf);
+        }
+    }
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// A buffer of the pool, put back when dropped.
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buf: Box<[u8]>,
+    /// `None` for a buffer of its own, freed when dropped
+    pool: Option<&'static Pool>,
+}
+
+impl PooledBuffer {
+    /// Take a buffer from the pool, allocating one if there's none.
+    pub fn take() -> Self {
+        POOL.take()
+    }
+
+    /// A buffer of `size` bytes outside the pool, it never grows.
+    pub fn unpooled(size: usize) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(size).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("new buffer failed: {}", e))
+        })?;
+        buf.resize(size, 0);
+        Ok(Self {
+            buf: buf.into_boxed_slice(),
+            pool: None,
+        })
+    }
+
+    /// Double the buffer up to the auto mode's maximum, its content is lost.
+    /// Returns false if it can't grow.
+    pub fn grow(&mut self) -> bool {
+        let Some(pool) = self.pool else {
+            return false;
+        };
+        let max_size = pool.max_size.load(Ordering::Relaxed);
+        if self.buf.len() >= max_size {
+            return false;
+        }
+        let size = (self.buf.len() * 2).min(max_size);
+        let old = std::mem::replace(&mut self.buf, vec![0; size].into_boxed_slice());
+        pool.put_back(old);
+        pool.grown.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Halve a grown buffer down to the pool's size, its content is lost.
+    /// Returns false if it isn't grown.
+    pub fn shrink(&mut self) -> bool {
+        let Some(pool) = self.pool else {
+            return false;
+        };
+        let size = pool.size.load(Ordering::Relaxed);
+        if self.buf.len() <= size {
+            return false;
+        }
+        let size = (self.buf.len() / 2).max(size);
+        self.buf = vec![0; size].into_boxed_slice();
+        true
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool {
+            pool.in_use.fetch_sub(1, Ordering::Relaxed);
+            pool.put_back(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pool, PoolStats, PooledBuffer};
+
+    /// a pool of its own, the global one is shared with the other tests
+    fn pool(size: usize, budget: usize, max_size: usize) -> &'static Pool {
+        Box::leak(Box::new(Pool::new(size, budget, max_size)))
+    }
+
+    #[test]
+    fn test_pool_reuse() {
+        let pool = pool(16, 32, 0);
+
+        let a = pool.take();
+        assert_eq!(a.len(), 16);
+        let ptr = a.as_ptr();
+        drop(a);
+        let a = pool.take();
+        assert_eq!(a.as_ptr(), ptr);
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                size: 16,
+                in_use: 1,
+                idle: 0,
+                allocated: 1,
+                reused: 1,
+                grown: 0,
+            }
+        );
+        drop(a);
+    }
+
+    #[test]
+    fn test_pool_budget() {
+        let pool = pool(16, 32, 0);
+
+        let bufs: Vec<_> = (0..3).map(|_| pool.take()).collect();
+        let stats = pool.stats();
+        assert_eq!((stats.in_use, stats.idle, stats.allocated), (3, 0, 3));
+
+        // room for two idle buffers
+        drop(bufs);
+        let stats = pool.stats();
+        assert_eq!((stats.in_use, stats.idle, stats.allocated), (0, 2, 3));
+
+        // buffers of the old size are freed
+        pool.configure(8, 32, None);
+        assert_eq!(pool.stats().idle, 0);
+        assert_eq!(pool.take().len(), 8);
+        assert_eq!(pool.stats().idle, 1);
+    }
+
+    #[test]
+    fn test_pool_grow() {
+        assert!(!pool(16, 32, 0).take().grow());
+
+        let pool = pool(16, 32, 40);
+        let mut a = pool.take();
+        assert!(a.grow());
+        assert_eq!(a.len(), 32);
+        assert!(a.grow());
+        assert_eq!(a.len(), 40);
+        assert!(!a.grow());
+        assert_eq!(pool.stats().grown, 2);
+        assert!(a.shrink());
+        assert_eq!(a.len(), 20);
+        assert!(a.shrink());
+        assert_eq!(a.len(), 16);
+        assert!(!a.shrink());
+        // the buffer it grew from is kept, the grown ones are freed
+        assert_eq!(pool.stats().idle, 1);
+        drop(a);
+        assert_eq!(pool.stats().idle, 2);
+        assert_eq!(pool.stats().in_use, 0);
+    }
+
+    #[test]
+    fn test_unpooled() {
+        let mut a = PooledBuffer::unpooled(10).unwrap();
+        assert_eq!(a.len(), 10);
+        assert!(!a.grow());
+        assert!(!a.shrink());
+    }
+}
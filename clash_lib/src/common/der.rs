@@ -0,0 +1,49 @@
+//! Just enough DER reading to pick fields out of certificates.
+
+/// Walk the top level of a DER structure, returning each element whole.
+pub fn der_elements(mut der: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut out = vec![];
+    while !der.is_empty() {
+        let first = *der.get(1)? as usize;
+        let (header, len) = if first < 0x80 {
+            (2, first)
+        } else {
+            let n = first & 0x7f;
+            if n > 4 {
+                return None;
+            }
+            let len = der
+                .get(2..2 + n)?
+                .iter()
+                .fold(0usize, |acc, x| acc << 8 | *x as usize);
+            (2 + n, len)
+        };
+        let end = header.checked_add(len)?;
+        out.push(der.get(..end)?);
+        der = &der[end..];
+    }
+    Some(out)
+}
+
+pub fn der_content(element: &[u8]) -> &[u8] {
+    let first = element[1] as usize;
+    if first < 0x80 {
+        &element[2..]
+    } else {
+        &element[2 + (first & 0x7f)..]
+    }
+}
+
+/// The encoded subject public key info of a certificate.
+pub fn spki_of(cert: &[u8]) -> Option<&[u8]> {
+    let cert = *der_elements(cert)?.first()?;
+    let tbs = *der_elements(der_content(cert))?.first()?;
+    let fields = der_elements(der_content(tbs))?;
+    let skip = if fields.first()?.first() == Some(&0xa0) {
+        1
+    } else {
+        0
+    };
+    // serial, signature, issuer, validity, subject, spki
+    fields.get(skip + 5).copied()
+}
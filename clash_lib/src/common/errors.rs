@@ -10,3 +10,52 @@ where
 {
     io::Error::new(io::ErrorKind::Other, format!("{:?}", anyhow::anyhow!(err)))
 }
+
+/// A failure to reach the proxy server or to set up the connection with it,
+/// as opposed to the destination behind it failing. Only these count
+/// against the health of a proxy.
+#[derive(Debug)]
+struct ServerError(io::Error);
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Mark `err` as the proxy server's fault, keeping its kind and message.
+pub fn server_error(err: io::Error) -> io::Error {
+    if is_server_error(&err) {
+        return err;
+    }
+    io::Error::new(err.kind(), ServerError(err))
+}
+
+pub fn is_server_error(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|x| x.is::<ServerError>())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{is_server_error, server_error};
+
+    #[test]
+    fn test_server_error() {
+        let err = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert!(!is_server_error(&err));
+
+        let err = server_error(err);
+        assert!(is_server_error(&err));
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(err.to_string(), "refused");
+        assert!(is_server_error(&server_error(err)));
+    }
+}
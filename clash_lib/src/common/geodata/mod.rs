@@ -1,5 +1,8 @@
 use crate::{
-    common::{http::HttpClient, utils::download},
+    common::{
+        http::HttpClient,
+        integrity::{download_verified, Integrity},
+    },
     Error,
 };
 use prost::Message;
@@ -18,6 +21,7 @@ impl GeoData {
     pub async fn new<P: AsRef<Path>>(
         path: P,
         download_url: Option<String>,
+        integrity: &Integrity,
         http_client: HttpClient,
     ) -> Result<Self, Error> {
         debug!("geosite path: {}", path.as_ref().to_string_lossy());
@@ -27,7 +31,7 @@ impl GeoData {
         if !geosite_file.exists() {
             if let Some(url) = download_url.as_ref() {
                 info!("downloading geodata from {}", url);
-                download(url, &geosite_file, &http_client)
+                download_verified(url, &geosite_file, &http_client, integrity)
                     .await
                     .map_err(|x| {
                         Error::InvalidConfig(format!(
@@ -50,6 +54,13 @@ impl GeoData {
         Ok(Self { cache })
     }
 
+    /// One without any site, for when it can't be loaded.
+    pub fn empty() -> Self {
+        Self {
+            cache: Default::default(),
+        }
+    }
+
     #[cfg(test)]
     pub async fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         let bytes = tokio::fs::read(path).await?;
@@ -0,0 +1,360 @@
+//! Checks of downloaded providers and geo databases, for those who'd rather
+//! fail than route by tampered data.
+//!
+//! A signature comes with the version of the content and an optional expiry,
+//! signed along with it, so content can't be replaced by an older one signed
+//! by the same key: the last version accepted for a file is kept beside it,
+//! in `<path>.version`.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use hyper::body;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::{
+        http::HttpClient,
+        utils::{download, encode_hex, sha256},
+    },
+    Error,
+};
+
+/// What downloaded content must pass before it's used, nothing by default.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Integrity {
+    /// hex encoded SHA256 of the content, for content that never changes
+    pub sha256: Option<String>,
+    /// base64 encoded Ed25519 public key the content must be signed by
+    pub public_key: Option<String>,
+    /// where the signature is, `<url>.sig` by default, see [`Signature`]
+    pub signature_url: Option<String>,
+}
+
+/// Starts what's signed, so a signature made for anything else, e.g. the
+/// bare content, isn't accepted.
+const SIGNING_CONTEXT: &str = "clash-rs signed content";
+
+/// A signature file, one `key: value` per line:
+/// ```text
+/// version: 42
+/// expires: 1767225600
+/// signature: <base64 Ed25519 signature of `Signature::message`>
+/// ```
+/// `expires`, in seconds since the epoch, is optional.
+#[derive(Debug, PartialEq)]
+pub struct Signature {
+    pub version: u64,
+    pub expires: Option<u64>,
+    pub signature: Vec<u8>,
+}
+
+impl Signature {
+    pub fn parse(raw: &[u8]) -> Result<Self, Error> {
+        let invalid =
+            |x: &str| Error::Crypto(format!("invalid signature file: {}", x));
+        let raw = std::str::from_utf8(raw).map_err(|_| invalid("not text"))?;
+        let (mut version, mut expires, mut signature) = (None, None, None);
+        for line in raw.lines().map(str::trim).filter(|x| !x.is_empty()) {
+            let (key, value) = line.split_once(':').ok_or_else(|| invalid(line))?;
+            let value = value.trim();
+            match key.trim() {
+                "version" => {
+                    version = Some(value.parse().map_err(|_| invalid(line))?)
+                }
+                "expires" => {
+                    expires = Some(value.parse().map_err(|_| invalid(line))?)
+                }
+                "signature" => {
+                    signature = Some(
+                        base64::engine::general_purpose::STANDARD
+                            .decode(value)
+                            .map_err(|_| invalid(line))?,
+                    )
+                }
+                // not signed, so not trusted either
+                _ => {}
+            }
+        }
+        Ok(Self {
+            version: version.ok_or_else(|| invalid("no version"))?,
+            expires,
+            signature: signature.ok_or_else(|| invalid("no signature"))?,
+        })
+    }
+
+    /// What's signed: the context, the version and expiry lines, then the
+    /// content.
+    pub fn message(version: u64, expires: Option<u64>, content: &[u8]) -> Vec<u8> {
+        let mut header = format!("{}\nversion: {}\n", SIGNING_CONTEXT, version);
+        if let Some(expires) = expires {
+            header.push_str(&format!("expires: {}\n", expires));
+        }
+        let mut message = header.into_bytes();
+        message.extend_from_slice(content);
+        message
+    }
+}
+
+/// where the last version accepted for `path` is kept
+fn version_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".version");
+    p.into()
+}
+
+/// The version of the signed content last accepted for `path`, 0 if none.
+pub fn current_version(path: &Path) -> u64 {
+    std::fs::read_to_string(version_path(path))
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+pub fn record_version(path: &Path, version: u64) -> std::io::Result<()> {
+    std::fs::write(version_path(path), version.to_string())
+}
+
+impl Integrity {
+    /// the url of the signature of the content at `url`, if one is needed
+    pub fn signature_url(&self, url: &str) -> Option<String> {
+        self.public_key.as_ref()?;
+        Some(
+            self.signature_url
+                .clone()
+                .unwrap_or_else(|| format!("{}.sig", url)),
+        )
+    }
+
+    /// download the signature of the content at `url`, if one is needed
+    pub async fn fetch_signature(
+        &self,
+        url: &str,
+        http_client: &HttpClient,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let Some(url) = self.signature_url(url) else {
+            return Ok(None);
+        };
+        let uri = url.parse::<hyper::Uri>().map_err(|x| {
+            Error::InvalidConfig(format!("invalid signature url {}: {}", url, x))
+        })?;
+        let res = http_client
+            .get(uri)
+            .await
+            .map_err(|x| Error::Operation(format!("{}: {}", url, x)))?;
+        if !res.status().is_success() {
+            return Err(Error::Operation(format!(
+                "signature download from {} failed: {}",
+                url,
+                res.status()
+            )));
+        }
+        let sig = body::to_bytes(res.into_body())
+            .await
+            .map_err(|x| Error::Operation(format!("{}: {}", url, x)))?;
+        Ok(Some(sig.to_vec()))
+    }
+
+    /// Check `content` against the checksum and the `signature` by the
+    /// public key, whichever are configured. A signature must be of a
+    /// version no older than `current` and not expired, its version is
+    /// returned.
+    pub fn verify(
+        &self,
+        content: &[u8],
+        signature: Option<&[u8]>,
+        current: u64,
+    ) -> Result<Option<u64>, Error> {
+        if let Some(expected) = &self.sha256 {
+            let actual = encode_hex(&sha256(content));
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
+                return Err(Error::Crypto(format!(
+                    "sha256 mismatch, expected {} got {}",
+                    expected, actual
+                )));
+            }
+        }
+
+        if let Some(key) = &self.public_key {
+            let key = base64::engine::general_purpose::STANDARD
+                .decode(key.trim())
+                .map_err(|x| {
+                    Error::InvalidConfig(format!("invalid public-key: {}", x))
+                })?;
+            let signature =
+                Signature::parse(signature.ok_or_else(|| {
+                    Error::Crypto("signature missing".to_owned())
+                })?)?;
+            let message =
+                Signature::message(signature.version, signature.expires, content);
+            UnparsedPublicKey::new(&ED25519, key)
+                .verify(&message, &signature.signature)
+                .map_err(|_| Error::Crypto("bad signature".to_owned()))?;
+
+            if signature.version < current {
+                return Err(Error::Crypto(format!(
+                    "version {} is older than the current {}",
+                    signature.version, current
+                )));
+            }
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            if signature.expires.is_some_and(|x| x < now) {
+                return Err(Error::Crypto("signature expired".to_owned()));
+            }
+            return Ok(Some(signature.version));
+        }
+
+        Ok(None)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sha256.is_none() && self.public_key.is_none()
+    }
+}
+
+/// `download` `url` to `path`, and remove it again unless it passes
+/// `integrity`.
+pub async fn download_verified<P: AsRef<Path> + Send + Sync>(
+    url: &str,
+    path: P,
+    http_client: &HttpClient,
+    integrity: &Integrity,
+) -> anyhow::Result<()> {
+    download(url, &path, http_client).await?;
+    let path = path.as_ref();
+    let rv = verify_download(url, path, path, http_client, integrity).await;
+    if rv.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(rv?)
+}
+
+/// Check `file`, downloaded from `url`, with `integrity`. The version of its
+/// signature is checked against and recorded for `path`, where it's used.
+pub async fn verify_download(
+    url: &str,
+    file: &Path,
+    path: &Path,
+    http_client: &HttpClient,
+    integrity: &Integrity,
+) -> Result<(), Error> {
+    if integrity.is_empty() {
+        return Ok(());
+    }
+    let content = tokio::fs::read(file).await?;
+    let signature = integrity.fetch_signature(url, http_client).await?;
+    if let Some(version) =
+        integrity.verify(&content, signature.as_deref(), current_version(path))?
+    {
+        record_version(path, version)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    use super::{current_version, record_version, Integrity, Signature};
+    use crate::common::utils::{encode_hex, sha256};
+
+    #[test]
+    fn test_sha256() {
+        let integrity = Integrity {
+            sha256: Some(encode_hex(&sha256(b"payload:\n  - example.com\n"))),
+            ..Default::default()
+        };
+        assert!(integrity.signature_url("https://x/rules.yaml").is_none());
+        assert_eq!(
+            integrity
+                .verify(b"payload:\n  - example.com\n", None, 0)
+                .unwrap(),
+            None
+        );
+        assert!(integrity
+            .verify(b"payload:\n  - evil.com\n", None, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_signature() {
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let sign = |version, expires: Option<u64>, content: &[u8]| {
+            let sig = pair.sign(&Signature::message(version, expires, content));
+            let mut file = format!("version: {}\n", version);
+            if let Some(expires) = expires {
+                file.push_str(&format!("expires: {}\n", expires));
+            }
+            file.push_str(&format!("signature: {}\n", b64.encode(sig.as_ref())));
+            file.into_bytes()
+        };
+
+        let integrity = Integrity {
+            public_key: Some(b64.encode(pair.public_key().as_ref())),
+            ..Default::default()
+        };
+        assert_eq!(
+            integrity.signature_url("https://x/rules.yaml").as_deref(),
+            Some("https://x/rules.yaml.sig")
+        );
+
+        let content: &[u8] = b"proxies: []";
+        let sig = sign(3, None, content);
+        assert_eq!(
+            integrity.verify(content, Some(&sig[..]), 0).unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            integrity.verify(content, Some(&sig[..]), 3).unwrap(),
+            Some(3)
+        );
+        // rolled back to an older version
+        assert!(integrity.verify(content, Some(&sig[..]), 4).is_err());
+        // another version than the one signed
+        let forged = String::from_utf8(sig.clone())
+            .unwrap()
+            .replace("version: 3", "version: 5");
+        assert!(integrity
+            .verify(content, Some(forged.as_bytes()), 4)
+            .is_err());
+
+        assert!(integrity
+            .verify(b"proxies: [evil]", Some(&sig[..]), 0)
+            .is_err());
+        assert!(integrity.verify(content, None, 0).is_err());
+        // the bare content signed
+        let bare =
+            format!("version: 3\nsignature: {}", b64.encode(pair.sign(content)));
+        assert!(integrity.verify(content, Some(bare.as_bytes()), 0).is_err());
+
+        let expired = sign(3, Some(1), content);
+        assert!(integrity.verify(content, Some(&expired[..]), 0).is_err());
+        let valid = sign(3, Some(u64::MAX), content);
+        assert!(integrity.verify(content, Some(&valid[..]), 0).is_ok());
+    }
+
+    #[test]
+    fn test_version_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules.yaml");
+        assert_eq!(current_version(&path), 0);
+        record_version(&path, 7).unwrap();
+        assert_eq!(current_version(&path), 7);
+        assert!(dir.path().join("rules.yaml.version").exists());
+    }
+}
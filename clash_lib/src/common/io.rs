@@ -10,6 +10,14 @@ use std::{
 use futures::ready;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
+use super::buffer_pool::PooledBuffer;
+
+/// full reads after which a buffer grows, in auto mode
+const GROW_AFTER_FULL_READS: u8 = 4;
+/// reads filling less than a quarter of a grown buffer after which it
+/// shrinks
+const SHRINK_AFTER_SHORT_READS: u8 = 16;
+
 #[derive(Debug)]
 pub enum CopyBidirectionalError {
     LeftClosed(std::io::Error),
@@ -56,39 +64,35 @@ pub struct CopyBuffer {
     pos: usize,
     cap: usize,
     amt: u64,
-    buf: Box<[u8]>,
+    /// reads in a row that filled the buffer
+    full_reads: u8,
+    /// reads in a row that filled less than a quarter of it
+    short_reads: u8,
+    buf: PooledBuffer,
 }
 
 impl CopyBuffer {
-    #[allow(unused)]
-    pub fn new() -> Self {
-        Self {
-            read_done: false,
-            need_flush: false,
-            pos: 0,
-            cap: 0,
-            amt: 0,
-            buf: vec![0; 2 * 1024].into_boxed_slice(),
-        }
+    /// with a buffer of the pool, see `relay-buffer`
+    pub fn pooled() -> Self {
+        Self::with_buffer(PooledBuffer::take())
     }
 
+    /// with a buffer of `size` bytes of its own, that doesn't grow
     pub fn new_with_capacity(size: usize) -> Result<Self, std::io::Error> {
-        let mut buf = Vec::new();
-        buf.try_reserve(size).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("new buffer failed: {}", e),
-            )
-        })?;
-        buf.resize(size, 0);
-        Ok(Self {
+        Ok(Self::with_buffer(PooledBuffer::unpooled(size)?))
+    }
+
+    fn with_buffer(buf: PooledBuffer) -> Self {
+        Self {
             read_done: false,
             need_flush: false,
             pos: 0,
             cap: 0,
             amt: 0,
-            buf: buf.into_boxed_slice(),
-        })
+            full_reads: 0,
+            short_reads: 0,
+            buf,
+        }
     }
 
     pub fn amount_transfered(&self) -> u64 {
@@ -109,6 +113,15 @@ impl CopyBuffer {
             // If our buffer is empty, then we need to read some data to
             // continue.
             if self.pos == self.cap && !self.read_done {
+                // a stream that keeps filling the buffer is faster than it
+                // lets through, it's empty here so growing loses nothing
+                if self.full_reads >= GROW_AFTER_FULL_READS {
+                    self.full_reads = 0;
+                    self.buf.grow();
+                } else if self.short_reads >= SHRINK_AFTER_SHORT_READS {
+                    self.short_reads = 0;
+                    self.buf.shrink();
+                }
                 let me = &mut *self;
                 let mut buf = ReadBuf::new(&mut me.buf);
 
@@ -129,6 +142,16 @@ impl CopyBuffer {
                 }
 
                 let n = buf.filled().len();
+                if n == self.buf.len() {
+                    self.full_reads = self.full_reads.saturating_add(1);
+                    self.short_reads = 0;
+                } else if n < self.buf.len() / 4 {
+                    self.full_reads = 0;
+                    self.short_reads = self.short_reads.saturating_add(1);
+                } else {
+                    self.full_reads = 0;
+                    self.short_reads = 0;
+                }
                 if n == 0 {
                     self.read_done = true;
                 } else {
@@ -178,6 +201,17 @@ enum TransferState {
     Done,
 }
 
+impl TransferState {
+    /// bytes copied so far, not counting a finished transfer
+    fn transferred(&self) -> u64 {
+        match self {
+            TransferState::Running(buf) => buf.amount_transfered(),
+            TransferState::ShuttingDown(count) => *count,
+            TransferState::Done => 0,
+        }
+    }
+}
+
 struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     a: &'a mut A,
     b: &'a mut B,
@@ -189,6 +223,10 @@ struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     b_to_a_delay: Option<Pin<Box<tokio::time::Sleep>>>,
     a_to_b_timeout_duration: Duration,
     b_to_a_timeout_duration: Duration,
+    idle_timeout_duration: Option<Duration>,
+    idle_delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// bytes copied both ways when the idle delay was last reset
+    last_transferred: u64,
 }
 
 impl<'a, A, B> Future for CopyBidirectional<'a, A, B>
@@ -211,6 +249,9 @@ where
             b_to_a_delay,
             a_to_b_timeout_duration,
             b_to_a_timeout_duration,
+            idle_timeout_duration,
+            idle_delay,
+            last_transferred,
         } = &mut *self;
 
         let mut a = Pin::new(a);
@@ -317,22 +358,103 @@ where
                 TransferState::Done => (),
             }
 
-            match (&a_to_b, &b_to_a) {
-                (TransferState::Done, TransferState::Done) => break,
-                _ => return Poll::Pending,
+            if let (TransferState::Done, TransferState::Done) = (&a_to_b, &b_to_a) {
+                break;
             }
+
+            if let Some(idle_timeout) = idle_timeout_duration {
+                let transferred = *a_to_b_count
+                    + *b_to_a_count
+                    + a_to_b.transferred()
+                    + b_to_a.transferred();
+                let deadline = tokio::time::Instant::now() + *idle_timeout;
+                match idle_delay {
+                    Some(delay) if transferred != *last_transferred => {
+                        delay.as_mut().reset(deadline)
+                    }
+                    Some(_) => {}
+                    None => {
+                        *idle_delay =
+                            Some(Box::pin(tokio::time::sleep_until(deadline)))
+                    }
+                }
+                *last_transferred = transferred;
+
+                if let Some(delay) = idle_delay {
+                    if delay.as_mut().poll(cx).is_ready() {
+                        return Poll::Ready(Err(CopyBidirectionalError::Other(
+                            io::Error::new(io::ErrorKind::TimedOut, "idle timeout"),
+                        )));
+                    }
+                }
+            }
+            return Poll::Pending;
         }
 
         Poll::Ready(Ok((*a_to_b_count, *b_to_a_count)))
     }
 }
 
+/// Relay with buffers of `size` bytes, allocated for this relay only.
+#[deprecated(
+    note = "use copy_pooled_bidirectional_with_timeout, sized by `relay-buffer`"
+)]
 pub async fn copy_buf_bidirectional_with_timeout<A, B>(
     a: &mut A,
     b: &mut B,
     size: usize,
     a_to_b_timeout_duration: Duration,
     b_to_a_timeout_duration: Duration,
+    idle_timeout_duration: Option<Duration>,
+) -> Result<(u64, u64), CopyBidirectionalError>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    copy_bidirectional_with(
+        a,
+        b,
+        CopyBuffer::new_with_capacity(size)?,
+        CopyBuffer::new_with_capacity(size)?,
+        a_to_b_timeout_duration,
+        b_to_a_timeout_duration,
+        idle_timeout_duration,
+    )
+    .await
+}
+
+/// Relay with buffers of the pool, see `relay-buffer`.
+pub async fn copy_pooled_bidirectional_with_timeout<A, B>(
+    a: &mut A,
+    b: &mut B,
+    a_to_b_timeout_duration: Duration,
+    b_to_a_timeout_duration: Duration,
+    idle_timeout_duration: Option<Duration>,
+) -> Result<(u64, u64), CopyBidirectionalError>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    copy_bidirectional_with(
+        a,
+        b,
+        CopyBuffer::pooled(),
+        CopyBuffer::pooled(),
+        a_to_b_timeout_duration,
+        b_to_a_timeout_duration,
+        idle_timeout_duration,
+    )
+    .await
+}
+
+async fn copy_bidirectional_with<A, B>(
+    a: &mut A,
+    b: &mut B,
+    a_to_b: CopyBuffer,
+    b_to_a: CopyBuffer,
+    a_to_b_timeout_duration: Duration,
+    b_to_a_timeout_duration: Duration,
+    idle_timeout_duration: Option<Duration>,
 ) -> Result<(u64, u64), CopyBidirectionalError>
 where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
@@ -341,14 +463,122 @@ where
     CopyBidirectional {
         a,
         b,
-        a_to_b: TransferState::Running(CopyBuffer::new_with_capacity(size)?),
-        b_to_a: TransferState::Running(CopyBuffer::new_with_capacity(size)?),
+        a_to_b: TransferState::Running(a_to_b),
+        b_to_a: TransferState::Running(b_to_a),
         a_to_b_count: 0,
         b_to_a_count: 0,
         a_to_b_delay: None,
         b_to_a_delay: None,
         a_to_b_timeout_duration,
         b_to_a_timeout_duration,
+        idle_timeout_duration,
+        idle_delay: None,
+        last_transferred: 0,
     }
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+
+    use super::{
+        copy_pooled_bidirectional_with_timeout, CopyBidirectionalError, CopyBuffer,
+        SHRINK_AFTER_SHORT_READS,
+    };
+    use crate::common::buffer_pool::Pool;
+
+    /// a reader of `n` bytes, one at a time
+    struct Trickle(usize);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if self.0 > 0 {
+                self.0 -= 1;
+                buf.put_slice(&[7]);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn copy(buf: &mut CopyBuffer, mut reader: impl AsyncRead + Unpin) -> u64 {
+        let mut writer = tokio::io::sink();
+        futures::future::poll_fn(|cx| {
+            buf.poll_copy(cx, Pin::new(&mut reader), Pin::new(&mut writer))
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_buffer_grows() {
+        let pool = Box::leak(Box::new(Pool::new(16, 1024, 64)));
+        let mut buf = CopyBuffer::with_buffer(pool.take());
+
+        let data = vec![7u8; 1024];
+        assert_eq!(copy(&mut buf, &data[..]).await, 1024);
+        // 16 to 32 to 64 bytes, after 4 full reads each
+        assert_eq!(buf.buf.len(), 64);
+        assert_eq!(pool.stats().grown, 2);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_shrinks() {
+        let pool = Box::leak(Box::new(Pool::new(16, 1024, 64)));
+        let mut pooled = pool.take();
+        assert!(pooled.grow() && pooled.grow());
+        let mut buf = CopyBuffer::with_buffer(pooled);
+
+        // one more read for the EOF, not enough to shrink
+        let n = SHRINK_AFTER_SHORT_READS as usize;
+        copy(&mut buf, Trickle(n - 1)).await;
+        assert_eq!(buf.buf.len(), 64);
+
+        // halved twice
+        let mut buf = CopyBuffer::with_buffer(buf.buf);
+        copy(&mut buf, Trickle(2 * n + 1)).await;
+        assert_eq!(buf.buf.len(), 16);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout() {
+        let (mut a, mut a_peer) = tokio::io::duplex(64);
+        let (mut b, _b_peer) = tokio::io::duplex(64);
+
+        let copy = tokio::spawn(async move {
+            copy_pooled_bidirectional_with_timeout(
+                &mut a,
+                &mut b,
+                Duration::from_secs(10),
+                Duration::from_secs(10),
+                Some(Duration::from_millis(300)),
+            )
+            .await
+        });
+
+        // traffic keeps the connection open
+        for _ in 0..3 {
+            tokio::time::advance(Duration::from_millis(100)).await;
+            a_peer.write_all(b"ping").await.unwrap();
+        }
+        assert!(!copy.is_finished());
+
+        tokio::time::advance(Duration::from_millis(600)).await;
+        match copy.await.unwrap() {
+            Err(CopyBidirectionalError::Other(e)) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::TimedOut)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
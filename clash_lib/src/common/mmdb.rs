@@ -4,28 +4,43 @@ use maxminddb::geoip2;
 use tracing::{debug, info, warn};
 
 use crate::{
-    common::{errors::map_io_error, http::HttpClient, utils::download},
+    common::{
+        errors::map_io_error,
+        http::HttpClient,
+        integrity::{download_verified, Integrity},
+    },
     Error,
 };
 
 pub struct Mmdb {
-    reader: maxminddb::Reader<Vec<u8>>,
+    /// `None` for the empty one
+    reader: Option<maxminddb::Reader<Vec<u8>>>,
 }
 
 impl Mmdb {
     pub async fn new<P: AsRef<Path>>(
         path: P,
         download_url: Option<String>,
+        integrity: &Integrity,
         http_client: HttpClient,
     ) -> Result<Mmdb, Error> {
         debug!("mmdb path: {}", path.as_ref().to_string_lossy());
-        let reader = Self::load_mmdb(path, download_url, &http_client).await?;
-        Ok(Self { reader })
+        let reader =
+            Self::load_mmdb(path, download_url, integrity, &http_client).await?;
+        Ok(Self {
+            reader: Some(reader),
+        })
+    }
+
+    /// One without any IP, for when it can't be loaded.
+    pub fn empty() -> Self {
+        Self { reader: None }
     }
 
     async fn load_mmdb<P: AsRef<Path>>(
         path: P,
         download_url: Option<String>,
+        integrity: &Integrity,
         http_client: &HttpClient,
     ) -> Result<maxminddb::Reader<Vec<u8>>, Error> {
         let mmdb_file = path.as_ref().to_path_buf();
@@ -33,9 +48,11 @@ impl Mmdb {
         if !mmdb_file.exists() {
             if let Some(url) = download_url.as_ref() {
                 info!("downloading mmdb from {}", url);
-                download(url, &mmdb_file, http_client).await.map_err(|x| {
-                    Error::InvalidConfig(format!("mmdb download failed: {}", x))
-                })?;
+                download_verified(url, &mmdb_file, http_client, integrity)
+                    .await
+                    .map_err(|x| {
+                        Error::InvalidConfig(format!("mmdb download failed: {}", x))
+                    })?;
             } else {
                 return Err(Error::InvalidConfig(format!(
                     "mmdb `{}` not found and mmdb_download_url is not set",
@@ -59,14 +76,14 @@ impl Mmdb {
                     fs::remove_file(&mmdb_file)?;
                     if let Some(url) = download_url.as_ref() {
                         info!("downloading mmdb from {}", url);
-                        download(url, &mmdb_file, http_client).await.map_err(
-                            |x| {
+                        download_verified(url, &mmdb_file, http_client, integrity)
+                            .await
+                            .map_err(|x| {
                                 Error::InvalidConfig(format!(
                                     "mmdb download failed: {}",
                                     x
                                 ))
-                            },
-                        )?;
+                            })?;
                         Ok(maxminddb::Reader::open_readfile(&path).map_err(|x| {
                             Error::InvalidConfig(format!(
                                 "cant open mmdb `{}`: {}",
@@ -91,8 +108,9 @@ impl Mmdb {
     }
 
     pub fn lookup(&self, ip: IpAddr) -> std::io::Result<geoip2::Country> {
-        self.reader
-            .lookup::<geoip2::Country>(ip)
-            .map_err(map_io_error)
+        let Some(reader) = &self.reader else {
+            return Err(std::io::ErrorKind::NotFound.into());
+        };
+        reader.lookup::<geoip2::Country>(ip).map_err(map_io_error)
     }
 }
@@ -1,11 +1,17 @@
 pub mod auth;
+pub mod buffer_pool;
 pub mod crypto;
+pub mod der;
 pub mod errors;
 pub mod geodata;
 pub mod http;
+pub mod integrity;
 pub mod io;
 pub mod mmdb;
+#[cfg(target_os = "linux")]
+pub mod splice;
 pub mod timed_future;
+pub mod timing;
 pub mod tls;
 pub mod trie;
 pub mod utils;
@@ -0,0 +1,331 @@
+//! In-kernel relay between two TCP sockets with splice(2), data never
+//! reaches userspace.
+
+use std::{
+    future::Future,
+    io,
+    net::Shutdown,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use tokio::{io::Interest, net::TcpStream, sync::Notify};
+
+use super::io::CopyBidirectionalError;
+
+/// bytes moved by a single splice call
+const CHUNK_SIZE: usize = 64 * 1024;
+
+struct Pipe {
+    r: OwnedFd,
+    w: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe {
+            libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC)
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both were just opened and are owned by nothing else
+        unsafe {
+            Ok(Self {
+                r: OwnedFd::from_raw_fd(fds[0]),
+                w: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Set to have the relay stop splicing, once nothing is left in its pipes.
+#[derive(Default)]
+struct Stop {
+    stopped: AtomicBool,
+    notify: Notify,
+}
+
+impl Stop {
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+
+    async fn wait(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.stopped.load(Ordering::Acquire) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Move everything from `src` to `dst` through a pipe, then shut down the
+/// write half of `dst`. `false` if stopped before the end by `stop`, with
+/// the rest left in `src`.
+/// The pipe is drained before the next read, so `EAGAIN` always comes from
+/// the socket side.
+async fn copy_one(
+    src: &TcpStream,
+    dst: &TcpStream,
+    on_bytes: &(dyn Fn(usize) + Sync),
+    stop: &Stop,
+) -> io::Result<bool> {
+    let pipe = Pipe::new()?;
+    loop {
+        // the pipe is empty while waiting for `src`, it's safe to stop
+        let n = tokio::select! {
+            n = src.async_io(Interest::READABLE, || {
+                splice(src.as_raw_fd(), pipe.w.as_raw_fd(), CHUNK_SIZE)
+            }) => n?,
+            _ = stop.wait() => return Ok(false),
+        };
+        if n == 0 {
+            break;
+        }
+
+        let mut pending = n;
+        while pending > 0 {
+            let m = dst
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.r.as_raw_fd(), dst.as_raw_fd(), pending)
+                })
+                .await?;
+            pending -= m;
+            on_bytes(m);
+        }
+    }
+    socket2::SockRef::from(dst).shutdown(Shutdown::Write)?;
+    Ok(true)
+}
+
+/// The splice counterpart of `copy_buf_bidirectional_with_timeout`: once a
+/// side is done, the other has `half_close_timeout` to finish, and the relay
+/// fails with `TimedOut` after `idle_timeout` without traffic.
+/// Once `interrupt` resolves, splicing stops with nothing read but not yet
+/// written, and `None` is returned for the caller to relay the rest.
+pub async fn copy_bidirectional(
+    a: &TcpStream,
+    b: &TcpStream,
+    on_a_to_b: &(dyn Fn(usize) + Sync),
+    on_b_to_a: &(dyn Fn(usize) + Sync),
+    half_close_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    interrupt: impl Future<Output = ()>,
+) -> Result<Option<(u64, u64)>, CopyBidirectionalError> {
+    let a_to_b_count = AtomicU64::new(0);
+    let b_to_a_count = AtomicU64::new(0);
+    let start = Instant::now();
+    // milliseconds since `start` of the last transfer
+    let last_active = AtomicU64::new(0);
+    let touch =
+        || last_active.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+    let count_a_to_b = |n| {
+        a_to_b_count.fetch_add(n as u64, Ordering::Relaxed);
+        on_a_to_b(n);
+        touch();
+    };
+    let count_b_to_a = |n| {
+        b_to_a_count.fetch_add(n as u64, Ordering::Relaxed);
+        on_b_to_a(n);
+        touch();
+    };
+    let stop = Stop::default();
+    let a_to_b = copy_one(a, b, &count_a_to_b, &stop);
+    let b_to_a = copy_one(b, a, &count_b_to_a, &stop);
+
+    // the other direction has `half_close_timeout` once a side is done, but
+    // all the time it needs to drain its pipe once stopped
+    let finish = |done, other| async move {
+        if done {
+            match tokio::time::timeout(half_close_timeout, other).await {
+                Ok(r) => r,
+                Err(_) => Ok(true),
+            }
+        } else {
+            other.await.map(|_| false)
+        }
+    };
+    // `Ok(false)` if stopped before both directions were done
+    let relay = async {
+        tokio::pin!(a_to_b, b_to_a);
+        tokio::select! {
+            r = &mut a_to_b => {
+                let done = r.map_err(CopyBidirectionalError::LeftClosed)?;
+                finish(done, b_to_a)
+                    .await
+                    .map_err(CopyBidirectionalError::RightClosed)
+            }
+            r = &mut b_to_a => {
+                let done = r.map_err(CopyBidirectionalError::RightClosed)?;
+                finish(done, a_to_b)
+                    .await
+                    .map_err(CopyBidirectionalError::LeftClosed)
+            }
+        }
+    };
+
+    let watchdog = async {
+        let Some(idle_timeout) = idle_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let idle = start.elapsed()
+                - Duration::from_millis(last_active.load(Ordering::Relaxed));
+            if idle >= idle_timeout {
+                return;
+            }
+            tokio::time::sleep(idle_timeout - idle).await;
+        }
+    };
+
+    let interrupt = async {
+        interrupt.await;
+        stop.stop();
+        // the relay ends once both directions stopped
+        std::future::pending::<()>().await
+    };
+
+    let done = tokio::select! {
+        r = relay => r?,
+        _ = watchdog => {
+            return Err(CopyBidirectionalError::Other(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "idle timeout",
+            )))
+        }
+        _ = interrupt => unreachable!(),
+    };
+
+    if !done {
+        return Ok(None);
+    }
+    Ok(Some((
+        a_to_b_count.load(Ordering::Relaxed),
+        b_to_a_count.load(Ordering::Relaxed),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) =
+            tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_splice_relay() {
+        let (mut client, a) = pair().await;
+        let (b, mut remote) = pair().await;
+
+        let uploaded = AtomicU64::new(0);
+        let relay = async {
+            super::copy_bidirectional(
+                &a,
+                &b,
+                &|n| {
+                    uploaded.fetch_add(n as u64, Ordering::Relaxed);
+                },
+                &|_| {},
+                Duration::from_secs(1),
+                None,
+                std::future::pending(),
+            )
+            .await
+        };
+        let peers = async {
+            let payload = vec![7u8; 200_000];
+            client.write_all(&payload).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let mut received = vec![];
+            remote.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, payload);
+
+            remote.write_all(b"pong").await.unwrap();
+            remote.shutdown().await.unwrap();
+            let mut reply = vec![];
+            client.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, b"pong");
+        };
+
+        let (r, _) = tokio::join!(relay, peers);
+        assert_eq!(r.unwrap(), Some((200_000, 4)));
+        assert_eq!(uploaded.load(Ordering::Relaxed), 200_000);
+    }
+
+    #[tokio::test]
+    async fn test_splice_interrupt() {
+        let (mut client, mut a) = pair().await;
+        let (b, mut remote) = pair().await;
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let relay = async {
+            super::copy_bidirectional(
+                &a,
+                &b,
+                &|_| {},
+                &|_| {},
+                Duration::from_secs(1),
+                None,
+                async {
+                    let _ = rx.await;
+                },
+            )
+            .await
+        };
+        let peers = async {
+            client.write_all(b"spliced").await.unwrap();
+            let mut received = [0u8; 7];
+            remote.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"spliced");
+            tx.send(()).unwrap();
+        };
+
+        let (r, _) = tokio::join!(relay, peers);
+        assert_eq!(r.unwrap(), None);
+
+        // whatever comes after is left for the caller
+        client.write_all(b"rest").await.unwrap();
+        let mut rest = [0u8; 4];
+        a.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"rest");
+    }
+}
@@ -0,0 +1,105 @@
+//! The phases of establishing a connection and how long each took.
+//!
+//! The dispatcher runs the phases it knows of with the durations of the
+//! connection in a task local. The DNS lookups and TCP connects deep in
+//! the outbounds record themselves into them with `record_phase`.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+tokio::task_local! {
+    static CURRENT: Arc<PhaseDurations>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectPhase {
+    RuleMatch,
+    Dns,
+    /// the TCP connect to the server or the destination
+    Connect,
+    /// TLS and the proxy protocol, what connecting the outbound took
+    /// besides DNS and TCP connects
+    Handshake,
+}
+
+impl ConnectPhase {
+    pub const ALL: [ConnectPhase; 4] = [
+        ConnectPhase::RuleMatch,
+        ConnectPhase::Dns,
+        ConnectPhase::Connect,
+        ConnectPhase::Handshake,
+    ];
+}
+
+/// Add `elapsed` to `phase` of the connection being dispatched, if any.
+pub fn record_phase(phase: ConnectPhase, elapsed: Duration) {
+    let _ = CURRENT.try_with(|x| x.add(phase, elapsed));
+}
+
+/// How long each phase of a connection took so far.
+#[derive(Default)]
+pub struct PhaseDurations {
+    /// by `ConnectPhase as usize`
+    phases: Mutex<[Duration; 4]>,
+}
+
+impl PhaseDurations {
+    fn add(&self, phase: ConnectPhase, elapsed: Duration) {
+        self.phases.lock().unwrap()[phase as usize] += elapsed;
+    }
+
+    pub fn get(&self, phase: ConnectPhase) -> Duration {
+        self.phases.lock().unwrap()[phase as usize]
+    }
+
+    /// Run `fut` for the connection. The time it takes is `phase`'s, but
+    /// for the DNS lookups and TCP connects recorded within it.
+    pub async fn run<F: Future>(
+        self: &Arc<Self>,
+        phase: ConnectPhase,
+        fut: F,
+    ) -> F::Output {
+        let nested =
+            || self.get(ConnectPhase::Dns) + self.get(ConnectPhase::Connect);
+        let before = nested();
+        let start = Instant::now();
+        let output = CURRENT.scope(self.clone(), fut).await;
+        self.add(phase, start.elapsed().saturating_sub(nested() - before));
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{record_phase, ConnectPhase, PhaseDurations};
+
+    #[tokio::test]
+    async fn test_phases() {
+        let phases = Arc::new(PhaseDurations::default());
+        // not within a connection
+        record_phase(ConnectPhase::Dns, Duration::from_secs(1));
+
+        phases
+            .run(ConnectPhase::RuleMatch, async {
+                record_phase(ConnectPhase::Dns, Duration::from_secs(5));
+            })
+            .await;
+        phases
+            .run(ConnectPhase::Handshake, async {
+                record_phase(ConnectPhase::Dns, Duration::from_secs(1));
+                record_phase(ConnectPhase::Connect, Duration::from_secs(2));
+            })
+            .await;
+
+        assert_eq!(phases.get(ConnectPhase::Dns), Duration::from_secs(6));
+        assert_eq!(phases.get(ConnectPhase::Connect), Duration::from_secs(2));
+        // less the DNS lookups and connects within
+        assert_eq!(phases.get(ConnectPhase::RuleMatch), Duration::ZERO);
+        assert!(phases.get(ConnectPhase::Handshake) < Duration::from_secs(1));
+    }
+}
@@ -8,8 +8,13 @@ use rustls::{
 };
 use tracing::warn;
 
-use rustls::{Certificate, ServerName};
-use std::{sync::Arc, time::SystemTime};
+use rustls::{Certificate, PrivateKey, ServerName};
+use std::{io::BufReader, path::Path, sync::Arc, time::SystemTime};
+
+use crate::{
+    common::{der::spki_of, utils},
+    Error,
+};
 
 pub static GLOBAL_ROOT_STORE: Lazy<Arc<RootCertStore>> =
     Lazy::new(global_root_store);
@@ -95,3 +100,192 @@ impl ServerCertVerifier for NoHostnameTlsVerifier {
         }
     }
 }
+
+/// Parse a `pin-sha256`, the hex or base64 encoded SHA256 of a certificate
+/// or of its public key info.
+pub fn parse_pin(pin: &str) -> Result<Vec<u8>, String> {
+    use base64::Engine;
+    let pin = pin.trim();
+    let decoded = if pin.len() == 64 && pin.bytes().all(|x| x.is_ascii_hexdigit()) {
+        utils::decode_hex(pin).map_err(|x| x.to_string())
+    } else {
+        base64::engine::general_purpose::STANDARD
+            .decode(pin)
+            .map_err(|x| x.to_string())
+    };
+    match decoded {
+        Ok(x) if x.len() == 32 => Ok(x),
+        Ok(_) => Err(format!("not a sha256: {}", pin)),
+        Err(e) => Err(format!("invalid pin-sha256 {}: {}", pin, e)),
+    }
+}
+
+/// Checks the server certificate against pinned hashes, after `inner`
+/// verified it, so a certificate issued by a compromised CA is refused.
+pub struct PinnedTlsVerifier {
+    pub pins: Vec<Vec<u8>>,
+    pub inner: Arc<dyn ServerCertVerifier>,
+}
+
+impl PinnedTlsVerifier {
+    fn pinned(&self, cert: &Certificate) -> bool {
+        let cert_hash = utils::sha256(&cert.0);
+        let spki_hash = spki_of(&cert.0).map(utils::sha256);
+        self.pins
+            .iter()
+            .any(|x| *x == cert_hash || Some(x) == spki_hash.as_ref())
+    }
+}
+
+impl ServerCertVerifier for PinnedTlsVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        if !self.pinned(end_entity) {
+            warn!("certificate of {:?} doesn't match the pin", server_name);
+            return Err(rustls::Error::General(
+                "certificate doesn't match pin-sha256".to_owned(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+}
+
+/// The PEM encoded certificate chain at `path`.
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let f = std::fs::File::open(path)?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(f))?;
+    if certs.is_empty() {
+        return Err(Error::InvalidConfig(format!(
+            "no certificate found in {}",
+            path.to_string_lossy()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// The first PEM encoded private key at `path`.
+pub fn load_private_key(path: &Path) -> Result<PrivateKey, Error> {
+    let f = std::fs::File::open(path)?;
+    let mut reader = BufReader::new(f);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(Error::InvalidConfig(format!(
+        "no private key found in {}",
+        path.to_string_lossy()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerName,
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::{parse_pin, DummyTlsVerifier, PinnedTlsVerifier};
+    use crate::{
+        app::mitm::cert::CertificateAuthority,
+        common::{
+            der::spki_of,
+            utils::{encode_hex, sha256},
+        },
+    };
+
+    struct Fixed(Arc<CertifiedKey>);
+
+    impl ResolvesServerCert for Fixed {
+        fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+            Some(self.0.clone())
+        }
+    }
+
+    async fn handshake(leaf: Arc<CertifiedKey>, pins: Vec<Vec<u8>>) -> bool {
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedTlsVerifier {
+                pins,
+                inner: Arc::new(DummyTlsVerifier),
+            }));
+        let client = TlsConnector::from(Arc::new(config));
+        let server = TlsAcceptor::from(Arc::new(
+            rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(Fixed(leaf))),
+        ));
+
+        let (c, s) = tokio::io::duplex(16384);
+        let (c, _) = tokio::join!(
+            client.connect(ServerName::try_from("example.com").unwrap(), c),
+            server.accept(s)
+        );
+        c.is_ok()
+    }
+
+    #[test]
+    fn test_parse_pin() {
+        let hash = sha256(b"cert");
+        assert_eq!(parse_pin(&encode_hex(&hash)).unwrap(), hash);
+        assert!(parse_pin("7HIpactkIAq2Y49orFOOQKurWxmmSFZhBCoQYcRhJ3Y=").is_ok());
+        assert!(parse_pin("abcd").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pinned() {
+        let (ca, _) = CertificateAuthority::generate().unwrap();
+        let leaf = ca.issue("example.com").unwrap();
+        let der = leaf.cert[0].0.clone();
+
+        assert!(handshake(leaf.clone(), vec![sha256(&der)]).await);
+        let spki = spki_of(&der).unwrap();
+        assert!(handshake(leaf.clone(), vec![sha256(spki)]).await);
+        assert!(!handshake(leaf, vec![sha256(b"another")]).await);
+    }
+}
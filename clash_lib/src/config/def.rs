@@ -1,4 +1,4 @@
-use crate::Error;
+use crate::{common::integrity::Integrity, proxy::utils::PortRange, Error};
 use std::{collections::HashMap, fmt::Display, path::PathBuf, str::FromStr};
 
 use serde::{Deserialize, Serialize};
@@ -50,6 +50,51 @@ impl Display for LogLevel {
     }
 }
 
+#[derive(PartialEq, Serialize, Deserialize, Default, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// a JSON object per line
+    Json,
+}
+
+/// What to do with UDP routed to an outbound that can't carry it.
+#[derive(PartialEq, Serialize, Deserialize, Default, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum UdpFallback {
+    /// drop the packets
+    #[default]
+    Reject,
+    /// send them over DIRECT instead
+    Direct,
+}
+
+/// When the proxies are built. Their connections, TLS sessions and tunnels
+/// are made on first use either way.
+#[derive(PartialEq, Serialize, Deserialize, Default, Copy, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutboundInit {
+    /// when the config is loaded
+    #[default]
+    Eager,
+    /// on first use
+    Lazy,
+    /// in the background after the config is loaded, a few at a time
+    WarmUp,
+}
+
+/// What to do with connections no rule matched.
+#[derive(PartialEq, Serialize, Deserialize, Default, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Unmatched {
+    /// send them over DIRECT
+    #[default]
+    Direct,
+    /// close them
+    Reject,
+}
+
 /// Example
 /// ```yaml
 /// ---
@@ -78,6 +123,8 @@ impl Display for LogLevel {
 ///     - 8.8.8.8
 ///   enhanced-mode: fake-ip
 ///   fake-ip-range: 198.18.0.2/16 # Fake IP addresses pool CIDR
+///   # fake IPs only for the queries hijacked from tun, see `tun.dns-hijack`
+///   # fake-ip-tun-only: true
 ///   # use-hosts: true # lookup hosts and return IP record
 
 ///   # Hostnames in this list will not be resolved with fake IPs
@@ -85,9 +132,12 @@ impl Display for LogLevel {
 ///   # real IP addresses
 ///   # fake-ip-filter:
 ///   #   - '*.lan'
+///   #   - '+.pool.ntp.org'
 ///   #   - localhost.ptlogin2.qq.com
+///   #   # a list of the geosite database
+///   #   - geosite:private
 
-///   # Supports UDP, TCP, DoT, DoH. You can specify the port to connect to.
+///   # Supports UDP, TCP, DoT, DoH, DoQ. You can specify the port to connect to.
 ///   # All DNS questions are sent directly to the nameserver, without proxies
 ///   # involved. Clash answers the DNS question with the first result gathered.
 ///   nameserver:
@@ -95,6 +145,7 @@ impl Display for LogLevel {
 ///     - 1.1.1.1 # default value
 ///     - tls://1.1.1.1:853 # DNS over TLS
 ///     - https://1.1.1.1/dns-query # DNS over HTTPS
+///     - quic://dns.adguard-dns.com # DNS over QUIC
 /// #    - dhcp://en0 # dns from dhcp
 
 /// allow-lan: true
@@ -142,6 +193,10 @@ impl Display for LogLevel {
 ///     proxies:
 ///       - DIRECT
 ///     strategy: round-robin
+///     # the share of each member, 1 if not set, members failing health
+///     # checks are left out while any other is up
+///     weights:
+///       DIRECT: 2
 ///     url: "http://www.gstatic.com/generate_204"
 ///     interval: 300
 
@@ -181,6 +236,12 @@ impl Display for LogLevel {
 ///       - h2
 ///       - http/1.1
 ///     skip-cert-verify: true
+///     # the TLS versions offered, also on vmess and socks5, "1.2" or "1.3"
+///     min-tls-version: "1.2"
+///     max-tls-version: "1.3"
+///     # pins the SHA256 of the server certificate or its public key, hex or base64
+///     pin-sha256:
+///       - 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
 
 /// proxy-providers:
 ///   file-provider:
@@ -210,6 +271,8 @@ impl Display for LogLevel {
 ///   - GEOIP,CN,DIRECT
 ///   - DST-PORT,53,trojan
 ///   - SRC-PORT,7777,DIRECT
+///   - NETWORK,udp,DIRECT
+///   - IN-USER,alice/bob,select
 ///   - MATCH, DIRECT
 /// ...
 /// ```
@@ -232,7 +295,16 @@ pub struct Config {
     /// ```
     pub mixed_port: Option<u16>,
 
-    /// HTTP and SOCKS5 proxy authentication
+    /// HTTP and SOCKS5 proxy authentication, as `user:password`
+    /// the user a connection authenticated as is matched by `IN-USER` rules,
+    /// shown as `inboundUser` by the connections API and has its traffic
+    /// counted by `GET /statistics`
+    /// # Example
+    /// ```yaml
+    /// authentication:
+    ///   - alice:secret
+    ///   - bob:hunter2
+    /// ```
     pub authentication: Vec<String>,
     /// Allow connections to the local-end server from other LAN IP addresses
     #[deprecated = "dont use. see `bind_address`"]
@@ -245,17 +317,60 @@ pub struct Config {
     /// - and if you don't want `allow_lan` to be enabled, you should set this
     ///   to `localhost` or `127.1`
     pub bind_address: String,
+    /// socket options of the HTTP, SOCKS5 and mixed listeners, sockets handed
+    /// over by socket activation keep the ones set by the service manager
+    /// # Example
+    /// ```yaml
+    /// inbound-tcp:
+    ///   # accept TCP Fast Open from clients, Linux and macOS only
+    ///   fast-open: true
+    ///   # seconds to wait for the first bytes of a connection before
+    ///   # accepting it, Linux only, 0 to accept right away
+    ///   defer-accept: 10
+    /// ```
+    pub inbound_tcp: InboundTcp,
     /// Clash router working mode
     /// Either `rule`, `global` or `direct`
     pub mode: RunMode,
     /// Log level
     /// Either `debug`, `info`, `warning`, `error` or `off`
     pub log_level: LogLevel,
+    /// Log format
+    /// Either `text` or `json`, one object per line with `time`, `level`,
+    /// `module`, `message`, `connection_id` and `fields`
+    pub log_format: LogFormat,
+    /// Log to a file besides the console, rotated by size
+    /// # Example
+    /// ```yaml
+    /// log-file:
+    ///   path: logs/clash.log
+    ///   # MB, the file is rotated when it grows past it, 0 to never rotate
+    ///   max-size: 10
+    ///   # rotated files kept
+    ///   max-backups: 5
+    ///   # days, older rotated files are removed, 0 to keep them
+    ///   max-age: 7
+    ///   # keep logging to stdout too
+    ///   console: true
+    /// ```
+    pub log_file: LogFile,
     /// DNS client/server settings
     pub dns: DNS,
     /// Profile settings
     pub profile: Profile,
     /// Proxy settings
+    ///
+    /// Any proxy can cap its TCP connections to each destination host, the
+    /// ones past `max` waiting up to `wait` seconds for a slot, 30 by
+    /// default, or rejected right away with 0.
+    /// ```yaml
+    /// proxies:
+    ///   - name: ss
+    ///     type: ss
+    ///     per-host-limit:
+    ///       max: 6
+    ///       wait: 30
+    /// ```
     #[serde(rename = "proxies")]
     pub proxy: Vec<HashMap<String, Value>>,
     #[serde(rename = "proxy-groups")]
@@ -264,8 +379,22 @@ pub struct Config {
     #[serde(rename = "rules")]
     /// Rule settings
     pub rule: Vec<String>,
-    /// Hosts
-    pub hosts: HashMap<String, String>,
+    /// the target of connections from an inbound that no rule matched, by
+    /// inbound name, `HTTP`, `SOCKS5`, `Mixed` or `TUN`, taking over from the
+    /// `MATCH` rule
+    /// # Example
+    /// ```yaml
+    /// inbound-final:
+    ///   TUN: auto
+    ///   SOCKS5: DIRECT
+    /// ```
+    pub inbound_final: HashMap<String, String>,
+    /// what to do with connections that neither a rule, `MATCH` included,
+    /// nor an `inbound-final` applies to, either `direct` or `reject`
+    pub unmatched: Unmatched,
+    /// Hosts, a domain maps to an IP, a list of them or another domain
+    /// resolved in its place
+    pub hosts: HashMap<String, HostValue>,
     /// Country database path relative to the $CWD
     pub mmdb: String,
     /// Country database download url
@@ -274,6 +403,29 @@ pub struct Config {
     pub geosite: String,
     /// Geosite database download url
     pub geosite_download_url: Option<String>,
+    /// checks of the downloaded country database, a fixed checksum or an
+    /// Ed25519 signature, unchecked by default
+    /// the same options go into http proxy and rule providers
+    /// a signature file has the version of the content, an optional expiry
+    /// and the signature of `clash-rs signed content\nversion: <version>\n`,
+    /// `expires: <unix seconds>\n` if set, then the content; a version older
+    /// than the last one accepted is rejected
+    /// ```text
+    /// version: 42
+    /// expires: 1767225600
+    /// signature: <base64>
+    /// ```
+    /// # Example
+    /// ```yaml
+    /// mmdb-integrity:
+    ///   sha256: 2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae
+    /// geosite-integrity:
+    ///   public-key: 11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=
+    ///   # `<url>.sig` by default
+    ///   signature-url: https://example.com/geosite.dat.sig
+    /// ```
+    pub mmdb_integrity: Integrity,
+    pub geosite_integrity: Integrity,
 
     // these options has default vals,
     // and needs extra processing
@@ -286,6 +438,49 @@ pub struct Config {
     pub external_controller: Option<String>,
     /// dashboard folder path relative to the $CWD
     pub external_ui: Option<String>,
+    /// dashboard sub folder under `external-ui`, allows keeping several
+    /// dashboards side by side
+    pub external_ui_name: Option<String>,
+    /// dashboard zip bundle url
+    /// the bundle is downloaded into the dashboard folder if it's missing,
+    /// and can be upgraded later via `POST /upgrade/ui`
+    pub external_ui_url: Option<String>,
+    /// external controller over TLS
+    /// # Example
+    /// ```yaml
+    /// external-controller-tls:
+    ///   listen: 0.0.0.0:9443
+    ///   certificate: cert.pem
+    ///   private-key: key.pem
+    ///   # optional, only accept clients presenting a certificate
+    ///   # signed by this CA
+    ///   client-ca: ca.pem
+    /// ```
+    pub external_controller_tls: Option<ControllerTls>,
+    /// external controller unix socket path relative to the $CWD
+    /// the socket is only accessible by the current user, no secret required
+    pub external_controller_unix: Option<String>,
+    /// external controller windows named pipe, e.g. `\\.\pipe\clash-rs`
+    /// remote clients are rejected, no secret required
+    pub external_controller_pipe: Option<String>,
+    /// external controller gRPC listen address, see `controller.proto`
+    /// uses the same secret as a bearer token in the `authorization` metadata
+    pub external_controller_grpc: Option<String>,
+    /// serve a PAC file pointing browsers at the inbound ports from the
+    /// external controller, without the secret
+    /// # Example
+    /// ```yaml
+    /// pac:
+    ///   path: /proxy.pac
+    ///   # the DOMAIN, DOMAIN-SUFFIX and DOMAIN-KEYWORD rules before any
+    ///   # other rule are checked by the browser, so that DIRECT domains
+    ///   # don't go through the proxy at all
+    ///   direct-rules: true
+    ///   # the proxy host in the file, by default the host the file was
+    ///   # fetched from
+    ///   proxy-host: 192.168.1.2
+    /// ```
+    pub pac: Option<Pac>,
     /// external controller secret
     pub secret: Option<String>,
     #[serde(rename = "interface-name")]
@@ -297,14 +492,278 @@ pub struct Config {
     /// # Note
     /// - not implemented yet
     pub routing_mask: Option<u32>,
+    /// local ports of the outbound sockets, e.g. for a firewall allowing
+    /// only those or to tell clash-rs traffic apart, any port by default.
+    /// A proxy can have a `port-range` of its own.
+    /// # Example
+    /// ```yaml
+    /// outbound-port-range: 40000-40999
+    /// proxies:
+    ///   - name: ss
+    ///     type: ss
+    ///     port-range: 41000-41099
+    /// ```
+    pub outbound_port_range: Option<String>,
+    /// when proxies, including those of providers, are built: `eager` when
+    /// the config is loaded, `lazy` on first use or `warm-up` in the
+    /// background, for configs with many proxies to start quickly
+    pub outbound_init: OutboundInit,
+    /// seconds a TCP connection may go without traffic in either direction
+    /// before it's closed, 0 to leave idle connections open
+    pub tcp_idle_timeout: u64,
+    /// seconds a UDP session may go without traffic before it's removed,
+    /// greater than 0 like the overrides
+    pub udp_idle_timeout: u64,
+    /// UDP idle timeouts of specific protocols, by the destination port
+    /// # Example
+    /// ```yaml
+    /// udp-idle-timeout: 60
+    /// udp-idle-timeout-overrides:
+    ///   # port 53
+    ///   dns: 10
+    ///   # port 443
+    ///   quic: 300
+    ///   # any other port or range of ports, the narrowest matching one
+    ///   # taking precedence over the above, the lowest of equally narrow
+    ///   # ones
+    ///   ports:
+    ///     3478: 120
+    ///     27000-27100: 600
+    /// ```
+    pub udp_idle_timeout_overrides: UdpIdleTimeoutOverrides,
+    /// what to do with UDP when the outbound it's routed to doesn't support
+    /// UDP, either `reject` or `direct`
+    pub udp_fallback: UdpFallback,
     #[serde(rename = "proxy-providers")]
     /// proxy provider settings
+    ///
+    /// The payload of a provider may declare `proxy-groups` of its proxies
+    /// too, added as `<provider>/<group>` and using its proxies as
+    /// `<provider>/<proxy>`, e.g. a group `auto` of a provider `sub` is
+    /// used as `sub/auto`. They're read at startup, changes to them apply
+    /// on reload.
     pub proxy_provider: Option<HashMap<String, HashMap<String, Value>>>,
     #[serde(rename = "rule-providers")]
     /// rule provider settings
+    /// http providers are only used if they pass the checks, if any
+    /// # Example
+    /// ```yaml
+    /// rule-providers:
+    ///   ads:
+    ///     type: http
+    ///     url: https://example.com/ads.yaml
+    ///     path: ./ads.yaml
+    ///     interval: 86400
+    ///     behavior: domain
+    ///     # signed with the key's private half at <url>.sig
+    ///     public-key: 11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=
+    ///   # made of other providers with union, intersection or difference,
+    ///   # the latter being the first minus the others
+    ///   ads-but-mine:
+    ///     type: combine
+    ///     operator: difference
+    ///     providers: [ads, my-exceptions]
+    /// ```
     pub rule_provider: Option<HashMap<String, HashMap<String, Value>>>,
+    /// when and how many http providers may update at once, e.g. so that
+    /// many sharing an interval don't saturate a slow uplink. The window
+    /// applies to scheduled updates only, the limit to every fetch.
+    /// # Example
+    /// ```yaml
+    /// provider-update:
+    ///   # local time, may span midnight
+    ///   window: 03:00-05:00
+    ///   # 0 for no limit
+    ///   concurrency: 2
+    /// ```
+    pub provider_update: ProviderUpdate,
     /// experimental settings, if any
+    /// # Example
+    /// ```yaml
+    /// experimental:
+    ///   # WASM plugins that inspect TCP connections before they're routed,
+    ///   # in order. needs the `wasm-plugins` feature
+    ///   plugins:
+    ///     - path: plugins/block-ads.wasm
+    ///       # instructions per connection, the plugin is skipped past it
+    ///       fuel: 10000000
+    ///       # MiB of linear memory
+    ///       memory: 16
+    /// ```
     pub experimental: Option<Experimental>,
+    /// event notifications
+    /// # Example
+    /// ```yaml
+    /// events:
+    ///   webhooks:
+    ///     - url: https://example.com/hook
+    ///       # all events are sent when omitted
+    ///       events: [proxy-down, provider-update-failed]
+    ///       headers:
+    ///         Authorization: Bearer token
+    ///   # fire `high-error-rate` when more than half of the connections to
+    ///   # an outbound fail within a minute
+    ///   error-rate:
+    ///     threshold: 0.5
+    ///     window: 60
+    ///     min-connections: 20
+    /// ```
+    pub events: Events,
+    /// config reloading
+    /// the config is reloaded on `PUT /configs`, SIGHUP, or when the file
+    /// changes if `watch` is set
+    /// # Example
+    /// ```yaml
+    /// reload:
+    ///   watch: true
+    ///   # seconds to let connections finish before they're closed, when
+    ///   # proxies or rules have changed
+    ///   drain-timeout: 30
+    ///   # close right away the connections through a proxy that a reload
+    ///   # removed or a selector switched away from, they're listed by
+    ///   # `GET /connections/aborted`
+    ///   abort-stale-connections: true
+    /// ```
+    pub reload: Reload,
+    /// on SIGTERM or ctrl-c the listeners stop first, then connections get
+    /// some time to finish before statistics and caches are saved
+    /// # Example
+    /// ```yaml
+    /// shutdown:
+    ///   # seconds, 0 to close connections right away
+    ///   drain-timeout: 10
+    /// ```
+    pub shutdown: Shutdown,
+    /// check the system clock against an NTP server, VMess handshakes fail
+    /// when it's off by more than two minutes
+    /// # Example
+    /// ```yaml
+    /// ntp-service:
+    ///   enable: true
+    ///   server: time.apple.com
+    ///   port: 123
+    ///   # minutes between checks
+    ///   interval: 30
+    ///   # correct the timestamps sent by VMess and simple-obfs by the
+    ///   # measured offset, only warn if false
+    ///   compensate: true
+    /// ```
+    pub ntp_service: NtpService,
+    /// bandwidth limits in bytes per second, each direction is limited
+    /// separately, 0 or absent for unlimited
+    /// can be changed at runtime with `PUT /bandwidth`
+    /// # Example
+    /// ```yaml
+    /// bandwidth:
+    ///   # each connection
+    ///   connection: 1048576
+    ///   # all connections from a source IP
+    ///   source: 4194304
+    ///   # all connections through an outbound
+    ///   proxies:
+    ///     ss01: 2097152
+    /// ```
+    pub bandwidth: Bandwidth,
+    /// the buffers relayed connections are copied through, two for each,
+    /// taken from a pool rather than allocated for each connection, and the
+    /// kernel buffers of TCP sockets
+    /// # Example
+    /// ```yaml
+    /// relay-buffer:
+    ///   # bytes of each buffer
+    ///   size: 16384
+    ///   # bytes of idle buffers kept for reuse
+    ///   budget: 8388608
+    ///   # grow the buffers of streams that keep filling them, up to
+    ///   # max-size, for fast single streams over high latency links,
+    ///   # they shrink back once the streams slow down
+    ///   auto: true
+    ///   max-size: 262144
+    ///   # SO_RCVBUF and SO_SNDBUF of dialed and listening sockets, the OS
+    ///   # default if 0, which keeps the kernel's autotuning
+    ///   socket-recv: 4194304
+    ///   socket-send: 4194304
+    /// ```
+    pub relay_buffer: RelayBuffer,
+    /// stop dialing a proxy for a while once most of its recent dials failed
+    /// to reach its server, its connections failing at once or going to
+    /// `reroute`; DIRECT, REJECT and groups are never cut off
+    /// # Example
+    /// ```yaml
+    /// circuit-breaker:
+    ///   enable: true
+    ///   # ratio of failed dials in a window that opens the circuit
+    ///   threshold: 0.5
+    ///   # seconds
+    ///   window: 30
+    ///   # outbounds with fewer dials in a window are left alone
+    ///   min-dials: 5
+    ///   # seconds the circuit stays open
+    ///   open-for: 15
+    ///   reroute: DIRECT
+    /// ```
+    pub circuit_breaker: CircuitBreaker,
+    /// read the host name from the first bytes of TLS and HTTP connections,
+    /// and the first packets of QUIC ones
+    /// # Example
+    /// ```yaml
+    /// sniffer:
+    ///   enable: true
+    ///   # route and dial by the sniffed host instead of the IP
+    ///   override-destination: true
+    ///   # all protocols on their usual ports if absent
+    ///   sniff:
+    ///     TLS:
+    ///       ports: [443, 8443]
+    ///     HTTP:
+    ///       ports: [80, 8080-8880]
+    ///       override-destination: false
+    ///     QUIC:
+    ///       ports: [443]
+    ///   # sniff these even when the client connects by domain
+    ///   force-domain:
+    ///     - +.v2ex.com
+    ///   # keep the original destination for these
+    ///   skip-domain:
+    ///     - +.push.apple.com
+    /// ```
+    pub sniffer: Sniffer,
+    /// decrypt HTTPS to the listed hosts and rewrite their requests. clients
+    /// must trust the CA, which is generated if the files don't exist. only
+    /// ECDSA P-256 CA keys are supported
+    /// # Example
+    /// ```yaml
+    /// mitm:
+    ///   enable: true
+    ///   ca-cert: mitm-ca.crt
+    ///   ca-key: mitm-ca.key
+    ///   hosts:
+    ///     - +.example.com
+    ///   # checked in order against the full URL, of the host intercepted
+    ///   # whatever the Host header says, the first reject, redirect or mock
+    ///   # wins
+    ///   rewrite:
+    ///     - url: ^https?://example\.com/ads/
+    ///       action: reject
+    ///     - url: ^https://example\.com/old/(.*)
+    ///       action: redirect
+    ///       location: https://example.com/new/$1
+    ///     - url: ^https://api\.example\.com/config
+    ///       action: mock
+    ///       status: 200
+    ///       headers:
+    ///         Content-Type: application/json
+    ///       body: '{"ads": false}'
+    ///     - url: ^https://example\.com/
+    ///       action: request-header
+    ///       remove: [X-Tracking-Id, Referer]
+    ///       set:
+    ///         DNT: "1"
+    ///     - url: ^https://example\.com/
+    ///       action: response-header
+    ///       remove: [Set-Cookie]
+    /// ```
+    pub mitm: Mitm,
 
     /// tun settings
     /// # Example
@@ -312,17 +771,34 @@ pub struct Config {
     /// tun:
     ///   enable: true
     ///   device-id: "dev://utun1989"
+    ///   mtu: 9000
+    ///   # coalesce TCP segments with the kernel, Linux only
+    ///   gso: true
+    ///   # ping for real, rather than getting host unreachable
+    ///   icmp-relay: true
+    ///   # routed into the tun
+    ///   route-address:
+    ///     - 0.0.0.0/1
+    ///     - 128.0.0.0/1
+    ///   # but these keep using the other routes, e.g. of a corporate VPN,
+    ///   # as do the proxy servers; alone, they're carved out of the default
+    ///   # route
+    ///   route-exclude-address:
+    ///     - 10.0.0.0/8
+    ///     - 192.168.0.0/16
     /// ```
     pub tun: Option<HashMap<String, Value>>,
 }
 
+/// Loads the file with its overrides applied, see [`super::overrides`].
 impl TryFrom<PathBuf> for Config {
     type Error = Error;
 
     fn try_from(value: PathBuf) -> Result<Self, Self::Error> {
-        let content = std::fs::read_to_string(value)?;
-        let config = content.parse::<Config>()?;
-        Ok(config)
+        let config = super::overrides::load(&value)?;
+        serde_yaml::from_value(config).map_err(|x| {
+            Error::InvalidConfig(format!("could not parse config: {}", x))
+        })
     }
 }
 
@@ -331,10 +807,7 @@ impl FromStr for Config {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         serde_yaml::from_str(s).map_err(|x| {
-            Error::InvalidConfig(format!(
-                "cound not parse config content {}: {}",
-                s, x
-            ))
+            Error::InvalidConfig(format!("could not parse config: {}", x))
         })
     }
 }
@@ -351,30 +824,60 @@ impl Default for Config {
             authentication: Default::default(),
             allow_lan: Default::default(),
             bind_address: String::from("*"),
+            inbound_tcp: Default::default(),
             mode: Default::default(),
             log_level: Default::default(),
+            log_format: Default::default(),
+            log_file: Default::default(),
             ipv6: Default::default(),
             external_controller: Default::default(),
+            external_controller_tls: Default::default(),
+            external_controller_unix: Default::default(),
+            external_controller_pipe: Default::default(),
+            external_controller_grpc: Default::default(),
+            pac: Default::default(),
             external_ui: Default::default(),
+            external_ui_name: Default::default(),
+            external_ui_url: Default::default(),
             secret: Default::default(),
             interface: Default::default(),
             routing_mask: Default::default(),
+            outbound_port_range: Default::default(),
+            outbound_init: Default::default(),
+            tcp_idle_timeout: 0,
+            udp_idle_timeout: 10,
+            udp_idle_timeout_overrides: Default::default(),
+            udp_fallback: Default::default(),
             proxy_provider: Default::default(),
             rule_provider: Default::default(),
+            provider_update: Default::default(),
             hosts: Default::default(),
             dns: Default::default(),
             experimental: Default::default(),
+            events: Default::default(),
+            reload: Default::default(),
+            shutdown: Default::default(),
+            ntp_service: Default::default(),
+            bandwidth: Default::default(),
+            relay_buffer: Default::default(),
+            circuit_breaker: Default::default(),
+            sniffer: Default::default(),
+            mitm: Default::default(),
             profile: Default::default(),
             proxy: Default::default(),
             proxy_group: Default::default(),
             rule: Default::default(),
+            inbound_final: Default::default(),
+            unmatched: Default::default(),
             mmdb: "Country.mmdb".to_string(),
             mmdb_download_url: Some(
                 "https://github.com/Loyalsoldier/geoip/releases/download/202307271745/Country.mmdb"
                     .to_owned(),
             ),
+            mmdb_integrity: Default::default(),
             geosite: "geosite.dat".to_string(),
             geosite_download_url: Some("https://github.com/Loyalsoldier/v2ray-rules-dat/releases/download/202406182210/geosite.dat".to_owned()),
+            geosite_integrity: Default::default(),
             tun: Default::default(),
         }
     }
@@ -387,6 +890,14 @@ pub enum DNSListen {
     Multiple(HashMap<String, String>),
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum HostValue {
+    /// an IP, or a domain the host is an alias of
+    One(String),
+    Many(Vec<String>),
+}
+
 /// DNS client/server settings
 /// This section is optional. When not present, the DNS server will be disabled
 /// and system DNS config will be used # Example
@@ -399,6 +910,35 @@ pub enum DNSListen {
 ///     tcp: 127.0.0.1:5353
 ///     doh: 127.0.0.1:5354
 ///     dot: 127.0.0.1:5355
+///   # answer from the expired cache when all upstreams fail or SERVFAIL
+///   serve-stale:
+///     enable: true
+///     # seconds an answer is kept past its expiry
+///     max-age: 86400
+///     # TTL of the stale answers
+///     answer-ttl: 30
+///   # answers are cached for their TTL, kept within these seconds
+///   cache-ttl:
+///     min: 0
+///     max: 86400
+///     # NXDOMAIN, empty and SERVFAIL answers, capped by their SOA's TTL
+///     negative: 30
+///   # how the nameservers of a list are asked: race, all at once, or
+///   # sequential/first-healthy, one at a time
+///   strategy: race
+///   # seconds before a query is given up
+///   query-timeout: 10
+///   # the certificate of the doh and dot listeners, paths relative to the
+///   # $CWD, a test certificate is used without it
+///   tls:
+///     certificate: cert.pem
+///     private-key: key.pem
+///     # the hostname DoH requests must be for
+///     hostname: dns.example.com
+///   # answered before any nameserver is asked
+///   records:
+///     - nas.home.lan A 192.168.1.10
+///     - media.home.lan CNAME nas.home.lan
 /// ```
 
 #[derive(Serialize, Deserialize)]
@@ -418,18 +958,36 @@ pub struct DNS {
     /// Fallback DNS filter
     pub fallback_filter: FallbackFilter,
     /// DNS server listening address. If not present, the DNS server will be
-    /// disabled.
+    /// disabled. A single address is listened on over both UDP and TCP.
     pub listen: Option<DNSListen>,
+    /// Certificate of the DoH and DoT listeners
+    pub tls: Option<DNSTls>,
     /// Whether to use fake IP addresses
     pub enhanced_mode: DNSMode,
     /// Fake IP addresses pool CIDR
     pub fake_ip_range: String,
-    /// Fake IP addresses filter
+    /// Domains answered with their real IPs in fake IP mode, wildcards or
+    /// `geosite:` lists
     pub fake_ip_filter: Vec<String>,
+    /// Give fake IPs to the queries hijacked from the tun device only, the
+    /// DNS listener answering with real IPs that applications may cache
+    /// across restarts
+    pub fake_ip_tun_only: bool,
     /// Default nameservers, used to resolve DoH hostnames
     pub default_nameserver: Vec<String>,
     /// Lookup domains via specific nameservers
     pub nameserver_policy: HashMap<String, String>,
+    /// Answer with expired cached answers when all upstreams fail, RFC 8767
+    pub serve_stale: ServeStale,
+    /// Bounds of how long answers are cached, for their TTL otherwise
+    pub cache_ttl: CacheTtl,
+    /// Static records, `name TYPE value` of type A, AAAA, CNAME or TXT,
+    /// answered before any nameserver is asked
+    pub records: Vec<String>,
+    /// How the nameservers of a list are queried
+    pub strategy: DNSStrategy,
+    /// Seconds before a query to the nameservers is given up
+    pub query_timeout: u64,
 }
 
 impl Default for DNS {
@@ -442,14 +1000,87 @@ impl Default for DNS {
             fallback: Default::default(),
             fallback_filter: Default::default(),
             listen: Default::default(),
+            tls: Default::default(),
             enhanced_mode: Default::default(),
             fake_ip_range: String::from("198.18.0.1/16"),
             fake_ip_filter: Default::default(),
+            fake_ip_tun_only: Default::default(),
             default_nameserver: vec![
                 String::from("114.114.114.114"),
                 String::from("8.8.8.8"),
             ],
             nameserver_policy: Default::default(),
+            serve_stale: Default::default(),
+            cache_ttl: Default::default(),
+            records: Default::default(),
+            strategy: Default::default(),
+            query_timeout: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ServeStale {
+    pub enable: bool,
+    /// seconds
+    pub max_age: u64,
+    /// seconds
+    pub answer_ttl: u32,
+}
+
+impl Default for ServeStale {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_age: 86400,
+            answer_ttl: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DNSTls {
+    /// Certificate chain path relative to the $CWD, PEM encoded
+    pub certificate: String,
+    /// Private key path relative to the $CWD, PEM encoded
+    pub private_key: String,
+    /// The hostname DoH requests must be for, any without it
+    pub hostname: Option<String>,
+}
+
+/// How a query is sent to the nameservers of a list.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DNSStrategy {
+    /// to all of them at once, the first answer wins
+    #[default]
+    Race,
+    /// to one at a time in order, until one answers
+    Sequential,
+    /// to one at a time, those that failed lately last
+    FirstHealthy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct CacheTtl {
+    /// seconds
+    pub min: u32,
+    /// seconds
+    pub max: u32,
+    /// seconds a name that doesn't exist, has no records of the type or
+    /// failed upstream is cached at most, 0 not to cache them
+    pub negative: u32,
+}
+
+impl Default for CacheTtl {
+    fn default() -> Self {
+        Self {
+            min: 0,
+            max: 86400,
+            negative: 30,
         }
     }
 }
@@ -487,7 +1118,376 @@ impl Default for FallbackFilter {
 }
 
 #[derive(Serialize, Deserialize, Default)]
-pub struct Experimental {}
+#[serde(rename_all = "kebab-case", default)]
+pub struct Experimental {
+    pub plugins: Vec<Plugin>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Plugin {
+    /// the .wasm file, relative to the working directory
+    pub path: String,
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+    /// MiB
+    #[serde(default = "default_plugin_memory")]
+    pub memory: usize,
+}
+
+fn default_plugin_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_plugin_memory() -> usize {
+    16
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ControllerTls {
+    /// The address the TLS controller listens on
+    pub listen: String,
+    /// Certificate chain path relative to the $CWD, PEM encoded
+    pub certificate: String,
+    /// Private key path relative to the $CWD, PEM encoded
+    pub private_key: String,
+    /// CA path relative to the $CWD, PEM encoded
+    /// when set, clients must present a certificate signed by this CA
+    pub client_ca: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Pac {
+    pub path: String,
+    pub direct_rules: bool,
+    pub proxy_host: Option<String>,
+}
+
+impl Default for Pac {
+    fn default() -> Self {
+        Self {
+            path: "/proxy.pac".to_owned(),
+            direct_rules: false,
+            proxy_host: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Events {
+    pub webhooks: Vec<Webhook>,
+    pub error_rate: ErrorRate,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Webhook {
+    pub url: String,
+    /// event types to send, all when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// extra request headers
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct CircuitBreaker {
+    pub enable: bool,
+    pub threshold: f64,
+    pub window: u64,
+    pub min_dials: u64,
+    pub open_for: u64,
+    /// the outbound to use while the circuit is open, none to fail
+    pub reroute: Option<String>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            threshold: 0.5,
+            window: 30,
+            min_dials: 5,
+            open_for: 15,
+            reroute: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ErrorRate {
+    /// ratio of failed connections to trigger the event
+    pub threshold: f64,
+    /// window in seconds
+    pub window: u64,
+    /// outbounds with fewer connections in a window are ignored
+    pub min_connections: u64,
+}
+
+impl Default for ErrorRate {
+    fn default() -> Self {
+        Self {
+            threshold: 0.5,
+            window: 60,
+            min_connections: 20,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ProviderUpdate {
+    /// `HH:MM-HH:MM` in local time, any time if unset
+    pub window: Option<String>,
+    /// fetches at once, 0 for no limit
+    pub concurrency: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Reload {
+    /// reload when the config file changes
+    pub watch: bool,
+    /// seconds
+    pub drain_timeout: u64,
+    pub abort_stale_connections: bool,
+}
+
+impl Default for Reload {
+    fn default() -> Self {
+        Self {
+            watch: false,
+            drain_timeout: 30,
+            abort_stale_connections: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct LogFile {
+    /// relative to the working directory, no file logging if absent
+    pub path: Option<String>,
+    /// MB
+    pub max_size: u64,
+    pub max_backups: usize,
+    /// days
+    pub max_age: u64,
+    pub console: bool,
+}
+
+impl Default for LogFile {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_size: 10,
+            max_backups: 5,
+            max_age: 0,
+            console: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct InboundTcp {
+    pub fast_open: bool,
+    /// seconds
+    pub defer_accept: u32,
+}
+
+impl Default for InboundTcp {
+    fn default() -> Self {
+        Self {
+            fast_open: true,
+            defer_accept: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Shutdown {
+    /// seconds
+    pub drain_timeout: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct NtpService {
+    pub enable: bool,
+    pub server: String,
+    pub port: u16,
+    /// minutes
+    pub interval: u64,
+    pub compensate: bool,
+}
+
+impl Default for NtpService {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            server: "time.apple.com".to_owned(),
+            port: 123,
+            interval: 30,
+            compensate: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct UdpIdleTimeoutOverrides {
+    pub dns: Option<u64>,
+    pub quic: Option<u64>,
+    pub ports: HashMap<PortRange, u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct RelayBuffer {
+    pub size: usize,
+    pub budget: usize,
+    pub auto: bool,
+    pub max_size: usize,
+    pub socket_recv: usize,
+    pub socket_send: usize,
+}
+
+impl Default for RelayBuffer {
+    fn default() -> Self {
+        Self {
+            size: 4 * 1024,
+            budget: 4 * 1024 * 1024,
+            auto: false,
+            max_size: 256 * 1024,
+            socket_recv: 0,
+            socket_send: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Bandwidth {
+    pub connection: u64,
+    pub source: u64,
+    pub proxies: HashMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Sniffer {
+    pub enable: bool,
+    pub override_destination: bool,
+    pub sniff: Option<SniffProtocols>,
+    pub force_domain: Vec<String>,
+    pub skip_domain: Vec<String>,
+}
+
+impl Default for Sniffer {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            override_destination: true,
+            sniff: None,
+            force_domain: Vec::new(),
+            skip_domain: Vec::new(),
+        }
+    }
+}
+
+/// a protocol absent here isn't sniffed
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SniffProtocols {
+    #[serde(rename = "TLS")]
+    pub tls: Option<SniffProtocol>,
+    #[serde(rename = "HTTP")]
+    pub http: Option<SniffProtocol>,
+    #[serde(rename = "QUIC")]
+    pub quic: Option<SniffProtocol>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct SniffProtocol {
+    /// all ports if empty
+    pub ports: Vec<PortRange>,
+    /// overrides the `override-destination` of the sniffer
+    pub override_destination: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Mitm {
+    pub enable: bool,
+    pub ca_cert: String,
+    pub ca_key: String,
+    pub hosts: Vec<String>,
+    pub rewrite: Vec<MitmRewrite>,
+}
+
+impl Default for Mitm {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            ca_cert: "mitm-ca.crt".to_owned(),
+            ca_key: "mitm-ca.key".to_owned(),
+            hosts: Vec::new(),
+            rewrite: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MitmRewrite {
+    /// a regex on the full URL
+    pub url: String,
+    #[serde(flatten)]
+    pub action: MitmAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum MitmAction {
+    /// answer 403 without reaching the server
+    Reject,
+    /// answer 302 to `location`, in which `$1` or `${name}` are the groups
+    /// of `url`
+    Redirect { location: String },
+    /// answer with this response without reaching the server
+    Mock {
+        #[serde(default = "default_mock_status")]
+        status: u16,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        #[serde(default)]
+        body: String,
+    },
+    RequestHeader {
+        #[serde(default)]
+        set: HashMap<String, String>,
+        #[serde(default)]
+        remove: Vec<String>,
+    },
+    ResponseHeader {
+        #[serde(default)]
+        set: HashMap<String, String>,
+        #[serde(default)]
+        remove: Vec<String>,
+    },
+}
+
+fn default_mock_status() -> u16 {
+    200
+}
 
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
@@ -512,7 +1512,12 @@ impl Default for Profile {
 mod tests {
     use serde_yaml::Value;
 
-    use super::Config;
+    use crate::{
+        config::internal::rule::{Resolve, RuleType},
+        session::Network,
+    };
+
+    use super::{Config, UdpFallback};
 
     #[test]
     fn parse_simple() {
@@ -595,16 +1600,23 @@ interface-name: en0
 # fwmark on Linux only
 routing-mark: 6666
 
+# Local ports of the outbound sockets, a proxy can set its own `port-range`
+# outbound-port-range: 40000-40999
+
 # Static hosts for DNS server and connection establishment (like /etc/hosts)
 #
 # Wildcard hostnames are supported (e.g. *.clash.dev, *.foo.*.example.com)
 # Non-wildcard domain names have a higher priority than wildcard domain names
 # e.g. foo.example.com > *.example.com > .example.com
 # P.S. +.foo.com equals to .foo.com and foo.com
+# A host can have several IPs, or be an alias of another domain (like a
+# CNAME), looked up in the hosts first and then resolved
 hosts:
   # '*.clash.dev': 127.0.0.1
   # '.dev': 127.0.0.1
   # 'alpha.clash.dev': '::1'
+  # 'beta.clash.dev': [10.0.0.1, 10.0.0.2, 'fd00::1']
+  # 'gamma.clash.dev': alpha.clash.dev
 
 profile:
   # Store the `select` results in $HOME/.config/clash/.cache
@@ -636,7 +1648,9 @@ dns:
   # real IP addresses
   # fake-ip-filter:
   #   - '*.lan'
+  #   - '+.pool.ntp.org'
   #   - localhost.ptlogin2.qq.com
+  #   - geosite:private
   
   # Supports UDP, TCP, DoT, DoH. You can specify the port to connect to.
   # All DNS questions are sent directly to the nameserver, without proxies
@@ -1011,4 +2025,59 @@ rules:
             Some("websocket")
         );
     }
+
+    #[test]
+    fn parse_rule_resolve() {
+        let cfg = r#"
+rules:
+  # optional param "resolve=local" dials the proxy with the IP resolved here,
+  # "resolve=remote" leaves the domain to the proxy and skips IP rules
+  - DOMAIN-SUFFIX,internal.example.com,DIRECT,resolve=local
+  - DOMAIN-SUFFIX,example.org,auto,resolve=remote
+  - MATCH,auto
+"#;
+        let c = cfg.parse::<Config>().expect("should parse");
+        let rules = c
+            .rule
+            .iter()
+            .map(|x| x.parse::<RuleType>())
+            .collect::<Result<Vec<_>, _>>()
+            .expect("should parse rules");
+        assert!(matches!(
+            rules[0],
+            RuleType::Resolve {
+                resolve: Resolve::Local,
+                ..
+            }
+        ));
+        assert!(matches!(
+            rules[1],
+            RuleType::Resolve {
+                resolve: Resolve::Remote,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn parse_udp_fallback() {
+        let cfg = r#"
+udp-fallback: direct
+rules:
+  # UDP goes DIRECT, whatever outbound the rules below would pick
+  - NETWORK,udp,DIRECT
+  - MATCH,auto
+"#;
+        let c = cfg.parse::<Config>().expect("should parse");
+        assert_eq!(c.udp_fallback, UdpFallback::Direct);
+        let rule = c.rule[0].parse::<RuleType>().expect("should parse rule");
+        assert!(matches!(
+            rule,
+            RuleType::Network {
+                network: Network::Udp,
+                ..
+            }
+        ));
+        assert_eq!(Config::default().udp_fallback, UdpFallback::Reject);
+    }
 }
@@ -1,21 +1,30 @@
 use std::collections::HashMap;
 
-use std::{fmt::Display, net::IpAddr, str::FromStr};
+use std::{
+    fmt::Display, net::IpAddr, ops::RangeInclusive, str::FromStr, time::Duration,
+};
 
 use serde::{de::value::MapDeserializer, Deserialize, Serialize};
 use serde_yaml::Value;
 
 use crate::{
-    app::{dns, remote_content_manager::providers::rule_provider::RuleSetBehavior},
-    common::auth,
+    app::{
+        dns,
+        remote_content_manager::providers::{
+            rule_provider::{RuleSetBehavior, RuleSetOperator},
+            update_policy::UpdatePolicy,
+        },
+    },
+    common::{auth, integrity::Integrity},
     config::{
-        def::{self, LogLevel, RunMode},
+        def::{self, LogFormat, LogLevel, OutboundInit, RunMode, UdpFallback},
         internal::{
             proxy::{OutboundProxy, PROXY_DIRECT, PROXY_REJECT},
             rule::RuleType,
         },
+        validate::validate,
     },
-    proxy::utils::Interface,
+    proxy::utils::{Interface, PortRange},
     Error,
 };
 
@@ -28,9 +37,21 @@ pub struct Config {
     pub dns: dns::Config,
     pub tun: TunConfig,
     pub experimental: Option<def::Experimental>,
+    pub events: def::Events,
+    pub reload: def::Reload,
+    pub log_file: def::LogFile,
+    pub shutdown: def::Shutdown,
+    pub ntp_service: def::NtpService,
+    pub bandwidth: def::Bandwidth,
+    pub relay_buffer: def::RelayBuffer,
+    pub circuit_breaker: def::CircuitBreaker,
+    pub sniffer: SnifferConfig,
+    pub mitm: MitmConfig,
     pub profile: Profile,
     pub rules: Vec<RuleType>,
+    pub finals: Finals,
     pub rule_providers: HashMap<String, RuleProviderDef>,
+    pub provider_update: UpdatePolicy,
     pub users: Vec<auth::User>,
     /// a list maintaining the order from the config file
     pub proxy_names: Vec<String>,
@@ -39,30 +60,16 @@ pub struct Config {
     pub proxy_providers: HashMap<String, OutboundProxyProviderDef>,
 }
 
-impl Config {
-    fn validate(self) -> Result<Self, crate::Error> {
-        for r in self.rules.iter() {
-            if !self.proxies.contains_key(r.target())
-                && !self.proxy_groups.contains_key(r.target())
-            {
-                return Err(Error::InvalidConfig(format!(
-                    "proxy `{}` referenced in a rule was not found",
-                    r.target()
-                )));
-            }
-        }
-        Ok(self)
-    }
-}
-
 impl TryFrom<def::Config> for Config {
     type Error = crate::Error;
 
     fn try_from(c: def::Config) -> Result<Self, Self::Error> {
+        validate(&c)?;
+
         let mut proxy_names =
             vec![String::from(PROXY_DIRECT), String::from(PROXY_REJECT)];
         #[allow(deprecated)]
-        Self {
+        Ok(Self {
             general: General {
                 inbound: Inbound {
                     port: c.port,
@@ -72,14 +79,23 @@ impl TryFrom<def::Config> for Config {
                     mixed_port: c.mixed_port,
                     authentication: c.authentication.clone(),
                     bind_address: c.bind_address.parse()?,
+                    tcp: c.inbound_tcp,
                 },
                 controller: Controller {
                     external_controller: c.external_controller.clone(),
+                    external_controller_tls: c.external_controller_tls.clone(),
+                    external_controller_unix: c.external_controller_unix.clone(),
+                    external_controller_pipe: c.external_controller_pipe.clone(),
+                    external_controller_grpc: c.external_controller_grpc.clone(),
                     external_ui: c.external_ui.clone(),
+                    external_ui_name: c.external_ui_name.clone(),
+                    external_ui_url: c.external_ui_url.clone(),
+                    pac: c.pac.clone(),
                     secret: c.secret.clone(),
                 },
                 mode: c.mode,
                 log_level: c.log_level,
+                log_format: c.log_format,
                 ipv6: c.ipv6,
                 interface: c.interface.as_ref().map(|iface| {
                     if let Ok(addr) = iface.parse::<IpAddr>() {
@@ -89,13 +105,72 @@ impl TryFrom<def::Config> for Config {
                     }
                 }),
                 routing_mask: c.routing_mask,
+                outbound_port_range: c
+                    .outbound_port_range
+                    .as_deref()
+                    .map(str::parse)
+                    .transpose()?,
+                outbound_init: c.outbound_init,
+                idle_timeouts: IdleTimeouts {
+                    tcp: (c.tcp_idle_timeout > 0)
+                        .then(|| Duration::from_secs(c.tcp_idle_timeout)),
+                    udp: udp_idle_timeout("udp-idle-timeout", c.udp_idle_timeout)?,
+                    udp_dns: c
+                        .udp_idle_timeout_overrides
+                        .dns
+                        .map(|x| {
+                            udp_idle_timeout("udp-idle-timeout-overrides.dns", x)
+                        })
+                        .transpose()?,
+                    udp_quic: c
+                        .udp_idle_timeout_overrides
+                        .quic
+                        .map(|x| {
+                            udp_idle_timeout("udp-idle-timeout-overrides.quic", x)
+                        })
+                        .transpose()?,
+                    udp_ports: c
+                        .udp_idle_timeout_overrides
+                        .ports
+                        .iter()
+                        .map(|(range, v)| {
+                            let key = format!(
+                                "udp-idle-timeout-overrides.ports.{}",
+                                range
+                            );
+                            Ok((*range, udp_idle_timeout(&key, *v)?))
+                        })
+                        .collect::<Result<_, Error>>()?,
+                },
+                udp_fallback: c.udp_fallback,
                 mmdb: c.mmdb.to_owned(),
                 mmdb_download_url: c.mmdb_download_url.to_owned(),
                 geosite: c.geosite.to_owned(),
                 geosite_download_url: c.geosite_download_url.to_owned(),
+                mmdb_integrity: c.mmdb_integrity.clone(),
+                geosite_integrity: c.geosite_integrity.clone(),
             },
             dns: (&c).try_into()?,
             experimental: c.experimental,
+            events: c.events,
+            reload: c.reload,
+            log_file: c.log_file,
+            shutdown: c.shutdown,
+            ntp_service: c.ntp_service,
+            bandwidth: c.bandwidth,
+            relay_buffer: c.relay_buffer,
+            circuit_breaker: c.circuit_breaker,
+            provider_update: UpdatePolicy {
+                window: c
+                    .provider_update
+                    .window
+                    .as_deref()
+                    .map(str::parse)
+                    .transpose()?,
+                concurrency: c.provider_update.concurrency,
+            },
+            sniffer: c.sniffer.try_into()?,
+            mitm: c.mitm.try_into()?,
             tun: match c.tun {
                 Some(mapping) => {
                     TunConfig::deserialize(MapDeserializer::new(mapping.into_iter()))
@@ -119,6 +194,10 @@ impl TryFrom<def::Config> for Config {
                         .map_err(|x| Error::InvalidConfig(x.to_string()))
                 })
                 .collect::<Result<Vec<_>, _>>()?,
+            finals: Finals {
+                inbounds: c.inbound_final,
+                unmatched: c.unmatched,
+            },
             rule_providers: c
                 .rule_provider
                 .map(|m| {
@@ -141,8 +220,8 @@ impl TryFrom<def::Config> for Config {
                                     Error,
                                 >(rv)
                             })
-                            .expect("proxy provider parse error")
                 })
+                .transpose()?
                 .unwrap_or_default(),
             users: c
                 .authentication
@@ -189,8 +268,7 @@ impl TryFrom<def::Config> for Config {
                             if let Some(name) = mapping.get("name") {
                                 Error::InvalidConfig(format!(
                                     "proxy group: {}: {}",
-                                    name.as_str()
-                                        .expect("proxy group name must be string"),
+                                    name.as_str().unwrap_or_default(),
                                     x
                                 ))
                             } else {
@@ -210,8 +288,9 @@ impl TryFrom<def::Config> for Config {
             proxy_providers: c
                 .proxy_provider
                 .map(|m| {
-                    m.into_iter()
-                        .try_fold(HashMap::new(), |mut rv, (name, mut body)| {
+                    m.into_iter().try_fold(
+                        HashMap::new(),
+                        |mut rv, (name, mut body)| {
                             body.insert(
                                 "name".to_owned(),
                                 serde_yaml::Value::String(name.clone()),
@@ -231,30 +310,194 @@ impl TryFrom<def::Config> for Config {
                                 >,
                                 Error,
                             >(rv)
-                        })
-                        .expect("proxy provider parse error")
+                        },
+                    )
                 })
+                .transpose()?
                 .unwrap_or_default(),
-        }
-        .validate()
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use crate::def;
 
-    use super::Config;
+    use super::{Config, RuleType, SniffProtocol};
 
     #[test]
     fn from_def_config() {
         let cfg = r#"
         port: 9090
+        udp-idle-timeout: 60
+        udp-idle-timeout-overrides:
+          dns: 5
         "#;
         let c = cfg.parse::<def::Config>().expect("should parse");
         assert_eq!(c.port, Some(9090));
         let cc: Config = c.try_into().expect("should into");
         assert_eq!(cc.general.inbound.port, Some(9090));
+
+        let timeouts = cc.general.idle_timeouts;
+        assert!(timeouts.tcp.is_none());
+        assert_eq!(timeouts.udp_for(53), Duration::from_secs(5));
+        assert_eq!(timeouts.udp_for(443), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn udp_idle_timeout_ports() {
+        let cfg = r#"
+        udp-idle-timeout: 60
+        udp-idle-timeout-overrides:
+          dns: 5
+          ports:
+            53: 3
+            27000-27100: 600
+            27015: 30
+            27050-27150: 900
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+
+        let timeouts = cc.general.idle_timeouts;
+        // before the DNS override
+        assert_eq!(timeouts.udp_for(53), Duration::from_secs(3));
+        assert_eq!(timeouts.udp_for(443), Duration::from_secs(60));
+        assert_eq!(timeouts.udp_for(27016), Duration::from_secs(600));
+        // the narrowest range
+        assert_eq!(timeouts.udp_for(27015), Duration::from_secs(30));
+        // the lower of equally narrow ones
+        assert_eq!(timeouts.udp_for(27075), Duration::from_secs(600));
+        assert_eq!(timeouts.udp_for(27125), Duration::from_secs(900));
+    }
+
+    #[test]
+    fn udp_idle_timeout_zero() {
+        for cfg in [
+            "udp-idle-timeout: 0",
+            "udp-idle-timeout-overrides: {dns: 0}",
+            "udp-idle-timeout-overrides: {ports: {3478: 0}}",
+        ] {
+            let c = cfg.parse::<def::Config>().expect("should parse");
+            let err = Config::try_from(c).err().expect("should be rejected");
+            assert!(err.to_string().contains("must be greater than 0"));
+        }
+    }
+
+    #[test]
+    fn sniffer_config() {
+        let cfg = r#"
+        sniffer:
+          enable: true
+          sniff:
+            TLS:
+              ports: [443, "8443-8444"]
+            HTTP:
+              override-destination: false
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        let tls = &cc.sniffer.protocols[0];
+        assert_eq!(tls.protocol, SniffProtocol::Tls);
+        assert_eq!(tls.ports, vec![443..=443, 8443..=8444]);
+        assert!(tls.override_destination);
+        assert!(cc.sniffer.protocols[1].ports.is_empty());
+        assert!(!cc.sniffer.protocols[1].override_destination);
+
+        let cfg = r#"
+        sniffer:
+          sniff:
+            TLS:
+              ports: ["443-80"]
+        "#;
+        assert!(cfg.parse::<def::Config>().is_err());
+    }
+
+    #[test]
+    fn mitm_config() {
+        let cfg = r#"
+        mitm:
+          enable: true
+          hosts: [+.example.com]
+          rewrite:
+            - url: ^https://example\.com/ads
+              action: reject
+            - url: ^https://example\.com/(.*)
+              action: redirect
+              location: https://example.org/$1
+            - url: .
+              action: mock
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.mitm.ca_cert, "mitm-ca.crt");
+        assert_eq!(cc.mitm.rewrite[0].action, def::MitmAction::Reject);
+        assert_eq!(
+            cc.mitm.rewrite[1].action,
+            def::MitmAction::Redirect {
+                location: "https://example.org/$1".to_owned()
+            }
+        );
+        assert!(matches!(
+            cc.mitm.rewrite[2].action,
+            def::MitmAction::Mock { status: 200, .. }
+        ));
+
+        let cfg = r#"
+        mitm:
+          rewrite:
+            - url: "("
+              action: reject
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        assert!(Config::try_from(c).is_err());
+    }
+
+    #[test]
+    fn udp_config() {
+        let cfg = r#"
+        udp-fallback: direct
+        rules:
+          - NETWORK,UDP,DIRECT
+          - MATCH,DIRECT
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.general.udp_fallback, def::UdpFallback::Direct);
+        assert!(matches!(
+            cc.rules[0],
+            RuleType::Network {
+                network: crate::session::Network::Udp,
+                ..
+            }
+        ));
+
+        let c = "rules: [NETWORK,icmp,DIRECT]"
+            .parse::<def::Config>()
+            .expect("should parse");
+        assert!(Config::try_from(c).is_err());
+    }
+
+    #[test]
+    fn finals_config() {
+        let cfg = r#"
+        unmatched: reject
+        inbound-final:
+          TUN: DIRECT
+        rules:
+          - DOMAIN,example.com,REJECT
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.finals.unmatched, def::Unmatched::Reject);
+        assert_eq!(cc.finals.inbounds["TUN"], "DIRECT");
+
+        let c = "inbound-final: {TUN: nope}"
+            .parse::<def::Config>()
+            .expect("should parse");
+        assert!(Config::try_from(c).is_err());
     }
 }
 
@@ -263,14 +506,209 @@ pub struct General {
     pub(crate) controller: Controller,
     pub mode: RunMode,
     pub log_level: LogLevel,
+    pub log_format: LogFormat,
     pub ipv6: bool,
     pub interface: Option<Interface>,
     pub routing_mask: Option<u32>,
+    pub outbound_port_range: Option<PortRange>,
+    pub outbound_init: OutboundInit,
+    pub idle_timeouts: IdleTimeouts,
+    pub udp_fallback: UdpFallback,
     pub mmdb: String,
     pub mmdb_download_url: Option<String>,
 
     pub geosite: String,
     pub geosite_download_url: Option<String>,
+    pub mmdb_integrity: Integrity,
+    pub geosite_integrity: Integrity,
+}
+
+/// Where connections go that no rule matched.
+#[derive(Clone, Debug, Default)]
+pub struct Finals {
+    /// by the name of the inbound they came from, over the `MATCH` rule
+    pub inbounds: HashMap<String, String>,
+    /// when there's neither
+    pub unmatched: def::Unmatched,
+}
+
+/// A UDP idle timeout of `secs` under `key`. There's no leaving idle UDP
+/// sessions open, a timeout of 0 would remove them as they're created.
+fn udp_idle_timeout(key: &str, secs: u64) -> Result<Duration, Error> {
+    if secs == 0 {
+        return Err(Error::InvalidConfig(format!(
+            "{} must be greater than 0",
+            key
+        )));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+#[derive(Clone, Debug)]
+pub struct IdleTimeouts {
+    /// `None` to leave idle connections open
+    pub tcp: Option<Duration>,
+    pub udp: Duration,
+    pub udp_dns: Option<Duration>,
+    pub udp_quic: Option<Duration>,
+    /// by the destination port, before `udp_dns` and `udp_quic`
+    pub udp_ports: Vec<(PortRange, Duration)>,
+}
+
+impl IdleTimeouts {
+    /// the idle timeout of a UDP session to `port`
+    pub fn udp_for(&self, port: u16) -> Duration {
+        let by_port = self
+            .udp_ports
+            .iter()
+            .filter(|(range, _)| range.contains(port))
+            .min_by_key(|(range, _)| (range.width(), range.start))
+            .map(|(_, timeout)| *timeout);
+        let overridden = by_port.or(match port {
+            53 => self.udp_dns,
+            443 => self.udp_quic,
+            _ => None,
+        });
+        overridden.unwrap_or(self.udp)
+    }
+}
+
+impl Default for IdleTimeouts {
+    fn default() -> Self {
+        Self {
+            tcp: None,
+            udp: Duration::from_secs(10),
+            udp_dns: None,
+            udp_quic: None,
+            udp_ports: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SniffProtocol {
+    Tls,
+    Http,
+    /// the SNI in the Initial packets
+    Quic,
+}
+
+#[derive(Clone, Debug)]
+pub struct SniffRule {
+    pub protocol: SniffProtocol,
+    /// all ports if empty
+    pub ports: Vec<RangeInclusive<u16>>,
+    pub override_destination: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SnifferConfig {
+    pub enable: bool,
+    pub protocols: Vec<SniffRule>,
+    pub force_domain: Vec<String>,
+    pub skip_domain: Vec<String>,
+}
+
+impl TryFrom<def::Sniffer> for SnifferConfig {
+    type Error = Error;
+
+    fn try_from(c: def::Sniffer) -> Result<Self, Self::Error> {
+        let to_ranges = |ports: Vec<PortRange>| {
+            ports
+                .into_iter()
+                .map(|x| x.start..=x.end)
+                .collect::<Vec<_>>()
+        };
+        let port = |p| PortRange { start: p, end: p };
+
+        let sniff = c.sniff.unwrap_or_else(|| def::SniffProtocols {
+            tls: Some(def::SniffProtocol {
+                ports: vec![port(443)],
+                override_destination: None,
+            }),
+            http: Some(def::SniffProtocol {
+                ports: vec![
+                    port(80),
+                    PortRange {
+                        start: 8080,
+                        end: 8880,
+                    },
+                ],
+                override_destination: None,
+            }),
+            quic: Some(def::SniffProtocol {
+                ports: vec![port(443)],
+                override_destination: None,
+            }),
+        });
+        let mut protocols = vec![];
+        for (protocol, p) in [
+            (SniffProtocol::Tls, sniff.tls),
+            (SniffProtocol::Http, sniff.http),
+            (SniffProtocol::Quic, sniff.quic),
+        ] {
+            if let Some(p) = p {
+                protocols.push(SniffRule {
+                    protocol,
+                    ports: to_ranges(p.ports),
+                    override_destination: p
+                        .override_destination
+                        .unwrap_or(c.override_destination),
+                });
+            }
+        }
+
+        Ok(Self {
+            enable: c.enable,
+            protocols,
+            force_domain: c.force_domain,
+            skip_domain: c.skip_domain,
+        })
+    }
+}
+
+pub struct MitmRule {
+    pub url: regex::Regex,
+    pub action: def::MitmAction,
+}
+
+pub struct MitmConfig {
+    pub enable: bool,
+    pub ca_cert: String,
+    pub ca_key: String,
+    pub hosts: Vec<String>,
+    pub rewrite: Vec<MitmRule>,
+}
+
+impl TryFrom<def::Mitm> for MitmConfig {
+    type Error = Error;
+
+    fn try_from(c: def::Mitm) -> Result<Self, Self::Error> {
+        let rewrite = c
+            .rewrite
+            .into_iter()
+            .map(|x| {
+                let url = regex::Regex::new(&x.url).map_err(|e| {
+                    Error::InvalidConfig(format!(
+                        "invalid mitm rewrite url {}: {}",
+                        x.url, e
+                    ))
+                })?;
+                Ok(MitmRule {
+                    url,
+                    action: x.action,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Self {
+            enable: c.enable,
+            ca_cert: c.ca_cert,
+            ca_key: c.ca_key,
+            hosts: c.hosts,
+            rewrite,
+        })
+    }
 }
 
 pub struct Profile {
@@ -291,7 +729,29 @@ pub struct TunConfig {
     /// tun device address
     /// default: 198.18.0.0/16
     pub network: Option<String>,
+    /// answers pings, default: the first address of `network`
     pub gateway: Option<IpAddr>,
+    /// default: 1500
+    pub mtu: Option<u16>,
+    /// let the kernel pass coalesced TCP segments, Linux dev:// devices
+    /// only
+    #[serde(default)]
+    pub gso: bool,
+    /// relay pings through the outbound interface instead of answering
+    /// them with host unreachable
+    #[serde(default)]
+    pub icmp_relay: bool,
+    /// prefixes routed into the tun device
+    #[serde(default)]
+    pub route_address: Vec<String>,
+    /// prefixes carved out of `route_address`, or of the default route if
+    /// it's empty, left to the other routes
+    #[serde(default)]
+    pub route_exclude_address: Vec<String>,
+    /// DNS servers, `ip:port` or `any:53`, the queries to which through the
+    /// device are answered by clash
+    #[serde(default)]
+    pub dns_hijack: Vec<String>,
 }
 
 #[derive(Clone, Default)]
@@ -341,12 +801,20 @@ pub struct Inbound {
     pub mixed_port: Option<u16>,
     pub authentication: Vec<String>,
     pub bind_address: BindAddress,
+    pub tcp: def::InboundTcp,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct Controller {
     pub external_controller: Option<String>,
+    pub external_controller_tls: Option<def::ControllerTls>,
+    pub external_controller_unix: Option<String>,
+    pub external_controller_pipe: Option<String>,
+    pub external_controller_grpc: Option<String>,
     pub external_ui: Option<String>,
+    pub external_ui_name: Option<String>,
+    pub external_ui_url: Option<String>,
+    pub pac: Option<def::Pac>,
     pub secret: Option<String>,
 }
 
@@ -356,6 +824,7 @@ pub struct Controller {
 pub enum RuleProviderDef {
     Http(HttpRuleProvider),
     File(FileRuleProvider),
+    Combine(CombineRuleProvider),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -364,6 +833,8 @@ pub struct HttpRuleProvider {
     pub interval: u64,
     pub behavior: RuleSetBehavior,
     pub path: String,
+    #[serde(flatten)]
+    pub integrity: Integrity,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -373,6 +844,14 @@ pub struct FileRuleProvider {
     pub behavior: RuleSetBehavior,
 }
 
+/// A set made of other rule providers.
+#[derive(Serialize, Deserialize)]
+pub struct CombineRuleProvider {
+    pub operator: RuleSetOperator,
+    /// for a difference, the first minus the others
+    pub providers: Vec<String>,
+}
+
 impl TryFrom<HashMap<String, Value>> for RuleProviderDef {
     type Error = crate::Error;
 
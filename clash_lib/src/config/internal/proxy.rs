@@ -1,4 +1,12 @@
-use crate::{common::utils::default_bool_true, config::utils, Error};
+use crate::{
+    common::{integrity::Integrity, utils::default_bool_true},
+    config::utils,
+    proxy::{
+        registry::{self, OutboundOptions},
+        transport::TlsVersion,
+    },
+    Error,
+};
 use serde::{de::value::MapDeserializer, Deserialize};
 use serde_yaml::Value;
 use std::{
@@ -44,49 +52,33 @@ pub fn map_serde_error(
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-#[serde(tag = "type")]
+#[derive(Debug)]
 pub enum OutboundProxyProtocol {
-    #[serde(skip)]
     Direct,
-    #[serde(skip)]
     Reject,
-    #[cfg(feature = "shadowsocks")]
-    #[serde(rename = "ss")]
-    Ss(OutboundShadowsocks),
-    #[serde(rename = "socks5")]
-    Socks5(OutboundSocks5),
-    #[serde(rename = "trojan")]
-    Trojan(OutboundTrojan),
-    #[serde(rename = "vmess")]
-    Vmess(OutboundVmess),
-    #[serde(rename = "wireguard")]
-    Wireguard(OutboundWireguard),
-    #[serde(rename = "tor")]
-    Tor(OutboundTor),
-    #[cfg(feature = "tuic")]
-    #[serde(rename = "tuic")]
-    Tuic(OutboundTuic),
+    /// built by the factory registered for its `type`, see
+    /// [`crate::proxy::registry`]
+    Registered(RegisteredOutbound),
+}
+
+#[derive(Debug)]
+pub struct RegisteredOutbound {
+    pub name: String,
+    pub kind: String,
+    pub options: OutboundOptions,
 }
 
 impl OutboundProxyProtocol {
-    fn name(&self) -> &str {
+    pub(crate) fn name(&self) -> &str {
         match &self {
             OutboundProxyProtocol::Direct => PROXY_DIRECT,
             OutboundProxyProtocol::Reject => PROXY_REJECT,
-            #[cfg(feature = "shadowsocks")]
-            OutboundProxyProtocol::Ss(ss) => &ss.name,
-            OutboundProxyProtocol::Socks5(socks5) => &socks5.name,
-            OutboundProxyProtocol::Trojan(trojan) => &trojan.name,
-            OutboundProxyProtocol::Vmess(vmess) => &vmess.name,
-            OutboundProxyProtocol::Wireguard(wireguard) => &wireguard.name,
-            OutboundProxyProtocol::Tor(tor) => &tor.name,
-            #[cfg(feature = "tuic")]
-            OutboundProxyProtocol::Tuic(tuic) => &tuic.name,
+            OutboundProxyProtocol::Registered(x) => &x.name,
         }
     }
 }
 
+/// Checked against the factory of its `type`, the outbound is built later.
 impl TryFrom<HashMap<String, Value>> for OutboundProxyProtocol {
     type Error = crate::Error;
 
@@ -98,25 +90,37 @@ impl TryFrom<HashMap<String, Value>> for OutboundProxyProtocol {
                 "missing field `name` in outbound proxy protocol".to_owned(),
             ))?
             .to_owned();
-        OutboundProxyProtocol::deserialize(MapDeserializer::new(mapping.into_iter()))
-            .map_err(map_serde_error(name))
+        let kind = mapping
+            .get("type")
+            .and_then(|x| x.as_str())
+            .ok_or_else(|| {
+                Error::InvalidConfig(format!("missing field `type` in {}", name))
+            })?
+            .to_owned();
+        let factory = registry::get(&kind).ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "unknown proxy type `{}` of {}",
+                kind, name
+            ))
+        })?;
+        factory.validate(&mapping)?;
+        registry::port_range(&mapping)?;
+        registry::host_limit(&mapping)?;
+
+        Ok(OutboundProxyProtocol::Registered(RegisteredOutbound {
+            name,
+            kind,
+            options: mapping,
+        }))
     }
 }
 
 impl Display for OutboundProxyProtocol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            #[cfg(feature = "shadowsocks")]
-            OutboundProxyProtocol::Ss(_) => write!(f, "Shadowsocks"),
-            OutboundProxyProtocol::Socks5(_) => write!(f, "Socks5"),
             OutboundProxyProtocol::Direct => write!(f, "{}", PROXY_DIRECT),
             OutboundProxyProtocol::Reject => write!(f, "{}", PROXY_REJECT),
-            OutboundProxyProtocol::Trojan(_) => write!(f, "Trojan"),
-            OutboundProxyProtocol::Vmess(_) => write!(f, "Vmess"),
-            OutboundProxyProtocol::Wireguard(_) => write!(f, "Wireguard"),
-            OutboundProxyProtocol::Tor(_) => write!(f, "Tor"),
-            #[cfg(feature = "tuic")]
-            OutboundProxyProtocol::Tuic(_) => write!(f, "Tuic"),
+            OutboundProxyProtocol::Registered(x) => write!(f, "{}", x.kind),
         }
     }
 }
@@ -145,7 +149,12 @@ pub struct OutboundSocks5 {
     pub password: Option<String>,
     #[serde(default = "Default::default")]
     pub tls: bool,
+    #[serde(alias = "servername")]
     pub sni: Option<String>,
+    pub alpn: Option<Vec<String>>,
+    pub min_tls_version: Option<TlsVersion>,
+    pub max_tls_version: Option<TlsVersion>,
+    pub pin_sha256: Option<Vec<String>>,
     #[serde(default = "Default::default")]
     pub skip_cert_verify: bool,
     #[serde(default = "default_bool_true")]
@@ -181,7 +190,11 @@ pub struct OutboundTrojan {
     pub port: u16,
     pub password: String,
     pub alpn: Option<Vec<String>>,
+    #[serde(alias = "servername")]
     pub sni: Option<String>,
+    pub min_tls_version: Option<TlsVersion>,
+    pub max_tls_version: Option<TlsVersion>,
+    pub pin_sha256: Option<Vec<String>>,
     pub skip_cert_verify: Option<bool>,
     pub udp: Option<bool>,
     pub network: Option<String>,
@@ -202,8 +215,13 @@ pub struct OutboundVmess {
     pub udp: Option<bool>,
     pub tls: Option<bool>,
     pub skip_cert_verify: Option<bool>,
-    #[serde(alias = "servername")]
+    #[serde(alias = "servername", alias = "sni")]
     pub server_name: Option<String>,
+    /// the ALPN of the `network` by default
+    pub alpn: Option<Vec<String>>,
+    pub min_tls_version: Option<TlsVersion>,
+    pub max_tls_version: Option<TlsVersion>,
+    pub pin_sha256: Option<Vec<String>>,
     pub network: Option<String>,
     pub ws_opts: Option<WsOpt>,
     pub h2_opts: Option<H2Opt>,
@@ -227,6 +245,8 @@ pub struct OutboundWireguard {
     pub dns: Option<Vec<String>>,
     pub allowed_ips: Option<Vec<String>>,
     pub reserved_bits: Option<Vec<u8>>,
+    /// over fake TCP to a phantun server, Linux only
+    pub faketcp: Option<bool>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -258,6 +278,7 @@ pub struct OutboundTuic {
     pub max_udp_relay_packet_size: Option<u64>,
     pub fast_open: Option<bool>,
     pub skip_cert_verify: Option<bool>,
+    pub pin_sha256: Option<Vec<String>>,
     pub max_open_stream: Option<u64>,
     pub sni: Option<String>,
     /// millis
@@ -303,6 +324,33 @@ impl OutboundGroupProtocol {
             OutboundGroupProtocol::Select(g) => g.proxies.as_ref(),
         }
     }
+
+    /// the name, the proxies and the providers of the group
+    pub fn parts_mut(
+        &mut self,
+    ) -> (
+        &mut String,
+        &mut Option<Vec<String>>,
+        &mut Option<Vec<String>>,
+    ) {
+        match self {
+            OutboundGroupProtocol::Relay(g) => {
+                (&mut g.name, &mut g.proxies, &mut g.use_provider)
+            }
+            OutboundGroupProtocol::UrlTest(g) => {
+                (&mut g.name, &mut g.proxies, &mut g.use_provider)
+            }
+            OutboundGroupProtocol::Fallback(g) => {
+                (&mut g.name, &mut g.proxies, &mut g.use_provider)
+            }
+            OutboundGroupProtocol::LoadBalance(g) => {
+                (&mut g.name, &mut g.proxies, &mut g.use_provider)
+            }
+            OutboundGroupProtocol::Select(g) => {
+                (&mut g.name, &mut g.proxies, &mut g.use_provider)
+            }
+        }
+    }
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundGroupProtocol {
@@ -382,6 +430,8 @@ pub struct OutboundGroupLoadBalance {
     pub interval: u64,
     pub lazy: Option<bool>,
     pub strategy: Option<LoadBalanceStrategy>,
+    /// the share of the traffic of members, by name, 1 if not set
+    pub weights: Option<HashMap<String, u32>>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default)]
@@ -420,6 +470,8 @@ pub struct OutboundHttpProvider {
     pub interval: u64,
     pub path: String,
     pub health_check: HealthCheck,
+    #[serde(flatten)]
+    pub integrity: Integrity,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -1,4 +1,4 @@
-use crate::Error;
+use crate::{session::Network, Error};
 use std::{fmt::Display, str::FromStr};
 
 pub enum RuleType {
@@ -41,6 +41,10 @@ pub enum RuleType {
         target: String,
         port: u16,
     },
+    Network {
+        network: Network,
+        target: String,
+    },
     ProcessName {
         process_name: String,
         target: String,
@@ -53,9 +57,39 @@ pub enum RuleType {
         rule_set: String,
         target: String,
     },
+    /// the user the inbound authenticated, one of `/` separated names
+    InUser {
+        users: Vec<String>,
+        target: String,
+    },
     Match {
         target: String,
     },
+    /// a rule with `resolve=local` or `resolve=remote`
+    Resolve {
+        rule: Box<RuleType>,
+        resolve: Resolve,
+    },
+}
+
+/// Where the destination domains of the connections a rule matches are
+/// resolved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolve {
+    /// here, the outbound is dialed by IP
+    Local,
+    /// by the proxy, the domain is never looked up here, not even by the IP
+    /// rules before this one
+    Remote,
+}
+
+impl Display for Resolve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Resolve::Local => "local",
+            Resolve::Remote => "remote",
+        })
+    }
 }
 
 impl RuleType {
@@ -70,10 +104,13 @@ impl RuleType {
             RuleType::SrcCidr { target, .. } => target,
             RuleType::SRCPort { target, .. } => target,
             RuleType::DSTPort { target, .. } => target,
+            RuleType::Network { target, .. } => target,
             RuleType::ProcessName { target, .. } => target,
             RuleType::ProcessPath { target, .. } => target,
             RuleType::RuleSet { target, .. } => target,
+            RuleType::InUser { target, .. } => target,
             RuleType::Match { target } => target,
+            RuleType::Resolve { rule, .. } => rule.target(),
         }
     }
 }
@@ -92,10 +129,13 @@ impl Display for RuleType {
             RuleType::SrcCidr { .. } => write!(f, "SRC-IP-CIDR"),
             RuleType::SRCPort { .. } => write!(f, "SRC-PORT"),
             RuleType::DSTPort { .. } => write!(f, "DST-PORT"),
+            RuleType::Network { .. } => write!(f, "NETWORK"),
             RuleType::ProcessName { .. } => write!(f, "PROCESS-NAME"),
             RuleType::ProcessPath { .. } => write!(f, "PROCESS-PATH"),
             RuleType::RuleSet { .. } => write!(f, "RULE-SET"),
+            RuleType::InUser { .. } => write!(f, "IN-USER"),
             RuleType::Match { .. } => write!(f, "MATCH"),
+            RuleType::Resolve { rule, .. } => rule.fmt(f),
         }
     }
 }
@@ -107,7 +147,22 @@ impl RuleType {
         target: &str,
         params: Option<Vec<&str>>,
     ) -> Result<Self, Error> {
-        match proto {
+        let params = params.unwrap_or_default();
+        let resolve = match params.iter().find_map(|x| x.strip_prefix("resolve=")) {
+            Some("local") => Some(Resolve::Local),
+            Some("remote") => Some(Resolve::Remote),
+            Some(x) => {
+                return Err(Error::InvalidConfig(format!(
+                    "invalid resolve: {}, expected local or remote",
+                    x
+                )))
+            }
+            None => None,
+        };
+        let no_resolve =
+            params.contains(&"no-resolve") || resolve == Some(Resolve::Remote);
+
+        let rule = match proto {
             "DOMAIN" => Ok(RuleType::Domain {
                 domain: payload.to_string(),
                 target: target.to_string(),
@@ -127,29 +182,17 @@ impl RuleType {
             "GEOIP" => Ok(RuleType::GeoIP {
                 target: target.to_string(),
                 country_code: payload.to_string(),
-                no_resolve: if let Some(params) = params {
-                    params.contains(&"no-resolve")
-                } else {
-                    false
-                },
+                no_resolve,
             }),
             "IP-CIDR" | "IP-CIDR6" => Ok(RuleType::IpCidr {
                 ipnet: payload.parse()?,
                 target: target.to_string(),
-                no_resolve: if let Some(params) = params {
-                    params.contains(&"no-resolve")
-                } else {
-                    false
-                },
+                no_resolve,
             }),
             "SRC-IP-CIDR" => Ok(RuleType::SrcCidr {
                 ipnet: payload.parse()?,
                 target: target.to_string(),
-                no_resolve: if let Some(params) = params {
-                    params.contains(&"no-resolve")
-                } else {
-                    false
-                },
+                no_resolve,
             }),
             "SRC-PORT" => Ok(RuleType::SRCPort {
                 target: target.to_string(),
@@ -163,6 +206,19 @@ impl RuleType {
                     .parse()
                     .unwrap_or_else(|_| panic!("invalid port: {}", payload)),
             }),
+            "NETWORK" => Ok(RuleType::Network {
+                network: match payload.to_ascii_lowercase().as_str() {
+                    "tcp" => Network::Tcp,
+                    "udp" => Network::Udp,
+                    _ => {
+                        return Err(Error::InvalidConfig(format!(
+                            "invalid network: {}",
+                            payload
+                        )))
+                    }
+                },
+                target: target.to_string(),
+            }),
             "PROCESS-NAME" => Ok(RuleType::ProcessName {
                 process_name: payload.to_string(),
                 target: target.to_string(),
@@ -175,6 +231,15 @@ impl RuleType {
                 rule_set: payload.to_string(),
                 target: target.to_string(),
             }),
+            "IN-USER" => Ok(RuleType::InUser {
+                users: payload
+                    .split('/')
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                target: target.to_string(),
+            }),
             "MATCH" => Ok(RuleType::Match {
                 target: target.to_string(),
             }),
@@ -182,6 +247,22 @@ impl RuleType {
                 "unsupported rule type: {}",
                 proto
             ))),
+        }?;
+
+        Ok(match resolve {
+            Some(resolve) => RuleType::Resolve {
+                rule: Box::new(rule),
+                resolve,
+            },
+            None => rule,
+        })
+    }
+
+    /// The rule without its `resolve` option.
+    pub fn inner(&self) -> &RuleType {
+        match self {
+            RuleType::Resolve { rule, .. } => rule.inner(),
+            _ => self,
         }
     }
 }
@@ -210,3 +291,52 @@ impl FromStr for RuleType {
         s.to_string().try_into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Resolve, RuleType};
+
+    #[test]
+    fn test_resolve_param() {
+        let rule: RuleType = "DOMAIN-SUFFIX,example.com,PROXY,resolve=local"
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            rule,
+            RuleType::Resolve {
+                resolve: Resolve::Local,
+                ..
+            }
+        ));
+        assert!(matches!(rule.inner(), RuleType::DomainSuffix { .. }));
+        assert_eq!(rule.target(), "PROXY");
+        assert_eq!(rule.to_string(), "DOMAIN-SUFFIX");
+
+        // IP rules never resolve domains that are resolved remotely
+        let rule: RuleType =
+            "IP-CIDR,10.0.0.0/8,DIRECT,resolve=remote".parse().unwrap();
+        assert!(matches!(
+            rule.inner(),
+            RuleType::IpCidr {
+                no_resolve: true,
+                ..
+            }
+        ));
+
+        assert!("DOMAIN,example.com,PROXY,resolve=proxy"
+            .parse::<RuleType>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_in_user() {
+        let rule: RuleType = "IN-USER,alice/bob,PROXY".parse().unwrap();
+        match rule {
+            RuleType::InUser { users, target } => {
+                assert_eq!(users, vec!["alice", "bob"]);
+                assert_eq!(target, "PROXY");
+            }
+            _ => panic!("not an IN-USER rule"),
+        }
+    }
+}
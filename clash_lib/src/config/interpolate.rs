@@ -0,0 +1,214 @@
+//! `${VAR}` and `!include` in config files.
+//!
+//! `${VAR}` in a string is replaced by the environment variable `VAR`,
+//! `${VAR:-default}` falls back to `default` when it's not set, and `$${` is
+//! a literal `${`. The value stays a string whatever it's replaced with, a
+//! password like `0123` isn't turned into a number.
+//!
+//! `!include rules.yaml` is replaced by the content of `rules.yaml`, relative
+//! to the including file.
+
+use std::path::Path;
+
+use serde_yaml::{value::TaggedValue, Value};
+
+use crate::Error;
+
+/// how deep `!include`s may nest, to catch files including each other
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Resolve the references in `value`, which was loaded from a file in `dir`.
+pub fn interpolate(value: &mut Value, dir: &Path) -> Result<(), Error> {
+    interpolate_with_depth(value, dir, 0)
+}
+
+fn interpolate_with_depth(
+    value: &mut Value,
+    dir: &Path,
+    depth: usize,
+) -> Result<(), Error> {
+    match value {
+        Value::String(s) => {
+            if let Some(v) = substitute(s)? {
+                *value = v;
+            }
+        }
+        Value::Sequence(seq) => {
+            for v in seq {
+                interpolate_with_depth(v, dir, depth)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (_, v) in map.iter_mut() {
+                interpolate_with_depth(v, dir, depth)?;
+            }
+        }
+        Value::Tagged(tagged) if tagged.tag == "include" => {
+            *value = include(tagged, dir, depth)?;
+        }
+        Value::Tagged(tagged) => {
+            interpolate_with_depth(&mut tagged.value, dir, depth)?
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+fn include(tagged: &TaggedValue, dir: &Path, depth: usize) -> Result<Value, Error> {
+    let Some(file) = tagged.value.as_str() else {
+        return Err(Error::InvalidConfig(
+            "!include takes a file name".to_owned(),
+        ));
+    };
+    if depth >= MAX_INCLUDE_DEPTH {
+        return Err(Error::InvalidConfig(format!(
+            "too many nested includes at {}",
+            file
+        )));
+    }
+
+    let path = dir.join(file);
+    let content = std::fs::read_to_string(&path).map_err(|e| {
+        Error::InvalidConfig(format!("could not include {}: {}", path.display(), e))
+    })?;
+    let mut value: Value = serde_yaml::from_str(&content).map_err(|e| {
+        Error::InvalidConfig(format!("could not parse {}: {}", path.display(), e))
+    })?;
+    let dir = path.parent().unwrap_or(dir);
+    interpolate_with_depth(&mut value, dir, depth + 1)?;
+    Ok(value)
+}
+
+/// The value of `s` with its references replaced, `None` if it has none.
+fn substitute(s: &str) -> Result<Option<Value>, Error> {
+    if !s.contains("${") {
+        return Ok(None);
+    }
+
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+
+        let reference = &rest[start + 2..start + len];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        match (std::env::var(name), default) {
+            (Ok(v), _) => out.push_str(&v),
+            (Err(_), Some(default)) => out.push_str(default),
+            (Err(_), None) => {
+                return Err(Error::InvalidConfig(format!(
+                    "environment variable {} is not set",
+                    name
+                )))
+            }
+        }
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+
+    Ok(Some(Value::String(out)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::interpolate;
+
+    #[test]
+    fn test_env() {
+        std::env::set_var("CLASH_TEST_INTERPOLATE_PORT", "7890");
+        std::env::set_var("CLASH_TEST_INTERPOLATE_PASSWORD", "p@ss: word");
+        std::env::remove_var("CLASH_TEST_INTERPOLATE_UNSET");
+
+        let mut v: Value = serde_yaml::from_str(
+            r#"
+            port: ${CLASH_TEST_INTERPOLATE_PORT}
+            password: ${CLASH_TEST_INTERPOLATE_PASSWORD}
+            url: http://${CLASH_TEST_INTERPOLATE_UNSET:-localhost}:${CLASH_TEST_INTERPOLATE_PORT}/
+            "#,
+        )
+        .unwrap();
+        interpolate(&mut v, ".".as_ref()).unwrap();
+        assert_eq!(v["port"], Value::from("7890"));
+        assert_eq!(v["password"], Value::from("p@ss: word"));
+        assert_eq!(v["url"], Value::from("http://localhost:7890/"));
+
+        let mut v: Value =
+            serde_yaml::from_str("secret: ${CLASH_TEST_INTERPOLATE_UNSET}").unwrap();
+        assert!(interpolate(&mut v, ".".as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_env_kept_as_string() {
+        std::env::set_var("CLASH_TEST_INTERPOLATE_SECRET", "0123");
+        std::env::set_var("CLASH_TEST_INTERPOLATE_FLAG", "true");
+
+        let mut v: Value = serde_yaml::from_str(
+            r#"
+            secret: ${CLASH_TEST_INTERPOLATE_SECRET}
+            flag: ${CLASH_TEST_INTERPOLATE_FLAG}
+            "#,
+        )
+        .unwrap();
+        interpolate(&mut v, ".".as_ref()).unwrap();
+        assert_eq!(v["secret"], Value::from("0123"));
+        assert_eq!(v["flag"], Value::from("true"));
+    }
+
+    #[test]
+    fn test_escape() {
+        std::env::set_var("CLASH_TEST_INTERPOLATE_ESCAPED", "value");
+
+        let mut v: Value = serde_yaml::from_str(
+            r#"
+            literal: $${CLASH_TEST_INTERPOLATE_UNSET}
+            mixed: a$${b}-${CLASH_TEST_INTERPOLATE_ESCAPED}
+            "#,
+        )
+        .unwrap();
+        interpolate(&mut v, ".".as_ref()).unwrap();
+        assert_eq!(v["literal"], Value::from("${CLASH_TEST_INTERPOLATE_UNSET}"));
+        assert_eq!(v["mixed"], Value::from("a${b}-value"));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("rules")).unwrap();
+        std::fs::write(
+            dir.path().join("rules/all.yaml"),
+            "- DOMAIN,example.com,DIRECT\n- !include ../last.yaml\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("last.yaml"), "MATCH,REJECT").unwrap();
+        std::fs::write(dir.path().join("loop.yaml"), "!include loop.yaml").unwrap();
+
+        let mut v: Value =
+            serde_yaml::from_str("rules: !include rules/all.yaml").unwrap();
+        interpolate(&mut v, dir.path()).unwrap();
+        assert_eq!(
+            v["rules"],
+            serde_yaml::from_str::<Value>(
+                "- DOMAIN,example.com,DIRECT\n- MATCH,REJECT"
+            )
+            .unwrap()
+        );
+
+        let mut v: Value =
+            serde_yaml::from_str("rules: !include loop.yaml").unwrap();
+        assert!(interpolate(&mut v, dir.path()).is_err());
+    }
+}
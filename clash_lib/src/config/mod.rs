@@ -1,5 +1,8 @@
 pub mod def;
 pub mod internal;
+mod interpolate;
+pub mod overrides;
 mod utils;
+mod validate;
 pub use def::DNSListen;
 pub use internal::InternalConfig as RuntimeConfig;
@@ -0,0 +1,150 @@
+//! Override files, merged on top of the config file they belong to.
+//!
+//! The overrides of `config.yaml` are the `*.yaml` and `*.yml` files in
+//! `config.d/` next to it, applied in file name order so later files win.
+//! Maps are merged key by key, anything else, lists included, replaces the
+//! value it overrides.
+//!
+//! Every file may use `${VAR}` and `!include`, see [`super::interpolate`].
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde_yaml::Value;
+
+use crate::{config::interpolate::interpolate, Error};
+
+/// The directory holding the overrides of the config file at `config`.
+pub fn override_dir(config: &Path) -> PathBuf {
+    config.with_extension("d")
+}
+
+/// The override files of the config file at `config`, in the order they
+/// apply.
+pub fn override_files(config: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = match std::fs::read_dir(override_dir(config)) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut files = vec![];
+    for entry in dir {
+        let path = entry?.path();
+        let is_yaml = path.extension().is_some_and(|x| x == "yaml" || x == "yml");
+        if is_yaml && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Merge `over` into `base`.
+pub fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Mapping(base), Value::Mapping(over)) => {
+            for (k, v) in over {
+                match base.get_mut(&k) {
+                    Some(x) => merge(x, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+/// Load the config file at `path` with its overrides applied.
+pub fn load(path: &Path) -> Result<Value, Error> {
+    let mut config = parse(path)?;
+    for file in override_files(path)? {
+        merge(&mut config, parse(&file)?);
+    }
+    Ok(config)
+}
+
+fn parse(path: &Path) -> Result<Value, Error> {
+    let content = std::fs::read_to_string(path)?;
+    let mut value = match serde_yaml::from_str(&content) {
+        // an empty file
+        Ok(Value::Null) => Value::Mapping(Default::default()),
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::InvalidConfig(format!(
+                "could not parse {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+    interpolate(&mut value, path.parent().unwrap_or(Path::new(".")))?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::{load, merge};
+
+    #[test]
+    fn test_merge() {
+        let mut base: Value = serde_yaml::from_str(
+            r#"
+            port: 7890
+            dns:
+              enable: true
+              nameserver: [1.1.1.1]
+            rules: [MATCH,DIRECT]
+            "#,
+        )
+        .unwrap();
+        let over: Value = serde_yaml::from_str(
+            r#"
+            dns:
+              nameserver: [8.8.8.8]
+            rules: [MATCH,REJECT]
+            secret: s3cret
+            "#,
+        )
+        .unwrap();
+        merge(&mut base, over);
+
+        let expected: Value = serde_yaml::from_str(
+            r#"
+            port: 7890
+            dns:
+              enable: true
+              nameserver: [8.8.8.8]
+            rules: [MATCH,REJECT]
+            secret: s3cret
+            "#,
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.yaml");
+        std::fs::write(&config, "port: 7890\nsocks-port: 7891\n").unwrap();
+        std::fs::create_dir(dir.path().join("config.d")).unwrap();
+        std::fs::write(dir.path().join("config.d/20-b.yml"), "port: 7893\n")
+            .unwrap();
+        std::fs::write(dir.path().join("config.d/10-a.yaml"), "port: 7892\n")
+            .unwrap();
+        std::fs::write(dir.path().join("config.d/30-c.txt"), "port: 7894\n")
+            .unwrap();
+        std::fs::write(dir.path().join("config.d/40-empty.yaml"), "").unwrap();
+
+        let c = load(&config).unwrap();
+        assert_eq!(c["port"], Value::from(7893));
+        assert_eq!(c["socks-port"], Value::from(7891));
+    }
+}
@@ -0,0 +1,466 @@
+//! Checks a config for mistakes before it's converted, so that all of them
+//! are reported at once along with where they are.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    net::IpAddr,
+};
+
+use serde_yaml::Value;
+
+use crate::{
+    app::dns,
+    config::{
+        def,
+        internal::{
+            proxy::{PROXY_DIRECT, PROXY_REJECT},
+            rule::RuleType,
+        },
+    },
+    Error,
+};
+
+/// the names the inbounds give their connections
+const INBOUND_NAMES: [&str; 4] = ["HTTP", "SOCKS5", "Mixed", "TUN"];
+
+struct Issue {
+    /// where in the config, e.g. `proxy-groups[1].proxies[0]`
+    path: String,
+    msg: String,
+    /// a known name close to the one that was not found
+    hint: Option<String>,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.msg)?;
+        if let Some(hint) = &self.hint {
+            write!(f, ", did you mean `{}`?", hint)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Issues(Vec<Issue>);
+
+impl Issues {
+    fn add(&mut self, path: impl Into<String>, msg: impl Display) {
+        self.0.push(Issue {
+            path: path.into(),
+            msg: msg.to_string(),
+            hint: None,
+        });
+    }
+
+    fn not_found<'a>(
+        &mut self,
+        path: impl Into<String>,
+        what: &str,
+        name: &str,
+        known: impl IntoIterator<Item = &'a str>,
+    ) {
+        self.0.push(Issue {
+            path: path.into(),
+            msg: format!("{} `{}` not found", what, name),
+            hint: suggest(name, known).map(ToOwned::to_owned),
+        });
+    }
+}
+
+/// Check `c`, the error lists every issue found.
+pub fn validate(c: &def::Config) -> Result<(), Error> {
+    let mut issues = Issues::default();
+
+    let mut proxies: HashSet<&str> = HashSet::from([PROXY_DIRECT, PROXY_REJECT]);
+    for (i, p) in c.proxy.iter().enumerate() {
+        let path = format!("proxies[{}]", i);
+        match p.get("name").and_then(Value::as_str) {
+            Some(name) => {
+                if !proxies.insert(name) {
+                    issues.add(path, format!("duplicated proxy name `{}`", name));
+                }
+            }
+            None => issues.add(path, "`name` is required"),
+        }
+    }
+    for (i, g) in c.proxy_group.iter().enumerate() {
+        let path = format!("proxy-groups[{}]", i);
+        match g.get("name").and_then(Value::as_str) {
+            Some(name) => {
+                if !proxies.insert(name) {
+                    issues.add(path, format!("duplicated proxy name `{}`", name));
+                }
+            }
+            None => issues.add(path, "`name` is required"),
+        }
+    }
+
+    let proxy_providers: HashSet<&str> = c
+        .proxy_provider
+        .iter()
+        .flat_map(|x| x.keys().map(String::as_str))
+        .collect();
+    // the proxies and groups of a provider's payload are only known once
+    // it's loaded, by `<provider>/<name>`
+    let is_proxy = |name: &str| {
+        proxies.contains(name)
+            || name.split_once('/').is_some_and(|(provider, name)| {
+                proxy_providers.contains(provider) && !name.is_empty()
+            })
+    };
+    for (i, g) in c.proxy_group.iter().enumerate() {
+        for (key, what, known) in [
+            ("proxies", "proxy", &proxies),
+            ("use", "proxy provider", &proxy_providers),
+        ] {
+            let Some(names) = g.get(key).and_then(Value::as_sequence) else {
+                continue;
+            };
+            for (j, name) in names.iter().enumerate() {
+                let path = format!("proxy-groups[{}].{}[{}]", i, key, j);
+                let found = |name: &str| match key {
+                    "proxies" => is_proxy(name),
+                    _ => known.contains(name),
+                };
+                match name.as_str() {
+                    Some(name) if !found(name) => {
+                        issues.not_found(path, what, name, known.iter().copied())
+                    }
+                    Some(_) => {}
+                    None => issues.add(path, "must be a string"),
+                }
+            }
+        }
+    }
+
+    let rule_providers: HashSet<&str> = c
+        .rule_provider
+        .iter()
+        .flat_map(|x| x.keys().map(String::as_str))
+        .collect();
+    let mut combined: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (name, body) in c.rule_provider.iter().flatten() {
+        if body.get("type").and_then(Value::as_str) != Some("combine") {
+            continue;
+        }
+        let path = format!("rule-providers.{}.providers", name);
+        let members = match body.get("providers").and_then(Value::as_sequence) {
+            Some(x) if !x.is_empty() => x,
+            _ => {
+                issues.add(path, "must be a list of rule providers");
+                continue;
+            }
+        };
+        let mut names = vec![];
+        for (j, member) in members.iter().enumerate() {
+            let path = format!("{}[{}]", path, j);
+            match member.as_str() {
+                Some(x) if !rule_providers.contains(x) => issues.not_found(
+                    path,
+                    "rule provider",
+                    x,
+                    rule_providers.iter().copied(),
+                ),
+                Some(x) => names.push(x),
+                None => issues.add(path, "must be a string"),
+            }
+        }
+        combined.insert(name, names);
+    }
+    for &name in combined.keys() {
+        if made_of(&combined, name, name, &mut HashSet::new()) {
+            issues.add(
+                format!("rule-providers.{}", name),
+                "is made of itself through other providers",
+            );
+        }
+    }
+
+    for (i, line) in c.rule.iter().enumerate() {
+        let path = format!("rules[{}]", i);
+        let rule = match line.parse::<RuleType>() {
+            Ok(rule) => rule,
+            Err(e) => {
+                issues.add(path, e);
+                continue;
+            }
+        };
+        if !is_proxy(rule.target()) {
+            issues.not_found(
+                path.clone(),
+                "proxy",
+                rule.target(),
+                proxies.iter().copied(),
+            );
+        }
+        if let RuleType::RuleSet { rule_set, .. } = rule.inner() {
+            if !rule_providers.contains(rule_set.as_str()) {
+                issues.not_found(
+                    path,
+                    "rule provider",
+                    rule_set,
+                    rule_providers.iter().copied(),
+                );
+            }
+        }
+    }
+
+    for (inbound, target) in &c.inbound_final {
+        if !INBOUND_NAMES.contains(&inbound.as_str()) {
+            issues.not_found("inbound-final", "inbound", inbound, INBOUND_NAMES);
+        }
+        if !is_proxy(target) {
+            issues.not_found(
+                format!("inbound-final.{}", inbound),
+                "proxy",
+                target,
+                proxies.iter().copied(),
+            );
+        }
+    }
+
+    if let Some(target) = &c.circuit_breaker.reroute {
+        if !is_proxy(target) {
+            issues.not_found(
+                "circuit-breaker.reroute",
+                "proxy",
+                target,
+                proxies.iter().copied(),
+            );
+        }
+    }
+
+    for (key, servers) in [
+        ("nameserver", &c.dns.nameserver),
+        ("fallback", &c.dns.fallback),
+        ("default-nameserver", &c.dns.default_nameserver),
+    ] {
+        for (i, server) in servers.iter().enumerate() {
+            let path = format!("dns.{}[{}]", key, i);
+            if let Err(e) = dns::Config::parse_nameserver(&[server.to_owned()]) {
+                issues.add(path, e);
+            } else if key == "default-nameserver"
+                && server.parse::<IpAddr>().is_err()
+            {
+                issues.add(path, "must be an ip address");
+            }
+        }
+    }
+
+    if let Some(pac) = &c.pac {
+        if !pac.path.starts_with('/') || pac.path.contains([':', '*']) {
+            issues.add("pac.path", "must be an absolute path like /proxy.pac");
+        }
+    }
+
+    match issues.0.len() {
+        0 => Ok(()),
+        1 => Err(Error::InvalidConfig(issues.0[0].to_string())),
+        n => Err(Error::InvalidConfig(format!(
+            "{} errors:\n{}",
+            n,
+            issues
+                .0
+                .iter()
+                .map(|x| format!("  {}", x))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ))),
+    }
+}
+
+/// Whether the combined provider `of` is made of `name`, directly or through
+/// other combined providers.
+fn made_of<'a>(
+    combined: &HashMap<&'a str, Vec<&'a str>>,
+    of: &'a str,
+    name: &str,
+    seen: &mut HashSet<&'a str>,
+) -> bool {
+    if !seen.insert(of) {
+        return false;
+    }
+    combined.get(of).is_some_and(|members| {
+        members
+            .iter()
+            .any(|&x| x == name || made_of(combined, x, name, seen))
+    })
+}
+
+/// The known name closest to `name`, if any is close enough to be a typo.
+fn suggest<'a>(
+    name: &str,
+    known: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let max = (name.chars().count() / 3).max(1);
+    known
+        .into_iter()
+        .map(|x| (edit_distance(&name.to_lowercase(), &x.to_lowercase()), x))
+        .filter(|(d, _)| *d <= max)
+        .min()
+        .map(|(_, x)| x)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                prev.min(cur).min(row[j]) + 1
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::def;
+
+    use super::{suggest, validate};
+
+    #[test]
+    fn test_suggest() {
+        let known = ["DIRECT", "REJECT", "auto", "Proxy"];
+        assert_eq!(suggest("DIRCT", known), Some("DIRECT"));
+        assert_eq!(suggest("proxy", known), Some("Proxy"));
+        assert_eq!(suggest("manual", known), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let cfg = r#"
+        proxies:
+          - name: ss
+            type: ss
+          - name: ss
+            type: ss
+        proxy-groups:
+          - name: auto
+            type: url-test
+            proxies: [ss, sss]
+            use: [provider]
+        rules:
+          - DOMAIN,example.com,autoo
+          - RULE-SET,ads,REJECT
+          - NOPE,example.com,DIRECT
+          - MATCH,DIRECT
+        dns:
+          nameserver: [1.1.1.1, "sdns://1.1.1.1"]
+          default-nameserver: [dns.google]
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let err = validate(&c).unwrap_err().to_string();
+        for msg in [
+            "8 errors",
+            "proxies[1]: duplicated proxy name `ss`",
+            "proxy-groups[0].proxies[1]: proxy `sss` not found, did you mean `ss`?",
+            "proxy-groups[0].use[0]: proxy provider `provider` not found",
+            "rules[0]: proxy `autoo` not found, did you mean `auto`?",
+            "rules[1]: rule provider `ads` not found",
+            "rules[2]: ",
+            "dns.nameserver[1]: ",
+            "dns.default-nameserver[0]: must be an ip address",
+        ] {
+            assert!(err.contains(msg), "`{}` missing in:\n{}", msg, err);
+        }
+
+        let c = "rules: [MATCH,DIRECT]"
+            .parse::<def::Config>()
+            .expect("should parse");
+        assert!(validate(&c).is_ok());
+    }
+
+    #[test]
+    fn test_validate_combined_providers() {
+        let cfg = r#"
+        rule-providers:
+          mine:
+            type: combine
+            operator: difference
+            providers: [mine, china, cn]
+          china:
+            type: combine
+            operator: union
+            providers: [mine]
+          empty:
+            type: combine
+            operator: union
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let err = validate(&c).unwrap_err().to_string();
+        for msg in [
+            "4 errors",
+            "rule-providers.mine.providers[2]: rule provider `cn` not found",
+            "rule-providers.mine: is made of itself",
+            "rule-providers.china: is made of itself",
+            "rule-providers.empty.providers: must be a list of rule providers",
+        ] {
+            assert!(err.contains(msg), "`{}` missing in:\n{}", msg, err);
+        }
+    }
+
+    #[test]
+    fn test_validate_inbound_final() {
+        let cfg = r#"
+        proxy-groups:
+          - name: auto
+            type: select
+            proxies: [DIRECT]
+        inbound-final:
+          TUN: Auto
+          socks5: DIRECT
+          Mixed: auto
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let err = validate(&c).unwrap_err().to_string();
+        for msg in [
+            "2 errors",
+            "inbound-final.TUN: proxy `Auto` not found, did you mean `auto`?",
+            "inbound-final: inbound `socks5` not found, did you mean `SOCKS5`?",
+        ] {
+            assert!(err.contains(msg), "`{}` missing in:\n{}", msg, err);
+        }
+    }
+
+    #[test]
+    fn test_validate_provider_groups() {
+        let cfg = r#"
+        proxy-providers:
+          sub:
+            type: file
+            path: ./sub.yaml
+        proxy-groups:
+          - name: select
+            type: select
+            proxies: [sub/auto, sub/hk, sub, other/auto, sub/]
+        rules:
+          - DOMAIN,example.com,sub/auto
+          - MATCH,other/auto
+        inbound-final:
+          TUN: sub/fallback
+        circuit-breaker:
+          reroute: sub/auto
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let err = validate(&c).unwrap_err().to_string();
+        for msg in [
+            "4 errors",
+            "proxy-groups[0].proxies[2]: proxy `sub` not found",
+            "proxy-groups[0].proxies[3]: proxy `other/auto` not found",
+            "proxy-groups[0].proxies[4]: proxy `sub/` not found",
+            "rules[1]: proxy `other/auto` not found",
+        ] {
+            assert!(err.contains(msg), "`{}` missing in:\n{}", msg, err);
+        }
+    }
+}
@@ -3,27 +3,42 @@ extern crate anyhow;
 
 use crate::{
     app::{
-        dispatcher::Dispatcher, dns, inbound::manager::InboundManager,
-        outbound::manager::OutboundManager, router::Router,
+        dispatcher::Dispatcher,
+        dns,
+        inbound::manager::{stop_listeners, InboundManager},
+        outbound::manager::OutboundManager,
+        router::Router,
     },
     config::{
         def,
         internal::{proxy::OutboundProxy, InternalConfig},
     },
 };
-use app::{dispatcher::StatisticsManager, dns::SystemResolver, profile};
+use app::{
+    api::GeoDatabases,
+    dispatcher::StatisticsManager,
+    dns::SystemResolver,
+    profile, profiles,
+    reload::{ConfigPath, ReloadSender},
+    remote_content_manager::providers::update_policy::set_update_policy,
+};
 use common::{auth, http::new_http_client, mmdb};
-use config::def::LogLevel;
-use once_cell::sync::OnceCell;
+use once_cell::sync::Lazy;
 use proxy::tun::get_tun_runner;
 
-use std::{io, path::PathBuf, sync::Arc};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
 use thiserror::Error;
 use tokio::{
-    sync::{broadcast, mpsc, oneshot, Mutex},
+    sync::{broadcast, mpsc, oneshot, Mutex, RwLock},
     task::JoinHandle,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 mod app;
 mod common;
@@ -32,10 +47,40 @@ mod proxy;
 mod session;
 
 use crate::common::geodata;
+pub use app::{events::Event, logging::LogEvent, profiles};
 pub use config::{
-    def::{Config as ClashConfigDef, DNS as ClashDNSConfigDef},
+    def::{Config as ClashConfigDef, LogLevel, DNS as ClashDNSConfigDef},
     DNSListen as ClashDNSListen, RuntimeConfig as ClashRuntimeConfig,
 };
+pub use proxy::{
+    registry::register_outbound,
+    utils::{set_socket_protector, SocketProtector},
+};
+
+/// Protocol servers to test outbounds against, see the `test-harness`
+/// feature.
+#[cfg(feature = "test-harness")]
+pub use proxy::utils::test_harness;
+
+/// What protocols added with [`register_outbound`] are built from.
+pub mod outbound {
+    pub use crate::{
+        app::{
+            dispatcher::{
+                BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram,
+                ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
+            },
+            dns::{ClashResolver, ThreadSafeDNSResolver},
+        },
+        proxy::{
+            datagram::UdpPacket,
+            registry::{parse, OutboundFactory, OutboundOptions},
+            utils::RemoteConnector,
+            AnyOutboundHandler, ConnectorType, OutboundHandler, OutboundType,
+        },
+        session::{Session, SocksAddr},
+    };
+}
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -88,6 +133,35 @@ impl Config {
             Config::Str(s) => s.parse::<def::Config>()?.try_into(),
         }
     }
+
+    /// Like `try_parse`, also loads the files the config refers to, their
+    /// paths relative to `cwd`.
+    pub fn try_parse_in(self, cwd: &Path) -> Result<InternalConfig, Error> {
+        let mut config = self.try_parse()?;
+        config.dns.load_tls(cwd)?;
+        Ok(config)
+    }
+
+    /// Like `try_parse_in`, also returns the raw config to diff against when
+    /// reloading.
+    fn try_parse_with_snapshot(
+        self,
+        cwd: &Path,
+    ) -> Result<(InternalConfig, Option<serde_json::Value>), Error> {
+        let c = match self {
+            Config::Internal(mut c) => {
+                c.dns.load_tls(cwd)?;
+                return Ok((c, None));
+            }
+            Config::Def(c) => c,
+            Config::File(file) => PathBuf::from(file).try_into()?,
+            Config::Str(s) => s.parse::<def::Config>()?,
+        };
+        let snapshot = serde_json::to_value(&c).ok();
+        let mut config: InternalConfig = c.try_into()?;
+        config.dns.load_tls(cwd)?;
+        Ok((config, snapshot))
+    }
 }
 
 pub struct GlobalState {
@@ -96,62 +170,211 @@ pub struct GlobalState {
     tunnel_listener_handle: Option<JoinHandle<Result<(), Error>>>,
     api_listener_handle: Option<JoinHandle<Result<(), Error>>>,
     dns_listener_handle: Option<JoinHandle<Result<(), Error>>>,
-    reload_tx: mpsc::Sender<(Config, oneshot::Sender<()>)>,
+    events_handle: Option<JoinHandle<Result<(), Error>>>,
+    ntp_handle: Option<JoinHandle<Result<(), Error>>>,
+    net_monitor_handle: Option<JoinHandle<Result<(), Error>>>,
+    reload_tx: ReloadSender,
+    config_path: ConfigPath,
+    shutdown_drain_timeout: Duration,
     cwd: String,
 }
 
-pub struct RuntimeController {
+/// when the process started, reported by the `/version` API
+pub(crate) static START_TIME: Lazy<chrono::DateTime<chrono::Utc>> =
+    Lazy::new(chrono::Utc::now);
+
+/// A running instance, returned by `start`.
+///
+/// The instance runs on its own thread with its own tokio runtime. `reload`,
+/// `shutdown` and `wait` block, so they must not be called from within an
+/// async context. Dropping the handle leaves the instance running.
+pub struct Handle {
     shutdown_tx: mpsc::Sender<()>,
+    reload_tx: ReloadSender,
+    log_tx: broadcast::Sender<LogEvent>,
+    statistics_manager: Arc<StatisticsManager>,
+    rt: tokio::runtime::Handle,
+    thread: Option<thread::JoinHandle<Result<(), Error>>>,
 }
 
-static RUNTIME_CONTROLLER: OnceCell<RuntimeController> = OnceCell::new();
+impl Handle {
+    /// Stop the instance and wait for the connections to drain.
+    pub fn shutdown(self) -> Result<(), Error> {
+        // the instance may already be shutting down on its own
+        let _ = self.shutdown_tx.try_send(());
+        self.wait()
+    }
 
-pub fn start(opts: Options) -> Result<(), Error> {
-    let rt = match opts.rt.as_ref().unwrap_or(&TokioRuntime::MultiThread) {
-        TokioRuntime::MultiThread => tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()?,
-        TokioRuntime::SingleThread => tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?,
-    };
+    /// Wait for the instance to stop, e.g. on ctrl-c.
+    pub fn wait(mut self) -> Result<(), Error> {
+        match self.thread.take().map(|x| x.join()) {
+            Some(Ok(rv)) => rv,
+            Some(Err(_)) => Err(Error::Operation("runtime panicked".to_owned())),
+            None => Ok(()),
+        }
+    }
 
-    rt.block_on(async {
-        match start_async(opts).await {
-            Err(e) => {
-                eprintln!("start error: {}", e);
-                Err(e)
+    /// Replace the running config, the old one stays in place if the new one
+    /// can't be applied.
+    pub fn reload(&self, config: Config) -> Result<(), Error> {
+        self.rt.block_on(async {
+            let (done, wait) = oneshot::channel();
+            self.reload_tx
+                .send((config, done))
+                .await
+                .map_err(|_| Error::Operation("instance stopped".to_owned()))?;
+            match wait.await {
+                Ok(rv) => rv.map_err(Error::Operation),
+                Err(_) => Err(Error::Operation("config reload aborted".to_owned())),
             }
-            Ok(_) => Ok(()),
-        }
-    })
-}
+        })
+    }
+
+    /// Call `f` with every log line logged from now on, at the configured
+    /// log level.
+    pub fn on_log<F>(&self, f: F)
+    where
+        F: Fn(LogEvent) + Send + 'static,
+    {
+        let mut rx = self.log_tx.subscribe();
+        self.rt.spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => f(event),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Call `f` with every operational event, e.g. a proxy going down.
+    pub fn on_event<F>(&self, f: F)
+    where
+        F: Fn(Event) + Send + 'static,
+    {
+        let mut rx = app::events::subscribe();
+        self.rt.spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => f(event),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
 
-pub fn shutdown() -> bool {
-    match RUNTIME_CONTROLLER.get() {
-        Some(controller) => controller.shutdown_tx.blocking_send(()).is_ok(),
-        _ => false,
+    /// Call `f` every second with the bytes uploaded and downloaded in the
+    /// last second.
+    pub fn on_traffic<F>(&self, f: F)
+    where
+        F: Fn(i64, i64) + Send + 'static,
+    {
+        let statistics_manager = self.statistics_manager.clone();
+        self.rt.spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                ticker.tick().await;
+                let (up, down) = statistics_manager.now();
+                f(up, down);
+            }
+        });
+    }
+
+    /// Call `f` every `period` from a task of the instance, so the calls
+    /// stop when its runtime is stuck, e.g. to ping a watchdog.
+    pub fn on_heartbeat<F>(&self, period: Duration, f: F)
+    where
+        F: Fn() + Send + 'static,
+    {
+        self.rt.spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            loop {
+                ticker.tick().await;
+                f();
+            }
+        });
     }
 }
 
-async fn start_async(opts: Options) -> Result<(), Error> {
-    let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+/// Start an instance on a new thread, returns once the listeners are up or
+/// with the error that kept it from starting.
+pub fn start(opts: Options) -> Result<Handle, Error> {
+    let (ready_tx, ready_rx) = std::sync::mpsc::sync_channel(1);
+
+    let thread = thread::Builder::new().name("clash".to_owned()).spawn(
+        move || -> Result<(), Error> {
+            let rt = match opts.rt.as_ref().unwrap_or(&TokioRuntime::MultiThread) {
+                TokioRuntime::MultiThread => {
+                    tokio::runtime::Builder::new_multi_thread()
+                        .enable_all()
+                        .build()?
+                }
+                TokioRuntime::SingleThread => {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?
+                }
+            };
+
+            rt.block_on(async {
+                match start_async(opts, ready_tx).await {
+                    Err(e) => {
+                        eprintln!("start error: {}", e);
+                        Err(e)
+                    }
+                    Ok(_) => Ok(()),
+                }
+            })
+        },
+    )?;
+
+    match ready_rx.recv() {
+        Ok(mut handle) => {
+            handle.thread = Some(thread);
+            Ok(handle)
+        }
+        // the sender is dropped when starting fails
+        Err(_) => match thread.join() {
+            Ok(Err(e)) => Err(e),
+            Ok(Ok(_)) => Err(Error::Operation("stopped while starting".to_owned())),
+            Err(_) => Err(Error::Operation("runtime panicked".to_owned())),
+        },
+    }
+}
 
-    let _ = RUNTIME_CONTROLLER.get_or_init(|| RuntimeController { shutdown_tx });
+async fn start_async(
+    opts: Options,
+    ready_tx: std::sync::mpsc::SyncSender<Handle>,
+) -> Result<(), Error> {
+    Lazy::force(&START_TIME);
 
-    let config: InternalConfig = opts.config.try_parse()?;
+    let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
 
+    let config_path = match &opts.config {
+        Config::File(path) => Some(path.clone()),
+        _ => None,
+    };
     let cwd = opts.cwd.unwrap_or_else(|| ".".to_string());
 
+    let (config, mut snapshot) =
+        opts.config.try_parse_with_snapshot(Path::new(&cwd))?;
+
     let (log_tx, _) = broadcast::channel(100);
 
     let log_collector = app::logging::EventCollector::new(vec![log_tx.clone()]);
 
+    let mut log_file = config.log_file.clone();
+    if let Some(path) = opts.log_file {
+        log_file.path = Some(path);
+    }
     let _g = app::logging::setup_logging(
         config.general.log_level,
+        config.general.log_format,
         log_collector,
         &cwd,
-        opts.log_file,
+        log_file,
     )
     .map_err(|x| eprintln!("failed to setup logging: {}", x))
     .unwrap_or_default();
@@ -168,36 +391,66 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     let cwd = PathBuf::from(cwd);
 
     debug!("initializing cache store");
+    let profile_name = config_path
+        .as_ref()
+        .and_then(|x| profiles::name_of(&cwd, Path::new(x)));
     let cache_store = profile::ThreadSafeCacheFile::new(
         cwd.join("cache.db").as_path().to_str().unwrap(),
         config.profile.store_selected,
+    )
+    .for_profile(profile_name.as_deref());
+
+    proxy::utils::set_source_port_range(config.general.outbound_port_range);
+    proxy::registry::set_outbound_init(config.general.outbound_init);
+    common::buffer_pool::set_buffer_pool(
+        config.relay_buffer.size,
+        config.relay_buffer.budget,
+        config
+            .relay_buffer
+            .auto
+            .then_some(config.relay_buffer.max_size),
     );
+    proxy::utils::set_socket_buffers(
+        config.relay_buffer.socket_recv,
+        config.relay_buffer.socket_send,
+    );
+    set_update_policy(config.provider_update);
 
     debug!("initializing dns resolver");
-    let system_resolver = Arc::new(
-        SystemResolver::new(config.general.ipv6 && config.dns.ipv6)
-            .map_err(|x| Error::DNSError(x.to_string()))?,
-    );
+    let system_resolver = new_system_resolver(&config)?;
     let client = new_http_client(system_resolver.clone())
         .map_err(|x| Error::DNSError(x.to_string()))?;
 
-    debug!("initializing mmdb");
-    let mmdb = Arc::new(
-        mmdb::Mmdb::new(
-            cwd.join(&config.general.mmdb),
-            config.general.mmdb_download_url,
-            client,
-        )
-        .await?,
-    );
+    let events_handle =
+        app::events::get_runner(config.events, client.clone()).map(tokio::spawn);
+    let net_monitor_handle = tokio::spawn(app::net_monitor::get_runner());
+
+    let geo_databases = GeoDatabases {
+        mmdb: cwd.join(&config.general.mmdb),
+        mmdb_download_url: config.general.mmdb_download_url.clone(),
+        geosite: cwd.join(&config.general.geosite),
+        geosite_download_url: config.general.geosite_download_url.clone(),
+        mmdb_integrity: config.general.mmdb_integrity.clone(),
+        geosite_integrity: config.general.geosite_integrity.clone(),
+    };
+
+    debug!("initializing mmdb and geosite");
+    let geosite_client = new_http_client(system_resolver)
+        .map_err(|x| Error::DNSError(x.to_string()))?;
+    let (mmdb, geodata) =
+        load_geo_databases(&cwd, &config.general, client, geosite_client).await;
 
     let dns_resolver = dns::new_resolver(
         &config.dns,
         Some(cache_store.clone()),
         Some(mmdb.clone()),
+        Some(geodata.clone()),
     )
     .await;
 
+    let ntp_handle = app::ntp::get_runner(config.ntp_service, dns_resolver.clone())
+        .map(tokio::spawn);
+
     debug!("initializing outbound manager");
     let outbound_manager = Arc::new(
         OutboundManager::new(
@@ -227,17 +480,6 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     );
 
     debug!("initializing router");
-    let client = new_http_client(system_resolver)
-        .map_err(|x| Error::DNSError(x.to_string()))?;
-    let geodata = Arc::new(
-        geodata::GeoData::new(
-            cwd.join(&config.general.geosite),
-            config.general.geosite_download_url,
-            client,
-        )
-        .await?,
-    );
-
     let router = Arc::new(
         Router::new(
             config.rules,
@@ -245,20 +487,35 @@ async fn start_async(opts: Options) -> Result<(), Error> {
             dns_resolver.clone(),
             mmdb,
             geodata,
+            cache_store.clone(),
             cwd.to_string_lossy().to_string(),
         )
-        .await,
+        .await
+        .with_finals(config.finals),
     );
 
-    let statistics_manager = StatisticsManager::new();
+    let statistics_manager = StatisticsManager::new(Some(cache_store.clone()));
+    statistics_manager.shaper().update(config.bandwidth);
+    statistics_manager.set_abort_stale(config.reload.abort_stale_connections);
 
-    let dispatcher = Arc::new(Dispatcher::new(
-        outbound_manager.clone(),
-        router.clone(),
-        dns_resolver.clone(),
-        config.general.mode,
-        statistics_manager.clone(),
-    ));
+    let dispatcher = Arc::new(
+        Dispatcher::new(
+            outbound_manager.clone(),
+            router.clone(),
+            dns_resolver.clone(),
+            config.general.mode,
+            config.general.idle_timeouts,
+            config.general.udp_fallback,
+            statistics_manager.clone(),
+            config.sniffer,
+            app::mitm::Mitm::new(config.mitm, &cwd)?,
+            app::plugins::Plugins::new(
+                config.experimental.map(|x| x.plugins).unwrap_or_default(),
+                &cwd,
+            )?,
+        )
+        .with_circuit_breaker(config.circuit_breaker),
+    );
 
     let authenticator = Arc::new(auth::PlainAuthenticator::new(config.users));
 
@@ -279,17 +536,28 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     debug!("initializing dns listener");
     let dns_listener_handle =
         dns::get_dns_listener(config.dns, dns_resolver.clone())
-            .await
+            .await?
             .map(tokio::spawn);
 
     let (reload_tx, mut reload_rx) = mpsc::channel(1);
+    let config_path: ConfigPath = Arc::new(RwLock::new(config_path));
+    #[cfg(unix)]
+    app::reload::reload_on_hangup(config_path.clone(), reload_tx.clone());
+    if config.reload.watch {
+        app::reload::watch(config_path.clone(), reload_tx.clone());
+    }
 
     let global_state = Arc::new(Mutex::new(GlobalState {
         log_level: config.general.log_level,
         inbound_listener_handle: Some(inbound_listener_handle),
         tunnel_listener_handle: tun_runner_handle,
         dns_listener_handle,
-        reload_tx,
+        events_handle,
+        ntp_handle,
+        net_monitor_handle: Some(net_monitor_handle),
+        reload_tx: reload_tx.clone(),
+        config_path: config_path.clone(),
+        shutdown_drain_timeout: Duration::from_secs(config.shutdown.drain_timeout),
         api_listener_handle: None,
         cwd: cwd.to_string_lossy().to_string(),
     }));
@@ -302,9 +570,10 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         global_state.clone(),
         dns_resolver,
         outbound_manager,
-        statistics_manager,
-        cache_store,
+        statistics_manager.clone(),
+        cache_store.clone(),
         router,
+        geo_databases,
         cwd.to_string_lossy().to_string(),
     );
     if let Some(r) = api_runner {
@@ -312,6 +581,20 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         global_state.lock().await.api_listener_handle = Some(api_listener_handle);
     }
 
+    let _ = ready_tx.send(Handle {
+        shutdown_tx,
+        reload_tx,
+        log_tx: log_tx.clone(),
+        statistics_manager: statistics_manager.clone(),
+        rt: tokio::runtime::Handle::current(),
+        thread: None,
+    });
+
+    // the reload task takes the originals
+    let rollback_tx = global_state.lock().await.reload_tx.clone();
+    let shutdown_global_state = global_state.clone();
+    let shutdown_statistics_manager = statistics_manager.clone();
+
     runners.push(Box::pin(async move {
         shutdown_rx.recv().await;
         info!("receiving shutdown signal");
@@ -323,194 +606,383 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     }));
 
     tasks.push(Box::pin(async move {
-        let _ = tokio::signal::ctrl_c().await;
+        app::shutdown::signal().await;
         Ok(())
     }));
 
     tasks.push(Box::pin(async move {
         while let Some((config, done)) = reload_rx.recv().await {
             info!("reloading config");
-            let config = match config.try_parse() {
-                Ok(c) => c,
-                Err(e) => {
-                    error!("failed to reload config: {}", e);
-                    continue;
-                }
+            let mut done = Some(done);
+            let path = match &config {
+                Config::File(path) => Some(path.clone()),
+                _ => None,
             };
-
-            debug!("reloading dns resolver");
-            let system_resolver = Arc::new(
-                SystemResolver::new(config.dns.ipv6)
-                    .map_err(|x| Error::DNSError(x.to_string()))?,
-            );
-            let client = new_http_client(system_resolver.clone())
-                .map_err(|x| Error::DNSError(x.to_string()))?;
-
-            debug!("reloading mmdb");
-            let mmdb = Arc::new(
-                mmdb::Mmdb::new(
-                    cwd.join(&config.general.mmdb),
-                    config.general.mmdb_download_url,
+            // past this the previous listeners are gone
+            let mut stopped = false;
+            let rv = async {
+                let (config, new_snapshot) = config.try_parse_with_snapshot(&cwd)?;
+                let diff = app::reload::ConfigDiff::between(
+                    snapshot.as_ref(),
+                    new_snapshot.as_ref(),
+                );
+                debug!("config changes: {:?}", diff);
+
+                proxy::utils::set_source_port_range(
+                    config.general.outbound_port_range,
+                );
+                proxy::registry::set_outbound_init(config.general.outbound_init);
+                common::buffer_pool::set_buffer_pool(
+                    config.relay_buffer.size,
+                    config.relay_buffer.budget,
+                    config
+                        .relay_buffer
+                        .auto
+                        .then_some(config.relay_buffer.max_size),
+                );
+                proxy::utils::set_socket_buffers(
+                    config.relay_buffer.socket_recv,
+                    config.relay_buffer.socket_send,
+                );
+                set_update_policy(config.provider_update);
+
+                debug!("reloading dns resolver");
+                let system_resolver = new_system_resolver(&config)?;
+                let client = new_http_client(system_resolver.clone())
+                    .map_err(|x| Error::DNSError(x.to_string()))?;
+
+                let events_runner =
+                    app::events::get_runner(config.events, client.clone());
+
+                let geo_databases = GeoDatabases {
+                    mmdb: cwd.join(&config.general.mmdb),
+                    mmdb_download_url: config.general.mmdb_download_url.clone(),
+                    geosite: cwd.join(&config.general.geosite),
+                    geosite_download_url: config
+                        .general
+                        .geosite_download_url
+                        .clone(),
+                    mmdb_integrity: config.general.mmdb_integrity.clone(),
+                    geosite_integrity: config.general.geosite_integrity.clone(),
+                };
+
+                debug!("reloading mmdb and geosite");
+                let geosite_client = new_http_client(system_resolver)
+                    .map_err(|x| Error::DNSError(x.to_string()))?;
+                let (mmdb, geodata) = load_geo_databases(
+                    &cwd,
+                    &config.general,
                     client,
+                    geosite_client,
                 )
-                .await?,
-            );
-
-            let client = new_http_client(system_resolver)
-                .map_err(|x| Error::DNSError(x.to_string()))?;
-            let geodata = Arc::new(
-                geodata::GeoData::new(
-                    cwd.join(&config.general.geosite),
-                    config.general.geosite_download_url,
-                    client,
+                .await;
+
+                debug!("reloading cache store");
+                let profile_name = path
+                    .as_ref()
+                    .and_then(|x| profiles::name_of(&cwd, Path::new(x)));
+                let cache_store = profile::ThreadSafeCacheFile::new(
+                    cwd.join("cache.db").as_path().to_str().unwrap(),
+                    config.profile.store_selected,
                 )
-                .await?,
-            );
-
-            debug!("reloading cache store");
-            let cache_store = profile::ThreadSafeCacheFile::new(
-                cwd.join("cache.db").as_path().to_str().unwrap(),
-                config.profile.store_selected,
-            );
-
-            let dns_resolver = dns::new_resolver(
-                &config.dns,
-                Some(cache_store.clone()),
-                Some(mmdb.clone()),
-            )
-            .await;
+                .for_profile(profile_name.as_deref());
 
-            debug!("reloading outbound manager");
-            let outbound_manager = Arc::new(
-                OutboundManager::new(
-                    config
-                        .proxies
-                        .into_values()
-                        .filter_map(|x| match x {
-                            OutboundProxy::ProxyServer(s) => Some(s),
-                            _ => None,
-                        })
-                        .collect(),
-                    config
-                        .proxy_groups
-                        .into_values()
-                        .filter_map(|x| match x {
-                            OutboundProxy::ProxyGroup(g) => Some(g),
-                            _ => None,
-                        })
-                        .collect(),
-                    config.proxy_providers,
-                    config.proxy_names,
-                    dns_resolver.clone(),
-                    cache_store.clone(),
-                    cwd.to_string_lossy().to_string(),
+                let dns_resolver = dns::new_resolver(
+                    &config.dns,
+                    Some(cache_store.clone()),
+                    Some(mmdb.clone()),
+                    Some(geodata.clone()),
                 )
-                .await?,
-            );
-
-            debug!("reloading router");
-            let router = Arc::new(
-                Router::new(
-                    config.rules,
-                    config.rule_providers,
+                .await;
+
+                debug!("reloading outbound manager");
+                let outbound_manager = Arc::new(
+                    OutboundManager::new(
+                        config
+                            .proxies
+                            .into_values()
+                            .filter_map(|x| match x {
+                                OutboundProxy::ProxyServer(s) => Some(s),
+                                _ => None,
+                            })
+                            .collect(),
+                        config
+                            .proxy_groups
+                            .into_values()
+                            .filter_map(|x| match x {
+                                OutboundProxy::ProxyGroup(g) => Some(g),
+                                _ => None,
+                            })
+                            .collect(),
+                        config.proxy_providers,
+                        config.proxy_names,
+                        dns_resolver.clone(),
+                        cache_store.clone(),
+                        cwd.to_string_lossy().to_string(),
+                    )
+                    .await?,
+                );
+
+                debug!("reloading router");
+                let router = Arc::new(
+                    Router::new(
+                        config.rules,
+                        config.rule_providers,
+                        dns_resolver.clone(),
+                        mmdb,
+                        geodata,
+                        cache_store.clone(),
+                        cwd.to_string_lossy().to_string(),
+                    )
+                    .await
+                    .with_finals(config.finals),
+                );
+
+                let dispatcher = Arc::new(
+                    Dispatcher::new(
+                        outbound_manager.clone(),
+                        router.clone(),
+                        dns_resolver.clone(),
+                        config.general.mode,
+                        config.general.idle_timeouts,
+                        config.general.udp_fallback,
+                        statistics_manager.clone(),
+                        config.sniffer,
+                        app::mitm::Mitm::new(config.mitm, &cwd)?,
+                        app::plugins::Plugins::new(
+                            config
+                                .experimental
+                                .map(|x| x.plugins)
+                                .unwrap_or_default(),
+                            &cwd,
+                        )?,
+                    )
+                    .with_circuit_breaker(config.circuit_breaker),
+                );
+
+                let authenticator =
+                    Arc::new(auth::PlainAuthenticator::new(config.users));
+
+                debug!("reloading inbound manager");
+                let inbound_manager = Arc::new(Mutex::new(InboundManager::new(
+                    config.general.inbound,
+                    dispatcher.clone(),
+                    authenticator,
+                )?));
+
+                // the listeners bind when they start running
+                let inbound_runner = inbound_manager.lock().await.get_runner()?;
+                let ntp_runner =
+                    app::ntp::get_runner(config.ntp_service, dns_resolver.clone());
+                let api_runner = app::api::get_api_runner(
+                    config.general.controller,
+                    log_tx.clone(),
+                    inbound_manager.clone(),
+                    dispatcher.clone(),
+                    global_state.clone(),
                     dns_resolver.clone(),
-                    mmdb,
-                    geodata,
+                    outbound_manager.clone(),
+                    statistics_manager.clone(),
+                    cache_store,
+                    router,
+                    geo_databases,
                     cwd.to_string_lossy().to_string(),
-                )
-                .await,
-            );
-
-            let statistics_manager = StatisticsManager::new();
-
-            let dispatcher = Arc::new(Dispatcher::new(
-                outbound_manager.clone(),
-                router.clone(),
-                dns_resolver.clone(),
-                config.general.mode,
-                statistics_manager.clone(),
-            ));
-
-            let authenticator =
-                Arc::new(auth::PlainAuthenticator::new(config.users));
-
-            debug!("reloading inbound manager");
-            let inbound_manager = Arc::new(Mutex::new(InboundManager::new(
-                config.general.inbound,
-                dispatcher.clone(),
-                authenticator,
-            )?));
-
-            done.send(()).unwrap();
-
-            debug!("stopping listeners");
-            let mut g = global_state.lock().await;
-            if let Some(h) = g.inbound_listener_handle.take() {
-                h.abort();
-            }
-            if let Some(h) = g.tunnel_listener_handle.take() {
-                h.abort();
-            }
-            if let Some(h) = g.dns_listener_handle.take() {
-                h.abort();
-            }
-            if let Some(h) = g.api_listener_handle.take() {
-                h.abort();
-            }
+                );
+
+                let previous = if diff.drains_connections() {
+                    statistics_manager.connection_ids().await
+                } else {
+                    vec![]
+                };
+
+                // the tun device and the dns sockets can only be taken over
+                // once the previous listeners let go of them
+                debug!("stopping listeners");
+                let mut g = global_state.lock().await;
+                stopped = true;
+                for handle in [
+                    g.inbound_listener_handle.take(),
+                    g.tunnel_listener_handle.take(),
+                    g.dns_listener_handle.take(),
+                    g.api_listener_handle.take(),
+                    g.events_handle.take(),
+                    g.ntp_handle.take(),
+                ] {
+                    stop_listeners(handle).await;
+                }
 
-            let inbound_listener_handle = inbound_manager
-                .lock()
-                .await
-                .get_runner()
-                .map(tokio::spawn)?;
-
-            let tun_runner_handle = get_tun_runner(
-                config.tun,
-                dispatcher.clone(),
-                dns_resolver.clone(),
-            )?
-            .map(tokio::spawn);
+                let tun_runner = get_tun_runner(
+                    config.tun,
+                    dispatcher.clone(),
+                    dns_resolver.clone(),
+                )?;
+
+                debug!("reloading dns listener");
+                let dns_listener =
+                    dns::get_dns_listener(config.dns, dns_resolver.clone()).await?;
+
+                g.inbound_listener_handle = Some(tokio::spawn(inbound_runner));
+                g.tunnel_listener_handle = tun_runner.map(tokio::spawn);
+                g.dns_listener_handle = dns_listener.map(tokio::spawn);
+                g.api_listener_handle = api_runner.map(tokio::spawn);
+                g.events_handle = events_runner.map(tokio::spawn);
+                g.ntp_handle = ntp_runner.map(tokio::spawn);
+                g.shutdown_drain_timeout =
+                    Duration::from_secs(config.shutdown.drain_timeout);
+                drop(g);
+
+                if let Some(done) = done.take() {
+                    let _ = done.send(Ok(()));
+                }
 
-            debug!("reloading dns listener");
-            let dns_listener_handle =
-                dns::get_dns_listener(config.dns, dns_resolver.clone())
-                    .await
-                    .map(tokio::spawn);
-
-            debug!("reloading api listener");
-            let api_listener_handle = app::api::get_api_runner(
-                config.general.controller,
-                log_tx.clone(),
-                inbound_manager.clone(),
-                dispatcher,
-                global_state.clone(),
-                dns_resolver,
-                outbound_manager,
-                statistics_manager,
-                cache_store,
-                router,
-                cwd.to_string_lossy().to_string(),
-            )
-            .map(tokio::spawn);
+                statistics_manager
+                    .set_abort_stale(config.reload.abort_stale_connections);
+                if diff.outbounds {
+                    let names = outbound_manager.proxy_names().await;
+                    statistics_manager
+                        .abort_stale("proxy removed by a reload", |chain| {
+                            chain.iter().any(|x| !names.contains(x))
+                        })
+                        .await;
+                    app::metrics::retain_proxies(|x| names.contains(x));
+                }
+                if diff.rules {
+                    app::metrics::reset_rule_hits();
+                }
+                statistics_manager.shaper().update(config.bandwidth);
+                app::reload::drain(
+                    statistics_manager.clone(),
+                    previous,
+                    Duration::from_secs(config.reload.drain_timeout),
+                );
+                snapshot = new_snapshot;
+                if let Some(path) = &path {
+                    *config_path.write().await = Some(path.clone());
+                }
+                Ok::<_, Error>(())
+            }
+            .await;
 
-            g.inbound_listener_handle = Some(inbound_listener_handle);
-            g.tunnel_listener_handle = tun_runner_handle;
-            g.dns_listener_handle = dns_listener_handle;
-            g.api_listener_handle = api_listener_handle;
+            match rv {
+                Ok(_) => {
+                    info!("config reloaded");
+                    app::events::publish(app::events::Event::ConfigReloaded);
+                }
+                Err(e) => {
+                    error!("failed to reload config: {}", e);
+                    app::events::publish(app::events::Event::ConfigReloadFailed {
+                        error: e.to_string(),
+                    });
+                    if let Some(done) = done.take() {
+                        let _ = done.send(Err(e.to_string()));
+                    }
+                    if stopped {
+                        rollback(&rollback_tx, snapshot.as_ref());
+                    }
+                }
+            }
         }
         Ok(())
     }));
 
-    futures::future::select_all(tasks).await.0.map_err(|x| {
+    let rv = futures::future::select_all(tasks).await.0.map_err(|x| {
         error!("runtime error: {}, shutting down", x);
         x
-    })
+    });
+
+    info!("shutting down");
+    let mut g = shutdown_global_state.lock().await;
+    for handle in [
+        g.inbound_listener_handle.take(),
+        g.tunnel_listener_handle.take(),
+        g.dns_listener_handle.take(),
+        g.api_listener_handle.take(),
+        g.net_monitor_handle.take(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        handle.abort();
+    }
+    let drain_timeout = g.shutdown_drain_timeout;
+    drop(g);
+    app::shutdown::drain(&shutdown_statistics_manager, drain_timeout).await;
+    app::shutdown::save(&shutdown_statistics_manager, &cache_store).await;
+
+    rv
+}
+
+/// The resolver of the HTTP clients, the same at start and on reload, with
+/// IPv6 only if it's enabled both in general and for DNS.
+fn new_system_resolver(
+    config: &InternalConfig,
+) -> Result<Arc<SystemResolver>, Error> {
+    SystemResolver::new(config.general.ipv6 && config.dns.ipv6)
+        .map(Arc::new)
+        .map_err(|x| Error::DNSError(x.to_string()))
+}
+
+/// The mmdb and geosite, both downloaded at the same time if they have to
+/// be. Either failing leaves its rules matching nothing rather than keeping
+/// clash-rs from starting.
+async fn load_geo_databases(
+    cwd: &Path,
+    general: &config::internal::config::General,
+    client: common::http::HttpClient,
+    geosite_client: common::http::HttpClient,
+) -> (Arc<mmdb::Mmdb>, Arc<geodata::GeoData>) {
+    let (mmdb, geodata) = tokio::join!(
+        mmdb::Mmdb::new(
+            cwd.join(&general.mmdb),
+            general.mmdb_download_url.clone(),
+            &general.mmdb_integrity,
+            client,
+        ),
+        geodata::GeoData::new(
+            cwd.join(&general.geosite),
+            general.geosite_download_url.clone(),
+            &general.geosite_integrity,
+            geosite_client,
+        ),
+    );
+    let mmdb = mmdb.unwrap_or_else(|e| {
+        error!("failed to load mmdb, GEOIP rules match nothing: {}", e);
+        mmdb::Mmdb::empty()
+    });
+    let geodata = geodata.unwrap_or_else(|e| {
+        error!("failed to load geosite, GEOSITE rules match nothing: {}", e);
+        geodata::GeoData::empty()
+    });
+    (Arc::new(mmdb), Arc::new(geodata))
+}
+
+/// Bring the previous config back after a reload failed with its listeners
+/// already stopped.
+fn rollback(reload_tx: &ReloadSender, snapshot: Option<&serde_json::Value>) {
+    let Some(previous) =
+        snapshot.and_then(|x| serde_json::from_value::<def::Config>(x.clone()).ok())
+    else {
+        error!("the previous config can't be restored, listeners stay down");
+        return;
+    };
+    warn!("restoring the previous config");
+    let (done, _) = tokio::sync::oneshot::channel();
+    if reload_tx.try_send((Config::Def(previous), done)).is_err() {
+        error!("the previous config can't be restored, listeners stay down");
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{shutdown, start, Config, Options};
-    use std::{thread, time::Duration};
+    use crate::{start, Config, Options};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
 
     #[test]
     fn start_and_stop() {
@@ -520,21 +992,35 @@ mod tests {
         mmdb: "tests/data/Country.mmdb"
         "#;
 
-        let handle = thread::spawn(|| {
-            start(Options {
-                config: Config::Str(conf.to_string()),
-                cwd: None,
-                rt: None,
-                log_file: None,
-            })
-            .unwrap()
-        });
+        let handle = start(Options {
+            config: Config::Str(conf.to_string()),
+            cwd: None,
+            rt: None,
+            log_file: None,
+        })
+        .unwrap();
 
-        thread::spawn(|| {
-            thread::sleep(Duration::from_secs(3));
-            assert!(shutdown());
-        });
+        let traffic = Arc::new(AtomicBool::new(false));
+        let t = traffic.clone();
+        handle.on_traffic(move |_, _| t.store(true, Ordering::Relaxed));
+
+        handle.reload(Config::Str(conf.to_string())).unwrap();
+        assert!(handle.reload(Config::Str("port: x".to_string())).is_err());
 
-        handle.join().unwrap();
+        thread::sleep(Duration::from_secs(2));
+        assert!(traffic.load(Ordering::Relaxed));
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn start_error() {
+        assert!(start(Options {
+            config: Config::Str("port: x".to_string()),
+            cwd: None,
+            rt: None,
+            log_file: None,
+        })
+        .is_err());
     }
 }
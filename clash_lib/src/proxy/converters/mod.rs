@@ -7,3 +7,17 @@ pub mod trojan;
 pub mod tuic;
 pub mod vmess;
 pub mod wireguard;
+
+/// the decoded `pin-sha256` of the outbound `name`
+fn parse_pins(
+    name: &str,
+    pins: Option<&Vec<String>>,
+) -> Result<Vec<Vec<u8>>, crate::Error> {
+    pins.into_iter()
+        .flatten()
+        .map(|x| {
+            crate::common::tls::parse_pin(x)
+                .map_err(|e| crate::Error::InvalidConfig(format!("{}: {}", name, e)))
+        })
+        .collect()
+}
@@ -28,6 +28,10 @@ impl TryFrom<&OutboundSocks5> for AnyOutboundHandler {
             udp: s.udp,
             tls: s.tls,
             sni: s.sni.clone().unwrap_or(s.server.to_owned()),
+            alpn: s.alpn.clone(),
+            min_tls_version: s.min_tls_version,
+            max_tls_version: s.max_tls_version,
+            pin_sha256: super::parse_pins(&s.name, s.pin_sha256.as_ref())?,
             skip_cert_verify: s.skip_cert_verify,
         });
         Ok(h)
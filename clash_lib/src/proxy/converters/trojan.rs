@@ -40,6 +40,9 @@ impl TryFrom<&OutboundTrojan> for AnyOutboundHandler {
                 .map(|x| x.to_owned())
                 .unwrap_or(s.server.to_owned()),
             alpn: s.alpn.as_ref().map(|x| x.to_owned()),
+            min_tls_version: s.min_tls_version,
+            max_tls_version: s.max_tls_version,
+            pin_sha256: super::parse_pins(&s.name, s.pin_sha256.as_ref())?,
             skip_cert_verify,
             transport: s
                 .network
@@ -59,6 +59,7 @@ impl TryFrom<&OutboundTuic> for AnyOutboundHandler {
                 .unwrap_or(VarInt::MAX),
             ip: s.ip.clone(),
             skip_cert_verify: s.skip_cert_verify.unwrap_or(false),
+            pin_sha256: super::parse_pins(&s.name, s.pin_sha256.as_ref())?,
             sni: s.sni.clone(),
             gc_interval: Duration::from_millis(s.gc_interval.unwrap_or(3000)),
             gc_lifetime: Duration::from_millis(s.gc_lifetime.unwrap_or(15000)),
@@ -130,19 +130,25 @@ impl TryFrom<&OutboundVmess> for AnyOutboundHandler {
                             .unwrap_or(s.server.to_owned())
                             .to_owned(),
                     ),
-                    alpn: s
-                        .network
-                        .as_ref()
-                        .map(|x| match x.as_str() {
-                            "ws" => Ok(vec!["http/1.1".to_owned()]),
-                            "http" => Ok(vec![]),
-                            "h2" | "grpc" => Ok(vec!["h2".to_owned()]),
-                            _ => Err(Error::InvalidConfig(format!(
-                                "unsupported network: {}",
-                                x
-                            ))),
-                        })
-                        .transpose()?,
+                    alpn: match &s.alpn {
+                        Some(alpn) => Some(alpn.clone()),
+                        None => s
+                            .network
+                            .as_ref()
+                            .map(|x| match x.as_str() {
+                                "ws" => Ok(vec!["http/1.1".to_owned()]),
+                                "http" => Ok(vec![]),
+                                "h2" | "grpc" => Ok(vec!["h2".to_owned()]),
+                                _ => Err(Error::InvalidConfig(format!(
+                                    "unsupported network: {}",
+                                    x
+                                ))),
+                            })
+                            .transpose()?,
+                    },
+                    min_version: s.min_tls_version,
+                    max_version: s.max_tls_version,
+                    pin_sha256: super::parse_pins(&s.name, s.pin_sha256.as_ref())?,
                 }),
                 false => None,
             },
@@ -1,4 +1,5 @@
 use ipnet::IpNet;
+use tracing::warn;
 
 use crate::{
     config::internal::proxy::OutboundWireguard,
@@ -21,6 +22,7 @@ impl TryFrom<&OutboundWireguard> for AnyOutboundHandler {
     type Error = crate::Error;
 
     fn try_from(s: &OutboundWireguard) -> Result<Self, Self::Error> {
+        warn!("wireguard is experimental");
         let h = Handler::new(HandlerOptions {
             name: s.name.to_owned(),
             server: s.server.to_owned(),
@@ -70,6 +72,7 @@ impl TryFrom<&OutboundWireguard> for AnyOutboundHandler {
             udp: s.udp.unwrap_or_default(),
             allowed_ips: s.allowed_ips.as_ref().map(|x| x.to_owned()),
             reserved_bits: s.reserved_bits.as_ref().map(|x| x.to_owned()),
+            faketcp: s.faketcp.unwrap_or_default(),
         });
         Ok(h)
     }
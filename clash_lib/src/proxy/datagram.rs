@@ -1,19 +1,23 @@
 use crate::{
     app::dns::ThreadSafeDNSResolver,
-    proxy::{socks::Socks5UDPCodec, AnyOutboundDatagram, InboundDatagram},
+    proxy::{
+        socks::Socks5UDPCodec,
+        utils::{BatchUdpSocket, BATCH_SIZE},
+        AnyOutboundDatagram, InboundDatagram,
+    },
     session::SocksAddr,
 };
-use bytes::Bytes;
-use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use bytes::BytesMut;
+use futures::{ready, Sink, Stream};
 use std::{
+    collections::VecDeque,
     fmt::{Debug, Display, Formatter},
     io,
-    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::{io::ReadBuf, net::UdpSocket};
-use tokio_util::udp::UdpFramed;
+use tokio::net::UdpSocket;
+use tokio_util::codec::{Decoder, Encoder};
 
 #[derive(Clone)]
 pub struct UdpPacket {
@@ -63,27 +67,26 @@ impl UdpPacket {
     }
 }
 
-pub struct InboundUdp<I> {
-    inner: I,
+/// The UDP relay of a SOCKS5 UDP ASSOCIATE.
+pub struct InboundUdp {
+    inner: BatchUdpSocket,
 }
 
-impl<I> InboundUdp<I>
-where
-    I: Stream + Unpin,
-    I: Sink<((Bytes, SocksAddr), SocketAddr)>,
-{
-    pub fn new(inner: I) -> Self {
-        Self { inner }
+impl InboundUdp {
+    pub fn new(inner: UdpSocket) -> Self {
+        Self {
+            inner: BatchUdpSocket::new(inner),
+        }
     }
 }
 
-impl Debug for InboundUdp<UdpFramed<Socks5UDPCodec>> {
+impl Debug for InboundUdp {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("InboundUdp").finish()
     }
 }
 
-impl Stream for InboundUdp<UdpFramed<Socks5UDPCodec>> {
+impl Stream for InboundUdp {
     type Item = UdpPacket;
 
     fn poll_next(
@@ -92,24 +95,27 @@ impl Stream for InboundUdp<UdpFramed<Socks5UDPCodec>> {
     ) -> Poll<Option<Self::Item>> {
         let pin = self.get_mut();
 
-        match pin.inner.poll_next_unpin(cx) {
-            Poll::Ready(item) => match item {
-                None => Poll::Ready(None),
-                Some(item) => match item {
-                    Ok(((dst, pkt), src)) => Poll::Ready(Some(UdpPacket {
+        loop {
+            let (data, src) = match ready!(pin.inner.poll_recv_from(cx)) {
+                Ok(x) => x,
+                Err(_) => return Poll::Ready(None),
+            };
+            match Socks5UDPCodec.decode(&mut BytesMut::from(data.as_slice())) {
+                Ok(Some((dst, pkt))) => {
+                    return Poll::Ready(Some(UdpPacket {
                         data: pkt.to_vec(),
                         src_addr: SocksAddr::Ip(src),
                         dst_addr: dst,
-                    })),
-                    Err(_) => Poll::Ready(None),
-                },
-            },
-            Poll::Pending => Poll::Pending,
+                    }))
+                }
+                Ok(None) => continue,
+                Err(_) => return Poll::Ready(None),
+            }
         }
     }
 }
 
-impl Sink<UdpPacket> for InboundUdp<UdpFramed<Socks5UDPCodec>> {
+impl Sink<UdpPacket> for InboundUdp {
     type Error = std::io::Error;
 
     fn poll_ready(
@@ -117,42 +123,44 @@ impl Sink<UdpPacket> for InboundUdp<UdpFramed<Socks5UDPCodec>> {
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
         let pin = self.get_mut();
-        pin.inner.poll_ready_unpin(cx)
+        if pin.inner.queued() >= BATCH_SIZE {
+            ready!(pin.inner.poll_send(cx))?;
+        }
+        Poll::Ready(Ok(()))
     }
 
     fn start_send(self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
         let pin = self.get_mut();
-        pin.inner.start_send_unpin((
-            (item.data.into(), item.src_addr),
-            item.dst_addr.must_into_socket_addr(),
-        ))
+        let mut buf = BytesMut::new();
+        Socks5UDPCodec.encode((item.data.into(), item.src_addr), &mut buf)?;
+        pin.inner
+            .queue_send(buf.to_vec(), item.dst_addr.must_into_socket_addr());
+        Ok(())
     }
 
     fn poll_flush(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        let pin = self.get_mut();
-        pin.inner.poll_flush_unpin(cx)
+        self.get_mut().inner.poll_send(cx)
     }
 
     fn poll_close(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        let pin = self.get_mut();
-        pin.inner.poll_close_unpin(cx)
+        self.get_mut().inner.poll_send(cx)
     }
 }
 
-impl InboundDatagram<UdpPacket> for InboundUdp<UdpFramed<Socks5UDPCodec>> {}
+impl InboundDatagram<UdpPacket> for InboundUdp {}
 
 #[must_use = "sinks do nothing unless polled"]
 pub struct OutboundDatagramImpl {
-    inner: UdpSocket,
+    inner: BatchUdpSocket,
     resolver: ThreadSafeDNSResolver,
-    flushed: bool,
-    pkt: Option<UdpPacket>,
+    /// packets whose destination may still need resolving
+    pending: VecDeque<UdpPacket>,
 }
 
 impl OutboundDatagramImpl {
@@ -162,10 +170,9 @@ impl OutboundDatagramImpl {
         resolver: ThreadSafeDNSResolver,
     ) -> AnyOutboundDatagram {
         let s = Self {
-            inner: udp,
+            inner: BatchUdpSocket::new(udp),
             resolver,
-            flushed: true,
-            pkt: None,
+            pending: VecDeque::new(),
         };
         Box::new(s) as _
     }
@@ -178,20 +185,15 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        if !self.flushed {
-            match self.poll_flush(cx)? {
-                Poll::Ready(()) => {}
-                Poll::Pending => return Poll::Pending,
-            }
+        if self.pending.len() + self.inner.queued() >= BATCH_SIZE {
+            ready!(self.poll_flush(cx))?;
         }
 
         Poll::Ready(Ok(()))
     }
 
     fn start_send(self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
-        let pin = self.get_mut();
-        pin.pkt = Some(item);
-        pin.flushed = false;
+        self.get_mut().pending.push_back(item);
         Ok(())
     }
 
@@ -199,61 +201,38 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        if self.flushed {
-            return Poll::Ready(Ok(()));
-        }
-
         let Self {
             ref mut inner,
-            ref mut pkt,
+            ref mut pending,
             ref resolver,
-            ..
         } = *self;
 
-        if pkt.is_some() {
-            let p = pkt.as_ref().unwrap();
-            let dst = &p.dst_addr;
-            let data = &p.data;
-            let dst = match dst {
+        while let Some(p) = pending.front() {
+            let dst = match &p.dst_addr {
                 SocksAddr::Domain(domain, port) => {
                     let domain = domain.to_string();
                     let port = *port;
                     let mut fut = resolver.resolve(domain.as_str(), false);
-                    let ip = ready!(fut.as_mut().poll(cx).map_err(|_| {
-                        io::Error::new(io::ErrorKind::Other, "resolve domain failed")
-                    }))?;
-                    if let Some(ip) = ip {
-                        (ip, port).into()
-                    } else {
-                        return Poll::Ready(Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("resolve domain failed: {}", domain),
-                        )));
-                    }
+                    let ip = match ready!(fut.as_mut().poll(cx)) {
+                        Ok(Some(ip)) => ip,
+                        Ok(None) | Err(_) => {
+                            pending.pop_front();
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                format!("resolve domain failed: {}", domain),
+                            )));
+                        }
+                    };
+                    (ip, port).into()
                 }
                 SocksAddr::Ip(addr) => *addr,
             };
 
-            let n = ready!(inner.poll_send_to(cx, data.as_slice(), dst))?;
-            let wrote_all = n == data.len();
-            self.pkt = None;
-            self.flushed = true;
-
-            let res = if wrote_all {
-                Ok(())
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "failed to write entire datagram",
-                ))
-            };
-            Poll::Ready(res)
-        } else {
-            Poll::Ready(Err(io::Error::new(
-                io::ErrorKind::Other,
-                "no packet to send",
-            )))
+            let p = pending.pop_front().unwrap();
+            inner.queue_send(p.data, dst);
         }
+
+        inner.poll_send(cx)
     }
 
     fn poll_close(
@@ -271,18 +250,12 @@ impl Stream for OutboundDatagramImpl {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        let Self { ref mut inner, .. } = *self;
-        let mut mem = vec![0u8; 65535];
-        let mut buf = ReadBuf::new(&mut mem);
-        match ready!(inner.poll_recv_from(cx, &mut buf)) {
-            Ok(src) => {
-                let data = buf.filled().to_vec();
-                Poll::Ready(Some(UdpPacket {
-                    data,
-                    src_addr: src.into(),
-                    dst_addr: SocksAddr::any_ipv4(),
-                }))
-            }
+        match ready!(self.inner.poll_recv_from(cx)) {
+            Ok((data, src)) => Poll::Ready(Some(UdpPacket {
+                data,
+                src_addr: src.into(),
+                dst_addr: SocksAddr::any_ipv4(),
+            })),
             Err(_) => Poll::Ready(None),
         }
     }
@@ -9,7 +9,7 @@ use crate::{
     config::internal::proxy::PROXY_DIRECT,
     proxy::{
         datagram::OutboundDatagramImpl,
-        utils::{new_tcp_stream, new_udp_socket},
+        utils::{dial_tcp, new_udp_socket},
         AnyOutboundHandler, OutboundHandler,
     },
     session::Session,
@@ -45,12 +45,15 @@ impl OutboundHandler for Handler {
         true
     }
 
+    /// Domains are dialed by name, resolved only when connecting, the
+    /// client's TLS and Host stay those of the domain. Rules with
+    /// `resolve=local` hand it an IP instead.
     async fn connect_stream(
         &self,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedStream> {
-        let s = new_tcp_stream(
+        let s = dial_tcp(
             resolver,
             sess.destination.host().as_str(),
             sess.destination.port(),
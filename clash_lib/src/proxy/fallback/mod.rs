@@ -15,7 +15,10 @@ use crate::{
 };
 
 use super::{
-    utils::{provider_helper::get_proxies_from_providers, RemoteConnector},
+    utils::{
+        provider_helper::get_proxies_from_providers, retry::dial_with_retry,
+        RemoteConnector,
+    },
     AnyOutboundHandler, ConnectorType, OutboundHandler, OutboundType,
 };
 
@@ -51,7 +54,7 @@ impl Handler {
     async fn find_alive_proxy(&self, touch: bool) -> AnyOutboundHandler {
         let proxies = self.get_proxies(touch).await;
         for proxy in proxies.iter() {
-            if self.proxy_manager.alive(proxy.name()).await {
+            if self.proxy_manager.available(proxy.name()).await {
                 debug!("`{}` fallback to `{}`", self.name(), proxy.name());
                 return proxy.clone();
             }
@@ -85,13 +88,19 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
         let proxy = self.find_alive_proxy(true).await;
-        match proxy.connect_stream(sess, resolver).await {
-            Ok(s) => {
-                s.append_to_chain(self.name()).await;
-                Ok(s)
-            }
-            Err(e) => Err(e),
-        }
+        let s = dial_with_retry(
+            self.name(),
+            proxy,
+            self.get_proxies(false).await,
+            &self.proxy_manager,
+            |proxy| {
+                let resolver = resolver.clone();
+                async move { proxy.connect_stream(sess, resolver).await }
+            },
+        )
+        .await?;
+        s.append_to_chain(self.name()).await;
+        Ok(s)
     }
 
     /// connect to remote target via UDP
@@ -101,7 +110,17 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
         let proxy = self.find_alive_proxy(true).await;
-        proxy.connect_datagram(sess, resolver).await
+        dial_with_retry(
+            self.name(),
+            proxy,
+            self.get_proxies(false).await,
+            &self.proxy_manager,
+            |proxy| {
+                let resolver = resolver.clone();
+                async move { proxy.connect_datagram(sess, resolver).await }
+            },
+        )
+        .await
     }
 
     async fn support_connector(&self) -> ConnectorType {
@@ -115,9 +134,25 @@ impl OutboundHandler for Handler {
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
         let proxy = self.find_alive_proxy(true).await;
-        proxy
-            .connect_stream_with_connector(sess, resolver, connector)
-            .await
+        dial_with_retry(
+            self.name(),
+            proxy,
+            self.get_proxies(false).await,
+            &self.proxy_manager,
+            |proxy| {
+                let resolver = resolver.clone();
+                async move {
+                    proxy
+                        .connect_stream_with_connector(sess, resolver, connector)
+                        .await
+                }
+            },
+        )
+        .await
+    }
+
+    async fn members(&self) -> Option<Vec<AnyOutboundHandler>> {
+        Some(get_proxies_from_providers(&self.providers, false).await)
     }
 
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
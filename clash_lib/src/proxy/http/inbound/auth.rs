@@ -24,11 +24,12 @@ fn decode_basic_proxy_authorization(cred: &str) -> Option<(String, String)> {
     Some((user.to_owned(), pass.to_owned()))
 }
 
-/// returns a auth required response on auth failure
+/// returns the authenticated user, or a auth required response on auth
+/// failure
 pub fn authenticate_req(
     req: &Request<Body>,
     authenticator: ThreadSafeAuthenticator,
-) -> Option<Response<Body>> {
+) -> Result<String, Response<Body>> {
     let auth_resp = Response::builder()
         .status(hyper::StatusCode::PROXY_AUTHENTICATION_REQUIRED)
         .header(hyper::header::PROXY_AUTHENTICATE, "Basic")
@@ -36,19 +37,19 @@ pub fn authenticate_req(
         .unwrap();
     let cred = parse_basic_proxy_authorization(req);
     if cred.is_none() {
-        return Some(auth_resp);
+        return Err(auth_resp);
     }
     let cred = decode_basic_proxy_authorization(cred.unwrap());
     if cred.is_none() {
-        return Some(auth_resp);
+        return Err(auth_resp);
     }
 
     let (user, pass) = cred.unwrap();
 
     if authenticator.authenticate(&user, &pass) {
-        None
+        Ok(user)
     } else {
         warn!("proxy authentication failed");
-        Some(auth_resp)
+        Err(auth_resp)
     }
 }
@@ -3,7 +3,9 @@ mod connector;
 mod proxy;
 
 use crate::{
+    app::inbound::activation,
     common::auth::ThreadSafeAuthenticator,
+    config::def::InboundTcp,
     proxy::{utils::apply_tcp_options, AnyInboundListener, InboundListener},
     Dispatcher,
 };
@@ -12,12 +14,12 @@ use async_trait::async_trait;
 pub use proxy::handle as handle_http;
 
 use std::{io, net::SocketAddr, sync::Arc};
-use tokio::net::TcpListener;
 use tracing::warn;
 
 #[derive(Clone)]
 pub struct Listener {
     addr: SocketAddr,
+    tcp_options: InboundTcp,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
 }
@@ -32,11 +34,13 @@ impl Listener {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(
         addr: SocketAddr,
+        tcp_options: InboundTcp,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
     ) -> AnyInboundListener {
         Arc::new(Self {
             addr,
+            tcp_options,
             dispatcher,
             authenticator,
         }) as _
@@ -54,7 +58,8 @@ impl InboundListener for Listener {
     }
 
     async fn listen_tcp(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener =
+            activation::bind_tcp("http", self.addr, &self.tcp_options).await?;
 
         loop {
             let (socket, src_addr) = listener.accept().await?;
@@ -65,7 +70,8 @@ impl InboundListener for Listener {
             let author = self.authenticator.clone();
 
             tokio::spawn(async move {
-                proxy::handle(Box::new(socket), src_addr, dispatcher, author).await
+                proxy::handle(Box::new(socket), src_addr, "HTTP", dispatcher, author)
+                    .await
             });
         }
     }
@@ -40,19 +40,28 @@ pub fn maybe_socks_addr(r: &Uri) -> Option<SocksAddr> {
 async fn proxy(
     req: Request<Body>,
     src: SocketAddr,
+    inbound_name: &'static str,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
 ) -> Result<Response<Body>, ProxyError> {
-    if authenticator.enabled() {
-        if let Some(res) = authenticate_req(&req, authenticator) {
-            return Ok(res);
+    let user = if authenticator.enabled() {
+        match authenticate_req(&req, authenticator) {
+            Ok(user) => Some(user),
+            Err(res) => return Ok(res),
         }
-    }
+    } else {
+        None
+    };
 
     let client = Client::builder()
         .http1_title_case_headers(true)
         .http1_preserve_header_case(true)
-        .build(Connector::new(src, dispatcher.clone()));
+        .build(Connector::new(
+            src,
+            inbound_name,
+            user.clone(),
+            dispatcher.clone(),
+        ));
 
     // TODO: handle other upgrades: https://github.com/hyperium/hyper/blob/master/examples/upgrades.rs
     if req.method() == Method::CONNECT {
@@ -65,6 +74,8 @@ async fn proxy(
                             typ: Type::HttpConnect,
                             source: src,
                             destination: addr,
+                            inbound_name: inbound_name.to_owned(),
+                            inbound_user: user,
 
                             ..Default::default()
                         };
@@ -102,6 +113,7 @@ async fn proxy(
 
 struct ProxyService {
     src: SocketAddr,
+    inbound_name: &'static str,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
 }
@@ -122,6 +134,7 @@ impl Service<Request<Body>> for ProxyService {
         Box::pin(proxy(
             req,
             self.src,
+            self.inbound_name,
             self.dispatcher.clone(),
             self.authenticator.clone(),
         ))
@@ -132,6 +145,7 @@ impl Service<Request<Body>> for ProxyService {
 pub async fn handle(
     stream: AnyStream,
     src: SocketAddr,
+    inbound_name: &'static str,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
 ) {
@@ -143,6 +157,7 @@ pub async fn handle(
                 stream,
                 ProxyService {
                     src,
+                    inbound_name,
                     dispatcher,
                     authenticator,
                 },
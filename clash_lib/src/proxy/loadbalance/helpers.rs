@@ -6,15 +6,42 @@ use public_suffix::{EffectiveTLDProvider, DEFAULT_PROVIDER};
 
 use crate::{proxy::AnyOutboundHandler, session::Session};
 
+/// the members to balance over and their weights
+pub type Weighted = Vec<(AnyOutboundHandler, u32)>;
+
 pub type StrategyFn = Box<
     dyn FnMut(
-            Vec<AnyOutboundHandler>,
+            Weighted,
             &Session,
         ) -> BoxFuture<'static, std::io::Result<AnyOutboundHandler>>
         + Send
         + Sync,
 >;
 
+fn total_weight(proxies: &Weighted) -> u64 {
+    proxies.iter().map(|(_, w)| *w as u64).sum()
+}
+
+/// The member at `point` of `0..total_weight`, each member taking up as
+/// much of it as its weight.
+fn at(proxies: &Weighted, point: u64) -> Option<AnyOutboundHandler> {
+    let mut end = 0;
+    for (proxy, weight) in proxies {
+        end += *weight as u64;
+        if point < end {
+            return Some(proxy.clone());
+        }
+    }
+    None
+}
+
+fn not_found() -> BoxFuture<'static, std::io::Result<AnyOutboundHandler>> {
+    Box::pin(futures::future::err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "no proxy found",
+    )))
+}
+
 fn get_key(sess: &Session) -> String {
     match &sess.destination {
         crate::session::SocksAddr::Ip(addr) => addr.ip().to_string(),
@@ -39,27 +66,82 @@ fn jump_hash(key: u64, buckets: i32) -> i32 {
 
 pub fn strategy_rr() -> StrategyFn {
     let mut index = 0;
-    Box::new(move |proxies: Vec<AnyOutboundHandler>, _: &Session| {
-        let len = proxies.len();
-        index = (index + 1) % len;
-        Box::pin(futures::future::ok(proxies[index].clone()))
+    Box::new(move |proxies: Weighted, _: &Session| {
+        let total = total_weight(&proxies);
+        if total == 0 {
+            return not_found();
+        }
+        index = (index + 1) % total;
+        match at(&proxies, index) {
+            Some(proxy) => Box::pin(futures::future::ok(proxy)),
+            None => not_found(),
+        }
     })
 }
 
 pub fn strategy_consistent_hashring() -> StrategyFn {
-    let max_retry = 5;
     Box::new(move |proxies, sess| {
         let key = murmur3_32(&mut Cursor::new(get_key(sess)), 0).unwrap() as u64;
-        let buckets = proxies.len() as i32;
-        for _ in 0..max_retry {
-            let index = jump_hash(key, buckets);
-            if let Some(proxy) = proxies.get(index as usize) {
-                return Box::pin(futures::future::ok(proxy.clone()));
-            }
+        let buckets = total_weight(&proxies).min(i32::MAX as u64) as i32;
+        match at(&proxies, jump_hash(key, buckets) as u64) {
+            Some(proxy) => Box::pin(futures::future::ok(proxy)),
+            None => not_found(),
         }
-        Box::pin(futures::future::err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "no proxy found",
-        )))
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::{
+        proxy::{mocks::MockDummyOutboundHandler, AnyOutboundHandler},
+        session::Session,
+    };
+
+    use super::{strategy_consistent_hashring, strategy_rr, Weighted};
+
+    fn proxy(name: &str) -> AnyOutboundHandler {
+        let mut proxy = MockDummyOutboundHandler::new();
+        proxy.expect_name().return_const(name.to_owned());
+        Arc::new(proxy)
+    }
+
+    #[tokio::test]
+    async fn test_weighted_rr() {
+        let proxies: Weighted =
+            vec![(proxy("a"), 3), (proxy("b"), 1), (proxy("c"), 0)];
+        let mut rr = strategy_rr();
+        let mut picked = HashMap::new();
+        for _ in 0..40 {
+            let p = rr(proxies.clone(), &Session::default()).await.unwrap();
+            *picked.entry(p.name().to_owned()).or_insert(0) += 1;
+        }
+        assert_eq!(picked["a"], 30);
+        assert_eq!(picked["b"], 10);
+        assert!(!picked.contains_key("c"));
+
+        assert!(rr(vec![], &Session::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_weighted_hashing() {
+        let mut hashing = strategy_consistent_hashring();
+        let sess = Session {
+            destination: ("example.com".to_owned(), 443).try_into().unwrap(),
+            ..Default::default()
+        };
+        let first = hashing(vec![(proxy("a"), 1), (proxy("b"), 1)], &sess)
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            let p = hashing(vec![(proxy("a"), 1), (proxy("b"), 1)], &sess)
+                .await
+                .unwrap();
+            assert_eq!(p.name(), first.name());
+        }
+        // the only member left takes over
+        let p = hashing(vec![(proxy("b"), 2)], &sess).await.unwrap();
+        assert_eq!(p.name(), "b");
+    }
+}
@@ -10,16 +10,23 @@ use crate::{
     app::{
         dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
-        remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider,
+        remote_content_manager::{
+            providers::proxy_provider::ThreadSafeProxyProvider, ProxyManager,
+        },
     },
     config::internal::proxy::LoadBalanceStrategy,
     session::Session,
 };
 
-use self::helpers::{strategy_consistent_hashring, strategy_rr, StrategyFn};
+use self::helpers::{
+    strategy_consistent_hashring, strategy_rr, StrategyFn, Weighted,
+};
 
 use super::{
-    utils::{provider_helper::get_proxies_from_providers, RemoteConnector},
+    utils::{
+        provider_helper::get_proxies_from_providers, retry::dial_with_retry,
+        RemoteConnector,
+    },
     AnyOutboundHandler, ConnectorType, OutboundHandler, OutboundType,
 };
 
@@ -28,6 +35,8 @@ pub struct HandlerOptions {
     pub name: String,
     pub udp: bool,
     pub strategy: LoadBalanceStrategy,
+    /// by member name, 1 if not set and 0 to leave a member out
+    pub weights: HashMap<String, u32>,
 }
 
 struct HandlerInner {
@@ -38,6 +47,7 @@ pub struct Handler {
     opts: HandlerOptions,
 
     providers: Vec<ThreadSafeProxyProvider>,
+    proxy_manager: ProxyManager,
 
     inner: Arc<Mutex<HandlerInner>>,
 }
@@ -46,6 +56,7 @@ impl Handler {
     pub fn new(
         opts: HandlerOptions,
         providers: Vec<ThreadSafeProxyProvider>,
+        proxy_manager: ProxyManager,
     ) -> Self {
         let strategy_fn = match opts.strategy {
             LoadBalanceStrategy::ConsistentHashing => strategy_consistent_hashring(),
@@ -55,6 +66,7 @@ impl Handler {
         Self {
             opts,
             providers,
+            proxy_manager,
             inner: Arc::new(Mutex::new(HandlerInner { strategy_fn })),
         }
     }
@@ -62,6 +74,46 @@ impl Handler {
     async fn get_proxies(&self, touch: bool) -> Vec<AnyOutboundHandler> {
         get_proxies_from_providers(&self.providers, touch).await
     }
+
+    /// The members with their weights, those failing health checks being
+    /// left out so their share goes to the others, unless they all are.
+    async fn get_weighted(&self, touch: bool) -> Weighted {
+        let weighted = self
+            .get_proxies(touch)
+            .await
+            .into_iter()
+            .map(|x| {
+                let weight = self.opts.weights.get(x.name()).copied().unwrap_or(1);
+                (x, weight)
+            })
+            .filter(|(_, weight)| *weight > 0)
+            .collect::<Vec<_>>();
+
+        let mut available = vec![];
+        for (proxy, weight) in weighted.iter() {
+            if self.proxy_manager.available(proxy.name()).await {
+                available.push((proxy.clone(), *weight));
+            }
+        }
+        if available.is_empty() {
+            weighted
+        } else {
+            available
+        }
+    }
+
+    /// The member the strategy picks for `sess`, and the members to retry
+    /// with if dialing through it fails.
+    async fn pick(
+        &self,
+        sess: &Session,
+    ) -> io::Result<(AnyOutboundHandler, Vec<AnyOutboundHandler>)> {
+        let proxies = self.get_weighted(true).await;
+        let members = proxies.iter().map(|(x, _)| x.clone()).collect();
+        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
+        debug!("{} use proxy {}", self.name(), proxy.name());
+        Ok((proxy, members))
+    }
 }
 
 #[async_trait::async_trait]
@@ -88,16 +140,20 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
-        debug!("{} use proxy {}", self.name(), proxy.name());
-        match proxy.connect_stream(sess, resolver).await {
-            Ok(s) => {
-                s.append_to_chain(self.name()).await;
-                Ok(s)
-            }
-            Err(e) => Err(e),
-        }
+        let (proxy, members) = self.pick(sess).await?;
+        let s = dial_with_retry(
+            self.name(),
+            proxy,
+            members,
+            &self.proxy_manager,
+            |proxy| {
+                let resolver = resolver.clone();
+                async move { proxy.connect_stream(sess, resolver).await }
+            },
+        )
+        .await?;
+        s.append_to_chain(self.name()).await;
+        Ok(s)
     }
 
     /// connect to remote target via UDP
@@ -106,10 +162,12 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
-        debug!("{} use proxy {}", self.name(), proxy.name());
-        proxy.connect_datagram(sess, resolver).await
+        let (proxy, members) = self.pick(sess).await?;
+        dial_with_retry(self.name(), proxy, members, &self.proxy_manager, |proxy| {
+            let resolver = resolver.clone();
+            async move { proxy.connect_datagram(sess, resolver).await }
+        })
+        .await
     }
 
     async fn support_connector(&self) -> ConnectorType {
@@ -122,12 +180,20 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
-        debug!("{} use proxy {}", self.name(), proxy.name());
-        proxy
-            .connect_stream_with_connector(sess, resolver, connector)
-            .await
+        let (proxy, members) = self.pick(sess).await?;
+        dial_with_retry(self.name(), proxy, members, &self.proxy_manager, |proxy| {
+            let resolver = resolver.clone();
+            async move {
+                proxy
+                    .connect_stream_with_connector(sess, resolver, connector)
+                    .await
+            }
+        })
+        .await
+    }
+
+    async fn members(&self) -> Option<Vec<AnyOutboundHandler>> {
+        Some(get_proxies_from_providers(&self.providers, false).await)
     }
 
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
@@ -141,6 +207,9 @@ impl OutboundHandler for Handler {
             Box::new(all.iter().map(|x| x.name().to_owned()).collect::<Vec<_>>())
                 as _,
         );
+        if !self.opts.weights.is_empty() {
+            m.insert("weights".to_string(), Box::new(self.opts.weights.clone()));
+        }
         m
     }
 }
@@ -1,5 +1,7 @@
 use crate::{
+    app::inbound::activation,
     common::auth::ThreadSafeAuthenticator,
+    config::def::InboundTcp,
     proxy::{AnyInboundListener, InboundListener},
     session::{Network, Session},
     Dispatcher,
@@ -7,13 +9,13 @@ use crate::{
 use async_trait::async_trait;
 use std::{net::SocketAddr, sync::Arc};
 
-use tokio::net::TcpListener;
 use tracing::warn;
 
 use super::{http, socks, utils::apply_tcp_options};
 
 pub struct Listener {
     addr: SocketAddr,
+    tcp_options: InboundTcp,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
 }
@@ -28,11 +30,13 @@ impl Listener {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(
         addr: SocketAddr,
+        tcp_options: InboundTcp,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
     ) -> AnyInboundListener {
         Arc::new(Self {
             addr,
+            tcp_options,
             dispatcher,
             authenticator,
         }) as _
@@ -50,7 +54,8 @@ impl InboundListener for Listener {
     }
 
     async fn listen_tcp(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener =
+            activation::bind_tcp("mixed", self.addr, &self.tcp_options).await?;
 
         loop {
             let (socket, _) = listener.accept().await?;
@@ -71,6 +76,7 @@ impl InboundListener for Listener {
                     let mut sess = Session {
                         network: Network::Tcp,
                         source: socket.peer_addr()?,
+                        inbound_name: "Mixed".to_owned(),
 
                         ..Default::default()
                     };
@@ -91,6 +97,7 @@ impl InboundListener for Listener {
                     http::handle_http(
                         Box::new(socket),
                         src,
+                        "Mixed",
                         dispatcher,
                         authenticator,
                     )
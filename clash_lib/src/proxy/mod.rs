@@ -19,7 +19,10 @@ use std::{
     sync::Arc,
 };
 
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
 
 use self::utils::RemoteConnector;
 
@@ -33,6 +36,7 @@ pub(crate) mod datagram;
 mod options;
 
 pub mod converters;
+pub mod registry;
 #[cfg(feature = "shadowsocks")]
 pub mod shadowsocks;
 pub mod socks;
@@ -51,7 +55,7 @@ pub mod relay;
 pub mod selector;
 pub mod urltest;
 
-mod transport;
+pub(crate) mod transport;
 
 #[cfg(test)]
 pub mod mocks;
@@ -76,9 +80,38 @@ impl<T> ProxyStream for T where
 }
 pub type AnyStream = Box<dyn ProxyStream>;
 
+/// Inbound streams that may be a plain TCP socket, which lets the relay
+/// splice it with a TCP outbound on Linux.
+pub trait AsTcpStream {
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl AsTcpStream for TcpStream {
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl AsTcpStream for &mut TcpStream {
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl AsTcpStream for tokio::io::DuplexStream {}
+
+impl AsTcpStream for hyper::upgrade::Upgraded {}
+
 pub trait InboundDatagram<Item>:
     Stream<Item = Item> + Sink<Item, Error = io::Error> + Send + Sync + Unpin + Debug
 {
+    /// Where the packets that can't be sent on are reported, for the
+    /// inbounds able to tell their clients.
+    fn unreachable(&self) -> Option<tokio::sync::mpsc::Sender<Item>> {
+        None
+    }
 }
 pub type AnyInboundDatagram =
     Box<dyn InboundDatagram<UdpPacket, Error = io::Error, Item = UdpPacket>>;
@@ -115,7 +148,7 @@ pub trait InboundListener: Send + Sync + Unpin {
 
 pub type AnyInboundListener = Arc<dyn InboundListener>;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy)]
 pub enum OutboundType {
     Shadowsocks,
     Vmess,
@@ -134,6 +167,9 @@ pub enum OutboundType {
 
     Direct,
     Reject,
+
+    /// a protocol registered by another crate, see [`registry`]
+    Plugin,
 }
 
 impl Display for OutboundType {
@@ -155,6 +191,8 @@ impl Display for OutboundType {
 
             OutboundType::Direct => write!(f, "Direct"),
             OutboundType::Reject => write!(f, "Reject"),
+
+            OutboundType::Plugin => write!(f, "Plugin"),
         }
     }
 }
@@ -220,6 +258,17 @@ pub trait OutboundHandler: Sync + Send + Unpin {
         ))
     }
 
+    /// for proxy groups
+    /// the proxies the group is composed of, None for plain proxies
+    async fn members(&self) -> Option<Vec<AnyOutboundHandler>> {
+        None
+    }
+
+    /// the address of the proxy server, None for those without one
+    fn server(&self) -> Option<(&str, u16)> {
+        None
+    }
+
     /// for API
     /// the map only contains basic information
     /// to populate history/liveness information, use the proxy_manager
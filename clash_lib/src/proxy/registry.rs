@@ -0,0 +1,366 @@
+//! The outbound protocols by their `type:` in the config.
+//!
+//! The built-in protocols register here like any other, so a feature-gated
+//! module or another crate can add one with [`register_outbound`] without
+//! touching the config parser or the outbound manager:
+//!
+//! ```ignore
+//! clash_lib::register_outbound("my-proto", |options: &OutboundOptions| {
+//!     let handler: AnyOutboundHandler = MyHandler::new(options)?;
+//!     Ok(handler)
+//! });
+//! ```
+
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, RwLock},
+};
+
+use once_cell::sync::Lazy;
+use serde::{de::value::MapDeserializer, de::DeserializeOwned, Deserialize};
+use serde_yaml::Value;
+use tracing::debug;
+
+use crate::{
+    config::{
+        def::OutboundInit,
+        internal::proxy::{
+            map_serde_error, OutboundProxyProtocol, OutboundSocks5, OutboundTor,
+            OutboundTrojan, OutboundVmess, OutboundWireguard,
+        },
+    },
+    proxy::{
+        direct, reject,
+        utils::{HostLimit, HostLimited, LazyOutbound, PortRange, PortRanged},
+        AnyOutboundHandler, OutboundType,
+    },
+    Error,
+};
+
+/// The settings of an outbound as written in the config, `name` and `type`
+/// included.
+pub type OutboundOptions = HashMap<String, Value>;
+
+/// Builds the outbounds of one `type`.
+pub trait OutboundFactory: Send + Sync {
+    /// Check `options` when the config is loaded, so mistakes are reported
+    /// before anything is built.
+    fn validate(&self, options: &OutboundOptions) -> Result<(), Error> {
+        let _ = options;
+        Ok(())
+    }
+
+    /// Build the outbound described by `options`.
+    fn create(&self, options: &OutboundOptions)
+        -> Result<AnyOutboundHandler, Error>;
+
+    /// the type of the outbounds built, told before they're built
+    fn proto(&self) -> OutboundType {
+        OutboundType::Plugin
+    }
+}
+
+impl<F> OutboundFactory for F
+where
+    F: Fn(&OutboundOptions) -> Result<AnyOutboundHandler, Error> + Send + Sync,
+{
+    fn create(
+        &self,
+        options: &OutboundOptions,
+    ) -> Result<AnyOutboundHandler, Error> {
+        self(options)
+    }
+}
+
+/// A protocol configured by the struct `T`.
+struct Typed<T>(OutboundType, PhantomData<fn() -> T>);
+
+impl<T> OutboundFactory for Typed<T>
+where
+    T: DeserializeOwned,
+    AnyOutboundHandler: TryFrom<T, Error = Error>,
+{
+    fn validate(&self, options: &OutboundOptions) -> Result<(), Error> {
+        parse::<T>(options).map(|_| ())
+    }
+
+    fn create(
+        &self,
+        options: &OutboundOptions,
+    ) -> Result<AnyOutboundHandler, Error> {
+        parse::<T>(options)?.try_into()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.0
+    }
+}
+
+fn typed<T>(proto: OutboundType) -> Arc<dyn OutboundFactory>
+where
+    T: DeserializeOwned + 'static,
+    AnyOutboundHandler: TryFrom<T, Error = Error>,
+{
+    Arc::new(Typed::<T>(proto, PhantomData))
+}
+
+/// Deserialize `options` into the settings struct of a protocol.
+pub fn parse<T: DeserializeOwned>(options: &OutboundOptions) -> Result<T, Error> {
+    let name = options
+        .get("name")
+        .and_then(|x| x.as_str())
+        .unwrap_or_default()
+        .to_owned();
+    T::deserialize(MapDeserializer::new(options.clone().into_iter()))
+        .map_err(map_serde_error(name))
+}
+
+static REGISTRY: Lazy<RwLock<HashMap<String, Arc<dyn OutboundFactory>>>> =
+    Lazy::new(|| {
+        let mut builtin = HashMap::from([
+            (
+                "socks5".to_owned(),
+                typed::<OutboundSocks5>(OutboundType::Socks5),
+            ),
+            (
+                "trojan".to_owned(),
+                typed::<OutboundTrojan>(OutboundType::Trojan),
+            ),
+            (
+                "vmess".to_owned(),
+                typed::<OutboundVmess>(OutboundType::Vmess),
+            ),
+            (
+                "wireguard".to_owned(),
+                typed::<OutboundWireguard>(OutboundType::WireGuard),
+            ),
+            ("tor".to_owned(), typed::<OutboundTor>(OutboundType::Tor)),
+        ]);
+        #[cfg(feature = "shadowsocks")]
+        builtin.insert(
+            "ss".to_owned(),
+            typed::<crate::config::internal::proxy::OutboundShadowsocks>(
+                OutboundType::Shadowsocks,
+            ),
+        );
+        #[cfg(feature = "tuic")]
+        builtin.insert(
+            "tuic".to_owned(),
+            typed::<crate::config::internal::proxy::OutboundTuic>(
+                OutboundType::Tuic,
+            ),
+        );
+        RwLock::new(builtin)
+    });
+
+static OUTBOUND_INIT: RwLock<OutboundInit> = RwLock::new(OutboundInit::Eager);
+
+/// Build the outbounds created from now on when the config is loaded, on
+/// first use or in the background.
+pub fn set_outbound_init(init: OutboundInit) {
+    *OUTBOUND_INIT.write().unwrap() = init;
+}
+
+/// Build the outbounds with `type: kind` with `factory`, replacing the one
+/// registered for it before, built-in or not. Configs loaded from then on can
+/// use it.
+pub fn register_outbound<F>(kind: &str, factory: F)
+where
+    F: OutboundFactory + 'static,
+{
+    debug!("registering outbound type {}", kind);
+    REGISTRY
+        .write()
+        .unwrap()
+        .insert(kind.to_owned(), Arc::new(factory));
+}
+
+/// The factory of the outbounds with `type: kind`.
+pub(crate) fn get(kind: &str) -> Option<Arc<dyn OutboundFactory>> {
+    REGISTRY.read().unwrap().get(kind).cloned()
+}
+
+/// The `port-range` of any outbound, its sockets bound to those local ports.
+pub(crate) fn port_range(
+    options: &OutboundOptions,
+) -> Result<Option<PortRange>, Error> {
+    match options.get("port-range") {
+        None => Ok(None),
+        Some(Value::Number(x)) => x.to_string().parse().map(Some),
+        Some(Value::String(x)) => x.parse().map(Some),
+        Some(x) => Err(Error::InvalidConfig(format!("invalid port-range: {:?}", x))),
+    }
+}
+
+pub(crate) fn host_limit(
+    options: &OutboundOptions,
+) -> Result<Option<HostLimit>, Error> {
+    let Some(value) = options.get("per-host-limit") else {
+        return Ok(None);
+    };
+    let limit = HostLimit::deserialize(value.clone()).map_err(|x| {
+        Error::InvalidConfig(format!("invalid per-host-limit: {}", x))
+    })?;
+    if limit.max == 0 {
+        return Err(Error::InvalidConfig(
+            "per-host-limit max must be above 0".to_owned(),
+        ));
+    }
+    Ok(Some(limit))
+}
+
+/// The `server` and `port` of any outbound that has them.
+fn server(options: &OutboundOptions) -> Option<(String, u16)> {
+    let host = options.get("server")?.as_str()?;
+    let port = options.get("port")?.as_u64()?;
+    Some((host.to_owned(), u16::try_from(port).ok()?))
+}
+
+/// Build the outbound `proto`.
+pub(crate) fn create(
+    proto: &OutboundProxyProtocol,
+) -> Result<AnyOutboundHandler, Error> {
+    match proto {
+        OutboundProxyProtocol::Direct => Ok(direct::Handler::new()),
+        OutboundProxyProtocol::Reject => Ok(reject::Handler::new()),
+        OutboundProxyProtocol::Registered(x) => {
+            let factory = get(&x.kind).ok_or_else(|| {
+                Error::InvalidConfig(format!(
+                    "unknown proxy type `{}` of {}",
+                    x.kind, x.name
+                ))
+            })?;
+            let range = port_range(&x.options)?;
+            let limit = host_limit(&x.options)?;
+            let init = *OUTBOUND_INIT.read().unwrap();
+            if init == OutboundInit::Eager {
+                return build(factory.as_ref(), &x.options, range, limit);
+            }
+
+            let options = x.options.clone();
+            let lazy = LazyOutbound::new(
+                x.name.clone(),
+                factory.proto(),
+                server(&x.options),
+                Arc::new(move || build(factory.as_ref(), &options, range, limit)),
+            );
+            if init == OutboundInit::WarmUp {
+                lazy.warm_up();
+            }
+            Ok(lazy)
+        }
+    }
+}
+
+fn build(
+    factory: &dyn OutboundFactory,
+    options: &OutboundOptions,
+    range: Option<PortRange>,
+    limit: Option<HostLimit>,
+) -> Result<AnyOutboundHandler, Error> {
+    let handler = factory.create(options)?;
+    let handler = match range {
+        Some(range) => PortRanged::new(handler, range),
+        None => handler,
+    };
+    Ok(match limit {
+        Some(limit) => HostLimited::new(handler, limit),
+        None => handler,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_yaml::Value;
+
+    use super::{create, register_outbound, OutboundOptions};
+    use crate::{
+        config::internal::proxy::OutboundProxyProtocol,
+        proxy::{direct, AnyOutboundHandler, OutboundType},
+        Error,
+    };
+
+    fn options(yaml: &str) -> HashMap<String, Value> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_builtin() {
+        let proto = OutboundProxyProtocol::try_from(options(
+            "{name: s5, type: socks5, server: 127.0.0.1, port: 1080}",
+        ))
+        .unwrap();
+        let handler = create(&proto).unwrap();
+        assert_eq!(handler.name(), "s5");
+        assert!(matches!(handler.proto(), OutboundType::Socks5));
+
+        // checked when the config is loaded
+        assert!(OutboundProxyProtocol::try_from(options(
+            "{name: s5, type: socks5, server: 127.0.0.1}"
+        ))
+        .is_err());
+        assert!(OutboundProxyProtocol::try_from(options(
+            "{name: x, type: unknown-proto}"
+        ))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_port_range() {
+        let proto = OutboundProxyProtocol::try_from(options(
+            "{name: s5, type: socks5, server: 127.0.0.1, port: 1080, \
+             port-range: 40000-40999}",
+        ))
+        .unwrap();
+        let handler = create(&proto).unwrap();
+        assert_eq!(handler.name(), "s5");
+        assert!(handler.as_map().await.contains_key("port-range"));
+
+        assert!(OutboundProxyProtocol::try_from(options(
+            "{name: s5, type: socks5, server: 127.0.0.1, port: 1080, \
+             port-range: 40999-40000}"
+        ))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_host_limit() {
+        let proto = OutboundProxyProtocol::try_from(options(
+            "{name: s5, type: socks5, server: 127.0.0.1, port: 1080, \
+             per-host-limit: {max: 6, wait: 0}}",
+        ))
+        .unwrap();
+        let handler = create(&proto).unwrap();
+        assert!(handler.as_map().await.contains_key("per-host-limit"));
+
+        for limit in ["{max: 0}", "{wait: 10}", "6"] {
+            assert!(OutboundProxyProtocol::try_from(options(&format!(
+                "{{name: s5, type: socks5, server: 127.0.0.1, port: 1080, \
+                 per-host-limit: {}}}",
+                limit
+            )))
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        register_outbound(
+            "test-alias-direct",
+            |_: &OutboundOptions| -> Result<AnyOutboundHandler, Error> {
+                Ok(direct::Handler::new())
+            },
+        );
+        let proto = OutboundProxyProtocol::try_from(options(
+            "{name: d, type: test-alias-direct}",
+        ))
+        .unwrap();
+        assert!(matches!(
+            create(&proto).unwrap().proto(),
+            OutboundType::Direct
+        ));
+    }
+}
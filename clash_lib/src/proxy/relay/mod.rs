@@ -7,10 +7,7 @@ use tracing::debug;
 
 use crate::{
     app::{
-        dispatcher::{
-            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram,
-            ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
-        },
+        dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
         remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider,
     },
@@ -77,7 +74,9 @@ impl OutboundHandler for Handler {
             1 => {
                 let proxy = proxies[0].clone();
                 debug!("tcp relay `{}` via proxy `{}`", self.name(), proxy.name());
-                proxy.connect_stream(sess, resolver).await
+                let s = proxy.connect_stream(sess, resolver).await?;
+                s.append_to_chain(self.name()).await;
+                Ok(s)
             }
             _ => {
                 let mut connector: Box<dyn RemoteConnector> =
@@ -102,9 +101,12 @@ impl OutboundHandler for Handler {
                     )
                     .await?;
 
-                let chained = ChainedStreamWrapper::new(s);
-                chained.append_to_chain(self.name()).await;
-                Ok(Box::new(chained))
+                // the hops to the last proxy, nearest to it first
+                for proxy in proxies.iter().rev() {
+                    s.append_to_chain(proxy.name()).await;
+                }
+                s.append_to_chain(self.name()).await;
+                Ok(s)
             }
         }
     }
@@ -122,7 +124,9 @@ impl OutboundHandler for Handler {
             1 => {
                 let proxy = proxies[0].clone();
                 debug!("udp relay `{}` via proxy `{}`", self.name(), proxy.name());
-                proxy.connect_datagram(sess, resolver).await
+                let d = proxy.connect_datagram(sess, resolver).await?;
+                d.append_to_chain(self.name()).await;
+                Ok(d)
             }
             _ => {
                 let mut connector: Box<dyn RemoteConnector> =
@@ -145,9 +149,12 @@ impl OutboundHandler for Handler {
                     )
                     .await?;
 
-                let chained = ChainedDatagramWrapper::new(d);
-                chained.append_to_chain(self.name()).await;
-                Ok(Box::new(chained))
+                // the hops to the last proxy, nearest to it first
+                for proxy in proxies.iter().rev() {
+                    d.append_to_chain(proxy.name()).await;
+                }
+                d.append_to_chain(self.name()).await;
+                Ok(d)
             }
         }
     }
@@ -156,6 +163,10 @@ impl OutboundHandler for Handler {
         ConnectorType::None
     }
 
+    async fn members(&self) -> Option<Vec<AnyOutboundHandler>> {
+        Some(get_proxies_from_providers(&self.providers, false).await)
+    }
+
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let all = get_proxies_from_providers(&self.providers, false).await;
 
@@ -174,6 +174,10 @@ impl OutboundHandler for Handler {
             .await
     }
 
+    async fn members(&self) -> Option<Vec<AnyOutboundHandler>> {
+        Some(get_proxies_from_providers(&self.providers, false).await)
+    }
+
     /// for API
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let all = get_proxies_from_providers(&self.providers, false).await;
@@ -199,6 +203,7 @@ mod tests {
     use crate::proxy::{
         mocks::{MockDummyOutboundHandler, MockDummyProxyProvider},
         selector::ThreadSafeSelectorControl,
+        OutboundHandler,
     };
 
     #[tokio::test]
@@ -257,5 +262,11 @@ mod tests {
 
         let fail = selector_control.lock().await.select("provider3").await;
         assert!(fail.is_err());
+
+        let members = outbound_handler.members().await.unwrap();
+        assert_eq!(
+            members.iter().map(|x| x.name()).collect::<Vec<_>>(),
+            vec!["provider1", "provider2"]
+        );
     }
 }
@@ -9,6 +9,7 @@ use shadowsocks::ProxySocket;
 use tokio::io::ReadBuf;
 use tracing::{debug, instrument, trace};
 
+use super::session::UdpSession;
 use crate::{
     app::dns::ThreadSafeDNSResolver,
     proxy::{datagram::UdpPacket, AnyOutboundDatagram},
@@ -23,6 +24,8 @@ pub struct OutboundDatagramShadowsocks {
     pkt: Option<UdpPacket>,
     buf: Vec<u8>,
     resolver: ThreadSafeDNSResolver,
+    /// with the 2022 ciphers
+    session: Option<UdpSession>,
 }
 
 impl OutboundDatagramShadowsocks {
@@ -31,6 +34,7 @@ impl OutboundDatagramShadowsocks {
         inner: ProxySocket,
         remote_addr: (String, u16),
         resolver: ThreadSafeDNSResolver,
+        session: Option<UdpSession>,
     ) -> AnyOutboundDatagram {
         let s = Self {
             inner,
@@ -39,6 +43,7 @@ impl OutboundDatagramShadowsocks {
             remote_addr: remote_addr.try_into().expect("must into socks addr"),
             buf: vec![0u8; 65535],
             resolver,
+            session,
         };
         Box::new(s) as _
     }
@@ -83,6 +88,7 @@ impl Sink<UdpPacket> for OutboundDatagramShadowsocks {
             ref remote_addr,
             ref mut flushed,
             ref mut resolver,
+            ref mut session,
             ..
         } = *self;
 
@@ -116,7 +122,17 @@ impl Sink<UdpPacket> for OutboundDatagramShadowsocks {
             let addr: shadowsocks::relay::Address =
                 (pkt.dst_addr.host(), pkt.dst_addr.port()).into();
 
-            let n = ready!(inner.poll_send_to(dst, &addr, data, cx))?;
+            let n = match session {
+                Some(session) => {
+                    let control = session.control();
+                    let n = ready!(
+                        inner.poll_send_to_with_ctrl(dst, &addr, &control, data, cx)
+                    )?;
+                    session.sent();
+                    n
+                }
+                None => ready!(inner.poll_send_to(dst, &addr, data, cx))?,
+            };
 
             debug!(
                 "send udp packet to remote ss server, len: {}, remote_addr: {}, \
@@ -166,21 +182,37 @@ impl Stream for OutboundDatagramShadowsocks {
         let Self {
             ref mut buf,
             ref inner,
+            ref mut session,
             ..
         } = *self;
 
-        let mut buf = ReadBuf::new(buf);
-
-        let rv = ready!(inner.poll_recv_from(cx, &mut buf));
-        debug!("recv udp packet from remote ss server: {:?}", rv);
-
-        match rv {
-            Ok((n, src, ..)) => Poll::Ready(Some(UdpPacket {
-                data: buf.filled()[..n].to_vec(),
-                src_addr: src.into(),
-                dst_addr: SocksAddr::any_ipv4(),
-            })),
-            Err(_) => Poll::Ready(None),
+        loop {
+            let mut buf = ReadBuf::new(buf);
+
+            let rv = ready!(inner.poll_recv_from_with_ctrl(cx, &mut buf));
+            debug!("recv udp packet from remote ss server: {:?}", rv);
+
+            match rv {
+                Ok((n, src, _, _, control)) => {
+                    if let (Some(session), Some(control)) =
+                        (session.as_mut(), control.as_ref())
+                    {
+                        if !session.accept(control) {
+                            debug!(
+                                "dropping replayed or stray udp packet from {}",
+                                src
+                            );
+                            continue;
+                        }
+                    }
+                    return Poll::Ready(Some(UdpPacket {
+                        data: buf.filled()[..n].to_vec(),
+                        src_addr: src.into(),
+                        dst_addr: SocksAddr::any_ipv4(),
+                    }));
+                }
+                Err(_) => return Poll::Ready(None),
+            }
         }
     }
 }
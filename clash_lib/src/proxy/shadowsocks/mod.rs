@@ -1,4 +1,5 @@
 mod datagram;
+mod session;
 mod shadow_tls;
 mod simple_obfs;
 mod stream;
@@ -26,7 +27,10 @@ use crate::{
 };
 use std::{collections::HashMap, io, sync::Arc};
 
-use self::{datagram::OutboundDatagramShadowsocks, stream::ShadowSocksStream};
+use self::{
+    datagram::OutboundDatagramShadowsocks, session::UdpSession,
+    stream::ShadowSocksStream,
+};
 
 use super::{
     utils::{new_tcp_stream, new_udp_socket, RemoteConnector},
@@ -201,6 +205,40 @@ impl Handler {
         Arc::new(Self { opts })
     }
 
+    fn cipher(&self) -> io::Result<CipherKind> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let kind = match self.opts.cipher.as_str() {
+            "aes-128-gcm" => CipherKind::AES_128_GCM,
+            "aes-256-gcm" => CipherKind::AES_256_GCM,
+            "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
+            "2022-blake3-aes-128-gcm" => CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+            "2022-blake3-aes-256-gcm" => CipherKind::AEAD2022_BLAKE3_AES_256_GCM,
+            "2022-blake3-chacha20-poly1305" => {
+                CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305
+            }
+            _ => return Err(invalid("unsupported cipher")),
+        };
+
+        // the 2022 ciphers take base64 keys, which the library panics on if
+        // they aren't, identity keys first separated by `:`
+        if kind.is_aead_2022() {
+            use base64::Engine;
+            let valid = self.opts.password.split(':').all(|key| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(key)
+                    .is_ok_and(|x| x.len() == kind.key_len())
+            });
+            if !valid {
+                return Err(invalid(&format!(
+                    "{} takes base64 encoded {} byte keys",
+                    self.opts.cipher,
+                    kind.key_len()
+                )));
+            }
+        }
+        Ok(kind)
+    }
+
     async fn proxy_stream(
         &self,
         s: AnyStream,
@@ -242,17 +280,7 @@ impl Handler {
         let cfg = ServerConfig::new(
             (self.opts.server.to_owned(), self.opts.port),
             self.opts.password.to_owned(),
-            match self.opts.cipher.as_str() {
-                "aes-128-gcm" => CipherKind::AES_128_GCM,
-                "aes-256-gcm" => CipherKind::AES_256_GCM,
-                "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "unsupported cipher",
-                    ))
-                }
-            },
+            self.cipher()?,
         );
 
         let stream = ProxyClientStream::from_stream(
@@ -276,6 +304,10 @@ impl OutboundHandler for Handler {
         OutboundType::Shadowsocks
     }
 
+    fn server(&self) -> Option<(&str, u16)> {
+        Some((&self.opts.server, self.opts.port))
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
@@ -319,17 +351,7 @@ impl OutboundHandler for Handler {
         let cfg = ServerConfig::new(
             (self.opts.server.to_owned(), self.opts.port),
             self.opts.password.to_owned(),
-            match self.opts.cipher.as_str() {
-                "aes-128-gcm" => CipherKind::AES_128_GCM,
-                "aes-256-gcm" => CipherKind::AES_256_GCM,
-                "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "unsupported cipher",
-                    ))
-                }
-            },
+            self.cipher()?,
         );
         let socket = new_udp_socket(
             None,
@@ -345,6 +367,7 @@ impl OutboundHandler for Handler {
             socket,
             (self.opts.server.to_owned(), self.opts.port),
             resolver,
+            cfg.method().is_aead_2022().then(UdpSession::default),
         );
         let d = ChainedDatagramWrapper::new(d);
         d.append_to_chain(self.name()).await;
@@ -385,9 +408,12 @@ mod tests {
     use super::super::utils::test_utils::{
         consts::*, docker_runner::DockerTestRunner,
     };
-    use crate::proxy::utils::test_utils::{
-        docker_runner::{DockerTestRunnerBuilder, MultiDockerTestRunner},
-        run_test_suites_and_cleanup, Suite,
+    use crate::proxy::utils::{
+        test_harness,
+        test_utils::{
+            docker_runner::{DockerTestRunnerBuilder, MultiDockerTestRunner},
+            run_test_suites_and_cleanup, Suite,
+        },
     };
 
     use super::*;
@@ -396,6 +422,26 @@ mod tests {
     const CIPHER: &str = "aes-256-gcm";
     const SHADOW_TLS_PASSWORD: &str = "password";
 
+    #[tokio::test]
+    async fn test_ss_in_process() -> anyhow::Result<()> {
+        for cipher in [CIPHER, "chacha20-ietf-poly1305"] {
+            let opts = |port| HandlerOptions {
+                name: "test-ss-in-process".to_owned(),
+                common_opts: Default::default(),
+                server: LOCAL_ADDR.to_owned(),
+                port,
+                password: PASSWORD.to_owned(),
+                cipher: cipher.to_owned(),
+                plugin_opts: Default::default(),
+                udp: false,
+            };
+            let kind = Handler { opts: opts(0) }.cipher()?;
+            let server = test_harness::shadowsocks_server(kind, PASSWORD).await?;
+            test_harness::echo_roundtrip(Handler::new(opts(server.port()))).await?;
+        }
+        Ok(())
+    }
+
     async fn get_ss_runner(port: u16) -> anyhow::Result<DockerTestRunner> {
         let host = format!("0.0.0.0:{}", port);
         DockerTestRunnerBuilder::new()
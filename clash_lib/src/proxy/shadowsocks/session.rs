@@ -0,0 +1,211 @@
+//! UDP sessions of the 2022 ciphers, see SIP022. Every association sends
+//! with its own random client session ID and a counting packet ID, and the
+//! packet IDs of each server session it hears from are checked against a
+//! sliding window, so a captured packet can't be replayed into the flow.
+//!
+//! The types don't depend on the socket, an inbound would use
+//! [`ServerSessions`] for its clients the same way.
+
+use std::time::{Duration, Instant};
+
+use shadowsocks::relay::udprelay::options::UdpSocketControlData;
+
+/// the words of the replay window bitmap
+const WORDS: usize = 32;
+/// how far behind the newest packet ID an older one is still accepted,
+/// a word less than the bitmap as it's reused as a ring
+const WINDOW: u64 = ((WORDS - 1) * 64) as u64;
+/// a session of the peer is dropped after going quiet this long, unless
+/// it's the only one
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+/// the peer sessions tracked at once, a peer only starts a new one when it
+/// restarts
+const MAX_SESSIONS: usize = 4;
+
+/// The packet IDs seen of one session, RFC 6479.
+#[derive(Clone)]
+pub struct ReplayWindow {
+    newest: u64,
+    bitmap: [u64; WORDS],
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self {
+            newest: 0,
+            bitmap: [0; WORDS],
+        }
+    }
+}
+
+impl ReplayWindow {
+    /// Record `id`, false if it was seen before or is too old to tell.
+    pub fn check(&mut self, id: u64) -> bool {
+        if id <= self.newest && self.newest - id >= WINDOW {
+            return false;
+        }
+
+        let block = id / 64;
+        if id > self.newest {
+            // clear the words the window slides over
+            let newest_block = self.newest / 64;
+            for i in 1..=(block - newest_block).min(WORDS as u64) {
+                self.bitmap[((newest_block + i) % WORDS as u64) as usize] = 0;
+            }
+            self.newest = id;
+        }
+
+        let word = &mut self.bitmap[(block % WORDS as u64) as usize];
+        let bit = 1 << (id % 64);
+        if *word & bit != 0 {
+            return false;
+        }
+        *word |= bit;
+        true
+    }
+}
+
+/// The sessions of a peer, by session ID.
+#[derive(Default)]
+pub struct ServerSessions {
+    sessions: Vec<(u64, ReplayWindow, Instant)>,
+}
+
+impl ServerSessions {
+    /// Record packet `packet_id` of session `session_id`, false if it's a
+    /// replay.
+    pub fn check(&mut self, session_id: u64, packet_id: u64) -> bool {
+        let now = Instant::now();
+        // a restarted peer may still have packets of its old session in
+        // flight
+        if self.sessions.len() > 1 {
+            self.sessions.retain(|(id, _, last_seen)| {
+                *id == session_id || now - *last_seen < SESSION_TIMEOUT
+            });
+        }
+
+        let i = match self.sessions.iter().position(|x| x.0 == session_id) {
+            Some(i) => i,
+            None => {
+                if self.sessions.len() >= MAX_SESSIONS {
+                    let oldest = self
+                        .sessions
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, x)| x.2)
+                        .map(|(i, _)| i)
+                        .expect("sessions not empty");
+                    self.sessions.swap_remove(oldest);
+                }
+                self.sessions.push((session_id, Default::default(), now));
+                self.sessions.len() - 1
+            }
+        };
+
+        let (_, window, last_seen) = &mut self.sessions[i];
+        if !window.check(packet_id) {
+            return false;
+        }
+        *last_seen = now;
+        true
+    }
+}
+
+/// The client side of a UDP association.
+pub struct UdpSession {
+    client_session_id: u64,
+    packet_id: u64,
+    servers: ServerSessions,
+}
+
+impl Default for UdpSession {
+    fn default() -> Self {
+        Self {
+            client_session_id: rand::random(),
+            packet_id: 1,
+            servers: Default::default(),
+        }
+    }
+}
+
+impl UdpSession {
+    /// The control data of the next packet to send.
+    pub fn control(&self) -> UdpSocketControlData {
+        let mut control = UdpSocketControlData::default();
+        control.client_session_id = self.client_session_id;
+        control.packet_id = self.packet_id;
+        control
+    }
+
+    /// Move on to the next packet ID once a packet is sent, starting a new
+    /// session when they run out.
+    pub fn sent(&mut self) {
+        match self.packet_id.checked_add(1) {
+            Some(id) => self.packet_id = id,
+            None => *self = Self::default(),
+        }
+    }
+
+    /// Whether a received packet belongs to this session and isn't a
+    /// replay.
+    pub fn accept(&mut self, control: &UdpSocketControlData) -> bool {
+        control.client_session_id == self.client_session_id
+            && self
+                .servers
+                .check(control.server_session_id, control.packet_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayWindow, ServerSessions, UdpSession, WINDOW};
+
+    #[test]
+    fn test_replay_window() {
+        let mut w = ReplayWindow::default();
+        assert!(w.check(1));
+        assert!(!w.check(1));
+        // out of order within the window
+        assert!(w.check(5));
+        assert!(w.check(3));
+        assert!(!w.check(3));
+
+        assert!(w.check(10_000));
+        assert!(w.check(10_000 - WINDOW + 1));
+        assert!(!w.check(10_000 - WINDOW));
+        // slid past everything seen before
+        assert!(w.check(100_000));
+        assert!(w.check(100_000 - 64));
+        assert!(!w.check(5));
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut s = ServerSessions::default();
+        assert!(s.check(1, 1));
+        assert!(!s.check(1, 1));
+        // a restarted server, packets of both are accepted
+        assert!(s.check(2, 1));
+        assert!(s.check(1, 2));
+        assert!(!s.check(2, 1));
+    }
+
+    #[test]
+    fn test_udp_session() {
+        let mut session = UdpSession::default();
+        let first = session.control();
+        session.sent();
+        let second = session.control();
+        assert_eq!(first.client_session_id, second.client_session_id);
+        assert_eq!(second.packet_id, first.packet_id + 1);
+
+        let mut reply = session.control();
+        reply.server_session_id = 7;
+        reply.packet_id = 1;
+        assert!(session.accept(&reply));
+        assert!(!session.accept(&reply));
+        reply.client_session_id ^= 1;
+        reply.packet_id = 2;
+        assert!(!session.accept(&reply));
+    }
+}
@@ -8,12 +8,11 @@ use std::{
 
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use bytes::BufMut;
-use chrono::Utc;
 use futures::pin_mut;
 use std::{future::Future, task::ready};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 
-use crate::proxy::AnyStream;
+use crate::{app::ntp, proxy::AnyStream};
 const CHUNK_SIZE: isize = 1 << 14; // 2 ** 14 == 16 * 1024
 
 #[derive(Debug)]
@@ -219,8 +218,10 @@ fn make_client_hello_msg<'a>(data: &[u8], server: &str) -> Cow<'a, Vec<u8>> {
     buf.put_slice(&[0x03, 0x03]);
 
     // random with timestamp, sid len, sid
-    buf.write_u32::<BigEndian>(Utc::now().timestamp() as u32)
-        .unwrap();
+    let now = ntp::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    buf.write_u32::<BigEndian>(now.as_secs() as u32).unwrap();
     buf.put_slice(&random_bytes);
     buf.put_u8(32);
     buf.put_slice(&session_id);
@@ -1,8 +1,11 @@
 mod datagram;
 mod stream;
+mod uot;
 
 use crate::{
+    app::inbound::activation,
     common::auth::ThreadSafeAuthenticator,
+    config::def::InboundTcp,
     proxy::{utils::apply_tcp_options, AnyInboundListener, InboundListener},
     session::{Network, Session, Type},
     Dispatcher,
@@ -10,13 +13,13 @@ use crate::{
 use async_trait::async_trait;
 use std::{net::SocketAddr, sync::Arc};
 pub use stream::handle_tcp;
-use tokio::net::TcpListener;
 use tracing::warn;
 
 pub use datagram::Socks5UDPCodec;
 
 pub struct Listener {
     addr: SocketAddr,
+    tcp_options: InboundTcp,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
 }
@@ -31,11 +34,13 @@ impl Listener {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(
         addr: SocketAddr,
+        tcp_options: InboundTcp,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
     ) -> AnyInboundListener {
         Arc::new(Self {
             addr,
+            tcp_options,
             dispatcher,
             authenticator,
         }) as _
@@ -53,7 +58,8 @@ impl InboundListener for Listener {
     }
 
     async fn listen_tcp(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener =
+            activation::bind_tcp("socks", self.addr, &self.tcp_options).await?;
 
         loop {
             let (socket, _) = listener.accept().await?;
@@ -64,6 +70,7 @@ impl InboundListener for Listener {
                 network: Network::Tcp,
                 typ: Type::Socks5,
                 source: socket.peer_addr()?,
+                inbound_name: "SOCKS5".to_owned(),
 
                 ..Default::default()
             };
@@ -4,13 +4,15 @@ use crate::{
         datagram::InboundUdp,
         socks::{
             socks5::{auth_methods, response_code, socks_command},
-            Socks5UDPCodec, SOCKS5_VERSION,
+            SOCKS5_VERSION,
         },
         utils::new_udp_socket,
     },
     session::{Network, Session, SocksAddr, Type},
     Dispatcher,
 };
+
+use super::uot::{self, InboundUot};
 use bytes::{BufMut, BytesMut};
 
 use std::{io, net::SocketAddr, str, sync::Arc};
@@ -18,9 +20,11 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
 };
-use tokio_util::udp::UdpFramed;
 use tracing::{instrument, trace, warn};
 
+/// room for a few full-sized packets each way
+const UOT_PIPE_SIZE: usize = 4 * (u16::MAX as usize + 4);
+
 #[instrument(skip(sess, s, dispatcher, authenticator))]
 pub async fn handle_tcp<'a>(
     sess: &'a mut Session,
@@ -98,6 +102,7 @@ pub async fn handle_tcp<'a>(
                 true => {
                     response = [0x1, response_code::SUCCEEDED];
                     s.write_all(&response).await?;
+                    sess.inbound_user = Some(user);
                 }
                 false => {
                     response = [0x1, response_code::FAILURE];
@@ -142,6 +147,12 @@ pub async fn handle_tcp<'a>(
             let bnd = SocksAddr::from(s.local_addr()?);
             bnd.write_buf(&mut buf);
             s.write_all(&buf[..]).await?;
+
+            if let Some(version) = uot::version(&dst) {
+                trace!("UDP over TCP {:?} from {}", version, s.peer_addr()?);
+                return handle_uot(sess, s, dispatcher, version).await;
+            }
+
             sess.destination = dst;
 
             dispatcher.dispatch_stream(sess.to_owned(), s).await;
@@ -173,13 +184,13 @@ pub async fn handle_tcp<'a>(
 
             let (close_handle, close_listener) = tokio::sync::oneshot::channel();
 
-            let framed = UdpFramed::new(udp_inbound, Socks5UDPCodec);
-
             let sess = Session {
                 network: Network::Udp,
                 typ: Type::Socks5,
                 packet_mark: None,
                 iface: None,
+                inbound_name: sess.inbound_name.clone(),
+                inbound_user: sess.inbound_user.clone(),
                 ..Default::default()
             };
 
@@ -187,7 +198,7 @@ pub async fn handle_tcp<'a>(
 
             tokio::spawn(async move {
                 let handle = dispatcher_cloned
-                    .dispatch_datagram(sess, Box::new(InboundUdp::new(framed)));
+                    .dispatch_datagram(sess, Box::new(InboundUdp::new(udp_inbound)));
                 close_listener.await.ok();
                 handle.send(0).ok();
             });
@@ -222,3 +233,32 @@ pub async fn handle_tcp<'a>(
         }
     }
 }
+
+/// Relay the UDP packets framed in the connection `s` until it's closed.
+async fn handle_uot(
+    sess: &Session,
+    s: &mut TcpStream,
+    dispatcher: Arc<Dispatcher>,
+    version: uot::Version,
+) -> io::Result<()> {
+    let src = SocksAddr::from(s.peer_addr()?);
+    let sess = Session {
+        network: Network::Udp,
+        typ: Type::Socks5,
+        source: sess.source,
+        inbound_name: sess.inbound_name.clone(),
+        inbound_user: sess.inbound_user.clone(),
+        ..Default::default()
+    };
+
+    // the datagram must own its stream, `s` is only borrowed
+    let (mut local, remote) = tokio::io::duplex(UOT_PIPE_SIZE);
+    let handle = dispatcher
+        .dispatch_datagram(sess, Box::new(InboundUot::new(remote, version, src)));
+    if let Err(e) = tokio::io::copy_bidirectional(s, &mut local).await {
+        warn!("UDP over TCP connection closed: {}", e);
+    }
+    handle.send(0).ok();
+
+    Ok(())
+}
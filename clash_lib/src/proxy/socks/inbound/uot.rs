@@ -0,0 +1,349 @@
+//! UDP over TCP, the extension of sing-box: a CONNECT to one of its magic
+//! domains carries the UDP packets of the client over the TCP connection,
+//! for clients that can't send UDP to us.
+//!
+//! v1 frames each packet as `[addr][len u16][payload]`. v2 starts with a
+//! request, `[is_connect u8][addr]`, and if it's a connect the packets all go
+//! to that address and are framed as `[len u16][payload]` alone.
+//!
+//! The address is a family, `0x00` IPv4, `0x01` IPv6 or `0x02` a domain
+//! prefixed with its length, followed by the port.
+
+use std::{
+    fmt::{Debug, Formatter},
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::{
+    proxy::{datagram::UdpPacket, InboundDatagram},
+    session::SocksAddr,
+};
+
+const MAGIC_V1: &str = "sp.udp-over-tcp.arpa";
+const MAGIC_V2: &str = "sp.v2.udp-over-tcp.arpa";
+
+const FAMILY_IPV4: u8 = 0x00;
+const FAMILY_IPV6: u8 = 0x01;
+const FAMILY_FQDN: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// The version if `dst` is a magic domain.
+pub fn version(dst: &SocksAddr) -> Option<Version> {
+    match dst {
+        SocksAddr::Domain(d, _) if d == MAGIC_V2 => Some(Version::V2),
+        SocksAddr::Domain(d, _) if d == MAGIC_V1 => Some(Version::V1),
+        _ => None,
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// the address at the start of `src` and its length, `None` if it's not
+/// all there yet
+fn peek_addr(src: &[u8]) -> io::Result<Option<(SocksAddr, usize)>> {
+    let Some(&family) = src.first() else {
+        return Ok(None);
+    };
+    let (addr_len, host) = match family {
+        FAMILY_IPV4 => (4, 1),
+        FAMILY_IPV6 => (16, 1),
+        FAMILY_FQDN => match src.get(1) {
+            Some(&n) => (n as usize, 2),
+            None => return Ok(None),
+        },
+        _ => return Err(invalid("unknown UoT address family")),
+    };
+    let len = host + addr_len + 2;
+    if src.len() < len {
+        return Ok(None);
+    }
+    let raw = &src[host..host + addr_len];
+    let port = u16::from_be_bytes([src[len - 2], src[len - 1]]);
+    let addr = match family {
+        FAMILY_IPV4 => SocksAddr::from((
+            Ipv4Addr::from(<[u8; 4]>::try_from(raw).unwrap()),
+            port,
+        )),
+        FAMILY_IPV6 => SocksAddr::from((
+            Ipv6Addr::from(<[u8; 16]>::try_from(raw).unwrap()),
+            port,
+        )),
+        _ => {
+            let domain = std::str::from_utf8(raw)
+                .map_err(|_| invalid("invalid UoT domain"))?
+                .to_owned();
+            SocksAddr::try_from((domain, port))?
+        }
+    };
+    Ok(Some((addr, len)))
+}
+
+fn write_addr(addr: &SocksAddr, dst: &mut BytesMut) {
+    match addr {
+        SocksAddr::Ip(SocketAddr::V4(a)) => {
+            dst.put_u8(FAMILY_IPV4);
+            dst.put_slice(&a.ip().octets());
+        }
+        SocksAddr::Ip(SocketAddr::V6(a)) => {
+            dst.put_u8(FAMILY_IPV6);
+            dst.put_slice(&a.ip().octets());
+        }
+        SocksAddr::Domain(d, _) => {
+            dst.put_u8(FAMILY_FQDN);
+            dst.put_u8(d.len() as u8);
+            dst.put_slice(d.as_bytes());
+        }
+    }
+    dst.put_u16(addr.port());
+}
+
+pub struct UotCodec {
+    /// the v2 request hasn't been read yet
+    request_pending: bool,
+    /// where all packets go, for a v2 connect
+    destination: Option<SocksAddr>,
+}
+
+impl UotCodec {
+    pub fn new(version: Version) -> Self {
+        Self {
+            request_pending: version == Version::V2,
+            destination: None,
+        }
+    }
+}
+
+impl Decoder for UotCodec {
+    type Error = io::Error;
+    type Item = (SocksAddr, BytesMut);
+
+    fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        if self.request_pending {
+            if src.is_empty() {
+                return Ok(None);
+            }
+            let Some((addr, len)) = peek_addr(&src[1..])? else {
+                return Ok(None);
+            };
+            if src[0] != 0 {
+                self.destination = Some(addr);
+            }
+            src.advance(1 + len);
+            self.request_pending = false;
+        }
+
+        let (addr, header) = match &self.destination {
+            Some(addr) => (addr.clone(), 0),
+            None => match peek_addr(src)? {
+                Some(x) => x,
+                None => return Ok(None),
+            },
+        };
+        if src.len() < header + 2 {
+            return Ok(None);
+        }
+        let n = u16::from_be_bytes([src[header], src[header + 1]]) as usize;
+        if src.len() < header + 2 + n {
+            src.reserve(header + 2 + n - src.len());
+            return Ok(None);
+        }
+        src.advance(header + 2);
+        Ok(Some((addr, src.split_to(n))))
+    }
+}
+
+impl Encoder<(Bytes, SocksAddr)> for UotCodec {
+    type Error = io::Error;
+
+    fn encode(
+        &mut self,
+        item: (Bytes, SocksAddr),
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        let (data, addr) = item;
+        if data.len() > u16::MAX as usize {
+            return Err(invalid("UoT packet too large"));
+        }
+        if self.destination.is_none() {
+            write_addr(&addr, dst);
+        }
+        dst.put_u16(data.len() as u16);
+        dst.put_slice(&data);
+        Ok(())
+    }
+}
+
+/// The packets of a UoT connection, from the client at `src`.
+pub struct InboundUot<S> {
+    inner: Framed<S, UotCodec>,
+    src: SocksAddr,
+}
+
+impl<S> InboundUot<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    pub fn new(inner: S, version: Version, src: SocksAddr) -> Self {
+        Self {
+            inner: Framed::new(inner, UotCodec::new(version)),
+            src,
+        }
+    }
+}
+
+impl<S> Debug for InboundUot<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InboundUot")
+            .field("src", &self.src)
+            .finish()
+    }
+}
+
+impl<S> Stream for InboundUot<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = UdpPacket;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match ready!(this.inner.poll_next_unpin(cx)) {
+            Some(Ok((dst, data))) => Poll::Ready(Some(UdpPacket {
+                data: data.to_vec(),
+                src_addr: this.src.clone(),
+                dst_addr: dst,
+            })),
+            _ => Poll::Ready(None),
+        }
+    }
+}
+
+impl<S> Sink<UdpPacket> for InboundUot<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        self.get_mut()
+            .inner
+            .start_send_unpin((Bytes::from(item.data), item.src_addr))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().inner.poll_close_unpin(cx)
+    }
+}
+
+impl<S> InboundDatagram<UdpPacket> for InboundUot<S> where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, Bytes, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::session::SocksAddr;
+
+    use super::{version, UotCodec, Version};
+
+    #[test]
+    fn test_uot_codec() {
+        assert_eq!(
+            version(&SocksAddr::Domain("sp.v2.udp-over-tcp.arpa".into(), 0)),
+            Some(Version::V2)
+        );
+        assert_eq!(
+            version(&SocksAddr::Domain("sp.udp-over-tcp.arpa".into(), 0)),
+            Some(Version::V1)
+        );
+        assert_eq!(version(&SocksAddr::Domain("example.com".into(), 0)), None);
+
+        // v1, an address for each packet
+        let mut codec = UotCodec::new(Version::V1);
+        let mut buf = BytesMut::new();
+        let dst: SocksAddr =
+            "1.1.1.1:53".parse::<std::net::SocketAddr>().unwrap().into();
+        codec
+            .encode((Bytes::from_static(b"hello"), dst.clone()), &mut buf)
+            .unwrap();
+        codec
+            .encode(
+                (
+                    Bytes::from_static(b"hi"),
+                    SocksAddr::Domain("example.com".into(), 443),
+                ),
+                &mut buf,
+            )
+            .unwrap();
+        let mut partial = buf.split_to(5);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        let (addr, data) = codec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!((addr, &data[..]), (dst.clone(), &b"hello"[..]));
+        let (addr, data) = codec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(addr, SocksAddr::Domain("example.com".into(), 443));
+        assert_eq!(&data[..], b"hi");
+        assert!(partial.is_empty());
+
+        // v2 connect, the packets go to the address of the request
+        let mut codec = UotCodec::new(Version::V2);
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[1, 0x02, 11]);
+        buf.put_slice(b"example.com");
+        buf.put_u16(443);
+        buf.put_u16(3);
+        buf.put_slice(b"abc");
+        let (addr, data) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(addr, SocksAddr::Domain("example.com".into(), 443));
+        assert_eq!(&data[..], b"abc");
+        codec
+            .encode((Bytes::from_static(b"xy"), dst), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], &[0, 2, b'x', b'y']);
+
+        assert!(UotCodec::new(Version::V1)
+            .decode(&mut BytesMut::from(&[0x07u8, 0, 0][..]))
+            .is_err());
+    }
+}
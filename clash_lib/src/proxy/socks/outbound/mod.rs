@@ -10,7 +10,7 @@ use crate::{
     },
     common::errors::new_io_error,
     proxy::{
-        transport::{self, TLSOptions},
+        transport::{self, TLSOptions, TlsVersion},
         utils::{new_tcp_stream, new_udp_socket, RemoteConnector},
         AnyOutboundHandler, AnyStream, CommonOption, ConnectorType, OutboundHandler,
         OutboundType,
@@ -36,6 +36,10 @@ pub struct HandlerOptions {
     pub udp: bool,
     pub tls: bool,
     pub sni: String,
+    pub alpn: Option<Vec<String>>,
+    pub min_tls_version: Option<TlsVersion>,
+    pub max_tls_version: Option<TlsVersion>,
+    pub pin_sha256: Vec<Vec<u8>>,
     pub skip_cert_verify: bool,
 }
 
@@ -49,6 +53,17 @@ impl Handler {
         Arc::new(Self { opts })
     }
 
+    fn tls_options(&self) -> TLSOptions {
+        TLSOptions {
+            skip_cert_verify: self.opts.skip_cert_verify,
+            sni: self.opts.sni.clone(),
+            alpn: self.opts.alpn.clone(),
+            min_version: self.opts.min_tls_version,
+            max_version: self.opts.max_tls_version,
+            pin_sha256: self.opts.pin_sha256.clone(),
+        }
+    }
+
     async fn inner_connect_stream(
         &self,
         s: AnyStream,
@@ -61,11 +76,7 @@ impl Handler {
                 self.opts.skip_cert_verify,
                 self.opts.sni
             );
-            let tls_opt = TLSOptions {
-                skip_cert_verify: self.opts.skip_cert_verify,
-                sni: self.opts.sni.clone(),
-                alpn: None,
-            };
+            let tls_opt = self.tls_options();
 
             transport::tls::wrap_stream(s, tls_opt, None).await?
         } else {
@@ -91,11 +102,7 @@ impl Handler {
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<Socks5Datagram> {
         let mut s = if self.opts.tls {
-            let tls_opt = TLSOptions {
-                skip_cert_verify: self.opts.skip_cert_verify,
-                sni: self.opts.sni.clone(),
-                alpn: None,
-            };
+            let tls_opt = self.tls_options();
 
             transport::tls::wrap_stream(s, tls_opt, None).await?
         } else {
@@ -157,6 +164,10 @@ impl OutboundHandler for Handler {
         OutboundType::Socks5
     }
 
+    fn server(&self) -> Option<(&str, u16)> {
+        Some((&self.opts.server, self.opts.port))
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
@@ -11,6 +11,6 @@ pub use grpc::GrpcStreamBuilder;
 pub use self::h2::Http2Config;
 
 pub mod tls {
-    pub use super::internal_tls::wrap_stream;
+    pub use super::internal_tls::{client_config, wrap_stream};
 }
-pub use internal_tls::TLSOptions;
+pub use internal_tls::{TLSOptions, TlsVersion};
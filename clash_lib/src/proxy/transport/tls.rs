@@ -1,33 +1,92 @@
-use std::io;
+use std::{io, sync::Arc};
 
-use serde::Serialize;
+use once_cell::sync::Lazy;
+use rustls::client::{
+    ClientSessionMemoryCache, ClientSessionStore, Resumption, ServerCertVerifier,
+    WebPkiVerifier,
+};
+use rustls::{ClientConfig, SupportedProtocolVersion};
+use serde::{Deserialize, Serialize};
 
-use crate::proxy::AnyStream;
+use crate::{common::errors::server_error, proxy::AnyStream};
 
-#[derive(Serialize, Clone)]
+/// the sessions kept for resumption of each store
+const MAX_SESSIONS: usize = 256;
+
+/// TLS sessions of the proxy servers by server name, so reconnecting after
+/// an idle timeout resumes instead of doing a full handshake.
+static SESSIONS: Lazy<Arc<dyn ClientSessionStore>> =
+    Lazy::new(|| Arc::new(ClientSessionMemoryCache::new(MAX_SESSIONS)));
+/// the sessions of servers whose certificates weren't verified, kept apart
+/// so they can't stand in for a verified one
+static UNVERIFIED_SESSIONS: Lazy<Arc<dyn ClientSessionStore>> =
+    Lazy::new(|| Arc::new(ClientSessionMemoryCache::new(MAX_SESSIONS)));
+
+/// `min-tls-version` and `max-tls-version` of the outbounds.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol(self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Default)]
 pub struct TLSOptions {
     pub skip_cert_verify: bool,
     pub sni: String,
     pub alpn: Option<Vec<String>>,
+    /// the versions to offer, all supported ones by default
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    /// SHA256 of the certificate or its public key info, one of which the
+    /// server's must match, see [`crate::common::tls::parse_pin`]
+    pub pin_sha256: Vec<Vec<u8>>,
 }
 
-pub async fn wrap_stream(
-    stream: AnyStream,
-    opt: TLSOptions,
-    expected_alpn: Option<&str>,
-) -> io::Result<AnyStream> {
-    use std::sync::Arc;
-
+/// The client config of every TLS connection to a proxy or a DNS server,
+/// so they all verify, resume and negotiate the same way.
+pub fn client_config(opt: &TLSOptions) -> io::Result<ClientConfig> {
     use crate::common::tls::{self, GLOBAL_ROOT_STORE};
 
-    let mut tls_config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
+    let versions = [TlsVersion::Tls12, TlsVersion::Tls13]
+        .into_iter()
+        .filter(|x| opt.min_version.map_or(true, |min| *x >= min))
+        .filter(|x| opt.max_version.map_or(true, |max| *x <= max))
+        .map(TlsVersion::protocol)
+        .collect::<Vec<_>>();
+    if versions.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "no tls version between {:?} and {:?}",
+                opt.min_version, opt.max_version
+            ),
+        ));
+    }
+
+    let mut tls_config = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&versions)
+        .map_err(|x| io::Error::new(io::ErrorKind::InvalidInput, x))?
         .with_root_certificates(GLOBAL_ROOT_STORE.clone())
         .with_no_client_auth();
     tls_config.alpn_protocols = opt
         .alpn
-        .unwrap_or_default()
-        .into_iter()
+        .iter()
+        .flatten()
         .map(|x| x.as_bytes().to_vec())
         .collect();
 
@@ -35,13 +94,45 @@ pub async fn wrap_stream(
         tls_config
             .dangerous()
             .set_certificate_verifier(Arc::new(tls::DummyTlsVerifier {}));
+        tls_config.resumption = Resumption::store(UNVERIFIED_SESSIONS.clone());
+    } else {
+        tls_config.resumption = Resumption::store(SESSIONS.clone());
+    }
+
+    if !opt.pin_sha256.is_empty() {
+        let inner: Arc<dyn ServerCertVerifier> = if opt.skip_cert_verify {
+            Arc::new(tls::DummyTlsVerifier)
+        } else {
+            Arc::new(WebPkiVerifier::new(GLOBAL_ROOT_STORE.clone(), None))
+        };
+        tls_config.dangerous().set_certificate_verifier(Arc::new(
+            tls::PinnedTlsVerifier {
+                pins: opt.pin_sha256.clone(),
+                inner,
+            },
+        ));
+        // a resumed session isn't checked against the pins
+        tls_config.resumption = Resumption::disabled();
     }
 
     tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    Ok(tls_config)
+}
+
+pub async fn wrap_stream(
+    stream: AnyStream,
+    opt: TLSOptions,
+    expected_alpn: Option<&str>,
+) -> io::Result<AnyStream> {
+    let tls_config = client_config(&opt)?;
 
     let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
-    let dns_name = rustls::ServerName::try_from(opt.sni.as_str())
-        .unwrap_or_else(|_| panic!("invalid server name: {}", opt.sni));
+    let dns_name = rustls::ServerName::try_from(opt.sni.as_str()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid server name: {}", opt.sni),
+        )
+    })?;
 
     let c = connector.connect(dns_name, stream).await.and_then(|x| {
         if let Some(expected_alpn) = expected_alpn {
@@ -59,5 +150,137 @@ pub async fn wrap_stream(
 
         Ok(x)
     });
-    c.map(|x| Box::new(x) as _)
+    c.map(|x| Box::new(x) as _).map_err(server_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use rustls::server::{ServerSessionMemoryCache, StoresServerSessions};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::common::tls;
+
+    use super::{client_config, wrap_stream, TLSOptions, TlsVersion};
+
+    /// The sessions of a server, counting the ones it resumed.
+    struct Sessions {
+        inner: Arc<ServerSessionMemoryCache>,
+        resumed: AtomicUsize,
+    }
+
+    impl Sessions {
+        fn hit(&self, value: Option<Vec<u8>>) -> Option<Vec<u8>> {
+            if value.is_some() {
+                self.resumed.fetch_add(1, Ordering::SeqCst);
+            }
+            value
+        }
+    }
+
+    impl StoresServerSessions for Sessions {
+        fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+            self.inner.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.hit(self.inner.get(key))
+        }
+
+        fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.hit(self.inner.take(key))
+        }
+
+        fn can_cache(&self) -> bool {
+            self.inner.can_cache()
+        }
+    }
+
+    #[test]
+    fn test_client_config() {
+        let config = client_config(&TLSOptions {
+            sni: "example.org".to_owned(),
+            alpn: Some(vec!["h2".to_owned(), "http/1.1".to_owned()]),
+            min_version: Some(TlsVersion::Tls13),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            config.alpn_protocols,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+
+        assert!(client_config(&TLSOptions {
+            min_version: Some(TlsVersion::Tls13),
+            max_version: Some(TlsVersion::Tls12),
+            ..Default::default()
+        })
+        .is_err());
+
+        let version: TlsVersion = serde_yaml::from_str("\"1.2\"").unwrap();
+        assert_eq!(version, TlsVersion::Tls12);
+    }
+
+    #[tokio::test]
+    async fn test_resumption() {
+        let base =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../clash/tests/data/config");
+        let sessions = Arc::new(Sessions {
+            inner: ServerSessionMemoryCache::new(16),
+            resumed: AtomicUsize::new(0),
+        });
+        let mut server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                tls::load_certs(&base.join("example.org.pem")).unwrap(),
+                tls::load_private_key(&base.join("example.org-key.pem")).unwrap(),
+            )
+            .unwrap();
+        server_config.session_storage = sessions.clone();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut s = acceptor.accept(stream).await.unwrap();
+                s.write_all(b"hello").await.unwrap();
+                s.flush().await.unwrap();
+                let _ = s.read(&mut [0; 1]).await;
+            }
+        });
+
+        let connect = || async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let opt = TLSOptions {
+                skip_cert_verify: true,
+                // a name of its own, so the sessions of other tests don't mix in
+                sni: "resumption.test".to_owned(),
+                ..Default::default()
+            };
+            let mut s = wrap_stream(Box::new(stream), opt, None).await.unwrap();
+            // the session tickets come before the data
+            let mut buf = [0; 5];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        };
+
+        connect().await;
+        assert_eq!(sessions.resumed.load(Ordering::SeqCst), 0);
+        // a new connection, with a new client config, resumes
+        connect().await;
+        assert_eq!(sessions.resumed.load(Ordering::SeqCst), 1);
+    }
 }
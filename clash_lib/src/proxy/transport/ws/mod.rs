@@ -11,7 +11,10 @@ use tokio_tungstenite::{
 pub use websocket::WebsocketConn;
 pub use websocket_early_data::WebsocketEarlyDataConn;
 
-use crate::{common::errors::map_io_error, proxy::AnyStream};
+use crate::{
+    common::errors::{map_io_error, server_error},
+    proxy::AnyStream,
+};
 
 pub struct WebsocketStreamBuilder {
     server: String,
@@ -80,13 +83,13 @@ impl WebsocketStreamBuilder {
             let (stream, resp) =
                 client_async_with_config(req, stream, self.ws_config)
                     .await
-                    .map_err(map_io_error)?;
+                    .map_err(|x| server_error(map_io_error(x)))?;
 
             if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
-                return Err(std::io::Error::new(
+                return Err(server_error(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
                     "invalid response",
-                ));
+                )));
             }
             Ok(Box::new(WebsocketConn::from_websocket(stream)))
         }
@@ -23,7 +23,7 @@ use self::datagram::OutboundDatagramTrojan;
 use super::{
     options::{GrpcOption, WsOption},
     transport,
-    transport::TLSOptions,
+    transport::{TLSOptions, TlsVersion},
     utils::{new_tcp_stream, RemoteConnector},
     AnyOutboundHandler, AnyStream, CommonOption, ConnectorType, OutboundHandler,
     OutboundType,
@@ -47,6 +47,9 @@ pub struct HandlerOptions {
     pub udp: bool,
     pub sni: String,
     pub alpn: Option<Vec<String>>,
+    pub min_tls_version: Option<TlsVersion>,
+    pub max_tls_version: Option<TlsVersion>,
+    pub pin_sha256: Vec<Vec<u8>>,
     pub skip_cert_verify: bool,
     pub transport: Option<Transport>,
 }
@@ -79,6 +82,9 @@ impl Handler {
                     .map(|x| x.to_owned())
                     .collect::<Vec<String>>(),
             )),
+            min_version: self.opts.min_tls_version,
+            max_version: self.opts.max_tls_version,
+            pin_sha256: self.opts.pin_sha256.clone(),
         };
 
         let s = transport::tls::wrap_stream(s, tls_opt, None).await?;
@@ -138,6 +144,10 @@ impl OutboundHandler for Handler {
         OutboundType::Trojan
     }
 
+    fn server(&self) -> Option<(&str, u16)> {
+        Some((&self.opts.server, self.opts.port))
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
@@ -272,8 +282,31 @@ mod tests {
         run_test_suites_and_cleanup, Suite,
     };
 
+    use crate::proxy::utils::test_harness;
+
     use super::*;
 
+    #[tokio::test]
+    async fn test_trojan_in_process() -> anyhow::Result<()> {
+        let server = test_harness::trojan_server("example").await?;
+        let opts = HandlerOptions {
+            name: "test-trojan-in-process".to_owned(),
+            common_opts: Default::default(),
+            server: server.ip().to_string(),
+            port: server.port(),
+            password: "example".to_owned(),
+            udp: false,
+            sni: "example.org".to_owned(),
+            alpn: None,
+            min_tls_version: None,
+            max_tls_version: None,
+            pin_sha256: vec![],
+            skip_cert_verify: true,
+            transport: None,
+        };
+        test_harness::echo_roundtrip(Handler::new(opts)).await
+    }
+
     async fn get_ws_runner() -> anyhow::Result<DockerTestRunner> {
         let test_config_dir = test_config_base_dir();
         let trojan_conf = test_config_dir.join("trojan-ws.json");
@@ -310,6 +343,9 @@ mod tests {
             udp: true,
             sni: "example.org".to_owned(),
             alpn: None,
+            min_tls_version: None,
+            max_tls_version: None,
+            pin_sha256: vec![],
             skip_cert_verify: true,
             transport: Some(Transport::Ws(WsOption {
                 path: "".to_owned(),
@@ -356,6 +392,9 @@ mod tests {
             udp: true,
             sni: "example.org".to_owned(),
             alpn: None,
+            min_tls_version: None,
+            max_tls_version: None,
+            pin_sha256: vec![],
             skip_cert_verify: true,
             transport: Some(Transport::Grpc(GrpcOption {
                 host: "example.org".to_owned(),
@@ -33,7 +33,10 @@ use crate::{
         },
         dns::ThreadSafeDNSResolver,
     },
-    common::tls::GLOBAL_ROOT_STORE,
+    common::{
+        errors::server_error,
+        tls::{PinnedTlsVerifier, GLOBAL_ROOT_STORE},
+    },
     proxy::tuic::types::{ServerAddr, TuicEndpoint},
     session::Session,
 };
@@ -45,7 +48,7 @@ use quinn::{
 };
 use tokio::sync::{Mutex as AsyncMutex, OnceCell};
 
-use rustls::client::ClientConfig as TlsConfig;
+use rustls::client::{ClientConfig as TlsConfig, Resumption, WebPkiVerifier};
 
 use self::types::{CongestionControl, TuicConnection, UdpRelayMode, UdpSession};
 
@@ -76,6 +79,9 @@ pub struct HandlerOptions {
     pub gc_lifetime: Duration,
     pub send_window: u64,
     pub receive_window: VarInt,
+    /// SHA256 of the server certificate or its public key, one of which it
+    /// must match
+    pub pin_sha256: Vec<Vec<u8>>,
 
     /// not used
     #[allow(dead_code)]
@@ -105,6 +111,10 @@ impl OutboundHandler for Handler {
         OutboundType::Tuic
     }
 
+    fn server(&self) -> Option<(&str, u16)> {
+        Some((&self.opts.server, self.opts.port))
+    }
+
     async fn support_udp(&self) -> bool {
         true
     }
@@ -114,7 +124,8 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedStream> {
-        self.do_connect_stream(sess, resolver).await.map_err(|e| {
+        let conn = self.connect_server(&resolver).await?;
+        self.do_connect_stream(sess, conn).await.map_err(|e| {
             tracing::error!("{:?}", e);
             std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
         })
@@ -125,7 +136,8 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedDatagram> {
-        self.do_connect_datagram(sess, resolver).await.map_err(|e| {
+        let conn = self.connect_server(&resolver).await?;
+        self.do_connect_datagram(sess, conn).await.map_err(|e| {
             tracing::error!("{:?}", e);
             std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
         })
@@ -164,6 +176,19 @@ impl Handler {
         crypto.alpn_protocols.clone_from(&opts.alpn);
         crypto.enable_early_data = true;
         crypto.enable_sni = !opts.disable_sni;
+        if !opts.pin_sha256.is_empty() {
+            crypto.dangerous().set_certificate_verifier(Arc::new(
+                PinnedTlsVerifier {
+                    pins: opts.pin_sha256.clone(),
+                    inner: Arc::new(WebPkiVerifier::new(
+                        GLOBAL_ROOT_STORE.clone(),
+                        None,
+                    )),
+                },
+            ));
+            // a resumed session isn't checked against the pins
+            crypto.resumption = Resumption::disabled();
+        }
         let mut quinn_config = QuinnConfig::new(Arc::new(crypto));
         let mut transport_config = QuinnTransportConfig::default();
         transport_config
@@ -263,12 +288,25 @@ impl Handler {
         tokio::time::timeout(self.opts.request_timeout, fut).await?
     }
 
+    /// The QUIC connection to the server, its failures are the server's.
+    async fn connect_server(
+        &self,
+        resolver: &ThreadSafeDNSResolver,
+    ) -> std::io::Result<Arc<TuicConnection>> {
+        self.get_conn(resolver).await.map_err(|e| {
+            tracing::error!("{:?}", e);
+            server_error(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e.to_string(),
+            ))
+        })
+    }
+
     async fn do_connect_stream(
         &self,
         sess: &Session,
-        resolver: ThreadSafeDNSResolver,
+        conn: Arc<TuicConnection>,
     ) -> Result<BoxedChainedStream> {
-        let conn = self.get_conn(&resolver).await?;
         let dest = sess.destination.clone().into_tuic();
         let tuic_tcp = conn.connect_tcp(dest).await?.compat();
         let s = ChainedStreamWrapper::new(tuic_tcp);
@@ -279,9 +317,8 @@ impl Handler {
     async fn do_connect_datagram(
         &self,
         sess: &Session,
-        resolver: ThreadSafeDNSResolver,
+        conn: Arc<TuicConnection>,
     ) -> Result<BoxedChainedDatagram> {
-        let conn = self.get_conn(&resolver).await?;
         let assos_id = self.next_assoc_id.fetch_add(1, Ordering::SeqCst);
         let quic_udp = TuicDatagramOutbound::new(assos_id, conn, sess.source.into());
         let s = ChainedDatagramWrapper::new(quic_udp);
@@ -11,6 +11,7 @@ use crate::{
 pub struct TunDatagram {
     rx: tokio::sync::mpsc::Receiver<UdpPacket>,
     tx: tokio::sync::mpsc::Sender<UdpPacket>,
+    unreachable: tokio::sync::mpsc::Sender<UdpPacket>,
 
     pkt: Option<UdpPacket>,
     flushed: bool,
@@ -24,12 +25,15 @@ impl TunDatagram {
         tx: tokio::sync::mpsc::Sender<UdpPacket>,
         // receive from tun
         rx: tokio::sync::mpsc::Receiver<UdpPacket>,
+        // the packets to answer with an ICMP error
+        unreachable: tokio::sync::mpsc::Sender<UdpPacket>,
         // the address of the tun udp socket
         local_addr: SocketAddr,
     ) -> Self {
         Self {
             rx,
             tx,
+            unreachable,
             pkt: None,
             flushed: true,
             local_addr,
@@ -37,7 +41,11 @@ impl TunDatagram {
     }
 }
 
-impl InboundDatagram<UdpPacket> for TunDatagram {}
+impl InboundDatagram<UdpPacket> for TunDatagram {
+    fn unreachable(&self) -> Option<tokio::sync::mpsc::Sender<UdpPacket>> {
+        Some(self.unreachable.clone())
+    }
+}
 
 impl Stream for TunDatagram {
     type Item = UdpPacket;
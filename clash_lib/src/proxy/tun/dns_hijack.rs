@@ -0,0 +1,81 @@
+//! DNS queries through the tun device to the servers in `dns-hijack` are
+//! answered by clash rather than routed, so they get fake IPs in fake-ip
+//! mode.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::Error;
+
+pub struct DnsHijack {
+    /// the servers, `None` for any address
+    servers: Vec<(Option<IpAddr>, u16)>,
+}
+
+impl DnsHijack {
+    /// `8.8.8.8:53`, `[2001:4860:4860::8888]:53` or `any:53`, optionally
+    /// prefixed with `udp://`, port 53 if it's left out
+    pub fn parse(servers: &[String]) -> Result<Self, Error> {
+        let servers = servers
+            .iter()
+            .map(|x| {
+                let invalid = || {
+                    Error::InvalidConfig(format!("invalid tun dns-hijack: {}", x))
+                };
+                let s = x.strip_prefix("udp://").unwrap_or(x);
+                if let Ok(ip) = s.parse::<IpAddr>() {
+                    return Ok((Some(ip), 53));
+                }
+                let (host, port) = s.rsplit_once(':').unwrap_or((s, "53"));
+                let port = port.parse::<u16>().map_err(|_| invalid())?;
+                let ip = match host {
+                    "any" | "0.0.0.0" | "[::]" => None,
+                    host => Some(
+                        host.trim_start_matches('[')
+                            .trim_end_matches(']')
+                            .parse::<IpAddr>()
+                            .map_err(|_| invalid())?,
+                    ),
+                };
+                Ok((ip, port))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { servers })
+    }
+
+    pub fn matches(&self, dst: &SocketAddr) -> bool {
+        self.servers.iter().any(|(ip, port)| {
+            *port == dst.port()
+                && match ip {
+                    Some(ip) => *ip == dst.ip(),
+                    None => true,
+                }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DnsHijack;
+
+    #[test]
+    fn test_dns_hijack() {
+        let hijack = DnsHijack::parse(&[
+            "any:53".to_owned(),
+            "udp://10.0.0.5:5353".to_owned(),
+            "[2001:4860:4860::8888]:853".to_owned(),
+        ])
+        .unwrap();
+        assert!(hijack.matches(&"1.1.1.1:53".parse().unwrap()));
+        assert!(hijack.matches(&"10.0.0.5:5353".parse().unwrap()));
+        assert!(!hijack.matches(&"10.0.0.6:5353".parse().unwrap()));
+        assert!(hijack.matches(&"[2001:4860:4860::8888]:853".parse().unwrap()));
+
+        let hijack = DnsHijack::parse(&["10.0.0.5".to_owned()]).unwrap();
+        assert!(hijack.matches(&"10.0.0.5:53".parse().unwrap()));
+        assert!(DnsHijack::parse(&["dns.google:53".to_owned()]).is_err());
+        assert!(DnsHijack::parse(&["any:dns".to_owned()]).is_err());
+        assert!(!DnsHijack::parse(&[])
+            .unwrap()
+            .matches(&"1.1.1.1:53".parse().unwrap()));
+    }
+}
@@ -0,0 +1,496 @@
+//! ICMP for the tun, which the stack has no use for. Pings to the gateway
+//! are answered, packets that would expire at the gateway get a time
+//! exceeded so traceroute shows it as the first hop, and pings elsewhere
+//! are either relayed through an ICMP socket on the outbound interface or
+//! answered with host unreachable. UDP packets the dispatcher drops, those
+//! rejected or whose outbound failed, get a host unreachable too.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, trace};
+
+use crate::proxy::utils::{get_outbound_interface, new_icmp_socket, Interface};
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_UDP: u8 = 17;
+const PROTO_ICMPV6: u8 = 58;
+/// how long a relayed ping waits for its reply
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+/// how many relayed pings may wait for their replies at once, the others
+/// are dropped
+const MAX_RELAYS: usize = 64;
+/// the longest IP header a reply may come with
+const MAX_IP_HEADER: usize = 60;
+/// the most of the offending packet quoted in an error, keeping it within
+/// the minimum MTU
+const MAX_QUOTE_V4: usize = 576 - 28;
+const MAX_QUOTE_V6: usize = 1280 - 48;
+
+/// The addresses and payload of an IP packet, without IPv6 extension
+/// headers.
+struct Ip<'a> {
+    src: IpAddr,
+    dst: IpAddr,
+    ttl: u8,
+    proto: u8,
+    payload: &'a [u8],
+}
+
+impl<'a> Ip<'a> {
+    fn parse(pkt: &'a [u8]) -> Option<Self> {
+        match pkt.first()? >> 4 {
+            4 if pkt.len() >= 20 => {
+                let len = (pkt[0] & 0x0f) as usize * 4;
+                let total = u16::from_be_bytes([pkt[2], pkt[3]]) as usize;
+                // later fragments have no header of the upper layer
+                let offset = u16::from_be_bytes([pkt[6], pkt[7]]) & 0x1fff;
+                if len < 20 || total < len || total > pkt.len() || offset != 0 {
+                    return None;
+                }
+                let src: [u8; 4] = pkt[12..16].try_into().unwrap();
+                let dst: [u8; 4] = pkt[16..20].try_into().unwrap();
+                Some(Self {
+                    src: Ipv4Addr::from(src).into(),
+                    dst: Ipv4Addr::from(dst).into(),
+                    ttl: pkt[8],
+                    proto: pkt[9],
+                    payload: &pkt[len..total],
+                })
+            }
+            6 if pkt.len() >= 40 => {
+                let total = 40 + u16::from_be_bytes([pkt[4], pkt[5]]) as usize;
+                if total > pkt.len() {
+                    return None;
+                }
+                let src: [u8; 16] = pkt[8..24].try_into().unwrap();
+                let dst: [u8; 16] = pkt[24..40].try_into().unwrap();
+                Some(Self {
+                    src: Ipv6Addr::from(src).into(),
+                    dst: Ipv6Addr::from(dst).into(),
+                    ttl: pkt[7],
+                    proto: pkt[6],
+                    payload: &pkt[40..total],
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn is_icmp(&self) -> bool {
+        match self.src {
+            IpAddr::V4(_) => self.proto == PROTO_ICMP,
+            IpAddr::V6(_) => self.proto == PROTO_ICMPV6,
+        }
+    }
+
+    /// An ICMP error must never be answered with another.
+    fn is_icmp_error(&self) -> bool {
+        self.is_icmp()
+            && self.payload.first().is_some_and(|x| match self.src {
+                IpAddr::V4(_) => ![0, 8, 13, 14].contains(x),
+                IpAddr::V6(_) => *x < 128,
+            })
+    }
+
+    fn is_echo_request(&self) -> bool {
+        self.is_icmp()
+            && self.payload.len() >= 8
+            && self.payload[1] == 0
+            && self.payload[0] == self.echo_type(true)
+    }
+
+    fn echo_type(&self, request: bool) -> u8 {
+        match (self.src, request) {
+            (IpAddr::V4(_), true) => 8,
+            (IpAddr::V4(_), false) => 0,
+            (IpAddr::V6(_), true) => 128,
+            (IpAddr::V6(_), false) => 129,
+        }
+    }
+}
+
+fn sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for x in &mut chunks {
+        acc += u16::from_be_bytes([x[0], x[1]]) as u32;
+    }
+    if let [x] = chunks.remainder() {
+        acc += (*x as u32) << 8;
+    }
+    acc
+}
+
+fn checksum(data: &[u8], acc: u32) -> [u8; 2] {
+    let mut acc = sum(data, acc);
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    (!(acc as u16)).to_be_bytes()
+}
+
+/// An IP packet from `src` to `dst` carrying the ICMP `message`, whose
+/// checksum is filled in.
+fn icmp_packet(src: IpAddr, dst: IpAddr, mut message: Vec<u8>) -> Vec<u8> {
+    message[2..4].fill(0);
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let csum = checksum(&message, 0);
+            message[2..4].copy_from_slice(&csum);
+
+            let mut pkt = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, PROTO_ICMP, 0, 0];
+            pkt[2..4].copy_from_slice(&((20 + message.len()) as u16).to_be_bytes());
+            pkt.extend(src.octets());
+            pkt.extend(dst.octets());
+            let csum = checksum(&pkt, 0);
+            pkt[10..12].copy_from_slice(&csum);
+            pkt.extend(message);
+            pkt
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let pseudo = sum(&src.octets(), 0)
+                + sum(&dst.octets(), 0)
+                + message.len() as u32
+                + PROTO_ICMPV6 as u32;
+            let csum = checksum(&message, pseudo);
+            message[2..4].copy_from_slice(&csum);
+
+            let mut pkt = vec![0x60, 0, 0, 0, 0, 0, PROTO_ICMPV6, 64];
+            pkt[4..6].copy_from_slice(&(message.len() as u16).to_be_bytes());
+            pkt.extend(src.octets());
+            pkt.extend(dst.octets());
+            pkt.extend(message);
+            pkt
+        }
+        _ => unreachable!("addresses of the same family"),
+    }
+}
+
+/// An IP packet from `src` to `dst` carrying the UDP `data`.
+fn udp_packet(src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(8 + data.len());
+    datagram.extend(src.port().to_be_bytes());
+    datagram.extend(dst.port().to_be_bytes());
+    datagram.extend(((8 + data.len()) as u16).to_be_bytes());
+    // no checksum, it's only ever quoted
+    datagram.extend([0, 0]);
+    datagram.extend_from_slice(data);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut pkt = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, PROTO_UDP, 0, 0];
+            pkt[2..4].copy_from_slice(&((20 + datagram.len()) as u16).to_be_bytes());
+            pkt.extend(src.octets());
+            pkt.extend(dst.octets());
+            let csum = checksum(&pkt, 0);
+            pkt[10..12].copy_from_slice(&csum);
+            pkt.extend(datagram);
+            pkt
+        }
+        (src, dst) => {
+            let to_v6 = |x: IpAddr| match x {
+                IpAddr::V4(x) => x.to_ipv6_mapped(),
+                IpAddr::V6(x) => x,
+            };
+            let mut pkt = vec![0x60, 0, 0, 0, 0, 0, PROTO_UDP, 64];
+            pkt[4..6].copy_from_slice(&(datagram.len() as u16).to_be_bytes());
+            pkt.extend(to_v6(src).octets());
+            pkt.extend(to_v6(dst).octets());
+            pkt.extend(datagram);
+            pkt
+        }
+    }
+}
+
+pub struct Icmp {
+    gateway: IpAddr,
+    relay: bool,
+    /// bounds the relayed pings waiting for their replies
+    relays: Arc<Semaphore>,
+    /// to the tun
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl Icmp {
+    /// The handler, and the packets it sends to the tun.
+    pub fn new(gateway: IpAddr, relay: bool) -> (Self, mpsc::Receiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::channel(64);
+        let relays = Arc::new(Semaphore::new(MAX_RELAYS));
+        (
+            Self {
+                gateway,
+                relay,
+                relays,
+                tx,
+            },
+            rx,
+        )
+    }
+
+    /// Answer the UDP `data` from `src` to `dst` that went nowhere with a
+    /// host unreachable.
+    pub fn udp_unreachable(&self, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        let pkt = udp_packet(src, dst, data);
+        if let Some(ip) = Ip::parse(&pkt) {
+            self.error(&ip, &pkt, unreachable_code(&ip));
+        }
+    }
+
+    /// Handle `pkt` read from the tun if it's ICMP's business, false to
+    /// leave it to the stack.
+    pub fn intercept(&self, pkt: &[u8]) -> bool {
+        let Some(ip) = Ip::parse(pkt) else {
+            return false;
+        };
+
+        if ip.ttl <= 1 && ip.dst != self.gateway && !ip.is_icmp_error() {
+            let time_exceeded = match ip.src {
+                IpAddr::V4(_) => (11, 0),
+                IpAddr::V6(_) => (3, 0),
+            };
+            self.error(&ip, pkt, time_exceeded);
+            return true;
+        }
+        if !ip.is_echo_request() {
+            return false;
+        }
+
+        if ip.dst == self.gateway {
+            self.send(echo_reply(&ip, &ip.payload[8..]));
+        } else if self.relay {
+            let Ok(permit) = self.relays.clone().try_acquire_owned() else {
+                trace!("too many pings relayed, dropping one to {}", ip.dst);
+                return true;
+            };
+            let tx = self.tx.clone();
+            let (src, dst) = (ip.src, ip.dst);
+            let request = ip.payload.to_vec();
+            let unreachable = self.error_packet(&ip, pkt, unreachable_code(&ip));
+            tokio::spawn(async move {
+                let _permit = permit;
+                let reply =
+                    match tokio::time::timeout(RELAY_TIMEOUT, ping(dst, &request))
+                        .await
+                    {
+                        Ok(Ok(data)) => {
+                            let ip = Ip {
+                                src,
+                                dst,
+                                ttl: 64,
+                                proto: 0,
+                                payload: &request,
+                            };
+                            echo_reply(&ip, &data)
+                        }
+                        Ok(Err(e)) => {
+                            debug!("failed to relay ping to {}: {}", dst, e);
+                            unreachable
+                        }
+                        // lost, just like it would be
+                        Err(_) => return,
+                    };
+                let _ = tx.try_send(reply);
+            });
+        } else {
+            self.error(&ip, pkt, unreachable_code(&ip));
+        }
+        true
+    }
+
+    fn send(&self, pkt: Vec<u8>) {
+        if self.tx.try_send(pkt).is_err() {
+            trace!("dropping an ICMP reply, the tun is busy");
+        }
+    }
+
+    fn error(&self, ip: &Ip, pkt: &[u8], typ_code: (u8, u8)) {
+        self.send(self.error_packet(ip, pkt, typ_code));
+    }
+
+    /// An ICMP error about `pkt` from the gateway, or from where it was
+    /// sent to if the gateway is of the other family.
+    fn error_packet(&self, ip: &Ip, pkt: &[u8], (typ, code): (u8, u8)) -> Vec<u8> {
+        let src = if self.gateway.is_ipv4() == ip.src.is_ipv4() {
+            self.gateway
+        } else {
+            ip.dst
+        };
+        let quote = match ip.src {
+            IpAddr::V4(_) => MAX_QUOTE_V4,
+            IpAddr::V6(_) => MAX_QUOTE_V6,
+        };
+        let mut message = vec![typ, code, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&pkt[..pkt.len().min(quote)]);
+        icmp_packet(src, ip.src, message)
+    }
+}
+
+fn unreachable_code(ip: &Ip) -> (u8, u8) {
+    match ip.src {
+        // host unreachable
+        IpAddr::V4(_) => (3, 1),
+        // address unreachable
+        IpAddr::V6(_) => (1, 3),
+    }
+}
+
+/// The reply to the echo request in `ip`, carrying `data`.
+fn echo_reply(ip: &Ip, data: &[u8]) -> Vec<u8> {
+    let mut message = ip.payload[..8].to_vec();
+    message[0] = ip.echo_type(false);
+    message.extend_from_slice(data);
+    icmp_packet(ip.dst, ip.src, message)
+}
+
+/// Send the echo `request` to `dst` from the outbound interface, returning
+/// the data of its reply.
+async fn ping(dst: IpAddr, request: &[u8]) -> io::Result<Vec<u8>> {
+    let iface = get_outbound_interface().map(|x| Interface::Name(x.name));
+    let socket = new_icmp_socket(dst.is_ipv6(), iface.as_ref())?;
+    socket.connect(SocketAddr::new(dst, 0)).await?;
+
+    let mut request = request.to_vec();
+    if dst.is_ipv4() {
+        request[2..4].fill(0);
+        let csum = checksum(&request, 0);
+        request[2..4].copy_from_slice(&csum);
+    }
+    socket.send(&request).await?;
+
+    // ping sockets put an identifier of their own in the request, raw ones
+    // send it as is
+    let raw = socket2::SockRef::from(&socket).r#type()? == socket2::Type::RAW;
+    let local_id = socket.local_addr()?.port().to_be_bytes();
+    let is_ours = |id: &[u8]| id == &request[4..6] || (!raw && id == &local_id[..]);
+
+    let reply_type = if dst.is_ipv4() { 0 } else { 129 };
+    // the reply echoes the request, IP header aside
+    let mut buf = vec![0; MAX_IP_HEADER + request.len()];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        // raw sockets get the ICMP of every host
+        if peer.ip() != dst {
+            continue;
+        }
+        let mut reply = &buf[..n];
+        // raw IPv4 sockets, and ping sockets on some systems, get the
+        // IP header too
+        if dst.is_ipv4() && reply.first().is_some_and(|x| x >> 4 == 4) {
+            let len = (reply[0] & 0x0f) as usize * 4;
+            reply = reply.get(len..).unwrap_or_default();
+        }
+        if reply.len() >= 8
+            && reply[0] == reply_type
+            && is_ours(&reply[4..6])
+            && reply[6..8] == request[6..8]
+        {
+            return Ok(reply[8..].to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{checksum, icmp_packet, sum, Icmp, Ip};
+
+    const CLIENT: [u8; 4] = [198, 18, 0, 5];
+    const GATEWAY: [u8; 4] = [198, 18, 0, 1];
+
+    fn echo_request(dst: [u8; 4]) -> Vec<u8> {
+        let message = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g'];
+        icmp_packet(IpAddr::from(CLIENT), IpAddr::from(dst), message)
+    }
+
+    #[test]
+    fn test_ping_gateway() {
+        let (icmp, mut rx) = Icmp::new(IpAddr::from(GATEWAY), false);
+        assert!(icmp.intercept(&echo_request(GATEWAY)));
+
+        let reply = rx.try_recv().unwrap();
+        let ip = Ip::parse(&reply).unwrap();
+        assert_eq!(ip.src, IpAddr::from(GATEWAY));
+        assert_eq!(ip.dst, IpAddr::from(CLIENT));
+        assert_eq!(ip.payload[0], 0);
+        assert_eq!(
+            &ip.payload[4..],
+            &[0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g']
+        );
+        assert_eq!(checksum(ip.payload, 0), [0, 0]);
+        assert_eq!(checksum(&reply[..20], 0), [0, 0]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let (icmp, mut rx) = Icmp::new(IpAddr::from(GATEWAY), false);
+        // not relayed
+        let request = echo_request([1, 1, 1, 1]);
+        assert!(icmp.intercept(&request));
+        let error = rx.try_recv().unwrap();
+        let ip = Ip::parse(&error).unwrap();
+        assert_eq!(ip.src, IpAddr::from(GATEWAY));
+        assert_eq!(&ip.payload[..2], &[3, 1]);
+        assert_eq!(&ip.payload[8..], &request[..]);
+
+        // expiring at the gateway
+        let mut request = request;
+        request[8] = 1;
+        request[10..12].fill(0);
+        let csum = checksum(&request[..20], 0);
+        request[10..12].copy_from_slice(&csum);
+        assert!(icmp.intercept(&request));
+        let error = rx.try_recv().unwrap();
+        assert_eq!(&Ip::parse(&error).unwrap().payload[..2], &[11, 0]);
+
+        // never about an error
+        let mut error = error;
+        error[8] = 1;
+        assert!(!icmp.intercept(&error));
+        assert!(rx.try_recv().is_err());
+
+        // not ICMP's
+        let udp = [
+            0x45, 0, 0, 28, 0, 0, 0, 0, 64, 17, 0, 0, 198, 18, 0, 5, 1, 1, 1, 1, 0,
+            53, 0, 53, 0, 8, 0, 0,
+        ];
+        assert!(!icmp.intercept(&udp));
+    }
+
+    #[test]
+    fn test_udp_unreachable() {
+        let (icmp, mut rx) = Icmp::new(IpAddr::from(GATEWAY), false);
+        let src = (IpAddr::from(CLIENT), 51234).into();
+        icmp.udp_unreachable(src, "1.1.1.1:53".parse().unwrap(), b"query");
+
+        let error = rx.try_recv().unwrap();
+        let ip = Ip::parse(&error).unwrap();
+        assert_eq!(ip.src, IpAddr::from(GATEWAY));
+        assert_eq!(ip.dst, IpAddr::from(CLIENT));
+        assert_eq!(&ip.payload[..2], &[3, 1]);
+        assert_eq!(checksum(ip.payload, 0), [0, 0]);
+
+        // quoting the datagram the client sent
+        let quoted = Ip::parse(&ip.payload[8..]).unwrap();
+        assert_eq!(quoted.src, IpAddr::from(CLIENT));
+        assert_eq!(quoted.dst, IpAddr::from([1, 1, 1, 1]));
+        assert_eq!(&quoted.payload[..4], &[0xc8, 0x22, 0, 53]);
+        assert_eq!(&quoted.payload[8..], b"query");
+    }
+
+    #[test]
+    fn test_icmpv6_checksum() {
+        let src: IpAddr = "fd00::2".parse().unwrap();
+        let dst: IpAddr = "fd00::1".parse().unwrap();
+        let pkt = icmp_packet(src, dst, vec![128, 0, 0, 0, 0, 1, 0, 1]);
+        let ip = Ip::parse(&pkt).unwrap();
+        assert!(ip.is_echo_request());
+        let pseudo = sum(&pkt[8..40], 0) + 8 + 58;
+        assert_eq!(checksum(ip.payload, pseudo), [0, 0]);
+    }
+}
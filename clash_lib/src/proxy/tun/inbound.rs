@@ -1,13 +1,27 @@
-use super::{datagram::TunDatagram, netstack};
-use std::{net::SocketAddr, sync::Arc};
+#[cfg(target_os = "linux")]
+use super::offload;
+use super::{datagram::TunDatagram, dns_hijack::DnsHijack, icmp, netstack, routes};
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use futures::{SinkExt, StreamExt};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use hickory_proto::{op, rr};
+use ipnet::IpNet;
+use tokio::sync::Semaphore;
+use tokio_stream::wrappers::ReceiverStream;
 use tracing::{debug, error, info, trace, warn};
 use tun::{Device, TunPacket};
 use url::Url;
 
 use crate::{
-    app::{dispatcher::Dispatcher, dns::ThreadSafeDNSResolver},
+    app::{
+        dispatcher::Dispatcher,
+        dns::{self, ThreadSafeDNSResolver},
+    },
     common::errors::{map_io_error, new_io_error},
     config::internal::config::TunConfig,
     proxy::{datagram::UdpPacket, utils::get_outbound_interface},
@@ -25,6 +39,7 @@ async fn handle_inbound_stream(
         network: Network::Tcp,
         typ: Type::Tun,
         source: local_addr,
+        inbound_name: "TUN".to_owned(),
         destination: remote_addr.into(),
         iface: get_outbound_interface()
             .map(|x| crate::proxy::utils::Interface::Name(x.name))
@@ -41,10 +56,15 @@ async fn handle_inbound_stream(
     dispatcher.dispatch_stream(sess, stream).await;
 }
 
+/// hijacked DNS queries answered at once, per TUN device
+const MAX_HIJACKED_QUERIES: usize = 256;
+
 async fn handle_inbound_datagram(
     socket: Box<netstack::UdpSocket>,
     dispatcher: Arc<Dispatcher>,
     resolver: ThreadSafeDNSResolver,
+    dns_hijack: DnsHijack,
+    icmp: Arc<icmp::Icmp>,
 ) {
     let local_addr = socket.local_addr();
     // tun i/o
@@ -58,14 +78,18 @@ async fn handle_inbound_datagram(
     // forward packets from tun to dispatcher
     let (d_tx, d_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
 
+    // the packets the dispatcher couldn't send on
+    let (u_tx, mut u_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+
     // for dispatcher - the dispatcher would receive packets from this channel,
     // which is from the stack and send back packets to this channel, which
     // is to the tun
-    let udp_stream = TunDatagram::new(l_tx, d_rx, local_addr);
+    let udp_stream = TunDatagram::new(l_tx, d_rx, u_tx, local_addr);
 
     let sess = Session {
         network: Network::Udp,
         typ: Type::Tun,
+        inbound_name: "TUN".to_owned(),
         iface: get_outbound_interface()
             .map(|x| crate::proxy::utils::Interface::Name(x.name))
             .inspect(|x| {
@@ -77,6 +101,10 @@ async fn handle_inbound_datagram(
 
     let closer = dispatcher.dispatch_datagram(sess, Box::new(udp_stream));
 
+    let hijack_ls = ls.clone();
+    let hijack_resolver = resolver.clone();
+    let hijacking = Arc::new(Semaphore::new(MAX_HIJACKED_QUERIES));
+
     // dispatcher -> tun
     let fut1 = tokio::spawn(async move {
         while let Some(pkt) = l_rx.recv().await {
@@ -111,6 +139,33 @@ async fn handle_inbound_datagram(
     // tun -> dispatcher
     let fut2 = tokio::spawn(async move {
         while let Ok((data, src_addr, dst_addr)) = lr.recv_from().await {
+            if dns_hijack.matches(&dst_addr) {
+                // dropped like any UDP packet under load, the client asks
+                // again
+                let Ok(permit) = hijacking.clone().try_acquire_owned() else {
+                    debug!("too many hijacked dns queries, dropping one");
+                    continue;
+                };
+                let ls = hijack_ls.clone();
+                let resolver = hijack_resolver.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    match dns::answer_hijacked(&resolver, &data).await {
+                        Ok(answer) => {
+                            if let Err(e) = ls.send_to(&answer, &dst_addr, &src_addr)
+                            {
+                                warn!(
+                                    "failed to send dns answer to netstack: {}",
+                                    e
+                                );
+                            }
+                        }
+                        Err(e) => debug!("hijacked dns query failed: {}", e),
+                    }
+                });
+                continue;
+            }
+
             let pkt = UdpPacket {
                 data,
                 src_addr: src_addr.into(),
@@ -130,9 +185,126 @@ async fn handle_inbound_datagram(
         closer.send(0).ok();
     });
 
+    // dispatcher -> icmp -> tun
+    let fut3 = tokio::spawn(async move {
+        while let Some(pkt) = u_rx.recv().await {
+            if let (SocksAddr::Ip(src), SocksAddr::Ip(dst)) =
+                (pkt.src_addr, pkt.dst_addr)
+            {
+                icmp.udp_unreachable(src, dst, &pkt.data);
+            }
+        }
+    });
+
     debug!("tun UDP ready");
 
-    let _ = futures::future::join(fut1, fut2).await;
+    let _ = futures::future::join3(fut1, fut2, fut3).await;
+}
+
+enum Device {
+    Tun(tun::AsyncDevice),
+    #[cfg(target_os = "linux")]
+    Offload(offload::Device),
+}
+
+/// Pass the packets between a plain tun and the stack.
+fn forward<Si, St>(
+    tun: tun::AsyncDevice,
+    mut stack_sink: Si,
+    mut stack_stream: St,
+) -> Vec<Runner>
+where
+    Si: Sink<Vec<u8>> + Send + Unpin + 'static,
+    Si::Error: Display,
+    St: Stream<Item = std::io::Result<Vec<u8>>> + Send + Unpin + 'static,
+{
+    let (mut tun_sink, mut tun_stream) = tun.into_framed().split();
+
+    let mut futs: Vec<Runner> = vec![];
+
+    // dispatcher -> stack -> tun
+    futs.push(Box::pin(async move {
+        while let Some(pkt) = stack_stream.next().await {
+            match pkt {
+                Ok(pkt) => {
+                    if let Err(e) = tun_sink.send(TunPacket::new(pkt)).await {
+                        error!("failed to send pkt to tun: {}", e);
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("tun stack error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        Err(Error::Operation("tun stopped unexpectedly 0".to_string()))
+    }));
+
+    // tun -> stack -> dispatcher
+    futs.push(Box::pin(async move {
+        while let Some(pkt) = tun_stream.next().await {
+            match pkt {
+                Ok(pkt) => {
+                    if let Err(e) = stack_sink.send(pkt.into_bytes().into()).await {
+                        error!("failed to send pkt to stack: {}", e);
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("tun stream error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        Err(Error::Operation("tun stopped unexpectedly 1".to_string()))
+    }));
+
+    futs
+}
+
+/// How often the proxy servers are looked up again for their routes, to
+/// follow provider updates and servers changing address.
+const BYPASS_REFRESH: Duration = Duration::from_secs(60);
+
+/// The addresses of all proxy servers, as host prefixes.
+async fn proxy_servers(
+    dispatcher: &Dispatcher,
+    resolver: &ThreadSafeDNSResolver,
+) -> Vec<IpNet> {
+    let mut ips = vec![];
+    for host in dispatcher.outbound_manager().server_hosts().await {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            ips.push(ip);
+            continue;
+        }
+        // all of its addresses, any of them may be dialed
+        let found = ips.len();
+        for typ in [rr::RecordType::A, rr::RecordType::AAAA] {
+            let Ok(name) = rr::Name::from_str_relaxed(&host) else {
+                break;
+            };
+            let mut m = op::Message::new();
+            m.add_query(op::Query::query(name, typ));
+            m.set_recursion_desired(true);
+            if let Ok(answer) = resolver.exchange(m).await {
+                ips.extend(dns::EnhancedResolver::ip_list_of_message(&answer));
+            }
+        }
+        // the system resolver can't exchange messages
+        if ips.len() == found {
+            match resolver.resolve(&host, false).await {
+                Ok(Some(ip)) => ips.push(ip),
+                _ => warn!("failed to resolve proxy server {} to route it", host),
+            }
+        }
+    }
+    let mut servers: Vec<_> = ips.into_iter().map(IpNet::from).collect();
+    servers.sort();
+    servers.dedup();
+    servers
 }
 
 pub fn get_runner(
@@ -150,6 +322,35 @@ pub fn get_runner(
     let u = Url::parse(&device_id)
         .map_err(|x| Error::InvalidConfig(format!("tun device {}", x)))?;
 
+    let network = cfg.network.as_deref().unwrap_or("198.18.0.0/16");
+    let gateway = match cfg.gateway {
+        Some(gateway) => gateway,
+        None => network
+            .parse::<IpNet>()
+            .ok()
+            .and_then(|x| x.hosts().next())
+            .ok_or_else(|| {
+                Error::InvalidConfig(format!("invalid tun network: {}", network))
+            })?,
+    };
+
+    let mut include = routes::parse("route-address", &cfg.route_address)?;
+    let exclude =
+        routes::parse("route-exclude-address", &cfg.route_exclude_address)?;
+    if include.is_empty() && !exclude.is_empty() {
+        include = routes::default_routes(gateway.is_ipv6());
+        debug!("tun route-exclude-address alone, routing {:?}", include);
+    }
+    let routes = routes::subtract(&include, &exclude);
+    let (icmp, icmp_rx) = icmp::Icmp::new(gateway, cfg.icmp_relay);
+    let icmp = Arc::new(icmp);
+    let dns_hijack = DnsHijack::parse(&cfg.dns_hijack)?;
+
+    let gso = cfg.gso && cfg!(target_os = "linux") && u.scheme() == "dev";
+    if cfg.gso && !gso {
+        warn!("tun gso is only supported for dev:// devices on Linux");
+    }
+
     let mut tun_cfg = tun::Configuration::default();
 
     match u.scheme() {
@@ -161,6 +362,9 @@ pub fn get_runner(
                 .parse()
                 .map_err(|x| Error::InvalidConfig(format!("tun fd {}", x)))?;
             tun_cfg.raw_fd(fd);
+            if !routes.is_empty() {
+                warn!("tun routes are ignored for a device passed by fd");
+            }
         }
         "dev" => {
             let dev = u.host().expect("tun dev must be provided").to_string();
@@ -174,66 +378,99 @@ pub fn get_runner(
         }
     }
 
+    if let Some(mtu) = cfg.mtu {
+        tun_cfg.mtu(mtu as i32);
+    }
     tun_cfg.up();
 
-    let tun = tun::create_as_async(&tun_cfg)
-        .map_err(|x| new_io_error(&format!("failed to create tun device: {}", x)))?;
+    let failed = |x: &dyn std::fmt::Display| {
+        new_io_error(&format!("failed to create tun device: {}", x))
+    };
+    let device = match u.host() {
+        #[cfg(target_os = "linux")]
+        Some(dev) if gso => Device::Offload(
+            offload::Device::create(&dev.to_string(), cfg.mtu.unwrap_or(1500))
+                .map_err(|x| failed(&x))?,
+        ),
+        _ => Device::Tun(tun::create_as_async(&tun_cfg).map_err(|x| failed(&x))?),
+    };
 
-    let tun_name = tun.get_ref().name().map_err(map_io_error)?;
+    let tun_name = match &device {
+        Device::Tun(tun) => tun.get_ref().name().map_err(map_io_error)?,
+        #[cfg(target_os = "linux")]
+        Device::Offload(dev) => dev.name().to_owned(),
+    };
     info!("tun started at {}", tun_name);
 
+    let routes = (u.scheme() == "dev" && !routes.is_empty()).then_some(routes);
+
     let (stack, mut tcp_listener, udp_socket) =
         netstack::NetStack::with_buffer_size(512, 256).map_err(map_io_error)?;
 
     Ok(Some(Box::pin(async move {
-        let framed = tun.into_framed();
-
-        let (mut tun_sink, mut tun_stream) = framed.split();
-        let (mut stack_sink, mut stack_stream) = stack.split();
-
-        let mut futs: Vec<Runner> = vec![];
-
-        // dispatcher -> stack -> tun
-        futs.push(Box::pin(async move {
-            while let Some(pkt) = stack_stream.next().await {
-                match pkt {
-                    Ok(pkt) => {
-                        if let Err(e) = tun_sink.send(TunPacket::new(pkt)).await {
-                            error!("failed to send pkt to tun: {}", e);
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        error!("tun stack error: {}", e);
-                        break;
-                    }
-                }
+        let (stack_sink, stack_stream) = stack.split();
+        // ICMP is answered before the stack sees it
+        let udp_icmp = icmp.clone();
+        let stack_sink = stack_sink.with_flat_map(move |pkt: Vec<u8>| {
+            futures::stream::iter((!icmp.intercept(&pkt)).then_some(Ok(pkt)))
+        });
+        let stack_stream = futures::stream::select(
+            stack_stream,
+            ReceiverStream::new(icmp_rx).map(Ok),
+        );
+
+        let mut futs: Vec<Runner> = match device {
+            Device::Tun(tun) => forward(tun, stack_sink, stack_stream),
+            #[cfg(target_os = "linux")]
+            Device::Offload(dev) => {
+                let dev = Arc::new(dev);
+                let dev2 = dev.clone();
+                let mut futs: Vec<Runner> = vec![];
+                // dispatcher -> stack -> tun
+                futs.push(Box::pin(async move {
+                    dev.from_stack(stack_stream).await;
+                    Err(Error::Operation("tun stopped unexpectedly 0".to_string()))
+                }));
+                // tun -> stack -> dispatcher
+                futs.push(Box::pin(async move {
+                    dev2.to_stack(stack_sink).await;
+                    Err(Error::Operation("tun stopped unexpectedly 1".to_string()))
+                }));
+                futs
             }
-
-            Err(Error::Operation("tun stopped unexpectedly 0".to_string()))
-        }));
-
-        // tun -> stack -> dispatcher
-        futs.push(Box::pin(async move {
-            while let Some(pkt) = tun_stream.next().await {
-                match pkt {
-                    Ok(pkt) => {
-                        if let Err(e) =
-                            stack_sink.send(pkt.into_bytes().into()).await
-                        {
-                            error!("failed to send pkt to stack: {}", e);
-                            break;
-                        }
+        };
+
+        // removed once the tun stops
+        if let Some(routes) = routes {
+            let mut installed = routes::Routes::install(&tun_name, routes).await?;
+            let dsp = dispatcher.clone();
+            let resolver = resolver.clone();
+            futs.push(Box::pin(async move {
+                let mut bypassed = vec![];
+                let mut refresh = tokio::time::interval(BYPASS_REFRESH);
+                loop {
+                    refresh.tick().await;
+                    let servers = proxy_servers(&dsp, &resolver).await;
+                    if servers == bypassed {
+                        continue;
                     }
-                    Err(e) => {
-                        error!("tun stream error: {}", e);
-                        break;
+                    let mut exclude = exclude.clone();
+                    exclude.extend(servers.iter());
+                    match installed
+                        .update(routes::subtract(&include, &exclude))
+                        .await
+                    {
+                        Ok(_) => {
+                            debug!("proxy servers routed around tun: {:?}", servers);
+                            bypassed = servers;
+                        }
+                        Err(e) => {
+                            warn!("failed to route proxy servers around tun: {}", e)
+                        }
                     }
                 }
-            }
-
-            Err(Error::Operation("tun stopped unexpectedly 1".to_string()))
-        }));
+            }));
+        }
 
         let dsp = dispatcher.clone();
         futs.push(Box::pin(async move {
@@ -254,7 +491,10 @@ pub fn get_runner(
         }));
 
         futs.push(Box::pin(async move {
-            handle_inbound_datagram(udp_socket, dispatcher, resolver).await;
+            handle_inbound_datagram(
+                udp_socket, dispatcher, resolver, dns_hijack, udp_icmp,
+            )
+            .await;
             Err(Error::Operation("tun stopped unexpectedly 3".to_string()))
         }));
 
@@ -1,4 +1,11 @@
 pub mod inbound;
 pub use netstack_lwip as netstack;
 mod datagram;
+mod dns_hijack;
+mod icmp;
+#[cfg(target_os = "linux")]
+mod offload;
+mod routes;
 pub use inbound::get_runner as get_tun_runner;
+
+impl crate::proxy::AsTcpStream for netstack::TcpStream {}
@@ -0,0 +1,586 @@
+//! A Linux tun device with virtio-net headers, so the kernel can hand over
+//! and take TCP segments coalesced up to 64KiB. What's read is split back
+//! into MTU-sized packets for the stack, which saves the syscalls rather
+//! than the stack's work, and the segments the stack sends out in a burst
+//! are coalesced before they're written.
+
+use std::{
+    ffi::CString,
+    fmt::Display,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use tokio::io::unix::AsyncFd;
+use tracing::{debug, error};
+
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const TUNSETOFFLOAD: libc::c_ulong = 0x400454d0;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+const TUN_F_CSUM: libc::c_ulong = 0x01;
+const TUN_F_TSO4: libc::c_ulong = 0x02;
+const TUN_F_TSO6: libc::c_ulong = 0x04;
+
+/// `struct virtio_net_hdr`
+const VNET_HDR_LEN: usize = 10;
+const VNET_F_NEEDS_CSUM: u8 = 1;
+const GSO_NONE: u8 = 0;
+const GSO_TCPV4: u8 = 1;
+const GSO_TCPV6: u8 = 4;
+const GSO_ECN: u8 = 0x80;
+
+/// the packets taken from the stack at once to be coalesced
+const MAX_BATCH: usize = 64;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const TCP_CWR: u8 = 0x80;
+
+/// `struct ifreq`, the name and the union as raw bytes
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    data: [u8; 24],
+}
+
+impl IfReq {
+    fn new(name: &str) -> io::Result<Self> {
+        let name = CString::new(name)?;
+        let name = name.as_bytes_with_nul();
+        if name.len() > libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tun name too long",
+            ));
+        }
+        let mut req = Self {
+            name: [0; libc::IFNAMSIZ],
+            data: [0; 24],
+        };
+        req.name[..name.len()].copy_from_slice(name);
+        Ok(req)
+    }
+
+    fn name(&self) -> String {
+        let len = self.name.iter().position(|x| *x == 0).unwrap_or(0);
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+
+    fn set_flags(&mut self, flags: libc::c_short) {
+        self.data[..2].copy_from_slice(&flags.to_ne_bytes());
+    }
+
+    fn flags(&self) -> libc::c_short {
+        libc::c_short::from_ne_bytes([self.data[0], self.data[1]])
+    }
+
+    fn set_mtu(&mut self, mtu: libc::c_int) {
+        self.data[..4].copy_from_slice(&mtu.to_ne_bytes());
+    }
+}
+
+fn ioctl(
+    fd: &OwnedFd,
+    request: libc::c_ulong,
+    arg: *mut libc::c_void,
+) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd.as_raw_fd(), request as _, arg) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub struct Device {
+    fd: AsyncFd<OwnedFd>,
+    name: String,
+}
+
+impl Device {
+    /// Create the tun `name` with the offloads on, and bring it up.
+    pub fn create(name: &str, mtu: u16) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::open(
+                b"/dev/net/tun\0".as_ptr() as *const libc::c_char,
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut req = IfReq::new(name)?;
+        req.set_flags(IFF_TUN | IFF_NO_PI | IFF_VNET_HDR);
+        ioctl(&fd, TUNSETIFF, &mut req as *mut IfReq as _)?;
+        // passed by value
+        let offload = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6;
+        ioctl(&fd, TUNSETOFFLOAD, offload as *mut libc::c_void)?;
+        // the kernel picks the name for a pattern like tun%d
+        let name = req.name();
+
+        let sock = unsafe {
+            libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0)
+        };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = unsafe { OwnedFd::from_raw_fd(sock) };
+
+        let mut req = IfReq::new(&name)?;
+        req.set_mtu(mtu as libc::c_int);
+        ioctl(&sock, libc::SIOCSIFMTU as _, &mut req as *mut IfReq as _)?;
+
+        let mut req = IfReq::new(&name)?;
+        ioctl(&sock, libc::SIOCGIFFLAGS as _, &mut req as *mut IfReq as _)?;
+        let flags =
+            req.flags() | (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        req.set_flags(flags);
+        ioctl(&sock, libc::SIOCSIFFLAGS as _, &mut req as *mut IfReq as _)?;
+
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            name,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let read = guard.try_io(|fd| {
+                let n = unsafe {
+                    libc::read(fd.as_raw_fd(), buf.as_mut_ptr() as _, buf.len())
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            if let Ok(read) = read {
+                return read;
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<()> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            let written = guard.try_io(|fd| {
+                let n = unsafe {
+                    libc::write(fd.as_raw_fd(), buf.as_ptr() as _, buf.len())
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+            if let Ok(written) = written {
+                return written;
+            }
+        }
+    }
+
+    /// tun -> stack, until either fails
+    pub async fn to_stack<S>(&self, mut stack: S)
+    where
+        S: Sink<Vec<u8>> + Unpin,
+        S::Error: Display,
+    {
+        let mut buf = vec![0; VNET_HDR_LEN + u16::MAX as usize];
+        let mut segments = vec![];
+        loop {
+            let n = match self.recv(&mut buf).await {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("tun stream error: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = split(&mut buf[..n], &mut segments) {
+                debug!("dropping a packet from tun: {}", e);
+                continue;
+            }
+            for pkt in segments.drain(..) {
+                if let Err(e) = stack.send(pkt).await {
+                    error!("failed to send pkt to stack: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// stack -> tun, until either fails
+    pub async fn from_stack<S>(&self, mut stack: S)
+    where
+        S: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+    {
+        let mut batch = vec![];
+        let mut done = false;
+        while !done {
+            match stack.next().await {
+                Some(Ok(pkt)) => batch.push(pkt),
+                Some(Err(e)) => {
+                    error!("tun stack error: {}", e);
+                    return;
+                }
+                None => return,
+            }
+            // whatever else is ready goes out with it
+            while batch.len() < MAX_BATCH {
+                match stack.next().now_or_never() {
+                    Some(Some(Ok(pkt))) => batch.push(pkt),
+                    Some(Some(Err(e))) => {
+                        error!("tun stack error: {}", e);
+                        done = true;
+                        break;
+                    }
+                    Some(None) => {
+                        done = true;
+                        break;
+                    }
+                    None => break,
+                }
+            }
+
+            for pkt in coalesce(batch.drain(..)) {
+                if let Err(e) = self.send(&pkt).await {
+                    error!("failed to send pkt to tun: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The IP and TCP header lengths of a TCP packet without IPv6 extension
+/// headers.
+fn tcp_headers(pkt: &[u8]) -> Option<(usize, usize)> {
+    let ip_len = match pkt.first()? >> 4 {
+        4 if pkt.len() >= 20 && pkt[9] == libc::IPPROTO_TCP as u8 => {
+            (pkt[0] & 0x0f) as usize * 4
+        }
+        6 if pkt.len() >= 40 && pkt[6] == libc::IPPROTO_TCP as u8 => 40,
+        _ => return None,
+    };
+    let tcp_len = (*pkt.get(ip_len + 12)? >> 4) as usize * 4;
+    (tcp_len >= 20 && pkt.len() >= ip_len + tcp_len).then_some((ip_len, tcp_len))
+}
+
+fn sum(data: &[u8], mut acc: u64) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    for x in &mut chunks {
+        acc += u16::from_be_bytes([x[0], x[1]]) as u64;
+    }
+    if let [x] = chunks.remainder() {
+        acc += (*x as u64) << 8;
+    }
+    acc
+}
+
+fn fold(mut acc: u64) -> u16 {
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    acc as u16
+}
+
+/// The pseudo header sum of the TCP segment of `pkt` that's `len` long.
+fn pseudo_header(pkt: &[u8], len: usize) -> u64 {
+    let addrs = if pkt[0] >> 4 == 4 {
+        &pkt[12..20]
+    } else {
+        &pkt[8..40]
+    };
+    sum(addrs, libc::IPPROTO_TCP as u64 + len as u64)
+}
+
+fn fix_ip_checksum(pkt: &mut [u8], ip_len: usize) {
+    if pkt[0] >> 4 == 4 {
+        pkt[10..12].fill(0);
+        let csum = !fold(sum(&pkt[..ip_len], 0));
+        pkt[10..12].copy_from_slice(&csum.to_be_bytes());
+    }
+}
+
+/// Fill in the IPv4 header and TCP checksums of `pkt`.
+fn fix_checksums(pkt: &mut [u8], ip_len: usize) {
+    fix_ip_checksum(pkt, ip_len);
+    let tcp = ip_len + 16;
+    pkt[tcp..tcp + 2].fill(0);
+    let csum = !fold(sum(&pkt[ip_len..], pseudo_header(pkt, pkt.len() - ip_len)));
+    pkt[tcp..tcp + 2].copy_from_slice(&csum.to_be_bytes());
+}
+
+/// Set the length of the IP header of `pkt` to its own.
+fn set_ip_len(pkt: &mut [u8]) {
+    if pkt[0] >> 4 == 4 {
+        let len = pkt.len() as u16;
+        pkt[2..4].copy_from_slice(&len.to_be_bytes());
+    } else {
+        let len = (pkt.len() - 40) as u16;
+        pkt[4..6].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Split a packet read from the device, virtio-net header first, into the
+/// packets it stands for, with their checksums filled in.
+fn split(buf: &mut [u8], out: &mut Vec<Vec<u8>>) -> Result<(), &'static str> {
+    if buf.len() <= VNET_HDR_LEN {
+        return Err("too short");
+    }
+    let (hdr, pkt) = buf.split_at_mut(VNET_HDR_LEN);
+    let flags = hdr[0];
+    let gso_type = hdr[1] & !GSO_ECN;
+    let gso_size = u16::from_le_bytes([hdr[4], hdr[5]]) as usize;
+    let csum_start = u16::from_le_bytes([hdr[6], hdr[7]]) as usize;
+    let csum_offset = u16::from_le_bytes([hdr[8], hdr[9]]) as usize;
+
+    if gso_type == GSO_NONE {
+        if flags & VNET_F_NEEDS_CSUM != 0 {
+            // the field holds the pseudo header sum already
+            let at = csum_start + csum_offset;
+            if at + 2 > pkt.len() {
+                return Err("invalid checksum offset");
+            }
+            let csum = !fold(sum(&pkt[csum_start..], 0));
+            pkt[at..at + 2].copy_from_slice(&csum.to_be_bytes());
+        }
+        out.push(pkt.to_vec());
+        return Ok(());
+    }
+    if gso_type != GSO_TCPV4 && gso_type != GSO_TCPV6 {
+        return Err("unexpected gso type");
+    }
+
+    let (ip_len, tcp_len) = tcp_headers(pkt).ok_or("not a TCP packet")?;
+    let hdr_len = ip_len + tcp_len;
+    if gso_size == 0 {
+        return Err("invalid gso size");
+    }
+    let seq = u32::from_be_bytes(pkt[ip_len + 4..ip_len + 8].try_into().unwrap());
+    let id = u16::from_be_bytes([pkt[4], pkt[5]]);
+    let payload = &pkt[hdr_len..];
+    let count = payload.len().div_ceil(gso_size);
+
+    for (i, chunk) in payload.chunks(gso_size).enumerate() {
+        let mut seg = Vec::with_capacity(hdr_len + chunk.len());
+        seg.extend_from_slice(&pkt[..hdr_len]);
+        seg.extend_from_slice(chunk);
+
+        set_ip_len(&mut seg);
+        if seg[0] >> 4 == 4 {
+            seg[4..6].copy_from_slice(&id.wrapping_add(i as u16).to_be_bytes());
+        }
+        let seq = seq.wrapping_add((i * gso_size) as u32);
+        seg[ip_len + 4..ip_len + 8].copy_from_slice(&seq.to_be_bytes());
+        if i + 1 < count {
+            seg[ip_len + 13] &= !(TCP_FIN | TCP_PSH);
+        }
+        if i > 0 {
+            seg[ip_len + 13] &= !TCP_CWR;
+        }
+        fix_checksums(&mut seg, ip_len);
+        out.push(seg);
+    }
+    Ok(())
+}
+
+/// A TCP packet the ones after it may be appended to.
+struct Coalescing {
+    pkt: Vec<u8>,
+    ip_len: usize,
+    hdr_len: usize,
+    mss: usize,
+    segments: usize,
+    next_seq: u32,
+}
+
+impl Coalescing {
+    fn new(pkt: Vec<u8>) -> Result<Self, Vec<u8>> {
+        let Some((ip_len, tcp_len)) = tcp_headers(&pkt) else {
+            return Err(pkt);
+        };
+        let hdr_len = ip_len + tcp_len;
+        let mss = pkt.len() - hdr_len;
+        if mss == 0 || pkt[ip_len + 13] != TCP_ACK {
+            return Err(pkt);
+        }
+        let seq =
+            u32::from_be_bytes(pkt[ip_len + 4..ip_len + 8].try_into().unwrap());
+        Ok(Self {
+            pkt,
+            ip_len,
+            hdr_len,
+            mss,
+            segments: 1,
+            next_seq: seq.wrapping_add(mss as u32),
+        })
+    }
+
+    /// Append `pkt` if it's the next segment of the same flow.
+    fn append(&mut self, pkt: &[u8]) -> bool {
+        let (ip_len, hdr_len) = (self.ip_len, self.hdr_len);
+        let cur = &self.pkt;
+        // only the last segment may be short or carry PSH
+        if cur.len() - hdr_len != self.segments * self.mss
+            || cur[ip_len + 13] != TCP_ACK
+            || pkt.len() <= hdr_len
+            || pkt.len() - hdr_len > self.mss
+            || cur.len() + pkt.len() - hdr_len > u16::MAX as usize
+            || tcp_headers(pkt) != Some((ip_len, hdr_len - ip_len))
+            || pkt[ip_len + 13] & !TCP_PSH != TCP_ACK
+        {
+            return false;
+        }
+        let seq =
+            u32::from_be_bytes(pkt[ip_len + 4..ip_len + 8].try_into().unwrap());
+        if seq != self.next_seq {
+            return false;
+        }
+        // the headers match but for the lengths, IDs, sequence numbers,
+        // flags and checksums
+        let same = if cur[0] >> 4 == 4 {
+            cur[..2] == pkt[..2]
+                && cur[6..10] == pkt[6..10]
+                && cur[12..ip_len] == pkt[12..ip_len]
+        } else {
+            cur[..4] == pkt[..4] && cur[6..40] == pkt[6..40]
+        };
+        let tcp = ip_len;
+        if !same
+            || cur[tcp..tcp + 4] != pkt[tcp..tcp + 4]
+            || cur[tcp + 8..tcp + 13] != pkt[tcp + 8..tcp + 13]
+            || cur[tcp + 14..tcp + 16] != pkt[tcp + 14..tcp + 16]
+            || cur[tcp + 18..hdr_len] != pkt[tcp + 18..hdr_len]
+        {
+            return false;
+        }
+
+        self.pkt[ip_len + 13] = pkt[ip_len + 13];
+        self.pkt.extend_from_slice(&pkt[hdr_len..]);
+        self.next_seq = seq.wrapping_add((pkt.len() - hdr_len) as u32);
+        self.segments += 1;
+        true
+    }
+
+    /// The packet with its virtio-net header, coalesced ones asking the
+    /// kernel to split them and fill in the TCP checksums.
+    fn finish(self) -> Vec<u8> {
+        let mut hdr = [0; VNET_HDR_LEN];
+        let mut pkt = self.pkt;
+        if self.segments > 1 {
+            set_ip_len(&mut pkt);
+            fix_ip_checksum(&mut pkt, self.ip_len);
+            let tcp = self.ip_len + 16;
+            let csum = fold(pseudo_header(&pkt, pkt.len() - self.ip_len));
+            pkt[tcp..tcp + 2].copy_from_slice(&csum.to_be_bytes());
+
+            hdr[0] = VNET_F_NEEDS_CSUM;
+            hdr[1] = if pkt[0] >> 4 == 4 {
+                GSO_TCPV4
+            } else {
+                GSO_TCPV6
+            };
+            hdr[2..4].copy_from_slice(&(self.hdr_len as u16).to_le_bytes());
+            hdr[4..6].copy_from_slice(&(self.mss as u16).to_le_bytes());
+            hdr[6..8].copy_from_slice(&(self.ip_len as u16).to_le_bytes());
+            hdr[8..10].copy_from_slice(&16u16.to_le_bytes());
+        }
+        let mut out = Vec::with_capacity(VNET_HDR_LEN + pkt.len());
+        out.extend_from_slice(&hdr);
+        out.extend_from_slice(&pkt);
+        out
+    }
+}
+
+/// The packets from the stack, consecutive segments of a TCP flow
+/// coalesced, each with its virtio-net header.
+fn coalesce(pkts: impl Iterator<Item = Vec<u8>>) -> Vec<Vec<u8>> {
+    let mut out = vec![];
+    let mut cur: Option<Coalescing> = None;
+    for pkt in pkts {
+        if cur.as_mut().is_some_and(|x| x.append(&pkt)) {
+            continue;
+        }
+        if let Some(cur) = cur.take() {
+            out.push(cur.finish());
+        }
+        match Coalescing::new(pkt) {
+            Ok(x) => cur = Some(x),
+            Err(pkt) => {
+                let mut plain = vec![0; VNET_HDR_LEN];
+                plain.extend_from_slice(&pkt);
+                out.push(plain);
+            }
+        }
+    }
+    if let Some(cur) = cur {
+        out.push(cur.finish());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coalesce, fix_checksums, fold, split, sum, TCP_ACK, TCP_PSH};
+
+    /// an IPv4 TCP segment with `len` bytes of payload
+    fn segment(id: u16, seq: u32, len: usize, flags: u8) -> Vec<u8> {
+        let mut pkt = vec![
+            0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        pkt[2..4].copy_from_slice(&((40 + len) as u16).to_be_bytes());
+        pkt[4..6].copy_from_slice(&id.to_be_bytes());
+        let mut tcp = vec![0x1f, 0x90, 0xc3, 0x50];
+        tcp.extend(seq.to_be_bytes());
+        tcp.extend([0, 0, 0, 1, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        pkt.extend(tcp);
+        pkt.extend((0..len).map(|x| x as u8));
+        fix_checksums(&mut pkt, 20);
+        pkt
+    }
+
+    #[test]
+    fn test_coalesce_and_split() {
+        let segments = vec![
+            segment(1, 1000, 100, TCP_ACK),
+            segment(2, 1100, 100, TCP_ACK),
+            segment(3, 1200, 50, TCP_ACK | TCP_PSH),
+        ];
+        let mut coalesced = coalesce(segments.clone().into_iter());
+        assert_eq!(coalesced.len(), 1);
+        assert_eq!(coalesced[0].len(), 10 + 40 + 250);
+
+        // the kernel sees it as one packet to split the way we do
+        let mut split_back = vec![];
+        split(&mut coalesced[0], &mut split_back).unwrap();
+        assert_eq!(split_back, segments);
+
+        // out of order segments go out alone
+        let mut segments = segments;
+        segments.swap(0, 1);
+        assert_eq!(coalesce(segments.into_iter()).len(), 3);
+    }
+
+    #[test]
+    fn test_split_needs_csum() {
+        let pkt = segment(1, 1000, 33, TCP_ACK);
+        let mut partial = pkt.clone();
+        // the pseudo header sum the kernel leaves in the field
+        let csum = fold(sum(&partial[12..20], 6 + 53));
+        partial[36..38].copy_from_slice(&csum.to_be_bytes());
+
+        let mut buf = vec![1, 0, 0, 0, 0, 0, 20, 0, 16, 0];
+        buf.extend(partial);
+        let mut out = vec![];
+        split(&mut buf, &mut out).unwrap();
+        assert_eq!(out, vec![pkt]);
+    }
+}
@@ -0,0 +1,343 @@
+//! The routes sending `route-address` into the tun device, less
+//! `route-exclude-address` and the proxy servers, whose traffic would loop
+//! back into the tun otherwise. They're installed with the system's route
+//! command once the device is up and removed when the tun stops.
+
+use ipnet::IpNet;
+use tracing::{debug, warn};
+
+use crate::Error;
+
+/// Parse the prefixes of `route-address` or `route-exclude-address`, a bare
+/// address being a single host.
+pub fn parse(field: &str, addrs: &[String]) -> Result<Vec<IpNet>, Error> {
+    addrs
+        .iter()
+        .map(|x| {
+            x.parse::<IpNet>()
+                .or_else(|_| x.parse::<std::net::IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    Error::InvalidConfig(format!("invalid tun {}: {}", field, x))
+                })
+        })
+        .collect()
+}
+
+/// The default route of the tun's family, as two halves so they're preferred
+/// to the system's own default route without replacing it. Used when only
+/// `route-exclude-address` is set.
+pub fn default_routes(v6: bool) -> Vec<IpNet> {
+    let halves: [&str; 2] = if v6 {
+        ["::/1", "8000::/1"]
+    } else {
+        ["0.0.0.0/1", "128.0.0.0/1"]
+    };
+    halves.iter().map(|x| x.parse().unwrap()).collect()
+}
+
+/// `include` with the `exclude` prefixes carved out, so what's excluded
+/// keeps going through the system's other routes. A whole default route is
+/// kept as its two halves.
+pub fn subtract(include: &[IpNet], exclude: &[IpNet]) -> Vec<IpNet> {
+    fn carve(net: IpNet, exclude: &[IpNet], out: &mut Vec<IpNet>) {
+        if exclude.iter().any(|x| x.contains(&net)) {
+            return;
+        }
+        if !exclude.iter().any(|x| net.contains(x)) {
+            out.push(net);
+            return;
+        }
+        // an excluded prefix is inside, so `net` isn't a host route
+        for half in net
+            .subnets(net.prefix_len() + 1)
+            .expect("prefix is not the longest")
+        {
+            carve(half, exclude, out);
+        }
+    }
+
+    let mut out = vec![];
+    for net in include {
+        carve(net.trunc(), exclude, &mut out);
+    }
+    IpNet::aggregate(&out)
+        .into_iter()
+        .flat_map(|x| match x.prefix_len() {
+            0 => x.subnets(1).expect("prefix is not the longest").collect(),
+            _ => vec![x],
+        })
+        .collect()
+}
+
+/// The installed routes, removed on drop.
+pub struct Routes {
+    device: String,
+    routes: Vec<IpNet>,
+}
+
+impl Routes {
+    pub async fn install(device: &str, routes: Vec<IpNet>) -> Result<Self, Error> {
+        let mut installed = Self {
+            device: device.to_owned(),
+            routes: vec![],
+        };
+        // what's installed so far is removed by the drop
+        installed.update(routes).await?;
+        Ok(installed)
+    }
+
+    /// Replace the installed routes with `routes`, adding the new ones
+    /// before removing the old ones so nothing falls out of the tun in
+    /// between. The commands run in one batch off the runtime.
+    pub async fn update(&mut self, routes: Vec<IpNet>) -> Result<(), Error> {
+        let changes = routes
+            .iter()
+            .filter(|x| !self.routes.contains(x))
+            .map(|x| (true, *x))
+            .chain(
+                self.routes
+                    .iter()
+                    .filter(|x| !routes.contains(x))
+                    .map(|x| (false, *x)),
+            )
+            .collect::<Vec<_>>();
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let device = self.device.clone();
+        let (changes, result) = tokio::task::spawn_blocking(move || {
+            let result = route_commands(&device, &changes);
+            (changes, result)
+        })
+        .await
+        .map_err(|e| Error::Operation(e.to_string()))?;
+
+        let failed = match &result {
+            Ok(_) => &[][..],
+            Err((failed, _)) => failed.as_slice(),
+        };
+        for (i, (add, route)) in changes.iter().enumerate() {
+            // failed removals are kept to be removed on drop
+            if failed.contains(&i) {
+                continue;
+            }
+            if *add {
+                debug!("route {} added to {}", route, self.device);
+                self.routes.push(*route);
+            } else {
+                debug!("route {} removed from {}", route, self.device);
+                self.routes.retain(|x| x != route);
+            }
+        }
+        result.map_err(|(_, e)| e)
+    }
+}
+
+impl Drop for Routes {
+    fn drop(&mut self) {
+        let changes = self.routes.iter().map(|x| (false, *x)).collect::<Vec<_>>();
+        if changes.is_empty() {
+            return;
+        }
+        match route_commands(&self.device, &changes) {
+            Ok(_) => debug!("routes removed from {}", self.device),
+            Err((_, e)) => warn!("failed to remove routes: {}", e),
+        }
+    }
+}
+
+/// Make the route `changes`, `true` adding, in order, with one process
+/// where the system allows. Those that failed are returned by index.
+fn route_commands(
+    device: &str,
+    changes: &[(bool, IpNet)],
+) -> Result<(), (Vec<usize>, Error)> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::{
+            fmt::Write as _,
+            io::Write as _,
+            process::{Command, Stdio},
+        };
+
+        let all = || (0..changes.len()).collect::<Vec<_>>();
+        let mut script = String::new();
+        for (add, route) in changes {
+            let _ = writeln!(
+                script,
+                "route {} {} dev {}",
+                if *add { "add" } else { "del" },
+                route,
+                device
+            );
+        }
+        // `-force` carries on past the commands that fail
+        let mut child = Command::new("ip")
+            .args(["-force", "-batch", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| (all(), e.into()))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(script.as_bytes())
+                .map_err(|e| (all(), e.into()))?;
+        }
+        let output = child.wait_with_output().map_err(|e| (all(), e.into()))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let failed = match failed_lines(&stderr) {
+            x if x.is_empty() => all(),
+            x => x,
+        };
+        Err((
+            failed,
+            Error::Operation(format!("ip route: {}", stderr.trim())),
+        ))
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let mut failed = vec![];
+        let mut error = None;
+        for (i, (add, route)) in changes.iter().enumerate() {
+            let route_s = route.to_string();
+            let mut cmd = std::process::Command::new("route");
+            cmd.args(["-n", if *add { "add" } else { "delete" }]);
+            match route {
+                IpNet::V4(_) => cmd.args(["-net", &route_s]),
+                IpNet::V6(_) => cmd.args(["-inet6", &route_s]),
+            };
+            cmd.args(["-interface", device]);
+            match cmd.output() {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => {
+                    failed.push(i);
+                    error = Some(Error::Operation(format!(
+                        "{:?}: {}",
+                        cmd,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )));
+                }
+                Err(e) => {
+                    failed.push(i);
+                    error = Some(e.into());
+                }
+            }
+        }
+        match error {
+            None => Ok(()),
+            Some(e) => Err((failed, e)),
+        }
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos"
+    )))]
+    {
+        let _ = device;
+        Err((
+            (0..changes.len()).collect(),
+            Error::Operation(format!(
+                "tun routes are not supported on {}",
+                std::env::consts::OS
+            )),
+        ))
+    }
+}
+
+/// The indexes of the commands `ip -batch` reports as failed, from lines
+/// like `Command failed -:3`.
+#[cfg(any(target_os = "linux", target_os = "android", test))]
+fn failed_lines(stderr: &str) -> Vec<usize> {
+    stderr
+        .lines()
+        .filter_map(|x| x.trim().strip_prefix("Command failed -:")?.parse().ok())
+        .filter_map(|x: usize| x.checked_sub(1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ipnet::IpNet;
+
+    use super::{default_routes, failed_lines, parse, subtract};
+
+    fn nets(s: &[&str]) -> Vec<IpNet> {
+        s.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = parse(
+            "route-address",
+            &[
+                "10.0.0.0/8".to_owned(),
+                "1.1.1.1".to_owned(),
+                "::/1".to_owned(),
+            ],
+        )
+        .unwrap();
+        assert_eq!(parsed, nets(&["10.0.0.0/8", "1.1.1.1/32", "::/1"]));
+        assert!(parse("route-address", &["10.0.0.0/33".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_subtract() {
+        assert_eq!(
+            subtract(&nets(&["10.0.0.0/8"]), &nets(&["10.128.0.0/9"])),
+            nets(&["10.0.0.0/9"])
+        );
+        // not replacing the system's default route
+        assert_eq!(
+            subtract(&default_routes(false), &[]),
+            nets(&["0.0.0.0/1", "128.0.0.0/1"])
+        );
+        assert_eq!(
+            subtract(&nets(&["::/0"]), &nets(&["10.0.0.0/8"])),
+            default_routes(true)
+        );
+        assert_eq!(
+            subtract(&nets(&["0.0.0.0/0"]), &nets(&["192.168.0.0/16"])),
+            nets(&[
+                "0.0.0.0/1",
+                "128.0.0.0/2",
+                "192.0.0.0/9",
+                "192.128.0.0/11",
+                "192.160.0.0/13",
+                "192.169.0.0/16",
+                "192.170.0.0/15",
+                "192.172.0.0/14",
+                "192.176.0.0/12",
+                "192.192.0.0/10",
+                "193.0.0.0/8",
+                "194.0.0.0/7",
+                "196.0.0.0/6",
+                "200.0.0.0/5",
+                "208.0.0.0/4",
+                "224.0.0.0/3",
+            ])
+        );
+        // wholly excluded, or of the other family
+        let excluded = subtract(&nets(&["10.1.0.0/16"]), &nets(&["10.0.0.0/8"]));
+        assert!(excluded.is_empty());
+        assert_eq!(
+            subtract(&nets(&["2000::/3"]), &nets(&["10.0.0.0/8"])),
+            nets(&["2000::/3"])
+        );
+    }
+
+    #[test]
+    fn test_failed_lines() {
+        let stderr = "RTNETLINK answers: File exists\nCommand failed -:2\n\
+                      RTNETLINK answers: No such process\nCommand failed -:5\n";
+        assert_eq!(failed_lines(stderr), vec![1, 4]);
+        assert!(failed_lines("").is_empty());
+    }
+}
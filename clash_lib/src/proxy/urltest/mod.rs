@@ -17,7 +17,10 @@ use crate::{
 };
 
 use super::{
-    utils::{provider_helper::get_proxies_from_providers, RemoteConnector},
+    utils::{
+        provider_helper::get_proxies_from_providers, retry::dial_with_retry,
+        RemoteConnector,
+    },
     AnyOutboundHandler, ConnectorType, OutboundHandler, OutboundType,
 };
 
@@ -82,7 +85,7 @@ impl Handler {
                 fast_not_exist = false;
             }
 
-            if !proxy_manager.alive(proxy.name()).await {
+            if !proxy_manager.available(proxy.name()).await {
                 continue;
             }
 
@@ -94,7 +97,7 @@ impl Handler {
 
             if inner.fastest_proxy.is_some()
                 || fast_not_exist
-                || proxy_manager.alive(fastest.name()).await
+                || proxy_manager.available(fastest.name()).await
                 || proxy_manager
                     .last_delay(inner.fastest_proxy.as_ref().unwrap().name())
                     .await
@@ -142,11 +145,17 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let s = self
-            .fastest(false)
-            .await
-            .connect_stream(sess, resolver)
-            .await?;
+        let s = dial_with_retry(
+            self.name(),
+            self.fastest(false).await,
+            self.get_proxies(false).await,
+            &self.proxy_manager,
+            |proxy| {
+                let resolver = resolver.clone();
+                async move { proxy.connect_stream(sess, resolver).await }
+            },
+        )
+        .await?;
         s.append_to_chain(self.name()).await;
         Ok(s)
     }
@@ -157,11 +166,17 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let d = self
-            .fastest(false)
-            .await
-            .connect_datagram(sess, resolver)
-            .await?;
+        let d = dial_with_retry(
+            self.name(),
+            self.fastest(false).await,
+            self.get_proxies(false).await,
+            &self.proxy_manager,
+            |proxy| {
+                let resolver = resolver.clone();
+                async move { proxy.connect_datagram(sess, resolver).await }
+            },
+        )
+        .await?;
         d.append_to_chain(self.name()).await;
         Ok(d)
     }
@@ -176,11 +191,21 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let s = self
-            .fastest(true)
-            .await
-            .connect_stream_with_connector(sess, resolver, connector)
-            .await?;
+        let s = dial_with_retry(
+            self.name(),
+            self.fastest(true).await,
+            self.get_proxies(false).await,
+            &self.proxy_manager,
+            |proxy| {
+                let resolver = resolver.clone();
+                async move {
+                    proxy
+                        .connect_stream_with_connector(sess, resolver, connector)
+                        .await
+                }
+            },
+        )
+        .await?;
 
         s.append_to_chain(self.name()).await;
         Ok(s)
@@ -192,10 +217,25 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedDatagram> {
-        self.fastest(true)
-            .await
-            .connect_datagram_with_connector(sess, resolver, connector)
-            .await
+        dial_with_retry(
+            self.name(),
+            self.fastest(true).await,
+            self.get_proxies(false).await,
+            &self.proxy_manager,
+            |proxy| {
+                let resolver = resolver.clone();
+                async move {
+                    proxy
+                        .connect_datagram_with_connector(sess, resolver, connector)
+                        .await
+                }
+            },
+        )
+        .await
+    }
+
+    async fn members(&self) -> Option<Vec<AnyOutboundHandler>> {
+        Some(get_proxies_from_providers(&self.providers, false).await)
     }
 
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
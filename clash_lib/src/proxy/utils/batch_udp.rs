@@ -0,0 +1,491 @@
+//! A UDP socket moving several datagrams per syscall: recvmmsg/sendmmsg with
+//! GRO and GSO where the kernel supports them on Linux, one datagram at a
+//! time elsewhere.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    task::{ready, Context, Poll},
+};
+
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+/// datagrams read or written per syscall
+pub const BATCH_SIZE: usize = 8;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+thread_local! {
+    /// one buffer per message of a batch, allocated on first read and
+    /// shared by the sockets read on the thread, the datagrams are copied
+    /// out before the read returns
+    static RECV_BUFS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+pub struct BatchUdpSocket {
+    inner: UdpSocket,
+    received: VecDeque<(Vec<u8>, SocketAddr)>,
+    send_queue: VecDeque<(Vec<u8>, SocketAddr)>,
+    /// whether UDP_SEGMENT may be used to send runs of equal sized datagrams
+    gso: bool,
+}
+
+impl BatchUdpSocket {
+    pub fn new(inner: UdpSocket) -> Self {
+        #[cfg(target_os = "linux")]
+        let gso = {
+            sys::enable_gro(&inner);
+            sys::gso_supported(&inner)
+        };
+        #[cfg(not(target_os = "linux"))]
+        let gso = false;
+
+        Self {
+            inner,
+            received: VecDeque::new(),
+            send_queue: VecDeque::new(),
+            gso,
+        }
+    }
+
+    pub fn poll_recv_from(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(Vec<u8>, SocketAddr)>> {
+        loop {
+            if let Some(x) = self.received.pop_front() {
+                return Poll::Ready(Ok(x));
+            }
+
+            ready!(self.inner.poll_recv_ready(cx))?;
+            let Self {
+                inner, received, ..
+            } = self;
+            match inner.try_io(tokio::io::Interest::READABLE, || {
+                RECV_BUFS.with(|bufs| {
+                    let mut bufs = bufs.borrow_mut();
+                    if bufs.is_empty() {
+                        *bufs = vec![vec![0; MAX_DATAGRAM_SIZE]; BATCH_SIZE];
+                    }
+                    recv_batch(inner, &mut bufs, received)
+                })
+            }) {
+                Ok(()) => {}
+                // readiness was cleared, the next poll registers the waker
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    /// Queue a datagram, it's sent by the next `poll_send`.
+    pub fn queue_send(&mut self, data: Vec<u8>, dst: SocketAddr) {
+        self.send_queue.push_back((data, dst));
+    }
+
+    pub fn queued(&self) -> usize {
+        self.send_queue.len()
+    }
+
+    /// Send all queued datagrams. A datagram that fails for good is dropped
+    /// and its error returned, one the kernel had no room for is sent again.
+    pub fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.send_queue.is_empty() {
+            ready!(self.inner.poll_send_ready(cx))?;
+            let Self {
+                inner,
+                send_queue,
+                gso,
+                ..
+            } = self;
+            match inner.try_io(tokio::io::Interest::WRITABLE, || {
+                send_batch(inner, send_queue, *gso)
+            }) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                #[cfg(target_os = "linux")]
+                Err(e)
+                    if self.gso
+                        && matches!(
+                            e.raw_os_error(),
+                            Some(libc::EIO | libc::EINVAL)
+                        ) =>
+                {
+                    // the device or the route can't segment, retry without
+                    self.gso = false;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                #[cfg(unix)]
+                Err(e)
+                    if matches!(
+                        e.raw_os_error(),
+                        Some(libc::ENOBUFS | libc::ENOMEM)
+                    ) =>
+                {
+                    // no room in the kernel for now, tried again on the next
+                    // turn of the runtime
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Err(e) => {
+                    if let Some((data, dst)) = self.send_queue.pop_front() {
+                        warn!(
+                            "dropped a {} byte datagram to {}: {}",
+                            data.len(),
+                            dst,
+                            e
+                        );
+                    }
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(target_os = "linux")]
+use sys::{recv_batch, send_batch};
+
+#[cfg(not(target_os = "linux"))]
+fn recv_batch(
+    socket: &UdpSocket,
+    bufs: &mut [Vec<u8>],
+    received: &mut VecDeque<(Vec<u8>, SocketAddr)>,
+) -> io::Result<()> {
+    let (n, src) = socket.try_recv_from(&mut bufs[0])?;
+    received.push_back((bufs[0][..n].to_vec(), src));
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_batch(
+    socket: &UdpSocket,
+    queue: &mut VecDeque<(Vec<u8>, SocketAddr)>,
+    _gso: bool,
+) -> io::Result<()> {
+    while let Some((data, dst)) = queue.front() {
+        socket.try_send_to(data, *dst)?;
+        queue.pop_front();
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        collections::VecDeque,
+        io,
+        mem::{size_of, zeroed},
+        net::SocketAddr,
+        os::fd::AsRawFd,
+    };
+
+    use tokio::net::UdpSocket;
+
+    use super::{BATCH_SIZE, MAX_DATAGRAM_SIZE};
+
+    /// most segments the kernel accepts in one GSO send
+    const MAX_GSO_SEGMENTS: usize = 64;
+    /// largest segments sent with GSO, what fits a 1500 bytes MTU after the
+    /// IP and UDP headers: the kernel rejects segments larger than the MTU
+    /// of the route
+    const MAX_GSO_SEGMENT_V4: usize = 1500 - 20 - 8;
+    const MAX_GSO_SEGMENT_V6: usize = 1500 - 40 - 8;
+
+    /// room for a single int control message
+    #[repr(align(8))]
+    struct Cmsg([u8; 32]);
+
+    pub fn enable_gro(socket: &UdpSocket) {
+        let on: libc::c_int = 1;
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &on as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as _,
+            );
+        }
+    }
+
+    pub fn gso_supported(socket: &UdpSocket) -> bool {
+        let mut v: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                &mut v as *mut _ as *mut libc::c_void,
+                &mut len,
+            ) == 0
+        }
+    }
+
+    fn to_std(
+        addr: &libc::sockaddr_storage,
+        len: libc::socklen_t,
+    ) -> Option<SocketAddr> {
+        unsafe { socket2::SockAddr::new(*addr, len) }.as_socket()
+    }
+
+    pub fn recv_batch(
+        socket: &UdpSocket,
+        bufs: &mut [Vec<u8>],
+        received: &mut VecDeque<(Vec<u8>, SocketAddr)>,
+    ) -> io::Result<()> {
+        let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { zeroed() };
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { zeroed() };
+        let mut cmsgs: [Cmsg; BATCH_SIZE] = unsafe { zeroed() };
+        let mut hdrs: [libc::mmsghdr; BATCH_SIZE] = unsafe { zeroed() };
+        for i in 0..BATCH_SIZE {
+            iovs[i].iov_base = bufs[i].as_mut_ptr() as _;
+            iovs[i].iov_len = bufs[i].len();
+            let h = &mut hdrs[i].msg_hdr;
+            h.msg_name = &mut addrs[i] as *mut _ as _;
+            h.msg_namelen = size_of::<libc::sockaddr_storage>() as _;
+            h.msg_iov = &mut iovs[i];
+            h.msg_iovlen = 1;
+            h.msg_control = cmsgs[i].0.as_mut_ptr() as _;
+            h.msg_controllen = cmsgs[i].0.len() as _;
+        }
+
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                hdrs.as_mut_ptr(),
+                BATCH_SIZE as _,
+                0 as _,
+                std::ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (i, hdr) in hdrs.iter().enumerate().take(n as usize) {
+            let Some(src) = to_std(&addrs[i], hdr.msg_hdr.msg_namelen) else {
+                continue;
+            };
+            let data = &bufs[i][..hdr.msg_len as usize];
+            // with GRO one message may hold several datagrams of this size
+            let mut segment = data.len();
+            unsafe {
+                let mut c = libc::CMSG_FIRSTHDR(&hdr.msg_hdr);
+                while !c.is_null() {
+                    if (*c).cmsg_level == libc::SOL_UDP
+                        && (*c).cmsg_type == libc::UDP_GRO
+                    {
+                        let size = std::ptr::read_unaligned(
+                            libc::CMSG_DATA(c) as *const libc::c_int
+                        );
+                        if size > 0 {
+                            segment = size as usize;
+                        }
+                    }
+                    c = libc::CMSG_NXTHDR(&hdr.msg_hdr, c);
+                }
+            }
+            if data.is_empty() {
+                received.push_back((Vec::new(), src));
+            }
+            for chunk in data.chunks(segment.max(1)) {
+                received.push_back((chunk.to_vec(), src));
+            }
+        }
+        Ok(())
+    }
+
+    /// How many queued datagrams from the front go into one GSO message:
+    /// a run to the same destination, all the size of the first except a
+    /// shorter last one, the first fitting the MTU.
+    fn gso_run(queue: &VecDeque<(Vec<u8>, SocketAddr)>, from: usize) -> usize {
+        let (first, dst) = &queue[from];
+        let size = first.len();
+        let max_segment = match dst {
+            SocketAddr::V4(_) => MAX_GSO_SEGMENT_V4,
+            SocketAddr::V6(_) => MAX_GSO_SEGMENT_V6,
+        };
+        if size == 0 || size > max_segment {
+            return 1;
+        }
+        let mut total = size;
+        let mut n = 1;
+        for (data, d) in queue.iter().skip(from + 1) {
+            if d != dst
+                || data.len() > size
+                || n == MAX_GSO_SEGMENTS
+                || total + data.len() > MAX_DATAGRAM_SIZE
+            {
+                break;
+            }
+            n += 1;
+            total += data.len();
+            if data.len() < size {
+                break;
+            }
+        }
+        n
+    }
+
+    pub fn send_batch(
+        socket: &UdpSocket,
+        queue: &mut VecDeque<(Vec<u8>, SocketAddr)>,
+        gso: bool,
+    ) -> io::Result<()> {
+        while !queue.is_empty() {
+            // (queued datagrams, payload, segment size)
+            let mut msgs: Vec<(usize, Vec<u8>, usize)> = Vec::new();
+            let mut from = 0;
+            while from < queue.len() && msgs.len() < BATCH_SIZE {
+                let run = if gso { gso_run(queue, from) } else { 1 };
+                let segment = queue[from].0.len();
+                let payload = if run == 1 {
+                    Vec::new()
+                } else {
+                    queue.range(from..from + run).fold(
+                        Vec::with_capacity(segment * run),
+                        |mut acc, (data, _)| {
+                            acc.extend_from_slice(data);
+                            acc
+                        },
+                    )
+                };
+                msgs.push((run, payload, segment));
+                from += run;
+            }
+
+            let mut addrs: Vec<socket2::SockAddr> = Vec::with_capacity(msgs.len());
+            let mut iovs: Vec<libc::iovec> = Vec::with_capacity(msgs.len());
+            let mut cmsgs: Vec<Cmsg> = Vec::with_capacity(msgs.len());
+            let mut hdrs: Vec<libc::mmsghdr> = Vec::with_capacity(msgs.len());
+            let mut from = 0;
+            for (run, payload, _) in &msgs {
+                let (data, dst) = &queue[from];
+                addrs.push((*dst).into());
+                let data = if *run == 1 { data } else { payload };
+                iovs.push(libc::iovec {
+                    iov_base: data.as_ptr() as *mut _,
+                    iov_len: data.len(),
+                });
+                cmsgs.push(unsafe { zeroed() });
+                from += run;
+            }
+            for (i, (run, _, segment)) in msgs.iter().enumerate() {
+                let mut h: libc::mmsghdr = unsafe { zeroed() };
+                h.msg_hdr.msg_name = addrs[i].as_ptr() as *mut _;
+                h.msg_hdr.msg_namelen = addrs[i].len();
+                h.msg_hdr.msg_iov = &mut iovs[i];
+                h.msg_hdr.msg_iovlen = 1;
+                if *run > 1 {
+                    h.msg_hdr.msg_control = cmsgs[i].0.as_mut_ptr() as _;
+                    h.msg_hdr.msg_controllen =
+                        unsafe { libc::CMSG_SPACE(size_of::<u16>() as _) } as _;
+                    unsafe {
+                        let c = libc::CMSG_FIRSTHDR(&h.msg_hdr);
+                        (*c).cmsg_level = libc::SOL_UDP;
+                        (*c).cmsg_type = libc::UDP_SEGMENT;
+                        (*c).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as _) as _;
+                        std::ptr::write_unaligned(
+                            libc::CMSG_DATA(c) as *mut u16,
+                            *segment as u16,
+                        );
+                    }
+                }
+                hdrs.push(h);
+            }
+
+            let n = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    hdrs.as_mut_ptr(),
+                    hdrs.len() as _,
+                    0 as _,
+                )
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let sent: usize = msgs.iter().take(n as usize).map(|x| x.0).sum();
+            queue.drain(..sent);
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::collections::VecDeque;
+
+        use super::gso_run;
+
+        #[test]
+        fn test_gso_run() {
+            let a = "127.0.0.1:1000".parse().unwrap();
+            let b = "127.0.0.1:2000".parse().unwrap();
+            let queue: VecDeque<_> = [
+                (vec![0; 1200], a),
+                (vec![0; 1200], a),
+                (vec![0; 800], a),
+                (vec![0; 800], a),
+                (vec![0; 100], b),
+            ]
+            .into();
+            assert_eq!(gso_run(&queue, 0), 3);
+            assert_eq!(gso_run(&queue, 2), 2);
+            assert_eq!(gso_run(&queue, 4), 1);
+        }
+
+        #[test]
+        fn test_gso_run_mtu() {
+            let v4 = "127.0.0.1:1000".parse().unwrap();
+            let v6 = "[::1]:1000".parse().unwrap();
+            for (dst, max) in [(v4, 1472), (v6, 1452)] {
+                let run = |size| {
+                    let queue: VecDeque<_> =
+                        [(vec![0; size], dst), (vec![0; size], dst)].into();
+                    gso_run(&queue, 0)
+                };
+                assert_eq!(run(max), 2);
+                // sent on their own
+                assert_eq!(run(max + 1), 1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use tokio::net::UdpSocket;
+
+    use super::BatchUdpSocket;
+
+    #[tokio::test]
+    async fn test_batch_udp() {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let src = a.local_addr().unwrap();
+        let dst = b.local_addr().unwrap();
+        let mut a = BatchUdpSocket::new(a);
+        let mut b = BatchUdpSocket::new(b);
+
+        let sizes = [1200, 1200, 1200, 700, 1, 30000];
+        for (i, n) in sizes.iter().enumerate() {
+            a.queue_send(vec![i as u8; *n], dst);
+        }
+        poll_fn(|cx| a.poll_send(cx)).await.unwrap();
+        assert_eq!(a.queued(), 0);
+
+        for (i, n) in sizes.iter().enumerate() {
+            let (data, from) = poll_fn(|cx| b.poll_recv_from(cx)).await.unwrap();
+            assert_eq!(from, src);
+            assert_eq!(data, vec![i as u8; *n]);
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Fake TCP, datagrams carried as the payload of TCP segments crafted on a
+//! raw socket, for networks throttling or blocking UDP. It speaks the
+//! protocol of phantun: a handshake like TCP's, then a segment for each
+//! datagram, with no retransmission or ordering.
+//!
+//! The system knows nothing of the connection and answers the segments of
+//! the server with resets, which have to be dropped, e.g. with
+//! `iptables -A OUTPUT -p tcp --tcp-flags RST RST -d <server> -j DROP`.
+//! The raw socket needs CAP_NET_RAW.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU32, Ordering::Relaxed},
+    time::Duration,
+};
+
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+use super::{new_raw_tcp_socket, protect_socket};
+
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDSHAKE_ATTEMPTS: usize = 3;
+
+/// the window scale phantun puts in its SYNs
+const WINDOW_SCALE: u8 = 14;
+
+struct Segment {
+    flags: u8,
+    seq: u32,
+    ack: u32,
+    /// where the payload is in the packet
+    payload: std::ops::Range<usize>,
+}
+
+/// A fake TCP connection to `remote`.
+pub struct FakeTcp {
+    socket: UdpSocket,
+    local: SocketAddr,
+    remote: SocketAddr,
+    /// a socket bound to the local port, for the system not to hand it out
+    _reserved: socket2::Socket,
+    /// the next sequence number to send
+    seq: AtomicU32,
+    /// the next sequence number expected from the server
+    ack: AtomicU32,
+}
+
+impl FakeTcp {
+    pub async fn connect(remote: SocketAddr) -> io::Result<Self> {
+        // the address the system would send from, outside of a VPN
+        let probe = socket2::Socket::new(
+            socket2::Domain::for_address(remote),
+            socket2::Type::DGRAM,
+            None,
+        )?;
+        protect_socket(&probe)?;
+        probe.connect(&remote.into())?;
+        let local_ip = probe
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::Error::other("not an inet socket"))?
+            .ip();
+
+        let reserved = socket2::Socket::new(
+            socket2::Domain::for_address(remote),
+            socket2::Type::STREAM,
+            None,
+        )?;
+        protect_socket(&reserved)?;
+        reserved.bind(&SocketAddr::new(local_ip, 0).into())?;
+        let local = reserved
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::Error::other("not an inet socket"))?;
+
+        let socket = new_raw_tcp_socket(remote.is_ipv6())?;
+        // only what comes from the server is received
+        socket.connect(SocketAddr::new(remote.ip(), 0)).await?;
+
+        let conn = Self {
+            socket,
+            local,
+            remote,
+            _reserved: reserved,
+            seq: AtomicU32::new(0),
+            ack: AtomicU32::new(0),
+        };
+        conn.handshake().await?;
+        Ok(conn)
+    }
+
+    async fn handshake(&self) -> io::Result<()> {
+        let mut buf = vec![0u8; 65535];
+        for _ in 0..HANDSHAKE_ATTEMPTS {
+            let isn = rand::random::<u32>();
+            self.send_segment(isn, 0, SYN, &[]).await?;
+
+            let syn_ack = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+                loop {
+                    let seg = self.recv_segment(&mut buf).await?;
+                    if seg.flags & (SYN | ACK) == SYN | ACK
+                        && seg.ack == isn.wrapping_add(1)
+                    {
+                        return io::Result::Ok(seg);
+                    }
+                }
+            })
+            .await;
+            let Ok(syn_ack) = syn_ack else {
+                continue;
+            };
+            let syn_ack = syn_ack?;
+
+            self.seq.store(isn.wrapping_add(1), Relaxed);
+            self.ack.store(syn_ack.seq.wrapping_add(1), Relaxed);
+            self.send_ack().await?;
+            debug!("faketcp {} -> {} connected", self.local, self.remote);
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("faketcp handshake with {} timed out", self.remote),
+        ))
+    }
+
+    pub async fn send(&self, payload: &[u8]) -> io::Result<()> {
+        let seq = self.seq.fetch_add(payload.len() as u32, Relaxed);
+        self.send_segment(seq, self.ack.load(Relaxed), ACK, payload)
+            .await
+    }
+
+    /// Receive a datagram into `buf`, connecting again if the server reset
+    /// the connection.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let seg = self.recv_segment(buf).await?;
+            if seg.flags & RST != 0 {
+                warn!("faketcp {} reset by the server", self.remote);
+                self.handshake().await?;
+                continue;
+            }
+            // our ACK of the handshake was lost
+            if seg.flags & SYN != 0 {
+                self.send_ack().await?;
+                continue;
+            }
+            if seg.payload.is_empty() {
+                continue;
+            }
+
+            let n = seg.payload.len();
+            self.ack.store(seg.seq.wrapping_add(n as u32), Relaxed);
+            buf.copy_within(seg.payload, 0);
+            return Ok(n);
+        }
+    }
+
+    async fn send_ack(&self) -> io::Result<()> {
+        let (seq, ack) = (self.seq.load(Relaxed), self.ack.load(Relaxed));
+        self.send_segment(seq, ack, ACK, &[]).await
+    }
+
+    async fn send_segment(
+        &self,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let segment = segment(self.local, self.remote, seq, ack, flags, payload);
+        self.socket.send(&segment).await?;
+        Ok(())
+    }
+
+    /// The next segment of this connection read into `buf`.
+    async fn recv_segment(&self, buf: &mut [u8]) -> io::Result<Segment> {
+        loop {
+            let n = self.socket.recv(buf).await?;
+            // IPv4 raw sockets receive the IP header too
+            let with_ip_header = self.remote.is_ipv4();
+            if let Some(seg) =
+                parse(with_ip_header, self.remote, self.local, &buf[..n])
+            {
+                return Ok(seg);
+            }
+        }
+    }
+}
+
+/// A segment from `src` to `dst`, with its checksum.
+fn segment(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let header_len = if flags & SYN != 0 { 24 } else { 20 };
+    let mut buf = Vec::with_capacity(header_len + payload.len());
+    buf.extend_from_slice(&src.port().to_be_bytes());
+    buf.extend_from_slice(&dst.port().to_be_bytes());
+    buf.extend_from_slice(&seq.to_be_bytes());
+    buf.extend_from_slice(&ack.to_be_bytes());
+    buf.push(((header_len / 4) as u8) << 4);
+    buf.push(flags);
+    // the window, the checksum and the urgent pointer
+    buf.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+    if flags & SYN != 0 {
+        // NOP, then the window scale
+        buf.extend_from_slice(&[1, 3, 3, WINDOW_SCALE]);
+    }
+    buf.extend_from_slice(payload);
+
+    let checksum = checksum(src.ip(), dst.ip(), &buf);
+    buf[16..18].copy_from_slice(&checksum.to_be_bytes());
+    buf
+}
+
+/// The segment in `pkt` if it's from `src` to `dst`.
+fn parse(
+    with_ip_header: bool,
+    src: SocketAddr,
+    dst: SocketAddr,
+    pkt: &[u8],
+) -> Option<Segment> {
+    let start = if with_ip_header {
+        (*pkt.first()? & 0x0f) as usize * 4
+    } else {
+        0
+    };
+    let tcp = pkt.get(start..)?;
+    if tcp.len() < 20
+        || u16::from_be_bytes([tcp[0], tcp[1]]) != src.port()
+        || u16::from_be_bytes([tcp[2], tcp[3]]) != dst.port()
+    {
+        return None;
+    }
+    let header_len = (tcp[12] >> 4) as usize * 4;
+    if header_len < 20 || header_len > tcp.len() {
+        return None;
+    }
+    Some(Segment {
+        flags: tcp[13],
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        ack: u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]),
+        payload: start + header_len..pkt.len(),
+    })
+}
+
+/// The TCP checksum of `segment`, over its pseudo header too.
+fn checksum(src: IpAddr, dst: IpAddr, segment: &[u8]) -> u16 {
+    fn sum(data: &[u8]) -> u32 {
+        data.chunks(2)
+            .map(|x| u16::from_be_bytes([x[0], *x.get(1).unwrap_or(&0)]) as u32)
+            .sum()
+    }
+
+    let mut acc = sum(segment);
+    acc += match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            sum(&src.octets()) + sum(&dst.octets()) + 6 + segment.len() as u32
+        }
+        (src, dst) => {
+            let ip6 = |x: IpAddr| match x {
+                IpAddr::V4(x) => x.to_ipv6_mapped().octets(),
+                IpAddr::V6(x) => x.octets(),
+            };
+            let len = segment.len() as u32;
+            sum(&ip6(src)) + sum(&ip6(dst)) + (len >> 16) + (len & 0xffff) + 6
+        }
+    };
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{checksum, parse, segment, ACK, SYN};
+
+    #[test]
+    fn test_segment() {
+        let client: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let server: SocketAddr = "1.2.3.4:4567".parse().unwrap();
+
+        let syn = segment(client, server, 7, 0, SYN, &[]);
+        assert_eq!(syn.len(), 24);
+        assert_eq!(syn[12] >> 4, 6);
+        // the checksum of a segment with its checksum is 0
+        assert_eq!(checksum(client.ip(), server.ip(), &syn), 0);
+
+        let data = segment(server, client, 100, 8, ACK, b"hello");
+        assert_eq!(data.len(), 25);
+        assert_eq!(checksum(server.ip(), client.ip(), &data), 0);
+
+        // as received on an IPv4 raw socket
+        let mut pkt = vec![0x45];
+        pkt.extend_from_slice(&[0; 19]);
+        pkt.extend_from_slice(&data);
+        let seg = parse(true, server, client, &pkt).unwrap();
+        assert_eq!((seg.flags, seg.seq, seg.ack), (ACK, 100, 8));
+        assert_eq!(&pkt[seg.payload], b"hello");
+
+        // of another connection
+        assert!(parse(true, client, server, &pkt).is_none());
+        assert!(parse(false, server, client, &data[..10]).is_none());
+
+        let v6_client: SocketAddr = "[fd00::2]:40000".parse().unwrap();
+        let v6_server: SocketAddr = "[2001:db8::1]:4567".parse().unwrap();
+        let data = segment(v6_client, v6_server, 1, 1, ACK, b"odd");
+        assert_eq!(checksum(v6_client.ip(), v6_server.ip(), &data), 0);
+        let seg = parse(false, v6_client, v6_server, &data).unwrap();
+        assert_eq!(&data[seg.payload], b"odd");
+    }
+}
@@ -0,0 +1,283 @@
+//! A cap on the TCP connections an outbound has open to each destination
+//! host at once, set per outbound with `per-host-limit`, like the six a
+//! browser opens, for upstream services that don't take more.
+
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use erased_serde::Serialize as ESerialize;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedStream, ProxyChain,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    proxy::{AnyOutboundHandler, ConnectorType, OutboundHandler, OutboundType},
+    session::Session,
+};
+
+use super::RemoteConnector;
+
+/// hosts tracked before the idle ones are forgotten
+const MAX_IDLE_HOSTS: usize = 1024;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HostLimit {
+    /// connections open to a host at once
+    pub max: usize,
+    /// seconds a connection past `max` waits for another to close, it's
+    /// rejected right away with 0
+    #[serde(default = "default_wait")]
+    pub wait: u64,
+}
+
+fn default_wait() -> u64 {
+    30
+}
+
+pub struct HostLimited {
+    inner: AnyOutboundHandler,
+    limit: HostLimit,
+    /// by destination host
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimited {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(inner: AnyOutboundHandler, limit: HostLimit) -> AnyOutboundHandler {
+        Arc::new(Self {
+            inner,
+            limit,
+            hosts: Default::default(),
+        })
+    }
+
+    async fn acquire(&self, sess: &Session) -> io::Result<OwnedSemaphorePermit> {
+        let semaphore = {
+            let mut hosts = self.hosts.lock().unwrap();
+            if hosts.len() >= MAX_IDLE_HOSTS {
+                hosts.retain(|_, x| x.available_permits() < self.limit.max);
+            }
+            hosts
+                .entry(sess.destination.host())
+                .or_insert_with(|| Arc::new(Semaphore::new(self.limit.max)))
+                .clone()
+        };
+
+        let full = || {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "{} has {} connections open to {} already",
+                    self.inner.name(),
+                    self.limit.max,
+                    sess.destination.host()
+                ),
+            )
+        };
+        if self.limit.wait == 0 {
+            return semaphore.try_acquire_owned().map_err(|_| full());
+        }
+        tokio::time::timeout(
+            Duration::from_secs(self.limit.wait),
+            semaphore.acquire_owned(),
+        )
+        .await
+        .map_err(|_| full())?
+        .map_err(|_| full())
+    }
+}
+
+/// A connection holding its host's slot until it's dropped.
+struct Limited {
+    inner: BoxedChainedStream,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Debug for Limited {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Limited")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl AsyncRead for Limited {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Limited {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl ChainedStream for Limited {
+    fn chain(&self) -> &ProxyChain {
+        self.inner.chain()
+    }
+
+    async fn append_to_chain(&self, name: &str) {
+        self.inner.append_to_chain(name).await
+    }
+
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        self.inner.tcp_stream()
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for HostLimited {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.inner.proto()
+    }
+
+    fn server(&self) -> Option<(&str, u16)> {
+        self.inner.server()
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.inner.support_udp().await
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let permit = self.acquire(sess).await?;
+        let inner = self.inner.connect_stream(sess, resolver).await?;
+        Ok(Box::new(Limited {
+            inner,
+            _permit: permit,
+        }))
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.inner.connect_datagram(sess, resolver).await
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        self.inner.support_connector().await
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        let permit = self.acquire(sess).await?;
+        let inner = self
+            .inner
+            .connect_stream_with_connector(sess, resolver, connector)
+            .await?;
+        Ok(Box::new(Limited {
+            inner,
+            _permit: permit,
+        }))
+    }
+
+    async fn connect_datagram_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.inner
+            .connect_datagram_with_connector(sess, resolver, connector)
+            .await
+    }
+
+    async fn members(&self) -> Option<Vec<AnyOutboundHandler>> {
+        self.inner.members().await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m = self.inner.as_map().await;
+        m.insert("per-host-limit".to_string(), Box::new(self.limit.max));
+        m
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{proxy::mocks::MockDummyOutboundHandler, session::Session};
+
+    use super::{HostLimit, HostLimited};
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let mut inner = MockDummyOutboundHandler::new();
+        inner.expect_name().return_const("proxy".to_owned());
+        let limited = HostLimited {
+            inner: Arc::new(inner),
+            limit: HostLimit { max: 1, wait: 0 },
+            hosts: Default::default(),
+        };
+        let sess = |host: &str| Session {
+            destination: (host.to_owned(), 443).try_into().unwrap(),
+            ..Default::default()
+        };
+
+        let permit = limited.acquire(&sess("example.com")).await.unwrap();
+        assert!(limited.acquire(&sess("example.com")).await.is_err());
+        // another host has slots of its own
+        let _other = limited.acquire(&sess("example.org")).await.unwrap();
+
+        drop(permit);
+        assert!(limited.acquire(&sess("example.com")).await.is_ok());
+    }
+}
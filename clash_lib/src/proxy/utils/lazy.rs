@@ -0,0 +1,258 @@
+//! Outbounds built on first use rather than when the config is loaded, so
+//! configs with hundreds of proxies start quickly and the ones never used
+//! take no memory. Chosen with `outbound-init`.
+//!
+//! Building is parsing the options and setting up the handler, its keys and
+//! TLS config. The connections, TLS sessions and tunnels are still made on
+//! first use, also when warmed up.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Weak},
+};
+
+use async_trait::async_trait;
+use erased_serde::Serialize as ESerialize;
+use tokio::sync::{OnceCell, Semaphore};
+use tracing::{debug, warn};
+
+use crate::{
+    app::{
+        dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
+        dns::ThreadSafeDNSResolver,
+    },
+    common::errors::new_io_error,
+    proxy::{AnyOutboundHandler, ConnectorType, OutboundHandler, OutboundType},
+    session::Session,
+    Error,
+};
+
+use super::RemoteConnector;
+
+type Build = Arc<dyn Fn() -> Result<AnyOutboundHandler, Error> + Send + Sync>;
+
+/// outbounds built at once when warming up
+static WARM_UPS: Semaphore = Semaphore::const_new(4);
+
+/// An outbound built by `build` the first time it's needed, built again if
+/// that failed.
+pub struct LazyOutbound {
+    name: String,
+    /// the type before it's built
+    proto: OutboundType,
+    /// the `server:` and `port:` of the config, known without building it
+    server: Option<(String, u16)>,
+    build: Build,
+    inner: OnceCell<AnyOutboundHandler>,
+}
+
+impl LazyOutbound {
+    pub fn new(
+        name: String,
+        proto: OutboundType,
+        server: Option<(String, u16)>,
+        build: Build,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            proto,
+            server,
+            build,
+            inner: OnceCell::new(),
+        })
+    }
+
+    /// Build it in the background, a few at a time, unless it's gone by
+    /// then.
+    pub fn warm_up(self: &Arc<Self>) {
+        let Ok(rt) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let this = Arc::downgrade(self);
+        rt.spawn(async move {
+            let Ok(_permit) = WARM_UPS.acquire().await else {
+                return;
+            };
+            let Some(this) = Weak::upgrade(&this) else {
+                return;
+            };
+            if let Err(e) = this.inner().await {
+                warn!("failed to warm up outbound {}: {}", this.name, e);
+            }
+        });
+    }
+
+    /// built off the runtime, the callers waiting for the first build
+    async fn inner(&self) -> io::Result<&AnyOutboundHandler> {
+        self.inner
+            .get_or_try_init(|| async {
+                debug!("building outbound {}", self.name);
+                let build = self.build.clone();
+                tokio::task::spawn_blocking(move || build())
+                    .await
+                    .map_err(|e| new_io_error(&e.to_string()))?
+                    .map_err(|e| {
+                        new_io_error(&format!(
+                            "failed to build {}: {}",
+                            self.name, e
+                        ))
+                    })
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for LazyOutbound {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn proto(&self) -> OutboundType {
+        match self.inner.get() {
+            Some(inner) => inner.proto(),
+            None => self.proto,
+        }
+    }
+
+    fn server(&self) -> Option<(&str, u16)> {
+        self.server
+            .as_ref()
+            .map(|(host, port)| (host.as_str(), *port))
+    }
+
+    async fn support_udp(&self) -> bool {
+        match self.inner().await {
+            Ok(inner) => inner.support_udp().await,
+            Err(_) => false,
+        }
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        self.inner().await?.connect_stream(sess, resolver).await
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.inner().await?.connect_datagram(sess, resolver).await
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        match self.inner().await {
+            Ok(inner) => inner.support_connector().await,
+            Err(_) => ConnectorType::None,
+        }
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        self.inner()
+            .await?
+            .connect_stream_with_connector(sess, resolver, connector)
+            .await
+    }
+
+    async fn connect_datagram_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.inner()
+            .await?
+            .connect_datagram_with_connector(sess, resolver, connector)
+            .await
+    }
+
+    /// not built for the API, only the type until it's been used
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        match self.inner.get() {
+            Some(inner) => inner.as_map().await,
+            None => {
+                let mut m = HashMap::new();
+                m.insert("type".to_string(), Box::new(self.proto()) as _);
+                m
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use crate::proxy::{direct, OutboundHandler, OutboundType};
+
+    use super::LazyOutbound;
+
+    #[tokio::test]
+    async fn test_lazy() {
+        let builds = Arc::new(AtomicUsize::new(0));
+        let counter = builds.clone();
+        let lazy = LazyOutbound::new(
+            "d".to_owned(),
+            OutboundType::Socks5,
+            Some(("10.0.0.1".to_owned(), 1080)),
+            Arc::new(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Ok(direct::Handler::new())
+            }),
+        );
+        assert_eq!(lazy.name(), "d");
+        assert!(matches!(lazy.proto(), OutboundType::Socks5));
+        assert_eq!(lazy.server(), Some(("10.0.0.1", 1080)));
+        assert_eq!(builds.load(Ordering::Relaxed), 0);
+
+        // built once, on first use
+        assert!(lazy.support_udp().await);
+        assert!(lazy.support_udp().await);
+        assert_eq!(builds.load(Ordering::Relaxed), 1);
+        assert!(matches!(lazy.proto(), OutboundType::Direct));
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        let builds = Arc::new(AtomicUsize::new(0));
+        let lazy = |builds: &Arc<AtomicUsize>| {
+            let counter = builds.clone();
+            LazyOutbound::new(
+                "d".to_owned(),
+                OutboundType::Plugin,
+                None,
+                Arc::new(move || {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(direct::Handler::new())
+                }),
+            )
+        };
+
+        let warm = (0..10).map(|_| lazy(&builds)).collect::<Vec<_>>();
+        warm.iter().for_each(|x| x.warm_up());
+        // gone before its turn
+        lazy(&builds).warm_up();
+
+        let raced = lazy(&builds);
+        let (a, b) = tokio::join!(raced.support_udp(), raced.support_udp());
+        assert!(a && b);
+
+        while warm.iter().any(|x| x.inner.get().is_none()) {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(builds.load(Ordering::Relaxed), 11);
+    }
+}
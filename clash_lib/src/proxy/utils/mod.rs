@@ -1,23 +1,39 @@
 use std::{
     fmt::Display,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::RwLock,
 };
 
+#[cfg(any(all(test, not(ci)), feature = "test-harness"))]
+pub mod test_harness;
 #[cfg(all(test, not(ci)))]
 pub mod test_utils;
 
+mod batch_udp;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod faketcp;
+mod host_limit;
+mod lazy;
+mod port_range;
 pub mod provider_helper;
 mod proxy_connector;
+pub mod retry;
 mod socket_helpers;
 
+pub use batch_udp::{BatchUdpSocket, BATCH_SIZE};
+pub use host_limit::{HostLimit, HostLimited};
+pub use lazy::LazyOutbound;
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use once_cell::sync::Lazy;
+pub(crate) use port_range::{bind_in_range, source_port_range, unspecified};
+pub use port_range::{set_source_port_range, PortRange, PortRanged};
 pub use proxy_connector::*;
 
 use serde::{Deserialize, Serialize};
 pub use socket_helpers::*;
 use tracing::{debug, trace};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct OutboundInterface {
     pub name: String,
     #[allow(unused)]
@@ -26,7 +42,27 @@ pub struct OutboundInterface {
     pub addr_v6: Option<Ipv6Addr>,
 }
 
+/// the default outbound interface, looked up again by
+/// `refresh_outbound_interface` when the network changes
+static OUTBOUND_INTERFACE: Lazy<RwLock<Option<OutboundInterface>>> =
+    Lazy::new(|| RwLock::new(find_outbound_interface()));
+
 pub fn get_outbound_interface() -> Option<OutboundInterface> {
+    OUTBOUND_INTERFACE.read().unwrap().clone()
+}
+
+/// Look up the default outbound interface again, returns whether it changed.
+pub fn refresh_outbound_interface() -> bool {
+    let found = find_outbound_interface();
+    let mut current = OUTBOUND_INTERFACE.write().unwrap();
+    if *current == found {
+        return false;
+    }
+    *current = found;
+    true
+}
+
+fn find_outbound_interface() -> Option<OutboundInterface> {
     fn get_outbound_ip_from_interface(
         iface: &NetworkInterface,
     ) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
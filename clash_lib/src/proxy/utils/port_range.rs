@@ -0,0 +1,306 @@
+//! The local ports outbound sockets are bound to, for firewalls allowing
+//! only some of them and for telling clash-rs traffic apart. Set globally
+//! with `outbound-port-range` and per outbound with `port-range`.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::RwLock,
+};
+
+use async_trait::async_trait;
+use erased_serde::Serialize as ESerialize;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    app::{
+        dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
+        dns::ThreadSafeDNSResolver,
+    },
+    proxy::{AnyOutboundHandler, ConnectorType, OutboundHandler, OutboundType},
+    session::Session,
+    Error,
+};
+
+use super::RemoteConnector;
+
+/// Ports to try before giving up on a range with most of them taken.
+const MAX_ATTEMPTS: u32 = 64;
+
+/// An inclusive range of ports, `40000-40999` or a single `40000`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "RawPortRange")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+/// a port range in the config, a single port may be a number
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPortRange {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<RawPortRange> for PortRange {
+    type Error = Error;
+
+    fn try_from(raw: RawPortRange) -> Result<Self, Self::Error> {
+        match raw {
+            RawPortRange::Port(p) => p.to_string().parse(),
+            RawPortRange::Range(r) => r.parse(),
+        }
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    /// how many ports there are besides the first
+    pub fn width(&self) -> u16 {
+        self.end - self.start
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidConfig(format!("invalid port range: {}", s));
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start = start.trim().parse::<u16>().map_err(|_| invalid())?;
+        let end = end.trim().parse::<u16>().map_err(|_| invalid())?;
+        if start == 0 || start > end {
+            return Err(invalid());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+static SOURCE_PORT_RANGE: Lazy<RwLock<Option<PortRange>>> =
+    Lazy::new(Default::default);
+
+tokio::task_local! {
+    static OUTBOUND_PORT_RANGE: PortRange;
+}
+
+/// Bind the outbound sockets to ports in `range`, any port if `None`.
+pub fn set_source_port_range(range: Option<PortRange>) {
+    *SOURCE_PORT_RANGE.write().unwrap() = range;
+}
+
+/// the range of the outbound dialing now, or the global one
+pub(crate) fn source_port_range() -> Option<PortRange> {
+    OUTBOUND_PORT_RANGE
+        .try_with(|x| *x)
+        .ok()
+        .or_else(|| *SOURCE_PORT_RANGE.read().unwrap())
+}
+
+/// Bind `socket` to `ip` and a free port of `range`, picked at random so
+/// the sockets of many connections rarely collide.
+pub(crate) fn bind_in_range(
+    socket: &socket2::Socket,
+    ip: IpAddr,
+    range: PortRange,
+) -> io::Result<()> {
+    let size = u32::from(range.end - range.start) + 1;
+    let offset = rand::thread_rng().gen_range(0..size);
+    for i in 0..size.min(MAX_ATTEMPTS) {
+        let port = range.start + ((offset + i) % size) as u16;
+        match socket.bind(&SocketAddr::new(ip, port).into()) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("no free port in the range {}", range),
+    ))
+}
+
+/// the address to bind to for a port only
+pub(crate) fn unspecified(v6: bool) -> IpAddr {
+    if v6 {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    }
+}
+
+/// An outbound with a `port-range` of its own, which applies to the sockets
+/// dialed while it connects.
+pub struct PortRanged {
+    inner: AnyOutboundHandler,
+    range: PortRange,
+}
+
+impl PortRanged {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(inner: AnyOutboundHandler, range: PortRange) -> AnyOutboundHandler {
+        std::sync::Arc::new(Self { inner, range })
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for PortRanged {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.inner.proto()
+    }
+
+    fn server(&self) -> Option<(&str, u16)> {
+        self.inner.server()
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.inner.support_udp().await
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        OUTBOUND_PORT_RANGE
+            .scope(self.range, self.inner.connect_stream(sess, resolver))
+            .await
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        OUTBOUND_PORT_RANGE
+            .scope(self.range, self.inner.connect_datagram(sess, resolver))
+            .await
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        self.inner.support_connector().await
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        OUTBOUND_PORT_RANGE
+            .scope(
+                self.range,
+                self.inner
+                    .connect_stream_with_connector(sess, resolver, connector),
+            )
+            .await
+    }
+
+    async fn connect_datagram_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        OUTBOUND_PORT_RANGE
+            .scope(
+                self.range,
+                self.inner
+                    .connect_datagram_with_connector(sess, resolver, connector),
+            )
+            .await
+    }
+
+    async fn members(&self) -> Option<Vec<AnyOutboundHandler>> {
+        self.inner.members().await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m = self.inner.as_map().await;
+        m.insert("port-range".to_string(), Box::new(self.range.to_string()));
+        m
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{bind_in_range, PortRange};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "40000-40999".parse::<PortRange>().unwrap(),
+            PortRange {
+                start: 40000,
+                end: 40999
+            }
+        );
+        let single = "40000".parse::<PortRange>().unwrap();
+        assert_eq!((single.start, single.end), (40000, 40000));
+        assert_eq!(single.to_string(), "40000");
+
+        assert!("40999-40000".parse::<PortRange>().is_err());
+        assert!("0-100".parse::<PortRange>().is_err());
+        assert!("40000-70000".parse::<PortRange>().is_err());
+        assert!("x".parse::<PortRange>().is_err());
+
+        let range = "27000-27100".parse::<PortRange>().unwrap();
+        assert!(range.contains(27050));
+        assert!(!range.contains(27101));
+        assert_eq!(range.width(), 100);
+    }
+
+    #[test]
+    fn test_bind_in_range() {
+        let range = PortRange {
+            start: 47310,
+            end: 47311,
+        };
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let new_socket = || {
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)
+                .unwrap()
+        };
+
+        let mut sockets = vec![];
+        for _ in 0..2 {
+            let socket = new_socket();
+            bind_in_range(&socket, ip, range).unwrap();
+            let port = socket.local_addr().unwrap().as_socket().unwrap().port();
+            assert!((range.start..=range.end).contains(&port));
+            sockets.push(socket);
+        }
+        // both ports are taken
+        assert!(bind_in_range(&new_socket(), ip, range).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Groups that pick a member by health try another alive member when
+//! dialing through the picked one fails because of the proxy server. A
+//! destination that can't be reached through one member would fail through
+//! the others too, so that's returned as is and doesn't count against the
+//! member.
+
+use std::{future::Future, io, time::Duration};
+
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::{
+    app::remote_content_manager::ProxyManager, common::errors::is_server_error,
+    proxy::AnyOutboundHandler,
+};
+
+/// how many other members are tried after the first dial fails
+const MAX_RETRIES: usize = 2;
+/// how long the retries may take altogether
+const RETRY_BUDGET: Duration = Duration::from_secs(5);
+
+/// Dial through `first`, then through up to `MAX_RETRIES` other alive
+/// `members` while the proxy servers fail. Every attempt is reported to
+/// `proxy_manager`, the last error is returned if none succeeds.
+pub async fn dial_with_retry<T, F, Fut>(
+    group: &str,
+    first: AnyOutboundHandler,
+    members: Vec<AnyOutboundHandler>,
+    proxy_manager: &ProxyManager,
+    dial: F,
+) -> io::Result<T>
+where
+    F: Fn(AnyOutboundHandler) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut err =
+        match report(proxy_manager, &first, dial(first.clone()).await).await {
+            Ok(v) => return Ok(v),
+            Err(e) if !is_server_error(&e) => return Err(e),
+            Err(e) => e,
+        };
+
+    let deadline = Instant::now() + RETRY_BUDGET;
+    let mut tried = vec![first.name().to_owned()];
+    for proxy in members {
+        if tried.len() > MAX_RETRIES {
+            break;
+        }
+        if tried.iter().any(|x| x == proxy.name())
+            || !proxy_manager.alive(proxy.name()).await
+        {
+            continue;
+        }
+        debug!(
+            "`{}` failed to dial through `{}`: {}, retrying with `{}`",
+            group,
+            tried.last().unwrap(),
+            err,
+            proxy.name()
+        );
+        tried.push(proxy.name().to_owned());
+
+        match tokio::time::timeout_at(deadline, dial(proxy.clone())).await {
+            Ok(rv) => match report(proxy_manager, &proxy, rv).await {
+                Ok(v) => return Ok(v),
+                Err(e) if !is_server_error(&e) => return Err(e),
+                Err(e) => err = e,
+            },
+            // out of time, the client has waited long enough
+            Err(_) => break,
+        }
+    }
+    Err(err)
+}
+
+/// Report the dial through `proxy` that ended with `rv`, a failure only if
+/// it's the proxy server's.
+async fn report<T>(
+    proxy_manager: &ProxyManager,
+    proxy: &AnyOutboundHandler,
+    rv: io::Result<T>,
+) -> io::Result<T> {
+    match &rv {
+        Ok(_) => proxy_manager.report_dial(proxy.name(), true).await,
+        Err(e) if is_server_error(e) => {
+            proxy_manager.report_dial(proxy.name(), false).await
+        }
+        Err(_) => {}
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        app::{dns::MockClashResolver, remote_content_manager::ProxyManager},
+        common::errors::server_error,
+        proxy::{mocks::MockDummyOutboundHandler, AnyOutboundHandler},
+    };
+
+    use super::dial_with_retry;
+
+    fn member(name: &str) -> AnyOutboundHandler {
+        let mut handler = MockDummyOutboundHandler::new();
+        handler.expect_name().return_const(name.to_owned());
+        Arc::new(handler)
+    }
+
+    #[tokio::test]
+    async fn test_dial_with_retry() {
+        let manager = ProxyManager::new(Arc::new(MockClashResolver::new()), None);
+        let members = vec![
+            member("a"),
+            member("b"),
+            member("c"),
+            member("d"),
+            member("e"),
+        ];
+        manager.report_alive("b", false).await;
+
+        let dialed = Arc::new(Mutex::new(vec![]));
+        let dial = |proxy: AnyOutboundHandler| {
+            let dialed = dialed.clone();
+            async move {
+                dialed.lock().unwrap().push(proxy.name().to_owned());
+                match proxy.name() {
+                    "e" => Ok(proxy.name().to_owned()),
+                    _ => Err(server_error(io::Error::other("refused"))),
+                }
+            }
+        };
+
+        // b is dead, e is one retry too many
+        let err = dial_with_retry(
+            "group",
+            members[0].clone(),
+            members.clone(),
+            &manager,
+            dial,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "refused");
+        assert_eq!(*dialed.lock().unwrap(), vec!["a", "c", "d"]);
+
+        dialed.lock().unwrap().clear();
+        let picked = dial_with_retry(
+            "group",
+            members[2].clone(),
+            vec![members[2].clone(), members[4].clone()],
+            &manager,
+            dial,
+        )
+        .await
+        .unwrap();
+        assert_eq!(picked, "e");
+        assert_eq!(*dialed.lock().unwrap(), vec!["c", "e"]);
+    }
+
+    #[tokio::test]
+    async fn test_destination_failure() {
+        let manager = ProxyManager::new(Arc::new(MockClashResolver::new()), None);
+        let members = vec![member("a"), member("b")];
+
+        let dialed = Arc::new(Mutex::new(vec![]));
+        let dial = |proxy: AnyOutboundHandler| {
+            let dialed = dialed.clone();
+            async move {
+                dialed.lock().unwrap().push(proxy.name().to_owned());
+                Err::<(), _>(io::Error::other("destination unreachable"))
+            }
+        };
+
+        // neither retried nor held against the proxy
+        for _ in 0..10 {
+            let err = dial_with_retry(
+                "group",
+                members[0].clone(),
+                members.clone(),
+                &manager,
+                dial,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.to_string(), "destination unreachable");
+        }
+        assert_eq!(*dialed.lock().unwrap(), vec!["a"; 10]);
+        assert!(manager.alive("a").await);
+    }
+}
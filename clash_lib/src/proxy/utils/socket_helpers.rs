@@ -1,9 +1,11 @@
 use std::{
     io,
     net::{IpAddr, SocketAddr},
-    time::Duration,
+    sync::RwLock,
+    time::{Duration, Instant},
 };
 
+use once_cell::sync::Lazy;
 use socket2::TcpKeepalive;
 use tokio::{
     net::{TcpSocket, TcpStream, UdpSocket},
@@ -14,8 +16,67 @@ use tokio::{
 use tracing::warn;
 use tracing::{debug, error};
 
-use super::Interface;
-use crate::{app::dns::ThreadSafeDNSResolver, proxy::AnyStream};
+use super::{bind_in_range, source_port_range, unspecified, Interface, PortRange};
+use crate::{
+    app::dns::ThreadSafeDNSResolver,
+    common::{
+        errors::server_error,
+        timing::{record_phase, ConnectPhase},
+    },
+    proxy::AnyStream,
+};
+
+/// Called with the fd of every outbound socket before it's bound or
+/// connected, returns false if the socket can't be used.
+pub type SocketProtector = Box<dyn Fn(i32) -> bool + Send + Sync>;
+
+static SOCKET_PROTECTOR: Lazy<RwLock<Option<SocketProtector>>> =
+    Lazy::new(Default::default);
+
+/// Hook outbound socket creation, e.g. for Android's
+/// `VpnService.protect()` to keep the sockets out of the VPN.
+pub fn set_socket_protector(protector: Option<SocketProtector>) {
+    *SOCKET_PROTECTOR.write().unwrap() = protector;
+}
+
+/// SO_RCVBUF and SO_SNDBUF of TCP sockets, 0 for the OS default
+static SOCKET_BUFFERS: RwLock<(usize, usize)> = RwLock::new((0, 0));
+
+/// Size the kernel buffers of the TCP sockets opened from now on, 0 leaving
+/// it to the OS, which tunes them itself on Linux.
+pub fn set_socket_buffers(recv: usize, send: usize) {
+    *SOCKET_BUFFERS.write().unwrap() = (recv, send);
+}
+
+/// Set on dialed sockets before connecting and on listeners, whose accepted
+/// sockets inherit them, for the window scale to be negotiated. A size set
+/// turns the kernel's autotuning off.
+pub(crate) fn apply_socket_buffers(socket: &socket2::Socket) -> io::Result<()> {
+    let (recv, send) = *SOCKET_BUFFERS.read().unwrap();
+    if recv > 0 {
+        socket.set_recv_buffer_size(recv)?;
+    }
+    if send > 0 {
+        socket.set_send_buffer_size(send)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn protect_socket(socket: &socket2::Socket) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(protect) = SOCKET_PROTECTOR.read().unwrap().as_ref() {
+        use std::os::fd::AsRawFd;
+        if !protect(socket.as_raw_fd()) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "failed to protect socket",
+            ));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = socket;
+    Ok(())
+}
 
 pub fn apply_tcp_options(s: TcpStream) -> std::io::Result<TcpStream> {
     #[cfg(not(target_os = "windows"))]
@@ -41,6 +102,29 @@ pub fn apply_tcp_options(s: TcpStream) -> std::io::Result<TcpStream> {
     }
 }
 
+/// Bind `socket` on `iface`, and to a port of `range` if there is one.
+fn bind_socket_in_range(
+    socket: &socket2::Socket,
+    iface: Option<&Interface>,
+    range: Option<PortRange>,
+    v6: bool,
+) -> io::Result<()> {
+    match (iface, range) {
+        (Some(Interface::IpAddr(ip)), Some(range)) => {
+            bind_in_range(socket, *ip, range)
+        }
+        (iface, range) => {
+            if let Some(iface) = iface {
+                must_bind_socket_on_interface(socket, iface)?;
+            }
+            if let Some(range) = range {
+                bind_in_range(socket, unspecified(v6), range)?;
+            }
+            Ok(())
+        }
+    }
+}
+
 fn must_bind_socket_on_interface(
     socket: &socket2::Socket,
     iface: &Interface,
@@ -76,6 +160,7 @@ fn must_bind_socket_on_interface(
     }
 }
 
+/// A connection to a proxy server, its failures are the server's.
 pub async fn new_tcp_stream<'a>(
     resolver: ThreadSafeDNSResolver,
     address: &'a str,
@@ -83,9 +168,32 @@ pub async fn new_tcp_stream<'a>(
     iface: Option<&'a Interface>,
     #[cfg(any(target_os = "linux", target_os = "android"))] packet_mark: Option<u32>,
 ) -> io::Result<AnyStream> {
-    let dial_addr = resolver
-        .resolve(address, false)
-        .await
+    dial_tcp(
+        resolver,
+        address,
+        port,
+        iface,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        packet_mark,
+    )
+    .await
+    .map(|x| Box::new(x) as _)
+    .map_err(server_error)
+}
+
+/// Same as `new_tcp_stream`, keeping the plain socket for callers that can
+/// make use of it.
+pub async fn dial_tcp<'a>(
+    resolver: ThreadSafeDNSResolver,
+    address: &'a str,
+    port: u16,
+    iface: Option<&'a Interface>,
+    #[cfg(any(target_os = "linux", target_os = "android"))] packet_mark: Option<u32>,
+) -> io::Result<TcpStream> {
+    let start = Instant::now();
+    let resolved = resolver.resolve(address, false).await;
+    record_phase(ConnectPhase::Dns, start.elapsed());
+    let dial_addr = resolved
         .map_err(|v| {
             io::Error::new(io::ErrorKind::Other, format!("dns failure: {}", v))
         })?
@@ -114,9 +222,15 @@ pub async fn new_tcp_stream<'a>(
         }
     };
 
-    if let Some(iface) = iface {
-        debug!("binding tcp socket to interface: {:?}", iface);
-        must_bind_socket_on_interface(&socket, iface)?;
+    protect_socket(&socket)?;
+
+    let range = source_port_range();
+    if iface.is_some() || range.is_some() {
+        debug!(
+            "binding tcp socket to interface: {:?}, ports: {:?}",
+            iface, range
+        );
+        bind_socket_in_range(&socket, iface, range, dial_addr.is_ipv6())?;
     }
 
     #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -126,16 +240,21 @@ pub async fn new_tcp_stream<'a>(
 
     socket.set_keepalive(true)?;
     socket.set_nodelay(true)?;
+    // before connecting, for the window scale to be negotiated
+    apply_socket_buffers(&socket)?;
     socket.set_nonblocking(true)?;
 
-    let stream = timeout(
+    let start = Instant::now();
+    let connected = timeout(
         Duration::from_secs(10),
         TcpSocket::from_std_stream(socket.into()).connect((dial_addr, port).into()),
     )
-    .await??;
+    .await;
+    record_phase(ConnectPhase::Connect, start.elapsed());
+    let stream = connected??;
 
     debug!("connected to {}[{}]:{}", address, dial_addr, port);
-    Ok(Box::new(stream))
+    Ok(stream)
 }
 
 pub async fn new_udp_socket(
@@ -164,26 +283,47 @@ pub async fn new_udp_socket(
         }
     };
 
+    protect_socket(&socket)?;
+
+    let range = source_port_range();
+    let v6 = src.is_some_and(|x| x.is_ipv6());
     match (src, iface) {
         (Some(_), Some(iface)) => {
             debug!("both src and iface are set, iface will be used: {:?}", src);
-            must_bind_socket_on_interface(&socket, iface).inspect_err(|x| {
-                error!("failed to bind socket to interface: {}", x);
-            })?;
-        }
-        (Some(src), None) => {
-            debug!("binding socket to: {:?}", src);
-            socket.bind(&(*src).into())?;
+            bind_socket_in_range(&socket, Some(iface), range, v6).inspect_err(
+                |x| {
+                    error!("failed to bind socket to interface: {}", x);
+                },
+            )?;
         }
+        (Some(src), None) => match range {
+            // a port of its own is kept
+            Some(range) if src.port() == 0 => {
+                debug!("binding socket to: {} in ports {}", src.ip(), range);
+                bind_in_range(&socket, src.ip(), range)?;
+            }
+            _ => {
+                debug!("binding socket to: {:?}", src);
+                socket.bind(&(*src).into())?;
+            }
+        },
         (None, Some(iface)) => {
             debug!("binding udp socket to interface: {:?}", iface);
-            must_bind_socket_on_interface(&socket, iface).inspect_err(|x| {
-                error!("failed to bind socket to interface: {}", x);
-            })?;
-        }
-        (None, None) => {
-            debug!("not binding socket to any address or interface");
+            bind_socket_in_range(&socket, Some(iface), range, v6).inspect_err(
+                |x| {
+                    error!("failed to bind socket to interface: {}", x);
+                },
+            )?;
         }
+        (None, None) => match range {
+            Some(range) => {
+                debug!("binding udp socket to ports: {}", range);
+                bind_in_range(&socket, unspecified(false), range)?;
+            }
+            None => {
+                debug!("not binding socket to any address or interface");
+            }
+        },
     }
 
     #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -197,6 +337,55 @@ pub async fn new_udp_socket(
     UdpSocket::from_std(socket.into())
 }
 
+/// A socket for ICMP echo requests, unprivileged where the system allows
+/// it and raw otherwise.
+pub fn new_icmp_socket(
+    v6: bool,
+    iface: Option<&Interface>,
+) -> io::Result<UdpSocket> {
+    let (domain, protocol) = if v6 {
+        (socket2::Domain::IPV6, socket2::Protocol::ICMPV6)
+    } else {
+        (socket2::Domain::IPV4, socket2::Protocol::ICMPV4)
+    };
+    let socket = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(protocol))
+        .or_else(|_| {
+            socket2::Socket::new(domain, socket2::Type::RAW, Some(protocol))
+        })?;
+
+    protect_socket(&socket)?;
+
+    if let Some(iface) = iface {
+        debug!("binding icmp socket to interface: {:?}", iface);
+        must_bind_socket_on_interface(&socket, iface)?;
+    }
+
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+/// A raw socket for the TCP segments of fake TCP, which the system hands
+/// all TCP received to.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn new_raw_tcp_socket(v6: bool) -> io::Result<UdpSocket> {
+    let domain = if v6 {
+        socket2::Domain::IPV6
+    } else {
+        socket2::Domain::IPV4
+    };
+    let socket = socket2::Socket::new(
+        domain,
+        socket2::Type::RAW,
+        Some(socket2::Protocol::TCP),
+    )?;
+
+    protect_socket(&socket)?;
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into())
+}
+
 #[cfg(test)]
 mod tests {
     use std::{net::IpAddr, time::Duration};
@@ -0,0 +1,200 @@
+//! Protocol servers run within the test process, for the roundtrip of an
+//! outbound without docker: the outbound connects through the server to an
+//! echo server, and everything sent has to come back.
+//!
+//! Each server relays TCP to the destination of the request and handles
+//! connections until the runtime of the test goes away. Built for the tests
+//! of this crate, and exported with the `test-harness` feature for others.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rand::RngCore;
+use sha2::{Digest, Sha224};
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+use crate::{
+    app::dns::SystemResolver,
+    common::{tls, utils},
+    proxy::AnyOutboundHandler,
+    session::{Session, SocksAddr},
+};
+
+/// the sizes of the payloads sent through the outbound, across the frame
+/// and record sizes of the protocols
+const PAYLOAD_SIZES: [usize; 5] = [1, 1400, 16 * 1024, 16 * 1024 + 1, 64 * 1024];
+
+/// where the certificates of the test configs are
+fn cert_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../clash/tests/data/config")
+}
+
+/// An echo server on a free local port.
+pub async fn echo_server() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Relay the proxied connection `inbound` to `target`.
+async fn relay<S>(mut inbound: S, target: SocksAddr) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut outbound = match target {
+        SocksAddr::Ip(addr) => TcpStream::connect(addr).await?,
+        SocksAddr::Domain(host, port) => {
+            TcpStream::connect((host.as_str(), port)).await?
+        }
+    };
+    copy_bidirectional(&mut inbound, &mut outbound).await?;
+    Ok(())
+}
+
+/// A trojan server for `password` on a free local port, with the
+/// certificate of example.org, which the outbound has to skip verifying.
+pub async fn trojan_server(password: &str) -> anyhow::Result<SocketAddr> {
+    let base = cert_dir();
+    let tls_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            tls::load_certs(&base.join("example.org.pem"))?,
+            tls::load_private_key(&base.join("example.org-key.pem"))?,
+        )?;
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+    let hash = utils::encode_hex(&Sha224::digest(password.as_bytes())[..]);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let hash = hash.clone();
+            tokio::spawn(async move {
+                let handle = async move {
+                    let mut s = acceptor.accept(stream).await?;
+                    let mut header = vec![0u8; hash.len() + 2];
+                    s.read_exact(&mut header).await?;
+                    if &header[..hash.len()] != hash.as_bytes() {
+                        anyhow::bail!("trojan password mismatch");
+                    }
+                    if s.read_u8().await? != 0x01 {
+                        anyhow::bail!("only trojan CONNECT is served");
+                    }
+                    let target = SocksAddr::read_from(&mut s).await?;
+                    s.read_exact(&mut [0u8; 2]).await?;
+                    relay(s, target).await?;
+                    Ok::<_, anyhow::Error>(())
+                };
+                if let Err(e) = handle.await {
+                    debug!("trojan test server: {}", e);
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// A shadowsocks server with `cipher` and `password` on a free local port.
+#[cfg(feature = "shadowsocks")]
+pub async fn shadowsocks_server(
+    cipher: shadowsocks::crypto::CipherKind,
+    password: &str,
+) -> anyhow::Result<SocketAddr> {
+    use shadowsocks::{
+        config::ServerType, context::Context, relay::socks5::Address, ProxyListener,
+        ServerConfig,
+    };
+
+    let ctx = Context::new_shared(ServerType::Server);
+    let cfg = ServerConfig::new(
+        "127.0.0.1:0".parse::<SocketAddr>()?,
+        password.to_owned(),
+        cipher,
+    );
+    let listener = ProxyListener::bind(ctx, &cfg).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut s, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let handle = async move {
+                    let target = match s.handshake().await? {
+                        Address::SocketAddress(addr) => SocksAddr::Ip(addr),
+                        Address::DomainNameAddress(host, port) => {
+                            SocksAddr::Domain(host, port)
+                        }
+                    };
+                    relay(s, target).await
+                };
+                if let Err(e) = handle.await {
+                    debug!("shadowsocks test server: {}", e);
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// A VMess server for the user `uuid` on a free local port, with the AEAD
+/// header and any of the securities, over plain TCP.
+pub async fn vmess_server(uuid: &str) -> anyhow::Result<SocketAddr> {
+    let uuid = uuid::Uuid::parse_str(uuid)?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                if let Err(e) = crate::proxy::vmess::serve(stream, &uuid).await {
+                    debug!("vmess test server: {}", e);
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Send payloads of several sizes through `handler` to an echo server and
+/// check that they come back unchanged.
+pub async fn echo_roundtrip(handler: AnyOutboundHandler) -> anyhow::Result<()> {
+    let echo = echo_server().await?;
+    let sess = Session {
+        destination: SocksAddr::Ip(echo),
+        ..Default::default()
+    };
+    let resolver = Arc::new(SystemResolver::new(false)?);
+    let mut stream = handler.connect_stream(&sess, resolver).await?;
+
+    for size in PAYLOAD_SIZES {
+        let mut sent = vec![0u8; size];
+        rand::thread_rng().fill_bytes(&mut sent);
+        stream.write_all(&sent).await?;
+        stream.flush().await?;
+
+        let mut received = vec![0u8; size];
+        stream.read_exact(&mut received).await?;
+        anyhow::ensure!(
+            sent == received,
+            "{} bytes through {} came back altered",
+            size,
+            handler.name()
+        );
+    }
+    Ok(())
+}
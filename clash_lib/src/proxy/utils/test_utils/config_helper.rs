@@ -40,8 +40,13 @@ pub async fn load_config() -> anyhow::Result<(
         .map_err(|x| Error::DNSError(x.to_string()))?;
 
     let mmdb = Arc::new(
-        mmdb::Mmdb::new(mmdb_path, config.general.mmdb_download_url.clone(), client)
-            .await?,
+        mmdb::Mmdb::new(
+            mmdb_path,
+            config.general.mmdb_download_url.clone(),
+            &config.general.mmdb_integrity,
+            client,
+        )
+        .await?,
     );
 
     debug!("initializing cache store");
@@ -51,8 +56,13 @@ pub async fn load_config() -> anyhow::Result<(
     );
 
     let dns_resolver = Arc::new(
-        dns::EnhancedResolver::new(&config.dns, cache_store.clone(), mmdb.clone())
-            .await,
+        dns::EnhancedResolver::new(
+            &config.dns,
+            cache_store.clone(),
+            mmdb.clone(),
+            None,
+        )
+        .await,
     );
 
     Ok((config, dns_resolver))
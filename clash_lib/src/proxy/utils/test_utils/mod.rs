@@ -229,7 +229,7 @@ pub async fn latency_test(
     handler: Arc<dyn OutboundHandler>,
 ) -> anyhow::Result<(u16, u16)> {
     let (_, resolver) = config_helper::load_config().await?;
-    let proxy_manager = ProxyManager::new(resolver.clone());
+    let proxy_manager = ProxyManager::new(resolver.clone(), None);
     proxy_manager
         .url_test(handler, "https://example.com", None)
         .await
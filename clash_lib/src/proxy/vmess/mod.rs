@@ -6,6 +6,9 @@ use tracing::debug;
 
 mod vmess_impl;
 
+#[cfg(any(all(test, not(ci)), feature = "test-harness"))]
+pub(crate) use vmess_impl::serve;
+
 use crate::{
     app::{
         dispatcher::{
@@ -167,6 +170,10 @@ impl OutboundHandler for Handler {
         OutboundType::Vmess
     }
 
+    fn server(&self) -> Option<(&str, u16)> {
+        Some((&self.opts.server, self.opts.port))
+    }
+
     /// whether the outbound handler support UDP
     async fn support_udp(&self) -> bool {
         self.opts.udp
@@ -306,6 +313,7 @@ impl OutboundHandler for Handler {
 
 #[cfg(all(test, not(ci)))]
 mod tests {
+    use crate::proxy::utils::test_harness;
     use crate::proxy::utils::test_utils::{
         config_helper::test_config_base_dir,
         consts::*,
@@ -315,6 +323,28 @@ mod tests {
 
     use super::*;
 
+    #[tokio::test]
+    async fn test_vmess_in_process() -> anyhow::Result<()> {
+        const UUID: &str = "b831381d-6324-4d53-ad4f-8cda48b30811";
+        let server = test_harness::vmess_server(UUID).await?;
+        for security in ["aes-128-gcm", "chacha20-poly1305", "none"] {
+            let opts = HandlerOptions {
+                name: format!("test-vmess-in-process-{}", security),
+                common_opts: Default::default(),
+                server: server.ip().to_string(),
+                port: server.port(),
+                uuid: UUID.into(),
+                alter_id: 0,
+                security: security.into(),
+                udp: false,
+                tls: None,
+                transport: None,
+            };
+            test_harness::echo_roundtrip(Handler::new(opts)).await?;
+        }
+        Ok(())
+    }
+
     async fn get_ws_runner() -> anyhow::Result<DockerTestRunner> {
         let test_config_dir = test_config_base_dir();
         let conf = test_config_dir.join("vmess-ws.json");
@@ -355,6 +385,7 @@ mod tests {
                 skip_cert_verify: true,
                 sni: "example.org".into(),
                 alpn: None,
+                ..Default::default()
             }),
             transport: Some(VmessTransport::Ws(WsOption {
                 path: "".to_owned(),
@@ -404,6 +435,7 @@ mod tests {
                 skip_cert_verify: true,
                 sni: "example.org".into(),
                 alpn: None,
+                ..Default::default()
             }),
             transport: Some(VmessTransport::Grpc(GrpcOption {
                 host: "example.org".to_owned(),
@@ -448,6 +480,7 @@ mod tests {
                 skip_cert_verify: true,
                 sni: "example.org".into(),
                 alpn: None,
+                ..Default::default()
             }),
             transport: Some(VmessTransport::H2(Http2Option {
                 host: vec!["example.org".into()],
@@ -4,6 +4,8 @@ mod header;
 // pub mod http;
 mod datagram;
 mod kdf;
+#[cfg(any(all(test, not(ci)), feature = "test-harness"))]
+mod server;
 mod stream;
 mod user;
 
@@ -27,3 +29,5 @@ const MAX_CHUNK_SIZE: usize = 17 * 1024;
 
 pub use client::{Builder, VmessOption};
 pub use datagram::OutboundDatagramVmess;
+#[cfg(any(all(test, not(ci)), feature = "test-harness"))]
+pub(crate) use server::serve;
@@ -0,0 +1,216 @@
+//! The server side of VMess with the AEAD header and the chunk stream, for
+//! the test harness: it opens the request of the outbound, connects the
+//! destination and relays the chunks both ways.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use aes_gcm::Aes128Gcm;
+use bytes::Buf;
+use chacha20poly1305::ChaCha20Poly1305;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    common::{
+        crypto::{self, AeadCipherHelper},
+        utils,
+    },
+    session::SocksAddr,
+};
+
+use super::{
+    cipher::{AeadCipher, VmessSecurity},
+    kdf::{
+        self, KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_IV,
+        KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_KEY,
+        KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_IV,
+        KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_KEY,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+    },
+    user, CHUNK_SIZE, COMMAND_TCP, MAX_CHUNK_SIZE, OPTION_CHUNK_STREAM,
+    SECURITY_AES_128_GCM, SECURITY_CHACHA20_POLY1305, SECURITY_NONE, VERSION,
+};
+
+/// the tag of the AEAD body ciphers
+const OVERHEAD: usize = 16;
+
+/// The cipher of the body chunks in one direction, none for the
+/// security `none`.
+fn body_cipher(
+    security: u8,
+    key: &[u8],
+    iv: &[u8],
+) -> anyhow::Result<Option<AeadCipher>> {
+    let security = match security {
+        SECURITY_NONE => return Ok(None),
+        SECURITY_AES_128_GCM => {
+            VmessSecurity::Aes128Gcm(Aes128Gcm::new_with_slice(key))
+        }
+        SECURITY_CHACHA20_POLY1305 => {
+            let mut full = [0u8; 32];
+            full[..16].copy_from_slice(&utils::md5(key));
+            let tmp = utils::md5(&full[..16]);
+            full[16..].copy_from_slice(&tmp);
+            VmessSecurity::ChaCha20Poly1305(ChaCha20Poly1305::new_with_slice(&full))
+        }
+        x => anyhow::bail!("unsupported vmess security {}", x),
+    };
+    Ok(Some(AeadCipher::new(iv, security)))
+}
+
+/// Serve one VMess TCP connection of the user `uuid` on `stream`. A
+/// request of another user fails to open.
+pub(crate) async fn serve<S>(mut stream: S, uuid: &uuid::Uuid) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let cmd_key = user::new_id(uuid).cmd_key;
+
+    let mut auth_id = [0u8; 16];
+    stream.read_exact(&mut auth_id).await?;
+    let mut sealed_len = [0u8; 2 + OVERHEAD];
+    stream.read_exact(&mut sealed_len).await?;
+    let mut nonce = [0u8; 8];
+    stream.read_exact(&mut nonce).await?;
+
+    let open = |salt_key: &[u8], salt_iv: &[u8], data: &[u8]| {
+        crypto::aes_gcm_decrypt(
+            &kdf::vmess_kdf_3_one_shot(&cmd_key, salt_key, &auth_id, &nonce)[..16],
+            &kdf::vmess_kdf_3_one_shot(&cmd_key, salt_iv, &auth_id, &nonce)[..12],
+            data,
+            Some(&auth_id),
+        )
+    };
+    let len = open(
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+        &sealed_len,
+    )?;
+    let len = u16::from_be_bytes(len[..2].try_into()?) as usize;
+    let mut sealed = vec![0u8; len + OVERHEAD];
+    stream.read_exact(&mut sealed).await?;
+    let header = open(
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV,
+        &sealed,
+    )?;
+
+    anyhow::ensure!(header.len() > 4, "vmess header too short");
+    let (mut buf, sum) = header.split_at(header.len() - 4);
+    anyhow::ensure!(
+        const_fnv1a_hash::fnv1a_hash_32(buf, None).to_be_bytes() == sum,
+        "vmess header checksum mismatch"
+    );
+    anyhow::ensure!(buf.get_u8() == VERSION, "unknown vmess version");
+    let mut req_iv = [0u8; 16];
+    buf.copy_to_slice(&mut req_iv);
+    let mut req_key = [0u8; 16];
+    buf.copy_to_slice(&mut req_key);
+    let resp_v = buf.get_u8();
+    anyhow::ensure!(
+        buf.get_u8() & OPTION_CHUNK_STREAM != 0,
+        "only the vmess chunk stream is served"
+    );
+    let security = buf.get_u8() & 0x0f;
+    buf.advance(1);
+    anyhow::ensure!(buf.get_u8() == COMMAND_TCP, "only vmess TCP is served");
+    let port = buf.get_u16();
+    let target = match buf.get_u8() {
+        0x01 => {
+            let mut ip = [0u8; 4];
+            buf.copy_to_slice(&mut ip);
+            SocksAddr::Ip((Ipv4Addr::from(ip), port).into())
+        }
+        0x02 => {
+            let len = buf.get_u8() as usize;
+            let host = String::from_utf8(buf[..len].to_vec())?;
+            SocksAddr::Domain(host, port)
+        }
+        0x03 => {
+            let mut ip = [0u8; 16];
+            buf.copy_to_slice(&mut ip);
+            SocksAddr::Ip((Ipv6Addr::from(ip), port).into())
+        }
+        x => anyhow::bail!("unknown vmess address type {}", x),
+    };
+
+    let resp_key = utils::sha256(&req_key)[..16].to_vec();
+    let resp_iv = utils::sha256(&req_iv)[..16].to_vec();
+    let seal = |salt_key: &[u8], salt_iv: &[u8], data: &[u8]| {
+        crypto::aes_gcm_encrypt(
+            &kdf::vmess_kdf_1_one_shot(&resp_key, salt_key)[..16],
+            &kdf::vmess_kdf_1_one_shot(&resp_iv, salt_iv)[..12],
+            data,
+            None,
+        )
+    };
+    let resp_header = [resp_v, 0, 0, 0];
+    let mut out = seal(
+        KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_KEY,
+        KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_IV,
+        &(resp_header.len() as u16).to_be_bytes(),
+    )?;
+    out.extend(seal(
+        KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_KEY,
+        KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_IV,
+        &resp_header,
+    )?);
+    stream.write_all(&out).await?;
+
+    let mut read_cipher = body_cipher(security, &req_key, &req_iv)?;
+    let mut write_cipher = body_cipher(security, &resp_key, &resp_iv)?;
+
+    let mut remote = match target {
+        SocksAddr::Ip(addr) => TcpStream::connect(addr).await?,
+        SocksAddr::Domain(host, port) => {
+            TcpStream::connect((host.as_str(), port)).await?
+        }
+    };
+    let (mut remote_r, mut remote_w) = remote.split();
+    let (mut r, mut w) = tokio::io::split(stream);
+
+    let upload = async {
+        loop {
+            let len = match r.read_u16().await {
+                Ok(len) => len as usize,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            anyhow::ensure!(len <= MAX_CHUNK_SIZE, "vmess chunk too large");
+            let mut chunk = vec![0u8; len];
+            r.read_exact(&mut chunk).await?;
+            if let Some(cipher) = read_cipher.as_mut() {
+                cipher.decrypt_inplace(&mut chunk)?;
+                chunk.truncate(len.saturating_sub(OVERHEAD));
+            }
+            remote_w.write_all(&chunk).await?;
+        }
+        remote_w.shutdown().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    let download = async {
+        let mut buf = vec![0u8; CHUNK_SIZE - OVERHEAD];
+        loop {
+            let n = remote_r.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            let mut chunk = buf[..n].to_vec();
+            if let Some(cipher) = write_cipher.as_mut() {
+                chunk.resize(n + OVERHEAD, 0);
+                cipher.encrypt_inplace(&mut chunk)?;
+            }
+            w.write_u16(chunk.len() as u16).await?;
+            w.write_all(&chunk).await?;
+        }
+        w.shutdown().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    tokio::try_join!(upload, download)?;
+    Ok(())
+}
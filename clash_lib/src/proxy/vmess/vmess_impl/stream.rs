@@ -9,6 +9,7 @@ use md5::Md5;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 
 use crate::{
+    app::ntp,
     common::{
         crypto::{self, AeadCipherHelper},
         errors::map_io_error,
@@ -250,7 +251,7 @@ where
             ..
         } = self;
 
-        let now = SystemTime::now()
+        let now = ntp::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .expect("check your system clock")
             .as_secs();
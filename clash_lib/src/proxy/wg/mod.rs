@@ -12,7 +12,7 @@ use crate::{
         },
         dns::ThreadSafeDNSResolver,
     },
-    common::errors::{map_io_error, new_io_error},
+    common::errors::{map_io_error, new_io_error, server_error},
     session::Session,
     Error,
 };
@@ -51,6 +51,7 @@ pub struct HandlerOptions {
     pub udp: bool,
     pub allowed_ips: Option<Vec<String>>,
     pub reserved_bits: Option<Vec<u8>>,
+    pub faketcp: bool,
 }
 
 struct Inner {
@@ -147,6 +148,7 @@ impl Handler {
                             }
                             None => [0, 0, 0],
                         },
+                        faketcp: self.opts.faketcp,
                     },
                     recv_pair.0,
                     send_pair.1,
@@ -216,6 +218,10 @@ impl OutboundHandler for Handler {
         OutboundType::WireGuard
     }
 
+    fn server(&self) -> Option<(&str, u16)> {
+        Some((&self.opts.server, self.opts.port))
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
@@ -229,7 +235,7 @@ impl OutboundHandler for Handler {
         let inner = self
             .initialize_inner(resolver.clone())
             .await
-            .map_err(map_io_error)?;
+            .map_err(|x| server_error(map_io_error(x)))?;
 
         let ip = if self.opts.remote_dns_resolve
             && sess.destination.is_domain()
@@ -357,6 +363,7 @@ mod tests {
             udp: true,
             allowed_ips: Some(vec!["0.0.0.0/0".to_owned()]),
             reserved_bits: None,
+            faketcp: false,
         };
         let handler = Handler::new(opts);
 
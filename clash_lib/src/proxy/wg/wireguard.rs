@@ -1,5 +1,6 @@
 use std::{
     fmt::Debug,
+    io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
     time::Duration,
@@ -23,15 +24,42 @@ use tokio::{
 };
 use tracing::{enabled, error, trace, trace_span, warn, Instrument};
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::proxy::utils::faketcp::FakeTcp;
 use crate::{proxy::utils::new_udp_socket, Error};
 
 use super::events::PortProtocol;
 
+/// How the packets get to the endpoint.
+enum Transport {
+    Udp(UdpSocket),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    FakeTcp(FakeTcp),
+}
+
+impl Transport {
+    async fn send(&self, packet: &[u8], endpoint: SocketAddr) -> io::Result<()> {
+        match self {
+            Transport::Udp(udp) => udp.send_to(packet, endpoint).await.map(|_| ()),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Transport::FakeTcp(tcp) => tcp.send(packet).await,
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Udp(udp) => udp.recv(buf).await,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Transport::FakeTcp(tcp) => tcp.recv(buf).await,
+        }
+    }
+}
+
 pub struct WireguardTunnel {
     pub(crate) source_peer_ip: Ipv4Addr,
     pub(crate) source_peer_ipv6: Option<Ipv6Addr>,
     peer: Arc<Mutex<Tunn>>,
-    udp: UdpSocket,
+    transport: Transport,
     pub(crate) endpoint: SocketAddr,
     allowed_ips: Vec<IpNet>,
     reserved_bits: [u8; 3],
@@ -61,6 +89,8 @@ pub struct Config {
     pub keepalive_seconds: Option<u16>,
     pub allowed_ips: Vec<IpNet>,
     pub reserved_bits: [u8; 3],
+    /// over fake TCP rather than UDP
+    pub faketcp: bool,
 }
 
 impl WireguardTunnel {
@@ -80,19 +110,31 @@ impl WireguardTunnel {
 
         let remote_endpoint = config.remote_endpoint;
 
-        let udp = new_udp_socket(
-            None,
-            None,
+        let transport = match config.faketcp {
             #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .await?;
+            true => Transport::FakeTcp(FakeTcp::connect(remote_endpoint).await?),
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            true => {
+                return Err(Error::InvalidConfig(
+                    "wireguard faketcp is only supported on Linux".to_owned(),
+                ))
+            }
+            false => Transport::Udp(
+                new_udp_socket(
+                    None,
+                    None,
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    None,
+                )
+                .await?,
+            ),
+        };
 
         Ok(Self {
             source_peer_ip: config.source_peer_ip,
             source_peer_ipv6: config.source_peer_ipv6,
             peer: Arc::new(Mutex::new(peer)),
-            udp,
+            transport,
             endpoint: remote_endpoint,
             allowed_ips: config.allowed_ips,
             reserved_bits: config.reserved_bits,
@@ -107,7 +149,7 @@ impl WireguardTunnel {
             packet[2] = self.reserved_bits[1];
             packet[3] = self.reserved_bits[2];
         }
-        self.udp.send_to(packet, self.endpoint).await?;
+        self.transport.send(packet, self.endpoint).await?;
         Ok(())
     }
 
@@ -181,7 +223,7 @@ impl WireguardTunnel {
 
         loop {
             let size = match self
-                .udp
+                .transport
                 .recv(&mut recv_buf)
                 .instrument(trace_span!(
                     "wg_receive",
@@ -12,7 +12,7 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 
 use erased_serde::Serialize as ESerialize;
 
-#[derive(Debug, PartialEq, Eq, Serialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Serialize)]
 pub enum SocksAddr {
     Ip(SocketAddr),
     Domain(String, u16),
@@ -390,6 +390,12 @@ pub struct Session {
     pub packet_mark: Option<u32>,
     /// The bind interface
     pub iface: Option<Interface>,
+    /// The name of the inbound listener that accepted the connection.
+    pub inbound_name: String,
+    /// The host name sniffed from the payload, if any.
+    pub sniff_host: Option<String>,
+    /// The user the inbound authenticated, if any.
+    pub inbound_user: Option<String>,
 }
 
 impl Session {
@@ -408,6 +414,18 @@ impl Session {
             Box::new(self.destination.port()) as _,
         );
         rv.insert("host".to_string(), Box::new(self.destination.host()) as _);
+        rv.insert(
+            "inboundName".to_string(),
+            Box::new(self.inbound_name.clone()) as _,
+        );
+        rv.insert(
+            "sniffHost".to_string(),
+            Box::new(self.sniff_host.clone().unwrap_or_default()) as _,
+        );
+        rv.insert(
+            "inboundUser".to_string(),
+            Box::new(self.inbound_user.clone().unwrap_or_default()) as _,
+        );
 
         rv
     }
@@ -422,6 +440,9 @@ impl Default for Session {
             destination: SocksAddr::any_ipv4(),
             packet_mark: None,
             iface: None,
+            inbound_name: String::new(),
+            sniff_host: None,
+            inbound_user: None,
         }
     }
 }
@@ -444,6 +465,9 @@ impl Debug for Session {
             .field("destination", &self.destination)
             .field("packet_mark", &self.packet_mark)
             .field("iface", &self.iface)
+            .field("inbound_name", &self.inbound_name)
+            .field("sniff_host", &self.sniff_host)
+            .field("inbound_user", &self.inbound_user)
             .finish()
     }
 }
@@ -457,6 +481,9 @@ impl Clone for Session {
             destination: self.destination.clone(),
             packet_mark: self.packet_mark,
             iface: self.iface.as_ref().cloned(),
+            inbound_name: self.inbound_name.clone(),
+            sniff_host: self.sniff_host.clone(),
+            inbound_user: self.inbound_user.clone(),
         }
     }
 }